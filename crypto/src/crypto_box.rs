@@ -9,6 +9,8 @@ use super::{nonce::Nonce, hash::{CryptoboxPublicKeyHash, HashType}};
 use std::ops::Deref;
 
 pub const BOX_ZERO_BYTES: usize = 32;
+/// Size of the authentication tag, which precedes the encrypted message
+pub const BOX_MAC_BYTES: usize = box_::MACBYTES;
 pub(crate) const CRYPTO_KEY_SIZE: usize = 32;
 pub(crate) const NONCE_SIZE: usize = 24;
 
@@ -132,16 +134,20 @@ pub fn precompute(pk_as_hex_string: &str, sk_as_hex_string: &str) -> Result<Prec
 /// * `nonce` - Nonce required to encode message
 /// * `pck` - Precomputed key required to encode message
 pub fn encrypt(msg: &[u8], nonce: &Nonce, pck: &PrecomputedKey) -> Result<Vec<u8>, CryptoError> {
-    let nonce_bytes = nonce.get_bytes();
-    if nonce_bytes.len() == NONCE_SIZE {
-        let mut nonce_arr = [0u8; NONCE_SIZE];
-        nonce_arr.copy_from_slice(&nonce_bytes);
-        let box_nonce = box_::Nonce(nonce_arr);
+    Ok(box_::seal_precomputed(msg, &box_nonce(nonce)?, &*pck))
+}
 
-        Ok(box_::seal_precomputed(msg, &box_nonce, &*pck))
-    } else {
-        Err(CryptoError::InvalidNonceSize(nonce_bytes.len()))
-    }
+/// Encrypt binary message in place and return its authentication tag.
+///
+/// Authentication tag followed by the encrypted `msg` is the same as the result of [encrypt].
+///
+/// # Arguments
+/// * `msg` - Binary message to be encoded, it is replaced by the encrypted message
+/// * `nonce` - Nonce required to encode message
+/// * `pck` - Precomputed key required to encode message
+pub fn encrypt_in_place(msg: &mut [u8], nonce: &Nonce, pck: &PrecomputedKey) -> Result<[u8; BOX_MAC_BYTES], CryptoError> {
+    let box_::Tag(tag) = box_::seal_detached_precomputed(msg, &box_nonce(nonce)?, &*pck);
+    Ok(tag)
 }
 
 /// Decrypt binary message into raw binary data
@@ -151,22 +157,35 @@ pub fn encrypt(msg: &[u8], nonce: &Nonce, pck: &PrecomputedKey) -> Result<Vec<u8
 /// * `nonce` - Nonce required to decode message
 /// * `pck` - Precomputed key required to decode message
 pub fn decrypt(enc: &[u8], nonce: &Nonce, pck: &PrecomputedKey) -> Result<Vec<u8>, CryptoError> {
+    match box_::open_precomputed(enc, &box_nonce(nonce)?, pck) {
+        Ok(msg) => Ok(msg),
+        Err(()) => Err(CryptoError::FailedToDecrypt)
+    }
+}
+
+/// Decrypt binary message in place, counterpart of [encrypt_in_place]
+///
+/// # Arguments
+/// * `enc` - Encoded message without authentication tag, it is replaced by the decoded message
+/// * `tag` - Authentication tag of the message
+/// * `nonce` - Nonce required to decode message
+/// * `pck` - Precomputed key required to decode message
+pub fn decrypt_in_place(enc: &mut [u8], tag: &[u8; BOX_MAC_BYTES], nonce: &Nonce, pck: &PrecomputedKey) -> Result<(), CryptoError> {
+    box_::open_detached_precomputed(enc, &box_::Tag(*tag), &box_nonce(nonce)?, pck)
+        .map_err(|()| CryptoError::FailedToDecrypt)
+}
+
+fn box_nonce(nonce: &Nonce) -> Result<box_::Nonce, CryptoError> {
     let nonce_bytes = nonce.get_bytes();
     if nonce_bytes.len() == NONCE_SIZE {
         let mut nonce_arr = [0u8; NONCE_SIZE];
         nonce_arr.copy_from_slice(&nonce_bytes);
-        let box_nonce = box_::Nonce(nonce_arr);
-
-        match box_::open_precomputed(enc, &box_nonce, pck) {
-            Ok(msg) => Ok(msg),
-            Err(()) => Err(CryptoError::FailedToDecrypt)
-        }
+        Ok(box_::Nonce(nonce_arr))
     } else {
         Err(CryptoError::InvalidNonceSize(nonce_bytes.len()))
     }
 }
 
-
 #[cfg(test)]
 mod tests {
     use failure::Error;
@@ -221,4 +240,26 @@ mod tests {
         let dec = String::from_utf8(decrypt(&enc, &nonce, &pck).unwrap())?;
         Ok(assert_eq!(msg, &dec))
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn encrypt_and_decrypt_in_place() -> Result<(), Error> {
+        let nonce = Nonce::new(&hex::decode("8dde158c55cff52f4be9352787d333e616a67853640d72c5")?);
+        let msg = hex::decode("00874d1b98317bd6efad8352a7144c9eb0b218c9130e0a875973908ddc894b764ffc0d7f176cf800b978af9e919bdc35122585168475096d0ebcaca1f2a1172412b91b363ff484d1c64c03417e0e755e696c386a0000002d53414e44424f5845445f54455a4f535f414c5048414e45545f323031382d31312d33305431353a33303a35365a00000000")?;
+        let pck = PrecomputedKey::from_hex("5228751a6f5a6494e38e1042f578e3a64ae3462b7899356f49e50be846c9609c")?;
+
+        let mut buf = msg.clone();
+        let tag = encrypt_in_place(&mut buf, &nonce, &pck)?;
+        let encrypted_msg = encrypt(&msg, &nonce, &pck)?;
+        assert_eq!(&encrypted_msg[..BOX_MAC_BYTES], &tag[..]);
+        assert_eq!(&encrypted_msg[BOX_MAC_BYTES..], &buf[..]);
+
+        decrypt_in_place(&mut buf, &tag, &nonce, &pck)?;
+        assert_eq!(msg, buf);
+
+        // tampered message is rejected
+        let mut buf = encrypted_msg[BOX_MAC_BYTES..].to_vec();
+        buf[0] ^= 1;
+        assert!(decrypt_in_place(&mut buf, &tag, &nonce, &pck).is_err());
+        Ok(())
+    }
+}
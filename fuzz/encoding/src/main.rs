@@ -70,6 +70,9 @@ fn gen_random_member() -> Encoding {
         String,
         Bytes,
         Timestamp,
+        BoundedString(rng.gen_range(0, 32)),
+        BoundedBytes(rng.gen_range(0, 32)),
+        Encoding::bounded_list(rng.gen_range(0, 8), Uint8),
         // TODO: Add implement for complex sub-types
     ];
 
@@ -6,14 +6,34 @@ use honggfuzz::fuzz;
 use log::debug;
 
 use tezos_messages::p2p::binary_message::BinaryMessage;
+use tezos_messages::p2p::encoding::limits::*;
 use tezos_messages::p2p::encoding::prelude::*;
 
 fn main() {
     loop {
         fuzz!(|data: &[u8]| {
-            if let Err(e) = PeerMessageResponse::from_bytes(data) {
-                debug!("PeerMessageResponse::from_bytes produced error for input: {:?}\nError:\n{:?}", data, e);
+            match PeerMessageResponse::from_bytes(data) {
+                Ok(response) => response.messages().iter().for_each(check_limits),
+                Err(e) => debug!("PeerMessageResponse::from_bytes produced error for input: {:?}\nError:\n{:?}", data, e),
             }
         });
     }
 }
+
+/// Successfully decoded message must never exceed the limits
+fn check_limits(message: &PeerMessage) {
+    match message {
+        PeerMessage::Advertise(message) => assert!(message.id().len() <= ADVERTISE_ID_LIST_MAX_LENGTH),
+        PeerMessage::CurrentBranch(message) => assert!(message.current_branch().history().len() <= CURRENT_BRANCH_HISTORY_MAX_LENGTH),
+        PeerMessage::CurrentHead(message) => {
+            assert!(message.current_mempool().known_valid().len() <= MEMPOOL_MAX_OPERATIONS);
+            assert!(message.current_mempool().pending().len() <= MEMPOOL_MAX_OPERATIONS);
+        }
+        PeerMessage::GetBlockHeaders(message) => assert!(message.get_block_headers().len() <= GET_BLOCK_HEADERS_MAX_LENGTH),
+        PeerMessage::GetOperations(message) => assert!(message.get_operations().len() <= GET_OPERATIONS_MAX_LENGTH),
+        PeerMessage::GetProtocols(message) => assert!(message.get_protocols().len() <= GET_PROTOCOLS_MAX_LENGTH),
+        PeerMessage::GetOperationHashesForBlocks(message) => assert!(message.get_operation_hashes_for_blocks().len() <= GET_OPERATION_HASHES_FOR_BLOCKS_MAX_LENGTH),
+        PeerMessage::GetOperationsForBlocks(message) => assert!(message.get_operations_for_blocks().len() <= GET_OPERATIONS_FOR_BLOCKS_MAX_LENGTH),
+        _ => (),
+    }
+}
@@ -0,0 +1,113 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Decodes p2p traffic captured by the node (see `--p2p-capture-dir`) and prints messages as JSON, one message per line.
+
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
+
+use clap::{App, Arg};
+use serde_json::{json, Value};
+
+use networking::p2p::capture::{capture_files, CaptureDecoder, CapturedMessage, DecodedMessage, Direction, read_capture};
+
+fn main() -> Result<(), failure::Error> {
+    let args = App::new("P2P capture decoder")
+        .about("Decodes captured p2p traffic to JSON")
+        .arg(Arg::with_name("capture")
+            .long("capture")
+            .takes_value(true)
+            .multiple(true)
+            .required(true)
+            .value_name("PATH")
+            .help("Capture file or directory with capture files"))
+        .arg(Arg::with_name("peer")
+            .long("peer")
+            .takes_value(true)
+            .multiple(true)
+            .value_name("PEER_ID")
+            .help("Print only messages of these peers"))
+        .arg(Arg::with_name("message-type")
+            .long("message-type")
+            .takes_value(true)
+            .multiple(true)
+            .value_name("TYPE")
+            .help("Print only messages of these types (case insensitive), e.g. GetBlockHeaders, CurrentHead, Metadata, Ack"))
+        .get_matches();
+
+    let peers: Vec<&str> = args.values_of("peer").map(|peers| peers.collect()).unwrap_or_default();
+    let message_types: Vec<String> = args.values_of("message-type")
+        .map(|types| types.map(str::to_lowercase).collect())
+        .unwrap_or_default();
+
+    let mut files = Vec::new();
+    for path in args.values_of("capture").into_iter().flatten().map(PathBuf::from) {
+        if path.is_dir() {
+            files.extend(capture_files(&path)?);
+        } else {
+            files.push(path);
+        }
+    }
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut decoder = CaptureDecoder::new();
+    for file in files {
+        for chunk in read_capture(&file)? {
+            if !peers.is_empty() && !peers.contains(&chunk.peer_id.as_str()) {
+                continue;
+            }
+            if let Some(decoded) = decoder.decode(chunk) {
+                for line in to_json(&decoded) {
+                    let message_type = line["type"].as_str().unwrap_or("").to_lowercase();
+                    if message_types.is_empty() || message_types.contains(&message_type) {
+                        writeln!(out, "{}", line)?;
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Convert decoded message to JSON lines, peer message response is split to its messages
+fn to_json(decoded: &DecodedMessage) -> Vec<Value> {
+    let messages = match &decoded.message {
+        Ok(CapturedMessage::Metadata(metadata)) => vec![("Metadata".to_string(), json!(metadata))],
+        Ok(CapturedMessage::Ack(ack)) => vec![type_and_value(json!(ack))],
+        Ok(CapturedMessage::Peer(response)) => response.messages().iter()
+            .map(|message| type_and_value(json!(message)))
+            .collect(),
+        Err(e) => vec![("Error".to_string(), json!(format!("{:?}", e)))],
+    };
+
+    let timestamp_ms = decoded.timestamp.duration_since(UNIX_EPOCH).map(|timestamp| timestamp.as_millis() as u64).unwrap_or(0);
+    let direction = match decoded.direction {
+        Direction::Incoming => "incoming",
+        Direction::Outgoing => "outgoing",
+    };
+    messages.into_iter()
+        .map(|(message_type, message)| json!({
+            "timestamp_ms": timestamp_ms,
+            "connection_id": decoded.connection_id,
+            "peer_id": decoded.peer_id,
+            "direction": direction,
+            "chunks": decoded.chunks,
+            "type": message_type,
+            "message": message,
+        }))
+        .collect()
+}
+
+/// Enum variants are serialized as `"Variant"` or `{"Variant": value}`, variant name is the message type
+fn type_and_value(value: Value) -> (String, Value) {
+    match value {
+        Value::String(variant) => (variant, Value::Null),
+        Value::Object(map) if map.len() == 1 => {
+            let (variant, value) = map.into_iter().next().unwrap();
+            (variant, value)
+        }
+        other => ("Unknown".to_string(), other),
+    }
+}
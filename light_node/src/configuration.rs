@@ -6,13 +6,18 @@ use std::env;
 use std::ffi::OsString;
 use std::fs;
 use std::io::{self, BufRead};
-use std::net::SocketAddr;
+use std::net::{AddrParseError, IpAddr, SocketAddr};
 use std::path::{Path, PathBuf};
 use std::time::Duration;
 
 use clap::{App, Arg};
 
-use shell::peer_manager::P2p;
+use crypto::hash::HashType;
+use networking::p2p::capture::CaptureConfig;
+use networking::p2p::peer::KeepaliveConfig;
+use networking::p2p::rate_limit::RateLimits;
+use shell::mempool_prevalidator::MempoolLimits;
+use shell::peer_manager::{P2p, PeerDiversityLimits};
 use shell::PeerConnectionThreshold;
 use storage::persistent::{DbConfiguration, DbConfigurationBuilder};
 use tezos_api::environment;
@@ -32,6 +37,8 @@ pub struct Logging {
     pub level: slog::Level,
     pub format: LogFormat,
     pub file: Option<PathBuf>,
+    /// If provided, network and shell channel traffic is recorded to this file (see [shell::recorder])
+    pub record_traffic_file: Option<PathBuf>,
 }
 
 #[derive(Debug, Clone)]
@@ -85,6 +92,7 @@ impl std::str::FromStr for LogFormat {
 #[derive(Debug, Clone)]
 pub struct Environment {
     pub p2p: P2p,
+    pub mempool: MempoolLimits,
     pub rpc: Rpc,
     pub logging: Logging,
     pub storage: Storage,
@@ -94,6 +102,8 @@ pub struct Environment {
     pub tezos_network: TezosEnvironment,
     pub enable_testchain: bool,
     pub tokio_threads: usize,
+    /// Peer behind our current head is considered unhelpful and disconnected, if its current head level stays the same for this long
+    pub current_head_update_timeout: Duration,
 
     /// This flag is used, just for to stop node immediatelly after generate identity,
     /// to prevent and initialize actors and create data (except identity)
@@ -160,7 +170,7 @@ pub fn tezos_app() -> App<'static, 'static> {
             .long("identity-expected-pow")
             .takes_value(true)
             .value_name("NUM")
-            .help("Expected power of identity for node. It is used to generate new identity. Default: 26.0")
+            .help("Expected power of identity for node. It is used to generate new identity and it is required from remote peers. Default: 26.0")
             .validator(parse_validator_fn!(f64, "Value must be a valid f64 number for expected_pow")))
         .arg(Arg::with_name("bootstrap-db-path")
             .long("bootstrap-db-path")
@@ -191,6 +201,12 @@ pub fn tezos_app() -> App<'static, 'static> {
             .value_name("PATH")
             .help("Path to the log file. If provided, logs are displayed the log file, otherwise in terminal.
                        In case it starts with ./ or ../, it is relative path to the current dir, otherwise to the --tezos-data-dir"))
+        .arg(Arg::with_name("record-traffic")
+            .long("record-traffic")
+            .takes_value(true)
+            .value_name("PATH")
+            .help("Path to the file, where network and shell channel traffic is recorded, so it can be replayed later.
+                       In case it starts with ./ or ../, it is relative path to the current dir, otherwise to the --tezos-data-dir"))
         .arg(Arg::with_name("log-format")
             .long("log-format")
             .takes_value(true)
@@ -212,13 +228,49 @@ pub fn tezos_app() -> App<'static, 'static> {
             .takes_value(true)
             .value_name("BOOL")
             .help("Enable or disable mempool"))
+        .args(
+            &[
+                Arg::with_name("mempool-max-operations")
+                    .long("mempool-max-operations")
+                    .takes_value(true)
+                    .value_name("NUM")
+                    .help("Maximal count of operations in mempool, default: 10000")
+                    .validator(parse_validator_fn!(usize, "Value must be a valid number")),
+                Arg::with_name("mempool-max-bytes")
+                    .long("mempool-max-bytes")
+                    .takes_value(true)
+                    .value_name("NUM")
+                    .help("Maximal size of operations in mempool in bytes, default: 52428800")
+                    .validator(parse_validator_fn!(usize, "Value must be a valid number")),
+                Arg::with_name("mempool-max-operations-per-peer")
+                    .long("mempool-max-operations-per-peer")
+                    .takes_value(true)
+                    .value_name("NUM")
+                    .help("Maximal count of operations in mempool received from one peer, default: 1000")
+                    .validator(parse_validator_fn!(usize, "Value must be a valid number")),
+                Arg::with_name("mempool-max-bytes-per-peer")
+                    .long("mempool-max-bytes-per-peer")
+                    .takes_value(true)
+                    .value_name("NUM")
+                    .help("Maximal size of operations in mempool received from one peer in bytes, default: 5242880")
+                    .validator(parse_validator_fn!(usize, "Value must be a valid number")),
+            ])
         .arg(Arg::with_name("private-node")
             .long("private-node")
             .takes_value(true)
             .value_name("BOOL")
-            .requires("peers")
             .conflicts_with("bootstrap-lookup-address")
-            .help("Enable or disable private node. Use peers to set IP addresses of the peers you want to connect to"))
+            .help("Enable or disable private node. Private node connects to and accepts only trusted peers (see --peers and --trusted-peer-ids) and does not advertise its peers"))
+        .arg(Arg::with_name("trusted-peer-ids")
+            .long("trusted-peer-ids")
+            .takes_value(true)
+            .value_name("PEER_ID")
+            .help("Peer ids of trusted peers, peers from --peers are trusted too. Trusted peers are never greylisted and always reconnected. Peer ids are delimited by a comma. Format: PEER_ID1,PEER_ID2")
+            .validator(|v| if v.split(',').all(|peer_id| HashType::CryptoboxPublicKeyHash.string_to_bytes(peer_id).is_ok()) {
+                Ok(())
+            } else {
+                Err(format!("Value '{}' is not valid. Expected format is: PEER_ID1,PEER_ID2", v))
+            }))
         .arg(Arg::with_name("network")
             .long("network")
             .takes_value(true)
@@ -230,6 +282,12 @@ pub fn tezos_app() -> App<'static, 'static> {
             .value_name("PORT")
             .help("Socket listening port for p2p for communication with tezos world")
             .validator(parse_validator_fn!(u16, "Value must be a valid port number")))
+        .arg(Arg::with_name("p2p-bind-address")
+            .long("p2p-bind-address")
+            .takes_value(true)
+            .value_name("IP")
+            .help("IP address where node listens for p2p connections, :: accepts both IPv4 and IPv6 connections. Default: ::")
+            .validator(parse_validator_fn!(IpAddr, "Value must be a valid IP address")))
         .arg(Arg::with_name("rpc-port")
             .long("rpc-port")
             .takes_value(true)
@@ -251,17 +309,10 @@ pub fn tezos_app() -> App<'static, 'static> {
             .long("peers")
             .takes_value(true)
             .value_name("IP:PORT")
-            .help("A peer to bootstrap the network from. Peers are delimited by a colon. Format: IP1:PORT1,IP2:PORT2,IP3:PORT3")
-            .validator(|v| {
-                let err_count = v.split(',')
-                    .map(|ip_port| ip_port.parse::<SocketAddr>())
-                    .filter(|v| v.is_err())
-                    .count();
-                if err_count == 0 {
-                    Ok(())
-                } else {
-                    Err(format!("Value '{}' is not valid. Expected format is: IP1:PORT1,IP2:PORT2,IP3:PORT3", v))
-                }
+            .help("A peer to bootstrap the network from. Peers are delimited by a colon. Format: IP1:PORT1,IP2:PORT2,[IPv6]:PORT3")
+            .validator(|v| match parse_peers(&v) {
+                Ok(_) => Ok(()),
+                Err(_) => Err(format!("Value '{}' is not valid. Expected format is: IP1:PORT1,IP2:PORT2,[IPv6]:PORT3", v)),
             }))
         .arg(Arg::with_name("peer-thresh-low")
             .long("peer-thresh-low")
@@ -275,6 +326,125 @@ pub fn tezos_app() -> App<'static, 'static> {
             .value_name("NUM")
             .help("Maximal number of peers to connect to")
             .validator(parse_validator_fn!(usize, "Value must be a valid number")))
+        .args(
+            &[
+                Arg::with_name("peer-max-per-ip")
+                    .long("peer-max-per-ip")
+                    .takes_value(true)
+                    .value_name("NUM")
+                    .help("Maximal number of connections with one IP address (loopback and private addresses are not limited), default: 2 (sandbox: unlimited)")
+                    .validator(parse_validator_fn!(usize, "Value must be a valid number")),
+                Arg::with_name("peer-max-per-subnet24")
+                    .long("peer-max-per-subnet24")
+                    .takes_value(true)
+                    .value_name("NUM")
+                    .help("Maximal number of connections with one /24 subnet (/48 for IPv6), default: 3 (sandbox: unlimited)")
+                    .validator(parse_validator_fn!(usize, "Value must be a valid number")),
+                Arg::with_name("peer-max-per-subnet16")
+                    .long("peer-max-per-subnet16")
+                    .takes_value(true)
+                    .value_name("NUM")
+                    .help("Maximal number of connections with one /16 subnet (/32 for IPv6), default: 6 (sandbox: unlimited)")
+                    .validator(parse_validator_fn!(usize, "Value must be a valid number")),
+                Arg::with_name("peer-reserved-trusted-percent")
+                    .long("peer-reserved-trusted-percent")
+                    .takes_value(true)
+                    .value_name("PERCENT")
+                    .help("Percentage of outgoing connections reserved for trusted and previously connected peers, default: 25 (sandbox: 0)")
+                    .validator(parse_validator_fn!(usize, "Value must be a valid number")),
+                Arg::with_name("peer-reserved-incoming-percent")
+                    .long("peer-reserved-incoming-percent")
+                    .takes_value(true)
+                    .value_name("PERCENT")
+                    .help("Percentage of connections reserved for incoming connections, default: 20 (sandbox: 0)")
+                    .validator(parse_validator_fn!(usize, "Value must be a valid number")),
+            ])
+        .args(
+            &[
+                Arg::with_name("peer-inbound-bytes-per-sec")
+                    .long("peer-inbound-bytes-per-sec")
+                    .takes_value(true)
+                    .value_name("NUM")
+                    .help("Maximal inbound bandwidth of one peer in bytes per second, 0 means unlimited, default: 10485760")
+                    .validator(parse_validator_fn!(u64, "Value must be a valid number")),
+                Arg::with_name("peer-get-block-headers-per-sec")
+                    .long("peer-get-block-headers-per-sec")
+                    .takes_value(true)
+                    .value_name("NUM")
+                    .help("Maximal count of block headers requested by one peer per second, 0 means unlimited, default: 100")
+                    .validator(parse_validator_fn!(u64, "Value must be a valid number")),
+                Arg::with_name("peer-get-operations-for-blocks-per-sec")
+                    .long("peer-get-operations-for-blocks-per-sec")
+                    .takes_value(true)
+                    .value_name("NUM")
+                    .help("Maximal count of operations for blocks requested by one peer per second, 0 means unlimited, default: 100")
+                    .validator(parse_validator_fn!(u64, "Value must be a valid number")),
+                Arg::with_name("peer-operations-per-sec")
+                    .long("peer-operations-per-sec")
+                    .takes_value(true)
+                    .value_name("NUM")
+                    .help("Maximal count of operations sent by one peer per second, 0 means unlimited, default: 200")
+                    .validator(parse_validator_fn!(u64, "Value must be a valid number")),
+                Arg::with_name("peer-messages-per-sec")
+                    .long("peer-messages-per-sec")
+                    .takes_value(true)
+                    .value_name("NUM")
+                    .help("Maximal count of other requests and unsolicited messages (responses are not counted) sent by one peer per second, 0 means unlimited, default: 100")
+                    .validator(parse_validator_fn!(u64, "Value must be a valid number")),
+                Arg::with_name("peer-max-throttle-secs")
+                    .long("peer-max-throttle-secs")
+                    .takes_value(true)
+                    .value_name("NUM")
+                    .help("Peer exceeding its request limits for longer period is disconnected, default: 30")
+                    .validator(parse_validator_fn!(u64, "Value must be a valid number")),
+                Arg::with_name("outbound-bytes-per-sec")
+                    .long("outbound-bytes-per-sec")
+                    .takes_value(true)
+                    .value_name("NUM")
+                    .help("Maximal outbound bandwidth of all peers together in bytes per second, 0 means unlimited, default: 0")
+                    .validator(parse_validator_fn!(u64, "Value must be a valid number")),
+            ])
+        .args(
+            &[
+                Arg::with_name("peer-ping-interval-secs")
+                    .long("peer-ping-interval-secs")
+                    .takes_value(true)
+                    .value_name("NUM")
+                    .help("Peer is pinged with GetCurrentHead, when nothing was received from it for this long, default: 10 (sandbox: 30)")
+                    .validator(parse_validator_fn!(u64, "Value must be a valid number")),
+                Arg::with_name("peer-dead-timeout-secs")
+                    .long("peer-dead-timeout-secs")
+                    .takes_value(true)
+                    .value_name("NUM")
+                    .help("Connection is closed, when nothing was received from the peer for this long, it has to be longer than the ping interval, default: 30 (sandbox: 90)")
+                    .validator(parse_validator_fn!(u64, "Value must be a valid number")),
+                Arg::with_name("peer-head-update-timeout-secs")
+                    .long("peer-head-update-timeout-secs")
+                    .takes_value(true)
+                    .value_name("NUM")
+                    .help("Peer behind our current head is disconnected, when its current head does not change for this long, default: 120 (sandbox: 600)")
+                    .validator(parse_validator_fn!(u64, "Value must be a valid number")),
+            ])
+        .arg(Arg::with_name("p2p-capture-dir")
+            .long("p2p-capture-dir")
+            .takes_value(true)
+            .value_name("PATH")
+            .help("Path to the directory, where decrypted p2p traffic is captured, capture files can be decoded by p2p_capture_decoder.
+                       In case it starts with ./ or ../, it is relative path to the current dir, otherwise to the --tezos-data-dir"))
+        .arg(Arg::with_name("p2p-capture-max-file-size")
+            .long("p2p-capture-max-file-size")
+            .takes_value(true)
+            .value_name("NUM")
+            .requires("p2p-capture-dir")
+            .help("Capture file is rotated, when it reaches this size in bytes, default: 104857600")
+            .validator(parse_validator_fn!(u64, "Value must be a valid number")))
+        .arg(Arg::with_name("p2p-capture-max-files")
+            .long("p2p-capture-max-files")
+            .takes_value(true)
+            .value_name("NUM")
+            .requires("p2p-capture-dir")
+            .help("Count of the newest capture files which are kept, default: 10")
+            .validator(parse_validator_fn!(usize, "Value must be a valid number")))
         .arg(Arg::with_name("protocol-runner")
             .long("protocol-runner")
             .takes_value(true)
@@ -437,7 +607,7 @@ fn validate_required_args(args: &clap::ArgMatches) {
     validate_required_arg(args, "identity-file");
     validate_required_arg(args, "identity-expected-pow");
 
-    // "bootstrap-lookup-address", "log-file" and "peers" are not required
+    // "bootstrap-lookup-address", "log-file", "record-traffic", "p2p-capture-dir" and "peers" are not required
 }
 
 // Validates single required arg. If missing, exit whole process
@@ -447,6 +617,25 @@ pub fn validate_required_arg(args: &clap::ArgMatches, arg_name: &str) {
     }
 }
 
+// Peer has to be pinged before its connection is considered dead, otherwise idle peers are disconnected without a ping
+fn validate_keepalive(keepalive: &KeepaliveConfig) -> Result<(), String> {
+    if keepalive.dead_timeout > keepalive.ping_interval {
+        Ok(())
+    } else {
+        Err(format!(
+            "--peer-dead-timeout-secs ({}) must be greater than --peer-ping-interval-secs ({})",
+            keepalive.dead_timeout.as_secs(), keepalive.ping_interval.as_secs(),
+        ))
+    }
+}
+
+// Parses comma separated peers, IPv6 addresses are enclosed in brackets, e.g. 1.2.3.4:9732,[::1]:9732
+fn parse_peers(peers: &str) -> Result<Vec<SocketAddr>, AddrParseError> {
+    peers.split(',')
+        .map(|ip_port| ip_port.parse::<SocketAddr>())
+        .collect()
+}
+
 // Returns final path. In case:
 //      1. path is relative -> final_path = tezos_data_dir / path
 //      2. path is absolute -> final_path = path
@@ -538,6 +727,17 @@ impl Environment {
             .parse::<TezosEnvironment>()
             .expect("Was expecting one value from TezosEnvironment");
 
+        // quiet networks produce blocks rarely, so their peers are allowed to stay silent for longer
+        let (ping_interval_secs, dead_timeout_secs, head_update_timeout_secs) = match tezos_network {
+            TezosEnvironment::Sandbox => ("30", "90", "600"),
+            _ => ("10", "30", "120"),
+        };
+        // sandbox nodes usually run on one host, so they are not limited by diversity rules
+        let default_peer_diversity = match tezos_network {
+            TezosEnvironment::Sandbox => PeerDiversityLimits::unlimited(),
+            _ => PeerDiversityLimits::default(),
+        };
+
         let data_dir: PathBuf = args.value_of("tezos-data-dir")
             .unwrap_or("")
             .parse::<PathBuf>()
@@ -550,6 +750,10 @@ impl Environment {
                     .unwrap_or("")
                     .parse::<u16>()
                     .expect("Was expecting value of p2p-port"),
+                bind_address: args.value_of("p2p-bind-address")
+                    .unwrap_or("::")
+                    .parse::<IpAddr>()
+                    .expect("Provided value cannot be converted to IP address"),
                 disable_bootstrap_lookup: args
                     .is_present("disable-bootstrap-lookup"),
                 bootstrap_lookup_addresses: args.
@@ -570,11 +774,11 @@ impl Environment {
                 }
                 ),
                 initial_peers: args.value_of("peers")
-                    .map(|peers_str| peers_str
-                        .split(',')
-                        .map(|ip_port| ip_port.parse().expect("Was expecting IP:PORT"))
-                        .collect()
-                    ).unwrap_or_default(),
+                    .map(|peers_str| parse_peers(peers_str).expect("Was expecting IP:PORT"))
+                    .unwrap_or_default(),
+                trusted_peer_ids: args.value_of("trusted-peer-ids")
+                    .map(|peer_ids_str| peer_ids_str.split(',').map(|peer_id| peer_id.to_string()).collect())
+                    .unwrap_or_default(),
                 peer_threshold: PeerConnectionThreshold::new(
                     args.value_of("peer-thresh-low")
                         .unwrap_or("")
@@ -585,15 +789,119 @@ impl Environment {
                         .parse::<usize>()
                         .expect("Provided value cannot be converted to number"),
                 ),
+                peer_diversity: PeerDiversityLimits {
+                    max_per_ip: args.value_of("peer-max-per-ip")
+                        .map(|value| value.parse::<usize>().expect("Provided value cannot be converted to number"))
+                        .unwrap_or(default_peer_diversity.max_per_ip),
+                    max_per_narrow_subnet: args.value_of("peer-max-per-subnet24")
+                        .map(|value| value.parse::<usize>().expect("Provided value cannot be converted to number"))
+                        .unwrap_or(default_peer_diversity.max_per_narrow_subnet),
+                    max_per_wide_subnet: args.value_of("peer-max-per-subnet16")
+                        .map(|value| value.parse::<usize>().expect("Provided value cannot be converted to number"))
+                        .unwrap_or(default_peer_diversity.max_per_wide_subnet),
+                    reserved_trusted_percent: args.value_of("peer-reserved-trusted-percent")
+                        .map(|value| value.parse::<usize>().expect("Provided value cannot be converted to number"))
+                        .unwrap_or(default_peer_diversity.reserved_trusted_percent),
+                    reserved_incoming_percent: args.value_of("peer-reserved-incoming-percent")
+                        .map(|value| value.parse::<usize>().expect("Provided value cannot be converted to number"))
+                        .unwrap_or(default_peer_diversity.reserved_incoming_percent),
+                    limit_local_addresses: false,
+                },
+                rate_limits: RateLimits {
+                    inbound_bytes_per_sec: args.value_of("peer-inbound-bytes-per-sec")
+                        .unwrap_or("10485760")
+                        .parse::<u64>()
+                        .expect("Provided value cannot be converted to number"),
+                    get_block_headers_per_sec: args.value_of("peer-get-block-headers-per-sec")
+                        .unwrap_or("100")
+                        .parse::<u64>()
+                        .expect("Provided value cannot be converted to number"),
+                    get_operations_for_blocks_per_sec: args.value_of("peer-get-operations-for-blocks-per-sec")
+                        .unwrap_or("100")
+                        .parse::<u64>()
+                        .expect("Provided value cannot be converted to number"),
+                    operations_per_sec: args.value_of("peer-operations-per-sec")
+                        .unwrap_or("200")
+                        .parse::<u64>()
+                        .expect("Provided value cannot be converted to number"),
+                    other_messages_per_sec: args.value_of("peer-messages-per-sec")
+                        .unwrap_or("100")
+                        .parse::<u64>()
+                        .expect("Provided value cannot be converted to number"),
+                    max_throttle_duration: args.value_of("peer-max-throttle-secs")
+                        .unwrap_or("30")
+                        .parse::<u64>()
+                        .map(Duration::from_secs)
+                        .expect("Provided value cannot be converted to number"),
+                    outbound_bytes_per_sec: args.value_of("outbound-bytes-per-sec")
+                        .unwrap_or("0")
+                        .parse::<u64>()
+                        .expect("Provided value cannot be converted to number"),
+                },
+                capture: args.value_of("p2p-capture-dir")
+                    .map(|v| v.parse::<PathBuf>().expect("Provided value cannot be converted to path"))
+                    .map(|dir| CaptureConfig {
+                        dir: get_final_path(&data_dir, dir),
+                        max_file_size: args.value_of("p2p-capture-max-file-size")
+                            .unwrap_or("104857600")
+                            .parse::<u64>()
+                            .expect("Provided value cannot be converted to number"),
+                        max_files: args.value_of("p2p-capture-max-files")
+                            .unwrap_or("10")
+                            .parse::<usize>()
+                            .expect("Provided value cannot be converted to number"),
+                    }),
+                keepalive: {
+                    let keepalive = KeepaliveConfig {
+                        // chain id is known only after storage is initialized
+                        ping_chain_id: None,
+                        ping_interval: args.value_of("peer-ping-interval-secs")
+                            .unwrap_or(ping_interval_secs)
+                            .parse::<u64>()
+                            .map(Duration::from_secs)
+                            .expect("Provided value cannot be converted to number"),
+                        dead_timeout: args.value_of("peer-dead-timeout-secs")
+                            .unwrap_or(dead_timeout_secs)
+                            .parse::<u64>()
+                            .map(Duration::from_secs)
+                            .expect("Provided value cannot be converted to number"),
+                    };
+                    if let Err(e) = validate_keepalive(&keepalive) {
+                        panic!("Invalid keepalive configuration: {}", e);
+                    }
+                    keepalive
+                },
                 private_node: args.value_of("private-node")
                     .unwrap_or("false")
                     .parse::<bool>()
                     .expect("Provided value cannot be converted to bool"),
+                expected_pow: args.value_of("identity-expected-pow")
+                    .unwrap_or("26.0")
+                    .parse::<f64>()
+                    .expect("Provided value cannot be converted to number"),
                 disable_mempool: args.value_of("disable-mempool")
                     .unwrap_or("false")
                     .parse::<bool>()
                     .expect("Provided value cannot be converted to bool"),
             },
+            mempool: MempoolLimits {
+                max_operations: args.value_of("mempool-max-operations")
+                    .unwrap_or("10000")
+                    .parse::<usize>()
+                    .expect("Provided value cannot be converted to number"),
+                max_bytes: args.value_of("mempool-max-bytes")
+                    .unwrap_or("52428800")
+                    .parse::<usize>()
+                    .expect("Provided value cannot be converted to number"),
+                max_operations_per_peer: args.value_of("mempool-max-operations-per-peer")
+                    .unwrap_or("1000")
+                    .parse::<usize>()
+                    .expect("Provided value cannot be converted to number"),
+                max_bytes_per_peer: args.value_of("mempool-max-bytes-per-peer")
+                    .unwrap_or("5242880")
+                    .parse::<usize>()
+                    .expect("Provided value cannot be converted to number"),
+            },
             rpc: crate::configuration::Rpc {
                 listener_port: args
                     .value_of("rpc-port")
@@ -629,6 +937,9 @@ impl Environment {
                         log_file_path
                     }
                 },
+                record_traffic_file: args.value_of("record-traffic")
+                    .map(|v| v.parse::<PathBuf>().expect("Provided value cannot be converted to path"))
+                    .map(|path| get_final_path(&data_dir, path)),
             },
             storage: crate::configuration::Storage {
                 tezos_data_dir: data_dir.clone(),
@@ -709,6 +1020,11 @@ impl Environment {
                 .unwrap_or("0")
                 .parse::<usize>()
                 .expect("Provided value cannot be converted to number"),
+            current_head_update_timeout: args.value_of("peer-head-update-timeout-secs")
+                .unwrap_or(head_update_timeout_secs)
+                .parse::<u64>()
+                .map(Duration::from_secs)
+                .expect("Provided value cannot be converted to number"),
             tezos_network,
             enable_testchain: args.value_of("enable-testchain")
                 .unwrap_or("false")
@@ -719,3 +1035,30 @@ impl Environment {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_peers() {
+        assert_eq!(
+            vec!["1.2.3.4:9732".parse::<SocketAddr>().unwrap(), "[::1]:9732".parse().unwrap(), "[2001:db8::1]:19732".parse().unwrap()],
+            parse_peers("1.2.3.4:9732,[::1]:9732,[2001:db8::1]:19732").unwrap()
+        );
+        assert!(parse_peers("::1:9732").is_err());
+        assert!(parse_peers("1.2.3.4").is_err());
+    }
+
+    #[test]
+    fn test_validate_keepalive() {
+        let keepalive = |ping_interval, dead_timeout| KeepaliveConfig {
+            ping_chain_id: None,
+            ping_interval: Duration::from_secs(ping_interval),
+            dead_timeout: Duration::from_secs(dead_timeout),
+        };
+        assert!(validate_keepalive(&keepalive(10, 30)).is_ok());
+        assert!(validate_keepalive(&keepalive(30, 30)).is_err());
+        assert!(validate_keepalive(&keepalive(30, 10)).is_err());
+    }
+}
@@ -2,27 +2,33 @@
 // SPDX-License-Identifier: MIT
 // #![forbid(unsafe_code)]
 
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 use std::sync::atomic::Ordering;
 use std::thread;
 use std::time::Duration;
 
 use riker::actors::*;
 use rocksdb::Cache;
-use slog::{crit, debug, Drain, error, info, Logger};
+use slog::{crit, debug, Drain, error, info, Logger, warn};
 
 use logging::detailed_json;
 use logging::file::FileAppenderBuilder;
 use monitoring::{Monitor, WebsocketHandler};
 use networking::p2p::network_channel::NetworkChannel;
+use networking::p2p::peer::KeepaliveConfig;
+use networking::p2p::stats::NetworkStats;
 use rpc::rpc_actor::RpcServer;
 use shell::chain_feeder::ChainFeeder;
 use shell::chain_manager::ChainManager;
+use shell::chain_registry::ChainRegistry;
+use shell::chain_supervisor::{ChainSupervisor, ChainSupervisorConfiguration};
 use shell::context_listener::ContextListener;
 use shell::mempool_prevalidator::MempoolPrevalidator;
-use shell::peer_manager::PeerManager;
+use shell::peer_manager::{P2p, PeerManager};
+use shell::recorder::{Recorder, RecordWriter};
 use shell::shell_channel::{ShellChannel, ShellChannelTopic, ShuttingDown};
-use storage::{block_storage, BlockMetaStorage, BlockStorage, ChainMetaStorage, check_database_compatibility, context_action_storage, ContextActionStorage, MempoolStorage, OperationsMetaStorage, OperationsStorage, resolve_storage_init_chain_data, StorageInitInfo, SystemStorage};
+use shell::shutdown::{is_current_head_committed, ShutdownCoordinator, ShutdownPhase, WorkerStatus};
+use storage::{block_storage, BlockMetaStorage, BlockStorage, ChainMetaStorage, check_database_compatibility, context_action_storage, ContextActionStorage, MempoolStorage, OperationsMetaStorage, OperationsStorage, PeerGreylistStorage, PeerStorage, ProtocolStorage, repair_half_applied_blocks_of_chains, resolve_storage_init_chain_data, StorageInitInfo, SystemStorage};
 use storage::merkle_storage::MerkleStorage;
 use storage::persistent::{CommitLogSchema, KeyValueSchema, open_cl, open_kv, PersistentStorage};
 use storage::persistent::sequence::Sequences;
@@ -204,6 +210,8 @@ fn block_on_actors(
         SUPPORTED_DISTRIBUTED_DB_VERSION,
         SUPPORTED_P2P_VERSION,
     );
+    // traffic statistics of all peers
+    let network_stats = NetworkStats::default();
 
     // create pool for ffi protocol runner connections (used just for readonly context)
     let tezos_readonly_api_pool = Arc::new(create_tezos_readonly_api_pool(
@@ -246,15 +254,15 @@ fn block_on_actors(
         ),
         log.clone(),
     );
-    let (apply_blocks_protocol_runner_endpoint_run_feature, apply_block_protocol_events, apply_block_protocol_commands) = match apply_blocks_protocol_runner_endpoint.start_in_restarting_mode() {
-        Ok(run_feature) => {
+    let (apply_blocks_protocol_runner_endpoint_run_feature, apply_blocks_protocol_runner_watchdog, apply_block_protocol_events, apply_block_protocol_commands) = match apply_blocks_protocol_runner_endpoint.start_in_restarting_mode() {
+        Ok((run_feature, watchdog)) => {
             info!(log, "Protocol runner started successfully"; "endpoint" => apply_blocks_protocol_runner_endpoint.name);
             let ProtocolRunnerEndpoint {
                 events: apply_block_protocol_events,
                 commands: apply_block_protocol_commands,
                 ..
             } = apply_blocks_protocol_runner_endpoint;
-            (run_feature, apply_block_protocol_events, apply_block_protocol_commands)
+            (run_feature, watchdog, apply_block_protocol_events, apply_block_protocol_commands)
         }
         Err(e) => shutdown_and_exit!(error!(log, "Failed to spawn protocol runner process"; "name" => apply_blocks_protocol_runner_endpoint.name, "reason" => e), actor_system),
     };
@@ -266,20 +274,32 @@ fn block_on_actors(
     let shell_channel = ShellChannel::actor(&actor_system)
         .expect("Failed to create shell channel");
 
+    // record network and shell traffic before any other actor starts to publish
+    if let Some(record_file) = &env.logging.record_traffic_file {
+        let writer = RecordWriter::create(record_file)
+            .expect("Failed to create traffic record file");
+        let _ = Recorder::actor(&actor_system, network_channel.clone(), shell_channel.clone(), writer)
+            .expect("Failed to create recorder");
+    }
+
     // it's important to start ContextListener before ChainFeeder, because chain_feeder can trigger init_genesis which sends ContextAction, and we need to process this action first
     let _ = ContextListener::actor(&actor_system, &persistent_storage, apply_block_protocol_events.expect("Context listener needs event server"), log.clone(), env.storage.store_context_actions)
         .expect("Failed to create context event listener");
-    let _ = ChainFeeder::actor(&actor_system, shell_channel.clone(), &persistent_storage, &init_storage_data, &tezos_env, apply_block_protocol_commands, log.clone())
+    let block_applier_status = WorkerStatus::new();
+    let _ = ChainFeeder::actor(&actor_system, shell_channel.clone(), &persistent_storage, &init_storage_data, &tezos_env, apply_block_protocol_commands, block_applier_status.clone(), log.clone())
         .expect("Failed to create chain feeder");
+    let chain_registry = Arc::new(RwLock::new(ChainRegistry::new(init_storage_data.chain_id.clone())));
     let _ = ChainManager::actor(
         &actor_system,
         network_channel.clone(),
         shell_channel.clone(),
         &persistent_storage,
         tezos_readonly_prevalidation_api_pool.clone(),
+        chain_registry.clone(),
         &init_storage_data.chain_id,
         is_sandbox,
         &env.p2p.peer_threshold,
+        env.current_head_update_timeout,
     ).expect("Failed to create chain manager");
 
     let _ = MempoolPrevalidator::actor(
@@ -288,23 +308,49 @@ fn block_on_actors(
         &persistent_storage,
         &init_storage_data,
         tezos_readonly_api_pool.clone(),
+        env.mempool.clone(),
         log.clone(),
     ).expect("Failed to create chain feeder");
+    let _ = ChainSupervisor::actor(
+        &actor_system,
+        network_channel.clone(),
+        shell_channel.clone(),
+        &persistent_storage,
+        chain_registry.clone(),
+        ChainSupervisorConfiguration {
+            tezos_readonly_api: tezos_readonly_api_pool.clone(),
+            tezos_readonly_prevalidation_api: tezos_readonly_prevalidation_api_pool.clone(),
+            mempool_limits: env.mempool.clone(),
+            is_sandbox,
+            peers_threshold: env.p2p.peer_threshold,
+            current_head_update_timeout: env.current_head_update_timeout,
+        },
+    ).expect("Failed to create chain supervisor");
 
     // and than open p2p and others
     let _ = PeerManager::actor(
         &actor_system,
         network_channel.clone(),
         shell_channel.clone(),
+        &persistent_storage,
         tokio_runtime.handle().clone(),
         identity,
         network_version.clone(),
-        env.p2p.clone(),
+        P2p {
+            // idle peers are pinged with GetCurrentHead of our chain
+            keepalive: KeepaliveConfig {
+                ping_chain_id: Some(init_storage_data.chain_id.clone()),
+                ..env.p2p.keepalive.clone()
+            },
+            ..env.p2p.clone()
+        },
+        network_stats.clone(),
     ).expect("Failed to create peer manager");
     let websocket_handler = WebsocketHandler::actor(&actor_system, env.rpc.websocket_address, log.clone())
         .expect("Failed to start websocket actor");
     let _ = Monitor::actor(&actor_system, network_channel.clone(), websocket_handler, shell_channel.clone(), &persistent_storage, &init_storage_data)
         .expect("Failed to create monitor actor");
+    let rpc_server_status = WorkerStatus::new();
     let _ = RpcServer::actor(
         &actor_system,
         shell_channel.clone(),
@@ -316,49 +362,105 @@ fn block_on_actors(
         tezos_without_context_api_pool.clone(),
         tezos_env.clone(),
         network_version,
+        network_stats,
         &init_storage_data,
+        chain_registry.clone(),
         is_sandbox,
+        rpc_server_status.clone(),
     ).expect("Failed to create RPC server");
 
-    tokio_runtime.block_on(async move {
+    tokio_runtime.block_on(async {
         use tokio::signal;
 
         signal::ctrl_c().await.expect("Failed to listen for ctrl-c event");
-        info!(log, "ctrl-c received!");
+    });
+    info!(log, "ctrl-c received!");
 
-        // disable/stop protocol runner for applying blocks feature
-        apply_blocks_protocol_runner_endpoint_run_feature.store(false, Ordering::Release);
+    let coordinator = ShutdownCoordinator::new(log.clone());
 
-        info!(log, "Sending shutdown notification to actors");
-        shell_channel.tell(
+    // stop accepting new peers and RPC requests, block applier stops to apply queued blocks
+    coordinator.run_phase(
+        ShutdownPhase::StopNetworkAndRpc,
+        || shell_channel.tell(
             Publish {
                 msg: ShuttingDown.into(),
                 topic: ShellChannelTopic::ShellCommands.into(),
             }, None,
-        );
+        ),
+        || !rpc_server_status.is_running(),
+    );
+
+    // block being applied is finished, the rest of the queue is dropped
+    coordinator.run_phase(
+        ShutdownPhase::DrainApplyQueue,
+        || (),
+        || !block_applier_status.is_running(),
+    );
 
-        // give actors some time to shut down
-        thread::sleep(Duration::from_secs(1));
+    // context listener has to store the commit of the last applied block of every active chain
+    coordinator.run_phase(
+        ShutdownPhase::WaitForContextCommit,
+        || (),
+        || chain_registry.read().unwrap().chains().iter()
+            .all(|chain| is_current_head_committed(&persistent_storage, &chain.chain_id).unwrap_or(true)),
+    );
 
+    let storage_status = WorkerStatus::new();
+    coordinator.run_phase(
+        ShutdownPhase::FlushStorage,
+        || {
+            let storage_status = storage_status.clone();
+            let persistent_storage = persistent_storage.clone();
+            let log = log.clone();
+            thread::spawn(move || {
+                if let Err(e) = persistent_storage.flush() {
+                    warn!(log, "Failed to flush databases"; "reason" => format!("{}", e));
+                }
+                storage_status.set_finished();
+            });
+        },
+        || !storage_status.is_running(),
+    );
 
-        info!(log, "Shutting down actors");
-        let _ = actor_system.shutdown().await;
-        info!(log, "Shutdown actors complete");
+    // disable/stop protocol runner for applying blocks feature, watchdog finishes after the runner process was terminated
+    let protocol_runners_status = WorkerStatus::new();
+    coordinator.run_phase(
+        ShutdownPhase::StopProtocolRunners,
+        || {
+            apply_blocks_protocol_runner_endpoint_run_feature.store(false, Ordering::Release);
+            let protocol_runners_status = protocol_runners_status.clone();
+            thread::spawn(move || {
+                let _ = apply_blocks_protocol_runner_watchdog.join();
+                protocol_runners_status.set_finished();
+            });
+        },
+        || !protocol_runners_status.is_running(),
+    );
 
-        thread::sleep(Duration::from_secs(1));
+    let actors_status = WorkerStatus::new();
+    coordinator.run_phase(
+        ShutdownPhase::StopActors,
+        || {
+            let actors_status = actors_status.clone();
+            thread::spawn(move || {
+                let _ = futures::executor::block_on(actor_system.shutdown());
+                actors_status.set_finished();
+            });
+        },
+        || !actors_status.is_running(),
+    );
 
-        info!(log, "Shutting down protocol runner pools");
-        drop(tezos_readonly_api_pool);
-        drop(tezos_readonly_prevalidation_api_pool);
-        drop(tezos_without_context_api_pool);
-        debug!(log, "Shutdown tezos_readonly_api complete");
+    info!(log, "Shutting down protocol runner pools");
+    drop(tezos_readonly_api_pool);
+    drop(tezos_readonly_prevalidation_api_pool);
+    drop(tezos_without_context_api_pool);
+    debug!(log, "Shutdown tezos_readonly_api complete");
 
-        info!(log, "Flushing databases");
-        drop(persistent_storage);
-        info!(log, "Databases flushed");
+    info!(log, "Closing databases");
+    drop(persistent_storage);
+    info!(log, "Databases closed");
 
-        info!(log, "Shutdown complete");
-    });
+    info!(log, "Shutdown complete"; "elapsed" => format!("{:?}", coordinator.elapsed()));
 }
 
 fn main() {
@@ -412,6 +514,9 @@ fn main() {
         Sequences::descriptor(&cache),
         MempoolStorage::descriptor(&cache),
         ChainMetaStorage::descriptor(&cache),
+        PeerGreylistStorage::descriptor(&cache),
+        PeerStorage::descriptor(&cache),
+        ProtocolStorage::descriptor(&cache),
     ];
 
     let rocks_db = match open_kv(&env.storage.db_path, schemas, &env.storage.db_cfg) {
@@ -443,7 +548,18 @@ fn main() {
             &env.storage.tezos_data_dir,
             &env.storage.patch_context,
             &log) {
-            Ok(init_data) => block_on_actors(env, tezos_env, init_data, tezos_identity, actor_system, persistent_storage, log),
+            Ok(init_data) => {
+                // context of the last applied block could be lost by unclean shutdown (of the main chain or of the test chain)
+                if let Err(e) = repair_half_applied_blocks_of_chains(
+                    &BlockStorage::new(&persistent_storage),
+                    &BlockMetaStorage::new(&persistent_storage),
+                    &ChainMetaStorage::new(&persistent_storage),
+                    &init_data.chain_id,
+                    &log) {
+                    shutdown_and_exit!(error!(log, "Failed to repair half applied blocks"; "reason" => e), actor_system)
+                }
+                block_on_actors(env, tezos_env, init_data, tezos_identity, actor_system, persistent_storage, log)
+            }
             Err(e) => shutdown_and_exit!(error!(log, "Failed to resolve init storage chain data."; "reason" => e), actor_system),
         }
     }
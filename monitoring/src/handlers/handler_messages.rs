@@ -2,10 +2,14 @@
 // SPDX-License-Identifier: MIT
 
 use std::iter::FromIterator;
+use std::time::Duration;
 
 use serde::Serialize;
 use slog_derive::SerdeValue;
 
+use networking::p2p::rate_limit::RateSnapshot;
+use networking::p2p::stats::TrafficStats;
+
 use crate::monitors::PeerMonitor;
 use crate::monitors::ChainMonitor;
 
@@ -79,16 +83,28 @@ pub struct PeerMetrics {
     transferred_bytes: usize,
     average_transfer_speed: f32,
     current_transfer_speed: f32,
+    inbound_bytes_per_sec: u64,
+    inbound_messages_per_sec: u64,
+    throttled: bool,
+    /// Round-trip time of the last keepalive ping
+    rtt_ms: Option<u64>,
+    /// Messages and bytes per message type and request latencies
+    traffic: TrafficStats,
 }
 
 impl PeerMetrics {
-    pub fn new(public_key: Option<String>, ip_address: String, transferred_bytes: usize, average_transfer_speed: f32, current_transfer_speed: f32) -> Self {
+    pub fn new(public_key: Option<String>, ip_address: String, transferred_bytes: usize, average_transfer_speed: f32, current_transfer_speed: f32, rate: RateSnapshot, rtt: Option<Duration>, traffic: TrafficStats) -> Self {
         Self {
             public_key,
             ip_address,
             transferred_bytes,
             average_transfer_speed,
             current_transfer_speed,
+            inbound_bytes_per_sec: rate.inbound_bytes_per_sec,
+            inbound_messages_per_sec: rate.inbound_messages_per_sec,
+            throttled: rate.throttled,
+            rtt_ms: rtt.map(|rtt| rtt.as_millis() as u64),
+            traffic,
         }
     }
 }
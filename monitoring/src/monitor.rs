@@ -223,6 +223,8 @@ impl Receive<NetworkChannelMsg> for Monitor {
                 let identifier = msg.peer.uri();
                 let mut monitor = PeerMonitor::new(identifier.clone());
                 monitor.addr = Some(msg.address);
+                monitor.rate = msg.rate.clone();
+                monitor.stats = msg.stats.clone();
                 if let Some(monitor) = self.peer_monitors.insert(msg.peer.uri().clone(), monitor) {
                     warn!(ctx.system.log(), "Duplicate monitor found for peer"; "peer" => monitor.identifier.to_string());
                 }
@@ -240,6 +242,7 @@ impl Receive<NetworkChannelMsg> for Monitor {
                 }
             }
             NetworkChannelMsg::PeerMessageReceived(msg) => self.process_peer_message(msg, &ctx.system.log()),
+            NetworkChannelMsg::PeerMisbehaved(_) => (),
         }
     }
 }
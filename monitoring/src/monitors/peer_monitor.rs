@@ -5,6 +5,9 @@ use std::{net::SocketAddr, time::Instant};
 
 use riker::actor::ActorUri;
 
+use networking::p2p::rate_limit::PeerRate;
+use networking::p2p::stats::PeerStats;
+
 use crate::handlers::handler_messages::PeerMetrics;
 
 /// Peer specific details about transfer *FROM* peer.
@@ -13,6 +16,10 @@ pub(crate) struct PeerMonitor {
     total_transferred: usize,
     pub addr: Option<SocketAddr>,
     pub public_key: Option<String>,
+    /// Current rate measured by the peer rate limiter
+    pub rate: PeerRate,
+    /// Messages and bytes per message type and request latencies of the peer
+    pub stats: PeerStats,
     current_transferred: usize,
     last_update: Instant,
     first_update: Instant,
@@ -26,6 +33,8 @@ impl PeerMonitor {
             total_transferred: 0,
             addr: None,
             public_key: None,
+            rate: PeerRate::default(),
+            stats: PeerStats::default(),
             current_transferred: 0,
             last_update: now,
             first_update: now,
@@ -52,6 +61,9 @@ impl PeerMonitor {
             self.total_transferred,
             self.avg_speed(),
             self.current_speed(),
+            self.rate.snapshot(),
+            self.stats.rtt(),
+            self.stats.snapshot(),
         );
 
         self.current_transferred = 0;
@@ -0,0 +1,198 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Throughput of the encrypted message stream over a loopback TCP connection.
+//!
+//! Every benchmark compares the `in_place` stream with the `previous` implementation, which encoded the message to a new
+//! vector and encrypted and wrote every chunk separately to a newly allocated chunk.
+
+use std::net::SocketAddr;
+
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main, Throughput};
+use hex::FromHex;
+use slog::{Discard, Logger, o};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::{Builder, Runtime};
+
+use crypto::crypto_box::{decrypt, encrypt, PrecomputedKey};
+use crypto::nonce::Nonce;
+use networking::p2p::stream::{CONTENT_LENGTH_MAX, EncryptedMessageReader, EncryptedMessageWriter, MessageReader, MessageStream, MessageWriter};
+use tezos_encoding::binary_reader::BinaryReaderError;
+use tezos_messages::p2p::binary_message::{BinaryChunk, BinaryMessage};
+use tezos_messages::p2p::encoding::prelude::*;
+
+/// Size of the data of one operation in the `OperationsForBlocks` response
+const OPERATION_DATA_SIZE: usize = 1024;
+/// Count of small messages, which are queued and written together
+const PIPELINED_MESSAGES: usize = 100;
+
+const PRECOMPUTED_KEY: &str = "5228751a6f5a6494e38e1042f578e3a64ae3462b7899356f49e50be846c9609c";
+const NONCE: &str = "8dde158c55cff52f4be9352787d333e616a67853640d72c5";
+
+/// Writer and reader of the same connection
+fn connect(runtime: &mut Runtime) -> (MessageWriter, MessageReader) {
+    runtime.block_on(async {
+        let mut listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (outgoing, incoming) = futures::join!(TcpStream::connect(address), listener.accept());
+        let (_, tx) = MessageStream::from(outgoing.unwrap()).split();
+        let (rx, _) = MessageStream::from(incoming.unwrap().0).split();
+        (tx, rx)
+    })
+}
+
+/// Writer and reader of the same encrypted connection
+fn connect_encrypted(runtime: &mut Runtime) -> (EncryptedMessageWriter, EncryptedMessageReader) {
+    let (tx, rx) = connect(runtime);
+    let precomputed_key = PrecomputedKey::from_hex(PRECOMPUTED_KEY).unwrap();
+    let nonce = Nonce::new(&hex::decode(NONCE).unwrap());
+    let log = Logger::root(Discard, o!());
+    (
+        EncryptedMessageWriter::new(tx, precomputed_key.clone(), nonce.clone(), "writer".to_string(), None, log.clone()),
+        EncryptedMessageReader::new(rx, precomputed_key, nonce, "reader".to_string(), None, log),
+    )
+}
+
+/// Writer and reader of the same encrypted connection, which allocate every message and chunk as the previous implementation did
+fn connect_previous(runtime: &mut Runtime) -> (PreviousWriter, PreviousReader) {
+    let (tx, rx) = connect(runtime);
+    let precomputed_key = PrecomputedKey::from_hex(PRECOMPUTED_KEY).unwrap();
+    let nonce = Nonce::new(&hex::decode(NONCE).unwrap());
+    (
+        PreviousWriter { tx, precomputed_key: precomputed_key.clone(), nonce: nonce.clone() },
+        PreviousReader { rx, precomputed_key, nonce },
+    )
+}
+
+struct PreviousWriter {
+    tx: MessageWriter,
+    precomputed_key: PrecomputedKey,
+    nonce: Nonce,
+}
+
+impl PreviousWriter {
+    async fn write_message(&mut self, message: &impl BinaryMessage) {
+        let message_bytes = message.as_bytes().unwrap();
+        for chunk_content_bytes in message_bytes.chunks(CONTENT_LENGTH_MAX) {
+            let nonce = self.nonce.increment();
+            let encrypted = encrypt(chunk_content_bytes, &std::mem::replace(&mut self.nonce, nonce), &self.precomputed_key).unwrap();
+            let chunk = BinaryChunk::from_content(&encrypted).unwrap();
+            self.tx.write_message(&chunk).await.unwrap();
+        }
+    }
+}
+
+struct PreviousReader {
+    rx: MessageReader,
+    precomputed_key: PrecomputedKey,
+    nonce: Nonce,
+}
+
+impl PreviousReader {
+    async fn read_message<M: BinaryMessage>(&mut self) -> M {
+        let mut input_remaining: usize = 0;
+        let mut input_data = vec![];
+        loop {
+            let chunk = self.rx.read_message().await.unwrap();
+            let nonce = self.nonce.increment();
+            let mut decrypted = decrypt(chunk.content(), &std::mem::replace(&mut self.nonce, nonce), &self.precomputed_key).unwrap();
+            input_remaining = input_remaining.saturating_sub(decrypted.len());
+            input_data.append(&mut decrypted);
+
+            if input_remaining == 0 {
+                match M::from_bytes(&input_data) {
+                    Ok(message) => break message,
+                    Err(BinaryReaderError::Underflow { bytes }) => input_remaining += bytes,
+                    Err(e) => panic!("Failed to read message: {:?}", e),
+                }
+            }
+        }
+    }
+}
+
+/// `OperationsForBlocks` response with `count` operations
+fn operations_for_blocks(count: usize) -> PeerMessageResponse {
+    let operations = (0..count)
+        .map(|i| {
+            // branch followed by operation data
+            let mut bytes = vec![1u8; 32];
+            bytes.extend(std::iter::repeat((i % 256) as u8).take(OPERATION_DATA_SIZE));
+            Operation::from_bytes(bytes).unwrap()
+        })
+        .collect();
+    PeerMessage::OperationsForBlocks(OperationsForBlocksMessage::new(OperationsForBlock::new(vec![2u8; 32], 4), Path::Op, operations)).into()
+}
+
+fn bench_operations_for_blocks(c: &mut Criterion) {
+    let mut runtime = Builder::new().basic_scheduler().enable_all().build().unwrap();
+    let (mut tx, mut rx) = connect_encrypted(&mut runtime);
+    let (mut previous_tx, mut previous_rx) = connect_previous(&mut runtime);
+
+    let mut group = c.benchmark_group("operations_for_blocks");
+    for count in &[64, 512, 2048] {
+        let message = operations_for_blocks(*count);
+        let message_size = message.as_bytes().unwrap().len();
+        group.throughput(Throughput::Bytes(message_size as u64));
+        group.bench_with_input(BenchmarkId::new("previous", message_size), &message, |b, message| {
+            b.iter(|| runtime.block_on(async {
+                futures::join!(previous_tx.write_message(message), previous_rx.read_message::<PeerMessageResponse>());
+            }))
+        });
+        group.bench_with_input(BenchmarkId::new("in_place", message_size), &message, |b, message| {
+            b.iter(|| runtime.block_on(async {
+                let (written, read) = futures::join!(tx.write_message(message), rx.read_message::<PeerMessageResponse>());
+                written.unwrap();
+                read.unwrap();
+            }))
+        });
+    }
+    group.finish();
+}
+
+fn bench_pipelined_requests(c: &mut Criterion) {
+    let mut runtime = Builder::new().basic_scheduler().enable_all().build().unwrap();
+    let (mut tx, mut rx) = connect_encrypted(&mut runtime);
+    let (mut previous_tx, mut previous_rx) = connect_previous(&mut runtime);
+    let message: PeerMessageResponse = PeerMessage::GetOperationsForBlocks(GetOperationsForBlocksMessage::new(
+        (0..10).map(|i| OperationsForBlock::new(vec![i; 32], 4)).collect()
+    )).into();
+
+    let mut group = c.benchmark_group("get_operations_for_blocks");
+    group.throughput(Throughput::Elements(PIPELINED_MESSAGES as u64));
+    group.bench_function("previous", |b| {
+        b.iter(|| runtime.block_on(async {
+            let write = async {
+                for _ in 0..PIPELINED_MESSAGES {
+                    previous_tx.write_message(&message).await;
+                }
+            };
+            let read = async {
+                for _ in 0..PIPELINED_MESSAGES {
+                    previous_rx.read_message::<PeerMessageResponse>().await;
+                }
+            };
+            futures::join!(write, read);
+        }))
+    });
+    group.bench_function("in_place", |b| {
+        b.iter(|| runtime.block_on(async {
+            let write = async {
+                for _ in 0..PIPELINED_MESSAGES {
+                    tx.encrypt_message(&message).unwrap();
+                }
+                tx.flush().await
+            };
+            let read = async {
+                for _ in 0..PIPELINED_MESSAGES {
+                    rx.read_message::<PeerMessageResponse>().await.unwrap();
+                }
+            };
+            let (written, _) = futures::join!(write, read);
+            written.unwrap();
+        }))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_operations_for_blocks, bench_pipelined_requests);
+criterion_main!(benches);
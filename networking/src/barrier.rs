@@ -0,0 +1,25 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Synchronization with the actors.
+//!
+//! Actor answers [Synchronize] with [Synchronized] to the sender, once it processed all messages it received before.
+//! Tests and replay tools use it to wait for the actors to process published events, instead of sleeping for a fixed time.
+//! Actors answer [Synchronize] only with the feature `barrier`, which is enabled just for the tests.
+
+use riker::actors::*;
+
+/// Message asks actor to answer [Synchronized] to the sender.
+#[derive(Clone, Debug)]
+pub struct Synchronize;
+
+/// Answer to the [Synchronize], all messages received by the actor before [Synchronize] were processed.
+#[derive(Clone, Debug)]
+pub struct Synchronized;
+
+#[inline]
+pub fn answer_synchronized(sender: Sender) {
+    if let Some(sender) = sender {
+        let _ = sender.try_tell(Synchronized, None);
+    }
+}
@@ -0,0 +1,123 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Source of time for timeouts and timers of the actors.
+//!
+//! Actors use [SystemClock] in the node, tests can use [VirtualClock] to move time forward
+//! without waiting, e.g. to check disconnection of stalled peers.
+//! Actors schedule their timers with [schedule] and [schedule_once], with [VirtualClock] no timer is run,
+//! the test sends the timer messages to the actors itself, so it fully controls, when they are processed.
+
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use riker::actors::*;
+
+pub type ClockRef = Arc<dyn Clock>;
+
+pub trait Clock: Send + Sync {
+    /// Current time
+    fn now(&self) -> Instant;
+
+    /// Time elapsed since `earlier`, zero if `earlier` is in the future
+    fn elapsed(&self, earlier: Instant) -> Duration {
+        self.now().saturating_duration_since(earlier)
+    }
+
+    /// Returns false, if actor timers are not run with this clock
+    fn runs_timers(&self) -> bool {
+        true
+    }
+}
+
+/// Real time
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    #[inline]
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Clock which moves only when [VirtualClock::advance] is called
+#[derive(Debug)]
+pub struct VirtualClock {
+    start: Instant,
+    elapsed: RwLock<Duration>,
+}
+
+impl VirtualClock {
+    pub fn new() -> Self {
+        VirtualClock { start: Instant::now(), elapsed: RwLock::new(Duration::from_secs(0)) }
+    }
+
+    /// Move time forward
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.write().unwrap() += duration;
+    }
+}
+
+impl Default for VirtualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.read().unwrap()
+    }
+
+    fn runs_timers(&self) -> bool {
+        false
+    }
+}
+
+pub fn system_clock() -> ClockRef {
+    Arc::new(SystemClock)
+}
+
+/// Send `msg` to the actor itself after `initial_delay` and then every `interval`, if the `clock` runs timers
+pub fn schedule<Msg, T>(clock: &ClockRef, ctx: &Context<Msg>, initial_delay: Duration, interval: Duration, msg: T) -> Option<ScheduleId>
+    where
+        Msg: Message,
+        T: Into<Msg>
+{
+    if clock.runs_timers() {
+        Some(ctx.schedule::<Msg, _>(initial_delay, interval, ctx.myself(), None, msg.into()))
+    } else {
+        None
+    }
+}
+
+/// Send `msg` to the actor itself after `delay`, if the `clock` runs timers
+pub fn schedule_once<Msg, T>(clock: &ClockRef, ctx: &Context<Msg>, delay: Duration, msg: T) -> Option<ScheduleId>
+    where
+        Msg: Message,
+        T: Into<Msg>
+{
+    if clock.runs_timers() {
+        Some(ctx.schedule_once::<Msg, _>(delay, ctx.myself(), None, msg.into()))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_virtual_clock() {
+        let clock = VirtualClock::new();
+        let start = clock.now();
+        assert_eq!(start, clock.now());
+        assert_eq!(Duration::from_secs(0), clock.elapsed(start));
+
+        clock.advance(Duration::from_secs(120));
+        assert_eq!(Duration::from_secs(120), clock.elapsed(start));
+        assert_eq!(Duration::from_secs(0), clock.elapsed(clock.now() + Duration::from_secs(1)));
+    }
+}
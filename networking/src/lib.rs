@@ -4,4 +4,7 @@
 
 //! This crate handles low level p2p communication.
 
+#[cfg(feature = "barrier")]
+pub mod barrier;
+pub mod clock;
 pub mod p2p;
\ No newline at end of file
@@ -0,0 +1,539 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Capture of the decrypted p2p traffic.
+//!
+//! Encrypted stream between peers cannot be inspected by tools like Wireshark, so when capture is enabled,
+//! every chunk is written as a [CapturedChunk] before it is encrypted and after it is decrypted.
+//! Each record carries connection id, peer id, direction, timestamp and the whole chunk (including its length prefix),
+//! so chunk boundaries are preserved.
+//!
+//! Records are prefixed with their length (u32, big endian) and encoded with bincode. Capture files are rotated,
+//! when they reach the configured size, and only the newest files are kept. Captured chunks can be joined back
+//! to the p2p messages by [CaptureDecoder].
+//!
+//! Connections only queue captured chunks, records are encoded and written by a dedicated writer thread,
+//! so capture never blocks the network tasks. When the writer cannot keep up, chunks are dropped.
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use failure::Fail;
+use serde::{Deserialize, Serialize};
+use slog::{Logger, warn};
+
+use tezos_encoding::binary_reader::BinaryReaderError;
+use tezos_messages::p2p::binary_message::{BinaryMessage, CONTENT_LENGTH_FIELD_BYTES};
+use tezos_messages::p2p::encoding::ack::AckMessage;
+use tezos_messages::p2p::encoding::metadata::MetadataMessage;
+use tezos_messages::p2p::encoding::peer::PeerMessageResponse;
+
+use crate::p2p::peer::PeerId;
+
+/// Extension of the capture files
+const CAPTURE_FILE_EXTENSION: &str = "cap";
+/// Count of captured chunks waiting for the writer thread, chunks over the limit are dropped
+const CAPTURE_QUEUE_CAPACITY: usize = 4096;
+/// Write failures and dropped chunks are logged at most once per this interval
+const CAPTURE_WARNING_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Possible errors for capturing and reading of captured traffic
+#[derive(Debug, Fail)]
+pub enum CaptureError {
+    #[fail(display = "Capture file I/O error! Reason: {:?}", error)]
+    IoError {
+        error: io::Error
+    },
+    #[fail(display = "Captured chunk serialization error! Reason: {:?}", error)]
+    SerializationError {
+        error: bincode::Error
+    },
+}
+
+impl From<io::Error> for CaptureError {
+    fn from(error: io::Error) -> Self {
+        CaptureError::IoError { error }
+    }
+}
+
+impl From<bincode::Error> for CaptureError {
+    fn from(error: bincode::Error) -> Self {
+        CaptureError::SerializationError { error }
+    }
+}
+
+impl slog::Value for CaptureError {
+    fn serialize(&self, _record: &slog::Record, key: slog::Key, serializer: &mut dyn slog::Serializer) -> slog::Result {
+        serializer.emit_arguments(key, &format_args!("{}", self))
+    }
+}
+
+/// Capture configuration
+#[derive(Clone, Debug)]
+pub struct CaptureConfig {
+    /// Directory, where capture files are written
+    pub dir: PathBuf,
+    /// Capture file is rotated, when it reaches this size in bytes
+    pub max_file_size: u64,
+    /// Count of the newest capture files which are kept, older files are removed
+    pub max_files: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Direction {
+    /// Chunk received from the remote peer
+    Incoming,
+    /// Chunk sent to the remote peer
+    Outgoing,
+}
+
+/// One decrypted chunk of the p2p connection
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CapturedChunk {
+    /// Id of the connection, unique during the node run
+    pub connection_id: u64,
+    pub peer_id: PeerId,
+    pub direction: Direction,
+    pub timestamp: SystemTime,
+    /// Decrypted chunk including its length prefix
+    pub chunk: Vec<u8>,
+}
+
+/// Rotating capture files
+struct CaptureFiles {
+    config: CaptureConfig,
+    /// Used in names of capture files, so files of the different node runs are not overwritten
+    started: u64,
+    /// Index of the next capture file
+    next_index: u64,
+    /// Currently written file and its size
+    current: Option<(BufWriter<File>, u64)>,
+    /// Capture files written by this node run, oldest first
+    written: VecDeque<PathBuf>,
+}
+
+impl CaptureFiles {
+    fn write_chunk(&mut self, chunk: &CapturedChunk) -> Result<(), CaptureError> {
+        let bytes = bincode::serialize(chunk)?;
+        let mut record = Vec::with_capacity(bytes.len() + 4);
+        record.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+        record.extend_from_slice(&bytes);
+        self.write(&record)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), CaptureError> {
+        let rotate = match &self.current {
+            Some((_, size)) => *size > 0 && *size + bytes.len() as u64 > self.config.max_file_size,
+            None => true,
+        };
+        if rotate {
+            self.rotate()?;
+        }
+
+        if let Some((file, size)) = self.current.as_mut() {
+            file.write_all(bytes)?;
+            *size += bytes.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), CaptureError> {
+        if let Some((file, _)) = self.current.as_mut() {
+            file.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Start a new capture file and remove files over the limit
+    fn rotate(&mut self) -> Result<(), CaptureError> {
+        self.flush()?;
+        fs::create_dir_all(&self.config.dir)?;
+        let path = self.config.dir.join(format!("p2p-capture-{}-{:06}.{}", self.started, self.next_index, CAPTURE_FILE_EXTENSION));
+        self.next_index += 1;
+        self.current = Some((BufWriter::new(OpenOptions::new().create(true).write(true).truncate(true).open(&path)?), 0));
+        self.written.push_back(path);
+
+        while self.written.len() > self.config.max_files.max(1) {
+            if let Some(oldest) = self.written.pop_front() {
+                fs::remove_file(oldest)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+enum CaptureEvent {
+    Chunk(CapturedChunk),
+    /// Write buffered records to the capture file and notify the sender
+    Flush(mpsc::Sender<()>),
+}
+
+/// Writer of the captured traffic shared by all connections
+#[derive(Clone)]
+pub struct TrafficCapture {
+    events: SyncSender<CaptureEvent>,
+    /// Count of chunks dropped because the writer thread could not keep up
+    dropped: Arc<AtomicU64>,
+    connection_id_generator: Arc<AtomicU64>,
+}
+
+impl TrafficCapture {
+    /// Start the writer thread, capture files are created when the first chunk is captured.
+    ///
+    /// Writer thread finishes, when all captures are dropped.
+    pub fn new(config: CaptureConfig, log: Logger) -> Result<Self, CaptureError> {
+        let started = SystemTime::now().duration_since(UNIX_EPOCH).map(|started| started.as_secs()).unwrap_or(0);
+        let files = CaptureFiles { config, started, next_index: 0, current: None, written: VecDeque::new() };
+        let (events_tx, events_rx) = mpsc::sync_channel(CAPTURE_QUEUE_CAPACITY);
+        let dropped = Arc::new(AtomicU64::new(0));
+        {
+            let dropped = dropped.clone();
+            thread::Builder::new()
+                .name("p2p-capture".to_string())
+                .spawn(move || write_captured_chunks(files, events_rx, dropped, log))?;
+        }
+        Ok(TrafficCapture {
+            events: events_tx,
+            dropped,
+            connection_id_generator: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    /// Start to capture a new connection with the peer
+    pub fn connection(&self, peer_id: &str) -> ConnectionCapture {
+        ConnectionCapture {
+            capture: self.clone(),
+            connection_id: self.connection_id_generator.fetch_add(1, Ordering::Relaxed),
+            peer_id: peer_id.to_string(),
+        }
+    }
+
+    /// Wait until all chunks captured so far are written to the capture files
+    pub fn flush(&self) {
+        let (done_tx, done_rx) = mpsc::channel();
+        if self.events.send(CaptureEvent::Flush(done_tx)).is_ok() {
+            let _ = done_rx.recv();
+        }
+    }
+
+    fn write(&self, chunk: CapturedChunk) {
+        if self.events.try_send(CaptureEvent::Chunk(chunk)).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Writer thread loop, buffered records are flushed whenever there is nothing else to write
+fn write_captured_chunks(mut files: CaptureFiles, events: Receiver<CaptureEvent>, dropped: Arc<AtomicU64>, log: Logger) {
+    let mut warnings = CaptureWarnings { log, failed: 0, last_error: None, last_warning: None };
+    loop {
+        let event = match events.try_recv() {
+            Ok(event) => event,
+            Err(TryRecvError::Empty) => {
+                if let Err(e) = files.flush() {
+                    warnings.failed(e);
+                }
+                match events.recv() {
+                    Ok(event) => event,
+                    Err(_) => break,
+                }
+            }
+            Err(TryRecvError::Disconnected) => break,
+        };
+
+        match event {
+            CaptureEvent::Chunk(chunk) => if let Err(e) = files.write_chunk(&chunk) {
+                warnings.failed(e);
+            },
+            CaptureEvent::Flush(done) => {
+                if let Err(e) = files.flush() {
+                    warnings.failed(e);
+                }
+                let _ = done.send(());
+            }
+        }
+        warnings.report(dropped.load(Ordering::Relaxed));
+    }
+
+    if let Err(e) = files.flush() {
+        warnings.failed(e);
+    }
+    warnings.report(dropped.load(Ordering::Relaxed));
+}
+
+/// Capture failures are collected and logged at most once per [CAPTURE_WARNING_INTERVAL]
+struct CaptureWarnings {
+    log: Logger,
+    /// Count of chunks, which failed to be written since the last warning
+    failed: u64,
+    last_error: Option<CaptureError>,
+    last_warning: Option<(Instant, u64)>,
+}
+
+impl CaptureWarnings {
+    fn failed(&mut self, error: CaptureError) {
+        self.failed += 1;
+        self.last_error = Some(error);
+    }
+
+    /// `dropped` is total count of the dropped chunks
+    fn report(&mut self, dropped: u64) {
+        let dropped_reported = self.last_warning.map(|(_, dropped_reported)| dropped_reported).unwrap_or(0);
+        if self.failed == 0 && dropped == dropped_reported {
+            return;
+        }
+        if self.last_warning.map(|(last_warning, _)| last_warning.elapsed() < CAPTURE_WARNING_INTERVAL).unwrap_or(false) {
+            return;
+        }
+
+        let reason = self.last_error.take().map(|e| e.to_string()).unwrap_or_else(|| "capture queue is full".to_string());
+        warn!(self.log, "Failed to capture p2p traffic"; "failed_chunks" => self.failed, "dropped_chunks" => dropped - dropped_reported, "reason" => reason);
+        self.failed = 0;
+        self.last_warning = Some((Instant::now(), dropped));
+    }
+}
+
+/// Capture of one connection
+#[derive(Clone)]
+pub struct ConnectionCapture {
+    capture: TrafficCapture,
+    connection_id: u64,
+    peer_id: PeerId,
+}
+
+impl ConnectionCapture {
+    /// Queue decrypted chunk content to be captured, failures are logged by the writer thread
+    pub fn record(&self, direction: Direction, content: &[u8]) {
+        let mut chunk = Vec::with_capacity(content.len() + CONTENT_LENGTH_FIELD_BYTES);
+        chunk.extend_from_slice(&(content.len() as u16).to_be_bytes());
+        chunk.extend_from_slice(content);
+        self.capture.write(CapturedChunk {
+            connection_id: self.connection_id,
+            peer_id: self.peer_id.clone(),
+            direction,
+            timestamp: SystemTime::now(),
+            chunk,
+        })
+    }
+}
+
+/// List capture files in the directory, oldest first
+pub fn capture_files<P: AsRef<Path>>(dir: P) -> Result<Vec<PathBuf>, CaptureError> {
+    let mut files = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().map(|extension| extension == CAPTURE_FILE_EXTENSION).unwrap_or(false))
+        .collect::<Vec<_>>();
+    files.sort();
+    Ok(files)
+}
+
+/// Read all captured chunks from the file.
+///
+/// Last chunk could be incomplete, if node was killed during capture, such chunk is ignored.
+pub fn read_capture<P: AsRef<Path>>(path: P) -> Result<Vec<CapturedChunk>, CaptureError> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut chunks = Vec::new();
+    loop {
+        let mut size = [0u8; 4];
+        match reader.read_exact(&mut size) {
+            Ok(()) => (),
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+
+        let mut bytes = vec![0u8; u32::from_be_bytes(size) as usize];
+        match reader.read_exact(&mut bytes) {
+            Ok(()) => chunks.push(bincode::deserialize(&bytes)?),
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(chunks)
+}
+
+/// Message decoded from the captured chunks.
+///
+/// Every connection starts with the metadata and ack exchange, all following messages are peer messages.
+#[derive(Debug)]
+pub enum CapturedMessage {
+    Metadata(MetadataMessage),
+    Ack(AckMessage),
+    Peer(PeerMessageResponse),
+}
+
+/// Message joined from the captured chunks
+#[derive(Debug)]
+pub struct DecodedMessage {
+    pub connection_id: u64,
+    pub peer_id: PeerId,
+    pub direction: Direction,
+    /// Timestamp of the last chunk of the message
+    pub timestamp: SystemTime,
+    /// Count of chunks the message was sent in
+    pub chunks: usize,
+    pub message: Result<CapturedMessage, BinaryReaderError>,
+}
+
+/// Received chunks of one direction of the connection
+#[derive(Default)]
+struct StreamState {
+    /// Content of the chunks of the incomplete message
+    data: Vec<u8>,
+    /// Count of chunks of the incomplete message
+    chunks: usize,
+    /// Count of messages already decoded from this stream
+    decoded: usize,
+}
+
+/// Joins captured chunks into messages, the same way as they are read from the encrypted stream
+#[derive(Default)]
+pub struct CaptureDecoder {
+    streams: HashMap<(u64, Direction), StreamState>,
+}
+
+impl CaptureDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feed next captured chunk, returns decoded message, if the chunk completed it
+    pub fn decode(&mut self, captured: CapturedChunk) -> Option<DecodedMessage> {
+        let stream = self.streams.entry((captured.connection_id, captured.direction)).or_default();
+        stream.data.extend_from_slice(captured.chunk.get(CONTENT_LENGTH_FIELD_BYTES..).unwrap_or_default());
+        stream.chunks += 1;
+
+        let message = match stream.decoded {
+            0 => MetadataMessage::from_bytes(&stream.data).map(CapturedMessage::Metadata),
+            1 => AckMessage::from_bytes(&stream.data).map(CapturedMessage::Ack),
+            _ => PeerMessageResponse::from_bytes(&stream.data).map(CapturedMessage::Peer),
+        };
+        if let Err(BinaryReaderError::Underflow { .. }) = message {
+            // wait for the next chunk
+            return None;
+        }
+
+        let chunks = stream.chunks;
+        stream.chunks = 0;
+        stream.data.clear();
+        stream.decoded += 1;
+        Some(DecodedMessage {
+            connection_id: captured.connection_id,
+            peer_id: captured.peer_id,
+            direction: captured.direction,
+            timestamp: captured.timestamp,
+            chunks,
+            message,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use slog::Discard;
+
+    use tezos_messages::p2p::encoding::prelude::{GetBlockHeadersMessage, PeerMessage};
+
+    use super::*;
+
+    #[test]
+    fn test_capture_and_decode() -> Result<(), failure::Error> {
+        let dir = env::temp_dir().join("__test_capture_and_decode");
+        if dir.exists() {
+            fs::remove_dir_all(&dir)?;
+        }
+        let capture = TrafficCapture::new(CaptureConfig { dir: dir.clone(), max_file_size: 256, max_files: 100 }, Logger::root(Discard, slog::o!()))?;
+        let connection = capture.connection("idtqxHUjbjbCfaDn4jczoPGsnhacKX");
+
+        connection.record(Direction::Incoming, &MetadataMessage::new(false, false).as_bytes()?);
+        connection.record(Direction::Incoming, &AckMessage::Ack.as_bytes()?);
+        // peer message split to two chunks
+        let message: PeerMessageResponse = PeerMessage::GetBlockHeaders(GetBlockHeadersMessage::new(vec![vec![1; 32]; 10])).into();
+        let message_bytes = message.as_bytes()?;
+        let (first, second) = message_bytes.split_at(100);
+        connection.record(Direction::Incoming, first);
+        connection.record(Direction::Incoming, second);
+        capture.flush();
+
+        let files = capture_files(&dir)?;
+        assert!(files.len() > 1, "capture file should be rotated");
+        let mut decoder = CaptureDecoder::new();
+        let decoded = files.iter()
+            .map(read_capture)
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .flatten()
+            .filter_map(|chunk| decoder.decode(chunk))
+            .collect::<Vec<_>>();
+
+        assert_eq!(3, decoded.len());
+        assert!(matches!(decoded[0].message, Ok(CapturedMessage::Metadata(_))));
+        assert!(matches!(decoded[1].message, Ok(CapturedMessage::Ack(AckMessage::Ack))));
+        assert_eq!(2, decoded[2].chunks);
+        match &decoded[2].message {
+            Ok(CapturedMessage::Peer(response)) => assert!(matches!(&response.messages()[0], PeerMessage::GetBlockHeaders(message) if message.get_block_headers().len() == 10)),
+            other => panic!("Unexpected message: {:?}", other),
+        }
+
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_capture_files_are_rotated() -> Result<(), failure::Error> {
+        let dir = env::temp_dir().join("__test_capture_files_are_rotated");
+        if dir.exists() {
+            fs::remove_dir_all(&dir)?;
+        }
+        let capture = TrafficCapture::new(CaptureConfig { dir: dir.clone(), max_file_size: 10, max_files: 2 }, Logger::root(Discard, slog::o!()))?;
+        let connection = capture.connection("idtqxHUjbjbCfaDn4jczoPGsnhacKX");
+        for _ in 0..5 {
+            connection.record(Direction::Outgoing, &[0; 20]);
+        }
+        capture.flush();
+
+        let files = capture_files(&dir)?;
+        assert_eq!(2, files.len());
+        assert!(files[1].to_string_lossy().ends_with("000004.cap"));
+
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    struct CountingDrain(Arc<AtomicU64>);
+
+    impl slog::Drain for CountingDrain {
+        type Ok = ();
+        type Err = slog::Never;
+
+        fn log(&self, _record: &slog::Record, _values: &slog::OwnedKVList) -> Result<(), slog::Never> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_capture_warnings_are_rate_limited() {
+        let logged = Arc::new(AtomicU64::new(0));
+        let mut warnings = CaptureWarnings { log: Logger::root(CountingDrain(logged.clone()), slog::o!()), failed: 0, last_error: None, last_warning: None };
+
+        // nothing failed, nothing is logged
+        warnings.report(0);
+        assert_eq!(0, logged.load(Ordering::Relaxed));
+
+        for dropped in 1..=100 {
+            warnings.failed(io::Error::from(ErrorKind::Other).into());
+            warnings.report(dropped);
+        }
+        assert_eq!(1, logged.load(Ordering::Relaxed));
+        assert_eq!(99, warnings.failed);
+    }
+}
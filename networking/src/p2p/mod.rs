@@ -3,6 +3,9 @@
 
 //! This module handles low level p2p communication.
 
-mod stream;
+pub mod stream;
 pub mod peer;
 pub mod network_channel;
+pub mod rate_limit;
+pub mod capture;
+pub mod stats;
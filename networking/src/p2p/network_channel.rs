@@ -7,11 +7,16 @@ use std::net::SocketAddr;
 use std::sync::Arc;
 
 use riker::actors::*;
+use serde::{Deserialize, Serialize};
 
+use crypto::hash::BlockHash;
+use tezos_messages::p2p::binary_message::MessageHash;
 use tezos_messages::p2p::encoding::metadata::MetadataMessage;
-use tezos_messages::p2p::encoding::peer::PeerMessageResponse;
+use tezos_messages::p2p::encoding::peer::{PeerMessage, PeerMessageResponse};
 
 use super::peer::PeerRef;
+use super::rate_limit::PeerRate;
+use super::stats::PeerStats;
 
 pub const DEFAULT_TOPIC: &str = "network";
 
@@ -22,6 +27,10 @@ pub const DEFAULT_TOPIC: &str = "network";
 pub struct PeerCreated {
     pub peer: PeerRef,
     pub address: SocketAddr,
+    /// Current inbound rate of the peer
+    pub rate: PeerRate,
+    /// Traffic statistics of the peer
+    pub stats: PeerStats,
 }
 
 /// Peer has been bootstrapped.
@@ -31,19 +40,71 @@ pub enum PeerBootstrapped {
         peer: PeerRef,
         peer_id: String,
         peer_metadata: MetadataMessage,
+        /// Port where remote peer accepts new connections
+        listener_port: u16,
     },
     Failure {
         address: SocketAddr,
         /// List of potential peers to connect to. Is extracted from `Nack`.
         potential_peers_to_connect: Option<Vec<String>>,
+        /// Set if bootstrap failed because of the remote peer misbehaviour.
+        misbehavior: Option<Misbehavior>,
     },
 }
 
+/// Kinds of peer misbehaviour which are taken into account when evaluating peer reputation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Misbehavior {
+    /// Peer failed to complete bootstrap (connection, metadata or ack exchange)
+    BootstrapFailed,
+    /// Peer does not support our network protocol version
+    UnsupportedProtocol,
+    /// Peer did not respond in time
+    Timeout,
+    /// Peer did not provide data which it was asked for
+    Stalled,
+    /// Peer sent invalid or unexpected block header
+    InvalidBlockHeader,
+    /// Peer sent invalid or unexpected operations
+    InvalidOperations,
+    /// Peer sent protocol sources which we did not ask for or which do not match the protocol hash
+    InvalidProtocol,
+    /// Peer exceeded its request rate limits for too long
+    RateLimitExceeded,
+    /// Proof of work stamp of the peer does not meet expected pow
+    InvalidProofOfWork,
+}
+
+/// Peer did something which should affect its reputation.
+#[derive(Clone, Debug)]
+pub struct PeerMisbehaved {
+    pub peer: PeerRef,
+    pub misbehavior: Misbehavior,
+    /// Peer should be disconnected. Peer manager stops the peer once the penalty is recorded,
+    /// peer which detected the misbehavior itself can already be stopped.
+    pub disconnect: bool,
+}
+
 /// We have received message from another peer
 #[derive(Clone, Debug)]
 pub struct PeerMessageReceived {
     pub peer: PeerRef,
     pub message: Arc<PeerMessageResponse>,
+    /// Hashes of the received block headers in the order of `BlockHeader` messages of the response,
+    /// they are computed once when the message is received.
+    pub block_header_hashes: Vec<BlockHash>,
+}
+
+impl PeerMessageReceived {
+    pub fn new(peer: PeerRef, message: Arc<PeerMessageResponse>) -> Self {
+        let block_header_hashes = message.messages().iter()
+            .filter_map(|message| match message {
+                PeerMessage::BlockHeader(message) => message.block_header().message_hash().ok(),
+                _ => None,
+            })
+            .collect();
+        PeerMessageReceived { peer, message, block_header_hashes }
+    }
 }
 
 /// Network channel event message.
@@ -52,6 +113,7 @@ pub enum NetworkChannelMsg {
     PeerCreated(PeerCreated),
     PeerBootstrapped(PeerBootstrapped),
     PeerMessageReceived(PeerMessageReceived),
+    PeerMisbehaved(PeerMisbehaved),
 }
 
 impl From<PeerCreated> for NetworkChannelMsg {
@@ -72,6 +134,12 @@ impl From<PeerMessageReceived> for NetworkChannelMsg {
     }
 }
 
+impl From<PeerMisbehaved> for NetworkChannelMsg {
+    fn from(msg: PeerMisbehaved) -> Self {
+        NetworkChannelMsg::PeerMisbehaved(msg)
+    }
+}
+
 /// Represents various topics
 pub enum NetworkChannelTopic {
     /// Events generated from networking layer
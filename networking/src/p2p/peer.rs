@@ -1,32 +1,53 @@
 // Copyright (c) SimpleStaking and Tezedge Contributors
 // SPDX-License-Identifier: MIT
 
-use std::net::{Ipv4Addr, Shutdown, SocketAddr, SocketAddrV4};
-use std::sync::Arc;
+use std::collections::{HashSet, VecDeque};
+use std::net::{Shutdown, SocketAddr};
+use std::sync::{Arc, Mutex as StdMutex};
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use failure::{Error, Fail};
+use futures::channel::{mpsc, oneshot};
+use futures::future::{self, Either};
 use futures::lock::Mutex;
+use futures::StreamExt;
 use riker::actors::*;
 use slog::{debug, info, Logger, trace, warn};
 use tokio::net::TcpStream;
 use tokio::runtime::Handle;
-use tokio::time::timeout;
+use tokio::task::JoinHandle;
+use tokio::time::{delay_for, timeout};
 
 use crypto::crypto_box::precompute;
-use crypto::hash::HashType;
+use crypto::hash::{ChainId, HashType};
 use crypto::nonce::{self, Nonce, NoncePair};
+use crypto::proof_of_work;
 use tezos_encoding::binary_reader::BinaryReaderError;
 use tezos_messages::p2p::binary_message::{BinaryChunk, BinaryChunkError, BinaryMessage};
 use tezos_messages::p2p::encoding::ack::{NackInfo, NackMotive};
 use tezos_messages::p2p::encoding::prelude::*;
 
-use super::network_channel::{NetworkChannelRef, NetworkChannelTopic, PeerBootstrapped, PeerMessageReceived};
+#[cfg(feature = "barrier")]
+use crate::barrier::{answer_synchronized, Synchronize};
+use crate::clock::{self, ClockRef};
+
+use super::capture::TrafficCapture;
+use super::network_channel::{Misbehavior, NetworkChannelRef, NetworkChannelTopic, PeerBootstrapped, PeerMessageReceived, PeerMisbehaved};
+use super::rate_limit::{OutboundBandwidth, PeerRateLimiter, PeerRateLimiting, Throttle};
+use super::stats::PeerStats;
 use super::stream::{EncryptedMessageReader, EncryptedMessageWriter, MessageStream, StreamError};
 
 const IO_TIMEOUT: Duration = Duration::from_secs(6);
-const READ_TIMEOUT_LONG: Duration = Duration::from_secs(30);
+/// How often peer checks, whether the connection is idle or dead
+const KEEPALIVE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Maximal count of messages waiting to be sent to the peer, when the queue is full, low priority messages are dropped
+/// and other messages wait in the overflow queue until the writer catches up
+const OUTBOUND_QUEUE_CAPACITY: usize = 1024;
+/// Maximal count of messages waiting in the overflow queue, peer which cannot keep up with more messages is disconnected
+const OUTBOUND_OVERFLOW_CAPACITY: usize = 4 * OUTBOUND_QUEUE_CAPACITY;
+/// Queued messages are encrypted and written to the socket together, up to this count of bytes
+const OUTBOUND_BATCH_MAX_BYTES: usize = 256 * 1024;
 
 static ACTOR_ID_GENERATOR: AtomicU64 = AtomicU64::new(0);
 
@@ -46,8 +67,16 @@ pub enum PeerError {
     NackWithMotiveReceived {
         nack_info: NackInfo
     },
+    #[fail(display = "Connection refused, NACK was sent with motive: {:?}", motive)]
+    ConnectionRefused {
+        motive: NackMotive
+    },
     #[fail(display = "Failed to create precomputed key")]
     FailedToPrecomputeKey,
+    #[fail(display = "Proof of work stamp of remote peer does not meet expected pow: {}", expected_pow)]
+    InvalidProofOfWork {
+        expected_pow: f64
+    },
     #[fail(display = "Network error: {}", message)]
     NetworkError {
         error: Error,
@@ -63,6 +92,22 @@ pub enum PeerError {
     },
 }
 
+impl PeerError {
+    /// Resolve misbehaviour of the remote peer which caused this error.
+    ///
+    /// `None` means that remote peer behaved correctly, e.g. it just refused connection and sent us potential peers instead.
+    pub fn misbehavior(&self) -> Option<Misbehavior> {
+        match self {
+            PeerError::NackWithMotiveReceived { .. } => None,
+            PeerError::ConnectionRefused { .. } => None,
+            PeerError::UnsupportedProtocol { .. } => Some(Misbehavior::UnsupportedProtocol),
+            PeerError::InvalidProofOfWork { .. } => Some(Misbehavior::InvalidProofOfWork),
+            PeerError::NetworkError { error, .. } if error.downcast_ref::<tokio::time::Elapsed>().is_some() => Some(Misbehavior::Timeout),
+            _ => Some(Misbehavior::BootstrapFailed),
+        }
+    }
+}
+
 impl From<tezos_encoding::ser::Error> for PeerError {
     fn from(error: tezos_encoding::ser::Error) -> Self {
         PeerError::SerializationError { error }
@@ -116,18 +161,79 @@ pub struct Bootstrap {
     incoming: bool,
     disable_mempool: bool,
     private_node: bool,
+    /// Connection is refused with this motive after metadata are exchanged
+    refuse: Option<NackMotive>,
+    /// Peers sent to the remote peer in `Nack`, so it can connect to them instead of us
+    potential_peers: Vec<String>,
+    /// Already connected peers, connection to the same peer is refused with `AlreadyConnected` motive
+    connected_peers: Option<ConnectedPeers>,
+    /// If set, connection with other peers is refused
+    accepted_peer_ids: Option<HashSet<PeerId>>,
 }
 
 impl Bootstrap {
     pub fn incoming(stream: Arc<Mutex<Option<TcpStream>>>, address: SocketAddr, disable_mempool: bool, private_node: bool) -> Self {
-        Bootstrap { stream, address, incoming: true, disable_mempool, private_node }
+        Bootstrap { stream, address, incoming: true, disable_mempool, private_node, refuse: None, potential_peers: Vec::new(), connected_peers: None, accepted_peer_ids: None }
     }
 
     pub fn outgoing(stream: TcpStream, address: SocketAddr, disable_mempool: bool, private_node: bool) -> Self {
-        Bootstrap { stream: Arc::new(Mutex::new(Some(stream))), address, incoming: false, disable_mempool, private_node }
+        Bootstrap { stream: Arc::new(Mutex::new(Some(stream))), address, incoming: false, disable_mempool, private_node, refuse: None, potential_peers: Vec::new(), connected_peers: None, accepted_peer_ids: None }
+    }
+
+    /// Refuse the connection during handshake, remote peer receives `Nack` with the `motive`
+    pub fn refuse(mut self, motive: NackMotive) -> Self {
+        self.refuse = Some(motive);
+        self
+    }
+
+    /// Accept connection only with the peers with `peer_ids`, other peers receive `Nack` without motive
+    pub fn accept_only(mut self, peer_ids: HashSet<PeerId>) -> Self {
+        self.accepted_peer_ids = Some(peer_ids);
+        self
+    }
+
+    /// Set already connected peers and potential peers, which are sent to the remote peer, if the connection is refused
+    pub fn nack_context(mut self, connected_peers: ConnectedPeers, potential_peers: Vec<String>) -> Self {
+        self.connected_peers = Some(connected_peers);
+        self.potential_peers = potential_peers;
+        self
+    }
+}
+
+/// Peer ids of peers with finished handshake, shared by all peers of the node.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectedPeers(Arc<StdMutex<HashSet<PeerId>>>);
+
+impl ConnectedPeers {
+    /// Register connected peer, returns `None` if the peer is already connected.
+    /// Peer is unregistered when returned value is dropped.
+    pub fn try_connect(&self, peer_id: &str) -> Option<ConnectedPeer> {
+        let mut peers = self.0.lock().unwrap();
+        if peers.insert(peer_id.to_string()) {
+            Some(ConnectedPeer { peers: self.clone(), peer_id: peer_id.to_string() })
+        } else {
+            None
+        }
+    }
+}
+
+/// Registration of a peer in [ConnectedPeers], valid until dropped
+#[derive(Debug)]
+pub struct ConnectedPeer {
+    peers: ConnectedPeers,
+    peer_id: PeerId,
+}
+
+impl Drop for ConnectedPeer {
+    fn drop(&mut self) {
+        self.peers.0.lock().unwrap().remove(&self.peer_id);
     }
 }
 
+/// Commands peer actor to ping the idle remote peer and to close the dead connection, see [KeepaliveConfig].
+#[derive(Clone, Debug)]
+pub struct CheckKeepalive;
+
 /// Commands peer actor to send a p2p message to a remote peer.
 #[derive(Clone, Debug)]
 pub struct SendMessage {
@@ -139,6 +245,25 @@ impl SendMessage {
     pub fn new(msg: PeerMessageResponse) -> Self {
         SendMessage { message: Arc::new(msg) }
     }
+
+    #[inline]
+    pub fn message(&self) -> &PeerMessageResponse {
+        &self.message
+    }
+
+    /// Returns true if message contains `Disconnect`, peer is stopped after such message is sent.
+    fn is_disconnect(&self) -> bool {
+        self.message.messages().iter().any(|message| matches!(message, PeerMessage::Disconnect))
+    }
+
+    /// Returns true if message contains just gossip, which is announced or requested again later,
+    /// so it can be dropped, when the peer cannot keep up with our messages.
+    fn is_low_priority(&self) -> bool {
+        self.message.messages().iter().all(|message| matches!(message,
+            PeerMessage::CurrentHead(_) | PeerMessage::GetCurrentHead(_) | PeerMessage::Operation(_)
+            | PeerMessage::GetOperations(_) | PeerMessage::Advertise(_) | PeerMessage::Bootstrap
+        ))
+    }
 }
 
 #[derive(Clone)]
@@ -146,10 +271,18 @@ struct Network {
     /// Message receiver boolean indicating whether
     /// more messages should be received from network
     rx_run: Arc<AtomicBool>,
-    /// Message sender
-    tx: Arc<Mutex<Option<EncryptedMessageWriter>>>,
+    /// Queue of messages to send, served by the [writer task](begin_process_outgoing)
+    tx: Arc<StdMutex<Option<mpsc::Sender<SendMessage>>>>,
+    /// Messages, which did not fit into the full queue, writer sends them once the queue is drained
+    overflow: Arc<StdMutex<VecDeque<SendMessage>>>,
     /// Socket address of the peer
     socket_address: SocketAddr,
+    /// Traffic statistics of the peer
+    stats: PeerStats,
+    /// Closes the connection, when nothing was received from the peer for too long
+    dead: Arc<StdMutex<Option<oneshot::Sender<()>>>>,
+    /// Network version negotiated with the peer during bootstrap
+    version: Arc<StdMutex<Option<NetworkVersion>>>,
 }
 
 /// Local node info
@@ -162,18 +295,48 @@ pub struct Local {
     secret_key: String,
     /// proof of work
     proof_of_work_stamp: String,
+    /// minimal proof of work, which is required from remote peers
+    expected_pow: f64,
     /// version of network protocol
     version: NetworkVersion,
+    /// capture of decrypted traffic, if enabled
+    capture: Option<TrafficCapture>,
 }
 
 impl Local {
-    pub fn new(listener_port: u16, public_key: String, secret_key: String, proof_of_work_stamp: String, network_version: NetworkVersion) -> Self {
+    pub fn new(listener_port: u16, public_key: String, secret_key: String, proof_of_work_stamp: String, expected_pow: f64, network_version: NetworkVersion, capture: Option<TrafficCapture>) -> Self {
         Local {
             listener_port,
             public_key,
             secret_key,
             proof_of_work_stamp,
+            expected_pow,
             version: network_version,
+            capture,
+        }
+    }
+}
+
+/// Liveness of the connection with the remote peer.
+///
+/// Quiet peers are pinged with `GetCurrentHead`, so a healthy connection always receives something
+/// and only connection which did not receive anything for `dead_timeout` is considered dead.
+#[derive(Clone, Debug)]
+pub struct KeepaliveConfig {
+    /// Chain id used in `GetCurrentHead` pings, peer is not pinged if not set
+    pub ping_chain_id: Option<ChainId>,
+    /// Peer is pinged, when nothing was received from it for this long
+    pub ping_interval: Duration,
+    /// Connection is closed, when nothing was received from the peer for this long
+    pub dead_timeout: Duration,
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        KeepaliveConfig {
+            ping_chain_id: None,
+            ping_interval: Duration::from_secs(10),
+            dead_timeout: Duration::from_secs(30),
         }
     }
 }
@@ -181,7 +344,8 @@ impl Local {
 pub type PeerRef = ActorRef<PeerMsg>;
 
 /// Represents a single p2p peer.
-#[actor(Bootstrap, SendMessage)]
+#[cfg_attr(feature = "barrier", actor(Bootstrap, SendMessage, CheckKeepalive, Synchronize))]
+#[cfg_attr(not(feature = "barrier"), actor(Bootstrap, SendMessage, CheckKeepalive))]
 pub struct Peer {
     /// All events generated by the peer will end up in this channel
     network_channel: NetworkChannelRef,
@@ -193,6 +357,14 @@ pub struct Peer {
     tokio_executor: Handle,
     /// IP address of the remote peer
     remote_addr: SocketAddr,
+    /// Inbound and outbound rate limits
+    rate_limiting: PeerRateLimiting,
+    /// Keepalive pings and dead connection timeout
+    keepalive: KeepaliveConfig,
+    /// Last time the peer was pinged
+    ping_last: Option<Instant>,
+    /// Source of time for keepalive
+    clock: ClockRef,
 }
 
 impl Peer {
@@ -203,35 +375,51 @@ impl Peer {
                  public_key: &str,
                  secret_key: &str,
                  proof_of_work_stamp: &str,
+                 expected_pow: f64,
                  version: NetworkVersion,
+                 capture: Option<TrafficCapture>,
+                 keepalive: KeepaliveConfig,
                  tokio_executor: Handle,
-                 socket_address: &SocketAddr) -> Result<PeerRef, CreateError>
+                 socket_address: &SocketAddr,
+                 rate_limiting: PeerRateLimiting,
+                 stats: PeerStats,
+                 clock: ClockRef) -> Result<PeerRef, CreateError>
     {
         let info = Local {
             listener_port,
             proof_of_work_stamp: proof_of_work_stamp.into(),
+            expected_pow,
             public_key: public_key.into(),
             secret_key: secret_key.into(),
             version,
+            capture,
         };
-        let props = Props::new_args::<Peer, _>((network_channel, Arc::new(info), tokio_executor, *socket_address));
+        let props = Props::new_args::<Peer, _>((network_channel, Arc::new(info), keepalive, tokio_executor, *socket_address, rate_limiting, stats, clock));
         let actor_id = ACTOR_ID_GENERATOR.fetch_add(1, Ordering::SeqCst);
         sys.actor_of_props(&format!("peer-{}", actor_id), props)
     }
 }
 
-impl ActorFactoryArgs<(NetworkChannelRef, Arc<Local>, Handle, SocketAddr)> for Peer {
-    fn create_args((event_channel, info, tokio_executor, socket_address): (NetworkChannelRef, Arc<Local>, Handle, SocketAddr)) -> Self {
+impl ActorFactoryArgs<(NetworkChannelRef, Arc<Local>, KeepaliveConfig, Handle, SocketAddr, PeerRateLimiting, PeerStats, ClockRef)> for Peer {
+    fn create_args((event_channel, info, keepalive, tokio_executor, socket_address, rate_limiting, stats, clock): (NetworkChannelRef, Arc<Local>, KeepaliveConfig, Handle, SocketAddr, PeerRateLimiting, PeerStats, ClockRef)) -> Self {
         Peer {
             network_channel: event_channel,
             local: info,
             net: Network {
                 rx_run: Arc::new(AtomicBool::new(false)),
-                tx: Arc::new(Mutex::new(None)),
+                tx: Arc::new(StdMutex::new(None)),
+                overflow: Arc::new(StdMutex::new(VecDeque::new())),
                 socket_address,
+                stats,
+                dead: Arc::new(StdMutex::new(None)),
+                version: Arc::new(StdMutex::new(None)),
             },
             tokio_executor,
-            remote_addr: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0)),
+            remote_addr: socket_address,
+            rate_limiting,
+            keepalive,
+            ping_last: None,
+            clock,
         }
     }
 }
@@ -258,41 +446,53 @@ impl Receive<Bootstrap> for Peer {
         let system = ctx.system.clone();
         let net = self.net.clone();
         let network_channel = self.network_channel.clone();
+        let rate_limiter = PeerRateLimiter::new(&self.rate_limiting.limits, self.rate_limiting.rate.clone(), Instant::now());
         self.remote_addr = msg.address;
 
-        self.tokio_executor.spawn(async move {
-            async fn setup_net(net: &Network, tx: EncryptedMessageWriter) {
-                net.rx_run.store(true, Ordering::Release);
-                *net.tx.lock().await = Some(tx);
-            }
+        let outbound_bandwidth = self.rate_limiting.outbound_bandwidth.clone();
+        let clock = self.clock.clone();
+        let (dead_tx, dead_rx) = oneshot::channel();
+        *self.net.dead.lock().unwrap() = Some(dead_tx);
+        clock::schedule_once(&self.clock, ctx, KEEPALIVE_CHECK_INTERVAL, CheckKeepalive);
 
+        self.tokio_executor.spawn(async move {
             let peer_address = msg.address;
             debug!(system.log(), "Bootstrapping"; "ip" => &peer_address, "peer" => myself.name());
             match bootstrap(msg, info, &system.log()).await {
-                Ok(BootstrapOutput(rx, tx, public_key, metadata)) => {
-                    debug!(system.log(), "Bootstrap successful"; "ip" => &peer_address, "peer" => myself.name(), "metadata" => format!("{:?}", &metadata));
-                    setup_net(&net, tx).await;
-
+                Ok(BootstrapOutput(rx, tx, public_key, metadata, listener_port, negotiated_version, _connected_peer)) => {
+                    debug!(system.log(), "Bootstrap successful"; "ip" => &peer_address, "peer" => myself.name(), "metadata" => format!("{:?}", &metadata), "version" => format!("{:?}", &negotiated_version));
                     let peer_id = HashType::CryptoboxPublicKeyHash.bytes_to_string(&public_key);
+                    let log = system.log().new(slog::o!("peer" => peer_id.clone()));
+
+                    // all messages are sent by a single writer task
+                    let (queue_tx, queue_rx) = mpsc::channel(OUTBOUND_QUEUE_CAPACITY);
+                    net.stats.connected(clock.now());
+                    *net.version.lock().unwrap() = Some(negotiated_version);
+                    net.rx_run.store(true, Ordering::Release);
+                    *net.tx.lock().unwrap() = Some(queue_tx);
+                    let writer = tokio::spawn(begin_process_outgoing(tx, queue_rx, net.overflow.clone(), net.stats.clone(), outbound_bandwidth, clock.clone(), myself.clone(), system.clone(), log.clone()));
+
                     // notify that peer was bootstrapped successfully
                     network_channel.tell(Publish {
                         msg: PeerBootstrapped::Success {
                             peer: myself.clone(),
                             peer_id: peer_id.clone(),
                             peer_metadata: metadata,
+                            listener_port,
                         }.into(),
                         topic: NetworkChannelTopic::NetworkEvents.into(),
                     }, Some(myself.clone().into()));
 
                     // begin to process incoming messages in a loop
-                    let log = system.log().new(slog::o!("peer" => peer_id));
-                    begin_process_incoming(rx, net, myself.clone(), network_channel, log, peer_address).await;
-                    // connection to peer was closed, stop this actor
+                    begin_process_incoming(rx, writer, net, dead_rx, clock, rate_limiter, myself.clone(), network_channel, log, peer_address).await;
+                    // connection to peer was closed (peer is unregistered from connected peers, when `_connected_peer` is dropped), stop this actor,
+                    // peer manager is notified by the termination just for its bookkeeping
                     system.stop(myself);
                 }
                 Err(err) => {
                     info!(system.log(), "Connection to peer failed"; "reason" => &err, "ip" => &peer_address, "peer" => myself.name());
 
+                    let misbehavior = err.misbehavior();
                     let potential_peers = match err {
                         PeerError::NackWithMotiveReceived { nack_info } => Some(nack_info.potential_peers_to_connect().clone()),
                         _ => None
@@ -303,6 +503,7 @@ impl Receive<Bootstrap> for Peer {
                         msg: PeerBootstrapped::Failure {
                             address: peer_address,
                             potential_peers_to_connect: potential_peers,
+                            misbehavior,
                         }.into(),
                         topic: NetworkChannelTopic::NetworkEvents.into(),
                     }, Some(myself.clone().into()));
@@ -318,35 +519,115 @@ impl Receive<SendMessage> for Peer {
     type Msg = PeerMsg;
 
     fn receive(&mut self, ctx: &Context<Self::Msg>, msg: SendMessage, _sender: Sender) {
-        let system = ctx.system.clone();
-        let myself = ctx.myself();
-        let tx = self.net.tx.clone();
-        self.tokio_executor.spawn(async move {
-            let mut tx_lock = tx.lock().await;
-            if let Some(tx) = tx_lock.as_mut() {
-                let write_result = timeout(IO_TIMEOUT, tx.write_message(&*msg.message)).await;
-                // release mutex as soon as possible
-                drop(tx_lock);
-
-                match write_result {
-                    Ok(write_result) => {
-                        if let Err(e) = write_result {
-                            warn!(system.log(), "Failed to send message"; "reason" => e);
-                            system.stop(myself);
-                        }
-                    }
-                    Err(_) => {
-                        warn!(system.log(), "Failed to send message"; "reason" => "timeout");
-                        system.stop(myself);
+        let mut tx_lock = self.net.tx.lock().unwrap();
+        if let Some(tx) = tx_lock.as_mut() {
+            let mut overflow = self.net.overflow.lock().unwrap();
+            // messages cannot overtake the messages waiting in the overflow queue
+            let msg = if overflow.is_empty() {
+                match tx.try_send(msg) {
+                    Ok(()) => return,
+                    Err(e) if e.is_full() => e.into_inner(),
+                    // writer task already finished and the peer is being stopped
+                    Err(_) => return,
+                }
+            } else {
+                msg
+            };
+
+            match push_overflow(&mut overflow, msg) {
+                Overflowed::Queued => (),
+                Overflowed::Dropped => debug!(ctx.system.log(), "Outbound queue is full, low priority message is dropped"; "peer" => ctx.myself().name()),
+                Overflowed::Exceeded => {
+                    warn!(ctx.system.log(), "Peer cannot keep up with our messages, disconnecting"; "peer" => ctx.myself().name(), "ip" => self.net.socket_address);
+                    overflow.clear();
+                    // closing the queue stops the writer task, reader is stopped as if the connection was dead
+                    tx_lock.take();
+                    if let Some(dead) = self.net.dead.lock().unwrap().take() {
+                        let _ = dead.send(());
                     }
+                    ctx.system.stop(ctx.myself());
                 }
             }
-        });
+        }
+    }
+}
+
+/// What happened to the message, which did not fit into the full outbound queue
+#[derive(Debug, PartialEq)]
+enum Overflowed {
+    /// Message waits in the overflow queue
+    Queued,
+    /// Low priority message was dropped
+    Dropped,
+    /// Overflow queue is full, peer should be disconnected
+    Exceeded,
+}
+
+fn push_overflow(overflow: &mut VecDeque<SendMessage>, msg: SendMessage) -> Overflowed {
+    if msg.is_low_priority() {
+        Overflowed::Dropped
+    } else if overflow.len() >= OUTBOUND_OVERFLOW_CAPACITY {
+        Overflowed::Exceeded
+    } else {
+        overflow.push_back(msg);
+        Overflowed::Queued
+    }
+}
+
+impl Receive<CheckKeepalive> for Peer {
+    type Msg = PeerMsg;
+
+    /// Ping the peer with `GetCurrentHead`, when nothing was received from it for `ping_interval`,
+    /// close the connection, when nothing was received for `dead_timeout`.
+    ///
+    /// Response to the ping is measured as round-trip time in [PeerStats].
+    fn receive(&mut self, ctx: &Context<Self::Msg>, _msg: CheckKeepalive, _sender: Sender) {
+        clock::schedule_once(&self.clock, ctx, KEEPALIVE_CHECK_INTERVAL, CheckKeepalive);
+
+        let now = self.clock.now();
+        let idle = match self.net.stats.idle(now) {
+            Some(idle) if self.net.rx_run.load(Ordering::Acquire) => idle,
+            // not connected yet or already closing
+            _ => return,
+        };
+
+        if idle >= self.keepalive.dead_timeout {
+            if let Some(dead) = self.net.dead.lock().unwrap().take() {
+                let _ = dead.send(());
+            }
+            return;
+        }
+
+        let ping_chain_id = match &self.keepalive.ping_chain_id {
+            Some(ping_chain_id) => ping_chain_id,
+            None => return,
+        };
+        let ping_due = self.ping_last.map_or(true, |ping_last| now.saturating_duration_since(ping_last) >= self.keepalive.ping_interval);
+        if idle < self.keepalive.ping_interval || !ping_due {
+            return;
+        }
+
+        let mut tx_lock = self.net.tx.lock().unwrap();
+        if let Some(tx) = tx_lock.as_mut() {
+            trace!(ctx.system.log(), "Peer is idle, sending ping"; "idle_secs" => idle.as_secs(), "peer" => ctx.myself().name());
+            // full queue is handled when the next message is sent, ping is just skipped
+            let _ = tx.try_send(SendMessage::new(GetCurrentHeadMessage::new(ping_chain_id.clone()).into()));
+            self.ping_last = Some(now);
+        }
+    }
+}
+
+#[cfg(feature = "barrier")]
+impl Receive<Synchronize> for Peer {
+    type Msg = PeerMsg;
+
+    fn receive(&mut self, _ctx: &Context<Self::Msg>, _msg: Synchronize, sender: Sender) {
+        answer_synchronized(sender);
     }
 }
 
 /// Output values of the successful bootstrap process
-pub struct BootstrapOutput(pub EncryptedMessageReader, pub EncryptedMessageWriter, pub PublicKey, pub MetadataMessage);
+pub struct BootstrapOutput(pub EncryptedMessageReader, pub EncryptedMessageWriter, pub PublicKey, pub MetadataMessage, pub u16, pub NetworkVersion, pub Option<ConnectedPeer>);
 
 pub async fn bootstrap(
     msg: Bootstrap,
@@ -392,6 +673,9 @@ pub async fn bootstrap(
     let peer_id = HashType::CryptoboxPublicKeyHash.bytes_to_string(&peer_public_key);
     debug!(log, "Received peer public key"; "public_key" => &peer_id);
 
+    // peer has to prove, that it spent some work on its identity
+    check_proof_of_work(&connection_message, info.expected_pow)?;
+
     // pre-compute encryption key
     let precomputed_key = match precompute(&hex::encode(peer_public_key), &info.secret_key) {
         Ok(key) => key,
@@ -399,8 +683,9 @@ pub async fn bootstrap(
     };
 
     // from now on all messages will be encrypted
-    let mut msg_tx = EncryptedMessageWriter::new(msg_tx, precomputed_key.clone(), nonce_local, peer_id.clone(), log.clone());
-    let mut msg_rx = EncryptedMessageReader::new(msg_rx, precomputed_key, nonce_remote, peer_id, log.clone());
+    let capture = info.capture.as_ref().map(|capture| capture.connection(&peer_id));
+    let mut msg_tx = EncryptedMessageWriter::new(msg_tx, precomputed_key.clone(), nonce_local, peer_id.clone(), capture.clone(), log.clone());
+    let mut msg_rx = EncryptedMessageReader::new(msg_rx, precomputed_key, nonce_remote, peer_id.clone(), capture, log.clone());
 
     let connecting_to_self = hex::encode(connection_message.public_key()) == info.public_key;
     if connecting_to_self {
@@ -417,19 +702,58 @@ pub async fn bootstrap(
     let metadata_received = timeout(IO_TIMEOUT, msg_rx.read_message::<MetadataMessage>()).await??;
     debug!(log, "Received remote peer metadata"; "disable_mempool" => metadata_received.disable_mempool(), "private_node" => metadata_received.private_node());
 
-    let protocol_not_supported = !connection_message.versions().iter().any(|version| supported_protocol_version.supports(version));
-    if protocol_not_supported {
-        // send nack
-        timeout(IO_TIMEOUT, msg_tx.write_message(&AckMessage::NackV0)).await??;
+    // remote peer, which does not understand nack with motive, receives just `NackV0`
+    let nack = |motive: NackMotive, understands_motive: bool| if understands_motive {
+        AckMessage::Nack(NackInfo::new(motive, &msg.potential_peers))
+    } else {
+        AckMessage::NackV0
+    };
 
-        return Err(
-            PeerError::UnsupportedProtocol {
-                supported_version: format!("{:?}", &supported_protocol_version),
-                incompatible_versions: format!("{:?}", &connection_message.versions()),
-            }
-        );
+    let negotiated_version = match supported_protocol_version.select_compatible(connection_message.versions()) {
+        Ok(version) => version,
+        Err(motive) => {
+            // no version was negotiated, so nack with motive is sent only if any of the announced versions supports it
+            let understands_motive = connection_message.versions().iter().any(NetworkVersion::supports_nack_with_motive);
+            timeout(IO_TIMEOUT, msg_tx.write_message(&nack(motive, understands_motive))).await??;
+
+            return Err(
+                PeerError::UnsupportedProtocol {
+                    supported_version: format!("{:?}", &supported_protocol_version),
+                    incompatible_versions: format!("{:?}", &connection_message.versions()),
+                }
+            );
+        }
+    };
+    debug!(log, "Negotiated network version"; "version" => format!("{:?}", &negotiated_version));
+    // from now on the nack is chosen by the negotiated p2p version
+    let understands_motive = negotiated_version.supports_nack_with_motive();
+    let nack = |motive: NackMotive| nack(motive, understands_motive);
+
+    if msg.accepted_peer_ids.as_ref().map(|peer_ids| !peer_ids.contains(&peer_id)).unwrap_or(false) {
+        debug!(log, "Refusing connection, peer is not accepted");
+        timeout(IO_TIMEOUT, msg_tx.write_message(&nack(NackMotive::NoMotive))).await??;
+        return Err(PeerError::ConnectionRefused { motive: NackMotive::NoMotive });
+    }
+
+    if let Some(motive) = msg.refuse {
+        debug!(log, "Refusing connection"; "motive" => format!("{:?}", &motive));
+        timeout(IO_TIMEOUT, msg_tx.write_message(&nack(motive))).await??;
+        return Err(PeerError::ConnectionRefused { motive });
     }
 
+    // register peer, so no other connection to the same peer can be established
+    let connected_peer = match &msg.connected_peers {
+        Some(connected_peers) => match connected_peers.try_connect(&peer_id) {
+            Some(connected_peer) => Some(connected_peer),
+            None => {
+                debug!(log, "Refusing connection, peer is already connected");
+                timeout(IO_TIMEOUT, msg_tx.write_message(&nack(NackMotive::AlreadyConnected))).await??;
+                return Err(PeerError::ConnectionRefused { motive: NackMotive::AlreadyConnected });
+            }
+        }
+        None => None,
+    };
+
     // send ack
     timeout(IO_TIMEOUT, msg_tx.write_message(&AckMessage::Ack)).await??;
 
@@ -439,7 +763,7 @@ pub async fn bootstrap(
     match ack_received {
         AckMessage::Ack => {
             debug!(log, "Received ACK");
-            Ok(BootstrapOutput(msg_rx, msg_tx, peer_public_key.clone(), metadata_received))
+            Ok(BootstrapOutput(msg_rx, msg_tx, peer_public_key.clone(), metadata_received, *connection_message.port(), negotiated_version, connected_peer))
         }
         AckMessage::NackV0 => {
             debug!(log, "Received NACK");
@@ -461,23 +785,139 @@ fn generate_nonces(sent_msg: &BinaryChunk, recv_msg: &BinaryChunk, incoming: boo
     nonce::generate_nonces(sent_msg.raw(), recv_msg.raw(), incoming)
 }
 
-/// Start to process incoming data
-async fn begin_process_incoming(mut rx: EncryptedMessageReader, net: Network, myself: PeerRef, event_channel: NetworkChannelRef, log: Logger, peer_address: SocketAddr) {
+/// Check, that proof of work stamp of the remote peer meets the expected pow
+fn check_proof_of_work(connection_message: &ConnectionMessage, expected_pow: f64) -> Result<(), PeerError> {
+    let data = [connection_message.public_key().as_slice(), connection_message.proof_of_work_stamp().as_slice()].concat();
+    proof_of_work::check_proof_of_work(&data, expected_pow)
+        .map_err(|()| PeerError::InvalidProofOfWork { expected_pow })
+}
+
+/// Wait until bytes sent before (by all peers) fit into the outbound bandwidth
+async fn throttle_outbound(outbound_bandwidth: &OutboundBandwidth) {
+    let delay = outbound_bandwidth.delay();
+    if delay > Duration::from_secs(0) {
+        delay_for(delay).await;
+    }
+}
+
+/// Send queued messages until the queue is closed, then return the writer, so the connection can be shut down.
+///
+/// Messages already waiting in the queue are encrypted to the same buffer and written to the socket at once.
+async fn begin_process_outgoing(mut tx: EncryptedMessageWriter, mut queue: mpsc::Receiver<SendMessage>, overflow: Arc<StdMutex<VecDeque<SendMessage>>>, stats: PeerStats, outbound_bandwidth: OutboundBandwidth, clock: ClockRef, myself: PeerRef, system: ActorSystem, log: Logger) -> EncryptedMessageWriter {
+    loop {
+        let msg = match try_next_outgoing(&mut queue, &overflow) {
+            Some(msg) => msg,
+            None => match queue.next().await {
+                Some(msg) => msg,
+                None => break,
+            }
+        };
+
+        // batch is written, when we are within outbound bandwidth limit, messages queued meanwhile are sent in the same batch
+        throttle_outbound(&outbound_bandwidth).await;
+
+        let mut batch = Vec::new();
+        let mut next = Some(msg);
+        while let Some(msg) = next.take() {
+            match tx.encrypt_message(msg.message()) {
+                Ok(message_bytes) => batch.push((msg, message_bytes as u64)),
+                Err(e) => {
+                    warn!(log, "Failed to send message"; "reason" => e);
+                    system.stop(myself);
+                    return tx;
+                }
+            }
+            if tx.pending_bytes() < OUTBOUND_BATCH_MAX_BYTES {
+                next = try_next_outgoing(&mut queue, &overflow);
+            }
+        }
+
+        match timeout(IO_TIMEOUT, tx.flush()).await {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => {
+                warn!(log, "Failed to send message"; "reason" => e);
+                system.stop(myself);
+                return tx;
+            }
+            Err(_) => {
+                warn!(log, "Failed to send message"; "reason" => "timeout");
+                system.stop(myself);
+                return tx;
+            }
+        }
+
+        for (msg, message_bytes) in &batch {
+            stats.sent_at(msg.message(), *message_bytes, clock.now());
+        }
+        outbound_bandwidth.sent(batch.iter().map(|(_, message_bytes)| message_bytes).sum());
+        if batch.iter().any(|(msg, _)| msg.is_disconnect()) {
+            // we told remote peer that we are closing the connection
+            system.stop(myself);
+            return tx;
+        }
+    }
+    tx
+}
+
+/// Next message waiting to be sent, messages from the overflow queue are sent after all messages from the queue,
+/// because peer actor fills the overflow queue only when the queue is full.
+fn try_next_outgoing(queue: &mut mpsc::Receiver<SendMessage>, overflow: &StdMutex<VecDeque<SendMessage>>) -> Option<SendMessage> {
+    match queue.try_next() {
+        Ok(msg) => msg,
+        Err(_) => overflow.lock().unwrap().pop_front(),
+    }
+}
+
+/// Start to process incoming data, connection is closed when peer actor resolves the connection is dead, see [CheckKeepalive].
+///
+/// Returns once both the reader and the writer finished and the connection is shut down.
+async fn begin_process_incoming(mut rx: EncryptedMessageReader, writer: JoinHandle<EncryptedMessageWriter>, net: Network, mut dead: oneshot::Receiver<()>, clock: ClockRef, mut rate_limiter: PeerRateLimiter, myself: PeerRef, event_channel: NetworkChannelRef, log: Logger, peer_address: SocketAddr) {
     info!(log, "Starting to accept messages"; "ip" => format!("{:?}", &peer_address));
 
+    let mut bytes_read = rx.bytes_read();
     while net.rx_run.load(Ordering::Acquire) {
-        match timeout(READ_TIMEOUT_LONG, rx.read_message::<PeerMessageResponse>()).await {
-            Ok(res) => match res {
+        let received = {
+            let read = rx.read_message::<PeerMessageResponse>();
+            futures::pin_mut!(read);
+            match future::select(read, &mut dead).await {
+                Either::Left((res, _)) => Some(res),
+                Either::Right(_) => None,
+            }
+        };
+        match received {
+            Some(res) => match res {
                 Ok(msg) => {
+                    let message_bytes = rx.bytes_read() - bytes_read;
+                    bytes_read = rx.bytes_read();
+                    let received = PeerMessageReceived::new(myself.clone(), Arc::new(msg));
+                    net.stats.received_at(&received.message, &received.block_header_hashes, message_bytes, clock.now());
+                    match rate_limiter.received(&received.message, message_bytes, Instant::now()) {
+                        Throttle::Pass => (),
+                        Throttle::Delay(delay) => {
+                            trace!(log, "Peer exceeded rate limit, message is delayed"; "delay_ms" => delay.as_millis() as u64);
+                            delay_for(delay).await;
+                        }
+                        Throttle::Disconnect => {
+                            warn!(log, "Peer exceeded rate limit for too long, disconnecting"; "ip" => format!("{:?}", &peer_address));
+                            event_channel.tell(
+                                Publish {
+                                    msg: PeerMisbehaved {
+                                        peer: myself.clone(),
+                                        misbehavior: Misbehavior::RateLimitExceeded,
+                                        disconnect: true,
+                                    }.into(),
+                                    topic: NetworkChannelTopic::NetworkEvents.into(),
+                                }, Some(myself.clone().into()));
+                            break;
+                        }
+                    }
+
                     let should_broadcast_message = net.rx_run.load(Ordering::Acquire);
                     if should_broadcast_message {
-                        trace!(log, "Message parsed successfully"; "msg" => format!("{:?}", &msg));
+                        trace!(log, "Message parsed successfully"; "msg" => format!("{:?}", &received.message));
                         event_channel.tell(
                             Publish {
-                                msg: PeerMessageReceived {
-                                    peer: myself.clone(),
-                                    message: Arc::new(msg),
-                                }.into(),
+                                msg: received.into(),
                                 topic: NetworkChannelTopic::NetworkEvents.into(),
                             }, Some(myself.clone().into()));
                     }
@@ -491,22 +931,80 @@ async fn begin_process_incoming(mut rx: EncryptedMessageReader, net: Network, my
                     }
                 }
             }
-            Err(_) => {
-                warn!(log, "Peer message read timed out"; "secs" => READ_TIMEOUT_LONG.as_secs());
+            None => {
+                // silent peer is just disconnected, it is not penalized, the connection could have been lost by our side
+                warn!(log, "Nothing received from peer, connection is dead");
                 break;
             }
         }
     }
 
     debug!(log, "Shutting down peer connection"; "ip" => format!("{:?}", &peer_address));
-    let mut tx_lock = net.tx.lock().await;
-    if let Some(tx) = tx_lock.take() {
-        let socket = rx.unsplit(tx);
-        match socket.shutdown(Shutdown::Both) {
-            Ok(()) => debug!(log, "Connection shutdown successful"; "socket" => format!("{:?}", socket)),
-            Err(err) => debug!(log, "Failed to shutdown connection"; "err" => format!("{:?}", err), "socket" => format!("{:?}", socket)),
+    // closing the queue stops the writer task, once it sends already queued messages
+    net.tx.lock().unwrap().take();
+    match writer.await {
+        Ok(tx) => {
+            let socket = rx.unsplit(tx);
+            match socket.shutdown(Shutdown::Both) {
+                Ok(()) => debug!(log, "Connection shutdown successful"; "socket" => format!("{:?}", socket)),
+                Err(err) => debug!(log, "Failed to shutdown connection"; "err" => format!("{:?}", err), "socket" => format!("{:?}", socket)),
+            }
         }
+        Err(err) => warn!(log, "Writer task failed"; "err" => format!("{:?}", err)),
     }
 
     info!(log, "Stopped to accept messages"; "ip" => format!("{:?}", &peer_address));
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Identity generated by the OCaml node (docker/identities/identity_ocaml.json), its stamp meets pow 26.0
+    const OCAML_PUBLIC_KEY: &str = "5fd7ba1d15650abc9a510c37a8bee566b708fe6577f0d832b903e8f13d71c94a";
+    const OCAML_PROOF_OF_WORK_STAMP: &str = "4b1354dcfc087e52c8fb510317b9464c297b8a55b79bfc95";
+
+    fn connection_message(public_key: &str, proof_of_work_stamp: &str) -> ConnectionMessage {
+        ConnectionMessage::new(9732, public_key, proof_of_work_stamp, &[0u8; 24], vec![NetworkVersion::new("TEST_CHAIN".to_string(), 0, 0)])
+    }
+
+    #[test]
+    fn test_push_overflow_is_capped() {
+        let mut overflow = VecDeque::new();
+        for _ in 0..OUTBOUND_OVERFLOW_CAPACITY {
+            assert_eq!(Overflowed::Queued, push_overflow(&mut overflow, SendMessage::new(PeerMessage::Disconnect.into())));
+        }
+        // low priority messages are dropped regardless of the cap
+        assert_eq!(Overflowed::Dropped, push_overflow(&mut overflow, SendMessage::new(PeerMessage::Bootstrap.into())));
+
+        assert_eq!(Overflowed::Exceeded, push_overflow(&mut overflow, SendMessage::new(PeerMessage::Disconnect.into())));
+        assert_eq!(OUTBOUND_OVERFLOW_CAPACITY, overflow.len());
+    }
+
+    #[test]
+    fn test_check_proof_of_work_accepts_ocaml_identity() {
+        let message = connection_message(OCAML_PUBLIC_KEY, OCAML_PROOF_OF_WORK_STAMP);
+        assert!(check_proof_of_work(&message, 0.0).is_ok());
+        assert!(check_proof_of_work(&message, 24.0).is_ok());
+        assert!(check_proof_of_work(&message, 26.0).is_ok());
+
+        // sandbox expects pow 0, which is met by any stamp
+        let message = connection_message(OCAML_PUBLIC_KEY, "000000000000000000000000000000000000000000000000");
+        assert!(check_proof_of_work(&message, 0.0).is_ok());
+    }
+
+    #[test]
+    fn test_check_proof_of_work_rejects_insufficient_stamp() {
+        // stamp does not meet higher pow
+        let message = connection_message(OCAML_PUBLIC_KEY, OCAML_PROOF_OF_WORK_STAMP);
+        assert!(matches!(check_proof_of_work(&message, 26.5), Err(PeerError::InvalidProofOfWork { .. })));
+
+        // stamp belongs to another public key
+        let message = connection_message("6e699289a794e629a13335c6fb7cfe6ee143c29609b020a49b5a575593824459", OCAML_PROOF_OF_WORK_STAMP);
+        assert!(matches!(check_proof_of_work(&message, 26.0), Err(PeerError::InvalidProofOfWork { .. })));
+
+        // zeroed stamp
+        let message = connection_message(OCAML_PUBLIC_KEY, "000000000000000000000000000000000000000000000000");
+        assert!(matches!(check_proof_of_work(&message, 26.0), Err(PeerError::InvalidProofOfWork { .. })));
+    }
+}
@@ -0,0 +1,419 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Rate limiting of the peer traffic.
+//!
+//! Every peer has token buckets for inbound bytes and for the requests, which are expensive to answer
+//! (`GetBlockHeaders`, `GetOperationsForBlocks`, `Operation`). Other requests and unsolicited messages share one bucket,
+//! responses to our requests are limited just by the inbound bytes. When some bucket is empty, processing of the next message
+//! is delayed, so the peer cannot send faster than allowed (we stop reading from the socket meanwhile).
+//! Peer, which exceeds its request limits continuously for longer than [RateLimits::max_throttle_duration], is disconnected.
+//!
+//! Outbound bandwidth is limited globally for all peers by [OutboundBandwidth].
+
+use std::cmp;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tezos_messages::p2p::encoding::prelude::*;
+
+/// Token bucket can hold tokens for this long period, so short bursts are not throttled
+const BURST_DURATION: Duration = Duration::from_secs(2);
+/// Measured rate is computed for this window
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Rate limits, zero means unlimited
+#[derive(Clone, Debug)]
+pub struct RateLimits {
+    /// Maximal inbound bytes per second of one peer, peer is just delayed when exceeding this limit
+    pub inbound_bytes_per_sec: u64,
+    /// Maximal count of requested block headers per second of one peer
+    pub get_block_headers_per_sec: u64,
+    /// Maximal count of requested operations for blocks per second of one peer
+    pub get_operations_for_blocks_per_sec: u64,
+    /// Maximal count of `Operation` messages per second of one peer
+    pub operations_per_sec: u64,
+    /// Maximal count of other requests and unsolicited messages per second of one peer (responses are not counted)
+    pub other_messages_per_sec: u64,
+    /// Peer exceeding request limits for longer period is disconnected
+    pub max_throttle_duration: Duration,
+    /// Maximal outbound bytes per second of all peers together
+    pub outbound_bytes_per_sec: u64,
+}
+
+impl RateLimits {
+    /// No rate limits
+    pub fn unlimited() -> Self {
+        RateLimits {
+            inbound_bytes_per_sec: 0,
+            get_block_headers_per_sec: 0,
+            get_operations_for_blocks_per_sec: 0,
+            operations_per_sec: 0,
+            other_messages_per_sec: 0,
+            max_throttle_duration: Duration::from_secs(30),
+            outbound_bytes_per_sec: 0,
+        }
+    }
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits {
+            inbound_bytes_per_sec: 10 * 1024 * 1024,
+            get_block_headers_per_sec: 100,
+            get_operations_for_blocks_per_sec: 100,
+            operations_per_sec: 200,
+            other_messages_per_sec: 100,
+            max_throttle_duration: Duration::from_secs(30),
+            outbound_bytes_per_sec: 0,
+        }
+    }
+}
+
+/// Token bucket, which can get into debt. Debt is paid by waiting.
+#[derive(Debug)]
+pub struct TokenBucket {
+    /// Tokens added per second
+    rate: f64,
+    /// Maximal count of tokens
+    capacity: f64,
+    /// Current count of tokens, negative value is a debt
+    tokens: f64,
+    /// Last time tokens were added
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// Create full bucket, returns `None` for unlimited rate
+    pub fn new(rate_per_sec: u64, now: Instant) -> Option<Self> {
+        if rate_per_sec == 0 {
+            return None;
+        }
+        let rate = rate_per_sec as f64;
+        let capacity = (rate * BURST_DURATION.as_secs_f64()).max(1.0);
+        Some(TokenBucket { rate, capacity, tokens: capacity, updated: now })
+    }
+
+    /// Take `amount` of tokens and return how long the caller should wait, until the bucket is not in debt
+    pub fn take(&mut self, amount: u64, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.updated);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.capacity);
+        self.updated = now;
+
+        self.tokens -= amount as f64;
+        if self.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+/// Take tokens from optional bucket
+fn take(bucket: &mut Option<TokenBucket>, amount: u64, now: Instant) -> Duration {
+    bucket.as_mut()
+        .map(|bucket| bucket.take(amount, now))
+        .unwrap_or_else(|| Duration::from_secs(0))
+}
+
+/// Outbound bandwidth shared by all peers
+#[derive(Clone, Debug)]
+pub struct OutboundBandwidth(Arc<Mutex<Option<TokenBucket>>>);
+
+impl OutboundBandwidth {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        OutboundBandwidth(Arc::new(Mutex::new(TokenBucket::new(bytes_per_sec, Instant::now()))))
+    }
+
+    /// How long the sender should wait, until the bytes sent before fit into the bandwidth
+    pub fn delay(&self) -> Duration {
+        take(&mut self.0.lock().unwrap(), 0, Instant::now())
+    }
+
+    /// Account sent bytes, they delay the next sending of all peers
+    pub fn sent(&self, bytes: u64) {
+        take(&mut self.0.lock().unwrap(), bytes, Instant::now());
+    }
+}
+
+/// Rate of one peer measured in the last second
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RateSnapshot {
+    pub inbound_bytes_per_sec: u64,
+    pub inbound_messages_per_sec: u64,
+    /// Peer is currently delayed because it exceeded some limit
+    pub throttled: bool,
+}
+
+/// Current rate of the peer, shared with monitoring
+#[derive(Clone, Debug, Default)]
+pub struct PeerRate(Arc<Mutex<Option<(RateSnapshot, Instant)>>>);
+
+impl PeerRate {
+    fn set(&self, snapshot: RateSnapshot, now: Instant) {
+        *self.0.lock().unwrap() = Some((snapshot, now));
+    }
+
+    /// Returns current rate, rate of the peer which did not send anything recently is zero
+    pub fn snapshot(&self) -> RateSnapshot {
+        match *self.0.lock().unwrap() {
+            Some((snapshot, measured)) if measured.elapsed() <= 2 * RATE_WINDOW => snapshot,
+            _ => RateSnapshot::default(),
+        }
+    }
+}
+
+/// Everything the peer needs for rate limiting
+#[derive(Clone, Debug)]
+pub struct PeerRateLimiting {
+    pub limits: RateLimits,
+    pub outbound_bandwidth: OutboundBandwidth,
+    /// Measured rate of the peer is published here
+    pub rate: PeerRate,
+}
+
+impl PeerRateLimiting {
+    pub fn new(limits: RateLimits, outbound_bandwidth: OutboundBandwidth) -> Self {
+        PeerRateLimiting { limits, outbound_bandwidth, rate: PeerRate::default() }
+    }
+
+    /// No limits, used by tests
+    pub fn unlimited() -> Self {
+        Self::new(RateLimits::unlimited(), OutboundBandwidth::new(0))
+    }
+}
+
+/// What to do with the received message
+#[derive(Debug, PartialEq)]
+pub enum Throttle {
+    /// Process message immediately
+    Pass,
+    /// Process message after the delay
+    Delay(Duration),
+    /// Peer exceeds the limits for too long, disconnect it
+    Disconnect,
+}
+
+/// Inbound rate limiter of one peer
+pub struct PeerRateLimiter {
+    bytes: Option<TokenBucket>,
+    get_block_headers: Option<TokenBucket>,
+    get_operations_for_blocks: Option<TokenBucket>,
+    operations: Option<TokenBucket>,
+    other_messages: Option<TokenBucket>,
+    max_throttle_duration: Duration,
+    /// Since when are the requests of the peer continuously delayed
+    throttled_since: Option<Instant>,
+    /// Start of the current measurement window with bytes and messages received in the window
+    window: (Instant, u64, u64),
+    rate: PeerRate,
+}
+
+impl PeerRateLimiter {
+    pub fn new(limits: &RateLimits, rate: PeerRate, now: Instant) -> Self {
+        PeerRateLimiter {
+            bytes: TokenBucket::new(limits.inbound_bytes_per_sec, now),
+            get_block_headers: TokenBucket::new(limits.get_block_headers_per_sec, now),
+            get_operations_for_blocks: TokenBucket::new(limits.get_operations_for_blocks_per_sec, now),
+            operations: TokenBucket::new(limits.operations_per_sec, now),
+            other_messages: TokenBucket::new(limits.other_messages_per_sec, now),
+            max_throttle_duration: limits.max_throttle_duration,
+            throttled_since: None,
+            window: (now, 0, 0),
+            rate,
+        }
+    }
+
+    /// Account received message of `bytes` size and decide, when it can be processed.
+    ///
+    /// Get requests are counted by the count of requested items.
+    pub fn received(&mut self, message: &PeerMessageResponse, bytes: u64, now: Instant) -> Throttle {
+        let mut requests_delay = Duration::from_secs(0);
+        for message in message.messages() {
+            let delay = match message {
+                PeerMessage::GetBlockHeaders(request) => take(&mut self.get_block_headers, request.get_block_headers().len() as u64, now),
+                PeerMessage::GetOperationsForBlocks(request) => take(&mut self.get_operations_for_blocks, request.get_operations_for_blocks().len() as u64, now),
+                PeerMessage::Operation(_) => take(&mut self.operations, 1, now),
+                // responses to our requests, we do not want to slow down our own download
+                PeerMessage::BlockHeader(_)
+                | PeerMessage::OperationsForBlocks(_)
+                | PeerMessage::OperationHashesForBlock(_)
+                | PeerMessage::Protocol(_)
+                | PeerMessage::CurrentBranch(_)
+                | PeerMessage::CurrentHead(_) => Duration::from_secs(0),
+                _ => take(&mut self.other_messages, 1, now),
+            };
+            requests_delay = cmp::max(requests_delay, delay);
+        }
+        let bytes_delay = take(&mut self.bytes, bytes, now);
+
+        let throttle = if requests_delay > Duration::from_secs(0) {
+            let throttled_since = *self.throttled_since.get_or_insert(now);
+            if now.saturating_duration_since(throttled_since) + requests_delay > self.max_throttle_duration {
+                Throttle::Disconnect
+            } else {
+                Throttle::Delay(cmp::max(requests_delay, bytes_delay))
+            }
+        } else {
+            self.throttled_since = None;
+            if bytes_delay > Duration::from_secs(0) {
+                Throttle::Delay(bytes_delay)
+            } else {
+                Throttle::Pass
+            }
+        };
+
+        self.measure(bytes, message.messages().len() as u64, throttle != Throttle::Pass, now);
+        throttle
+    }
+
+    fn measure(&mut self, bytes: u64, messages: u64, throttled: bool, now: Instant) {
+        let (window_start, window_bytes, window_messages) = &mut self.window;
+        *window_bytes += bytes;
+        *window_messages += messages;
+        let elapsed = now.saturating_duration_since(*window_start);
+        if elapsed >= RATE_WINDOW {
+            let secs = elapsed.as_secs_f64();
+            self.rate.set(RateSnapshot {
+                inbound_bytes_per_sec: (*window_bytes as f64 / secs) as u64,
+                inbound_messages_per_sec: (*window_messages as f64 / secs) as u64,
+                throttled,
+            }, now);
+            self.window = (now, 0, 0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crypto::hash::HashType;
+
+    use super::*;
+
+    fn get_block_headers(count: usize) -> PeerMessageResponse {
+        let hash = HashType::BlockHash.string_to_bytes("BLockGenesisGenesisGenesisGenesisGenesisd6f5afWyME7").unwrap();
+        PeerMessage::GetBlockHeaders(GetBlockHeadersMessage::new(vec![hash; count])).into()
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(10, now).unwrap();
+
+        // burst of two seconds is allowed
+        assert_eq!(Duration::from_secs(0), bucket.take(20, now));
+        assert_eq!(Duration::from_millis(500), bucket.take(5, now));
+        // debt is paid after 500ms
+        assert_eq!(Duration::from_secs(0), bucket.take(0, now + Duration::from_millis(500)));
+        assert_eq!(Duration::from_secs(1), bucket.take(10, now + Duration::from_millis(500)));
+
+        assert!(TokenBucket::new(0, now).is_none());
+    }
+
+    #[test]
+    fn test_flooding_peer_is_delayed_and_disconnected() {
+        let limits = RateLimits {
+            get_block_headers_per_sec: 10,
+            max_throttle_duration: Duration::from_secs(5),
+            ..RateLimits::unlimited()
+        };
+        let rate = PeerRate::default();
+        let start = Instant::now();
+        let mut limiter = PeerRateLimiter::new(&limits, rate.clone(), start);
+
+        // burst is within limits
+        assert_eq!(Throttle::Pass, limiter.received(&get_block_headers(10), 100, start));
+        assert_eq!(Throttle::Pass, limiter.received(&get_block_headers(10), 100, start));
+        // over limit requests are delayed
+        assert_eq!(Throttle::Delay(Duration::from_secs(1)), limiter.received(&get_block_headers(10), 100, start));
+
+        // peer keeps sending requests as soon as it can, it is disconnected after the throttle duration
+        let mut now = start;
+        let mut delays = 0;
+        loop {
+            now += Duration::from_secs(1);
+            match limiter.received(&get_block_headers(20), 100, now) {
+                Throttle::Delay(delay) => {
+                    now += delay;
+                    delays += 1;
+                }
+                Throttle::Disconnect => break,
+                Throttle::Pass => panic!("Flooding peer should not pass"),
+            }
+        }
+        assert!(delays > 0);
+        assert!(now - start <= Duration::from_secs(10));
+        assert!(rate.0.lock().unwrap().unwrap().0.throttled);
+    }
+
+    #[test]
+    fn test_responses_are_not_counted_as_requests() {
+        let limits = RateLimits {
+            other_messages_per_sec: 1,
+            max_throttle_duration: Duration::from_secs(5),
+            ..RateLimits::unlimited()
+        };
+        let start = Instant::now();
+        let mut limiter = PeerRateLimiter::new(&limits, PeerRate::default(), start);
+        let header = BlockHeaderBuilder::default()
+            .level(1)
+            .proto(1)
+            .predecessor(HashType::BlockHash.string_to_bytes("BLockGenesisGenesisGenesisGenesisGenesisd6f5afWyME7").unwrap())
+            .timestamp(0)
+            .validation_pass(0)
+            .operations_hash(HashType::OperationListListHash.string_to_bytes("LLoZS2LW3rEi7KYU4ouBQtorua37aWWCtpDmv1n2x3xoKi6sVXLWp").unwrap())
+            .fitness(vec![])
+            .context(HashType::ContextHash.string_to_bytes("CoVmAcMV64uAQo8XvfLr9VDuz7HVZLT4cgK1w1qYmTjQNbGwQwDd").unwrap())
+            .protocol_data(vec![])
+            .build().unwrap();
+
+        // downloaded block headers are responses, they are not throttled
+        for _ in 0..10 {
+            assert_eq!(Throttle::Pass, limiter.received(&PeerMessage::BlockHeader(header.clone().into()).into(), 100, start));
+        }
+        // unsolicited messages are
+        assert_eq!(Throttle::Pass, limiter.received(&PeerMessage::Bootstrap.into(), 100, start));
+        assert_eq!(Throttle::Pass, limiter.received(&PeerMessage::Bootstrap.into(), 100, start));
+        assert_eq!(Throttle::Delay(Duration::from_secs(1)), limiter.received(&PeerMessage::Bootstrap.into(), 100, start));
+    }
+
+    #[test]
+    fn test_outbound_bandwidth() {
+        let bandwidth = OutboundBandwidth::new(1000);
+        assert_eq!(Duration::from_secs(0), bandwidth.delay());
+
+        // burst is sent without waiting, next sending waits for the debt
+        bandwidth.sent(2000);
+        assert_eq!(Duration::from_secs(0), bandwidth.delay());
+        bandwidth.sent(1000);
+        assert!(bandwidth.delay() > Duration::from_millis(900));
+
+        assert_eq!(Duration::from_secs(0), OutboundBandwidth::new(0).delay());
+    }
+
+    #[test]
+    fn test_peer_within_limits_is_not_throttled() {
+        let limits = RateLimits {
+            get_block_headers_per_sec: 10,
+            inbound_bytes_per_sec: 1000,
+            max_throttle_duration: Duration::from_secs(5),
+            ..RateLimits::unlimited()
+        };
+        let rate = PeerRate::default();
+        let start = Instant::now();
+        let mut limiter = PeerRateLimiter::new(&limits, rate.clone(), start);
+
+        for second in 1..=60 {
+            let now = start + Duration::from_secs(second);
+            assert_eq!(Throttle::Pass, limiter.received(&get_block_headers(10), 500, now));
+        }
+        assert_eq!(
+            Some(RateSnapshot { inbound_bytes_per_sec: 500, inbound_messages_per_sec: 1, throttled: false }),
+            rate.0.lock().unwrap().map(|(snapshot, _)| snapshot),
+        );
+
+        // too many bytes are just delayed
+        let now = start + Duration::from_secs(61);
+        assert_eq!(Throttle::Delay(Duration::from_secs(1)), limiter.received(&get_block_headers(1), 3000, now));
+    }
+}
@@ -0,0 +1,595 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Traffic statistics of the peers.
+//!
+//! Every peer counts sent and received messages and bytes per message type in its [PeerStats],
+//! all counters are also added to the [NetworkStats] shared by all peers. Counters are atomic,
+//! so peers do not wait for each other, when they count their messages.
+//! Latency between our requests and responses of the remote peer is measured for
+//! `GetBlockHeaders`→`BlockHeader`, `GetOperationsForBlocks`→`OperationsForBlocks`, `GetCurrentBranch`→`CurrentBranch`
+//! and `GetCurrentHead`→`CurrentHead`. The last one is used by keepalive pings, so the latest of these latencies is
+//! reported as the round-trip time of the peer.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crypto::hash::{BlockHash, ChainId};
+use tezos_messages::p2p::encoding::prelude::*;
+
+/// Upper bounds of the latency histogram buckets in milliseconds, the last bucket is unbounded
+const LATENCY_BUCKETS_MS: [u64; 10] = [10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000];
+/// Requests without response for this long are not measured anymore
+const PENDING_REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+/// Maximal count of requests of one peer waiting for the response
+const MAX_PENDING_REQUESTS: usize = 4096;
+/// Current inflow and outflow are averaged over at least this interval
+const FLOW_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Names of the message types used as keys of the statistics, indexed by [message_type_index]
+const MESSAGE_TYPES: [&str; 20] = [
+    "Disconnect", "Advertise", "SwapRequest", "SwapAck", "Bootstrap", "GetCurrentBranch", "CurrentBranch", "Deactivate",
+    "GetCurrentHead", "CurrentHead", "GetBlockHeaders", "BlockHeader", "GetOperations", "Operation", "GetProtocols",
+    "Protocol", "GetOperationHashesForBlocks", "OperationHashesForBlock", "GetOperationsForBlocks", "OperationsForBlocks",
+];
+/// Names of the request types with measured latency, indexed by [PendingRequest::request_type_index]
+const REQUEST_TYPES: [&str; 4] = ["GetBlockHeaders", "GetOperationsForBlocks", "GetCurrentBranch", "GetCurrentHead"];
+
+fn message_type_index(message: &PeerMessage) -> usize {
+    match message {
+        PeerMessage::Disconnect => 0,
+        PeerMessage::Advertise(_) => 1,
+        PeerMessage::SwapRequest(_) => 2,
+        PeerMessage::SwapAck(_) => 3,
+        PeerMessage::Bootstrap => 4,
+        PeerMessage::GetCurrentBranch(_) => 5,
+        PeerMessage::CurrentBranch(_) => 6,
+        PeerMessage::Deactivate(_) => 7,
+        PeerMessage::GetCurrentHead(_) => 8,
+        PeerMessage::CurrentHead(_) => 9,
+        PeerMessage::GetBlockHeaders(_) => 10,
+        PeerMessage::BlockHeader(_) => 11,
+        PeerMessage::GetOperations(_) => 12,
+        PeerMessage::Operation(_) => 13,
+        PeerMessage::GetProtocols(_) => 14,
+        PeerMessage::Protocol(_) => 15,
+        PeerMessage::GetOperationHashesForBlocks(_) => 16,
+        PeerMessage::OperationHashesForBlock(_) => 17,
+        PeerMessage::GetOperationsForBlocks(_) => 18,
+        PeerMessage::OperationsForBlocks(_) => 19,
+    }
+}
+
+/// Name of the message type used as a key of the statistics
+pub fn message_type(message: &PeerMessage) -> &'static str {
+    MESSAGE_TYPES[message_type_index(message)]
+}
+
+/// Count of messages and their bytes
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct MessageCounter {
+    pub messages: u64,
+    pub bytes: u64,
+}
+
+impl MessageCounter {
+    fn add(&mut self, other: MessageCounter) {
+        self.messages += other.messages;
+        self.bytes += other.bytes;
+    }
+}
+
+/// Histogram of request/response latencies
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct LatencyHistogram {
+    /// Upper bounds of the buckets in milliseconds, the last bucket (not listed here) is unbounded
+    pub buckets_ms: Vec<u64>,
+    /// Count of responses in every bucket, it has one more item than `buckets_ms`
+    pub counts: Vec<u64>,
+    pub count: u64,
+    pub sum_ms: u64,
+}
+
+impl LatencyHistogram {
+    /// Average latency in milliseconds
+    pub fn average_ms(&self) -> Option<u64> {
+        if self.count > 0 {
+            Some(self.sum_ms / self.count)
+        } else {
+            None
+        }
+    }
+}
+
+/// Traffic statistics, counters are keyed by [message type](message_type)
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct TrafficStats {
+    pub total_sent: MessageCounter,
+    pub total_received: MessageCounter,
+    pub sent: BTreeMap<&'static str, MessageCounter>,
+    pub received: BTreeMap<&'static str, MessageCounter>,
+    /// Latencies keyed by request message type
+    pub latency: BTreeMap<&'static str, LatencyHistogram>,
+}
+
+/// Network statistics in the format of the OCaml node `/network/stat`
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct NetworkStat {
+    /// Bytes sent to all peers, 64-bit integer is encoded as a string
+    pub total_sent: String,
+    /// Bytes received from all peers, 64-bit integer is encoded as a string
+    pub total_recv: String,
+    /// Received bytes per second since the previous sample
+    pub current_inflow: u64,
+    /// Sent bytes per second since the previous sample
+    pub current_outflow: u64,
+}
+
+#[derive(Debug, Default)]
+struct AtomicMessageCounter {
+    messages: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl AtomicMessageCounter {
+    fn add(&self, bytes: u64) {
+        self.messages.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn load(&self) -> MessageCounter {
+        MessageCounter { messages: self.messages.load(Ordering::Relaxed), bytes: self.bytes.load(Ordering::Relaxed) }
+    }
+}
+
+#[derive(Debug, Default)]
+struct AtomicLatencyHistogram {
+    /// Count of responses in every bucket, the last bucket is unbounded
+    counts: [AtomicU64; LATENCY_BUCKETS_MS.len() + 1],
+    count: AtomicU64,
+    sum_ms: AtomicU64,
+}
+
+impl AtomicLatencyHistogram {
+    fn add(&self, latency: Duration) {
+        let latency_ms = latency.as_millis() as u64;
+        let bucket = LATENCY_BUCKETS_MS.iter()
+            .position(|bound| latency_ms <= *bound)
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_ms.fetch_add(latency_ms, Ordering::Relaxed);
+    }
+
+    fn load(&self) -> LatencyHistogram {
+        LatencyHistogram {
+            buckets_ms: LATENCY_BUCKETS_MS.to_vec(),
+            counts: self.counts.iter().map(|count| count.load(Ordering::Relaxed)).collect(),
+            count: self.count.load(Ordering::Relaxed),
+            sum_ms: self.sum_ms.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Atomic counters behind the [TrafficStats]
+#[derive(Debug, Default)]
+struct TrafficCounters {
+    sent: [AtomicMessageCounter; MESSAGE_TYPES.len()],
+    received: [AtomicMessageCounter; MESSAGE_TYPES.len()],
+    latency: [AtomicLatencyHistogram; REQUEST_TYPES.len()],
+}
+
+impl TrafficCounters {
+    fn total(counters: &[AtomicMessageCounter]) -> MessageCounter {
+        counters.iter().fold(MessageCounter::default(), |mut total, counter| {
+            total.add(counter.load());
+            total
+        })
+    }
+
+    fn by_message_type(counters: &[AtomicMessageCounter]) -> BTreeMap<&'static str, MessageCounter> {
+        counters.iter()
+            .zip(MESSAGE_TYPES.iter())
+            .map(|(counter, message_type)| (*message_type, counter.load()))
+            .filter(|(_, counter)| counter.messages > 0)
+            .collect()
+    }
+
+    fn snapshot(&self) -> TrafficStats {
+        TrafficStats {
+            total_sent: Self::total(&self.sent),
+            total_received: Self::total(&self.received),
+            sent: Self::by_message_type(&self.sent),
+            received: Self::by_message_type(&self.received),
+            latency: self.latency.iter()
+                .zip(REQUEST_TYPES.iter())
+                .map(|(latency, request_type)| (*request_type, latency.load()))
+                .filter(|(_, latency)| latency.count > 0)
+                .collect(),
+        }
+    }
+}
+
+/// Request, which waits for the response
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum PendingRequest {
+    BlockHeader(BlockHash),
+    OperationsForBlock(BlockHash, i8),
+    CurrentBranch(ChainId),
+    CurrentHead(ChainId),
+}
+
+impl PendingRequest {
+    fn request_type_index(&self) -> usize {
+        match self {
+            PendingRequest::BlockHeader(_) => 0,
+            PendingRequest::OperationsForBlock(..) => 1,
+            PendingRequest::CurrentBranch(_) => 2,
+            PendingRequest::CurrentHead(_) => 3,
+        }
+    }
+
+    /// Requests expecting response, which are contained in the sent message
+    fn requested(message: &PeerMessage) -> Vec<PendingRequest> {
+        match message {
+            PeerMessage::GetBlockHeaders(message) => message.get_block_headers().iter()
+                .map(|hash| PendingRequest::BlockHeader(hash.clone()))
+                .collect(),
+            PeerMessage::GetOperationsForBlocks(message) => message.get_operations_for_blocks().iter()
+                .map(|block| PendingRequest::OperationsForBlock(block.block_hash().clone(), block.validation_pass()))
+                .collect(),
+            PeerMessage::GetCurrentBranch(message) => vec![PendingRequest::CurrentBranch(message.chain_id.clone())],
+            PeerMessage::GetCurrentHead(message) => vec![PendingRequest::CurrentHead(message.chain_id().clone())],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Request answered by the received message, `block_header_hash` is the hash of the received block header
+    fn answered(message: &PeerMessage, block_header_hash: Option<&BlockHash>) -> Option<PendingRequest> {
+        match message {
+            PeerMessage::BlockHeader(_) => block_header_hash.cloned().map(PendingRequest::BlockHeader),
+            PeerMessage::OperationsForBlocks(message) => {
+                let block = message.operations_for_block();
+                Some(PendingRequest::OperationsForBlock(block.block_hash().clone(), block.validation_pass()))
+            }
+            PeerMessage::CurrentBranch(message) => Some(PendingRequest::CurrentBranch(message.chain_id().clone())),
+            PeerMessage::CurrentHead(message) => Some(PendingRequest::CurrentHead(message.chain_id().clone())),
+            _ => None,
+        }
+    }
+}
+
+/// Totals of the previous [NetworkStat] sample, current flows are computed from them
+#[derive(Debug, Default)]
+struct FlowSample {
+    sampled: Option<Instant>,
+    sent_bytes: u64,
+    received_bytes: u64,
+    current_inflow: u64,
+    current_outflow: u64,
+}
+
+/// Statistics of all peers together, including already disconnected peers
+#[derive(Clone, Debug, Default)]
+pub struct NetworkStats {
+    traffic: Arc<TrafficCounters>,
+    flow: Arc<Mutex<FlowSample>>,
+}
+
+impl NetworkStats {
+    pub fn snapshot(&self) -> TrafficStats {
+        self.traffic.snapshot()
+    }
+
+    /// Total and current traffic at `now`, current flows are averaged since the previous sample,
+    /// which is taken at most once per [FLOW_SAMPLE_INTERVAL]
+    pub fn stat_at(&self, now: Instant) -> NetworkStat {
+        let sent_bytes = TrafficCounters::total(&self.traffic.sent).bytes;
+        let received_bytes = TrafficCounters::total(&self.traffic.received).bytes;
+
+        let mut flow = self.flow.lock().unwrap();
+        let elapsed = flow.sampled.map(|sampled| now.saturating_duration_since(sampled));
+        if elapsed.map_or(true, |elapsed| elapsed >= FLOW_SAMPLE_INTERVAL) {
+            if let Some(elapsed) = elapsed {
+                let elapsed = elapsed.as_secs_f64();
+                flow.current_inflow = (received_bytes.saturating_sub(flow.received_bytes) as f64 / elapsed) as u64;
+                flow.current_outflow = (sent_bytes.saturating_sub(flow.sent_bytes) as f64 / elapsed) as u64;
+            }
+            flow.sampled = Some(now);
+            flow.sent_bytes = sent_bytes;
+            flow.received_bytes = received_bytes;
+        }
+
+        NetworkStat {
+            total_sent: sent_bytes.to_string(),
+            total_recv: received_bytes.to_string(),
+            current_inflow: flow.current_inflow,
+            current_outflow: flow.current_outflow,
+        }
+    }
+}
+
+/// State of the peer used to measure latencies, it is locked only by the peer itself and its monitors
+#[derive(Debug, Default)]
+struct PeerStatsInner {
+    pending: HashMap<PendingRequest, Instant>,
+    /// When the last message was received from the peer or when the connection was established
+    last_received: Option<Instant>,
+    /// Latest measured `GetCurrentHead`→`CurrentHead` latency
+    rtt: Option<Duration>,
+}
+
+/// Statistics of one peer, shared by the peer actor and monitors
+#[derive(Clone, Debug, Default)]
+pub struct PeerStats {
+    traffic: Arc<TrafficCounters>,
+    inner: Arc<Mutex<PeerStatsInner>>,
+    network: NetworkStats,
+}
+
+impl PeerStats {
+    pub fn new(network: &NetworkStats) -> Self {
+        PeerStats { traffic: Arc::default(), inner: Arc::default(), network: network.clone() }
+    }
+
+    /// Connection with the peer was established, idle time is measured from now until something is received
+    pub fn connected(&self, now: Instant) {
+        self.inner.lock().unwrap().last_received = Some(now);
+    }
+
+    pub fn snapshot(&self) -> TrafficStats {
+        self.traffic.snapshot()
+    }
+
+    /// Round-trip time of the last answered `GetCurrentHead`
+    pub fn rtt(&self) -> Option<Duration> {
+        self.inner.lock().unwrap().rtt
+    }
+
+    /// How long nothing was received from the peer, `None` if the peer is not connected yet
+    pub fn idle(&self, now: Instant) -> Option<Duration> {
+        self.inner.lock().unwrap().last_received
+            .map(|last_received| now.saturating_duration_since(last_received))
+    }
+
+    /// Count message sent to the peer at `now`, `bytes` is the size of the whole response
+    pub fn sent_at(&self, response: &PeerMessageResponse, bytes: u64, now: Instant) {
+        let bytes = bytes_per_message(response, bytes);
+        for message in response.messages() {
+            let message_type = message_type_index(message);
+            self.traffic.sent[message_type].add(bytes);
+            self.network.traffic.sent[message_type].add(bytes);
+
+            let requested = PendingRequest::requested(message);
+            if !requested.is_empty() {
+                let mut inner = self.inner.lock().unwrap();
+                inner.pending.retain(|_, requested_at| now.duration_since(*requested_at) < PENDING_REQUEST_TIMEOUT);
+                for request in requested {
+                    if inner.pending.len() >= MAX_PENDING_REQUESTS {
+                        break;
+                    }
+                    inner.pending.insert(request, now);
+                }
+            }
+        }
+    }
+
+    /// Count message received from the peer at `now`, `bytes` is the size of the whole response.
+    ///
+    /// `block_header_hashes` are hashes of the received block headers in the order of `BlockHeader` messages
+    /// of the response, see [PeerMessageReceived](super::network_channel::PeerMessageReceived).
+    pub fn received_at(&self, response: &PeerMessageResponse, block_header_hashes: &[BlockHash], bytes: u64, now: Instant) {
+        let bytes = bytes_per_message(response, bytes);
+        let mut block_header_hashes = block_header_hashes.iter();
+        let mut inner = self.inner.lock().unwrap();
+        inner.last_received = Some(now);
+        for message in response.messages() {
+            let message_type = message_type_index(message);
+            self.traffic.received[message_type].add(bytes);
+            self.network.traffic.received[message_type].add(bytes);
+
+            let block_header_hash = match message {
+                PeerMessage::BlockHeader(_) => block_header_hashes.next(),
+                _ => None,
+            };
+            if inner.pending.is_empty() {
+                continue;
+            }
+            if let Some(request) = PendingRequest::answered(message, block_header_hash) {
+                if let Some(requested_at) = inner.pending.remove(&request) {
+                    let latency = now.duration_since(requested_at);
+                    if let PendingRequest::CurrentHead(_) = request {
+                        inner.rtt = Some(latency);
+                    }
+                    self.traffic.latency[request.request_type_index()].add(latency);
+                    self.network.traffic.latency[request.request_type_index()].add(latency);
+                }
+            }
+        }
+    }
+}
+
+/// Bytes of the response are divided between its messages
+fn bytes_per_message(response: &PeerMessageResponse, bytes: u64) -> u64 {
+    bytes / (response.messages().len().max(1) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_count_messages_and_latency() {
+        let network = NetworkStats::default();
+        let peer = PeerStats::new(&network);
+        let chain_id = vec![1, 2, 3, 4];
+        let now = Instant::now();
+
+        let request: PeerMessageResponse = PeerMessage::GetCurrentBranch(GetCurrentBranchMessage::new(chain_id.clone())).into();
+        peer.sent_at(&request, 20, now);
+        peer.sent_at(&request, 20, now);
+        let head = BlockHeaderBuilder::default()
+            .level(1)
+            .proto(1)
+            .predecessor(vec![0; 32])
+            .timestamp(0)
+            .validation_pass(0)
+            .operations_hash(vec![0; 32])
+            .fitness(vec![])
+            .context(vec![0; 32])
+            .protocol_data(vec![])
+            .build()
+            .unwrap();
+        let response: PeerMessageResponse = PeerMessage::CurrentBranch(CurrentBranchMessage::new(chain_id, CurrentBranch::new(head, vec![]))).into();
+        peer.received_at(&response, &[], 200, now + Duration::from_millis(30));
+
+        let stats = peer.snapshot();
+        assert_eq!(MessageCounter { messages: 2, bytes: 40 }, stats.sent["GetCurrentBranch"]);
+        assert_eq!(MessageCounter { messages: 1, bytes: 200 }, stats.received["CurrentBranch"]);
+        assert_eq!(MessageCounter { messages: 1, bytes: 200 }, stats.total_received);
+        let latency = &stats.latency["GetCurrentBranch"];
+        assert_eq!(1, latency.count);
+        assert_eq!(Some(30), latency.average_ms());
+        // 30ms falls to the bucket up to 50ms
+        assert_eq!(1, latency.counts[2]);
+
+        // the same response again is not measured
+        peer.received_at(&response, &[], 200, now + Duration::from_millis(40));
+        assert_eq!(1, peer.snapshot().latency["GetCurrentBranch"].count);
+
+        // network statistics sum all peers
+        let other_peer = PeerStats::new(&network);
+        other_peer.received_at(&PeerMessage::Bootstrap.into(), &[], 10, now);
+        let network_stats = network.snapshot();
+        assert_eq!(MessageCounter { messages: 3, bytes: 410 }, network_stats.total_received);
+        assert_eq!(1, network_stats.latency["GetCurrentBranch"].count);
+    }
+
+    #[test]
+    fn test_ping_rtt_and_idle() {
+        let peer = PeerStats::new(&NetworkStats::default());
+        let chain_id = vec![1, 2, 3, 4];
+        let now = Instant::now();
+        assert_eq!(None, peer.idle(now));
+        assert_eq!(None, peer.rtt());
+        peer.connected(now);
+        assert_eq!(Some(Duration::from_secs(1)), peer.idle(now + Duration::from_secs(1)));
+
+        peer.sent_at(&PeerMessage::GetCurrentHead(GetCurrentHeadMessage::new(chain_id.clone())).into(), 20, now);
+        let head = BlockHeaderBuilder::default()
+            .level(1)
+            .proto(1)
+            .predecessor(vec![0; 32])
+            .timestamp(0)
+            .validation_pass(0)
+            .operations_hash(vec![0; 32])
+            .fitness(vec![])
+            .context(vec![0; 32])
+            .protocol_data(vec![])
+            .build()
+            .unwrap();
+        let response: PeerMessageResponse = PeerMessage::CurrentHead(CurrentHeadMessage::new(chain_id, head, Mempool::default())).into();
+        peer.received_at(&response, &[], 200, now + Duration::from_millis(80));
+
+        assert_eq!(Some(Duration::from_millis(80)), peer.rtt());
+        assert_eq!(1, peer.snapshot().latency["GetCurrentHead"].count);
+        assert_eq!(Some(Duration::from_secs(5)), peer.idle(now + Duration::from_millis(5080)));
+    }
+
+    fn block_header(level: i32) -> BlockHeader {
+        BlockHeaderBuilder::default()
+            .level(level)
+            .proto(1)
+            .predecessor(vec![0; 32])
+            .timestamp(0)
+            .validation_pass(0)
+            .operations_hash(vec![0; 32])
+            .fitness(vec![])
+            .context(vec![0; 32])
+            .protocol_data(vec![])
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_block_header_latency_is_measured_by_received_hash() {
+        let peer = PeerStats::new(&NetworkStats::default());
+        let now = Instant::now();
+        let (requested_hash, other_hash) = (vec![1; 32], vec![2; 32]);
+        peer.sent_at(&PeerMessage::GetBlockHeaders(GetBlockHeadersMessage::new(vec![requested_hash.clone()])).into(), 40, now);
+
+        let response: PeerMessageResponse = PeerMessage::BlockHeader(block_header(1).into()).into();
+        // header, which was not requested, is counted, but not measured
+        peer.received_at(&response, &[other_hash], 100, now + Duration::from_millis(10));
+        // header without known hash is not measured
+        peer.received_at(&response, &[], 100, now + Duration::from_millis(10));
+        assert!(!peer.snapshot().latency.contains_key("GetBlockHeaders"));
+
+        peer.received_at(&response, &[requested_hash], 100, now + Duration::from_millis(20));
+        let stats = peer.snapshot();
+        assert_eq!(MessageCounter { messages: 3, bytes: 300 }, stats.received["BlockHeader"]);
+        assert_eq!(Some(20), stats.latency["GetBlockHeaders"].average_ms());
+    }
+
+    #[test]
+    fn test_network_stat_in_ocaml_format() {
+        let network = NetworkStats::default();
+        let peer = PeerStats::new(&network);
+        let now = Instant::now();
+
+        peer.sent_at(&PeerMessage::Bootstrap.into(), 100, now);
+        peer.received_at(&PeerMessage::Bootstrap.into(), &[], 1_000, now);
+        let stat = network.stat_at(now);
+        assert_eq!("100", stat.total_sent);
+        assert_eq!("1000", stat.total_recv);
+        // flow is not known until the second sample
+        assert_eq!(0, stat.current_inflow);
+
+        peer.sent_at(&PeerMessage::Bootstrap.into(), 200, now);
+        peer.received_at(&PeerMessage::Bootstrap.into(), &[], 4_000, now);
+        // sample is not taken more often than once per interval
+        assert_eq!(0, network.stat_at(now + Duration::from_millis(500)).current_inflow);
+        let stat = network.stat_at(now + Duration::from_secs(2));
+        assert_eq!("300", stat.total_sent);
+        assert_eq!("5000", stat.total_recv);
+        assert_eq!(2_000, stat.current_inflow);
+        assert_eq!(100, stat.current_outflow);
+
+        // nothing transferred since the last sample
+        let stat = network.stat_at(now + Duration::from_secs(4));
+        assert_eq!(0, stat.current_inflow);
+        assert_eq!(0, stat.current_outflow);
+    }
+
+    #[test]
+    fn test_concurrent_peers_are_counted() {
+        let network = NetworkStats::default();
+        let peers = (0..8)
+            .map(|_| {
+                let peer = PeerStats::new(&network);
+                std::thread::spawn(move || {
+                    let now = Instant::now();
+                    for _ in 0..1000 {
+                        peer.sent_at(&PeerMessage::Bootstrap.into(), 10, now);
+                        peer.received_at(&PeerMessage::Bootstrap.into(), &[], 20, now);
+                    }
+                    peer
+                })
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect::<Vec<_>>();
+
+        for peer in peers {
+            assert_eq!(MessageCounter { messages: 1000, bytes: 10_000 }, peer.snapshot().total_sent);
+        }
+        let stats = network.snapshot();
+        assert_eq!(MessageCounter { messages: 8000, bytes: 80_000 }, stats.total_sent);
+        assert_eq!(MessageCounter { messages: 8000, bytes: 160_000 }, stats.received["Bootstrap"]);
+    }
+}
@@ -4,6 +4,11 @@
 //! This module encapsulates p2p communication between peers.
 //!
 //! It provides message packaging from/to binary format, encryption, message nonce handling.
+//! Decrypted chunks can be captured for debugging (see [capture](crate::p2p::capture)).
+//!
+//! Encrypted streams reuse their buffers and encrypt and decrypt chunks in place, so reading or writing a message
+//! does not allocate per chunk. Writer can [queue](EncryptedMessageWriter::encrypt_message) several messages,
+//! which are then [written](EncryptedMessageWriter::flush) to the socket at once.
 
 use std::convert::TryInto;
 use std::io;
@@ -12,19 +17,27 @@ use bytes::Buf;
 use failure::{Error, Fail};
 use failure::_core::time::Duration;
 use slog::{FnValue, Logger, o, trace};
-use tokio::io::{ReadHalf, WriteHalf};
+use tokio::io::{BufReader, ReadHalf, WriteHalf};
 use tokio::net::TcpStream;
 use tokio::prelude::*;
 
-use crypto::crypto_box::{CryptoError, decrypt, encrypt, PrecomputedKey};
+use crypto::crypto_box::{BOX_MAC_BYTES, CryptoError, decrypt_in_place, encrypt_in_place, PrecomputedKey};
 use crypto::nonce::Nonce;
 use tezos_encoding::binary_reader::BinaryReaderError;
 use tezos_messages::p2p::binary_message::{BinaryChunk, BinaryChunkError, BinaryMessage, CONTENT_LENGTH_FIELD_BYTES};
+use tezos_messages::p2p::encoding::limits::MESSAGE_MAX_SIZE;
 
+use crate::p2p::capture::{ConnectionCapture, Direction};
 use crate::p2p::peer::PeerId;
 
 /// Max allowed content length in bytes when taking into account extra data added by encryption
 pub const CONTENT_LENGTH_MAX: usize = tezos_messages::p2p::binary_message::CONTENT_LENGTH_MAX - crypto::crypto_box::BOX_ZERO_BYTES;
+/// Size of the chunk header, which precedes the encrypted content: content length and authentication tag
+const CHUNK_HEADER_BYTES: usize = CONTENT_LENGTH_FIELD_BYTES + BOX_MAC_BYTES;
+/// Size of the read buffer of the network stream
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+/// Message buffers which grew over this capacity (because of some large message) are released after use
+const BUFFER_RETAIN_CAPACITY: usize = 1024 * 1024;
 
 /// This is common error that might happen when communicating with peer over the network.
 #[derive(Debug, Fail)]
@@ -45,6 +58,11 @@ pub enum StreamError {
     DeserializationError {
         error: BinaryReaderError
     },
+    #[fail(display = "Message is too large, size: {}, max: {}", size, max)]
+    MessageTooLarge {
+        size: usize,
+        max: usize,
+    },
     #[fail(display = "Network error: {}, cause: {}", message, error)]
     NetworkError {
         message: &'static str,
@@ -96,7 +114,7 @@ impl MessageStream {
 
         let (rx, tx) = tokio::io::split(stream);
         MessageStream {
-            reader: MessageReader { stream: rx },
+            reader: MessageReader { stream: BufReader::with_capacity(READ_BUFFER_SIZE, rx) },
             writer: MessageWriter { stream: tx },
         }
     }
@@ -116,7 +134,7 @@ impl From<TcpStream> for MessageStream {
 /// Reader of the TCP/IP connection.
 pub struct MessageReader {
     /// reader part or the TCP/IP network stream
-    stream: ReadHalf<TcpStream>
+    stream: BufReader<ReadHalf<TcpStream>>
 }
 
 impl MessageReader {
@@ -163,6 +181,11 @@ impl MessageWriter {
     pub async fn write_message(&mut self, bytes: &BinaryChunk) -> Result<(), StreamError> {
         Ok(self.stream.write_all(bytes.raw()).await?)
     }
+
+    #[inline]
+    async fn write_all(&mut self, bytes: &[u8]) -> Result<(), StreamError> {
+        Ok(self.stream.write_all(bytes).await?)
+    }
 }
 
 /// The `EncryptedMessageWriter` encapsulates process of the encrypted outgoing message transmission.
@@ -175,35 +198,113 @@ pub struct EncryptedMessageWriter {
     nonce_local: Nonce,
     /// Outgoing message writer
     tx: MessageWriter,
+    /// Encrypted chunks waiting to be written to the network stream
+    buffer: Vec<u8>,
+    /// Count of all bytes written to the network stream
+    bytes_written: u64,
+    /// Captures chunks before encryption, if enabled
+    capture: Option<ConnectionCapture>,
     /// Logger
     log: Logger,
 }
 
 impl EncryptedMessageWriter {
-    pub fn new(tx: MessageWriter, precomputed_key: PrecomputedKey, nonce_local: Nonce, peer_id: PeerId, log: Logger) -> Self {
+    pub fn new(tx: MessageWriter, precomputed_key: PrecomputedKey, nonce_local: Nonce, peer_id: PeerId, capture: Option<ConnectionCapture>, log: Logger) -> Self {
         let log = log.new(o!("peer" => peer_id));
-        EncryptedMessageWriter { tx, precomputed_key, nonce_local, log }
+        EncryptedMessageWriter { tx, precomputed_key, nonce_local, buffer: Vec::new(), bytes_written: 0, capture, log }
     }
 
+    /// Encrypt and write message to the network stream, together with already [queued](Self::encrypt_message) messages
     pub async fn write_message<'a>(&'a mut self, message: &'a impl BinaryMessage) -> Result<(), StreamError> {
-        let message_bytes = message.as_bytes()?;
-        trace!(self.log, "Writing message"; "message" => FnValue(|_| hex::encode(&message_bytes)));
-
-        for chunk_content_bytes in message_bytes.chunks(CONTENT_LENGTH_MAX) {
-            // encrypt
-            let message_bytes_encrypted = match encrypt(chunk_content_bytes, &self.nonce_fetch_increment(), &self.precomputed_key) {
-                Ok(msg) => msg,
-                Err(error) => return Err(StreamError::FailedToEncryptMessage { error })
-            };
-
-            // send
-            let chunk = BinaryChunk::from_content(&message_bytes_encrypted)?;
-            self.tx.write_message(&chunk).await?;
+        self.encrypt_message(message)?;
+        self.flush().await
+    }
+
+    /// Encrypt message to the internal buffer, it is written to the network stream by the next [flush](Self::flush).
+    ///
+    /// Returns count of bytes, which will be written to the network stream for this message.
+    pub fn encrypt_message(&mut self, message: &impl BinaryMessage) -> Result<usize, StreamError> {
+        let message_start = self.buffer.len();
+        match self.encode_and_encrypt(message, message_start) {
+            Ok(()) => Ok(self.buffer.len() - message_start),
+            Err(e) => {
+                self.buffer.truncate(message_start);
+                Err(e)
+            }
         }
+    }
 
+    /// Encode message straight into the buffer after the header of the first chunk and encrypt its chunks in place.
+    fn encode_and_encrypt(&mut self, message: &impl BinaryMessage, message_start: usize) -> Result<(), StreamError> {
+        let content_start = message_start + CHUNK_HEADER_BYTES;
+        self.buffer.resize(content_start, 0);
+        message.write_bytes(&mut self.buffer)?;
+        trace!(self.log, "Writing message"; "message" => FnValue(|_| hex::encode(&self.buffer[content_start..])));
+
+        // content over the first chunk is moved out and appended back chunk by chunk, each behind its own header
+        let rest = if self.buffer.len() - content_start > CONTENT_LENGTH_MAX {
+            self.buffer.split_off(content_start + CONTENT_LENGTH_MAX)
+        } else {
+            Vec::new()
+        };
+
+        self.encrypt_chunk(message_start)?;
+        for chunk_content_bytes in rest.chunks(CONTENT_LENGTH_MAX) {
+            let chunk_start = self.buffer.len();
+            self.buffer.extend_from_slice(&[0; CHUNK_HEADER_BYTES]);
+            self.buffer.extend_from_slice(chunk_content_bytes);
+            self.encrypt_chunk(chunk_start)?;
+        }
         Ok(())
     }
 
+    /// Encrypt the last chunk in the buffer, which starts at `chunk_start` with the space reserved for its header.
+    ///
+    /// Chunk is [length][authentication tag][encrypted content], content is encrypted directly in the buffer.
+    fn encrypt_chunk(&mut self, chunk_start: usize) -> Result<(), StreamError> {
+        let tag_start = chunk_start + CONTENT_LENGTH_FIELD_BYTES;
+        let content_start = tag_start + BOX_MAC_BYTES;
+        if let Some(capture) = &self.capture {
+            capture.record(Direction::Outgoing, &self.buffer[content_start..]);
+        }
+
+        let chunk_length = (self.buffer.len() - tag_start) as u16;
+        self.buffer[chunk_start..tag_start].copy_from_slice(&chunk_length.to_be_bytes());
+
+        let nonce = self.nonce_fetch_increment();
+        let tag = encrypt_in_place(&mut self.buffer[content_start..], &nonce, &self.precomputed_key)
+            .map_err(|error| StreamError::FailedToEncryptMessage { error })?;
+        self.buffer[tag_start..content_start].copy_from_slice(&tag);
+        Ok(())
+    }
+
+    /// Write all encrypted messages to the network stream
+    pub async fn flush(&mut self) -> Result<(), StreamError> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+
+        self.tx.write_all(&self.buffer).await?;
+        self.bytes_written += self.buffer.len() as u64;
+        self.buffer.clear();
+        if self.buffer.capacity() > BUFFER_RETAIN_CAPACITY {
+            self.buffer = Vec::new();
+        }
+        Ok(())
+    }
+
+    /// Count of bytes of encrypted messages, which were not written to the network stream yet
+    #[inline]
+    pub fn pending_bytes(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Count of all bytes written to the network stream
+    #[inline]
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
     #[inline]
     fn nonce_fetch_increment(&mut self) -> Nonce {
         let incremented = self.nonce_local.increment();
@@ -221,15 +322,21 @@ pub struct EncryptedMessageReader {
     nonce_remote: Nonce,
     /// Incoming message reader
     rx: MessageReader,
+    /// Decrypted content of the chunks of the message being read
+    buffer: Vec<u8>,
+    /// Count of all bytes read from the network stream
+    bytes_read: u64,
+    /// Captures chunks after decryption, if enabled
+    capture: Option<ConnectionCapture>,
     /// Logger
     log: Logger,
 }
 
 impl EncryptedMessageReader {
     /// Create new encrypted message from async reader and peer data
-    pub fn new(rx: MessageReader, precomputed_key: PrecomputedKey, nonce_remote: Nonce, peer_id: PeerId, log: Logger) -> Self {
+    pub fn new(rx: MessageReader, precomputed_key: PrecomputedKey, nonce_remote: Nonce, peer_id: PeerId, capture: Option<ConnectionCapture>, log: Logger) -> Self {
         let log = log.new(o!("peer" => peer_id));
-        EncryptedMessageReader { rx, precomputed_key, nonce_remote, log }
+        EncryptedMessageReader { rx, precomputed_key, nonce_remote, buffer: Vec::new(), bytes_read: 0, capture, log }
     }
 
     /// Consume content of inner message reader into specific message
@@ -238,37 +345,62 @@ impl EncryptedMessageReader {
             M: BinaryMessage
     {
         let mut input_remaining = 0;
-        let mut input_data = vec![];
+        self.buffer.clear();
 
-        loop {
+        let result = loop {
             // read
-            let message_encrypted = self.rx.read_message().await?;
+            let chunk_length = (&self.rx.read_message_length_bytes().await?[..]).get_u16() as usize;
+            if chunk_length < BOX_MAC_BYTES {
+                break Err(StreamError::FailedToDecryptMessage { error: CryptoError::FailedToDecrypt });
+            }
+            let mut tag = [0; BOX_MAC_BYTES];
+            self.rx.stream.read_exact(&mut tag).await?;
+            // encrypted content is read directly behind the already decrypted chunks
+            let chunk_start = self.buffer.len();
+            let message_size = chunk_start + chunk_length - BOX_MAC_BYTES;
+            if message_size > MESSAGE_MAX_SIZE {
+                break Err(StreamError::MessageTooLarge { size: message_size, max: MESSAGE_MAX_SIZE });
+            }
+            self.buffer.resize(message_size, 0);
+            self.rx.stream.read_exact(&mut self.buffer[chunk_start..]).await?;
+            self.bytes_read += (CONTENT_LENGTH_FIELD_BYTES + chunk_length) as u64;
 
             // decrypt
-            match decrypt(message_encrypted.content(), &self.nonce_fetch_increment(), &self.precomputed_key) {
-                Ok(mut message_decrypted) => {
-                    trace!(self.log, "Message received"; "message" => FnValue(|_| hex::encode(&message_decrypted)));
-                    if input_remaining >= message_decrypted.len() {
-                        input_remaining -= message_decrypted.len();
-                    } else {
-                        input_remaining = 0;
-                    }
-
-                    input_data.append(&mut message_decrypted);
-
-                    if input_remaining == 0 {
-                        match M::from_bytes(&input_data) {
-                            Ok(message) => break Ok(message),
-                            Err(BinaryReaderError::Underflow { bytes }) => input_remaining += bytes,
-                            Err(e) => break Err(e.into()),
-                        }
-                    }
-                }
-                Err(error) => {
-                    break Err(StreamError::FailedToDecryptMessage { error });
+            let nonce = self.nonce_fetch_increment();
+            if let Err(error) = decrypt_in_place(&mut self.buffer[chunk_start..], &tag, &nonce, &self.precomputed_key) {
+                break Err(StreamError::FailedToDecryptMessage { error });
+            }
+
+            let message_decrypted = &self.buffer[chunk_start..];
+            trace!(self.log, "Message received"; "message" => FnValue(|_| hex::encode(message_decrypted)));
+            if let Some(capture) = &self.capture {
+                capture.record(Direction::Incoming, message_decrypted);
+            }
+            if input_remaining >= message_decrypted.len() {
+                input_remaining -= message_decrypted.len();
+            } else {
+                input_remaining = 0;
+            }
+
+            if input_remaining == 0 {
+                match M::from_bytes(&self.buffer) {
+                    Ok(message) => break Ok(message),
+                    Err(BinaryReaderError::Underflow { bytes }) => input_remaining += bytes,
+                    Err(e) => break Err(e.into()),
                 }
             }
+        };
+
+        if self.buffer.capacity() > BUFFER_RETAIN_CAPACITY {
+            self.buffer = Vec::new();
         }
+        result
+    }
+
+    /// Count of all bytes read from the network stream
+    #[inline]
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
     }
 
     #[inline]
@@ -278,6 +410,67 @@ impl EncryptedMessageReader {
     }
 
     pub fn unsplit(self, tx: EncryptedMessageWriter) -> TcpStream {
-        self.rx.stream.unsplit(tx.tx.stream)
+        self.rx.stream.into_inner().unsplit(tx.tx.stream)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use hex::FromHex;
+    use slog::Discard;
+    use tokio::net::TcpListener;
+
+    use tezos_messages::p2p::encoding::prelude::*;
+
+    use super::*;
+
+    const PRECOMPUTED_KEY: &str = "5228751a6f5a6494e38e1042f578e3a64ae3462b7899356f49e50be846c9609c";
+    const NONCE: &str = "8dde158c55cff52f4be9352787d333e616a67853640d72c5";
+
+    /// Writer and reader of the same encrypted connection
+    async fn connect_encrypted() -> (EncryptedMessageWriter, EncryptedMessageReader) {
+        let mut listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (outgoing, incoming) = futures::join!(TcpStream::connect(address), listener.accept());
+        let (_, tx) = MessageStream::from(outgoing.unwrap()).split();
+        let (rx, _) = MessageStream::from(incoming.unwrap().0).split();
+
+        let precomputed_key = PrecomputedKey::from_hex(PRECOMPUTED_KEY).unwrap();
+        let nonce = Nonce::new(&hex::decode(NONCE).unwrap());
+        let log = Logger::root(Discard, o!());
+        (
+            EncryptedMessageWriter::new(tx, precomputed_key.clone(), nonce.clone(), "writer".to_string(), None, log.clone()),
+            EncryptedMessageReader::new(rx, precomputed_key, nonce, "reader".to_string(), None, log),
+        )
+    }
+
+    /// `OperationsForBlocks` response, whose encoded size exceeds `size`
+    fn operations_for_blocks_larger_than(size: usize) -> PeerMessageResponse {
+        let operation = Operation::from_bytes(vec![1u8; 32 + 1024]).unwrap();
+        let operations = vec![operation; size / 1024 + 1];
+        PeerMessage::OperationsForBlocks(OperationsForBlocksMessage::new(OperationsForBlock::new(vec![2u8; 32], 4), Path::Op, operations)).into()
+    }
+
+    #[test]
+    fn test_read_message_rejects_too_large_message() {
+        let mut runtime = tokio::runtime::Builder::new().basic_scheduler().enable_all().build().unwrap();
+        runtime.block_on(async {
+            let (mut tx, mut rx) = connect_encrypted().await;
+            let message = operations_for_blocks_larger_than(MESSAGE_MAX_SIZE);
+            // writer is dropped together with the runtime, it does not need to finish the message
+            tokio::spawn(async move {
+                let _ = tx.write_message(&message).await;
+            });
+
+            match rx.read_message::<PeerMessageResponse>().await {
+                Err(StreamError::MessageTooLarge { size, max }) => {
+                    assert_eq!(MESSAGE_MAX_SIZE, max);
+                    assert!(size > max);
+                }
+                result => panic!("Expected MessageTooLarge, got: {:?}", result.map(|_| ())),
+            }
+        });
+    }
+}
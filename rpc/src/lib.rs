@@ -104,6 +104,14 @@ pub(crate) fn not_found() -> ServiceResult {
         .body(Body::from("not found"))?)
 }
 
+/// Generate 501 response for the services, which are not supported (yet)
+pub(crate) fn not_implemented(message: &str) -> ServiceResult {
+    Ok(Response::builder()
+        .status(StatusCode::from_u16(501)?)
+        .header(hyper::header::CONTENT_TYPE, "text/plain")
+        .body(Body::from(message.to_string()))?)
+}
+
 /// Generate 500 error
 pub(crate) fn error(error: failure::Error) -> ServiceResult {
     Ok(Response::builder()
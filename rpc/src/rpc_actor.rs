@@ -2,14 +2,18 @@
 // SPDX-License-Identifier: MIT
 
 use std::net::SocketAddr;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 
+use futures::channel::oneshot;
 use getset::{CopyGetters, Getters, Setters};
 use riker::actors::*;
-use slog::{Logger, warn};
+use slog::{info, Logger, warn};
 use tokio::runtime::Handle;
 
 use crypto::hash::ChainId;
+use networking::p2p::stats::NetworkStats;
+use shell::chain_registry::ChainRegistryRef;
+use shell::shutdown::WorkerStatus;
 use shell::shell_channel::{BlockApplied, CurrentMempoolState, ShellChannelMsg, ShellChannelRef, ShellChannelTopic};
 use storage::persistent::PersistentStorage;
 use storage::StorageInitInfo;
@@ -42,17 +46,25 @@ pub struct RpcCollectedState {
     is_sandbox: bool,
 }
 
+/// Signals the HTTP server to stop accepting new requests
+type ShutdownSignal = Arc<Mutex<Option<oneshot::Sender<()>>>>;
+
 /// Actor responsible for managing HTTP REST API and server, and to share parts of inner actor
 /// system with the server.
 #[actor(ShellChannelMsg)]
 pub struct RpcServer {
     shell_channel: ShellChannelRef,
     state: RpcCollectedStateRef,
+    shutdown_signal: ShutdownSignal,
 }
 
 impl RpcServer {
     pub fn name() -> &'static str { "rpc-server" }
 
+    /// Create new actor instance and spawn the HTTP server.
+    ///
+    /// Server stops accepting requests on [ShuttingDown](shell::shell_channel::ShuttingDown), `rpc_server_status` is set as finished,
+    /// when the pending requests are served.
     pub fn actor(
         sys: &ActorSystem,
         shell_channel: ShellChannelRef,
@@ -64,8 +76,11 @@ impl RpcServer {
         tezos_without_context_api: Arc<TezosApiConnectionPool>,
         tezos_env: TezosEnvironmentConfiguration,
         network_version: NetworkVersion,
+        network_stats: NetworkStats,
         init_storage_data: &StorageInitInfo,
-        is_sandbox: bool) -> Result<RpcServerRef, CreateError> {
+        chain_registry: ChainRegistryRef,
+        is_sandbox: bool,
+        rpc_server_status: WorkerStatus) -> Result<RpcServerRef, CreateError> {
         let shared_state = Arc::new(RwLock::new(RpcCollectedState {
             current_head: load_current_head(persistent_storage, &init_storage_data.chain_id, &sys.log()),
             chain_id: init_storage_data.chain_id.clone(),
@@ -73,9 +88,10 @@ impl RpcServer {
             head_update_time: current_time_timestamp(),
             is_sandbox,
         }));
+        let (shutdown_sender, shutdown_receiver) = oneshot::channel();
         let actor_ref = sys.actor_of_props::<RpcServer>(
             Self::name(),
-            Props::new_args((shell_channel.clone(), shared_state.clone())),
+            Props::new_args((shell_channel.clone(), shared_state.clone(), Arc::new(Mutex::new(Some(shutdown_sender))))),
         )?;
 
         // spawn RPC JSON server
@@ -86,20 +102,27 @@ impl RpcServer {
                 shell_channel,
                 tezos_env,
                 network_version,
+                network_stats,
                 persistent_storage,
                 tezos_readonly_api,
                 tezos_readonly_prevalidation_api,
                 tezos_without_context_api,
                 &init_storage_data.genesis_block_header_hash,
                 shared_state,
+                chain_registry,
                 &sys.log(),
             );
             let inner_log = sys.log();
 
             tokio_executor.spawn(async move {
-                if let Err(e) = spawn_server(&rpc_listen_address, env).await {
-                    warn!(inner_log, "HTTP Server encountered failure"; "error" => format!("{}", e));
+                let shutdown = async {
+                    let _ = shutdown_receiver.await;
+                };
+                match spawn_server(&rpc_listen_address, env, shutdown).await {
+                    Ok(()) => info!(inner_log, "HTTP Server stopped"),
+                    Err(e) => warn!(inner_log, "HTTP Server encountered failure"; "error" => format!("{}", e)),
                 }
+                rpc_server_status.set_finished();
             });
         }
 
@@ -107,9 +130,9 @@ impl RpcServer {
     }
 }
 
-impl ActorFactoryArgs<(ShellChannelRef, RpcCollectedStateRef)> for RpcServer {
-    fn create_args((shell_channel, state): (ShellChannelRef, RpcCollectedStateRef)) -> Self {
-        Self { shell_channel, state }
+impl ActorFactoryArgs<(ShellChannelRef, RpcCollectedStateRef, ShutdownSignal)> for RpcServer {
+    fn create_args((shell_channel, state, shutdown_signal): (ShellChannelRef, RpcCollectedStateRef, ShutdownSignal)) -> Self {
+        Self { shell_channel, state, shutdown_signal }
     }
 }
 
@@ -121,6 +144,10 @@ impl Actor for RpcServer {
             actor: Box::new(ctx.myself()),
             topic: ShellChannelTopic::ShellEvents.into(),
         }, ctx.myself().into());
+        self.shell_channel.tell(Subscribe {
+            actor: Box::new(ctx.myself()),
+            topic: ShellChannelTopic::ShellCommands.into(),
+        }, ctx.myself().into());
     }
 
     fn recv(&mut self, ctx: &Context<Self::Msg>, msg: Self::Msg, sender: Option<BasicActorRef>) {
@@ -142,6 +169,11 @@ impl Receive<ShellChannelMsg> for RpcServer {
                 let current_state = &mut *self.state.write().unwrap();
                 current_state.current_mempool_state = Some(result);
             }
+            ShellChannelMsg::ShuttingDown(_) => {
+                if let Some(shutdown_signal) = self.shutdown_signal.lock().unwrap().take() {
+                    let _ = shutdown_signal.send(());
+                }
+            }
             _ => (/* Not yet implemented, do nothing */),
         }
     }
@@ -6,7 +6,7 @@ use slog::warn;
 
 use crate::{empty, make_json_response, result_to_json_response, ServiceResult, unwrap_block_hash};
 use crate::server::{HasSingleValue, Params, Query, RpcServiceEnvironment};
-use crate::services::base_services;
+use crate::services::{base_services, network_services};
 
 pub async fn dev_blocks(_: Request<Body>, _: Params, query: Query, env: RpcServiceEnvironment) -> ServiceResult {
     warn!(env.log(), "Getting dev_blocks");
@@ -58,6 +58,10 @@ pub async fn dev_stats_storage(_: Request<Body>, _: Params, _: Query, env: RpcSe
         env.log())
 }
 
+pub async fn dev_stats_network(_: Request<Body>, _: Params, _: Query, env: RpcServiceEnvironment) -> ServiceResult {
+    make_json_response(&network_services::get_network_traffic_stats(env.network_stats()))
+}
+
 pub async fn dev_stats_memory(_: Request<Body>, _: Params, _: Query, env: RpcServiceEnvironment) -> ServiceResult {
     match base_services::get_stats_memory() {
         Ok(resp) => make_json_response(&resp),
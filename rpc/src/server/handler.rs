@@ -7,8 +7,9 @@ use hyper::{Body, Request};
 use slog::warn;
 use serde::Serialize;
 
-use crypto::hash::HashType;
+use crypto::hash::{ChainId, HashType};
 use shell::shell_channel::BlockApplied;
+use storage::GreylistKey;
 use tezos_api::ffi::{JsonRpcRequest};
 use tezos_messages::ts_to_rfc3339;
 use tezos_wrapper::service::{ProtocolServiceError, ProtocolError};
@@ -22,13 +23,15 @@ use crate::{
     },
     make_json_response,
     make_json_stream_response,
+    not_found,
+    not_implemented,
     result_option_to_json_response,
     result_to_json_response,
     ServiceResult,
     services,
 };
 use crate::server::{HasSingleValue, HResult, Params, Query, RpcServiceEnvironment};
-use crate::services::base_services;
+use crate::services::{base_services, network_services};
 
 #[derive(Serialize)]
 pub struct ErrorMessage {
@@ -36,6 +39,17 @@ pub struct ErrorMessage {
     message: String,
 }
 
+/// Resolve chain from the path parameter `chain_id`, which is `main`, `test` or base58 encoded chain id.
+/// Returns chain id and true, if it is the main chain, or `None`, if the chain is not active.
+fn resolve_chain(chain_id: &str, env: &RpcServiceEnvironment) -> Option<(ChainId, bool)> {
+    let chain_registry = env.chain_registry().read().unwrap();
+    chain_registry.resolve(chain_id)
+        .map(|chain_id| {
+            let is_main_chain = chain_registry.is_main_chain(&chain_id);
+            (chain_id, is_main_chain)
+        })
+}
+
 /// Helper function for generating current TimeStamp
 #[allow(dead_code)]
 fn timestamp() -> TimeStamp {
@@ -63,8 +77,8 @@ pub async fn commit_hash(_: Request<Body>, _: Params, _: Query, _: RpcServiceEnv
     make_json_response(&resp)
 }
 
-pub async fn active_chains(_: Request<Body>, _: Params, _: Query, _: RpcServiceEnvironment) -> HResult {
-    empty()
+pub async fn active_chains(_: Request<Body>, _: Params, _: Query, env: RpcServiceEnvironment) -> HResult {
+    result_to_json_response(base_services::get_active_chains(env.chain_registry()), env.log())
 }
 
 pub async fn protocols(_: Request<Body>, _: Params, _: Query, _: RpcServiceEnvironment) -> HResult {
@@ -78,11 +92,10 @@ pub async fn valid_blocks(_: Request<Body>, _: Params, _: Query, _: RpcServiceEn
 pub async fn head_chain(_: Request<Body>, params: Params, _: Query, env: RpcServiceEnvironment) -> ServiceResult {
     let chain_id = params.get_str("chain_id").unwrap();
 
-    if chain_id == "main" {
-        make_json_stream_response(base_services::get_current_head_monitor_header(env.state())?.unwrap())
-    } else {
-        // TODO: implement... 
-        empty()
+    match resolve_chain(chain_id, &env) {
+        Some((_, true)) => make_json_stream_response(base_services::get_current_head_monitor_header(env.state())?.unwrap()),
+        Some((_, false)) => not_implemented("monitoring of the test chain head is not supported"),
+        None => not_found(),
     }
 }
 
@@ -91,14 +104,14 @@ pub async fn chains_block_id(_: Request<Body>, params: Params, _: Query, env: Rp
     let block_id = params.get_str("block_id").unwrap();
 
     use crate::encoding::chain::BlockInfo;
-    if chain_id == "main" {
-        if block_id == "head" {
+    match resolve_chain(chain_id, &env) {
+        Some((_, true)) => if block_id == "head" {
             result_option_to_json_response(base_services::get_full_current_head(env.state()).map(|res| res.map(BlockInfo::from)), env.log())
         } else {
             result_option_to_json_response(base_services::get_full_block(block_id, env.persistent_storage(), env.state()).map(|res| res.map(BlockInfo::from)), env.log())
-        }
-    } else {
-        empty()
+        },
+        Some((chain_id, false)) => result_option_to_json_response(base_services::get_test_chain_full_block(&chain_id, block_id, env.persistent_storage()).map(|res| res.map(BlockInfo::from)), env.log()),
+        None => not_found(),
     }
 }
 
@@ -106,14 +119,14 @@ pub async fn chains_block_id_header(_: Request<Body>, params: Params, _: Query,
     let chain_id = params.get_str("chain_id").unwrap();
     let block_id = params.get_str("block_id").unwrap();
 
-    if chain_id == "main" {
-        if block_id == "head" {
+    match resolve_chain(chain_id, &env) {
+        Some((_, true)) => if block_id == "head" {
             result_option_to_json_response(base_services::get_current_head_header(env.state()).map(|res| res), env.log())
         } else {
             result_option_to_json_response(base_services::get_block_header(block_id, env.persistent_storage(), env.state()).map(|res| res), env.log())
-        }
-    } else {
-        empty()
+        },
+        Some((chain_id, false)) => result_option_to_json_response(base_services::get_test_chain_block_header(&chain_id, block_id, env.persistent_storage()), env.log()),
+        None => not_found(),
     }
 }
 
@@ -121,14 +134,14 @@ pub async fn chains_block_id_header_shell(_: Request<Body>, params: Params, _: Q
     let chain_id = params.get_str("chain_id").unwrap();
     let block_id = params.get_str("block_id").unwrap();
 
-    if chain_id == "main" {
-        if block_id == "head" {
+    match resolve_chain(chain_id, &env) {
+        Some((_, true)) => if block_id == "head" {
             result_option_to_json_response(base_services::get_current_head_shell_header(env.state()).map(|res| res), env.log())
         } else {
             result_option_to_json_response(base_services::get_block_shell_header(block_id, env.persistent_storage(), env.state()).map(|res| res), env.log())
-        }
-    } else {
-        empty()
+        },
+        Some((chain_id, false)) => result_option_to_json_response(base_services::get_test_chain_block_header(&chain_id, block_id, env.persistent_storage()).map(|res| res.map(|header| header.to_shell_header())), env.log()),
+        None => not_found(),
     }
 }
 
@@ -211,19 +224,19 @@ pub async fn votes_listings(_: Request<Body>, params: Params, _: Query, env: Rpc
 pub async fn mempool_pending_operations(_: Request<Body>, params: Params, _: Query, env: RpcServiceEnvironment) -> ServiceResult {
     let chain_id = params.get_str("chain_id").unwrap();
 
-    if chain_id == "main" {
-        result_to_json_response(
+    match resolve_chain(chain_id, &env) {
+        Some((_, true)) => result_to_json_response(
             services::mempool_services::get_pending_operations(env.state(), env.log()),
             env.log(),
-        )
-    } else {
-        unimplemented!("not implemented yet")
+        ),
+        Some((_, false)) => not_implemented("mempool of the test chain is not supported"),
+        None => not_found(),
     }
 }
 
 pub async fn inject_operation(req: Request<Body>, _: Params, _: Query, env: RpcServiceEnvironment) -> ServiceResult {
     let operation_data_raw = hyper::body::aggregate(req).await?;
-    let operation_data: String = serde_json::from_reader(&mut operation_data_raw.reader())?;
+    let operation_data: serde_json::Value = serde_json::from_reader(&mut operation_data_raw.reader())?;
 
     let shell_channel = env.shell_channel();
 
@@ -249,6 +262,61 @@ pub async fn inject_block(req: Request<Body>, _: Params, _: Query, env: RpcServi
     )
 }
 
+pub async fn get_protocols(_: Request<Body>, _: Params, _: Query, env: RpcServiceEnvironment) -> ServiceResult {
+    result_to_json_response(base_services::get_stored_protocols(env.persistent_storage()), env.log())
+}
+
+pub async fn get_protocol(_: Request<Body>, params: Params, _: Query, env: RpcServiceEnvironment) -> ServiceResult {
+    let protocol_hash = params.get_str("protocol_hash").unwrap();
+    result_option_to_json_response(base_services::get_stored_protocol(protocol_hash, env.persistent_storage()), env.log())
+}
+
+pub async fn network_greylist(_: Request<Body>, _: Params, _: Query, env: RpcServiceEnvironment) -> ServiceResult {
+    result_to_json_response(network_services::get_greylist(env.persistent_storage()), env.log())
+}
+
+pub async fn network_stat(_: Request<Body>, _: Params, _: Query, env: RpcServiceEnvironment) -> ServiceResult {
+    make_json_response(&network_services::get_network_stat(env.network_stats()))
+}
+
+pub async fn network_point_ban(_: Request<Body>, params: Params, _: Query, env: RpcServiceEnvironment) -> ServiceResult {
+    let point = params.get_str("point").unwrap();
+
+    result_to_json_response(
+        network_services::parse_point(point)
+            .and_then(|key| network_services::ban(key, env.shell_channel().clone())),
+        env.log(),
+    )
+}
+
+pub async fn network_point_unban(_: Request<Body>, params: Params, _: Query, env: RpcServiceEnvironment) -> ServiceResult {
+    let point = params.get_str("point").unwrap();
+
+    result_to_json_response(
+        network_services::parse_point(point)
+            .and_then(|key| network_services::unban(key, env.shell_channel().clone())),
+        env.log(),
+    )
+}
+
+pub async fn network_peer_ban(_: Request<Body>, params: Params, _: Query, env: RpcServiceEnvironment) -> ServiceResult {
+    let peer_id = params.get_str("peer_id").unwrap();
+
+    result_to_json_response(
+        network_services::ban(GreylistKey::PeerId(peer_id.to_string()), env.shell_channel().clone()),
+        env.log(),
+    )
+}
+
+pub async fn network_peer_unban(_: Request<Body>, params: Params, _: Query, env: RpcServiceEnvironment) -> ServiceResult {
+    let peer_id = params.get_str("peer_id").unwrap();
+
+    result_to_json_response(
+        network_services::unban(GreylistKey::PeerId(peer_id.to_string()), env.shell_channel().clone()),
+        env.log(),
+    )
+}
+
 pub async fn get_block_protocols(_: Request<Body>, params: Params, _: Query, env: RpcServiceEnvironment) -> ServiceResult {
     let _chain_id = params.get_str("chain_id").unwrap();
     let block_id = params.get_str("block_id").unwrap();
@@ -272,12 +340,12 @@ pub async fn get_block_hash(_: Request<Body>, params: Params, _: Query, env: Rpc
 
 pub async fn get_chain_id(_: Request<Body>, params: Params, _: Query, env: RpcServiceEnvironment) -> ServiceResult {
     // this chain_id (e.g. main) reporesents the "alias" for the actial base58 encoded id (e.g. NetXdQprcVkpaWU)
-    let _chain_id = params.get_str("chain_id").unwrap();
+    let chain_id = params.get_str("chain_id").unwrap();
 
-    result_to_json_response(
-        base_services::get_chain_id(env.state()),
-        env.log(),
-    )
+    match resolve_chain(chain_id, &env) {
+        Some((chain_id, _)) => result_to_json_response(base_services::get_chain_id(&chain_id), env.log()),
+        None => not_found(),
+    }
 }
 
 pub async fn get_contract_counter(_: Request<Body>, params: Params, _: Query, env: RpcServiceEnvironment) -> ServiceResult {
@@ -14,6 +14,8 @@ use riker::actors::ActorSystem;
 use slog::Logger;
 
 use crypto::hash::{BlockHash, HashType};
+use networking::p2p::stats::NetworkStats;
+use shell::chain_registry::ChainRegistryRef;
 use shell::shell_channel::ShellChannelRef;
 use storage::persistent::PersistentStorage;
 use tezos_api::environment::TezosEnvironmentConfiguration;
@@ -41,12 +43,16 @@ pub struct RpcServiceEnvironment {
     #[get = "pub(crate)"]
     state: RpcCollectedStateRef,
     #[get = "pub(crate)"]
+    chain_registry: ChainRegistryRef,
+    #[get = "pub(crate)"]
     shell_channel: ShellChannelRef,
     #[get = "pub(crate)"]
     tezos_environment: TezosEnvironmentConfiguration,
     #[get = "pub(crate)"]
     network_version: NetworkVersion,
     #[get = "pub(crate)"]
+    network_stats: NetworkStats,
+    #[get = "pub(crate)"]
     log: Logger,
 
     #[get = "pub(crate)"]
@@ -64,12 +70,14 @@ impl RpcServiceEnvironment {
         shell_channel: ShellChannelRef,
         tezos_environment: TezosEnvironmentConfiguration,
         network_version: NetworkVersion,
+        network_stats: NetworkStats,
         persistent_storage: &PersistentStorage,
         tezos_readonly_api: Arc<TezosApiConnectionPool>,
         tezos_readonly_prevalidation_api: Arc<TezosApiConnectionPool>,
         tezos_without_context_api: Arc<TezosApiConnectionPool>,
         genesis_hash: &BlockHash,
         state: RpcCollectedStateRef,
+        chain_registry: ChainRegistryRef,
         log: &Logger) -> Self {
         Self {
             sys,
@@ -77,9 +85,11 @@ impl RpcServiceEnvironment {
             shell_channel: shell_channel.clone(),
             tezos_environment,
             network_version,
+            network_stats,
             persistent_storage: persistent_storage.clone(),
             genesis_hash: HashType::BlockHash.bytes_to_string(genesis_hash),
             state,
+            chain_registry,
             log: log.clone(),
             tezos_readonly_api,
             tezos_readonly_prevalidation_api,
@@ -98,7 +108,9 @@ pub type Handler = Arc<dyn Fn(Request<Body>, Params, Query, RpcServiceEnvironmen
 
 
 /// Spawn new HTTP server on given address interacting with specific actor system
-pub fn spawn_server(bind_address: &SocketAddr, env: RpcServiceEnvironment) -> impl Future<Output=Result<(), hyper::Error>> {
+/// Server stops accepting new connections, when `shutdown` completes, and finishes, when pending requests are served.
+pub fn spawn_server<F>(bind_address: &SocketAddr, env: RpcServiceEnvironment, shutdown: F) -> impl Future<Output=Result<(), hyper::Error>>
+    where F: Future<Output=()> {
     let routes = Arc::new(router::create_routes(env.state().read().unwrap().is_sandbox()));
 
     hyper::Server::bind(bind_address)
@@ -135,6 +147,7 @@ pub fn spawn_server(bind_address: &SocketAddr, env: RpcServiceEnvironment) -> im
                 }))
             }
         }))
+        .with_graceful_shutdown(shutdown)
 }
 
 /// Helper for parsing URI queries.
@@ -31,6 +31,14 @@ pub(crate) fn create_routes(is_sandbox: bool) -> PathTree<Handler> {
     routes.handle("/chains/:chain_id/blocks/:block_id/hash", handler::get_block_hash);
     routes.handle("/chains/:chain_id/blocks/:block_id/operation_hashes", handler::get_block_operation_hashes);
     routes.handle("/injection/operation", handler::inject_operation);
+    routes.handle("/network/greylist", handler::network_greylist);
+    routes.handle("/network/stat", handler::network_stat);
+    routes.handle("/network/points/:point/ban", handler::network_point_ban);
+    routes.handle("/network/points/:point/unban", handler::network_point_unban);
+    routes.handle("/network/peers/:peer_id/ban", handler::network_peer_ban);
+    routes.handle("/network/peers/:peer_id/unban", handler::network_peer_unban);
+    routes.handle("/protocols", handler::get_protocols);
+    routes.handle("/protocols/:protocol_hash", handler::get_protocol);
 
     // TODO: TE-226 - implement correctly or just remove, it will be part of protocol router
     // there should be just two endpoints: context/raw/json (from protocol), context/raw/bytes (shell rpc)
@@ -68,6 +76,7 @@ pub(crate) fn create_routes(is_sandbox: bool) -> PathTree<Handler> {
     routes.handle("/dev/chains/main/actions/blocks/:block_hash", dev_handler::dev_action_cursor);
     routes.handle("/dev/chains/main/actions/contracts/:contract_address", dev_handler::dev_action_cursor);
     routes.handle("/stats/memory", dev_handler::dev_stats_memory);
+    routes.handle("/stats/network", dev_handler::dev_stats_network);
     routes.handle("/stats/database_mem", dev_handler::database_memstats);
     //routes.handle("/stats/storage", dev_handler::dev_stats_storage);
 
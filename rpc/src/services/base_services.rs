@@ -8,19 +8,25 @@ use failure::bail;
 use serde::{Deserialize, Serialize};
 use slog::Logger;
 
-use crypto::hash::{chain_id_to_b58_string, HashType};
+use crypto::hash::{BlockHash, chain_id_to_b58_string, ChainId, HashType};
+use shell::chain_registry::ChainRegistryRef;
 use shell::shell_channel::BlockApplied;
 use shell::stats::memory::{Memory, MemoryData, MemoryStatsResult};
-use storage::{BlockHeaderWithHash, BlockStorage, BlockStorageReader, ContextActionRecordValue, ContextActionStorage, num_from_slice};
+use storage::{BlockHeaderWithHash, BlockStorage, BlockStorageReader, ChainMetaStorage, ContextActionRecordValue, ContextActionStorage, num_from_slice, ProtocolStorage};
 use storage::block_storage::BlockJsonData;
+use storage::chain_meta_storage::ChainMetaStorageReader;
 use storage::context::{ContextApi, TezedgeContext};
 use storage::context_action_storage::{ContextActionFilters, ContextActionJson, contract_id_to_contract_address_for_index};
 use storage::persistent::PersistentStorage;
 use storage::merkle_storage::MerkleStorageStats;
 use tezos_context::channel::ContextAction;
+use tezos_messages::p2p::encoding::prelude::Protocol;
 use tezos_messages::p2p::encoding::version::NetworkVersion;
 use tezos_messages::protocol::{RpcJsonMap, UniversalValue};
+use tezos_messages::ts_to_rfc3339;
 
+use crate::encoding::base_types::TimeStamp;
+use crate::encoding::monitor::{ActiveChains, ChainStatus};
 use crate::helpers::{BlockHeaderInfo, BlockHeaderShellInfo, FullBlockInfo, get_action_types, get_block_hash_by_block_id, get_context_protocol_params, get_level_by_block_id, MonitorHeadStream, NodeVersion, PagedResult, Protocols};
 use crate::rpc_actor::RpcCollectedStateRef;
 
@@ -426,11 +432,65 @@ pub(crate) fn get_block_hash(block_id: &str, persistent_storage: &PersistentStor
 }
 
 /// Returns the chain id for the requested chain
-pub(crate) fn get_chain_id(state: &RpcCollectedStateRef) -> Result<String, failure::Error> {
-    // Note: for now, we only support one chain
-    // TODO: rework to support multiple chains
-    let state = state.read().unwrap();
-    Ok(chain_id_to_b58_string(state.chain_id()))
+pub(crate) fn get_chain_id(chain_id: &ChainId) -> Result<String, failure::Error> {
+    Ok(chain_id_to_b58_string(chain_id))
+}
+
+/// Get chains validated by the node
+pub(crate) fn get_active_chains(chain_registry: &ChainRegistryRef) -> Result<ActiveChains, failure::Error> {
+    let chain_registry = chain_registry.read().unwrap();
+    let active_chains = chain_registry.chains().iter()
+        .map(|chain| {
+            let chain_id = chain_id_to_b58_string(&chain.chain_id);
+            match &chain.test_chain {
+                _ if chain.stopping => ChainStatus::stopping(chain_id),
+                Some(test_chain) => match (&test_chain.protocol, test_chain.expiration) {
+                    (Some(protocol), Some(expiration)) => ChainStatus::detailed(
+                        chain_id,
+                        HashType::ProtocolHash.bytes_to_string(protocol),
+                        TimeStamp::Rfc(ts_to_rfc3339(expiration)),
+                    ),
+                    _ => ChainStatus::basic(chain_id),
+                },
+                None => ChainStatus::basic(chain_id),
+            }
+        })
+        .collect();
+    Ok(active_chains)
+}
+
+/// Get information about block of the test chain, `block_id` is "head" or block hash
+pub(crate) fn get_test_chain_full_block(chain_id: &ChainId, block_id: &str, persistent_storage: &PersistentStorage) -> Result<Option<FullBlockInfo>, failure::Error> {
+    let block_hash = match get_test_chain_block_hash(chain_id, block_id, persistent_storage)? {
+        Some(block_hash) => block_hash,
+        None => return Ok(None),
+    };
+    let chain_id = chain_id_to_b58_string(chain_id);
+    Ok(BlockStorage::new(persistent_storage).get_with_json_data(&block_hash)?
+        .map(|(header, json_data)| FullBlockInfo::new(&BlockApplied::new(header, json_data), &chain_id)))
+}
+
+/// Get information about block header of the test chain, `block_id` is "head" or block hash
+pub(crate) fn get_test_chain_block_header(chain_id: &ChainId, block_id: &str, persistent_storage: &PersistentStorage) -> Result<Option<BlockHeaderInfo>, failure::Error> {
+    let block_hash = match get_test_chain_block_hash(chain_id, block_id, persistent_storage)? {
+        Some(block_hash) => block_hash,
+        None => return Ok(None),
+    };
+    let chain_id = chain_id_to_b58_string(chain_id);
+    Ok(BlockStorage::new(persistent_storage).get_with_json_data(&block_hash)?
+        .map(|(header, json_data)| BlockHeaderInfo::new(&BlockApplied::new(header, json_data), &chain_id)))
+}
+
+/// Blocks of the test chain are not indexed by level, so only "head" and block hash are supported
+fn get_test_chain_block_hash(chain_id: &ChainId, block_id: &str, persistent_storage: &PersistentStorage) -> Result<Option<BlockHash>, failure::Error> {
+    if block_id == "head" {
+        Ok(ChainMetaStorage::new(persistent_storage).get_current_head(chain_id)?.map(BlockHash::from))
+    } else {
+        match HashType::BlockHash.string_to_bytes(block_id) {
+            Ok(block_hash) => Ok(Some(block_hash)),
+            Err(_) => bail!("Unsupported block id for test chain: {}", block_id),
+        }
+    }
 }
 
 /// Returns the chain id for the requested chain
@@ -513,6 +573,21 @@ pub(crate) fn get_block_level_by_block_id(block_id: &str, offset: i64, persisten
     }
 }
 
+/// Get hashes of all protocols, which sources are stored by the node
+pub(crate) fn get_stored_protocols(persistent_storage: &PersistentStorage) -> Result<Vec<String>, failure::Error> {
+    let protocols = ProtocolStorage::new(persistent_storage).hashes()?
+        .iter()
+        .map(|protocol_hash| HashType::ProtocolHash.bytes_to_string(protocol_hash))
+        .collect();
+    Ok(protocols)
+}
+
+/// Get sources of the protocol
+pub(crate) fn get_stored_protocol(protocol_hash: &str, persistent_storage: &PersistentStorage) -> Result<Option<Protocol>, failure::Error> {
+    let protocol_hash = HashType::ProtocolHash.string_to_bytes(protocol_hash)?;
+    Ok(ProtocolStorage::new(persistent_storage).get(&protocol_hash)?)
+}
+
 #[inline]
 fn map_header_and_json_to_full_block_info(header: BlockHeaderWithHash, json_data: BlockJsonData, state: &RpcCollectedStateRef) -> FullBlockInfo {
     let state = state.read().unwrap();
@@ -14,8 +14,11 @@ use shell::validation;
 use storage::{BlockMetaStorage, BlockMetaStorageReader, BlockStorage, BlockStorageReader, MempoolStorage};
 use storage::mempool_storage::MempoolOperationType;
 use tezos_api::ffi::{Applied, ComputePathRequest, Errored};
-use tezos_messages::p2p::binary_message::{BinaryMessage, MessageHash};
-use tezos_messages::p2p::encoding::operation::DecodedOperation;
+use tezos_encoding::de;
+use tezos_encoding::encoding::{Encoding, Field, HasEncoding};
+use tezos_encoding::has_encoding;
+use tezos_encoding::json_reader::JsonReader;
+use tezos_messages::p2p::binary_message::{BinaryMessage, JsonMessage, MessageHash};
 use tezos_messages::p2p::encoding::prelude::{BlockHeader, Operation};
 
 use crate::rpc_actor::{RpcCollectedState, RpcCollectedStateRef};
@@ -31,12 +34,19 @@ pub struct MempoolOperations {
     pub unprocessed: Vec<Value>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct InjectedBlockWithOperations {
-    pub data: String,
-    pub operations: Vec<Vec<DecodedOperation>>,
+    pub data: Vec<u8>,
+    pub operations: Vec<Vec<Operation>>,
 }
 
+has_encoding!(InjectedBlockWithOperations, INJECTED_BLOCK_WITH_OPERATIONS_ENCODING, {
+    Encoding::Obj(vec![
+        Field::new("data", Encoding::Bytes),
+        Field::new("operations", Encoding::list(Encoding::list(Operation::encoding().clone()))),
+    ])
+});
+
 pub fn get_pending_operations(
     state: &RpcCollectedStateRef,
     _log: &Logger) -> Result<MempoolOperations, failure::Error> {
@@ -129,7 +139,7 @@ fn convert_errored(errored: &Vec<Errored>, operations: &HashMap<OperationHash, O
 }
 
 pub fn inject_operation(
-    operation_data: &str,
+    operation_data: &Value,
     env: &RpcServiceEnvironment,
     shell_channel: ShellChannelRef) -> Result<String, failure::Error> {
     let persistent_storage = env.persistent_storage();
@@ -137,8 +147,9 @@ pub fn inject_operation(
     let block_meta_storage: Box<dyn BlockMetaStorageReader> = Box::new(BlockMetaStorage::new(persistent_storage));
     let state = env.state();
 
-    // parse operation data
-    let operation: Operation = Operation::from_bytes(hex::decode(operation_data)?)?;
+    // parse operation data, operation is injected as hex encoded bytes
+    let operation_bytes: Vec<u8> = de::from_value(&JsonReader::new().read(operation_data, &Encoding::Bytes)?)?;
+    let operation: Operation = Operation::from_bytes(operation_bytes)?;
     let operation_hash = operation.message_hash()?;
     let state = state.read().unwrap();
 
@@ -170,6 +181,7 @@ pub fn inject_operation(
             msg: MempoolOperationReceived {
                 operation_hash,
                 operation_type: MempoolOperationType::Pending,
+                peer_id: None,
             }.into(),
             topic: ShellChannelTopic::ShellEvents.into(),
         }, None);
@@ -181,18 +193,14 @@ pub fn inject_block(
     injection_data: &str,
     env: &RpcServiceEnvironment,
     shell_channel: ShellChannelRef) -> Result<String, failure::Error> {
-    let block_with_op: InjectedBlockWithOperations = serde_json::from_str(injection_data)?;
+    let block_with_op = InjectedBlockWithOperations::from_json(&serde_json::from_str(injection_data)?)?;
 
-    let header: BlockHeader = BlockHeader::from_bytes(hex::decode(block_with_op.data)?)?;
+    let header: BlockHeader = BlockHeader::from_bytes(block_with_op.data)?;
     let block_hash = HashType::BlockHash.bytes_to_string(&header.message_hash()?);
 
     // special case for block on level 1 - has 0 validation passes
     let validation_passes: Option<Vec<Vec<Operation>>> = if header.validation_pass() > 0 {
-        Some(block_with_op.operations.into_iter()
-            .map(|validation_pass| validation_pass.into_iter()
-                .map(|op| op.into())
-                .collect())
-            .collect())
+        Some(block_with_op.operations)
     } else {
         None
     };
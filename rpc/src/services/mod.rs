@@ -5,5 +5,6 @@
 
 pub mod base_services;
 pub mod mempool_services;
+pub mod network_services;
 pub mod protocol;
 pub mod stats_services;
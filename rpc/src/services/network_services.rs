@@ -0,0 +1,91 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use failure::format_err;
+use riker::actors::*;
+use serde::Serialize;
+
+use networking::p2p::stats::{NetworkStat, NetworkStats, TrafficStats};
+use shell::shell_channel::{ShellChannelMsg, ShellChannelRef, ShellChannelTopic};
+use storage::{GreylistKey, PeerGreylistStorage};
+use storage::persistent::PersistentStorage;
+use tezos_messages::ts_to_rfc3339;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct GreylistedPeer {
+    ip: Option<String>,
+    peer_id: Option<String>,
+    banned_until: String,
+    ban_count: u32,
+    active: bool,
+}
+
+/// Get all greylist entries (including expired ones)
+pub(crate) fn get_greylist(persistent_storage: &PersistentStorage) -> Result<Vec<GreylistedPeer>, failure::Error> {
+    let now = SystemTime::now();
+    let greylist = PeerGreylistStorage::new(persistent_storage).iter()?
+        .into_iter()
+        .map(|(key, entry)| {
+            let (ip, peer_id) = match key {
+                GreylistKey::Ip(ip) => (Some(ip.to_string()), None),
+                GreylistKey::PeerId(peer_id) => (None, Some(peer_id)),
+            };
+            let banned_until = entry.banned_until()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs() as i64)
+                .unwrap_or(0);
+            GreylistedPeer {
+                ip,
+                peer_id,
+                banned_until: ts_to_rfc3339(banned_until),
+                ban_count: *entry.ban_count(),
+                active: entry.is_banned_at(now),
+            }
+        })
+        .collect();
+    Ok(greylist)
+}
+
+/// Get total and current traffic of all peers in the format of the OCaml node
+pub(crate) fn get_network_stat(network_stats: &NetworkStats) -> NetworkStat {
+    network_stats.stat_at(Instant::now())
+}
+
+/// Get traffic statistics of all peers since the node started per message type
+pub(crate) fn get_network_traffic_stats(network_stats: &NetworkStats) -> TrafficStats {
+    network_stats.snapshot()
+}
+
+/// Parse point, which can be either `ip` or `ip:port`
+pub(crate) fn parse_point(point: &str) -> Result<GreylistKey, failure::Error> {
+    if let Ok(address) = point.parse::<SocketAddr>() {
+        Ok(GreylistKey::Ip(address.ip()))
+    } else if let Ok(ip) = point.parse::<IpAddr>() {
+        Ok(GreylistKey::Ip(ip))
+    } else {
+        Err(format_err!("Invalid point: {}", point))
+    }
+}
+
+/// Ask peer manager to greylist IP address or peer id
+pub(crate) fn ban(key: GreylistKey, shell_channel: ShellChannelRef) -> Result<(), failure::Error> {
+    shell_channel.tell(
+        Publish {
+            msg: ShellChannelMsg::BanPeer(key),
+            topic: ShellChannelTopic::ShellCommands.into(),
+        }, None);
+    Ok(())
+}
+
+/// Ask peer manager to remove IP address or peer id from greylist
+pub(crate) fn unban(key: GreylistKey, shell_channel: ShellChannelRef) -> Result<(), failure::Error> {
+    shell_channel.tell(
+        Publish {
+            msg: ShellChannelMsg::UnbanPeer(key),
+            topic: ShellChannelTopic::ShellCommands.into(),
+        }, None);
+    Ok(())
+}
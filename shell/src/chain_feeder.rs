@@ -20,10 +20,12 @@ use storage::{BlockMetaStorage, BlockStorage, BlockStorageReader, ChainMetaStora
 use storage::chain_meta_storage::ChainMetaStorageReader;
 use storage::persistent::PersistentStorage;
 use tezos_api::environment::TezosEnvironmentConfiguration;
-use tezos_api::ffi::ApplyBlockRequest;
+use tezos_api::ffi::{ApplyBlockRequest, ApplyBlockResponse};
+use tezos_messages::rfc3339_to_ts;
 use tezos_wrapper::service::{IpcCmdServer, ProtocolController, ProtocolServiceError};
 
-use crate::shell_channel::{BlockApplied, ShellChannelMsg, ShellChannelRef, ShellChannelTopic};
+use crate::shell_channel::{BlockApplied, ShellChannelMsg, ShellChannelRef, ShellChannelTopic, TestChainForked};
+use crate::shutdown::WorkerStatus;
 use crate::subscription::subscribe_to_shell_events;
 
 type SharedJoinHandle = Arc<Mutex<Option<JoinHandle<Result<(), Error>>>>>;
@@ -59,6 +61,9 @@ impl ChainFeeder {
     /// This actor spawns a new thread in which it will periodically monitor [`persistent_storage`](PersistentStorage).
     /// Purpose of the monitoring thread is to detect whether it is possible to apply blocks received by the p2p layer.
     /// If the block can be applied, it is sent via IPC to the `protocol_runner`, where it is then applied by calling a tezos ffi.
+    ///
+    /// When the thread finishes (after [ShuttingDown](crate::shell_channel::ShuttingDown) the block being applied is finished and queued blocks are dropped),
+    /// `block_applier_status` is set as finished.
     pub fn actor(
         sys: &impl ActorRefFactory,
         shell_channel: ShellChannelRef,
@@ -66,6 +71,7 @@ impl ChainFeeder {
         init_storage_data: &StorageInitInfo,
         tezos_env: &TezosEnvironmentConfiguration,
         ipc_server: IpcCmdServer,
+        block_applier_status: WorkerStatus,
         log: Logger) -> Result<ChainFeederRef, CreateError> {
 
         // spawn thread which processes event
@@ -112,6 +118,8 @@ impl ChainFeeder {
                     }
                 }
 
+                info!(log, "Block applier thread finished");
+                block_applier_status.set_finished();
                 Ok(())
             })
         };
@@ -293,6 +301,9 @@ fn feed_chain_to_protocol(
                                 "context_hash" => HashType::ContextHash.bytes_to_string(&apply_block_result.context_hash),
                                 "validation_result_message" => &apply_block_result.validation_result_message);
 
+                            // resolve forked test chain before the result is stored
+                            let test_chain_forked = resolve_test_chain_forked(&apply_block_result);
+
                             // Lets mark header as applied and store result
                             let mut current_head_meta = block_meta_storage.get(&block_hash)?.unwrap();
 
@@ -310,11 +321,29 @@ fn feed_chain_to_protocol(
                                 let current_head = block_storage.get(&block_hash)?.unwrap();
 
                                 // notify others that the block successfully applied
+                                // (blocks of the test chain are announced to the test chain validator)
+                                let topic = if &request.chain_id == chain_id {
+                                    ShellChannelTopic::ShellEvents
+                                } else {
+                                    ShellChannelTopic::TestChainEvents(request.chain_id.clone())
+                                };
                                 shell_channel.tell(
                                     Publish {
                                         msg: BlockApplied::new(current_head, block_json_data).into(),
-                                        topic: ShellChannelTopic::ShellEvents.into(),
+                                        topic: topic.into(),
                                     }, None);
+
+                                // notify chain supervisor to start the test chain
+                                if let Some(test_chain_forked) = test_chain_forked {
+                                    info!(log, "Block forked test chain";
+                                               "block_header_hash" => block_hash_encoding.bytes_to_string(&block_hash),
+                                               "test_chain_id" => HashType::ChainId.bytes_to_string(&test_chain_forked.chain_id));
+                                    shell_channel.tell(
+                                        Publish {
+                                            msg: test_chain_forked.into(),
+                                            topic: ShellChannelTopic::ShellEvents.into(),
+                                        }, None);
+                                }
                             }
                         }
                         Err(err) => {
@@ -326,6 +355,14 @@ fn feed_chain_to_protocol(
                 }
                 Event::ShuttingDown => {
                     apply_block_run.store(false, Ordering::Release);
+
+                    // queued blocks are not marked as applied, so they are scheduled again after restart
+                    let cancelled_count = block_applier_event_receiver.try_iter()
+                        .filter(|event| matches!(event, Event::ApplyBlock(..)))
+                        .count();
+                    if cancelled_count > 0 {
+                        info!(log, "Apply of the queued blocks was cancelled"; "count" => cancelled_count);
+                    }
                 }
             }
         }
@@ -334,6 +371,31 @@ fn feed_chain_to_protocol(
     Ok(())
 }
 
+/// Resolve test chain forked by the applied block,
+/// protocol and expiration of the test chain are taken from `test_chain_status` of the block metadata
+fn resolve_test_chain_forked(apply_block_result: &ApplyBlockResponse) -> Option<TestChainForked> {
+    if !apply_block_result.forking_testchain {
+        return None;
+    }
+    let forking_testchain_data = apply_block_result.forking_testchain_data.as_ref()?;
+
+    let test_chain_status = serde_json::from_str::<serde_json::Value>(&apply_block_result.block_header_proto_metadata_json)
+        .ok()
+        .and_then(|metadata| metadata.get("test_chain_status").cloned())
+        .unwrap_or_default();
+    let protocol = test_chain_status["protocol"].as_str()
+        .and_then(|protocol| HashType::ProtocolHash.string_to_bytes(protocol).ok());
+    let expiration = test_chain_status["expiration"].as_str()
+        .and_then(|expiration| rfc3339_to_ts(expiration).ok());
+
+    Some(TestChainForked {
+        chain_id: forking_testchain_data.test_chain_id.clone(),
+        forking_block_hash: forking_testchain_data.forking_block_hash.clone(),
+        protocol,
+        expiration,
+    })
+}
+
 /// This initializes ocaml runtime and protocol context,
 /// if we start with new databazes without genesis,
 /// it ensures correct initialization of storage with genesis and his data.
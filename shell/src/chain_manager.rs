@@ -7,12 +7,14 @@
 //!
 //! Also responsible for:
 //! -- managing attribute current head (BlockApplied event is trigger)
-//! -- start test chain (if needed)
+//!
+//! One chain manager runs for every active chain (see [chain_registry](crate::chain_registry)),
+//! it processes only p2p messages routed to its chain.
 //!
 //! see more description in [process_shell_channel_message][ShellChannelMsg::BlockApplied]
 
 use std::cmp;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, RwLock};
 use std::time::{Duration, Instant, SystemTime};
 
@@ -21,10 +23,13 @@ use itertools::Itertools;
 use riker::actors::*;
 use slog::{debug, info, Logger, trace, warn};
 
-use crypto::hash::{BlockHash, ChainId, HashType, OperationHash};
-use networking::p2p::network_channel::{NetworkChannelMsg, NetworkChannelRef, PeerBootstrapped};
+use crypto::hash::{BlockHash, ChainId, HashType, OperationHash, ProtocolHash};
+#[cfg(feature = "barrier")]
+use networking::barrier::{answer_synchronized, Synchronize};
+use networking::clock::{self, ClockRef, system_clock};
+use networking::p2p::network_channel::{Misbehavior, NetworkChannelMsg, NetworkChannelRef, NetworkChannelTopic, PeerBootstrapped, PeerMessageReceived, PeerMisbehaved};
 use networking::p2p::peer::{PeerRef, SendMessage};
-use storage::{BlockHeaderWithHash, BlockMetaStorage, BlockMetaStorageReader, BlockStorage, BlockStorageReader, ChainMetaStorage, MempoolStorage, OperationsStorage, OperationsStorageReader, StorageError};
+use storage::{BlockHeaderWithHash, BlockJsonData, BlockMetaStorage, BlockMetaStorageReader, BlockStorage, BlockStorageReader, ChainMetaStorage, MempoolStorage, OperationKey, OperationsStorage, OperationsStorageReader, ProtocolStorage, StorageError};
 use storage::chain_meta_storage::ChainMetaStorageReader;
 use storage::mempool_storage::MempoolOperationType;
 use storage::persistent::PersistentStorage;
@@ -32,19 +37,18 @@ use tezos_api::ffi::ApplyBlockRequest;
 use tezos_messages::Head;
 use tezos_messages::p2p::binary_message::MessageHash;
 use tezos_messages::p2p::encoding::block_header::Level;
+use tezos_messages::p2p::encoding::limits::{GET_BLOCK_HEADERS_MAX_LENGTH, GET_PROTOCOLS_MAX_LENGTH, MEMPOOL_MAX_OPERATIONS};
 use tezos_messages::p2p::encoding::prelude::*;
 use tezos_wrapper::TezosApiConnectionPool;
 
 use crate::{PeerConnectionThreshold, validation};
+use crate::chain_registry::{ChainRegistryRef, MessageRoute, route_peer_message};
 use crate::shell_channel::{AllBlockOperationsReceived, BlockReceived, CurrentMempoolState, MempoolOperationReceived, ShellChannelMsg, ShellChannelRef, ShellChannelTopic};
 use crate::state::block_state::{BlockchainState, HeadResult, MissingBlock};
+use crate::state::download_scheduler::DownloadQueue;
 use crate::state::operations_state::{MissingOperations, OperationsState};
 use crate::subscription::*;
 
-/// Limit to how many blocks to request in a batch
-const BLOCK_HEADERS_BATCH_SIZE: usize = 10;
-/// Limit to how many block operations to request in a batch
-const BLOCK_OPERATIONS_BATCH_SIZE: usize = 10;
 /// Limit to how many mempool operations to request in a batch
 const MEMPOOL_OPERATIONS_BATCH_SIZE: usize = 10;
 /// How often to check chain completeness
@@ -53,8 +57,6 @@ const CHECK_CHAIN_COMPLETENESS_INTERVAL: Duration = Duration::from_secs(30);
 const ASK_CURRENT_BRANCH_INTERVAL: Duration = Duration::from_secs(15);
 /// How often to print stats in logs
 const LOG_INTERVAL: Duration = Duration::from_secs(60);
-/// After this time we will disconnect peer if his current head level stays the same
-const CURRENT_HEAD_LEVEL_UPDATE_TIMEOUT: Duration = Duration::from_secs(120);
 /// After this time peer will be disconnected if it fails to respond to our request
 const SILENT_PEER_TIMEOUT: Duration = Duration::from_secs(30);
 /// Maximum timeout duration in sandbox mode (do not disconnect peers in sandbox mode)
@@ -64,6 +66,10 @@ const STALLED_CHAIN_COMPLETENESS_TIMEOUT: Duration = Duration::from_secs(240);
 const BLOCK_HASH_ENCODING: HashType = HashType::BlockHash;
 /// Mempool operation time to live
 const MEMPOOL_OPERATION_TTL: Duration = Duration::from_secs(60);
+/// After this time unanswered protocol request can be sent again
+const PROTOCOL_REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+/// Protocol, whose sources were not served, is not requested again for this time
+const UNAVAILABLE_PROTOCOL_TTL: Duration = Duration::from_secs(600);
 
 /// Message commands [`ChainManager`] to disconnect stalled peers.
 #[derive(Clone, Debug)]
@@ -142,7 +148,8 @@ struct Stats {
 }
 
 /// Purpose of this actor is to perform chain synchronization.
-#[actor(DisconnectStalledPeers, CheckChainCompleteness, ApplyCompletedBlock, CheckMempoolCompleteness, AskPeersAboutCurrentBranch, LogStats, NetworkChannelMsg, ShellChannelMsg, SystemEvent, DeadLetter)]
+#[cfg_attr(feature = "barrier", actor(DisconnectStalledPeers, CheckChainCompleteness, ApplyCompletedBlock, CheckMempoolCompleteness, AskPeersAboutCurrentBranch, LogStats, Synchronize, NetworkChannelMsg, ShellChannelMsg, SystemEvent, DeadLetter))]
+#[cfg_attr(not(feature = "barrier"), actor(DisconnectStalledPeers, CheckChainCompleteness, ApplyCompletedBlock, CheckMempoolCompleteness, AskPeersAboutCurrentBranch, LogStats, NetworkChannelMsg, ShellChannelMsg, SystemEvent, DeadLetter))]
 pub struct ChainManager {
     /// All events generated by the network layer will end up in this channel
     network_channel: NetworkChannelRef,
@@ -155,11 +162,19 @@ pub struct ChainManager {
     /// Block meta storage
     block_meta_storage: Box<dyn BlockMetaStorageReader>,
     /// Chain meta storage
-    chain_meta_storage: Box<dyn ChainMetaStorageReader>,
+    chain_meta_storage: ChainMetaStorage,
     /// Operations storage
     operations_storage: Box<dyn OperationsStorageReader>,
     /// Mempool operation storage
     mempool_storage: MempoolStorage,
+    /// Protocol sources storage
+    protocol_storage: ProtocolStorage,
+    /// Protocols which were requested from peers, but not received yet, with the time of the request and the asked peer
+    requested_protocols: HashMap<ProtocolHash, (Instant, ActorUri)>,
+    /// Protocols whose sources were not served by the asked peer, with the time when the request was dropped
+    unavailable_protocols: HashMap<ProtocolHash, Instant>,
+    /// Peers which sent us the block headers, missing protocols needed to apply the block are requested from them
+    block_sources: HashMap<BlockHash, ActorUri>,
     /// Holds state of the blockchain
     chain_state: BlockchainState,
     /// Holds state of the operations
@@ -179,9 +194,21 @@ pub struct ChainManager {
     is_bootstrapped: bool,
     /// Indicates threshold for minimal count of bootstrapped peers to mark chain_manager as bootstrapped
     num_of_peers_for_bootstrap_threshold: usize,
+    /// Peer behind our current head is disconnected, if its current head level stays the same for this long
+    current_head_update_timeout: Duration,
 
     /// Protocol runner pool dedicated to prevalidation
     tezos_readonly_prevalidation_api: Arc<TezosApiConnectionPool>,
+
+    /// Source of time for timeouts
+    clock: ClockRef,
+
+    /// Registry of the active chains
+    chain_registry: ChainRegistryRef,
+    /// Indicates that this chain manager validates the main chain
+    is_main_chain: bool,
+    /// Topic of the shell channel, where events of this chain are published
+    events_topic: Topic,
 }
 
 /// Reference to [chain manager](ChainManager) actor.
@@ -195,31 +222,57 @@ impl ChainManager {
         shell_channel: ShellChannelRef,
         persistent_storage: &PersistentStorage,
         tezos_readonly_prevalidation_api: Arc<TezosApiConnectionPool>,
+        chain_registry: ChainRegistryRef,
         chain_id: &ChainId,
         is_sandbox: bool,
-        peers_threshold: &PeerConnectionThreshold) -> Result<ChainManagerRef, CreateError> {
+        peers_threshold: &PeerConnectionThreshold,
+        current_head_update_timeout: Duration) -> Result<ChainManagerRef, CreateError> {
+        Self::actor_with_clock(sys, network_channel, shell_channel, persistent_storage, tezos_readonly_prevalidation_api, chain_registry, chain_id, is_sandbox, peers_threshold, current_head_update_timeout, system_clock())
+    }
+
+    /// Create new actor instance, which uses `clock` for all timeouts (see [VirtualClock](networking::clock::VirtualClock)).
+    pub fn actor_with_clock(
+        sys: &impl ActorRefFactory,
+        network_channel: NetworkChannelRef,
+        shell_channel: ShellChannelRef,
+        persistent_storage: &PersistentStorage,
+        tezos_readonly_prevalidation_api: Arc<TezosApiConnectionPool>,
+        chain_registry: ChainRegistryRef,
+        chain_id: &ChainId,
+        is_sandbox: bool,
+        peers_threshold: &PeerConnectionThreshold,
+        current_head_update_timeout: Duration,
+        clock: ClockRef) -> Result<ChainManagerRef, CreateError> {
+        let name = if chain_registry.read().unwrap().is_main_chain(chain_id) {
+            ChainManager::name().to_string()
+        } else {
+            format!("{}-{}", ChainManager::name(), HashType::ChainId.bytes_to_string(chain_id))
+        };
+
         sys.actor_of_props::<ChainManager>(
-            ChainManager::name(),
+            &name,
             Props::new_args((
                 network_channel,
                 shell_channel,
                 persistent_storage.clone(),
                 tezos_readonly_prevalidation_api,
+                chain_registry,
                 chain_id.clone(),
                 is_sandbox,
-                peers_threshold.num_of_peers_for_bootstrap_threshold()
+                peers_threshold.num_of_peers_for_bootstrap_threshold(),
+                current_head_update_timeout,
+                clock,
             )),
         )
     }
 
-    /// The `ChainManager` is intended to serve as a singleton actor so that's why
-    /// we won't support multiple names per instance.
+    /// Chain manager of the main chain is a singleton actor, chain managers of the test chains have the chain id appended to the name.
     fn name() -> &'static str {
         "chain-manager"
     }
 
     fn check_mempool_completeness(&mut self, _ctx: &Context<ChainManagerMsg>) {
-        let ChainManager { peers, .. } = self;
+        let ChainManager { peers, clock, .. } = self;
 
         // check for missing mempool operations
         peers.values_mut()
@@ -241,28 +294,44 @@ impl ChainManager {
                     .map(|(op_hash, _)| op_hash)
                     .collect();
 
-                peer.mempool_operations_request_last = Instant::now();
+                peer.mempool_operations_request_last = clock.now();
                 tell_peer(GetOperationsMessage::new(ops_to_get).into(), peer);
             });
     }
 
     /// Check for missing blocks in local chain copy, and schedule downloading for those blocks
     fn check_chain_completeness(&mut self, ctx: &Context<ChainManagerMsg>) -> Result<(), Error> {
-        let ChainManager { peers, chain_state, operations_state, stats, .. } = self;
+        self.release_unanswered_protocol_requests(ctx);
+        self.request_missing_protocols();
+
+        let ChainManager { peers, chain_state, operations_state, stats, is_bootstrapped, chain_registry, is_main_chain, clock, .. } = self;
+
+        if !*is_main_chain {
+            chain_registry.write().unwrap().expire_test_chain_requests(clock.now());
+        }
+
+        // requests, which were not answered on time, are returned to be re-assigned (possibly to another peer)
+        for peer in peers.values_mut() {
+            for missing_block in peer.queued_block_headers.expire(clock.now()) {
+                chain_state.push_missing_block(missing_block)?;
+            }
+            operations_state.push_missing_block_operations(peer.queued_block_operations.expire(clock.now()).into_iter())?;
+        }
 
         // check for missing blocks
+        // missing blocks are drained from the lowest level, so the best scoring peer gets the segment, which is needed first
         if chain_state.has_missing_blocks() {
             peers.values_mut()
-                .filter(|peer| peer.current_head_level.is_some())
+                .filter(|peer| !peer.chain_deactivated && peer.current_head_level.is_some())
                 .filter(|peer| peer.available_block_queue_capacity() > 0)
-                .sorted_by_key(|peer| peer.available_block_queue_capacity()).rev()
+                .sorted_by(|a, b| b.queued_block_headers.score().partial_cmp(&a.queued_block_headers.score()).unwrap_or(cmp::Ordering::Equal))
                 .for_each(|peer| {
                     let mut missing_blocks = chain_state.drain_missing_blocks(peer.available_block_queue_capacity(), peer.current_head_level.unwrap());
                     if !missing_blocks.is_empty() {
                         let queued_blocks = missing_blocks.drain(..)
                             .map(|missing_block| {
                                 let missing_block_hash = missing_block.block_hash.clone();
-                                if peer.queued_block_headers.insert(missing_block_hash.clone(), missing_block).is_none() {
+                                if peer.queued_block_headers.insert(missing_block, clock.now()) {
                                     // block was not already present in queue
                                     Some(missing_block_hash)
                                 } else {
@@ -274,8 +343,14 @@ impl ChainManager {
                             .collect::<Vec<_>>();
 
                         if !queued_blocks.is_empty() {
-                            peer.block_request_last = Instant::now();
-                            tell_peer(GetBlockHeadersMessage::new(queued_blocks).into(), peer);
+                            peer.block_request_last = clock.now();
+                            if !*is_main_chain {
+                                let mut chain_registry = chain_registry.write().unwrap();
+                                queued_blocks.iter().for_each(|block_hash| chain_registry.add_test_chain_request(block_hash.clone(), clock.now()));
+                            }
+                            // batch can be bigger than the count of headers allowed in one request
+                            queued_blocks.chunks(GET_BLOCK_HEADERS_MAX_LENGTH)
+                                .for_each(|block_hashes| tell_peer(GetBlockHeadersMessage::new(block_hashes.to_vec()).into(), peer));
                         }
                     }
                 });
@@ -284,15 +359,15 @@ impl ChainManager {
         // check for missing block operations
         if operations_state.has_missing_block_operations() {
             peers.values_mut()
-                .filter(|peer| peer.current_head_level.is_some())
+                .filter(|peer| !peer.chain_deactivated && peer.current_head_level.is_some())
                 .filter(|peer| peer.available_block_operations_queue_capacity() > 0)
-                .sorted_by_key(|peer| peer.available_block_operations_queue_capacity()).rev()
+                .sorted_by(|a, b| b.queued_block_operations.score().partial_cmp(&a.queued_block_operations.score()).unwrap_or(cmp::Ordering::Equal))
                 .for_each(|peer| {
                     let missing_operations = operations_state.drain_missing_block_operations(peer.available_block_operations_queue_capacity(), peer.current_head_level.unwrap());
                     if !missing_operations.is_empty() {
                         let queued_operations = missing_operations.iter()
                             .map(|missing_operation| {
-                                if peer.queued_block_operations.insert(missing_operation.block_hash.clone(), missing_operation.clone()).is_none() {
+                                if peer.queued_block_operations.insert(missing_operation.clone(), clock.now()) {
                                     // operations were not already present in queue
                                     Some(missing_operation)
                                 } else {
@@ -304,16 +379,27 @@ impl ChainManager {
                             .collect::<Vec<_>>();
 
                         if !queued_operations.is_empty() {
-                            peer.block_operations_request_last = Instant::now();
-                            queued_operations.iter()
-                                .for_each(|&missing_operation| tell_peer(GetOperationsForBlocksMessage::new(missing_operation.into()).into(), peer));
+                            peer.block_operations_request_last = clock.now();
+                            if !*is_main_chain {
+                                let mut chain_registry = chain_registry.write().unwrap();
+                                queued_operations.iter().for_each(|missing_operation| chain_registry.add_test_chain_request(missing_operation.block_hash.clone(), clock.now()));
+                            }
+                            if *is_bootstrapped {
+                                // new blocks mostly contain operations, which we already have in mempool,
+                                // so ask just for operation hashes at first
+                                queued_operations.iter()
+                                    .for_each(|&missing_operation| tell_peer(GetOperationHashesForBlocksMessage::new(missing_operation.into()).into(), peer));
+                            } else {
+                                queued_operations.iter()
+                                    .for_each(|&missing_operation| tell_peer(GetOperationsForBlocksMessage::new(missing_operation.into()).into(), peer));
+                            }
                         }
                     }
                 });
         }
 
         if let (Some(applied_block_last), Some(hydrated_state_last)) = (stats.applied_block_last, stats.hydrated_state_last) {
-            if (applied_block_last.elapsed() > STALLED_CHAIN_COMPLETENESS_TIMEOUT) && (hydrated_state_last.elapsed() > STALLED_CHAIN_COMPLETENESS_TIMEOUT) {
+            if (clock.elapsed(applied_block_last) > STALLED_CHAIN_COMPLETENESS_TIMEOUT) && (clock.elapsed(hydrated_state_last) > STALLED_CHAIN_COMPLETENESS_TIMEOUT) {
                 self.hydrate_state(ctx);
             }
         }
@@ -331,22 +417,31 @@ impl ChainManager {
             peers,
             chain_state,
             operations_state,
+            network_channel,
             shell_channel,
             block_storage,
             block_meta_storage,
             operations_storage,
             stats,
             mempool_storage,
+            protocol_storage,
+            requested_protocols,
+            unavailable_protocols,
+            block_sources,
             current_head,
+            clock,
+            chain_registry,
+            is_main_chain,
+            events_topic,
             ..
         } = self;
 
         match msg {
-            NetworkChannelMsg::PeerBootstrapped(PeerBootstrapped::Success { peer, peer_metadata, .. }) => {
+            NetworkChannelMsg::PeerBootstrapped(PeerBootstrapped::Success { peer, peer_id, peer_metadata, .. }) => {
                 let log = ctx.system.log().new(slog::o!("peer" => peer.name().to_string()));
 
                 debug!(log, "Requesting current branch");
-                let peer = PeerState::new(peer, peer_metadata);
+                let peer = PeerState::new(peer, peer_id, peer_metadata, clock.now());
                 // store peer
                 let actor_uri = peer.peer_ref.uri().clone();
                 self.peers.insert(actor_uri.clone(), peer);
@@ -356,12 +451,31 @@ impl ChainManager {
             }
             NetworkChannelMsg::PeerMessageReceived(received) => {
                 let log = ctx.system.log().new(slog::o!("peer" => received.peer.name().to_string()));
+                // responses are not tagged with chain id, so unexpected responses are reported just by the main chain validator
+                // and only if they could not be requested by the test chain validator
+                let report_unexpected_response = |hash: &[u8]| *is_main_chain && !chain_registry.read().unwrap().is_test_chain_request(hash);
 
                 match peers.get_mut(received.peer.uri()) {
                     Some(peer) => {
+                        // block headers are hashed once, when they are received
+                        let mut block_header_hashes = received.block_header_hashes.iter();
                         for message in received.message.messages() {
+                            let block_header_hash = match message {
+                                PeerMessage::BlockHeader(_) => block_header_hashes.next(),
+                                _ => None,
+                            };
+                            match route_peer_message(message) {
+                                MessageRoute::Chain(chain_id) if chain_id != chain_state.get_chain_id() => continue,
+                                MessageRoute::SharedRequest if !*is_main_chain => continue,
+                                _ => (),
+                            }
+
                             match message {
                                 PeerMessage::CurrentBranch(message) => {
+                                    if chain_state.get_chain_id() == message.chain_id() {
+                                        peer.chain_deactivated = false;
+                                    }
+
                                     // at first, check if we can accept branch or just ignore it
                                     if !chain_state.can_accept_branch(&message, &current_head.local) {
                                         let head = message.current_branch().current_head();
@@ -378,6 +492,7 @@ impl ChainManager {
                                             &message_current_head_block_hash,
                                             message_current_head,
                                             message.current_branch().history(),
+                                            current_head.local.as_ref().map(|head| *head.level()).unwrap_or(0),
                                         )?;
 
                                         // if needed, update remote current head
@@ -392,7 +507,7 @@ impl ChainManager {
                                         // update peer stats
                                         if peer.current_head_level.is_none() || (message_current_head_level > peer.current_head_level.unwrap()) {
                                             peer.current_head_level = Some(message_current_head_level);
-                                            peer.current_head_update_last = Instant::now();
+                                            peer.current_head_update_last = clock.now();
                                         }
 
                                         // notify others that new block was received
@@ -402,7 +517,7 @@ impl ChainManager {
                                                     hash: message_current_head_block_hash,
                                                     level: message_current_head_level,
                                                 }.into(),
-                                                topic: ShellChannelTopic::ShellEvents.into(),
+                                                topic: events_topic.clone(),
                                             }, Some(ctx.myself().into()));
 
                                         // trigger CheckChainCompleteness
@@ -421,10 +536,14 @@ impl ChainManager {
                                     }
                                 }
                                 PeerMessage::BlockHeader(message) => {
-                                    let block_header_with_hash = BlockHeaderWithHash::new(message.block_header().clone()).unwrap();
-                                    match peer.queued_block_headers.remove(&block_header_with_hash.hash) {
+                                    let block_header_with_hash = match block_header_hash {
+                                        Some(hash) => BlockHeaderWithHash { hash: hash.clone(), header: Arc::new(message.block_header().clone()) },
+                                        None => BlockHeaderWithHash::new(message.block_header().clone())?,
+                                    };
+                                    match peer.queued_block_headers.remove(&block_header_with_hash.hash, clock.now()) {
                                         Some(_) => {
-                                            peer.block_response_last = Instant::now();
+                                            peer.block_response_last = clock.now();
+                                            block_sources.insert(block_header_with_hash.hash.clone(), received.peer.uri().clone());
 
                                             let (block_metadata, is_new_block, are_operations_complete) =
                                                 chain_state.process_block_header(&block_header_with_hash, &log)
@@ -446,7 +565,7 @@ impl ChainManager {
 
                                             if is_new_block {
                                                 // update stats
-                                                stats.unseen_block_last = Instant::now();
+                                                stats.unseen_block_last = clock.now();
                                                 stats.unseen_block_count += 1;
 
                                                 // trigger CheckChainCompleteness
@@ -459,12 +578,18 @@ impl ChainManager {
                                                             hash: block_header_with_hash.hash,
                                                             level: block_header_with_hash.header.level(),
                                                         }.into(),
-                                                        topic: ShellChannelTopic::ShellEvents.into(),
+                                                        topic: events_topic.clone(),
                                                     }, Some(ctx.myself().into()));
                                             }
                                         }
-                                        None => {
+                                        None => if peer.queued_block_headers.is_expired(&block_header_with_hash.hash) {
+                                            // request was already re-assigned
+                                            trace!(log, "Received late block header"; "block_header_hash" => BLOCK_HASH_ENCODING.bytes_to_string(&block_header_with_hash.hash));
+                                        } else if !report_unexpected_response(&block_header_with_hash.hash) {
+                                            trace!(log, "Received block header not requested by this chain"; "block_header_hash" => BLOCK_HASH_ENCODING.bytes_to_string(&block_header_with_hash.hash));
+                                        } else {
                                             warn!(log, "Received unexpected block header"; "block_header_hash" => BLOCK_HASH_ENCODING.bytes_to_string(&block_header_with_hash.hash));
+                                            notify_peer_misbehaved(network_channel, &received.peer, Misbehavior::InvalidBlockHeader, false, ctx);
                                         }
                                     }
                                 }
@@ -496,12 +621,12 @@ impl ChainManager {
                                         Some(missing_operations) => {
                                             let operation_was_expected = missing_operations.validation_passes.remove(&operations.operations_for_block().validation_pass());
                                             if operation_was_expected {
-                                                peer.block_operations_response_last = Instant::now();
+                                                peer.block_operations_response_last = clock.now();
                                                 trace!(log, "Received operations validation pass"; "validation_pass" => operations.operations_for_block().validation_pass(), "block_header_hash" => BLOCK_HASH_ENCODING.bytes_to_string(&block_hash));
 
                                                 if operations_state.process_block_operations(&operations)? {
                                                     // update stats
-                                                    stats.unseen_block_operations_last = Instant::now();
+                                                    stats.unseen_block_operations_last = clock.now();
 
                                                     // notify others that new all operations for block were received
                                                     let block_meta = block_meta_storage.get(&block_hash)?.ok_or(StorageError::MissingKey)?;
@@ -526,23 +651,103 @@ impl ChainManager {
                                                                 hash: block_hash.clone(),
                                                                 level: block_meta.level(),
                                                             }.into(),
-                                                            topic: ShellChannelTopic::ShellEvents.into(),
+                                                            topic: events_topic.clone(),
                                                         }, Some(ctx.myself().into()));
 
                                                     // remove operations from queue
-                                                    peer.queued_block_operations.remove(&block_hash);
+                                                    peer.queued_block_operations.remove(&block_hash, clock.now());
                                                 }
                                             } else {
                                                 warn!(log, "Received unexpected validation pass"; "validation_pass" => operations.operations_for_block().validation_pass(), "block_header_hash" => BLOCK_HASH_ENCODING.bytes_to_string(&block_hash));
-                                                ctx.system.stop(received.peer.clone());
+                                                notify_peer_misbehaved(network_channel, &received.peer, Misbehavior::InvalidOperations, true, ctx);
                                             }
                                         }
-                                        None => {
+                                        None => if peer.queued_block_operations.is_expired(&block_hash) {
+                                            // request was already re-assigned
+                                            trace!(log, "Received late operations"; "block_header_hash" => BLOCK_HASH_ENCODING.bytes_to_string(&block_hash));
+                                        } else if !report_unexpected_response(&block_hash) {
+                                            trace!(log, "Received operations not requested by this chain"; "block_header_hash" => BLOCK_HASH_ENCODING.bytes_to_string(&block_hash));
+                                        } else {
                                             warn!(log, "Received unexpected operations");
-                                            ctx.system.stop(received.peer.clone());
+                                            notify_peer_misbehaved(network_channel, &received.peer, Misbehavior::InvalidOperations, true, ctx);
                                         }
                                     }
                                 }
+                                PeerMessage::GetOperationHashesForBlocks(message) => {
+                                    for get_op in message.get_operation_hashes_for_blocks() {
+                                        if get_op.validation_pass() < 0 {
+                                            continue;
+                                        }
+
+                                        let key = OperationKey::new(get_op.hash(), get_op.validation_pass() as u8);
+                                        if let Some(op) = operations_storage.get(&key)? {
+                                            let operation_hashes = op.operations().iter()
+                                                .map(|operation| operation.message_hash())
+                                                .collect::<Result<Vec<_>, _>>()?;
+                                            let msg = OperationHashesForBlocksMessage::new(get_op.clone(), op.operation_hashes_path().clone(), operation_hashes);
+                                            tell_peer(msg.into(), peer);
+                                        }
+                                    }
+                                }
+                                PeerMessage::OperationHashesForBlock(message) => {
+                                    let block_hash = message.operation_hashes_for_block().hash();
+                                    let validation_pass = message.operation_hashes_for_block().validation_pass();
+                                    let operation_was_expected = peer.queued_block_operations.get(block_hash)
+                                        .map(|missing_operations| missing_operations.validation_passes.contains(&validation_pass))
+                                        .unwrap_or(false);
+
+                                    if operation_was_expected {
+                                        peer.block_operations_response_last = clock.now();
+
+                                        // try to assemble operations from mempool, so we do not need to download them again
+                                        let mut operations = Vec::with_capacity(message.operation_hashes().len());
+                                        for operation_hash in message.operation_hashes() {
+                                            match mempool_storage.find(operation_hash)? {
+                                                Some(operation) => operations.push(Operation::from(operation)),
+                                                None => break,
+                                            }
+                                        }
+
+                                        let operations_for_block = OperationsForBlock::new(block_hash.clone(), validation_pass);
+                                        if operations.len() == message.operation_hashes().len() {
+                                            trace!(log, "Operations validation pass resolved from mempool"; "validation_pass" => validation_pass, "block_header_hash" => BLOCK_HASH_ENCODING.bytes_to_string(block_hash));
+                                            // process operations the same way, as if they were received from the peer
+                                            let operations = OperationsForBlocksMessage::new(operations_for_block, message.operation_hashes_path().clone(), operations);
+                                            ctx.myself().tell(
+                                                NetworkChannelMsg::PeerMessageReceived(PeerMessageReceived::new(
+                                                    received.peer.clone(),
+                                                    Arc::new(PeerMessage::OperationsForBlocks(operations).into()),
+                                                )),
+                                                None,
+                                            );
+                                        } else {
+                                            // some operations are missing, so download whole validation pass
+                                            peer.block_operations_request_last = clock.now();
+                                            tell_peer(GetOperationsForBlocksMessage::new(vec![operations_for_block]).into(), peer);
+                                        }
+                                    } else if peer.queued_block_operations.is_expired(block_hash) {
+                                        // request was already re-assigned
+                                        trace!(log, "Received late operation hashes"; "validation_pass" => validation_pass, "block_header_hash" => BLOCK_HASH_ENCODING.bytes_to_string(block_hash));
+                                    } else if !report_unexpected_response(block_hash) {
+                                        trace!(log, "Received operation hashes not requested by this chain"; "validation_pass" => validation_pass, "block_header_hash" => BLOCK_HASH_ENCODING.bytes_to_string(block_hash));
+                                    } else {
+                                        warn!(log, "Received unexpected operation hashes"; "validation_pass" => validation_pass, "block_header_hash" => BLOCK_HASH_ENCODING.bytes_to_string(block_hash));
+                                        notify_peer_misbehaved(network_channel, &received.peer, Misbehavior::InvalidOperations, false, ctx);
+                                    }
+                                }
+                                PeerMessage::Deactivate(message) => {
+                                    // peer does not participate on our chain anymore, so stop synchronizing with it
+                                    // (test chain is stopped just by chain supervisor on expiration or according to the main chain head)
+                                    debug!(log, "Peer deactivated chain"; "chain_id" => HashType::ChainId.bytes_to_string(message.deactivate()));
+                                    peer.chain_deactivated = true;
+                                    peer.current_head_level = None;
+                                    for missing_block in peer.queued_block_headers.drain() {
+                                        chain_state.push_missing_block(missing_block)?;
+                                    }
+                                    operations_state.push_missing_block_operations(peer.queued_block_operations.drain())?;
+                                    peer.missing_mempool_operations.clear();
+                                    peer.queued_mempool_operations.clear();
+                                }
                                 PeerMessage::GetOperationsForBlocks(message) => {
                                     for get_op in message.get_operations_for_blocks() {
                                         if get_op.validation_pass() < 0 {
@@ -557,15 +762,16 @@ impl ChainManager {
                                 }
                                 PeerMessage::CurrentHead(message) => {
                                     if chain_state.get_chain_id() == message.chain_id() {
+                                        peer.chain_deactivated = false;
                                         let peer_current_mempool = message.current_mempool();
 
                                         // all operations (known_valid + pending) should be added to pending and validated afterwards
-                                        // enqueue mempool operations for retrieval
-                                        peer_current_mempool.known_valid().iter().cloned()
-                                            .for_each(|operation_hash| {
-                                                peer.missing_mempool_operations.push((operation_hash, MempoolOperationType::Pending));
-                                            });
-                                        peer_current_mempool.pending().iter().cloned()
+                                        // enqueue mempool operations for retrieval (except recently refused ones)
+                                        let refused_operations = resolve_refused_operations(&self.current_mempool_state);
+                                        peer_current_mempool.known_valid().iter()
+                                            .chain(peer_current_mempool.pending().iter())
+                                            .filter(|operation_hash| !refused_operations.contains(*operation_hash))
+                                            .cloned()
                                             .for_each(|operation_hash| {
                                                 peer.missing_mempool_operations.push((operation_hash, MempoolOperationType::Pending));
                                             });
@@ -622,7 +828,7 @@ impl ChainManager {
                                             }
 
                                             // store mempool operation
-                                            peer.mempool_operations_response_last = Instant::now();
+                                            peer.mempool_operations_response_last = clock.now();
                                             mempool_storage.put(operation_type.clone(), message.clone(), op_ttl)?;
 
                                             // trigger CheckMempoolCompleteness
@@ -634,13 +840,34 @@ impl ChainManager {
                                                     msg: MempoolOperationReceived {
                                                         operation_hash,
                                                         operation_type,
+                                                        peer_id: Some(peer.peer_id.clone()),
                                                     }.into(),
-                                                    topic: ShellChannelTopic::ShellEvents.into(),
+                                                    topic: events_topic.clone(),
                                                 }, Some(ctx.myself().into()));
                                         }
                                         None => debug!(log, "Unexpected mempool operation received")
                                     }
                                 }
+                                PeerMessage::GetProtocols(message) => {
+                                    for protocol_hash in message.get_protocols() {
+                                        if let Some(protocol) = protocol_storage.get(protocol_hash)? {
+                                            tell_peer(ProtocolMessage::new(protocol).into(), peer);
+                                        }
+                                    }
+                                }
+                                PeerMessage::Protocol(message) => {
+                                    // hash is computed from the received sources, so sources which do not match the requested hash are not accepted
+                                    let protocol_hash = message.protocol().message_hash()?;
+                                    if requested_protocols.remove(&protocol_hash).is_some() {
+                                        info!(log, "Received protocol sources"; "protocol_hash" => HashType::ProtocolHash.bytes_to_string(&protocol_hash));
+                                        unavailable_protocols.remove(&protocol_hash);
+                                        protocol_storage.put(&protocol_hash, message.protocol())?;
+                                    } else if report_unexpected_response(&protocol_hash) && !protocol_storage.contains(&protocol_hash)? {
+                                        // peer sent protocol, which we did not ask for, or its sources do not match requested hash
+                                        warn!(log, "Received unexpected protocol"; "protocol_hash" => HashType::ProtocolHash.bytes_to_string(&protocol_hash));
+                                        notify_peer_misbehaved(network_channel, &received.peer, Misbehavior::InvalidProtocol, false, ctx);
+                                    }
+                                }
                                 PeerMessage::Bootstrap => {
                                     // on bootstrap reset peer state
                                 }
@@ -657,6 +884,76 @@ impl ChainManager {
         Ok(())
     }
 
+    /// Block is applied by the protocol announced in the `next_protocol` of its predecessor.
+    /// If sources of that protocol are not stored yet, they are requested in the background from the peer,
+    /// which sent us the block. Block is applied by the protocol runner regardless of the sources.
+    ///
+    /// Returns `true`, if the protocol was requested.
+    fn request_protocol_sources(&mut self, block_hash: &BlockHash, log: &Logger) -> Result<bool, Error> {
+        let predecessor = match self.block_storage.get(block_hash)? {
+            Some(block) => block.header.predecessor().clone(),
+            None => return Ok(false),
+        };
+        let protocol_hash = match self.block_storage.get_with_json_data(&predecessor)? {
+            Some((_, json_data)) => match resolve_next_protocol(&json_data)? {
+                Some(protocol_hash) => protocol_hash,
+                None => return Ok(false),
+            },
+            None => return Ok(false),
+        };
+        if self.requested_protocols.contains_key(&protocol_hash)
+            || self.unavailable_protocols.contains_key(&protocol_hash)
+            || self.protocol_storage.contains(&protocol_hash)? {
+            return Ok(false);
+        }
+
+        let ChainManager { peers, block_sources, requested_protocols, chain_registry, is_main_chain, clock, .. } = self;
+
+        // sources of injected blocks and blocks of disconnected peers cannot be requested
+        let peer = match block_sources.get(block_hash).and_then(move |peer_uri| peers.get_mut(peer_uri)) {
+            Some(peer) => peer,
+            None => return Ok(false),
+        };
+
+        debug!(log, "Requesting protocol sources"; "protocol_hash" => HashType::ProtocolHash.bytes_to_string(&protocol_hash), "peer" => peer.peer_ref.name());
+        requested_protocols.insert(protocol_hash.clone(), (clock.now(), peer.peer_ref.uri().clone()));
+        if !*is_main_chain {
+            chain_registry.write().unwrap().add_test_chain_request(protocol_hash.clone(), clock.now());
+        }
+        peer.missing_protocols.push(protocol_hash);
+        Ok(true)
+    }
+
+    /// Missing protocols are requested from their peers, in chunks of at most `GET_PROTOCOLS_MAX_LENGTH` hashes.
+    fn request_missing_protocols(&mut self) {
+        self.peers.values_mut()
+            .filter(|peer| !peer.missing_protocols.is_empty())
+            .for_each(|peer| {
+                let missing_protocols = peer.missing_protocols.drain(..).collect::<Vec<_>>();
+                missing_protocols.chunks(GET_PROTOCOLS_MAX_LENGTH)
+                    .for_each(|protocol_hashes| tell_peer(GetProtocolsMessage::new(protocol_hashes).into(), peer));
+            });
+    }
+
+    /// Protocol requests, which were not answered on time or whose peer disconnected, are dropped
+    /// and their protocols are not requested again for [`UNAVAILABLE_PROTOCOL_TTL`].
+    fn release_unanswered_protocol_requests(&mut self, ctx: &Context<ChainManagerMsg>) {
+        let ChainManager { requested_protocols, unavailable_protocols, peers, clock, .. } = self;
+
+        unavailable_protocols.retain(|_, released| clock.elapsed(*released) <= UNAVAILABLE_PROTOCOL_TTL);
+
+        let unanswered = requested_protocols.iter()
+            .filter(|(_, (requested, peer_uri))| clock.elapsed(*requested) > PROTOCOL_REQUEST_TIMEOUT || !peers.contains_key(peer_uri))
+            .map(|(protocol_hash, _)| protocol_hash.clone())
+            .collect::<Vec<_>>();
+
+        for protocol_hash in unanswered {
+            warn!(ctx.system.log(), "Protocol sources were not received"; "protocol_hash" => HashType::ProtocolHash.bytes_to_string(&protocol_hash));
+            requested_protocols.remove(&protocol_hash);
+            unavailable_protocols.insert(protocol_hash, clock.now());
+        }
+    }
+
     fn process_shell_channel_message(&mut self, ctx: &Context<ChainManagerMsg>, msg: ShellChannelMsg) -> Result<(), Error> {
         match msg {
             ShellChannelMsg::BlockApplied(message) => {
@@ -686,7 +983,7 @@ impl ChainManager {
                     self.shell_channel.tell(
                         Publish {
                             msg: ShellChannelMsg::NewCurrentHead(new_head, message.clone()),
-                            topic: ShellChannelTopic::ShellEvents.into(),
+                            topic: self.events_topic.clone(),
                         }, Some(ctx.myself().into()));
 
                     // broadcast new head/branch to other peers
@@ -716,6 +1013,8 @@ impl ChainManager {
                     }
                 }
 
+                self.block_sources.remove(&message.header().hash);
+
                 // check successors, if can be applied
                 self.check_successors_for_apply(ctx, &message.header().hash)?;
             }
@@ -777,7 +1076,7 @@ impl ChainManager {
 
                 if is_new_block {
                     // update stats
-                    self.stats.unseen_block_last = Instant::now();
+                    self.stats.unseen_block_last = self.clock.now();
                     self.stats.unseen_block_count += 1;
 
                     // notify others that new block (header) was received
@@ -787,7 +1086,7 @@ impl ChainManager {
                                 hash: block_header_hash.clone(),
                                 level: block_header_with_hash.header.level(),
                             }.into(),
-                            topic: ShellChannelTopic::ShellEvents.into(),
+                            topic: self.events_topic.clone(),
                         }, Some(ctx.myself().into()));
 
                     // handle operations (if expecting any)
@@ -806,7 +1105,7 @@ impl ChainManager {
                             if self.operations_state.process_block_operations(&msg)? {
                                 are_operations_complete = true;
                                 // update stats
-                                self.stats.unseen_block_operations_last = Instant::now();
+                                self.stats.unseen_block_operations_last = self.clock.now();
 
                                 // notify others that new all operations for block were received
                                 self.shell_channel.tell(
@@ -815,7 +1114,7 @@ impl ChainManager {
                                             hash: block_header_hash.clone(),
                                             level: block_metadata.level(),
                                         }.into(),
-                                        topic: ShellChannelTopic::ShellEvents.into(),
+                                        topic: self.events_topic.clone(),
                                     }, Some(ctx.myself().into()));
                             }
                         }
@@ -868,7 +1167,7 @@ impl ChainManager {
             "missing_blocks" => self.chain_state.missing_blocks_count(),
             "missing_block_operations" => self.operations_state.missing_block_operations_count(),
         );
-        self.stats.hydrated_state_last = Some(Instant::now());
+        self.stats.hydrated_state_last = Some(self.clock.now());
     }
 
     /// Updates currnet local head and some stats.
@@ -877,7 +1176,7 @@ impl ChainManager {
         let new_level = new_head.level().clone();
         self.current_head.local = Some(new_head);
         self.stats.applied_block_level = Some(new_level);
-        self.stats.applied_block_last = Some(Instant::now());
+        self.stats.applied_block_last = Some(self.clock.now());
         self.resolve_is_bootstrapped(log);
     }
 
@@ -952,6 +1251,12 @@ impl ChainManager {
             None => return Err(format_err!("Block metadata not found for block_hash: {}", HashType::BlockHash.bytes_to_string(&msg.block_hash))),
         }
 
+        // sources of the protocol are fetched in the background, block is applied without waiting for them
+        if self.request_protocol_sources(&msg.block_hash, &ctx.system.log())? {
+            // missing protocols of the blocks already waiting in the mailbox are requested together
+            ctx.myself().tell(CheckChainCompleteness, None);
+        }
+
         // collect data
         let request = self.prepare_apply_request(&msg.block_hash)?;
 
@@ -998,16 +1303,25 @@ impl ChainManager {
     }
 }
 
-impl ActorFactoryArgs<(NetworkChannelRef, ShellChannelRef, PersistentStorage, Arc<TezosApiConnectionPool>, ChainId, bool, usize)> for ChainManager {
-    fn create_args((network_channel, shell_channel, persistent_storage, tezos_readonly_prevalidation_api, chain_id, is_sandbox, num_of_peers_for_bootstrap_threshold): (NetworkChannelRef, ShellChannelRef, PersistentStorage, Arc<TezosApiConnectionPool>, ChainId, bool, usize)) -> Self {
+impl ActorFactoryArgs<(NetworkChannelRef, ShellChannelRef, PersistentStorage, Arc<TezosApiConnectionPool>, ChainRegistryRef, ChainId, bool, usize, Duration, ClockRef)> for ChainManager {
+    fn create_args((network_channel, shell_channel, persistent_storage, tezos_readonly_prevalidation_api, chain_registry, chain_id, is_sandbox, num_of_peers_for_bootstrap_threshold, current_head_update_timeout, clock): (NetworkChannelRef, ShellChannelRef, PersistentStorage, Arc<TezosApiConnectionPool>, ChainRegistryRef, ChainId, bool, usize, Duration, ClockRef)) -> Self {
+        let (is_main_chain, events_topic) = {
+            let chain_registry = chain_registry.read().unwrap();
+            (chain_registry.is_main_chain(&chain_id), chain_registry.events_topic(&chain_id))
+        };
+
         ChainManager {
             network_channel,
             shell_channel,
             block_storage: Box::new(BlockStorage::new(&persistent_storage)),
             block_meta_storage: Box::new(BlockMetaStorage::new(&persistent_storage)),
-            chain_meta_storage: Box::new(ChainMetaStorage::new(&persistent_storage)),
+            chain_meta_storage: ChainMetaStorage::new(&persistent_storage),
             operations_storage: Box::new(OperationsStorage::new(&persistent_storage)),
             mempool_storage: MempoolStorage::new(&persistent_storage),
+            protocol_storage: ProtocolStorage::new(&persistent_storage),
+            requested_protocols: HashMap::new(),
+            unavailable_protocols: HashMap::new(),
+            block_sources: HashMap::new(),
             chain_state: BlockchainState::new(&persistent_storage, &chain_id),
             operations_state: OperationsState::new(&persistent_storage, &chain_id),
             peers: HashMap::new(),
@@ -1019,8 +1333,8 @@ impl ActorFactoryArgs<(NetworkChannelRef, ShellChannelRef, PersistentStorage, Ar
             shutting_down: false,
             stats: Stats {
                 unseen_block_count: 0,
-                unseen_block_last: Instant::now(),
-                unseen_block_operations_last: Instant::now(),
+                unseen_block_last: clock.now(),
+                unseen_block_operations_last: clock.now(),
                 applied_block_last: None,
                 applied_block_level: None,
                 hydrated_state_last: None,
@@ -1028,7 +1342,12 @@ impl ActorFactoryArgs<(NetworkChannelRef, ShellChannelRef, PersistentStorage, Ar
             is_sandbox,
             is_bootstrapped: false,
             num_of_peers_for_bootstrap_threshold,
+            current_head_update_timeout,
             tezos_readonly_prevalidation_api,
+            clock,
+            chain_registry,
+            is_main_chain,
+            events_topic,
         }
     }
 }
@@ -1039,41 +1358,41 @@ impl Actor for ChainManager {
     fn pre_start(&mut self, ctx: &Context<Self::Msg>) {
         subscribe_to_actor_terminated(ctx.system.sys_events(), ctx.myself());
         subscribe_to_network_events(&self.network_channel, ctx.myself());
-        subscribe_to_shell_events(&self.shell_channel, ctx.myself());
+        subscribe_to_chain_events(&self.shell_channel, self.events_topic.clone(), ctx.myself());
         subscribe_to_dead_letters(ctx.system.dead_letters(), ctx.myself());
 
         self.hydrate_state(ctx);
 
-        ctx.schedule::<Self::Msg, _>(
+        clock::schedule(
+            &self.clock,
+            ctx,
             CHECK_CHAIN_COMPLETENESS_INTERVAL / 4,
             CHECK_CHAIN_COMPLETENESS_INTERVAL,
-            ctx.myself(),
-            None,
-            CheckChainCompleteness.into());
-        ctx.schedule::<Self::Msg, _>(
+            CheckChainCompleteness);
+        clock::schedule(
+            &self.clock,
+            ctx,
             ASK_CURRENT_BRANCH_INTERVAL,
             ASK_CURRENT_BRANCH_INTERVAL,
-            ctx.myself(),
-            None,
-            AskPeersAboutCurrentBranch.into());
-        ctx.schedule::<Self::Msg, _>(
+            AskPeersAboutCurrentBranch);
+        clock::schedule(
+            &self.clock,
+            ctx,
             LOG_INTERVAL / 2,
             LOG_INTERVAL,
-            ctx.myself(),
-            None,
-            LogStats.into());
+            LogStats);
 
         let peer_timeout = if self.is_sandbox {
             SILENT_PEER_TIMEOUT_SANDBOX
         } else {
             SILENT_PEER_TIMEOUT / 2
         };
-        ctx.schedule::<Self::Msg, _>(
+        clock::schedule(
+            &self.clock,
+            ctx,
             peer_timeout,
             peer_timeout,
-            ctx.myself(),
-            None,
-            DisconnectStalledPeers.into());
+            DisconnectStalledPeers);
     }
 
     fn sys_recv(&mut self, ctx: &Context<Self::Msg>, msg: SystemMsg, sender: Option<BasicActorRef>) {
@@ -1093,13 +1412,15 @@ impl Receive<SystemEvent> for ChainManager {
     fn receive(&mut self, _ctx: &Context<Self::Msg>, msg: SystemEvent, _sender: Option<BasicActorRef>) {
         if let SystemEvent::ActorTerminated(evt) = msg {
             if let Some(mut peer) = self.peers.remove(evt.actor.uri()) {
+                self.block_sources.retain(|_, peer_uri| peer_uri != evt.actor.uri());
+
                 peer.queued_block_headers
                     .drain()
-                    .for_each(|(_, missing_block)| {
+                    .for_each(|missing_block| {
                         self.chain_state.push_missing_block(missing_block).expect("Failed to re-schedule block hash");
                     });
 
-                self.operations_state.push_missing_block_operations(peer.queued_block_operations.drain().map(|(_, op)| op))
+                self.operations_state.push_missing_block_operations(peer.queued_block_operations.drain())
                     .expect("Failed to return to queue")
             }
         }
@@ -1114,6 +1435,15 @@ impl Receive<DeadLetter> for ChainManager {
     }
 }
 
+#[cfg(feature = "barrier")]
+impl Receive<Synchronize> for ChainManager {
+    type Msg = ChainManagerMsg;
+
+    fn receive(&mut self, _ctx: &Context<Self::Msg>, _msg: Synchronize, sender: Sender) {
+        answer_synchronized(sender);
+    }
+}
+
 impl Receive<LogStats> for ChainManager {
     type Msg = ChainManagerMsg;
 
@@ -1132,25 +1462,29 @@ impl Receive<LogStats> for ChainManager {
             "block_count" => self.stats.unseen_block_count,
             "missing_blocks" => self.chain_state.missing_blocks_count(),
             "missing_block_operations" => self.operations_state.missing_block_operations_count(),
-            "last_block_secs" => self.stats.unseen_block_last.elapsed().as_secs(),
-            "last_block_operations_secs" => self.stats.unseen_block_operations_last.elapsed().as_secs(),
+            "last_block_secs" => self.clock.elapsed(self.stats.unseen_block_last).as_secs(),
+            "last_block_operations_secs" => self.clock.elapsed(self.stats.unseen_block_operations_last).as_secs(),
             "applied_block_level" => self.stats.applied_block_level,
-            "applied_block_secs" => self.stats.applied_block_last.map(|i| i.elapsed().as_secs()));
+            "applied_block_secs" => self.stats.applied_block_last.map(|i| self.clock.elapsed(i).as_secs()));
         for peer in self.peers.values() {
             debug!(log, "Peer state info";
                 "actor_ref" => format!("{}", peer.peer_ref),
                 "queued_block_headers" => peer.queued_block_headers.len(),
                 "queued_block_operations" => peer.queued_block_operations.len(),
-                "block_request_secs" => peer.block_request_last.elapsed().as_secs(),
-                "block_response_secs" => peer.block_response_last.elapsed().as_secs(),
-                "block_operations_request_secs" => peer.block_operations_request_last.elapsed().as_secs(),
-                "block_operations_response_secs" => peer.block_operations_response_last.elapsed().as_secs(),
-                "mempool_operations_request_secs" => peer.mempool_operations_request_last.elapsed().as_secs(),
-                "mempool_operations_response_secs" => peer.mempool_operations_response_last.elapsed().as_secs(),
+                "block_batch_size" => peer.queued_block_headers.batch_size(),
+                "block_latency_ms" => peer.queued_block_headers.latency().map(|latency| latency.as_millis() as u64),
+                "block_operations_batch_size" => peer.queued_block_operations.batch_size(),
+                "block_operations_latency_ms" => peer.queued_block_operations.latency().map(|latency| latency.as_millis() as u64),
+                "block_request_secs" => self.clock.elapsed(peer.block_request_last).as_secs(),
+                "block_response_secs" => self.clock.elapsed(peer.block_response_last).as_secs(),
+                "block_operations_request_secs" => self.clock.elapsed(peer.block_operations_request_last).as_secs(),
+                "block_operations_response_secs" => self.clock.elapsed(peer.block_operations_response_last).as_secs(),
+                "mempool_operations_request_secs" => self.clock.elapsed(peer.mempool_operations_request_last).as_secs(),
+                "mempool_operations_response_secs" => self.clock.elapsed(peer.mempool_operations_response_last).as_secs(),
                 "current_head_level" => peer.current_head_level,
-                "current_head_update_secs" => peer.current_head_update_last.elapsed().as_secs());
+                "current_head_update_secs" => self.clock.elapsed(peer.current_head_update_last).as_secs());
         }
-        info!(log, "Various info"; "peer_count" => self.peers.len(), "hydrated_state_secs" => self.stats.hydrated_state_last.map(|i| i.elapsed().as_secs()));
+        info!(log, "Various info"; "peer_count" => self.peers.len(), "hydrated_state_secs" => self.stats.hydrated_state_last.map(|i| self.clock.elapsed(i).as_secs()));
     }
 }
 
@@ -1158,36 +1492,44 @@ impl Receive<DisconnectStalledPeers> for ChainManager {
     type Msg = ChainManagerMsg;
 
     fn receive(&mut self, ctx: &Context<Self::Msg>, _msg: DisconnectStalledPeers, _sender: Sender) {
+        let local_head_level = self.current_head.local.as_ref().map(|head| *head.level());
         self.peers.iter()
             .for_each(|(uri, state)| {
                 let block_response_pending = state.block_request_last > state.block_response_last;
                 let block_operations_response_pending = state.block_operations_request_last > state.block_operations_response_last;
                 let mempool_operations_response_pending = state.mempool_operations_request_last > state.mempool_operations_response_last;
 
-                let should_disconnect = if state.current_head_update_last.elapsed() > CURRENT_HEAD_LEVEL_UPDATE_TIMEOUT {
-                    warn!(ctx.system.log(), "Peer failed to update its current head"; "peer" => format!("{}", uri));
+                // peer with the same head as ours is not stalled, the chain itself may be quiet (e.g. sandbox)
+                let is_behind = match (state.current_head_level, local_head_level) {
+                    (Some(peer_level), Some(local_level)) => peer_level < local_level,
+                    (None, _) => true,
+                    (Some(_), None) => false,
+                };
+
+                let should_disconnect = if !state.chain_deactivated && is_behind && self.clock.elapsed(state.current_head_update_last) > self.current_head_update_timeout {
+                    warn!(ctx.system.log(), "Peer failed to update its current head"; "peer" => format!("{}", uri), "current_head_level" => state.current_head_level, "local_head_level" => local_head_level);
                     true
                 } else if block_response_pending && (state.block_request_last - state.block_response_last > SILENT_PEER_TIMEOUT) {
-                    warn!(ctx.system.log(), "Peer did not respond to our request for block on time"; "peer" => format!("{}", uri), "request_secs" => state.block_request_last.elapsed().as_secs(), "response_secs" => state.block_response_last.elapsed().as_secs());
+                    warn!(ctx.system.log(), "Peer did not respond to our request for block on time"; "peer" => format!("{}", uri), "request_secs" => self.clock.elapsed(state.block_request_last).as_secs(), "response_secs" => self.clock.elapsed(state.block_response_last).as_secs());
                     true
                 } else if block_operations_response_pending && (state.block_operations_request_last - state.block_operations_response_last > SILENT_PEER_TIMEOUT) {
-                    warn!(ctx.system.log(), "Peer did not respond to our request for block operations on time"; "peer" => format!("{}", uri), "request_secs" => state.block_operations_request_last.elapsed().as_secs(), "response_secs" => state.block_operations_response_last.elapsed().as_secs());
+                    warn!(ctx.system.log(), "Peer did not respond to our request for block operations on time"; "peer" => format!("{}", uri), "request_secs" => self.clock.elapsed(state.block_operations_request_last).as_secs(), "response_secs" => self.clock.elapsed(state.block_operations_response_last).as_secs());
                     true
-                } else if block_response_pending && !state.queued_block_headers.is_empty() && (state.block_response_last.elapsed() > SILENT_PEER_TIMEOUT) {
-                    warn!(ctx.system.log(), "Peer is not providing requested blocks"; "peer" => format!("{}", uri), "queued_count" => state.queued_block_headers.len(), "response_secs" => state.block_response_last.elapsed().as_secs());
+                } else if block_response_pending && !state.queued_block_headers.is_empty() && (self.clock.elapsed(state.block_response_last) > SILENT_PEER_TIMEOUT) {
+                    warn!(ctx.system.log(), "Peer is not providing requested blocks"; "peer" => format!("{}", uri), "queued_count" => state.queued_block_headers.len(), "response_secs" => self.clock.elapsed(state.block_response_last).as_secs());
                     true
-                } else if block_operations_response_pending && !state.queued_block_operations.is_empty() && (state.block_operations_response_last.elapsed() > SILENT_PEER_TIMEOUT) {
-                    warn!(ctx.system.log(), "Peer is not providing requested block operations"; "peer" => format!("{}", uri), "queued_count" => state.queued_block_operations.len(), "response_secs" => state.block_operations_response_last.elapsed().as_secs());
+                } else if block_operations_response_pending && !state.queued_block_operations.is_empty() && (self.clock.elapsed(state.block_operations_response_last) > SILENT_PEER_TIMEOUT) {
+                    warn!(ctx.system.log(), "Peer is not providing requested block operations"; "peer" => format!("{}", uri), "queued_count" => state.queued_block_operations.len(), "response_secs" => self.clock.elapsed(state.block_operations_response_last).as_secs());
                     true
-                } else if mempool_operations_response_pending && !state.queued_mempool_operations.is_empty() && (state.mempool_operations_response_last.elapsed() > SILENT_PEER_TIMEOUT) {
-                    warn!(ctx.system.log(), "Peer is not providing requested mempool operations"; "peer" => format!("{}", uri), "queued_count" => state.queued_mempool_operations.len(), "response_secs" => state.mempool_operations_response_last.elapsed().as_secs());
+                } else if mempool_operations_response_pending && !state.queued_mempool_operations.is_empty() && (self.clock.elapsed(state.mempool_operations_response_last) > SILENT_PEER_TIMEOUT) {
+                    warn!(ctx.system.log(), "Peer is not providing requested mempool operations"; "peer" => format!("{}", uri), "queued_count" => state.queued_mempool_operations.len(), "response_secs" => self.clock.elapsed(state.mempool_operations_response_last).as_secs());
                     true
                 } else {
                     false
                 };
 
                 if should_disconnect {
-                    ctx.system.stop(state.peer_ref.clone());
+                    notify_peer_misbehaved(&self.network_channel, &state.peer_ref, Misbehavior::Stalled, true, ctx);
                 }
             });
     }
@@ -1260,8 +1602,10 @@ impl Receive<AskPeersAboutCurrentBranch> for ChainManager {
 
     fn receive(&mut self, _ctx: &Context<Self::Msg>, _msg: AskPeersAboutCurrentBranch, _sender: Sender) {
         let ChainManager { peers, chain_state, .. } = self;
-        peers.iter_mut()
-            .for_each(|(_, peer)| tell_peer(GetCurrentBranchMessage::new(chain_state.get_chain_id().clone()).into(), peer))
+        // peers, which deactivated our chain, are asked again only after they announce their head or branch themselves
+        peers.values_mut()
+            .filter(|peer| !peer.chain_deactivated)
+            .for_each(|peer| tell_peer(GetCurrentBranchMessage::new(chain_state.get_chain_id().clone()).into(), peer))
     }
 }
 
@@ -1269,15 +1613,17 @@ impl Receive<AskPeersAboutCurrentBranch> for ChainManager {
 struct PeerState {
     /// Reference to peer actor
     peer_ref: PeerRef,
+    /// Peer id of the remote peer
+    peer_id: String,
     /// Has peer enabled mempool
     mempool_enabled: bool,
     /// Is bootstrapped flag
     is_bootstrapped: bool,
 
-    /// Queued blocks
-    queued_block_headers: HashMap<BlockHash, MissingBlock>,
-    /// Queued block operations
-    queued_block_operations: HashMap<BlockHash, MissingOperations>,
+    /// Queued blocks, with measured download performance of the peer
+    queued_block_headers: DownloadQueue<MissingBlock>,
+    /// Queued block operations, with measured download performance of the peer
+    queued_block_operations: DownloadQueue<MissingOperations>,
     /// Level of the current head received from peer
     current_head_level: Option<i32>,
     /// Last time we received updated head from peer
@@ -1301,45 +1647,44 @@ struct PeerState {
     /// Queued mempool operations. This map holds an operation hash and
     /// a tuple of type of a mempool operation with its time to live.
     queued_mempool_operations: HashMap<OperationHash, (MempoolOperationType, SystemTime)>,
+
+    /// Protocols, whose sources will be requested from the peer by the next chain completeness check.
+    missing_protocols: Vec<ProtocolHash>,
+
+    /// Peer sent `Deactivate` for our chain, it will be activated again by the next `CurrentBranch` or `CurrentHead`
+    chain_deactivated: bool,
 }
 
 impl PeerState {
-    fn new(peer_ref: PeerRef, peer_metadata: MetadataMessage) -> Self {
+    fn new(peer_ref: PeerRef, peer_id: String, peer_metadata: MetadataMessage, now: Instant) -> Self {
         PeerState {
             peer_ref,
+            peer_id,
             mempool_enabled: !peer_metadata.disable_mempool(),
             is_bootstrapped: false,
-            queued_block_headers: HashMap::new(),
-            queued_block_operations: HashMap::new(),
+            queued_block_headers: DownloadQueue::new(),
+            queued_block_operations: DownloadQueue::new(),
             missing_mempool_operations: Vec::new(),
             queued_mempool_operations: HashMap::default(),
+            missing_protocols: Vec::new(),
+            chain_deactivated: false,
             current_head_level: None,
-            current_head_update_last: Instant::now(),
-            block_request_last: Instant::now(),
-            block_response_last: Instant::now(),
-            block_operations_request_last: Instant::now(),
-            block_operations_response_last: Instant::now(),
-            mempool_operations_request_last: Instant::now(),
-            mempool_operations_response_last: Instant::now(),
+            current_head_update_last: now,
+            block_request_last: now,
+            block_response_last: now,
+            block_operations_request_last: now,
+            block_operations_response_last: now,
+            mempool_operations_request_last: now,
+            mempool_operations_response_last: now,
         }
     }
 
     fn available_block_queue_capacity(&self) -> usize {
-        let queued_count = self.queued_block_headers.len();
-        if queued_count < BLOCK_HEADERS_BATCH_SIZE {
-            BLOCK_HEADERS_BATCH_SIZE - queued_count
-        } else {
-            0
-        }
+        self.queued_block_headers.available_capacity()
     }
 
     fn available_block_operations_queue_capacity(&self) -> usize {
-        let queued_count = self.queued_block_operations.len();
-        if queued_count < BLOCK_OPERATIONS_BATCH_SIZE {
-            BLOCK_OPERATIONS_BATCH_SIZE - queued_count
-        } else {
-            0
-        }
+        self.queued_block_operations.available_capacity()
     }
 
     fn available_mempool_operations_queue_capacity(&self) -> usize {
@@ -1356,10 +1701,33 @@ fn tell_peer(msg: PeerMessageResponse, peer: &PeerState) {
     peer.peer_ref.tell(SendMessage::new(msg), None);
 }
 
+/// Notify peer manager that peer misbehaved, so it can adjust peer reputation.
+///
+/// If `disconnect` is set, peer manager stops the peer after the penalty is recorded.
+fn notify_peer_misbehaved(network_channel: &NetworkChannelRef, peer: &PeerRef, misbehavior: Misbehavior, disconnect: bool, ctx: &Context<ChainManagerMsg>) {
+    network_channel.tell(
+        Publish {
+            msg: PeerMisbehaved {
+                peer: peer.clone(),
+                misbehavior,
+                disconnect,
+            }.into(),
+            topic: NetworkChannelTopic::NetworkEvents.into(),
+        }, Some(ctx.myself().into()));
+}
+
+/// Extract `next_protocol` hash from the block header metadata, it is the protocol of the successor blocks
+fn resolve_next_protocol(json_data: &BlockJsonData) -> Result<Option<ProtocolHash>, Error> {
+    let metadata: serde_json::Value = serde_json::from_str(json_data.block_header_proto_metadata_json())?;
+    metadata["next_protocol"].as_str()
+        .map(|protocol_hash| HashType::ProtocolHash.string_to_bytes(protocol_hash).map_err(Error::from))
+        .transpose()
+}
+
 fn resolve_mempool_to_send(mempool_state: &CurrentMempoolState) -> Mempool {
-    // collect for mempool
-    let known_valid = mempool_state.result.applied.iter().map(|a| a.hash.clone()).collect::<Vec<OperationHash>>();
-    let pending = mempool_state.pending.iter().cloned().collect::<Vec<OperationHash>>();
+    // collect for mempool, lists are limited by the encoding
+    let known_valid = mempool_state.result.applied.iter().map(|a| a.hash.clone()).take(MEMPOOL_MAX_OPERATIONS).collect::<Vec<OperationHash>>();
+    let pending = mempool_state.pending.iter().cloned().take(MEMPOOL_MAX_OPERATIONS).collect::<Vec<OperationHash>>();
 
     Mempool::new(known_valid, pending)
 }
@@ -1385,6 +1753,19 @@ fn resolve_mempool_to_send_to_peer(peer: &PeerState, mempool_state: &Option<Arc<
     }
 }
 
+/// Returns operations, which were refused by mempool, so there is no need to fetch them again
+fn resolve_refused_operations(mempool_state: &Option<Arc<RwLock<CurrentMempoolState>>>) -> HashSet<OperationHash> {
+    if let Some(mempool_state) = mempool_state {
+        let mempool_state = mempool_state.read().unwrap();
+        mempool_state.result.refused
+            .iter()
+            .map(|op| op.hash.clone())
+            .collect()
+    } else {
+        HashSet::new()
+    }
+}
+
 #[cfg(test)]
 pub mod tests {
     use std::net::SocketAddr;
@@ -1393,13 +1774,16 @@ pub mod tests {
     use slog::{Drain, Level, Logger};
 
     use networking::p2p::network_channel::NetworkChannel;
-    use networking::p2p::peer::Peer;
+    use networking::p2p::peer::{KeepaliveConfig, Peer};
+    use networking::p2p::rate_limit::PeerRateLimiting;
+    use networking::p2p::stats::{NetworkStats, PeerStats};
     use storage::tests_common::TmpStorage;
     use tezos_api::environment::{TEZOS_ENV, TezosEnvironment, TezosEnvironmentConfiguration};
     use tezos_api::ffi::TezosRuntimeConfiguration;
     use tezos_wrapper::service::ProtocolEndpointConfiguration;
     use tezos_wrapper::TezosApiConnectionPoolConfiguration;
 
+    use crate::chain_registry::ChainRegistry;
     use crate::shell_channel::{ShellChannel, ShuttingDown};
 
     use super::*;
@@ -1432,12 +1816,18 @@ pub mod tests {
             "eaef40186db19fd6f56ed5b1af57f9d9c8a1eed85c29f8e4daaa7367869c0f0b",
             "eaef40186db19fd6f56ed5b1af57f9d9c8a1eed85c29f8e4daaa7367869c0f0b",
             "000000000000000000000000000000000000000000000000",
+            0f64,
             NetworkVersion::new("testet".to_string(), 0, 0),
+            None,
+            KeepaliveConfig::default(),
             tokio_runtime.handle().clone(),
             &socket_address,
+            PeerRateLimiting::unlimited(),
+            PeerStats::new(&NetworkStats::default()),
+            system_clock(),
         ).unwrap();
 
-        PeerState::new(peer, MetadataMessage::new(false, false))
+        PeerState::new(peer, "idtJunqYgSTgkwuPkFSBBGLh4fVtYM".to_string(), MetadataMessage::new(false, false), Instant::now())
     }
 
     fn assert_peer_bootstrapped(chain_manager: &mut ChainManager, peer_uri: &ActorUri, expected_is_bootstrap: bool) {
@@ -1490,9 +1880,12 @@ pub mod tests {
             shell_channel.clone(),
             storage.storage().clone(),
             pool,
+            Arc::new(RwLock::new(ChainRegistry::new(chain_id.clone()))),
             chain_id,
             false,
             1,
+            Duration::from_secs(120),
+            system_clock(),
         ));
 
         // empty chain_manager
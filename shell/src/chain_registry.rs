@@ -0,0 +1,306 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Registry of the chains, which are validated by the node.
+//!
+//! Main chain is always active. Test chain is registered, when an applied block forks it
+//! (see [ForkingTestchainData](tezos_api::ffi::ForkingTestchainData)), and it is removed,
+//! when it expires or is deactivated. Every active chain has its own chain validator
+//! ([ChainManager](crate::chain_manager::ChainManager) + [MempoolPrevalidator](crate::mempool_prevalidator::MempoolPrevalidator)),
+//! test chain validators are run by [ChainSupervisor](crate::chain_supervisor::ChainSupervisor).
+//!
+//! Shell events of the main chain are published as [ShellChannelTopic::ShellEvents],
+//! events of the test chains have their own topic (see [ChainRegistry::events_topic]).
+//! Incoming p2p messages are routed to the chain validators by [route_peer_message]. Responses are not tagged
+//! with the chain id, so hashes requested by the test chain validators are kept in the registry for a while
+//! (see [ChainRegistry::is_test_chain_request]) and main chain validator does not penalize peers for them.
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use riker::actors::Topic;
+
+use crypto::hash::{BlockHash, ChainId, HashType, ProtocolHash};
+use tezos_messages::p2p::encoding::prelude::PeerMessage;
+
+use crate::shell_channel::ShellChannelTopic;
+
+/// Requests of the test chain validators are forgotten after this time, it is longer than any request timeout
+const TEST_CHAIN_REQUEST_TTL: Duration = Duration::from_secs(300);
+
+/// Thread safe reference to a shared chain registry
+pub type ChainRegistryRef = Arc<RwLock<ChainRegistry>>;
+
+/// Attributes of the test chain
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TestChain {
+    /// Block which forked the test chain, `None` if the test chain was restored from storage
+    pub forking_block_hash: Option<BlockHash>,
+    /// Protocol tested on the test chain
+    pub protocol: Option<ProtocolHash>,
+    /// UNIX timestamp, when test chain expires
+    pub expiration: Option<i64>,
+}
+
+/// Chain validated by the node
+#[derive(Clone, Debug, PartialEq)]
+pub struct ActiveChain {
+    pub chain_id: ChainId,
+    /// Test chain attributes, `None` for the main chain
+    pub test_chain: Option<TestChain>,
+    /// Chain validator is being stopped
+    pub stopping: bool,
+}
+
+impl ActiveChain {
+    #[inline]
+    pub fn is_test_chain(&self) -> bool {
+        self.test_chain.is_some()
+    }
+}
+
+/// Active chains, main chain is always the first one
+#[derive(Debug)]
+pub struct ChainRegistry {
+    chains: Vec<ActiveChain>,
+    /// Hashes of the blocks and protocols requested by the test chain validators, with the time of the request
+    test_chain_requests: HashMap<Vec<u8>, Instant>,
+}
+
+impl ChainRegistry {
+    pub fn new(main_chain_id: ChainId) -> Self {
+        ChainRegistry {
+            chains: vec![ActiveChain { chain_id: main_chain_id, test_chain: None, stopping: false }],
+            test_chain_requests: HashMap::new(),
+        }
+    }
+
+    #[inline]
+    pub fn main_chain_id(&self) -> &ChainId {
+        &self.chains[0].chain_id
+    }
+
+    #[inline]
+    pub fn is_main_chain(&self, chain_id: &ChainId) -> bool {
+        self.main_chain_id() == chain_id
+    }
+
+    /// Running test chain (test chain, which is being stopped, is not returned)
+    pub fn test_chain_id(&self) -> Option<&ChainId> {
+        self.chains.iter()
+            .find(|chain| chain.is_test_chain() && !chain.stopping)
+            .map(|chain| &chain.chain_id)
+    }
+
+    #[inline]
+    pub fn has_test_chains(&self) -> bool {
+        self.chains.len() > 1
+    }
+
+    pub fn get(&self, chain_id: &ChainId) -> Option<&ActiveChain> {
+        self.chains.iter().find(|chain| &chain.chain_id == chain_id)
+    }
+
+    #[inline]
+    pub fn chains(&self) -> &[ActiveChain] {
+        &self.chains
+    }
+
+    /// Register new test chain, returns false, if the chain is already registered
+    pub fn register_test_chain(&mut self, chain_id: ChainId, test_chain: TestChain) -> bool {
+        if self.get(&chain_id).is_some() {
+            false
+        } else {
+            self.chains.push(ActiveChain { chain_id, test_chain: Some(test_chain), stopping: false });
+            true
+        }
+    }
+
+    /// Mark test chain as stopping, returns false, if the chain is not a running test chain
+    pub fn mark_stopping(&mut self, chain_id: &ChainId) -> bool {
+        match self.chains.iter_mut().find(|chain| &chain.chain_id == chain_id && chain.is_test_chain()) {
+            Some(chain) if !chain.stopping => {
+                chain.stopping = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Remove test chain from the registry, main chain cannot be removed
+    pub fn unregister(&mut self, chain_id: &ChainId) -> Option<ActiveChain> {
+        let position = self.chains.iter().position(|chain| &chain.chain_id == chain_id && chain.is_test_chain())?;
+        let chain = self.chains.remove(position);
+        if !self.has_test_chains() {
+            self.test_chain_requests.clear();
+        }
+        Some(chain)
+    }
+
+    /// Remember block (header or operations) or protocol hash requested by the test chain validator
+    pub fn add_test_chain_request(&mut self, hash: Vec<u8>, now: Instant) {
+        self.test_chain_requests.insert(hash, now);
+    }
+
+    /// Response for the `hash` could be requested by the test chain validator
+    #[inline]
+    pub fn is_test_chain_request(&self, hash: &[u8]) -> bool {
+        self.test_chain_requests.contains_key(hash)
+    }
+
+    /// Forget requests older than [`TEST_CHAIN_REQUEST_TTL`], their responses are not expected anymore
+    pub fn expire_test_chain_requests(&mut self, now: Instant) {
+        self.test_chain_requests.retain(|_, requested| now.saturating_duration_since(*requested) <= TEST_CHAIN_REQUEST_TTL);
+    }
+
+    /// Resolve chain from the RPC path parameter, which is `main`, `test` or base58 encoded chain id.
+    /// Returns `None`, if the chain is not active.
+    pub fn resolve(&self, chain: &str) -> Option<ChainId> {
+        match chain {
+            "main" => Some(self.main_chain_id().clone()),
+            "test" => self.test_chain_id().cloned(),
+            chain_id => HashType::ChainId.string_to_bytes(chain_id).ok()
+                .filter(|chain_id| self.get(chain_id).is_some()),
+        }
+    }
+
+    /// Topic of the shell channel, where events of the chain validator are published
+    pub fn events_topic(&self, chain_id: &ChainId) -> Topic {
+        if self.is_main_chain(chain_id) {
+            ShellChannelTopic::ShellEvents.into()
+        } else {
+            ShellChannelTopic::TestChainEvents(chain_id.clone()).into()
+        }
+    }
+}
+
+/// Describes, which chain validator should process the p2p message
+#[derive(Debug, PartialEq)]
+pub enum MessageRoute<'a> {
+    /// Message belongs to the chain validator of the chain
+    Chain(&'a ChainId),
+    /// Request for the data, which are shared by all chains (blocks, operations, protocols), it is answered by the main chain validator
+    SharedRequest,
+    /// Response to the request of some chain validator, it is processed by the validator, which requested it
+    Response,
+    /// Message is not processed by chain validators (e.g. handled by peer manager)
+    Other,
+}
+
+pub fn route_peer_message(message: &PeerMessage) -> MessageRoute {
+    match message {
+        PeerMessage::GetCurrentBranch(message) => MessageRoute::Chain(&message.chain_id),
+        PeerMessage::CurrentBranch(message) => MessageRoute::Chain(message.chain_id()),
+        PeerMessage::Deactivate(message) => MessageRoute::Chain(message.deactivate()),
+        PeerMessage::GetCurrentHead(message) => MessageRoute::Chain(message.chain_id()),
+        PeerMessage::CurrentHead(message) => MessageRoute::Chain(message.chain_id()),
+        PeerMessage::GetBlockHeaders(_)
+        | PeerMessage::GetOperations(_)
+        | PeerMessage::GetProtocols(_)
+        | PeerMessage::GetOperationHashesForBlocks(_)
+        | PeerMessage::GetOperationsForBlocks(_) => MessageRoute::SharedRequest,
+        PeerMessage::BlockHeader(_)
+        | PeerMessage::Operation(_)
+        | PeerMessage::Protocol(_)
+        | PeerMessage::OperationHashesForBlock(_)
+        | PeerMessage::OperationsForBlocks(_) => MessageRoute::Response,
+        PeerMessage::Disconnect
+        | PeerMessage::Advertise(_)
+        | PeerMessage::SwapRequest(_)
+        | PeerMessage::SwapAck(_)
+        | PeerMessage::Bootstrap => MessageRoute::Other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tezos_messages::p2p::encoding::prelude::{DeactivateMessage, GetBlockHeadersMessage, GetCurrentHeadMessage};
+
+    use super::*;
+
+    #[test]
+    fn test_resolve_chain() -> Result<(), failure::Error> {
+        let main_chain_id = HashType::ChainId.string_to_bytes("NetXgtSLGNJvNye")?;
+        let test_chain_id = HashType::ChainId.string_to_bytes("NetXdQprcVkpaWU")?;
+        let mut registry = ChainRegistry::new(main_chain_id.clone());
+
+        assert_eq!(Some(main_chain_id.clone()), registry.resolve("main"));
+        assert_eq!(Some(main_chain_id.clone()), registry.resolve("NetXgtSLGNJvNye"));
+        assert_eq!(None, registry.resolve("test"));
+        assert_eq!(None, registry.resolve("NetXdQprcVkpaWU"));
+        assert_eq!(None, registry.resolve("invalid"));
+
+        assert!(registry.register_test_chain(test_chain_id.clone(), TestChain::default()));
+        assert!(!registry.register_test_chain(test_chain_id.clone(), TestChain::default()));
+        assert_eq!(Some(test_chain_id.clone()), registry.resolve("test"));
+        assert_eq!(Some(test_chain_id.clone()), registry.resolve("NetXdQprcVkpaWU"));
+
+        // stopping test chain is still active, but it is not resolved as "test" anymore
+        assert!(registry.mark_stopping(&test_chain_id));
+        assert!(!registry.mark_stopping(&test_chain_id));
+        assert!(!registry.mark_stopping(&main_chain_id));
+        assert_eq!(None, registry.resolve("test"));
+        assert_eq!(Some(test_chain_id.clone()), registry.resolve("NetXdQprcVkpaWU"));
+
+        // requests of the test chain are forgotten with the test chain
+        let block_hash = HashType::BlockHash.string_to_bytes("BLockGenesisGenesisGenesisGenesisGenesisb83baZgbyZe")?;
+        registry.add_test_chain_request(block_hash.clone(), Instant::now());
+        assert!(registry.is_test_chain_request(&block_hash));
+
+        assert!(registry.unregister(&main_chain_id).is_none());
+        assert!(registry.unregister(&test_chain_id).is_some());
+        assert!(!registry.has_test_chains());
+        assert!(!registry.is_test_chain_request(&block_hash));
+        assert_eq!(None, registry.resolve("NetXdQprcVkpaWU"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_expire_test_chain_requests() -> Result<(), failure::Error> {
+        let main_chain_id = HashType::ChainId.string_to_bytes("NetXgtSLGNJvNye")?;
+        let test_chain_id = HashType::ChainId.string_to_bytes("NetXdQprcVkpaWU")?;
+        let mut registry = ChainRegistry::new(main_chain_id);
+        assert!(registry.register_test_chain(test_chain_id, TestChain::default()));
+
+        let old_hash = HashType::BlockHash.string_to_bytes("BLockGenesisGenesisGenesisGenesisGenesisb83baZgbyZe")?;
+        let new_hash = HashType::BlockHash.string_to_bytes("BLockGenesisGenesisGenesisGenesisGenesisd6f5afWyME7")?;
+        let requested = Instant::now();
+        registry.add_test_chain_request(old_hash.clone(), requested);
+        registry.add_test_chain_request(new_hash.clone(), requested + Duration::from_secs(60));
+
+        registry.expire_test_chain_requests(requested + TEST_CHAIN_REQUEST_TTL);
+        assert!(registry.is_test_chain_request(&old_hash));
+        registry.expire_test_chain_requests(requested + TEST_CHAIN_REQUEST_TTL + Duration::from_secs(1));
+        assert!(!registry.is_test_chain_request(&old_hash));
+        assert!(registry.is_test_chain_request(&new_hash));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_events_topic() -> Result<(), failure::Error> {
+        let main_chain_id = HashType::ChainId.string_to_bytes("NetXgtSLGNJvNye")?;
+        let test_chain_id = HashType::ChainId.string_to_bytes("NetXdQprcVkpaWU")?;
+        let registry = ChainRegistry::new(main_chain_id.clone());
+
+        let main_topic: Topic = ShellChannelTopic::ShellEvents.into();
+        assert_eq!(main_topic, registry.events_topic(&main_chain_id));
+        assert_ne!(main_topic, registry.events_topic(&test_chain_id));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_route_peer_message() -> Result<(), failure::Error> {
+        let chain_id = HashType::ChainId.string_to_bytes("NetXgtSLGNJvNye")?;
+
+        assert_eq!(MessageRoute::Chain(&chain_id), route_peer_message(&PeerMessage::GetCurrentHead(GetCurrentHeadMessage::new(chain_id.clone()))));
+        assert_eq!(MessageRoute::Chain(&chain_id), route_peer_message(&PeerMessage::Deactivate(DeactivateMessage::new(chain_id.clone()))));
+        assert_eq!(MessageRoute::SharedRequest, route_peer_message(&PeerMessage::GetBlockHeaders(GetBlockHeadersMessage::new(vec![]))));
+        assert_eq!(MessageRoute::Other, route_peer_message(&PeerMessage::Bootstrap));
+
+        Ok(())
+    }
+}
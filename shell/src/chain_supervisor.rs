@@ -0,0 +1,423 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Starts and stops validators of the test chains.
+//!
+//! When an applied block forks a test chain ([TestChainForked]), the test chain is registered in the
+//! [ChainRegistry](crate::chain_registry::ChainRegistry) and its own [ChainManager] and [MempoolPrevalidator]
+//! are started. Test chain validator is stopped, when the test chain expires or when the `test_chain_status`
+//! of the main chain head does not run it anymore. `Deactivate` messages of the peers just stop synchronization
+//! with the peer, which sent it (see [ChainManager]), they never stop the test chain itself.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use failure::{Error, format_err};
+use riker::actors::*;
+use slog::{debug, info, warn};
+
+use crypto::hash::{ChainId, HashType};
+use networking::p2p::network_channel::{NetworkChannelMsg, NetworkChannelRef, PeerBootstrapped};
+use storage::{BlockJsonData, BlockStorage, BlockStorageReader, ChainMetaStorage};
+use storage::chain_meta_storage::ChainMetaStorageReader;
+use storage::persistent::PersistentStorage;
+use tezos_messages::Head;
+use tezos_wrapper::TezosApiConnectionPool;
+
+use crate::chain_manager::{ChainManager, ChainManagerRef};
+use crate::chain_registry::{ChainRegistryRef, TestChain};
+use crate::mempool_prevalidator::{MempoolLimits, MempoolPrevalidator, MempoolPrevalidatorRef};
+use crate::PeerConnectionThreshold;
+use crate::shell_channel::{ShellChannelMsg, ShellChannelRef, ShuttingDown, TestChainForked};
+use crate::subscription::*;
+
+/// How often to check expiration of the test chains
+const CHECK_TEST_CHAIN_EXPIRATION_INTERVAL: Duration = Duration::from_secs(60);
+/// Validator of the stopped test chain has this time to process already received messages
+const STOP_TEST_CHAIN_LINGER: Duration = Duration::from_secs(5);
+
+/// Check, if some test chain expired
+#[derive(Clone, Debug)]
+pub struct CheckTestChainExpiration;
+
+/// Stop validator of the test chain, which was already notified about shutting down
+#[derive(Clone, Debug)]
+pub struct StopTestChainValidator {
+    chain_id: ChainId,
+}
+
+/// Status of the test chain in the metadata of the main chain block
+#[derive(Debug, PartialEq)]
+enum TestChainStatus {
+    NotRunning,
+    Forking,
+    Running(ChainId),
+}
+
+/// Resolve `test_chain_status` from the block metadata, `None` if the metadata do not contain it
+fn resolve_test_chain_status(json_data: &BlockJsonData) -> Option<TestChainStatus> {
+    let metadata = serde_json::from_str::<serde_json::Value>(json_data.block_header_proto_metadata_json()).ok()?;
+    let test_chain_status = metadata.get("test_chain_status")?;
+    match test_chain_status["status"].as_str()? {
+        "not_running" => Some(TestChainStatus::NotRunning),
+        "forking" => Some(TestChainStatus::Forking),
+        "running" => test_chain_status["chain_id"].as_str()
+            .and_then(|chain_id| HashType::ChainId.string_to_bytes(chain_id).ok())
+            .map(TestChainStatus::Running),
+        _ => None,
+    }
+}
+
+/// Validator of the test chain
+struct TestChainValidator {
+    chain_manager: ChainManagerRef,
+    mempool_prevalidator: MempoolPrevalidatorRef,
+}
+
+/// Arguments of the test chain validators
+#[derive(Clone)]
+pub struct ChainSupervisorConfiguration {
+    pub tezos_readonly_api: Arc<TezosApiConnectionPool>,
+    pub tezos_readonly_prevalidation_api: Arc<TezosApiConnectionPool>,
+    pub mempool_limits: MempoolLimits,
+    pub is_sandbox: bool,
+    pub peers_threshold: PeerConnectionThreshold,
+    /// Peer behind our current head is disconnected, if its current head level stays the same for this long
+    pub current_head_update_timeout: Duration,
+}
+
+/// This actor is responsible for the lifecycle of the test chain validators.
+#[actor(CheckTestChainExpiration, StopTestChainValidator, NetworkChannelMsg, ShellChannelMsg, SystemEvent)]
+pub struct ChainSupervisor {
+    /// All events generated by the network layer will end up in this channel
+    network_channel: NetworkChannelRef,
+    /// All events from shell will be published to this channel
+    shell_channel: ShellChannelRef,
+    persistent_storage: PersistentStorage,
+    block_storage: BlockStorage,
+    chain_meta_storage: ChainMetaStorage,
+    /// Chains validated by the node
+    chain_registry: ChainRegistryRef,
+    configuration: ChainSupervisorConfiguration,
+    /// Running test chain validators
+    validators: HashMap<ChainId, TestChainValidator>,
+    /// Bootstrapped peers, they are introduced to the validators of the new test chains
+    peers: HashMap<ActorUri, PeerBootstrapped>,
+    /// Indicates that system is shutting down
+    shutting_down: bool,
+}
+
+/// Reference to [chain supervisor](ChainSupervisor) actor
+pub type ChainSupervisorRef = ActorRef<ChainSupervisorMsg>;
+
+impl ChainSupervisor {
+    pub fn actor(
+        sys: &impl ActorRefFactory,
+        network_channel: NetworkChannelRef,
+        shell_channel: ShellChannelRef,
+        persistent_storage: &PersistentStorage,
+        chain_registry: ChainRegistryRef,
+        configuration: ChainSupervisorConfiguration) -> Result<ChainSupervisorRef, CreateError> {
+        sys.actor_of_props::<ChainSupervisor>(
+            ChainSupervisor::name(),
+            Props::new_args((network_channel, shell_channel, persistent_storage.clone(), chain_registry, configuration)),
+        )
+    }
+
+    /// The `ChainSupervisor` is intended to serve as a singleton actor so that's why
+    /// we won't support multiple names per instance.
+    fn name() -> &'static str {
+        "chain-supervisor"
+    }
+
+    fn start_test_chain(&mut self, ctx: &Context<ChainSupervisorMsg>, chain_id: ChainId, test_chain: TestChain) -> Result<(), Error> {
+        if self.shutting_down {
+            return Ok(());
+        }
+
+        let forking_block_hash = test_chain.forking_block_hash.clone();
+        let main_chain_id = {
+            let mut chain_registry = self.chain_registry.write().unwrap();
+            if !chain_registry.register_test_chain(chain_id.clone(), test_chain) {
+                debug!(ctx.system.log(), "Test chain is already active"; "chain_id" => HashType::ChainId.bytes_to_string(&chain_id));
+                return Ok(());
+            }
+            chain_registry.main_chain_id().clone()
+        };
+        self.chain_meta_storage.set_test_chain_id(&main_chain_id, &chain_id)?;
+
+        // test chain starts from the block, which forked it
+        if self.chain_meta_storage.get_current_head(&chain_id)?.is_none() {
+            if let Some(forking_block_hash) = forking_block_hash {
+                if let Some(forking_block) = self.block_storage.get(&forking_block_hash)? {
+                    self.chain_meta_storage.set_current_head(
+                        &chain_id,
+                        Head::new(forking_block.hash.clone(), forking_block.header.level(), forking_block.header.fitness().clone()),
+                    )?;
+                }
+            }
+        }
+
+        let ChainSupervisorConfiguration { tezos_readonly_api, tezos_readonly_prevalidation_api, mempool_limits, is_sandbox, peers_threshold, current_head_update_timeout } = &self.configuration;
+        let chain_manager = ChainManager::actor(
+            ctx,
+            self.network_channel.clone(),
+            self.shell_channel.clone(),
+            &self.persistent_storage,
+            tezos_readonly_prevalidation_api.clone(),
+            self.chain_registry.clone(),
+            &chain_id,
+            *is_sandbox,
+            peers_threshold,
+            *current_head_update_timeout,
+        ).map_err(|e| format_err!("Failed to create test chain manager, reason: {:?}", e))?;
+        let mempool_prevalidator = MempoolPrevalidator::actor_for_test_chain(
+            ctx,
+            self.shell_channel.clone(),
+            &self.persistent_storage,
+            &chain_id,
+            tezos_readonly_api.clone(),
+            mempool_limits.clone(),
+            ctx.system.log(),
+        ).map_err(|e| format_err!("Failed to create test chain mempool prevalidator, reason: {:?}", e))?;
+
+        // peers connected before the test chain was forked are introduced to the new chain manager
+        for peer in self.peers.values() {
+            chain_manager.tell(NetworkChannelMsg::PeerBootstrapped(peer.clone()), None);
+        }
+
+        info!(ctx.system.log(), "Test chain validator started"; "chain_id" => HashType::ChainId.bytes_to_string(&chain_id));
+        self.validators.insert(chain_id, TestChainValidator { chain_manager, mempool_prevalidator });
+        Ok(())
+    }
+
+    fn stop_test_chain(&mut self, ctx: &Context<ChainSupervisorMsg>, chain_id: &ChainId, reason: &str) {
+        if !self.chain_registry.write().unwrap().mark_stopping(chain_id) {
+            return;
+        }
+
+        info!(ctx.system.log(), "Stopping test chain validator"; "chain_id" => HashType::ChainId.bytes_to_string(chain_id), "reason" => reason);
+        if let Some(validator) = self.validators.get(chain_id) {
+            // validators stop processing of new messages at first, actors are stopped later
+            validator.chain_manager.tell(ShellChannelMsg::ShuttingDown(ShuttingDown), None);
+            validator.mempool_prevalidator.tell(ShellChannelMsg::ShuttingDown(ShuttingDown), None);
+        }
+        ctx.schedule_once(
+            STOP_TEST_CHAIN_LINGER,
+            ctx.myself(),
+            None,
+            StopTestChainValidator { chain_id: chain_id.clone() });
+    }
+
+    fn process_network_channel_message(&mut self, msg: NetworkChannelMsg) {
+        if let NetworkChannelMsg::PeerBootstrapped(msg) = msg {
+            if let PeerBootstrapped::Success { peer, .. } = &msg {
+                self.peers.insert(peer.uri().clone(), msg.clone());
+            }
+        }
+    }
+
+    /// Test chains, which are not running according to the new head of the main chain, are stopped
+    fn check_test_chain_status(&mut self, ctx: &Context<ChainSupervisorMsg>, json_data: &BlockJsonData) {
+        let running_chain_id = match resolve_test_chain_status(json_data) {
+            Some(TestChainStatus::NotRunning) => None,
+            Some(TestChainStatus::Running(chain_id)) => Some(chain_id),
+            Some(TestChainStatus::Forking) | None => return,
+        };
+        let stopped = self.chain_registry.read().unwrap().chains().iter()
+            .filter(|chain| chain.is_test_chain() && !chain.stopping)
+            .filter(|chain| running_chain_id.as_ref() != Some(&chain.chain_id))
+            .map(|chain| chain.chain_id.clone())
+            .collect::<Vec<_>>();
+        for chain_id in stopped {
+            self.stop_test_chain(ctx, &chain_id, "not running on the main chain");
+        }
+    }
+}
+
+impl ActorFactoryArgs<(NetworkChannelRef, ShellChannelRef, PersistentStorage, ChainRegistryRef, ChainSupervisorConfiguration)> for ChainSupervisor {
+    fn create_args((network_channel, shell_channel, persistent_storage, chain_registry, configuration): (NetworkChannelRef, ShellChannelRef, PersistentStorage, ChainRegistryRef, ChainSupervisorConfiguration)) -> Self {
+        ChainSupervisor {
+            network_channel,
+            shell_channel,
+            block_storage: BlockStorage::new(&persistent_storage),
+            chain_meta_storage: ChainMetaStorage::new(&persistent_storage),
+            persistent_storage,
+            chain_registry,
+            configuration,
+            validators: HashMap::new(),
+            peers: HashMap::new(),
+            shutting_down: false,
+        }
+    }
+}
+
+impl Actor for ChainSupervisor {
+    type Msg = ChainSupervisorMsg;
+
+    fn pre_start(&mut self, ctx: &Context<Self::Msg>) {
+        subscribe_to_actor_terminated(ctx.system.sys_events(), ctx.myself());
+        subscribe_to_network_events(&self.network_channel, ctx.myself());
+        subscribe_to_shell_events(&self.shell_channel, ctx.myself());
+
+        // restore test chain, which was active before restart
+        let main_chain_id = self.chain_registry.read().unwrap().main_chain_id().clone();
+        match self.chain_meta_storage.get_test_chain_id(&main_chain_id) {
+            Ok(Some(test_chain_id)) => {
+                if let Err(e) = self.start_test_chain(ctx, test_chain_id, TestChain::default()) {
+                    warn!(ctx.system.log(), "Failed to restore test chain"; "reason" => format!("{:?}", e));
+                }
+            }
+            Ok(None) => (),
+            Err(e) => warn!(ctx.system.log(), "Failed to read test chain id"; "reason" => format!("{:?}", e)),
+        }
+
+        ctx.schedule::<Self::Msg, _>(
+            CHECK_TEST_CHAIN_EXPIRATION_INTERVAL,
+            CHECK_TEST_CHAIN_EXPIRATION_INTERVAL,
+            ctx.myself(),
+            None,
+            CheckTestChainExpiration.into());
+    }
+
+    fn sys_recv(&mut self, ctx: &Context<Self::Msg>, msg: SystemMsg, sender: Option<BasicActorRef>) {
+        if let SystemMsg::Event(evt) = msg {
+            self.receive(ctx, evt, sender);
+        }
+    }
+
+    fn recv(&mut self, ctx: &Context<Self::Msg>, msg: Self::Msg, sender: Sender) {
+        self.receive(ctx, msg, sender);
+    }
+}
+
+impl Receive<SystemEvent> for ChainSupervisor {
+    type Msg = ChainSupervisorMsg;
+
+    fn receive(&mut self, _ctx: &Context<Self::Msg>, msg: SystemEvent, _sender: Option<BasicActorRef>) {
+        if let SystemEvent::ActorTerminated(evt) = msg {
+            self.peers.remove(evt.actor.uri());
+        }
+    }
+}
+
+impl Receive<NetworkChannelMsg> for ChainSupervisor {
+    type Msg = ChainSupervisorMsg;
+
+    fn receive(&mut self, _ctx: &Context<Self::Msg>, msg: NetworkChannelMsg, _sender: Sender) {
+        if self.shutting_down {
+            return;
+        }
+        self.process_network_channel_message(msg)
+    }
+}
+
+impl Receive<ShellChannelMsg> for ChainSupervisor {
+    type Msg = ChainSupervisorMsg;
+
+    fn receive(&mut self, ctx: &Context<Self::Msg>, msg: ShellChannelMsg, _sender: Sender) {
+        match msg {
+            ShellChannelMsg::TestChainForked(TestChainForked { chain_id, forking_block_hash, protocol, expiration }) => {
+                let test_chain = TestChain { forking_block_hash: Some(forking_block_hash), protocol, expiration };
+                if let Err(e) = self.start_test_chain(ctx, chain_id, test_chain) {
+                    warn!(ctx.system.log(), "Failed to start test chain validator"; "reason" => format!("{:?}", e));
+                }
+            }
+            ShellChannelMsg::NewCurrentHead(_, block) if !self.shutting_down => {
+                // only events of the main chain are received
+                self.check_test_chain_status(ctx, block.json_data());
+            }
+            ShellChannelMsg::ShuttingDown(_) => {
+                self.shutting_down = true;
+            }
+            _ => (),
+        }
+    }
+}
+
+impl Receive<CheckTestChainExpiration> for ChainSupervisor {
+    type Msg = ChainSupervisorMsg;
+
+    fn receive(&mut self, ctx: &Context<Self::Msg>, _msg: CheckTestChainExpiration, _sender: Sender) {
+        if self.shutting_down {
+            return;
+        }
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs() as i64).unwrap_or(0);
+        let expired = self.chain_registry.read().unwrap().chains().iter()
+            .filter(|chain| !chain.stopping)
+            .filter(|chain| match &chain.test_chain {
+                Some(TestChain { expiration: Some(expiration), .. }) => *expiration <= now,
+                _ => false,
+            })
+            .map(|chain| chain.chain_id.clone())
+            .collect::<Vec<_>>();
+        for chain_id in expired {
+            self.stop_test_chain(ctx, &chain_id, "expired");
+        }
+    }
+}
+
+impl Receive<StopTestChainValidator> for ChainSupervisor {
+    type Msg = ChainSupervisorMsg;
+
+    fn receive(&mut self, ctx: &Context<Self::Msg>, msg: StopTestChainValidator, _sender: Sender) {
+        if let Some(validator) = self.validators.remove(&msg.chain_id) {
+            ctx.stop(validator.chain_manager);
+            ctx.stop(validator.mempool_prevalidator);
+        }
+
+        let main_chain_id = {
+            let mut chain_registry = self.chain_registry.write().unwrap();
+            chain_registry.unregister(&msg.chain_id);
+            chain_registry.main_chain_id().clone()
+        };
+        match self.chain_meta_storage.get_test_chain_id(&main_chain_id) {
+            Ok(Some(test_chain_id)) if test_chain_id == msg.chain_id => {
+                if let Err(e) = self.chain_meta_storage.remove_test_chain_id(&main_chain_id) {
+                    warn!(ctx.system.log(), "Failed to remove test chain id"; "reason" => format!("{:?}", e));
+                }
+            }
+            Ok(_) => (),
+            Err(e) => warn!(ctx.system.log(), "Failed to read test chain id"; "reason" => format!("{:?}", e)),
+        }
+
+        info!(ctx.system.log(), "Test chain validator stopped"; "chain_id" => HashType::ChainId.bytes_to_string(&msg.chain_id));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use storage::BlockJsonDataBuilder;
+
+    use super::*;
+
+    fn json_data(block_header_proto_metadata_json: &str) -> BlockJsonData {
+        BlockJsonDataBuilder::default()
+            .block_header_proto_json("{}".to_string())
+            .block_header_proto_metadata_json(block_header_proto_metadata_json.to_string())
+            .operations_proto_metadata_json("[]".to_string())
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_resolve_test_chain_status() -> Result<(), failure::Error> {
+        let test_chain_id = HashType::ChainId.string_to_bytes("NetXdQprcVkpaWU")?;
+
+        assert_eq!(Some(TestChainStatus::NotRunning), resolve_test_chain_status(&json_data(r#"{ "test_chain_status": { "status": "not_running" } }"#)));
+        assert_eq!(
+            Some(TestChainStatus::Forking),
+            resolve_test_chain_status(&json_data(r#"{ "test_chain_status": { "status": "forking", "protocol": "PsCARTHAGazKbHtnKfLzQg3kms52kSRpgnDY982a9oYsSXRLQEb", "expiration": "2020-08-01T00:00:00Z" } }"#)),
+        );
+        assert_eq!(
+            Some(TestChainStatus::Running(test_chain_id)),
+            resolve_test_chain_status(&json_data(r#"{ "test_chain_status": { "status": "running", "chain_id": "NetXdQprcVkpaWU", "genesis": "BLockGenesisGenesisGenesisGenesisGenesisd6f5afWyME7", "protocol": "PsCARTHAGazKbHtnKfLzQg3kms52kSRpgnDY982a9oYsSXRLQEb", "expiration": "2020-08-01T00:00:00Z" } }"#)),
+        );
+        assert_eq!(None, resolve_test_chain_status(&json_data(r#"{ "protocol": "PsCARTHAGazKbHtnKfLzQg3kms52kSRpgnDY982a9oYsSXRLQEb" }"#)));
+        assert_eq!(None, resolve_test_chain_status(&json_data("invalid")));
+
+        Ok(())
+    }
+}
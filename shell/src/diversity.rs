@@ -0,0 +1,311 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Connection diversity rules protecting the node against eclipse attacks.
+//!
+//! One operator controlling many addresses could otherwise occupy all our peer slots. So we limit the number of
+//! connections per IP address and per subnet (IPv4 /24 and /16, IPv6 /48 and /32), reserve part of the slots for
+//! incoming connections and part of the outgoing slots for trusted and previously connected peers.
+//! When we have too many peers, peers from the most crowded subnets are disconnected first.
+//!
+//! Loopback and private (LAN) addresses are not limited per IP and subnet, all nodes of the local cluster share them.
+
+use std::cmp;
+use std::fmt;
+use std::net::IpAddr;
+
+use tezos_messages::p2p::point::canonical_ip;
+
+use crate::peer_manager::PeerDiversityLimits;
+
+/// How we got to know the peer
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ConnectionKind {
+    /// Remote peer connected to us
+    Incoming,
+    /// Outgoing connection to configured peer, it is not subject to IP and subnet limits
+    Trusted,
+    /// Outgoing connection to peer, we were already connected to in the past
+    Known,
+    /// Outgoing connection to advertised or discovered peer
+    Unknown,
+}
+
+impl ConnectionKind {
+    /// Connection can use the outgoing slots reserved for trusted and known peers
+    fn is_reliable(self) -> bool {
+        matches!(self, ConnectionKind::Trusted | ConnectionKind::Known)
+    }
+}
+
+/// Connection as seen by diversity rules
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Connection {
+    pub(crate) ip: IpAddr,
+    pub(crate) kind: ConnectionKind,
+}
+
+impl Connection {
+    pub(crate) fn new(ip: IpAddr, kind: ConnectionKind) -> Self {
+        Connection { ip: canonical_ip(ip), kind }
+    }
+}
+
+/// Reason why connection is not allowed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum DiversityViolation {
+    IpLimit,
+    /// Limit of the narrow subnet with the prefix length was reached
+    NarrowSubnetLimit(u32),
+    /// Limit of the wide subnet with the prefix length was reached
+    WideSubnetLimit(u32),
+    IncomingSlotsReserved,
+    TrustedSlotsReserved,
+}
+
+impl fmt::Display for DiversityViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DiversityViolation::IpLimit => write!(f, "too many connections with the IP address"),
+            DiversityViolation::NarrowSubnetLimit(prefix) => write!(f, "too many connections with the /{} subnet", prefix),
+            DiversityViolation::WideSubnetLimit(prefix) => write!(f, "too many connections with the /{} subnet", prefix),
+            DiversityViolation::IncomingSlotsReserved => write!(f, "remaining slots are reserved for incoming connections"),
+            DiversityViolation::TrustedSlotsReserved => write!(f, "remaining outgoing slots are reserved for trusted and known peers"),
+        }
+    }
+}
+
+/// Prefix length of the narrow subnet (IPv4 /24, IPv6 /48)
+fn narrow_prefix(ip: &IpAddr) -> u32 {
+    if ip.is_ipv4() { 24 } else { 48 }
+}
+
+/// Prefix length of the wide subnet (IPv4 /16, IPv6 /32)
+fn wide_prefix(ip: &IpAddr) -> u32 {
+    if ip.is_ipv4() { 16 } else { 32 }
+}
+
+/// Network part of the narrow subnet
+fn narrow_subnet(ip: &IpAddr) -> IpAddr {
+    mask(ip, narrow_prefix(ip))
+}
+
+/// Network part of the wide subnet
+fn wide_subnet(ip: &IpAddr) -> IpAddr {
+    mask(ip, wide_prefix(ip))
+}
+
+fn mask(ip: &IpAddr, prefix: u32) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => IpAddr::V4((u32::from(*ip) & (u32::MAX << (32 - prefix))).into()),
+        IpAddr::V6(ip) => IpAddr::V6((u128::from(*ip) & (u128::MAX << (128 - prefix))).into()),
+    }
+}
+
+/// Loopback, private, link local or unique local (IPv6 fc00::/7) address
+fn is_local(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local(),
+        IpAddr::V6(ip) => ip.is_loopback() || (ip.segments()[0] & 0xfe00) == 0xfc00 || (ip.segments()[0] & 0xffc0) == 0xfe80,
+    }
+}
+
+/// Applies [PeerDiversityLimits] to the connections
+pub(crate) struct PeerDiversity {
+    limits: PeerDiversityLimits,
+    /// Maximal count of all connections, see `peer-thresh-high`
+    max_connections: usize,
+}
+
+impl PeerDiversity {
+    pub(crate) fn new(limits: PeerDiversityLimits, max_connections: usize) -> Self {
+        PeerDiversity { limits, max_connections }
+    }
+
+    /// Count of connection slots which can be used by outgoing connections
+    fn outgoing_slots(&self) -> usize {
+        self.max_connections - self.max_connections * cmp::min(self.limits.reserved_incoming_percent, 100) / 100
+    }
+
+    /// Count of outgoing slots which can be used by unknown peers
+    fn unknown_outgoing_slots(&self) -> usize {
+        let outgoing_slots = self.outgoing_slots();
+        outgoing_slots - outgoing_slots * cmp::min(self.limits.reserved_trusted_percent, 100) / 100
+    }
+
+    /// Check if the new connection can be opened besides existing `connections`
+    pub(crate) fn check(&self, connections: &[Connection], candidate: &Connection) -> Result<(), DiversityViolation> {
+        if candidate.kind != ConnectionKind::Trusted && (self.limits.limit_local_addresses || !is_local(&candidate.ip)) {
+            let narrow = narrow_subnet(&candidate.ip);
+            let wide = wide_subnet(&candidate.ip);
+            if connections.iter().filter(|c| c.ip == candidate.ip).count() >= self.limits.max_per_ip {
+                return Err(DiversityViolation::IpLimit);
+            }
+            if connections.iter().filter(|c| narrow_subnet(&c.ip) == narrow).count() >= self.limits.max_per_narrow_subnet {
+                return Err(DiversityViolation::NarrowSubnetLimit(narrow_prefix(&candidate.ip)));
+            }
+            if connections.iter().filter(|c| wide_subnet(&c.ip) == wide).count() >= self.limits.max_per_wide_subnet {
+                return Err(DiversityViolation::WideSubnetLimit(wide_prefix(&candidate.ip)));
+            }
+        }
+
+        if candidate.kind != ConnectionKind::Incoming {
+            let outgoing = connections.iter().filter(|c| c.kind != ConnectionKind::Incoming).count();
+            if outgoing >= self.outgoing_slots() {
+                return Err(DiversityViolation::IncomingSlotsReserved);
+            }
+            let unknown_outgoing = connections.iter().filter(|c| c.kind == ConnectionKind::Unknown).count();
+            if !candidate.kind.is_reliable() && unknown_outgoing >= self.unknown_outgoing_slots() {
+                return Err(DiversityViolation::TrustedSlotsReserved);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Choose `count` connections to disconnect, returns their indexes.
+    ///
+    /// Connections from the most represented subnets and IP addresses are chosen first, trusted peers are chosen last.
+    pub(crate) fn choose_evicted(&self, connections: &[Connection], count: usize) -> Vec<usize> {
+        let mut remaining = (0..connections.len()).collect::<Vec<_>>();
+        let mut evicted = Vec::with_capacity(count);
+
+        while evicted.len() < count && !remaining.is_empty() {
+            let crowd = |ip: &IpAddr| {
+                let narrow = narrow_subnet(ip);
+                let wide = wide_subnet(ip);
+                remaining.iter().fold((0, 0, 0), |(wide_count, narrow_count, ip_count), idx| {
+                    let other = &connections[*idx].ip;
+                    (
+                        wide_count + (wide_subnet(other) == wide) as usize,
+                        narrow_count + (narrow_subnet(other) == narrow) as usize,
+                        ip_count + (other == ip) as usize,
+                    )
+                })
+            };
+            let position = (0..remaining.len())
+                .max_by_key(|position| {
+                    let connection = &connections[remaining[*position]];
+                    let (wide_count, narrow_count, ip_count) = crowd(&connection.ip);
+                    (connection.kind != ConnectionKind::Trusted, narrow_count, wide_count, ip_count)
+                })
+                .unwrap();
+            evicted.push(remaining.remove(position));
+        }
+
+        evicted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> PeerDiversityLimits {
+        PeerDiversityLimits {
+            max_per_ip: 2,
+            max_per_narrow_subnet: 3,
+            max_per_wide_subnet: 4,
+            reserved_trusted_percent: 25,
+            reserved_incoming_percent: 20,
+            limit_local_addresses: false,
+        }
+    }
+
+    /// Simulates `AcceptPeer` and `ConnectToPeer` events, accepted connection is added to `connections`
+    fn connect(diversity: &PeerDiversity, connections: &mut Vec<Connection>, ip: &str, kind: ConnectionKind) -> Result<(), DiversityViolation> {
+        let candidate = Connection::new(ip.parse().unwrap(), kind);
+        diversity.check(connections, &candidate)?;
+        connections.push(candidate);
+        Ok(())
+    }
+
+    #[test]
+    fn test_ip_and_subnet_limits() {
+        let diversity = PeerDiversity::new(limits(), 20);
+        let mut connections = vec![];
+
+        assert_eq!(Ok(()), connect(&diversity, &mut connections, "45.0.1.1", ConnectionKind::Incoming));
+        assert_eq!(Ok(()), connect(&diversity, &mut connections, "45.0.1.1", ConnectionKind::Unknown));
+        assert_eq!(Err(DiversityViolation::IpLimit), connect(&diversity, &mut connections, "45.0.1.1", ConnectionKind::Incoming));
+        // IPv4 mapped address is the same IP address
+        assert_eq!(Err(DiversityViolation::IpLimit), connect(&diversity, &mut connections, "::ffff:45.0.1.1", ConnectionKind::Known));
+
+        assert_eq!(Ok(()), connect(&diversity, &mut connections, "45.0.1.2", ConnectionKind::Unknown));
+        assert_eq!(Err(DiversityViolation::NarrowSubnetLimit(24)), connect(&diversity, &mut connections, "45.0.1.3", ConnectionKind::Incoming));
+
+        assert_eq!(Ok(()), connect(&diversity, &mut connections, "45.0.2.1", ConnectionKind::Unknown));
+        assert_eq!(Err(DiversityViolation::WideSubnetLimit(16)), connect(&diversity, &mut connections, "45.0.3.1", ConnectionKind::Unknown));
+
+        // other subnets and trusted peers are not limited
+        assert_eq!(Ok(()), connect(&diversity, &mut connections, "45.1.1.1", ConnectionKind::Unknown));
+        assert_eq!(Ok(()), connect(&diversity, &mut connections, "45.0.1.1", ConnectionKind::Trusted));
+
+        // IPv6 subnets
+        assert_eq!(Ok(()), connect(&diversity, &mut connections, "2001:db8:1:1::1", ConnectionKind::Unknown));
+        assert_eq!(Ok(()), connect(&diversity, &mut connections, "2001:db8:1:2::1", ConnectionKind::Unknown));
+        assert_eq!(Ok(()), connect(&diversity, &mut connections, "2001:db8:1:3::1", ConnectionKind::Unknown));
+        assert_eq!(Err(DiversityViolation::NarrowSubnetLimit(48)), connect(&diversity, &mut connections, "2001:db8:1:4::1", ConnectionKind::Unknown));
+    }
+
+    #[test]
+    fn test_local_addresses_are_not_limited() {
+        let diversity = PeerDiversity::new(limits(), 40);
+        let mut connections = vec![];
+
+        for ip in &["127.0.0.1", "127.0.0.1", "127.0.0.1", "10.0.1.1", "10.0.1.1", "10.0.1.2", "192.168.1.1", "::1", "::1", "::1", "fd00::1", "fd00::1", "fd00::1"] {
+            assert_eq!(Ok(()), connect(&diversity, &mut connections, ip, ConnectionKind::Unknown));
+        }
+
+        // unless configured otherwise
+        let diversity = PeerDiversity::new(PeerDiversityLimits { limit_local_addresses: true, ..limits() }, 20);
+        let mut connections = vec![];
+        assert_eq!(Ok(()), connect(&diversity, &mut connections, "127.0.0.1", ConnectionKind::Incoming));
+        assert_eq!(Ok(()), connect(&diversity, &mut connections, "127.0.0.1", ConnectionKind::Incoming));
+        assert_eq!(Err(DiversityViolation::IpLimit), connect(&diversity, &mut connections, "127.0.0.1", ConnectionKind::Incoming));
+    }
+
+    #[test]
+    fn test_violation_shows_prefix_length() {
+        assert_eq!("too many connections with the /24 subnet", DiversityViolation::NarrowSubnetLimit(24).to_string());
+        assert_eq!("too many connections with the /32 subnet", DiversityViolation::WideSubnetLimit(32).to_string());
+    }
+
+    #[test]
+    fn test_reserved_slots() {
+        // 10 slots: 2 are reserved for incoming, 2 of 8 outgoing for trusted and known peers
+        let diversity = PeerDiversity::new(limits(), 10);
+        let mut connections = vec![];
+
+        for i in 1..=6 {
+            assert_eq!(Ok(()), connect(&diversity, &mut connections, &format!("45.{}.0.1", i), ConnectionKind::Unknown));
+        }
+        assert_eq!(Err(DiversityViolation::TrustedSlotsReserved), connect(&diversity, &mut connections, "45.7.0.1", ConnectionKind::Unknown));
+        assert_eq!(Ok(()), connect(&diversity, &mut connections, "45.7.0.1", ConnectionKind::Known));
+        assert_eq!(Ok(()), connect(&diversity, &mut connections, "45.8.0.1", ConnectionKind::Trusted));
+        assert_eq!(Err(DiversityViolation::IncomingSlotsReserved), connect(&diversity, &mut connections, "45.9.0.1", ConnectionKind::Trusted));
+
+        // incoming connections can still use the reserved slots
+        assert_eq!(Ok(()), connect(&diversity, &mut connections, "45.9.0.1", ConnectionKind::Incoming));
+        assert_eq!(Ok(()), connect(&diversity, &mut connections, "45.10.0.1", ConnectionKind::Incoming));
+    }
+
+    #[test]
+    fn test_evict_over_represented_subnets() {
+        let diversity = PeerDiversity::new(limits(), 10);
+        let connections = ["45.0.1.1", "45.0.1.2", "45.0.1.3", "45.0.2.1", "45.1.0.1", "45.2.0.1"].iter()
+            .map(|ip| Connection::new(ip.parse().unwrap(), ConnectionKind::Unknown))
+            .chain(vec![Connection::new("45.0.1.4".parse().unwrap(), ConnectionKind::Trusted)])
+            .collect::<Vec<_>>();
+
+        let mut evicted = diversity.choose_evicted(&connections, 2);
+        evicted.sort();
+        assert_eq!(2, evicted.len());
+        // two of the three untrusted peers from the crowded 45.0.1.0/24 subnet
+        assert!(evicted.iter().all(|idx| *idx < 3));
+
+        // trusted peer is evicted last
+        let evicted = diversity.choose_evicted(&connections, connections.len());
+        assert_eq!(Some(&6), evicted.last());
+    }
+}
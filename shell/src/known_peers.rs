@@ -0,0 +1,333 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Known peer points with their connection history.
+//!
+//! All points we learn about (DNS bootstrap, configured peers, `Advertise` and `Nack` messages) are persisted
+//! together with the outcome of our connection attempts. When we need more peers, candidates are chosen by score:
+//! trusted points first, then points we were recently connected to, while repeatedly failing points are avoided.
+
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+
+use rand::seq::SliceRandom;
+
+use storage::{PeerPointInfo, PeerStorage, StorageError};
+
+/// Limit how many not trusted points we remember
+const MAX_KNOWN_POINTS: usize = 1_000;
+/// Point which was never connected successfully is forgotten after this count of failures
+const MAX_FAILURES_TO_FORGET: u32 = 8;
+/// Wait at least this long before retrying failed point, every subsequent failure doubles the interval
+const RETRY_BASE_INTERVAL: Duration = Duration::from_secs(10);
+/// Maximal exponent used for retry interval
+const RETRY_MAX_EXPONENT: u32 = 8;
+
+/// Score of the point, candidates with higher score are preferred
+fn score(info: &PeerPointInfo, now: SystemTime) -> i64 {
+    let mut score = 0;
+    if info.trusted() {
+        score += 1_000;
+    }
+    if let Some(last_success) = info.last_success() {
+        // recently connected points are preferred, bonus fades out within a week
+        let age_hours = now.duration_since(last_success).map(|age| age.as_secs() / 3_600).unwrap_or(0);
+        score += 500 - 2 * cmp::min(age_hours, 168) as i64;
+    }
+    score - 50 * i64::from(info.failure_count())
+}
+
+/// Last time we tried to connect to the point, successfully or not
+fn last_seen(info: &PeerPointInfo) -> Option<SystemTime> {
+    cmp::max(info.last_success(), info.last_failure())
+}
+
+/// Returns true if we should not retry the failed point yet
+fn is_backing_off(info: &PeerPointInfo, now: SystemTime) -> bool {
+    match info.last_failure() {
+        Some(last_failure) if info.failure_count() > 0 => {
+            let exponent = cmp::min(info.failure_count() - 1, RETRY_MAX_EXPONENT);
+            last_failure + RETRY_BASE_INTERVAL * 2u32.pow(exponent) > now
+        }
+        _ => false,
+    }
+}
+
+pub(crate) struct KnownPeers {
+    /// All known points
+    points: HashMap<SocketAddr, PeerPointInfo>,
+    /// Points are persisted here
+    storage: PeerStorage,
+}
+
+impl KnownPeers {
+    pub(crate) fn new(storage: PeerStorage) -> Self {
+        KnownPeers {
+            points: HashMap::new(),
+            storage,
+        }
+    }
+
+    /// Load persisted points from storage. Trusted flag is recomputed from the currently configured `trusted_points`,
+    /// so points removed from the configuration are no longer trusted.
+    pub(crate) fn load(&mut self, trusted_points: &HashSet<SocketAddr>) -> Result<(), StorageError> {
+        self.points = self.storage.iter()?.into_iter().collect();
+        for (point, info) in self.points.iter_mut() {
+            let trusted = trusted_points.contains(point);
+            if info.trusted() != trusted {
+                info.set_trusted(trusted);
+                self.storage.put(point, info)?;
+            }
+        }
+        for point in trusted_points {
+            self.add(*point, None, true)?;
+        }
+        Ok(())
+    }
+
+    /// Remember new point. Already known point can only be promoted to trusted point.
+    ///
+    /// When there are too many points, the not trusted point with the lowest score is forgotten to make room
+    /// for the new one, unless all remembered points are better than a fresh point.
+    pub(crate) fn add(&mut self, point: SocketAddr, advertised_by: Option<String>, trusted: bool) -> Result<(), StorageError> {
+        match self.points.get_mut(&point) {
+            Some(info) => {
+                if trusted && !info.trusted() {
+                    info.set_trusted(true);
+                    self.storage.put(&point, info)?;
+                }
+            }
+            None => {
+                let info = PeerPointInfo::new(advertised_by, trusted);
+                if !trusted && self.points.len() >= MAX_KNOWN_POINTS && !self.evict_worse_than(&info)? {
+                    return Ok(());
+                }
+                self.storage.put(&point, &info)?;
+                self.points.insert(point, info);
+            }
+        }
+        Ok(())
+    }
+
+    /// Forget the not trusted point with the lowest score (the least recently seen one among equal scores),
+    /// if it is not better than `candidate`. Returns true if some point was forgotten.
+    fn evict_worse_than(&mut self, candidate: &PeerPointInfo) -> Result<bool, StorageError> {
+        let now = SystemTime::now();
+        let worst = self.points.iter()
+            .filter(|(_, info)| !info.trusted())
+            .min_by_key(|(_, info)| (score(info, now), last_seen(info)))
+            .filter(|(_, info)| score(info, now) <= score(candidate, now))
+            .map(|(point, _)| *point);
+        match worst {
+            Some(point) => {
+                self.storage.delete(&point)?;
+                self.points.remove(&point);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Returns connection history of the point
+    pub(crate) fn get(&self, point: &SocketAddr) -> Option<&PeerPointInfo> {
+        self.points.get(point)
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item=(&SocketAddr, &PeerPointInfo)> {
+        self.points.iter()
+    }
+
+    /// Connection to the point was successful
+    pub(crate) fn record_success(&mut self, point: &SocketAddr, peer_id: String) -> Result<(), StorageError> {
+        if let Some(info) = self.points.get_mut(point) {
+            info.record_success(peer_id, SystemTime::now());
+            self.storage.put(point, info)?;
+        }
+        Ok(())
+    }
+
+    /// Connection to the point failed. Points which never worked are forgotten after too many failures.
+    pub(crate) fn record_failure(&mut self, point: &SocketAddr) -> Result<(), StorageError> {
+        if let Some(info) = self.points.get_mut(point) {
+            info.record_failure(SystemTime::now());
+            let forget = !info.trusted() && info.last_success().is_none() && info.failure_count() >= MAX_FAILURES_TO_FORGET;
+            if forget {
+                self.storage.delete(point)?;
+                self.points.remove(point);
+            } else {
+                self.storage.put(point, info)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Choose up to `count` points with the highest score, which are not backing off and are not excluded.
+    pub(crate) fn select_candidates<F>(&self, count: usize, exclude: F) -> Vec<SocketAddr>
+        where F: Fn(&SocketAddr) -> bool
+    {
+        let now = SystemTime::now();
+        let mut candidates = self.points.iter()
+            .filter(|(point, info)| !exclude(point) && !is_backing_off(info, now))
+            .map(|(point, info)| (*point, score(info, now)))
+            .collect::<Vec<_>>();
+        // randomize points with the same score as a security measurement
+        candidates.shuffle(&mut rand::thread_rng());
+        candidates.sort_by_key(|(_, score)| cmp::Reverse(*score));
+        candidates.into_iter()
+            .take(count)
+            .map(|(point, _)| point)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::iter::FromIterator;
+
+    use failure::Error;
+
+    use storage::tests_common::TmpStorage;
+
+    use super::*;
+
+    #[test]
+    fn test_select_candidates_by_score() -> Result<(), Error> {
+        let tmp_storage = TmpStorage::create("__test_known_peers_select")?;
+        let mut known_peers = KnownPeers::new(PeerStorage::new(tmp_storage.storage()));
+
+        let trusted: SocketAddr = "10.0.0.1:9732".parse()?;
+        let good: SocketAddr = "10.0.0.2:9732".parse()?;
+        let unknown: SocketAddr = "10.0.0.3:9732".parse()?;
+        let failing: SocketAddr = "10.0.0.4:9732".parse()?;
+
+        known_peers.add(unknown, Some("idtgUrRAAvuH3cbZrXyfaKZovoFUqt".to_string()), false)?;
+        known_peers.add(failing, None, false)?;
+        known_peers.add(good, None, false)?;
+        known_peers.add(trusted, None, true)?;
+        known_peers.record_success(&good, "idrr4xmYRZRzyRr2ehVBU5HXPp64tn".to_string())?;
+        known_peers.record_failure(&failing)?;
+
+        // failing point is backing off
+        assert_eq!(vec![trusted, good, unknown], known_peers.select_candidates(10, |_| false));
+        assert_eq!(vec![trusted, good], known_peers.select_candidates(2, |_| false));
+        assert_eq!(vec![good, unknown], known_peers.select_candidates(10, |point| point == &trusted));
+
+        // recently failed point is backing off too
+        known_peers.record_failure(&good)?;
+        assert_eq!(vec![trusted, unknown], known_peers.select_candidates(10, |_| false));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_forget_failing_points() -> Result<(), Error> {
+        let tmp_storage = TmpStorage::create("__test_known_peers_forget")?;
+        let mut known_peers = KnownPeers::new(PeerStorage::new(tmp_storage.storage()));
+
+        let trusted: SocketAddr = "10.0.0.1:9732".parse()?;
+        let failing: SocketAddr = "10.0.0.2:9732".parse()?;
+        known_peers.add(trusted, None, true)?;
+        known_peers.add(failing, None, false)?;
+
+        for _ in 0..MAX_FAILURES_TO_FORGET {
+            known_peers.record_failure(&trusted)?;
+            known_peers.record_failure(&failing)?;
+        }
+
+        let mut reloaded = KnownPeers::new(PeerStorage::new(tmp_storage.storage()));
+        reloaded.load(&HashSet::from_iter(vec![trusted]))?;
+        assert!(reloaded.points.contains_key(&trusted));
+        assert!(!reloaded.points.contains_key(&failing));
+        assert_eq!(MAX_FAILURES_TO_FORGET, reloaded.points[&trusted].failure_count());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_points_are_persisted() -> Result<(), Error> {
+        let tmp_storage = TmpStorage::create("__test_known_peers_persisted")?;
+
+        let good: SocketAddr = "10.0.0.2:9732".parse()?;
+        {
+            let mut known_peers = KnownPeers::new(PeerStorage::new(tmp_storage.storage()));
+            known_peers.add(good, None, false)?;
+            known_peers.record_success(&good, "idrr4xmYRZRzyRr2ehVBU5HXPp64tn".to_string())?;
+        }
+
+        // after restart the last good peer is still available
+        let mut known_peers = KnownPeers::new(PeerStorage::new(tmp_storage.storage()));
+        assert!(known_peers.select_candidates(10, |_| false).is_empty());
+        known_peers.load(&HashSet::new())?;
+        assert_eq!(vec![good], known_peers.select_candidates(10, |_| false));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_evict_worst_points_when_full() -> Result<(), Error> {
+        let tmp_storage = TmpStorage::create("__test_known_peers_evict")?;
+        let mut known_peers = KnownPeers::new(PeerStorage::new(tmp_storage.storage()));
+
+        let trusted: SocketAddr = "10.0.0.1:9732".parse()?;
+        let good: SocketAddr = "10.0.0.2:9732".parse()?;
+        let failing: SocketAddr = "10.0.0.3:9732".parse()?;
+        known_peers.add(trusted, None, true)?;
+        known_peers.add(good, None, false)?;
+        known_peers.add(failing, None, false)?;
+        known_peers.record_success(&good, "idrr4xmYRZRzyRr2ehVBU5HXPp64tn".to_string())?;
+        known_peers.record_failure(&failing)?;
+        for i in 0..(MAX_KNOWN_POINTS - 3) {
+            known_peers.add(SocketAddr::from(([10, 1, (i / 256) as u8, (i % 256) as u8], 9732)), None, false)?;
+        }
+        assert_eq!(MAX_KNOWN_POINTS, known_peers.points.len());
+
+        // failing point is forgotten first, then points which were never tried
+        let new_point: SocketAddr = "10.2.0.1:9732".parse()?;
+        known_peers.add(new_point, None, false)?;
+        assert_eq!(MAX_KNOWN_POINTS, known_peers.points.len());
+        assert!(known_peers.get(&new_point).is_some());
+        assert!(known_peers.get(&failing).is_none());
+
+        for i in 0..MAX_KNOWN_POINTS {
+            known_peers.add(SocketAddr::from(([10, 3, (i / 256) as u8, (i % 256) as u8], 9732)), None, false)?;
+        }
+        assert_eq!(MAX_KNOWN_POINTS, known_peers.points.len());
+        assert!(known_peers.get(&trusted).is_some());
+        assert!(known_peers.get(&good).is_some());
+
+        // eviction is persisted
+        let mut reloaded = KnownPeers::new(PeerStorage::new(tmp_storage.storage()));
+        reloaded.load(&HashSet::from_iter(vec![trusted]))?;
+        assert_eq!(MAX_KNOWN_POINTS, reloaded.points.len());
+        assert!(reloaded.get(&failing).is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_trusted_points_are_recomputed_on_load() -> Result<(), Error> {
+        let tmp_storage = TmpStorage::create("__test_known_peers_trusted")?;
+
+        let removed: SocketAddr = "10.0.0.1:9732".parse()?;
+        let configured: SocketAddr = "10.0.0.2:9732".parse()?;
+        {
+            let mut known_peers = KnownPeers::new(PeerStorage::new(tmp_storage.storage()));
+            known_peers.load(&HashSet::from_iter(vec![removed]))?;
+            assert!(known_peers.get(&removed).map(PeerPointInfo::trusted).unwrap_or(false));
+        }
+
+        // point removed from the configuration is not trusted after restart
+        let mut known_peers = KnownPeers::new(PeerStorage::new(tmp_storage.storage()));
+        known_peers.load(&HashSet::from_iter(vec![configured]))?;
+        assert_eq!(Some(false), known_peers.get(&removed).map(PeerPointInfo::trusted));
+        assert_eq!(Some(true), known_peers.get(&configured).map(PeerPointInfo::trusted));
+
+        let mut reloaded = KnownPeers::new(PeerStorage::new(tmp_storage.storage()));
+        reloaded.load(&HashSet::new())?;
+        assert_eq!(Some(false), reloaded.get(&configured).map(PeerPointInfo::trusted));
+
+        Ok(())
+    }
+}
@@ -5,15 +5,23 @@
 //! This crate contains all shell actors plus few types used to handle the complexity of chain synchronisation process.
 
 mod collections;
+mod diversity;
+mod known_peers;
+mod reputation;
+mod trusted_peers;
 mod state;
 
 pub mod stats;
 pub mod shell_channel;
 pub mod chain_feeder;
+pub mod chain_registry;
+pub mod chain_supervisor;
 pub mod context_listener;
 pub mod chain_manager;
 pub mod peer_manager;
 pub mod mempool_prevalidator;
+pub mod recorder;
+pub mod shutdown;
 pub mod validation;
 
 /// Simple threshold, for representing integral ranges.
@@ -96,6 +104,26 @@ pub(crate) mod subscription {
             }, None);
     }
 
+    /// Subscribe to events of one chain validator (see [ChainRegistry::events_topic](crate::chain_registry::ChainRegistry::events_topic)) and to shell commands
+    #[inline]
+    pub(crate) fn subscribe_to_chain_events<M, E>(shell_channel: &ChannelRef<E>, events_topic: Topic, myself: ActorRef<M>)
+        where
+            M: Message,
+            E: Message + Into<M>
+    {
+        shell_channel.tell(
+            Subscribe {
+                actor: Box::new(myself.clone()),
+                topic: events_topic,
+            }, None);
+
+        shell_channel.tell(
+            Subscribe {
+                actor: Box::new(myself),
+                topic: ShellChannelTopic::ShellCommands.into(),
+            }, None);
+    }
+
     #[inline]
     pub(crate) fn subscribe_to_dead_letters<M, E>(dl_channel: &ChannelRef<E>, myself: ActorRef<M>)
         where
@@ -16,32 +16,64 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{channel, Receiver as QueueReceiver, Sender as QueueSender};
 use std::thread;
 use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
 use failure::{Error, Fail};
 use riker::actors::*;
 use slog::{debug, info, Logger, trace, warn};
 
 use crypto::hash::{BlockHash, ChainId, HashType, OperationHash};
-use storage::{BlockStorage, BlockStorageReader, MempoolStorage, StorageError, StorageInitInfo};
+use storage::{BlockStorage, BlockStorageReader, MempoolStorage, OperationsStorage, OperationsStorageReader, StorageError, StorageInitInfo};
 use storage::chain_meta_storage::{ChainMetaStorage, ChainMetaStorageReader};
 use storage::mempool_storage::MempoolOperationType;
 use storage::persistent::PersistentStorage;
 use tezos_api::ffi::{BeginConstructionRequest, PrevalidatorWrapper, ValidateOperationRequest, ValidateOperationResult};
+use tezos_messages::p2p::binary_message::{MessageHash, MessageHashError};
 use tezos_messages::p2p::encoding::block_header::BlockHeader;
 use tezos_messages::p2p::encoding::prelude::Operation;
 use tezos_wrapper::service::{ProtocolController, ProtocolServiceError};
 use tezos_wrapper::TezosApiConnectionPool;
 
 use crate::shell_channel::{CurrentMempoolState, ShellChannelMsg, ShellChannelRef, ShellChannelTopic};
-use crate::subscription::subscribe_to_shell_events;
+use crate::state::mempool_pool::{OperationPool, PoolInsert};
+use crate::subscription::subscribe_to_chain_events;
 
 type SharedJoinHandle = Arc<Mutex<Option<JoinHandle<Result<(), Error>>>>>;
 
+/// Limits of the mempool size, when limit is reached, the least valuable operations are evicted.
+///
+/// Per peer limits are applied to operations received from that peer, locally injected operations
+/// are limited just by the overall limits.
+#[derive(Clone, Debug)]
+pub struct MempoolLimits {
+    /// Maximal count of operations in mempool
+    pub max_operations: usize,
+    /// Maximal size of all operations in mempool
+    pub max_bytes: usize,
+    /// Maximal count of operations received from one peer
+    pub max_operations_per_peer: usize,
+    /// Maximal size of operations received from one peer
+    pub max_bytes_per_peer: usize,
+}
+
+impl Default for MempoolLimits {
+    fn default() -> Self {
+        MempoolLimits {
+            max_operations: 10_000,
+            max_bytes: 50 * 1024 * 1024,
+            max_operations_per_peer: 1_000,
+            max_bytes_per_peer: 5 * 1024 * 1024,
+        }
+    }
+}
+
 /// Feeds blocks and operations to the tezos protocol (ocaml code).
 #[actor(ShellChannelMsg)]
 pub struct MempoolPrevalidator {
     /// All events from shell will be published to this channel
     shell_channel: ShellChannelRef,
+    /// Topic of the events of the validated chain
+    events_topic: Topic,
 
     validator_event_sender: Arc<Mutex<QueueSender<Event>>>,
     validator_run: Arc<AtomicBool>,
@@ -50,10 +82,13 @@ pub struct MempoolPrevalidator {
 
 enum Event {
     NewHead(BlockHash, Arc<BlockHeader>),
-    ValidateOperation(OperationHash, MempoolOperationType),
+    ValidateOperation(OperationHash, MempoolOperationType, Option<String>),
     ShuttingDown,
 }
 
+/// How long are `refused` operations remembered, so we do not fetch them from peers again
+const REFUSED_OPERATION_TTL: Duration = Duration::from_secs(5 * 60);
+
 /// Reference to [chain feeder](ChainFeeder) actor
 pub type MempoolPrevalidatorRef = ActorRef<MempoolPrevalidatorMsg>;
 
@@ -64,6 +99,57 @@ impl MempoolPrevalidator {
         persistent_storage: &PersistentStorage,
         init_storage_data: &StorageInitInfo,
         tezos_readonly_api: Arc<TezosApiConnectionPool>,
+        limits: MempoolLimits,
+        log: Logger) -> Result<MempoolPrevalidatorRef, CreateError> {
+        Self::create(
+            sys,
+            Self::name().to_string(),
+            shell_channel,
+            ShellChannelTopic::ShellEvents.into(),
+            persistent_storage,
+            &init_storage_data.chain_id,
+            true,
+            tezos_readonly_api,
+            limits,
+            log,
+        )
+    }
+
+    /// Create mempool of the test chain, its events are published with the test chain topic (see [ChainRegistry::events_topic](crate::chain_registry::ChainRegistry::events_topic)).
+    ///
+    /// Operations stored in mempool storage belong to the main chain, so they are not loaded by the test chain mempool.
+    pub fn actor_for_test_chain(
+        sys: &impl ActorRefFactory,
+        shell_channel: ShellChannelRef,
+        persistent_storage: &PersistentStorage,
+        chain_id: &ChainId,
+        tezos_readonly_api: Arc<TezosApiConnectionPool>,
+        limits: MempoolLimits,
+        log: Logger) -> Result<MempoolPrevalidatorRef, CreateError> {
+        Self::create(
+            sys,
+            format!("{}-{}", Self::name(), HashType::ChainId.bytes_to_string(chain_id)),
+            shell_channel,
+            ShellChannelTopic::TestChainEvents(chain_id.clone()).into(),
+            persistent_storage,
+            chain_id,
+            false,
+            tezos_readonly_api,
+            limits,
+            log,
+        )
+    }
+
+    fn create(
+        sys: &impl ActorRefFactory,
+        name: String,
+        shell_channel: ShellChannelRef,
+        events_topic: Topic,
+        persistent_storage: &PersistentStorage,
+        chain_id: &ChainId,
+        load_stored_operations: bool,
+        tezos_readonly_api: Arc<TezosApiConnectionPool>,
+        limits: MempoolLimits,
         log: Logger) -> Result<MempoolPrevalidatorRef, CreateError> {
 
         // spawn thread which processes event
@@ -72,13 +158,15 @@ impl MempoolPrevalidator {
         let validator_thread = {
             let persistent_storage = persistent_storage.clone();
             let shell_channel = shell_channel.clone();
+            let events_topic = events_topic.clone();
             let validator_run = validator_run.clone();
-            let chain_id = init_storage_data.chain_id.clone();
+            let chain_id = chain_id.clone();
 
             thread::spawn(move || {
                 let mut block_storage = BlockStorage::new(&persistent_storage);
                 let mut chain_meta_storage = ChainMetaStorage::new(&persistent_storage);
                 let mut mempool_storage = MempoolStorage::new(&persistent_storage);
+                let mut operations_storage = OperationsStorage::new(&persistent_storage);
 
                 while validator_run.load(Ordering::Acquire) {
                     match tezos_readonly_api.pool.get() {
@@ -87,9 +175,13 @@ impl MempoolPrevalidator {
                                 &mut block_storage,
                                 &mut chain_meta_storage,
                                 &mut mempool_storage,
+                                &mut operations_storage,
                                 &chain_id,
+                                &limits,
+                                load_stored_operations,
                                 &validator_run,
                                 &shell_channel,
+                                &events_topic,
                                 &protocol_controller.api,
                                 &mut validator_event_receiver,
                                 &log,
@@ -112,15 +204,14 @@ impl MempoolPrevalidator {
 
         // create actor
         let myself = sys.actor_of_props::<MempoolPrevalidator>(
-            MempoolPrevalidator::name(),
-            Props::new_args((shell_channel, validator_run, Arc::new(Mutex::new(Some(validator_thread))), Arc::new(Mutex::new(validator_event_sender)))),
+            &name,
+            Props::new_args((shell_channel, events_topic, validator_run, Arc::new(Mutex::new(Some(validator_thread))), Arc::new(Mutex::new(validator_event_sender)))),
         )?;
 
         Ok(myself)
     }
 
-    /// The `MempoolPrevalidator` is intended to serve as a singleton actor so that's why
-    /// we won't support multiple names per instance.
+    /// Mempool of the main chain is a singleton actor, mempools of the test chains have the chain id appended to the name.
     fn name() -> &'static str {
         "mempool-prevalidator"
     }
@@ -136,7 +227,7 @@ impl MempoolPrevalidator {
             ShellChannelMsg::MempoolOperationReceived(operation) => {
                 // add operation to queue for validation
                 self.validator_event_sender.lock().unwrap().send(
-                    Event::ValidateOperation(operation.operation_hash.clone(), operation.operation_type)
+                    Event::ValidateOperation(operation.operation_hash.clone(), operation.operation_type, operation.peer_id.clone())
                 )?;
             }
             ShellChannelMsg::ShuttingDown(_) => {
@@ -151,10 +242,11 @@ impl MempoolPrevalidator {
     }
 }
 
-impl ActorFactoryArgs<(ShellChannelRef, Arc<AtomicBool>, SharedJoinHandle, Arc<Mutex<QueueSender<Event>>>)> for MempoolPrevalidator {
-    fn create_args((shell_channel, validator_run, validator_thread, validator_event_sender): (ShellChannelRef, Arc<AtomicBool>, SharedJoinHandle, Arc<Mutex<QueueSender<Event>>>)) -> Self {
+impl ActorFactoryArgs<(ShellChannelRef, Topic, Arc<AtomicBool>, SharedJoinHandle, Arc<Mutex<QueueSender<Event>>>)> for MempoolPrevalidator {
+    fn create_args((shell_channel, events_topic, validator_run, validator_thread, validator_event_sender): (ShellChannelRef, Topic, Arc<AtomicBool>, SharedJoinHandle, Arc<Mutex<QueueSender<Event>>>)) -> Self {
         MempoolPrevalidator {
             shell_channel,
+            events_topic,
             validator_run,
             validator_thread,
             validator_event_sender,
@@ -166,7 +258,7 @@ impl Actor for MempoolPrevalidator {
     type Msg = MempoolPrevalidatorMsg;
 
     fn pre_start(&mut self, ctx: &Context<Self::Msg>) {
-        subscribe_to_shell_events(&self.shell_channel, ctx.myself());
+        subscribe_to_chain_events(&self.shell_channel, self.events_topic.clone(), ctx.myself());
     }
 
     fn post_stop(&mut self) {
@@ -198,11 +290,16 @@ impl Receive<ShellChannelMsg> for MempoolPrevalidator {
 /// - `validation_result`
 ///     - contains results of all validated operations
 ///     - also contains `known_valid` operations, which where validated as `applied`
-/// - `pending`
-///     - operations, which where not validated yet or endorsements (`branch_refused`, `branched_delay`, `refused`?)
-///     - are being processed sequentially, after validation, they are moved to `validation_result`
-/// - `operations`
-///     - kind of cache, contains operation data
+/// - `pool`
+///     - bounded pool with operation data, which holds also `pending` operations
+///     - pending operations were not validated yet or endorsements (`branch_refused`, `branched_delay`, `refused`?)
+///     - pending operations are being processed by priority, after validation, they are moved to `validation_result`
+///
+/// On a new head (see [MempoolState::reinit]) validated operations are reclassified:
+/// - operations included in the new head are removed
+/// - `applied` and `branch_delayed` operations are moved back to pending for revalidation
+/// - `branch_refused` operations are kept until a branch switch, then they are revalidated
+/// - `refused` operations are removed together with their data, just their hashes are remembered for [REFUSED_OPERATION_TTL], so they are not refetched
 #[derive(Clone, Debug)]
 pub struct MempoolState {
     /// Original tezos prevalidator has prevalidator.fitness which is used for set_head comparision
@@ -212,76 +309,173 @@ pub struct MempoolState {
 
     /// Actual cumulated operation results
     validation_result: ValidateOperationResult,
+    /// Index of operations from `validation_result`
+    validated: HashSet<OperationHash>,
+    /// Time, when operation was refused, refused operations are remembered here for [REFUSED_OPERATION_TTL]
+    refused_at: HashMap<OperationHash, Instant>,
 
     /// In-memory store of actual operations
-    operations: HashMap<OperationHash, Operation>,
-    // TODO: pendings limit
-    // TODO: pendings as vec and order
-    pending: HashSet<OperationHash>,
+    pool: OperationPool,
 }
 
 impl MempoolState {
-    fn new(prevalidator: Option<PrevalidatorWrapper>, predecessor: Option<BlockHash>, pending_operations: HashMap<OperationHash, Operation>) -> MempoolState {
+    fn new(prevalidator: Option<PrevalidatorWrapper>, predecessor: Option<BlockHash>, limits: MempoolLimits) -> MempoolState {
         MempoolState {
             prevalidator,
             predecessor,
-            pending: pending_operations.keys().cloned().collect(),
             validation_result: ValidateOperationResult::default(),
-            operations: pending_operations,
+            validated: HashSet::new(),
+            refused_at: HashMap::new(),
+            pool: OperationPool::new(limits),
         }
     }
 
     /// Reinitialize state for new prevalidator and head, returns unneeded operation hashes
-    fn reinit(&mut self, prevalidator: Option<PrevalidatorWrapper>, predecessor: Option<BlockHash>) -> Vec<OperationHash> {
+    fn reinit(&mut self,
+              prevalidator: Option<PrevalidatorWrapper>,
+              predecessor: Option<BlockHash>,
+              included_operations: &HashSet<OperationHash>,
+              is_branch_switch: bool,
+              now: Instant) -> Vec<OperationHash> {
+        let mut unneeded_operations = Vec::new();
+
+        // operations included in the new head are done
+        for oph in included_operations {
+            let in_pool = self.pool.remove(oph).is_some();
+            let was_validated = self.validated.contains(oph);
+            if was_validated {
+                self.remove_validated(oph);
+            }
+            self.refused_at.remove(oph);
+            if in_pool || was_validated {
+                unneeded_operations.push(oph.clone());
+            }
+        }
 
-        // we want to validate pending operations with new prevalidator, so other "already_validated" can be removed
-        let unneeded_operations: Vec<OperationHash> = self.operations
-            .keys()
-            .filter(|&key| !self.pending.contains(key))
-            .map(|k| k.clone())
+        // applied and branch_delayed operations needs to be revalidated with new prevalidator
+        let mut to_revalidate: Vec<OperationHash> = self.validation_result.applied.iter().map(|op| op.hash.clone())
+            .chain(self.validation_result.branch_delayed.iter().map(|op| op.hash.clone()))
             .collect();
+        // branch_refused operations could be valid on the other branch
+        if is_branch_switch {
+            to_revalidate.extend(self.validation_result.branch_refused.iter().map(|op| op.hash.clone()));
+        }
+        for oph in to_revalidate {
+            self.remove_validated(&oph);
+            self.pool.add_to_pending(&oph);
+        }
 
-        // remove unneeded
-        for oph in &unneeded_operations {
-            self.operations.remove(oph);
+        // refused operations are just remembered for a while (without operation data),
+        // so they are removed from the results together with the data
+        self.refused_at.retain(|_, refused_at| now.duration_since(*refused_at) < REFUSED_OPERATION_TTL);
+        for op in std::mem::take(&mut self.validation_result.refused) {
+            self.validated.remove(&op.hash);
+            if self.pool.remove(&op.hash).is_some() {
+                unneeded_operations.push(op.hash);
+            }
         }
+
+        // operations which are neither pending nor validated, are not needed anymore
+        for oph in self.pool.not_pending() {
+            if !self.validated.contains(&oph) {
+                self.pool.remove(&oph);
+                unneeded_operations.push(oph);
+            }
+        }
+
         self.predecessor = predecessor;
         self.prevalidator = prevalidator;
-        self.validation_result = ValidateOperationResult::default();
 
         unneeded_operations
     }
 
     fn add_result(&mut self, new_result: ValidateOperationResult) -> bool {
+        // operation could be reclassified, so we keep it just in one category
+        new_result.applied.iter().map(|op| &op.hash)
+            .chain(new_result.branch_delayed.iter().map(|op| &op.hash))
+            .chain(new_result.branch_refused.iter().map(|op| &op.hash))
+            .chain(new_result.refused.iter().map(|op| &op.hash))
+            .for_each(|oph| {
+                if self.validated.remove(oph) {
+                    self.validation_result.applied.retain(|op| !op.hash.eq(oph));
+                    self.validation_result.branch_delayed.retain(|op| !op.hash.eq(oph));
+                    self.validation_result.branch_refused.retain(|op| !op.hash.eq(oph));
+                    self.validation_result.refused.retain(|op| !op.hash.eq(oph));
+                    self.refused_at.remove(oph);
+                }
+            });
+
+        let now = Instant::now();
+        self.refused_at.extend(new_result.refused.iter().map(|op| (op.hash.clone(), now)));
+        self.validated.extend(new_result.applied.iter().map(|op| op.hash.clone()));
+        self.validated.extend(new_result.branch_delayed.iter().map(|op| op.hash.clone()));
+        self.validated.extend(new_result.branch_refused.iter().map(|op| op.hash.clone()));
+        self.validated.extend(new_result.refused.iter().map(|op| op.hash.clone()));
         self.validation_result.merge(new_result)
     }
 
-    fn add_to_pending(&mut self, operation_hash: OperationHash, operation: Operation) {
-        self.operations.insert(operation_hash.clone(), operation);
-        let _ = self.pending.insert(operation_hash);
+    /// Add operation to pending, returns operations, which were removed from mempool (evicted or rejected)
+    fn add_to_pending(&mut self, operation_hash: OperationHash, operation: Operation, peer_id: Option<String>) -> Vec<OperationHash> {
+        match self.pool.insert(operation_hash.clone(), operation, peer_id) {
+            PoolInsert::Inserted { evicted } => {
+                for oph in &evicted {
+                    self.remove_validated(oph);
+                }
+                evicted
+            }
+            PoolInsert::AlreadyPresent => vec![],
+            PoolInsert::Rejected => vec![operation_hash],
+        }
+    }
+
+    /// Remove operation from `validation_result`
+    fn remove_validated(&mut self, operation_hash: &OperationHash) {
+        if self.validated.remove(operation_hash) {
+            self.validation_result.applied.retain(|op| !op.hash.eq(operation_hash));
+            self.validation_result.branch_delayed.retain(|op| !op.hash.eq(operation_hash));
+            self.validation_result.branch_refused.retain(|op| !op.hash.eq(operation_hash));
+            self.validation_result.refused.retain(|op| !op.hash.eq(operation_hash));
+            self.refused_at.remove(operation_hash);
+        }
     }
 
     fn remove_from_pending(&mut self, operation_hash: &OperationHash) -> bool {
-        self.pending.remove(operation_hash)
+        self.pool.remove_from_pending(operation_hash)
     }
 
     /// Indicates, that pending operations can be handled
     fn can_handle_pending(&self) -> bool {
-        !self.pending.is_empty() && self.prevalidator.is_some()
+        self.pool.pending_len() > 0 && self.prevalidator.is_some()
     }
 
-    /// Indicates, that the operation was already validated and is in the mempool
+    /// Indicates, that the operation was already validated and is in the mempool (or was recently refused)
     fn is_already_validated(&self, operation_hash: &OperationHash) -> bool {
-        if self.validation_result.applied.iter().find(|op| op.hash.eq(operation_hash)).is_some() {
-            true
-        } else if self.validation_result.branch_delayed.iter().find(|op| op.hash.eq(operation_hash)).is_some() {
-            true
-        } else if self.validation_result.branch_refused.iter().find(|op| op.hash.eq(operation_hash)).is_some() {
-            true
-        } else if self.validation_result.refused.iter().find(|op| op.hash.eq(operation_hash)).is_some() {
-            true
-        } else {
-            false
+        self.validated.contains(operation_hash) || self.refused_at.contains_key(operation_hash)
+    }
+}
+
+/// Checks, if the block `ancestor` is on the branch of the block with `header`, blocks are walked back by predecessors down to the level of the `ancestor`
+fn is_ancestor(block_storage: &BlockStorage, ancestor: &BlockHash, header: &BlockHeader) -> Result<bool, StorageError> {
+    let ancestor_level = match block_storage.get(ancestor)? {
+        Some(ancestor) => ancestor.header.level(),
+        None => return Ok(false),
+    };
+
+    let mut predecessor = header.predecessor().clone();
+    let mut predecessor_level = header.level() - 1;
+    loop {
+        if &predecessor == ancestor {
+            return Ok(true);
+        }
+        if predecessor_level <= ancestor_level {
+            return Ok(false);
+        }
+        match block_storage.get(&predecessor)? {
+            Some(block) => {
+                predecessor = block.header.predecessor().clone();
+                predecessor_level = block.header.level() - 1;
+            }
+            None => return Ok(false),
         }
     }
 }
@@ -297,6 +491,10 @@ pub enum PrevalidationError {
     ProtocolServiceError {
         error: ProtocolServiceError
     },
+    #[fail(display = "Failed to calculate operation hash! Reason: {:?}", error)]
+    OperationHashError {
+        error: MessageHashError
+    },
 }
 
 impl From<ProtocolServiceError> for PrevalidationError {
@@ -311,13 +509,23 @@ impl From<StorageError> for PrevalidationError {
     }
 }
 
+impl From<MessageHashError> for PrevalidationError {
+    fn from(error: MessageHashError) -> Self {
+        PrevalidationError::OperationHashError { error }
+    }
+}
+
 fn process_prevalidation(
     block_storage: &BlockStorage,
     chain_meta_storage: &ChainMetaStorage,
     mempool_storage: &MempoolStorage,
+    operations_storage: &OperationsStorage,
     chain_id: &ChainId,
+    limits: &MempoolLimits,
+    load_stored_operations: bool,
     validator_run: &AtomicBool,
     shell_channel: &ShellChannelRef,
+    events_topic: &Topic,
     protocol_controller: &ProtocolController,
     validator_event_receiver: &mut QueueReceiver<Event>,
     log: &Logger,
@@ -327,11 +535,14 @@ fn process_prevalidation(
     // hydrate state
     let mut state = hydrate_state(
         &shell_channel,
+        events_topic,
         block_storage,
         chain_meta_storage,
         mempool_storage,
         &protocol_controller,
         &chain_id,
+        limits,
+        load_stored_operations,
         &log,
     )?;
 
@@ -344,25 +555,27 @@ fn process_prevalidation(
                     debug!(log, "Mempool - new head received, so begin construction a new context";
                                 "received_block_hash" => HashType::BlockHash.bytes_to_string(&header_hash));
 
+                    // operations included in the new head are not needed in mempool anymore
+                    let included_operations = block_operation_hashes(operations_storage, &header_hash)?;
+                    // new head which is not a descendant of the previous one means, that we switched branch
+                    let is_branch_switch = match &state.predecessor {
+                        Some(predecessor) => !is_ancestor(block_storage, predecessor, &header)?,
+                        None => false,
+                    };
+
                     // try to begin construction new context
                     let (prevalidator, head) = begin_construction(&protocol_controller, &chain_id, header_hash, header, &log)?;
 
                     // reinitialize state for new prevalidator and head
-                    let operations_to_delete = state.reinit(prevalidator, head);
+                    let operations_to_delete = state.reinit(prevalidator, head, &included_operations, is_branch_switch, Instant::now());
 
                     // notify other actors
-                    notify_mempool_changed(&shell_channel, &state);
+                    notify_mempool_changed(&shell_channel, events_topic, &state);
 
                     // clear unneeded operations from mempool storage
-                    operations_to_delete
-                        .iter()
-                        .for_each(|oph| {
-                            if let Err(err) = mempool_storage.delete(&oph) {
-                                warn!(log, "Mempool - delete operation failed"; "hash" => HashType::OperationHash.bytes_to_string(&oph), "error" => format!("{:?}", err))
-                            }
-                        });
+                    delete_operations(mempool_storage, &operations_to_delete, &log);
                 }
-                Event::ValidateOperation(oph, mempool_operation_type) => {
+                Event::ValidateOperation(oph, mempool_operation_type, peer_id) => {
                     // TODO: handling when operation not exists - can happen?
                     if let Some(operation) = mempool_storage.get(mempool_operation_type, oph.clone())? {
 
@@ -372,7 +585,8 @@ fn process_prevalidation(
                             debug!(log, "Mempool - received validate operation event - operation already validated"; "hash" => HashType::OperationHash.bytes_to_string(&oph));
                         } else {
                             // just add operations to pendings
-                            state.add_to_pending(oph, operation.into());
+                            let operations_to_delete = state.add_to_pending(oph, operation.into(), peer_id);
+                            delete_operations(mempool_storage, &operations_to_delete, &log);
                         }
                     } else {
                         debug!(log, "Mempool - received validate operation event - operations was previously validated and removed from mempool storage"; "hash" => HashType::OperationHash.bytes_to_string(&oph));
@@ -385,7 +599,7 @@ fn process_prevalidation(
         }
 
         // 2. lets handle pending operations (if any)
-        handle_pending_operations(&shell_channel, &protocol_controller, &mut state, &log);
+        handle_pending_operations(&shell_channel, events_topic, &protocol_controller, &mut state, &log);
     }
 
     Ok(())
@@ -393,11 +607,14 @@ fn process_prevalidation(
 
 fn hydrate_state(
     shell_channel: &ShellChannelRef,
+    events_topic: &Topic,
     block_storage: &BlockStorage,
     chain_meta_storage: &ChainMetaStorage,
     mempool_storage: &MempoolStorage,
     protocol_controller: &ProtocolController,
     chain_id: &ChainId,
+    limits: &MempoolLimits,
+    load_stored_operations: bool,
     log: &Logger) -> Result<MempoolState, PrevalidationError> {
 
     // load current head
@@ -412,19 +629,21 @@ fn hydrate_state(
         None => (None, None)
     };
 
-    // read from Mempool_storage (just pending) -> add to queue for validation -> pending
-    let pending = mempool_storage.iter()?
-        .into_iter()
-        .map(|(key, value)| (key, value.operation().clone()))
-        .collect();
-
     // internal mempool state
-    let mut state = MempoolState::new(prevalidator, head, pending);
+    let mut state = MempoolState::new(prevalidator, head, limits.clone());
+
+    // read from Mempool_storage (just pending) -> add to queue for validation -> pending
+    if load_stored_operations {
+        for (key, value) in mempool_storage.iter()? {
+            let operations_to_delete = state.add_to_pending(key, value.operation().clone(), None);
+            delete_operations(mempool_storage, &operations_to_delete, log);
+        }
+    }
 
     // TODO: do we need this?
     // and process it immediatly on startup, before any event received to clean old stored unprocessed operations
     if state.can_handle_pending() {
-        handle_pending_operations(&shell_channel, &protocol_controller, &mut state, &log);
+        handle_pending_operations(&shell_channel, events_topic, &protocol_controller, &mut state, &log);
     }
 
     Ok(state)
@@ -456,8 +675,8 @@ fn begin_construction(protocol_controller: &ProtocolController,
     Ok(result)
 }
 
-fn handle_pending_operations(shell_channel: &ShellChannelRef, protocol_controller: &ProtocolController, state: &mut MempoolState, log: &Logger) {
-    debug!(log, "Mempool - handle_pending_operations"; "pendings" => state.pending.len(), "can_handle" => state.can_handle_pending());
+fn handle_pending_operations(shell_channel: &ShellChannelRef, events_topic: &Topic, protocol_controller: &ProtocolController, state: &mut MempoolState, log: &Logger) {
+    debug!(log, "Mempool - handle_pending_operations"; "pendings" => state.pool.pending_len(), "can_handle" => state.can_handle_pending());
 
     if !state.can_handle_pending() {
         trace!(log, "Mempool - handle_pending_operations - nothing to handle");
@@ -473,13 +692,12 @@ fn handle_pending_operations(shell_channel: &ShellChannelRef, protocol_controlle
 
     // TODO: verify - probably does not needed 'state_changed'
     let mut state_changed = false;
-    // lets iterate pendings and validate them
-    let mut pending_ops = state.pending.clone();
-    pending_ops
-        .drain()
+    // lets iterate pendings by priority and validate them
+    state.pool.pending_by_priority()
+        .into_iter()
         .for_each(|pending_op| {
             // handle validation
-            match state.operations.get(&pending_op) {
+            match state.pool.get(&pending_op) {
                 Some(operation) => {
                     trace!(log, "Mempool - lets validate "; "hash" => HashType::OperationHash.bytes_to_string(&pending_op));
 
@@ -516,12 +734,34 @@ fn handle_pending_operations(shell_channel: &ShellChannelRef, protocol_controlle
 
     // lets notify actors about changed mempool
     if state_changed {
-        notify_mempool_changed(&shell_channel, &state);
+        notify_mempool_changed(&shell_channel, events_topic, &state);
+    }
+}
+
+/// Load hashes of all operations included in the block
+fn block_operation_hashes(operations_storage: &OperationsStorage, block_hash: &BlockHash) -> Result<HashSet<OperationHash>, PrevalidationError> {
+    let mut hashes = HashSet::new();
+    for operations in operations_storage.get_operations(block_hash)? {
+        for operation in operations.operations() {
+            hashes.insert(operation.message_hash()?);
+        }
     }
+    Ok(hashes)
+}
+
+/// Remove operations from mempool storage
+fn delete_operations(mempool_storage: &MempoolStorage, operations_to_delete: &[OperationHash], log: &Logger) {
+    operations_to_delete
+        .iter()
+        .for_each(|oph| {
+            if let Err(err) = mempool_storage.delete(&oph) {
+                warn!(log, "Mempool - delete operation failed"; "hash" => HashType::OperationHash.bytes_to_string(&oph), "error" => format!("{:?}", err))
+            }
+        });
 }
 
 /// Notify other actors that mempool state changed
-fn notify_mempool_changed(shell_channel: &ShellChannelRef, mempool_state: &MempoolState) {
+fn notify_mempool_changed(shell_channel: &ShellChannelRef, events_topic: &Topic, mempool_state: &MempoolState) {
     let (protocol, fitness) = if let Some(prevalidator) = &mempool_state.prevalidator {
         (Some(prevalidator.protocol.clone()), prevalidator.context_fitness.clone())
     } else {
@@ -533,12 +773,12 @@ fn notify_mempool_changed(shell_channel: &ShellChannelRef, mempool_state: &Mempo
             msg: CurrentMempoolState {
                 head: mempool_state.predecessor.clone(),
                 result: mempool_state.validation_result.clone(),
-                operations: mempool_state.operations.clone(),
+                operations: mempool_state.pool.operations(),
                 protocol,
                 fitness,
-                pending: mempool_state.pending.clone(),
+                pending: mempool_state.pool.pending(),
             }.into(),
-            topic: ShellChannelTopic::ShellEvents.into(),
+            topic: events_topic.clone(),
         },
         None,
     );
@@ -547,39 +787,230 @@ fn notify_mempool_changed(shell_channel: &ShellChannelRef, mempool_state: &Mempo
 
 #[cfg(test)]
 mod tests {
+    use storage::BlockHeaderWithHash;
+    use storage::tests_common::TmpStorage;
+    use tezos_api::ffi::{Applied, Errored, OperationProtocolDataJsonWithErrorListJson};
     use tezos_messages::p2p::binary_message::BinaryMessage;
+    use tezos_messages::p2p::encoding::block_header::BlockHeaderBuilder;
 
     use super::*;
 
+    const OP_HASH1: &str = "opJ4FdKumPfykAP9ZqwY7rNB8y1SiMupt44RqBDMWL7cmb4xbNr";
+    const OP_HASH2: &str = "onvN8U6QJ6DGJKVYkHXYRtFm3tgBJScj9P5bbPjSZUuFaGzwFuJ";
+
+    fn operation() -> Result<Operation, failure::Error> {
+        Ok(Operation::from_bytes(hex::decode("10490b79070cf19175cd7e3b9c1ee66f6e85799980404b119132ea7e58a4a97e000008c387fa065a181d45d47a9b78ddc77e92a881779ff2cbabbf9646eade4bf1405a08e00b725ed849eea46953b10b5cdebc518e6fd47e69b82d2ca18c4cf6d2f312dd08")?)?)
+    }
+
+    fn applied(hash: &OperationHash) -> Applied {
+        Applied {
+            hash: hash.clone(),
+            protocol_data_json: "{}".to_string(),
+        }
+    }
+
+    fn errored(hash: &OperationHash) -> Errored {
+        Errored {
+            hash: hash.clone(),
+            is_endorsement: None,
+            protocol_data_json_with_error_json: OperationProtocolDataJsonWithErrorListJson {
+                protocol_data_json: "{}".to_string(),
+                error_json: "[]".to_string(),
+            },
+        }
+    }
+
+    /// Simulates validation of operation - takes operation from pending and adds result
+    fn validate(state: &mut MempoolState, operation_hash: &OperationHash, result: ValidateOperationResult) -> Result<(), failure::Error> {
+        state.add_to_pending(operation_hash.clone(), operation()?, None);
+        state.remove_from_pending(operation_hash);
+        state.add_result(result);
+        Ok(())
+    }
+
     #[test]
     fn test_state_reinit() -> Result<(), failure::Error> {
         let op_hash1 = HashType::OperationHash.string_to_bytes("opJ4FdKumPfykAP9ZqwY7rNB8y1SiMupt44RqBDMWL7cmb4xbNr")?;
         let op_hash2 = HashType::OperationHash.string_to_bytes("onvN8U6QJ6DGJKVYkHXYRtFm3tgBJScj9P5bbPjSZUuFaGzwFuJ")?;
 
         // init state
-        let mut operations = HashMap::new();
-        operations.insert(
+        let mut state = MempoolState::new(None, None, MempoolLimits::default());
+        state.add_to_pending(
             op_hash1.clone(),
             Operation::from_bytes(hex::decode("10490b79070cf19175cd7e3b9c1ee66f6e85799980404b119132ea7e58a4a97e000008c387fa065a181d45d47a9b78ddc77e92a881779ff2cbabbf9646eade4bf1405a08e00b725ed849eea46953b10b5cdebc518e6fd47e69b82d2ca18c4cf6d2f312dd08")?)?,
+            None,
         );
-        operations.insert(
+        state.add_to_pending(
             op_hash2.clone(),
             Operation::from_bytes(hex::decode("10490b79070cf19175cd7e3b9c1ee66f6e85799980404b119132ea7e58a4a97e000008c387fa065a181d45d47a9b78ddc77e92a881779ff2cbabbf9646eade4bf1405a08e00b725ed849eea46953b10b5cdebc518e6fd47e69b82d2ca18c4cf6d2f312dd08")?)?,
+            None,
         );
-        let mut state = MempoolState::new(None, None, operations);
-        assert_eq!(2, state.pending.len());
-        assert_eq!(2, state.operations.len());
+        assert_eq!(2, state.pool.pending_len());
+        assert_eq!(2, state.pool.len());
 
         // remove from pending
         state.remove_from_pending(&op_hash1);
 
         // reinit state
-        let unneeded = state.reinit(None, None);
-        assert_eq!(1, state.pending.len());
-        assert_eq!(1, state.operations.len());
-        assert!(state.pending.contains(&op_hash2));
+        let unneeded = state.reinit(None, None, &HashSet::new(), false, Instant::now());
+        assert_eq!(1, state.pool.pending_len());
+        assert_eq!(1, state.pool.len());
+        assert!(state.pool.is_pending(&op_hash2));
+        assert!(unneeded.contains(&op_hash1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_state_reinit_removes_included_operations() -> Result<(), failure::Error> {
+        let op_hash1 = HashType::OperationHash.string_to_bytes(OP_HASH1)?;
+        let op_hash2 = HashType::OperationHash.string_to_bytes(OP_HASH2)?;
+
+        let mut state = MempoolState::new(None, None, MempoolLimits::default());
+        validate(&mut state, &op_hash1, ValidateOperationResult { applied: vec![applied(&op_hash1)], ..Default::default() })?;
+        state.add_to_pending(op_hash2.clone(), operation()?, None);
+
+        // both operations were included in the new head
+        let included = vec![op_hash1.clone(), op_hash2.clone()].into_iter().collect();
+        let unneeded = state.reinit(None, None, &included, false, Instant::now());
+
+        assert_eq!(0, state.pool.len());
+        assert_eq!(0, state.pool.pending_len());
+        assert!(state.validation_result.applied.is_empty());
+        assert!(!state.is_already_validated(&op_hash1));
         assert!(unneeded.contains(&op_hash1));
+        assert!(unneeded.contains(&op_hash2));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_state_reinit_revalidates_applied_and_branch_delayed() -> Result<(), failure::Error> {
+        let op_hash1 = HashType::OperationHash.string_to_bytes(OP_HASH1)?;
+        let op_hash2 = HashType::OperationHash.string_to_bytes(OP_HASH2)?;
+
+        let mut state = MempoolState::new(None, None, MempoolLimits::default());
+        validate(&mut state, &op_hash1, ValidateOperationResult { applied: vec![applied(&op_hash1)], ..Default::default() })?;
+        validate(&mut state, &op_hash2, ValidateOperationResult { branch_delayed: vec![errored(&op_hash2)], ..Default::default() })?;
+        assert_eq!(0, state.pool.pending_len());
+
+        let unneeded = state.reinit(None, None, &HashSet::new(), false, Instant::now());
+
+        // both are back in pending and waits for revalidation
+        assert!(unneeded.is_empty());
+        assert_eq!(2, state.pool.pending_len());
+        assert!(state.pool.is_pending(&op_hash1));
+        assert!(state.pool.is_pending(&op_hash2));
+        assert!(state.validation_result.applied.is_empty());
+        assert!(state.validation_result.branch_delayed.is_empty());
+        assert!(!state.is_already_validated(&op_hash1));
+        assert!(!state.is_already_validated(&op_hash2));
 
         Ok(())
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_state_reinit_keeps_branch_refused_until_branch_switch() -> Result<(), failure::Error> {
+        let op_hash1 = HashType::OperationHash.string_to_bytes(OP_HASH1)?;
+
+        let mut state = MempoolState::new(None, None, MempoolLimits::default());
+        validate(&mut state, &op_hash1, ValidateOperationResult { branch_refused: vec![errored(&op_hash1)], ..Default::default() })?;
+
+        // the same branch - operation is kept as branch_refused
+        let unneeded = state.reinit(None, None, &HashSet::new(), false, Instant::now());
+        assert!(unneeded.is_empty());
+        assert_eq!(1, state.pool.len());
+        assert_eq!(0, state.pool.pending_len());
+        assert_eq!(1, state.validation_result.branch_refused.len());
+        assert!(state.is_already_validated(&op_hash1));
+
+        // branch switch - operation is revalidated
+        let unneeded = state.reinit(None, None, &HashSet::new(), true, Instant::now());
+        assert!(unneeded.is_empty());
+        assert!(state.pool.is_pending(&op_hash1));
+        assert!(state.validation_result.branch_refused.is_empty());
+        assert!(!state.is_already_validated(&op_hash1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_state_reinit_remembers_refused_for_ttl() -> Result<(), failure::Error> {
+        let op_hash1 = HashType::OperationHash.string_to_bytes(OP_HASH1)?;
+
+        let mut state = MempoolState::new(None, None, MempoolLimits::default());
+        validate(&mut state, &op_hash1, ValidateOperationResult { refused: vec![errored(&op_hash1)], ..Default::default() })?;
+
+        // operation data are not needed anymore, but operation is remembered as refused
+        let unneeded = state.reinit(None, None, &HashSet::new(), false, Instant::now());
+        assert!(unneeded.contains(&op_hash1));
+        assert_eq!(0, state.pool.len());
+        assert!(state.validation_result.refused.is_empty());
+        assert!(state.is_already_validated(&op_hash1));
+
+        // after ttl, operation is forgotten
+        let unneeded = state.reinit(None, None, &HashSet::new(), false, Instant::now() + REFUSED_OPERATION_TTL);
+        assert!(unneeded.is_empty());
+        assert!(state.validation_result.refused.is_empty());
+        assert!(!state.is_already_validated(&op_hash1));
+
+        Ok(())
+    }
+
+    /// Stores block on top of the `predecessor`, `branch` distinguishes blocks of different forks
+    fn store_block(block_storage: &BlockStorage, predecessor: &BlockHeaderWithHash, branch: u8) -> Result<BlockHeaderWithHash, failure::Error> {
+        let header = BlockHeaderBuilder::default()
+            .level(predecessor.header.level() + 1)
+            .proto(1)
+            .predecessor(predecessor.hash.clone())
+            .timestamp(predecessor.header.timestamp() + 60)
+            .validation_pass(0)
+            .operations_hash(predecessor.header.operations_hash().clone())
+            .fitness(vec![])
+            .context(predecessor.header.context().clone())
+            .protocol_data(vec![branch])
+            .build().unwrap();
+        let block = BlockHeaderWithHash::new(header)?;
+        block_storage.put_block_header(&block)?;
+        Ok(block)
+    }
+
+    #[test]
+    fn test_is_ancestor() -> Result<(), failure::Error> {
+        let tmp_storage = TmpStorage::create_to_out_dir("__mempool_test_is_ancestor")?;
+        let block_storage = BlockStorage::new(tmp_storage.storage());
+
+        let genesis = BlockHeaderWithHash::new(
+            BlockHeaderBuilder::default()
+                .level(0)
+                .proto(0)
+                .predecessor(vec![0; 32])
+                .timestamp(0)
+                .validation_pass(0)
+                .operations_hash(vec![0; 32])
+                .fitness(vec![])
+                .context(vec![0; 32])
+                .protocol_data(vec![])
+                .build().unwrap()
+        )?;
+        block_storage.put_block_header(&genesis)?;
+        let level1 = store_block(&block_storage, &genesis, 0)?;
+        let level2 = store_block(&block_storage, &level1, 0)?;
+        let level3 = store_block(&block_storage, &level2, 0)?;
+        let level4 = store_block(&block_storage, &level3, 0)?;
+        let fork_level2 = store_block(&block_storage, &level1, 1)?;
+        let fork_level3 = store_block(&block_storage, &fork_level2, 1)?;
+
+        // direct successor and a head several blocks ahead on the same branch
+        assert!(is_ancestor(&block_storage, &level1.hash, &level2.header)?);
+        assert!(is_ancestor(&block_storage, &level1.hash, &level4.header)?);
+
+        // heads on the other branch
+        assert!(!is_ancestor(&block_storage, &level2.hash, &fork_level3.header)?);
+        assert!(!is_ancestor(&block_storage, &level4.hash, &fork_level3.header)?);
+        assert!(!is_ancestor(&block_storage, &level3.hash, &level3.header)?);
+        assert!(is_ancestor(&block_storage, &level1.hash, &fork_level3.header)?);
+
+        Ok(())
+    }
+}
@@ -4,48 +4,69 @@
 //! Manages connected peers.
 
 use std::cmp;
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
 use std::iter::FromIterator;
-use std::net::{IpAddr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::time::{Duration, Instant};
 
 use dns_lookup::LookupError;
 use futures::lock::Mutex;
-use rand::seq::SliceRandom;
+use net2::TcpBuilder;
+use rand::seq::IteratorRandom;
 use riker::actors::*;
 use slog::{debug, info, Logger, trace, warn};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::runtime::Handle;
 use tokio::time::timeout;
 
-use networking::p2p::network_channel::{NetworkChannelMsg, NetworkChannelRef, NetworkChannelTopic, PeerBootstrapped, PeerCreated};
-use networking::p2p::peer::{Bootstrap, Peer, PeerRef, SendMessage};
+#[cfg(feature = "barrier")]
+use networking::barrier::{answer_synchronized, Synchronize};
+use networking::clock::{self, ClockRef, system_clock};
+use networking::p2p::capture::{CaptureConfig, TrafficCapture};
+use networking::p2p::network_channel::{Misbehavior, NetworkChannelMsg, NetworkChannelRef, NetworkChannelTopic, PeerBootstrapped, PeerCreated, PeerMisbehaved};
+use networking::p2p::peer::{bootstrap, Bootstrap, ConnectedPeers, KeepaliveConfig, Local, Peer, PeerId, PeerRef, SendMessage};
+use networking::p2p::rate_limit::{OutboundBandwidth, PeerRateLimiting, RateLimits};
+use networking::p2p::stats::{NetworkStats, PeerStats};
+use storage::{GreylistKey, PeerGreylistStorage, PeerStorage};
+use storage::persistent::PersistentStorage;
 use tezos_identity::Identity;
+use tezos_messages::p2p::encoding::ack::NackMotive;
+use tezos_messages::p2p::encoding::limits::ADVERTISE_ID_LIST_MAX_LENGTH;
 use tezos_messages::p2p::encoding::prelude::*;
+use tezos_messages::p2p::point::{canonical_point, parse_point};
 
+use crate::diversity::{Connection, ConnectionKind, DiversityViolation, PeerDiversity};
+use crate::known_peers::KnownPeers;
 use crate::PeerConnectionThreshold;
+use crate::reputation::PeerReputation;
 use crate::shell_channel::{ShellChannelMsg, ShellChannelRef};
 use crate::subscription::*;
+use crate::trusted_peers::TrustedPeers;
 
 /// Timeout for outgoing connections
 const CONNECT_TIMEOUT: Duration = Duration::from_secs(8);
-/// Whitelist all IP addresses after 30 minutes
-const WHITELIST_INTERVAL: Duration = Duration::from_secs(1_800);
 /// How often to do DNS peer discovery
 const DISCOVERY_INTERVAL: Duration = Duration::from_secs(60);
 /// Limit how often we allow to trigger check of a peer count
 const CHECK_PEER_COUNT_LIMIT: Duration = Duration::from_secs(5);
+/// Minimal interval between two swaps, also how long we wait for the swap ack
+const SWAP_LINGER: Duration = Duration::from_secs(30);
+/// Maximal number of potential peers sent to the remote peer in `Nack`
+const NACK_POTENTIAL_PEERS_COUNT: usize = 50;
+/// Maximal number of incoming connections being refused at the same time, more connections are just dropped
+const MAX_REFUSED_CONNECTIONS: usize = 16;
+/// Maximal number of pending connections in the listener queue
+const LISTEN_BACKLOG: i32 = 1024;
+/// Number of terminated peers remembered to penalize their misbehavior reported before the termination
+const TERMINATED_PEERS_MAX: usize = 64;
 
 /// Check peer threshold
 #[derive(Clone, Debug)]
 pub struct CheckPeerCount;
 
-/// Whitelist all IP address.
-#[derive(Clone, Debug)]
-pub struct WhitelistAllIpAddresses;
-
 /// Accept incoming peer connection.
 #[derive(Clone, Debug)]
 pub struct AcceptPeer {
@@ -62,20 +83,82 @@ pub struct ConnectToPeer {
 #[derive(Debug, Clone)]
 pub struct P2p {
     pub listener_port: u16,
+    /// IP address where node listens for incoming connections, unspecified IPv6 address `::` accepts IPv4 connections too
+    pub bind_address: IpAddr,
     pub disable_bootstrap_lookup: bool,
     pub bootstrap_lookup_addresses: Vec<String>,
+    /// Trusted points, node connects to them at start and keeps reconnecting them
     pub initial_peers: Vec<SocketAddr>,
+    /// Trusted peer ids, trusted peers are never greylisted and private node accepts only them
+    pub trusted_peer_ids: Vec<PeerId>,
     pub peer_threshold: PeerConnectionThreshold,
     pub disable_mempool: bool,
     pub private_node: bool,
+    /// Minimal proof of work, which is required from remote peers
+    pub expected_pow: f64,
+    pub peer_diversity: PeerDiversityLimits,
+    pub rate_limits: RateLimits,
+    /// If provided, decrypted p2p traffic is captured (see [networking::p2p::capture])
+    pub capture: Option<CaptureConfig>,
+    /// Pinging of idle peers and timeout of dead connections
+    pub keepalive: KeepaliveConfig,
+}
+
+/// Limits of connections from the same network area, so one operator cannot occupy all our peer slots.
+///
+/// Configured trusted peers (see `--peers`) and loopback and private addresses are not subject to IP and subnet limits.
+#[derive(Clone, Copy, Debug)]
+pub struct PeerDiversityLimits {
+    /// Maximal count of connections with one IP address
+    pub max_per_ip: usize,
+    /// Maximal count of connections with one IPv4 /24 (IPv6 /48) subnet
+    pub max_per_narrow_subnet: usize,
+    /// Maximal count of connections with one IPv4 /16 (IPv6 /32) subnet
+    pub max_per_wide_subnet: usize,
+    /// Percentage of outgoing connection slots reserved for trusted peers and peers we were connected to before
+    pub reserved_trusted_percent: usize,
+    /// Percentage of all connection slots reserved for incoming connections
+    pub reserved_incoming_percent: usize,
+    /// Apply IP and subnet limits to loopback and private addresses too
+    pub limit_local_addresses: bool,
+}
+
+impl PeerDiversityLimits {
+    /// No diversity limits and no reserved slots
+    pub fn unlimited() -> Self {
+        PeerDiversityLimits {
+            max_per_ip: usize::MAX,
+            max_per_narrow_subnet: usize::MAX,
+            max_per_wide_subnet: usize::MAX,
+            reserved_trusted_percent: 0,
+            reserved_incoming_percent: 0,
+            limit_local_addresses: false,
+        }
+    }
+}
+
+impl Default for PeerDiversityLimits {
+    fn default() -> Self {
+        PeerDiversityLimits {
+            max_per_ip: 2,
+            max_per_narrow_subnet: 3,
+            max_per_wide_subnet: 6,
+            reserved_trusted_percent: 25,
+            reserved_incoming_percent: 20,
+            limit_local_addresses: false,
+        }
+    }
 }
 
 /// This actor is responsible for peer management.
 ///
 /// It monitors number of connected peers. If the number of connected peers is too low it tries to
 /// connect to more peers. If the number of connected peers is too high, then randomly selected peers
-/// are disconnected.
-#[actor(CheckPeerCount, WhitelistAllIpAddresses, AcceptPeer, ConnectToPeer, NetworkChannelMsg, ShellChannelMsg, SystemEvent, DeadLetter)]
+/// are disconnected, peers from the most represented subnets first. When the high threshold is reached,
+/// peers are rotated by swapping them with other nodes (see [SwapRequest](PeerMessage::SwapRequest)).
+/// Connections have to satisfy [diversity limits](PeerDiversityLimits).
+#[cfg_attr(feature = "barrier", actor(CheckPeerCount, AcceptPeer, ConnectToPeer, Synchronize, NetworkChannelMsg, ShellChannelMsg, SystemEvent, DeadLetter))]
+#[cfg_attr(not(feature = "barrier"), actor(CheckPeerCount, AcceptPeer, ConnectToPeer, NetworkChannelMsg, ShellChannelMsg, SystemEvent, DeadLetter))]
 pub struct PeerManager {
     /// All events generated by the network layer will end up in this channel
     network_channel: NetworkChannelRef,
@@ -83,39 +166,73 @@ pub struct PeerManager {
     shell_channel: ShellChannelRef,
     /// Peer count threshold
     threshold: PeerConnectionThreshold,
+    /// Limits connections per IP address and subnet
+    diversity: PeerDiversity,
+    /// Inbound rate limits of every peer
+    rate_limits: RateLimits,
+    /// Outbound bandwidth shared by all peers
+    outbound_bandwidth: OutboundBandwidth,
     /// Map of all peers
     peers: HashMap<ActorUri, PeerState>,
+    /// Recently terminated peers, peer stops itself, so its misbehavior can be processed after its termination
+    terminated_peers: VecDeque<(ActorUri, PeerState)>,
+    /// Peer ids of peers with finished handshake, connection to already connected peer is refused
+    connected_peers: ConnectedPeers,
+    /// Number of incoming connections, which are being refused right now
+    refused_connections: Arc<AtomicUsize>,
     /// DNS addresses used for bootstrapping
     bootstrap_addresses: Vec<String>,
     /// Disable DNS bootstrap addresses lookup, if true
     disable_bootstrap_lookup: bool,
     /// List of initial peers to connect to
     initial_peers: HashSet<SocketAddr>,
+    /// Peers configured by the node operator, they are exempt from scoring and bans
+    trusted_peers: TrustedPeers,
     /// Indicates that mempool should be disabled
     disable_mempool: bool,
     /// Indicates that p2p is working in private mode
     private_node: bool,
-    /// List of potential peers to connect to
-    potential_peers: HashSet<SocketAddr>,
+    /// Persisted potential peers to connect to, with their connection history
+    known_peers: KnownPeers,
     /// Tokio runtime
     tokio_executor: Handle,
     /// We will listen for incoming connection at this port
     listener_port: u16,
+    /// We will listen for incoming connection at this IP address
+    bind_address: IpAddr,
     /// Tezos identity
     identity: Identity,
+    /// Minimal proof of work of remote peers
+    expected_pow: f64,
     /// Network/protocol version
     network_version: NetworkVersion,
+    /// Capture of decrypted traffic is started with this configuration, if enabled
+    capture_config: Option<CaptureConfig>,
+    /// Capture of decrypted traffic shared by all peers, if enabled
+    capture: Option<TrafficCapture>,
+    /// Pinging of idle peers and timeout of dead connections
+    keepalive: KeepaliveConfig,
+    /// Traffic statistics of all peers
+    network_stats: NetworkStats,
     /// Message receiver boolean indicating whether
     /// more connections should be accepted from network
     rx_run: Arc<AtomicBool>,
-    /// set of blacklisted IP addresses
-    ip_blacklist: HashSet<IpAddr>,
+    /// Peer scores and greylisted IP addresses and peer ids
+    reputation: PeerReputation,
     /// Last time we did DNS peer discovery
     discovery_last: Option<Instant>,
     /// Last time we checked peer count
     check_peer_count_last: Option<Instant>,
+    /// Last swap request we sent: when, to which peer and which of our peers we proposed
+    swap_request_last: Option<(Instant, ActorUri, PeerId)>,
+    /// Last time we accepted a swap
+    swap_accepted_last: Option<Instant>,
+    /// Points we are connecting to because of a swap, mapped to the peer id which should be disconnected
+    pending_swaps: HashMap<SocketAddr, PeerId>,
     /// Indicates that system is shutting down
     shutting_down: bool,
+    /// Source of time for timeouts and timers of the peer manager and its peers
+    clock: ClockRef,
 }
 
 /// Reference to [peer manager](PeerManager) actor.
@@ -125,20 +242,40 @@ impl PeerManager {
     pub fn actor(sys: &impl ActorRefFactory,
                  network_channel: NetworkChannelRef,
                  shell_channel: ShellChannelRef,
+                 persistent_storage: &PersistentStorage,
                  tokio_executor: Handle,
                  identity: Identity,
                  network_version: NetworkVersion,
                  p2p_config: P2p,
+                 network_stats: NetworkStats,
+    ) -> Result<PeerManagerRef, CreateError> {
+        Self::actor_with_clock(sys, network_channel, shell_channel, persistent_storage, tokio_executor, identity, network_version, p2p_config, network_stats, system_clock())
+    }
+
+    /// Create new actor instance, which uses `clock` for all timeouts and timers, also of its peers (see [VirtualClock](networking::clock::VirtualClock)).
+    pub fn actor_with_clock(sys: &impl ActorRefFactory,
+                            network_channel: NetworkChannelRef,
+                            shell_channel: ShellChannelRef,
+                            persistent_storage: &PersistentStorage,
+                            tokio_executor: Handle,
+                            identity: Identity,
+                            network_version: NetworkVersion,
+                            p2p_config: P2p,
+                            network_stats: NetworkStats,
+                            clock: ClockRef,
     ) -> Result<PeerManagerRef, CreateError> {
         sys.actor_of_props::<PeerManager>(
             PeerManager::name(),
             Props::new_args((
                 network_channel,
                 shell_channel,
+                persistent_storage.clone(),
                 tokio_executor,
                 identity,
                 network_version,
                 p2p_config,
+                network_stats,
+                clock,
             )),
         )
     }
@@ -149,35 +286,126 @@ impl PeerManager {
         "peer-manager"
     }
 
-    /// Try to discover new remote peers to connect
+    /// Try to discover new remote peers to connect, private node does not discover peers
     fn discover_peers(&mut self, log: &Logger) {
-        if self.peers.is_empty() || self.discovery_last.filter(|discovery_last| discovery_last.elapsed() <= DISCOVERY_INTERVAL).is_none() {
-            self.discovery_last = Some(Instant::now());
+        if self.private_node {
+            return;
+        }
+        if self.peers.is_empty() || self.discovery_last.filter(|discovery_last| self.clock.elapsed(*discovery_last) <= DISCOVERY_INTERVAL).is_none() {
+            self.discovery_last = Some(self.clock.now());
 
             if !self.disable_bootstrap_lookup {
                 info!(log, "Doing peer DNS lookup"; "bootstrap_addresses" => format!("{:?}", &self.bootstrap_addresses));
                 dns_lookup_peers(&self.bootstrap_addresses, &log).iter()
                     .for_each(|address| {
-                        if !self.is_blacklisted(&address.ip()) {
+                        if !self.is_greylisted(&address.ip()) {
                             info!(log, "Found potential peer"; "address" => address);
-                            self.potential_peers.insert(*address);
+                            if let Err(e) = self.known_peers.add(*address, None, false) {
+                                warn!(log, "Failed to store potential peer"; "address" => address, "reason" => e);
+                            }
                         }
                     });
             }
-
-            if self.potential_peers.is_empty() {
-                info!(log, "Using initial peers as a potential peers"; "initial_peers" => format!("{:?}", &self.initial_peers));
-                // DNS discovery yield no results, use initial peers
-                self.potential_peers.extend(&self.initial_peers);
-            }
         } else {
             self.peers.values()
                 .for_each(|peer_state| peer_state.peer_ref.tell(SendMessage::new(PeerMessage::Bootstrap.into()), None));
         }
     }
 
+    /// Choose best known peers to connect to, skipping connected and greylisted ones and ones violating diversity limits.
+    /// Trusted peers are skipped too, they are [reconnected](PeerManager::reconnect_trusted_peers) separately.
+    fn select_peers_to_connect(&self, count: usize) -> Vec<SocketAddr> {
+        if self.private_node {
+            return Vec::new();
+        }
+        let connected = self.peers.values()
+            .map(|peer_state| peer_state.address)
+            .collect::<HashSet<_>>();
+        let mut connections = self.connections();
+        let mut selected = Vec::with_capacity(count);
+        let exclude = |address: &SocketAddr| connected.contains(address)
+            || self.is_greylisted(&address.ip())
+            || self.is_trusted_point(address);
+        for address in self.known_peers.select_candidates(usize::MAX, exclude) {
+            if selected.len() >= count {
+                break;
+            }
+            let candidate = Connection::new(address.ip(), self.outgoing_kind(&address));
+            if self.diversity.check(&connections, &candidate).is_ok() {
+                connections.push(candidate);
+                selected.push(address);
+            }
+        }
+        selected
+    }
+
+    /// All connections as seen by diversity rules
+    fn connections(&self) -> Vec<Connection> {
+        self.peers.values()
+            .map(PeerState::connection)
+            .collect()
+    }
+
+    /// Kind of the outgoing connection according to what we know about the address
+    fn outgoing_kind(&self, address: &SocketAddr) -> ConnectionKind {
+        if self.is_trusted_point(address) {
+            return ConnectionKind::Trusted;
+        }
+        match self.known_peers.get(address) {
+            Some(info) if info.trusted() => ConnectionKind::Trusted,
+            Some(info) if info.last_success().is_some() => ConnectionKind::Known,
+            _ => ConnectionKind::Unknown,
+        }
+    }
+
+    /// Point is configured as trusted, or trusted peer id was seen on it last time
+    fn is_trusted_point(&self, point: &SocketAddr) -> bool {
+        self.trusted_peers.is_trusted_point(point)
+            || self.known_peers.get(point)
+                .and_then(|info| info.peer_id().as_ref())
+                .map(|peer_id| self.trusted_peers.is_trusted_peer_id(peer_id))
+                .unwrap_or(false)
+    }
+
+    fn is_trusted_peer(&self, peer_state: &PeerState) -> bool {
+        self.trusted_peers.is_trusted(&peer_state.address, peer_state.listener_address.as_ref(), peer_state.peer_id.as_deref())
+    }
+
+    /// Connect to trusted points we are not connected to, including points where trusted peer ids were seen last time
+    fn reconnect_trusted_peers(&self, ctx: &Context<PeerManagerMsg>) {
+        let mut points = self.trusted_peers.points().iter()
+            .map(|point| (*point, None))
+            .collect::<HashMap<_, _>>();
+        for (point, info) in self.known_peers.iter() {
+            if let Some(peer_id) = info.peer_id().as_ref().filter(|peer_id| self.trusted_peers.is_trusted_peer_id(peer_id)) {
+                points.insert(*point, Some(peer_id));
+            }
+        }
+
+        for (point, peer_id) in points {
+            let connected = self.peers.values()
+                .any(|peer_state| peer_state.address == point
+                    || peer_state.listener_address == Some(point)
+                    || (peer_id.is_some() && peer_state.peer_id.as_ref() == peer_id));
+            if !connected {
+                ctx.myself().tell(ConnectToPeer { address: point }, None);
+            }
+        }
+    }
+
+    /// Check if new connection with the address does not violate diversity limits, trusted peers are not limited
+    fn check_diversity(&self, address: &SocketAddr, kind: ConnectionKind, trusted: bool) -> Result<(), DiversityViolation> {
+        if trusted {
+            return Ok(());
+        }
+        self.diversity.check(&self.connections(), &Connection::new(address.ip(), kind))
+    }
+
     /// Create new peer actor
-    fn create_peer(&mut self, sys: &impl ActorRefFactory, socket_address: &SocketAddr) -> PeerRef {
+    fn create_peer(&mut self, sys: &impl ActorRefFactory, socket_address: &SocketAddr, kind: ConnectionKind) -> PeerRef {
+        let rate_limiting = PeerRateLimiting::new(self.rate_limits.clone(), self.outbound_bandwidth.clone());
+        let rate = rate_limiting.rate.clone();
+        let stats = PeerStats::new(&self.network_stats);
         let peer = Peer::actor(
             sys,
             self.network_channel.clone(),
@@ -185,18 +413,26 @@ impl PeerManager {
             &self.identity.public_key,
             &self.identity.secret_key,
             &self.identity.proof_of_work_stamp,
+            self.expected_pow,
             self.network_version.clone(),
+            self.capture.clone(),
+            self.keepalive.clone(),
             self.tokio_executor.clone(),
             socket_address,
+            rate_limiting,
+            stats.clone(),
+            self.clock.clone(),
         ).unwrap();
 
-        self.peers.insert(peer.uri().clone(), PeerState { peer_ref: peer.clone(), address: *socket_address });
+        self.peers.insert(peer.uri().clone(), PeerState { peer_ref: peer.clone(), address: *socket_address, listener_address: None, peer_id: None, kind, private_node: false, stopped: false });
 
         self.network_channel.tell(
             Publish {
                 msg: PeerCreated {
                     peer: peer.clone(),
                     address: *socket_address,
+                    rate,
+                    stats,
                 }.into(),
                 topic: NetworkChannelTopic::NetworkEvents.into(),
             }, None);
@@ -204,16 +440,71 @@ impl PeerManager {
         peer
     }
 
-    /// Check if given ip address is blacklisted to connect to
-    fn is_blacklisted(&self, ip_address: &IpAddr) -> bool {
-        self.ip_blacklist.contains(ip_address)
+    /// Check if given ip address is greylisted to connect to
+    fn is_greylisted(&self, ip_address: &IpAddr) -> bool {
+        self.reputation.is_greylisted(&GreylistKey::Ip(*ip_address))
+    }
+
+    /// Penalize IP address and peer id of the misbehaving peer.
+    ///
+    /// Returns true if peer was greylisted and should be disconnected.
+    fn penalize(&mut self, address: &SocketAddr, peer_id: Option<PeerId>, misbehavior: Misbehavior, log: &Logger) -> Result<bool, failure::Error> {
+        let mut keys = vec![GreylistKey::Ip(address.ip())];
+        if let Some(peer_id) = peer_id {
+            keys.push(GreylistKey::PeerId(peer_id));
+        }
+
+        let mut greylisted = false;
+        for key in keys {
+            if let Some(ban_duration) = self.reputation.penalize(key.clone(), misbehavior)? {
+                info!(log, "Greylisting peer because of misbehaviour"; "key" => format!("{:?}", key), "misbehavior" => format!("{:?}", misbehavior), "ban_secs" => ban_duration.as_secs());
+                greylisted = true;
+            }
+        }
+        Ok(greylisted)
+    }
+
+    /// Stop the peer actor, peer stays registered until it is terminated
+    fn stop_peer(&mut self, peer: &PeerRef, ctx: &Context<PeerManagerMsg>) {
+        if let Some(peer_state) = self.peers.get_mut(peer.uri()) {
+            peer_state.stopped = true;
+        }
+        ctx.system.stop(peer.clone());
+    }
+
+    /// Disconnect all peers which match greylisted IP address or peer id, trusted peers are never disconnected
+    fn disconnect_greylisted(&mut self, ctx: &Context<PeerManagerMsg>) {
+        let greylisted_peers = self.peers.values()
+            .filter(|peer_state| !self.is_trusted_peer(peer_state))
+            .filter(|peer_state| {
+                self.is_greylisted(&peer_state.address.ip())
+                    || peer_state.peer_id.as_ref().map(|peer_id| self.reputation.is_greylisted(&GreylistKey::PeerId(peer_id.clone()))).unwrap_or(false)
+            })
+            .map(|peer_state| peer_state.peer_ref.clone())
+            .collect::<Vec<_>>();
+        greylisted_peers.iter().for_each(|peer| self.stop_peer(peer, ctx));
     }
 
     fn process_shell_channel_message(&mut self, ctx: &Context<PeerManagerMsg>, msg: ShellChannelMsg) -> Result<(), failure::Error> {
         match msg {
+            ShellChannelMsg::BanPeer(key) if self.trusted_peers.is_trusted_key(&key) => {
+                info!(ctx.system.log(), "Trusted peer cannot be greylisted"; "key" => format!("{:?}", key));
+            }
+            ShellChannelMsg::BanPeer(key) => {
+                let ban_duration = self.reputation.ban(key.clone())?;
+                info!(ctx.system.log(), "Greylisting peer on request"; "key" => format!("{:?}", key), "ban_secs" => ban_duration.as_secs());
+                self.disconnect_greylisted(ctx);
+            }
+            ShellChannelMsg::UnbanPeer(key) => {
+                info!(ctx.system.log(), "Removing peer from greylist on request"; "key" => format!("{:?}", key));
+                self.reputation.unban(&key)?;
+            }
             ShellChannelMsg::ShuttingDown(_) => {
                 self.shutting_down = true;
                 unsubscribe_from_dead_letters(ctx.system.dead_letters(), ctx.myself());
+                // stop accepting new connections and disconnect all peers
+                self.rx_run.store(false, Ordering::Release);
+                self.peers.values().for_each(|peer_state| ctx.system.stop(peer_state.peer_ref.clone()));
             }
             _ => ()
         }
@@ -223,27 +514,220 @@ impl PeerManager {
 
     fn trigger_check_peer_count(&mut self, ctx: &Context<PeerManagerMsg>) {
         let should_trigger = self.check_peer_count_last
-            .map(|check_peer_count_last| check_peer_count_last.elapsed() > CHECK_PEER_COUNT_LIMIT)
+            .map(|check_peer_count_last| self.clock.elapsed(check_peer_count_last) > CHECK_PEER_COUNT_LIMIT)
             .unwrap_or(true);
 
         if should_trigger {
-            self.check_peer_count_last = Some(Instant::now());
+            self.check_peer_count_last = Some(self.clock.now());
             ctx.myself().tell(CheckPeerCount, None);
         }
     }
 
-    fn process_potential_peers(&mut self, potential_peers: &[String]) {
+    /// Remember advertised points, private node connects only to trusted peers, so it ignores them
+    fn process_potential_peers(&mut self, potential_peers: &[String], advertised_by: Option<PeerId>) -> Result<(), failure::Error> {
+        if self.private_node {
+            return Ok(());
+        }
         let sock_addresses = potential_peers.iter()
-            .filter_map(|str_ip_port| str_ip_port.parse().ok())
-            .filter(|address: &SocketAddr| !self.is_blacklisted(&address.ip()))
+            .filter_map(|point| parse_point(point))
+            .filter(|address: &SocketAddr| !self.is_greylisted(&address.ip()))
             .collect::<Vec<_>>();
-        self.potential_peers.extend(sock_addresses);
+        for address in sock_addresses {
+            self.known_peers.add(address, advertised_by.clone(), false)?;
+        }
+        Ok(())
+    }
+
+    /// Check if we are already connected to the point or peer id
+    fn is_connected(&self, point: &SocketAddr, peer_id: &str) -> bool {
+        self.peers.values()
+            .any(|peer_state| peer_state.address == *point
+                || peer_state.listener_address.as_ref() == Some(point)
+                || peer_state.peer_id.as_deref() == Some(peer_id))
+    }
+
+    /// Random sample of listener addresses of our bootstrapped peers, which are sent in `Nack`, so the remote peer can connect to them instead.
+    /// Private node does not share its peers and private peers are not shared.
+    fn potential_peers(&self) -> Vec<String> {
+        if self.private_node {
+            return Vec::new();
+        }
+        self.peers.values()
+            .filter(|peer_state| peer_state.peer_id.is_some() && !peer_state.private_node)
+            .filter_map(|peer_state| peer_state.listener_address)
+            .choose_multiple(&mut rand::thread_rng(), NACK_POTENTIAL_PEERS_COUNT)
+            .into_iter()
+            .map(|address| address.to_string())
+            .collect()
+    }
+
+    /// Refuse incoming connection during handshake, no peer actor is created for it.
+    /// Remote peer receives `Nack` with the motive and potential peers to connect to instead.
+    fn refuse_connection(&self, msg: AcceptPeer, motive: NackMotive, log: Logger) {
+        let info = Arc::new(Local::new(
+            self.listener_port,
+            self.identity.public_key.clone(),
+            self.identity.secret_key.clone(),
+            self.identity.proof_of_work_stamp.clone(),
+            self.expected_pow,
+            self.network_version.clone(),
+            self.capture.clone(),
+        ));
+        let refused = Bootstrap::incoming(msg.stream, msg.address, self.disable_mempool, self.private_node)
+            .refuse(motive)
+            .nack_context(self.connected_peers.clone(), self.potential_peers());
+        let refused_connections = self.refused_connections.clone();
+        refused_connections.fetch_add(1, Ordering::AcqRel);
+
+        self.tokio_executor.spawn(async move {
+            if let Err(e) = bootstrap(refused, info, &log).await {
+                debug!(log, "Incoming connection refused"; "ip" => msg.address, "reason" => e);
+            }
+            refused_connections.fetch_sub(1, Ordering::AcqRel);
+        });
+    }
+
+    /// Peer can be proposed to other peers in a swap, trusted peers are not given away and private peers are not shared
+    fn is_swappable(&self, peer_state: &PeerState) -> bool {
+        !peer_state.private_node && !self.is_trusted_peer(peer_state)
+    }
+
+    /// Randomly choose one of bootstrapped peers, which is not excluded
+    fn choose_bootstrapped_peer<F>(&self, exclude: F) -> Option<&PeerState>
+        where F: Fn(&PeerState) -> bool
+    {
+        self.peers.values()
+            .filter(|peer_state| peer_state.listener_address.is_some() && peer_state.peer_id.is_some())
+            .filter(|peer_state| !exclude(peer_state))
+            .choose(&mut rand::thread_rng())
+    }
+
+    /// Ask one of our peers to swap one of our other peers for some of its peers, private node does not swap peers
+    fn propose_swap(&mut self, excluded_peers: &HashSet<ActorUri>, log: &Logger) {
+        if self.private_node {
+            return;
+        }
+        let swapped_recently = self.swap_request_last.as_ref().map(|(requested, ..)| self.clock.elapsed(*requested) <= SWAP_LINGER).unwrap_or(false)
+            || self.swap_accepted_last.map(|accepted| self.clock.elapsed(accepted) <= SWAP_LINGER).unwrap_or(false);
+        if swapped_recently {
+            return;
+        }
+
+        let recipient = match self.choose_bootstrapped_peer(|peer_state| excluded_peers.contains(peer_state.peer_ref.uri())) {
+            Some(recipient) => recipient.peer_ref.clone(),
+            None => return,
+        };
+        let proposed = self.choose_bootstrapped_peer(|peer_state| peer_state.peer_ref == recipient || excluded_peers.contains(peer_state.peer_ref.uri()) || !self.is_swappable(peer_state))
+            .and_then(PeerState::swap_point);
+        if let Some((proposed_point, proposed_peer_id)) = proposed {
+            info!(log, "Proposing swap"; "peer" => recipient.name(), "proposed_point" => proposed_point, "proposed_peer_id" => &proposed_peer_id);
+            let msg = SwapMessage::new(&proposed_point, &proposed_peer_id);
+            recipient.tell(SendMessage::new(PeerMessage::SwapRequest(msg).into()), None);
+            self.swap_request_last = Some((self.clock.now(), recipient.uri().clone(), proposed_peer_id));
+        }
+    }
+
+    /// Remote peer asked us to swap one of our peers for the proposed point.
+    ///
+    /// We answer with one of our other peers, connect to the proposed point and once connected, we disconnect the peer we gave away.
+    fn process_swap_request(&mut self, source: &PeerRef, msg: &SwapMessage, ctx: &Context<PeerManagerMsg>) -> Result<(), failure::Error> {
+        if self.private_node {
+            debug!(ctx.system.log(), "Ignoring swap request, private node does not swap peers"; "peer" => source.name());
+            return Ok(());
+        }
+        let rate_limited = self.swap_accepted_last
+            .map(|accepted| self.clock.elapsed(accepted) <= SWAP_LINGER)
+            .unwrap_or(false);
+        if rate_limited {
+            debug!(ctx.system.log(), "Ignoring swap request, swap was accepted recently"; "peer" => source.name());
+            return Ok(());
+        }
+
+        let point = match self.validate_swap_point(msg) {
+            Some(point) => point,
+            None => {
+                debug!(ctx.system.log(), "Ignoring swap request"; "peer" => source.name(), "point" => msg.point(), "peer_id" => msg.peer_id());
+                return Ok(());
+            }
+        };
+
+        let proposed = self.choose_bootstrapped_peer(|peer_state| peer_state.peer_ref == *source || peer_state.peer_id.as_ref() == Some(msg.peer_id()) || !self.is_swappable(peer_state))
+            .and_then(PeerState::swap_point);
+        if let Some((proposed_point, proposed_peer_id)) = proposed {
+            info!(ctx.system.log(), "Accepting swap request"; "peer" => source.name(), "point" => point, "proposed_point" => proposed_point, "proposed_peer_id" => &proposed_peer_id);
+            let ack = SwapMessage::new(&proposed_point, &proposed_peer_id);
+            source.tell(SendMessage::new(PeerMessage::SwapAck(ack).into()), None);
+
+            self.swap_accepted_last = Some(self.clock.now());
+            let advertised_by = self.peers.get(source.uri()).and_then(|peer_state| peer_state.peer_id.clone());
+            self.start_swap(point, proposed_peer_id, advertised_by, ctx)?;
+        }
+        Ok(())
+    }
+
+    /// Remote peer accepted our swap request, connect to its point and disconnect the peer we proposed
+    fn process_swap_ack(&mut self, source: &PeerRef, msg: &SwapMessage, ctx: &Context<PeerManagerMsg>) -> Result<(), failure::Error> {
+        let proposed_peer_id = match self.swap_request_last.take() {
+            Some((requested, recipient, proposed_peer_id)) if self.clock.elapsed(*requested) <= SWAP_LINGER && recipient == *source.uri() => proposed_peer_id,
+            swap_request_last => {
+                self.swap_request_last = swap_request_last;
+                debug!(ctx.system.log(), "Ignoring unexpected swap ack"; "peer" => source.name());
+                return Ok(());
+            }
+        };
+
+        match self.validate_swap_point(msg) {
+            Some(point) => {
+                info!(ctx.system.log(), "Swap request accepted by peer"; "peer" => source.name(), "point" => point, "peer_id" => msg.peer_id());
+                self.swap_accepted_last = Some(self.clock.now());
+                let advertised_by = self.peers.get(source.uri()).and_then(|peer_state| peer_state.peer_id.clone());
+                self.start_swap(point, proposed_peer_id, advertised_by, ctx)
+            }
+            None => {
+                debug!(ctx.system.log(), "Ignoring swap ack"; "peer" => source.name(), "point" => msg.point(), "peer_id" => msg.peer_id());
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns swap point if we are allowed to connect to it
+    fn validate_swap_point(&self, msg: &SwapMessage) -> Option<SocketAddr> {
+        let point = parse_point(msg.point())?;
+        let acceptable = *msg.peer_id() != self.identity.peer_id
+            && !self.is_greylisted(&point.ip())
+            && !self.reputation.is_greylisted(&GreylistKey::PeerId(msg.peer_id().clone()))
+            && !self.is_connected(&point, msg.peer_id());
+        if acceptable {
+            Some(point)
+        } else {
+            None
+        }
+    }
+
+    /// Connect to the new point, peer `swapped_peer_id` will be disconnected once the connection is bootstrapped
+    fn start_swap(&mut self, point: SocketAddr, swapped_peer_id: PeerId, advertised_by: Option<PeerId>, ctx: &Context<PeerManagerMsg>) -> Result<(), failure::Error> {
+        self.pending_swaps.insert(point, swapped_peer_id);
+        ctx.myself().tell(ConnectToPeer { address: point }, None);
+        self.known_peers.add(point, advertised_by, false)?;
+        Ok(())
+    }
+
+    /// Swap connection was bootstrapped, disconnect the swapped peer
+    fn finish_swap(&mut self, point: &SocketAddr, log: &Logger) {
+        if let Some(swapped_peer_id) = self.pending_swaps.remove(point) {
+            let swapped_peer = self.peers.values()
+                .find(|peer_state| peer_state.peer_id.as_ref() == Some(&swapped_peer_id));
+            if let Some(swapped_peer) = swapped_peer {
+                info!(log, "Swap finished, disconnecting swapped peer"; "point" => point, "peer_id" => &swapped_peer_id, "peer" => swapped_peer.peer_ref.name());
+                swapped_peer.peer_ref.tell(SendMessage::new(PeerMessage::Disconnect.into()), None);
+            }
+        }
     }
 }
 
-impl ActorFactoryArgs<(NetworkChannelRef, ShellChannelRef, Handle, Identity, NetworkVersion, P2p)> for PeerManager {
-    fn create_args((network_channel, shell_channel, tokio_executor, identity, network_version, p2p_config):
-                   (NetworkChannelRef, ShellChannelRef, Handle, Identity, NetworkVersion, P2p)) -> Self
+impl ActorFactoryArgs<(NetworkChannelRef, ShellChannelRef, PersistentStorage, Handle, Identity, NetworkVersion, P2p, NetworkStats, ClockRef)> for PeerManager {
+    fn create_args((network_channel, shell_channel, persistent_storage, tokio_executor, identity, network_version, p2p_config, network_stats, clock):
+                   (NetworkChannelRef, ShellChannelRef, PersistentStorage, Handle, Identity, NetworkVersion, P2p, NetworkStats, ClockRef)) -> Self
     {
         PeerManager {
             network_channel,
@@ -251,20 +735,37 @@ impl ActorFactoryArgs<(NetworkChannelRef, ShellChannelRef, Handle, Identity, Net
             tokio_executor,
             bootstrap_addresses: p2p_config.bootstrap_lookup_addresses,
             disable_bootstrap_lookup: p2p_config.disable_bootstrap_lookup,
+            trusted_peers: TrustedPeers::new(&p2p_config.initial_peers, &p2p_config.trusted_peer_ids),
             initial_peers: HashSet::from_iter(p2p_config.initial_peers),
             threshold: p2p_config.peer_threshold,
+            diversity: PeerDiversity::new(p2p_config.peer_diversity, p2p_config.peer_threshold.high),
+            outbound_bandwidth: OutboundBandwidth::new(p2p_config.rate_limits.outbound_bytes_per_sec),
+            rate_limits: p2p_config.rate_limits,
             listener_port: p2p_config.listener_port,
+            bind_address: p2p_config.bind_address,
             identity,
+            expected_pow: p2p_config.expected_pow,
             network_version,
+            capture_config: p2p_config.capture,
+            capture: None,
+            keepalive: p2p_config.keepalive,
+            network_stats,
             disable_mempool: p2p_config.disable_mempool,
             private_node: p2p_config.private_node,
             rx_run: Arc::new(AtomicBool::new(true)),
-            potential_peers: HashSet::new(),
+            known_peers: KnownPeers::new(PeerStorage::new(&persistent_storage)),
             peers: HashMap::new(),
-            ip_blacklist: HashSet::new(),
+            terminated_peers: VecDeque::new(),
+            connected_peers: ConnectedPeers::default(),
+            refused_connections: Arc::new(AtomicUsize::new(0)),
+            reputation: PeerReputation::new(PeerGreylistStorage::new(&persistent_storage)),
             discovery_last: None,
             check_peer_count_last: None,
+            swap_request_last: None,
+            swap_accepted_last: None,
+            pending_swaps: HashMap::new(),
             shutting_down: false,
+            clock,
         }
     }
 }
@@ -278,33 +779,42 @@ impl Actor for PeerManager {
         subscribe_to_shell_events(&self.shell_channel, ctx.myself());
         subscribe_to_dead_letters(ctx.system.dead_letters(), ctx.myself());
 
-        ctx.schedule::<Self::Msg, _>(
+        if let Err(e) = self.reputation.load() {
+            warn!(ctx.system.log(), "Failed to load peer greylist"; "reason" => e);
+        }
+        if let Err(e) = self.known_peers.load(&self.initial_peers) {
+            warn!(ctx.system.log(), "Failed to load known peers"; "reason" => e);
+        }
+        if let Some(capture_config) = self.capture_config.take() {
+            match TrafficCapture::new(capture_config, ctx.system.log()) {
+                Ok(capture) => self.capture = Some(capture),
+                Err(e) => warn!(ctx.system.log(), "Failed to start capture of p2p traffic"; "reason" => e),
+            }
+        }
+
+        clock::schedule(
+            &self.clock,
+            ctx,
             Duration::from_secs(3),
             Duration::from_secs(10),
-            ctx.myself(),
-            None,
-            CheckPeerCount.into());
-        ctx.schedule::<Self::Msg, _>(
-            WHITELIST_INTERVAL,
-            WHITELIST_INTERVAL,
-            ctx.myself(),
-            None,
-            WhitelistAllIpAddresses.into());
-
-
-        let listener_port = self.listener_port;
+            CheckPeerCount);
+
+        let listener_address = SocketAddr::new(self.bind_address, self.listener_port);
         let myself = ctx.myself();
         let rx_run = self.rx_run.clone();
         let log = ctx.system.log();
 
         // start to listen for incoming p2p connections
         self.tokio_executor.spawn(async move {
-            begin_listen_incoming(listener_port, myself, rx_run, &log).await;
+            begin_listen_incoming(listener_address, myself, rx_run, &log).await;
         });
     }
 
     fn post_stop(&mut self) {
         self.rx_run.store(false, Ordering::Relaxed);
+        if let Some(capture) = &self.capture {
+            capture.flush();
+        }
     }
 
     fn sys_recv(&mut self, ctx: &Context<Self::Msg>, msg: SystemMsg, sender: Option<BasicActorRef>) {
@@ -343,13 +853,33 @@ impl Receive<SystemEvent> for PeerManager {
 
     fn receive(&mut self, ctx: &Context<Self::Msg>, msg: SystemEvent, _sender: Option<BasicActorRef>) {
         if let SystemEvent::ActorTerminated(evt) = msg {
-            if self.peers.remove(evt.actor.uri()).is_some() {
+            if let Some(peer_state) = self.peers.remove(evt.actor.uri()) {
+                // outgoing peer which never bootstrapped successfully
+                if !peer_state.is_incoming() && peer_state.peer_id.is_none() {
+                    if let Err(e) = self.known_peers.record_failure(&peer_state.address) {
+                        warn!(ctx.system.log(), "Failed to store peer connection failure"; "address" => peer_state.address, "reason" => e);
+                    }
+                    self.pending_swaps.remove(&peer_state.address);
+                }
+                if self.terminated_peers.len() >= TERMINATED_PEERS_MAX {
+                    self.terminated_peers.pop_front();
+                }
+                self.terminated_peers.push_back((evt.actor.uri().clone(), peer_state));
                 self.trigger_check_peer_count(ctx);
             }
         }
     }
 }
 
+#[cfg(feature = "barrier")]
+impl Receive<Synchronize> for PeerManager {
+    type Msg = PeerManagerMsg;
+
+    fn receive(&mut self, _ctx: &Context<Self::Msg>, _msg: Synchronize, sender: Sender) {
+        answer_synchronized(sender);
+    }
+}
+
 impl Receive<CheckPeerCount> for PeerManager {
     type Msg = PeerManagerMsg;
 
@@ -359,34 +889,48 @@ impl Receive<CheckPeerCount> for PeerManager {
             return;
         }
 
+        self.reconnect_trusted_peers(ctx);
+
         if self.peers.len() < self.threshold.low {
             // peer count is too low, try to connect to more peers
             warn!(ctx.system.log(), "Peer count is too low"; "actual" => self.peers.len(), "required" => self.threshold.low);
-            if self.potential_peers.len() < self.threshold.low {
+
+            let num_required_peers = cmp::max((self.threshold.high + 3 * self.threshold.low) / 4 - self.peers.len(), self.threshold.low);
+            let mut addresses_to_connect = self.select_peers_to_connect(num_required_peers);
+            if addresses_to_connect.len() < self.threshold.low {
                 self.discover_peers(&ctx.system.log());
+                addresses_to_connect = self.select_peers_to_connect(num_required_peers);
             }
 
-            let num_required_peers = cmp::max((self.threshold.high + 3 * self.threshold.low) / 4 - self.peers.len(), self.threshold.low);
-            let mut addresses_to_connect = self.potential_peers.iter().cloned().collect::<Vec<SocketAddr>>();
-            // randomize peers as a security measurement
-            addresses_to_connect.shuffle(&mut rand::thread_rng());
             addresses_to_connect
-                .drain(0..cmp::min(num_required_peers, addresses_to_connect.len()))
-                .for_each(|address| {
-                    self.potential_peers.remove(&address);
-                    ctx.myself().tell(ConnectToPeer { address }, ctx.myself().into())
-                });
-        } else if self.peers.len() > self.threshold.high {
-            // peer count is too high, disconnect some peers
-            warn!(ctx.system.log(), "Peer count is too high. Some peers will be stopped"; "actual" => self.peers.len(), "limit" => self.threshold.high);
-
-            // stop some peers
-            self.peers.values()
-                .take(self.peers.len() - self.threshold.high)
-                .for_each(|peer_state| ctx.system.stop(peer_state.peer_ref.clone()))
+                .into_iter()
+                .for_each(|address| ctx.myself().tell(ConnectToPeer { address }, ctx.myself().into()));
+        } else if self.peers.len() >= self.threshold.high {
+            let mut stopped_peers = HashSet::new();
+            if self.peers.len() > self.threshold.high {
+                // peer count is too high, disconnect some peers
+                warn!(ctx.system.log(), "Peer count is too high. Some peers will be stopped"; "actual" => self.peers.len(), "limit" => self.threshold.high);
+
+                // stop some peers, prefer peers from over-represented subnets, trusted peers are never stopped
+                let peers = self.peers.values()
+                    .filter(|peer_state| !self.is_trusted_peer(peer_state))
+                    .collect::<Vec<_>>();
+                let connections = peers.iter().map(|peer_state| peer_state.connection()).collect::<Vec<_>>();
+                let evicted_peers = self.diversity.choose_evicted(&connections, cmp::min(peers.len(), self.peers.len() - self.threshold.high))
+                    .into_iter()
+                    .map(|idx| peers[idx].peer_ref.clone())
+                    .collect::<Vec<_>>();
+                for peer in evicted_peers {
+                    self.stop_peer(&peer, ctx);
+                    stopped_peers.insert(peer.uri().clone());
+                }
+            }
+
+            // we do not accept new connections anymore, rotate peers by swapping them
+            self.propose_swap(&stopped_peers, &ctx.system.log());
         }
 
-        self.check_peer_count_last = Some(Instant::now());
+        self.check_peer_count_last = Some(self.clock.now());
     }
 }
 
@@ -403,69 +947,135 @@ impl Receive<NetworkChannelMsg> for PeerManager {
                         PeerMessage::Advertise(message) => {
                             // extract potential peers from the advertise message
                             info!(ctx.system.log(), "Received advertise message"; "peer" => received.peer.name(), "peers" => format!("{:?}", message.id().join(", ")));
-                            self.process_potential_peers(message.id());
+                            let advertised_by = self.peers.get(received.peer.uri()).and_then(|peer_state| peer_state.peer_id.clone());
+                            if let Err(e) = self.process_potential_peers(message.id(), advertised_by) {
+                                warn!(ctx.system.log(), "Failed to store advertised peers"; "reason" => format!("{:?}", e));
+                            }
+                        }
+                        PeerMessage::Bootstrap if self.private_node => {
+                            // private node does not share its peers
+                            trace!(ctx.system.log(), "Ignoring bootstrap message, private node does not advertise its peers"; "peer" => received.peer.name());
                         }
                         PeerMessage::Bootstrap => {
-                            // to a bootstrap message we will respond with list of potential peers
+                            // to a bootstrap message we will respond with listener addresses of our other bootstrapped peers
                             trace!(ctx.system.log(), "Received bootstrap message"; "peer" => received.peer.name());
                             let addresses = self.peers.values()
-                                .filter(|peer_state| peer_state.peer_ref != received.peer)
-                                .map(|peer_state| peer_state.address)
+                                .filter(|peer_state| peer_state.peer_ref != received.peer && peer_state.peer_id.is_some() && !peer_state.private_node)
+                                .filter_map(|peer_state| peer_state.listener_address)
+                                .take(ADVERTISE_ID_LIST_MAX_LENGTH)
                                 .collect::<Vec<_>>();
                             let msg = AdvertiseMessage::new(&addresses);
                             received.peer.tell(SendMessage::new(PeerMessage::Advertise(msg).into()), None);
                         }
+                        PeerMessage::SwapRequest(message) => {
+                            if let Err(e) = self.process_swap_request(&received.peer, message, ctx) {
+                                warn!(ctx.system.log(), "Failed to process swap request"; "reason" => format!("{:?}", e));
+                            }
+                        }
+                        PeerMessage::SwapAck(message) => {
+                            if let Err(e) = self.process_swap_ack(&received.peer, message, ctx) {
+                                warn!(ctx.system.log(), "Failed to process swap ack"; "reason" => format!("{:?}", e));
+                            }
+                        }
                         _ => {}
                     });
                 self.trigger_check_peer_count(ctx);
             }
-            NetworkChannelMsg::PeerBootstrapped(PeerBootstrapped::Failure { address, potential_peers_to_connect }) => {
+            NetworkChannelMsg::PeerBootstrapped(PeerBootstrapped::Success { peer, peer_id, peer_metadata, listener_port }) => {
+                if self.reputation.is_greylisted(&GreylistKey::PeerId(peer_id.clone())) && !self.trusted_peers.is_trusted_peer_id(&peer_id) {
+                    info!(ctx.system.log(), "Peer is greylisted - will be disconnected"; "peer_id" => &peer_id, "peer" => peer.name());
+                    self.stop_peer(&peer, ctx);
+                }
+                let mut swap_point = None;
+                // peers which are being stopped are not accounted as successful connections
+                if let Some(peer_state) = self.peers.get_mut(peer.uri()).filter(|peer_state| !peer_state.stopped) {
+                    peer_state.peer_id = Some(peer_id.clone());
+                    peer_state.listener_address = Some(SocketAddr::new(peer_state.address.ip(), listener_port));
+                    peer_state.private_node = peer_metadata.private_node();
+                    if !peer_state.is_incoming() {
+                        if let Err(e) = self.known_peers.record_success(&peer_state.address, peer_id) {
+                            warn!(ctx.system.log(), "Failed to store peer connection success"; "address" => peer_state.address, "reason" => e);
+                        }
+                        swap_point = Some(peer_state.address);
+                    }
+                }
+                if let Some(swap_point) = swap_point {
+                    self.finish_swap(&swap_point, &ctx.system.log());
+                }
+            }
+            NetworkChannelMsg::PeerBootstrapped(PeerBootstrapped::Failure { address, potential_peers_to_connect, misbehavior }) => {
                 // received message that bootstrap process failed for the peer
-                match potential_peers_to_connect {
-                    Some(peers) => {
-                        self.process_potential_peers(&peers);
-                        self.trigger_check_peer_count(ctx);
+                if let Some(peers) = potential_peers_to_connect {
+                    if let Err(e) = self.process_potential_peers(&peers, None) {
+                        warn!(ctx.system.log(), "Failed to store potential peers"; "reason" => format!("{:?}", e));
                     }
-                    None => {
-                        info!(ctx.system.log(), "Blacklisting IP because peer failed at bootstrap process"; "ip" => format!("{}", address.ip()));
-                        self.ip_blacklist.insert(address.ip());
+                    self.trigger_check_peer_count(ctx);
+                }
+                if let Some(misbehavior) = misbehavior.filter(|_| !self.is_trusted_point(&address)) {
+                    if let Err(e) = self.penalize(&address, None, misbehavior, &ctx.system.log()) {
+                        warn!(ctx.system.log(), "Failed to penalize peer"; "ip" => address, "reason" => format!("{:?}", e));
                     }
                 }
             }
+            NetworkChannelMsg::PeerMisbehaved(PeerMisbehaved { peer, misbehavior, disconnect }) => {
+                // trusted peers are exempt from scoring, peer could have already stopped itself
+                let peer_info = self.peers.get(peer.uri())
+                    .or_else(|| self.terminated_peers.iter().find(|(uri, _)| uri == peer.uri()).map(|(_, peer_state)| peer_state))
+                    .filter(|peer_state| !self.is_trusted_peer(peer_state))
+                    .map(|peer_state| (peer_state.address, peer_state.peer_id.clone()));
+                let greylisted = match peer_info {
+                    Some((address, peer_id)) => match self.penalize(&address, peer_id, misbehavior, &ctx.system.log()) {
+                        Ok(greylisted) => greylisted,
+                        Err(e) => {
+                            warn!(ctx.system.log(), "Failed to penalize peer"; "ip" => address, "reason" => format!("{:?}", e));
+                            false
+                        }
+                    },
+                    None => false,
+                };
+                // peer is stopped only after the penalty was recorded
+                if (disconnect || greylisted) && self.peers.contains_key(peer.uri()) {
+                    self.stop_peer(&peer, ctx);
+                }
+            }
             _ => ()
         }
     }
 }
 
-impl Receive<WhitelistAllIpAddresses> for PeerManager {
-    type Msg = PeerManagerMsg;
-
-    fn receive(&mut self, ctx: &Context<Self::Msg>, _msg: WhitelistAllIpAddresses, _sender: Sender) {
-        info!(ctx.system.log(), "Whitelisting all IP addresses");
-        self.ip_blacklist.clear();
-    }
-}
-
 impl Receive<ConnectToPeer> for PeerManager {
     type Msg = PeerManagerMsg;
 
     fn receive(&mut self, ctx: &Context<Self::Msg>, msg: ConnectToPeer, _sender: Sender) {
         // received message instructing this actor that it should open new p2p connection to the remote peer
 
-        if self.is_blacklisted(&msg.address.ip()) {
-            debug!(ctx.system.log(), "Peer is blacklisted - will not connect"; "ip" => format!("{}", msg.address.ip()));
+        let kind = self.outgoing_kind(&msg.address);
+        let trusted = self.is_trusted_point(&msg.address);
+        if self.shutting_down {
+            debug!(ctx.system.log(), "System is shutting down - will not connect"; "ip" => format!("{}", msg.address.ip()));
+        } else if self.private_node && !trusted {
+            debug!(ctx.system.log(), "Peer is not trusted, private node will not connect"; "ip" => format!("{}", msg.address.ip()));
+            self.pending_swaps.remove(&msg.address);
+        } else if !trusted && self.is_greylisted(&msg.address.ip()) {
+            debug!(ctx.system.log(), "Peer is greylisted - will not connect"; "ip" => format!("{}", msg.address.ip()));
+        } else if let Err(violation) = self.check_diversity(&msg.address, kind, trusted) {
+            debug!(ctx.system.log(), "Connection would violate diversity limits - will not connect"; "ip" => format!("{}", msg.address.ip()), "reason" => violation.to_string());
+            self.pending_swaps.remove(&msg.address);
         } else {
-            let peer = self.create_peer(ctx, &msg.address);
+            let peer = self.create_peer(ctx, &msg.address, kind);
+            let myself = ctx.myself();
             let system = ctx.system.clone();
             let disable_mempool = self.disable_mempool;
             let private_node = self.private_node;
+            let connected_peers = self.connected_peers.clone();
+            let potential_peers = self.potential_peers();
 
             self.tokio_executor.spawn(async move {
                 info!(system.log(), "Connecting to IP"; "ip" => msg.address, "peer" => peer.name());
                 match timeout(CONNECT_TIMEOUT, TcpStream::connect(&msg.address)).await {
                     Ok(Ok(stream)) => {
                         info!(system.log(), "Connection successful"; "ip" => msg.address);
-                        peer.tell(Bootstrap::outgoing(stream, msg.address, disable_mempool, private_node), None);
+                        peer.tell(Bootstrap::outgoing(stream, msg.address, disable_mempool, private_node).nack_context(connected_peers, potential_peers), None);
                     }
                     Ok(Err(e)) => {
                         info!(system.log(), "Connection failed"; "ip" => msg.address, "peer" => peer.name(), "reason" => format!("{:?}", e));
@@ -473,7 +1083,8 @@ impl Receive<ConnectToPeer> for PeerManager {
                     }
                     Err(_) => {
                         info!(system.log(), "Connection timed out"; "ip" => msg.address, "peer" => peer.name());
-                        system.stop(peer);
+                        // tell it directly to ourselves, peer is stopped once the penalty is recorded
+                        myself.tell(NetworkChannelMsg::from(PeerMisbehaved { peer, misbehavior: Misbehavior::Timeout, disconnect: true }), None);
                     }
                 }
             });
@@ -485,12 +1096,30 @@ impl Receive<AcceptPeer> for PeerManager {
     type Msg = PeerManagerMsg;
 
     fn receive(&mut self, ctx: &Context<Self::Msg>, msg: AcceptPeer, _sender: Sender) {
-        if self.is_blacklisted(&msg.address.ip()) {
-            debug!(ctx.system.log(), "Peer is blacklisted - will not accept connection"; "ip" => format!("{}", msg.address.ip()));
+        // peer id is not known yet, so incoming connection is not trusted until the handshake is finished
+        if self.shutting_down {
+            debug!(ctx.system.log(), "System is shutting down - will not accept connection"; "ip" => format!("{}", msg.address.ip()));
+        } else if self.private_node && self.trusted_peers.peer_ids().is_empty() {
+            debug!(ctx.system.log(), "No trusted peer ids, private node will not accept connection"; "ip" => format!("{}", msg.address.ip()));
+            drop(msg.stream);
+        } else if self.is_greylisted(&msg.address.ip()) {
+            debug!(ctx.system.log(), "Peer is greylisted - will not accept connection"; "ip" => format!("{}", msg.address.ip()));
+        } else if let Err(violation) = self.check_diversity(&msg.address, ConnectionKind::Incoming, false) {
+            debug!(ctx.system.log(), "Connection would violate diversity limits - will not accept connection"; "ip" => format!("{}", msg.address.ip()), "reason" => violation.to_string());
+            drop(msg.stream);
         } else if self.peers.len() < self.threshold.high {
             info!(ctx.system.log(), "Connection from"; "ip" => msg.address);
-            let peer = self.create_peer(ctx, &msg.address);
-            peer.tell(Bootstrap::incoming(msg.stream, msg.address, self.disable_mempool, self.private_node), None);
+            let mut bootstrap = Bootstrap::incoming(msg.stream, msg.address, self.disable_mempool, self.private_node)
+                .nack_context(self.connected_peers.clone(), self.potential_peers());
+            if self.private_node {
+                // private node accepts only trusted peer ids, peer id is known during handshake
+                bootstrap = bootstrap.accept_only(self.trusted_peers.peer_ids().clone());
+            }
+            let peer = self.create_peer(ctx, &msg.address, ConnectionKind::Incoming);
+            peer.tell(bootstrap, None);
+        } else if self.refused_connections.load(Ordering::Acquire) < MAX_REFUSED_CONNECTIONS {
+            debug!(ctx.system.log(), "Refusing incoming peer connection because peer limit was reached"; "ip" => msg.address);
+            self.refuse_connection(msg, NackMotive::TooManyConnections, ctx.system.log());
         } else {
             debug!(ctx.system.log(), "Cannot accept incoming peer connection because peer limit was reached");
             drop(msg.stream); // not needed, just wanted to be explicit here
@@ -499,18 +1128,46 @@ impl Receive<AcceptPeer> for PeerManager {
 }
 
 /// Start to listen for incoming connections indefinitely.
-async fn begin_listen_incoming(listener_port: u16, peer_manager: PeerManagerRef, rx_run: Arc<AtomicBool>, log: &Logger) {
-    let listener_address = format!("0.0.0.0:{}", listener_port).parse::<SocketAddr>().expect("Failed to parse listener address");
-    let mut listener = TcpListener::bind(&listener_address).await.expect("Failed to bind to address");
-    info!(log, "Start to listen for incoming p2p connections"; "port" => listener_port);
+async fn begin_listen_incoming(listener_address: SocketAddr, peer_manager: PeerManagerRef, rx_run: Arc<AtomicBool>, log: &Logger) {
+    let mut listener = match bind_listener(listener_address) {
+        Ok(listener) => listener,
+        Err(e) if listener_address.ip() == IpAddr::V6(Ipv6Addr::UNSPECIFIED) => {
+            // host without IPv6 support
+            warn!(log, "Failed to bind to IPv6 address, only IPv4 connections will be accepted"; "reason" => format!("{}", e));
+            bind_listener(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), listener_address.port())).expect("Failed to bind to address")
+        }
+        Err(e) => panic!("Failed to bind to address {}: {}", listener_address, e),
+    };
+    info!(log, "Start to listen for incoming p2p connections"; "address" => listener_address);
 
     while rx_run.load(Ordering::Acquire) {
         if let Ok((stream, address)) = listener.accept().await {
+            // IPv4 peers connected to dual-stack listener have IPv4-mapped IPv6 address
+            let address = canonical_point(address);
             peer_manager.tell(AcceptPeer { stream: Arc::new(Mutex::new(Some(stream))), address }, None);
         }
     }
 
-    info!(log, "Stop listening for incoming p2p connections"; "port" => listener_port);
+    info!(log, "Stop listening for incoming p2p connections"; "address" => listener_address);
+}
+
+/// Bind the p2p listener. IPv6 listener is always dual-stack (`IPV6_V6ONLY` is disabled),
+/// so it accepts IPv4 connections regardless of the OS default.
+fn bind_listener(listener_address: SocketAddr) -> io::Result<TcpListener> {
+    let builder = match listener_address {
+        SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+        SocketAddr::V6(_) => {
+            let builder = TcpBuilder::new_v6()?;
+            builder.only_v6(false)?;
+            builder
+        }
+    };
+    let listener = builder
+        .reuse_address(true)?
+        .bind(listener_address)?
+        .listen(LISTEN_BACKLOG)?;
+    listener.set_nonblocking(true)?;
+    TcpListener::from_std(listener)
 }
 
 /// Do DNS lookup for collection of names and create collection of socket addresses
@@ -548,4 +1205,33 @@ struct PeerState {
     peer_ref: PeerRef,
     /// Peer IP address
     address: SocketAddr,
+    /// Address where peer accepts connections, known after successful bootstrap
+    listener_address: Option<SocketAddr>,
+    /// Peer id, known after successful bootstrap
+    peer_id: Option<PeerId>,
+    /// Remote peer initiated the connection or how we got to know the peer
+    kind: ConnectionKind,
+    /// Remote peer is private node, it must not be advertised to other peers
+    private_node: bool,
+    /// Peer was asked to stop, it stays registered until it is terminated
+    stopped: bool,
+}
+
+impl PeerState {
+    /// Remote peer initiated the connection
+    fn is_incoming(&self) -> bool {
+        self.kind == ConnectionKind::Incoming
+    }
+
+    fn connection(&self) -> Connection {
+        Connection::new(self.address.ip(), self.kind)
+    }
+
+    /// Point and peer id which can be offered to other peers in a swap
+    fn swap_point(&self) -> Option<(SocketAddr, PeerId)> {
+        match (self.listener_address, &self.peer_id) {
+            (Some(listener_address), Some(peer_id)) => Some((listener_address, peer_id.clone())),
+            _ => None,
+        }
+    }
 }
@@ -0,0 +1,429 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Recorder of the network and shell channel traffic.
+//!
+//! Recorder actor subscribes to the [NetworkChannel](networking::p2p::network_channel::NetworkChannel) and
+//! the [ShellChannel](crate::shell_channel::ShellChannel) and writes every event to a file as a [Record].
+//! Peers are identified by their actor name, received p2p messages are stored in their binary encoding.
+//!
+//! File consists of records, each record is prefixed with its length (u32, big endian) and encoded with bincode.
+//! Recorded file can be read by [read_records] and replayed, so captured incident can be turned to a regression test.
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use failure::Fail;
+use riker::actors::*;
+use serde::{Deserialize, Serialize};
+use slog::{info, warn};
+
+use crypto::hash::{BlockHash, OperationHash};
+#[cfg(feature = "barrier")]
+use networking::barrier::{answer_synchronized, Synchronize};
+use networking::p2p::network_channel::{Misbehavior, NetworkChannelMsg, NetworkChannelRef, PeerBootstrapped, PeerCreated, PeerMessageReceived, PeerMisbehaved};
+use storage::{BlockHeaderWithHash, BlockJsonData};
+use storage::mempool_storage::MempoolOperationType;
+use tezos_messages::Head;
+use tezos_messages::p2p::binary_message::BinaryMessage;
+use tezos_messages::p2p::encoding::metadata::MetadataMessage;
+use tezos_messages::p2p::encoding::peer::PeerMessageResponse;
+
+use crate::shell_channel::{AllBlockOperationsReceived, BlockApplied, BlockReceived, MempoolOperationReceived, ShellChannelMsg, ShellChannelRef};
+use crate::subscription::{subscribe_to_network_events, subscribe_to_shell_events};
+
+/// Maximal size of one encoded record, metadata of the applied blocks can have several megabytes
+const MAX_RECORD_SIZE: usize = 64 * 1024 * 1024;
+
+/// Possible errors for recording and reading of records
+#[derive(Debug, Fail)]
+pub enum RecorderError {
+    #[fail(display = "Record file I/O error! Reason: {:?}", error)]
+    IoError {
+        error: io::Error
+    },
+    #[fail(display = "Record serialization error! Reason: {:?}", error)]
+    SerializationError {
+        error: bincode::Error
+    },
+    #[fail(display = "Failed to encode peer message")]
+    MessageEncodeError,
+    #[fail(display = "Failed to decode peer message")]
+    MessageDecodeError,
+    #[fail(display = "Record is too large, size: {}, max: {}", size, max)]
+    RecordTooLarge {
+        size: usize,
+        max: usize,
+    },
+}
+
+impl From<io::Error> for RecorderError {
+    fn from(error: io::Error) -> Self {
+        RecorderError::IoError { error }
+    }
+}
+
+impl From<bincode::Error> for RecorderError {
+    fn from(error: bincode::Error) -> Self {
+        RecorderError::SerializationError { error }
+    }
+}
+
+/// Serializable representation of the network and shell channel events.
+///
+/// Peers are identified by the name of the peer actor.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum RecordedEvent {
+    PeerCreated {
+        peer: String,
+        address: SocketAddr,
+    },
+    PeerBootstrapped {
+        peer: String,
+        peer_id: String,
+        peer_metadata: MetadataMessage,
+        listener_port: u16,
+    },
+    PeerBootstrapFailed {
+        address: SocketAddr,
+        potential_peers_to_connect: Option<Vec<String>>,
+        misbehavior: Option<Misbehavior>,
+    },
+    PeerMessageReceived {
+        peer: String,
+        /// Binary encoded [PeerMessageResponse]
+        message: Vec<u8>,
+    },
+    PeerMisbehaved {
+        peer: String,
+        misbehavior: Misbehavior,
+        disconnect: bool,
+    },
+    NewCurrentHead {
+        head: Head,
+        header: BlockHeaderWithHash,
+        json_data: BlockJsonData,
+    },
+    BlockApplied {
+        header: BlockHeaderWithHash,
+        json_data: BlockJsonData,
+    },
+    BlockReceived {
+        hash: BlockHash,
+        level: i32,
+    },
+    AllBlockOperationsReceived {
+        hash: BlockHash,
+        level: i32,
+    },
+    MempoolOperationReceived {
+        operation_hash: OperationHash,
+        operation_type: MempoolOperationType,
+        peer_id: Option<String>,
+    },
+    ShuttingDown,
+}
+
+impl RecordedEvent {
+    /// Resolve recorded event from network channel message
+    pub fn from_network_msg(msg: &NetworkChannelMsg) -> Result<RecordedEvent, RecorderError> {
+        let event = match msg {
+            NetworkChannelMsg::PeerCreated(PeerCreated { peer, address, .. }) => RecordedEvent::PeerCreated {
+                peer: peer.name().to_string(),
+                address: *address,
+            },
+            NetworkChannelMsg::PeerBootstrapped(PeerBootstrapped::Success { peer, peer_id, peer_metadata, listener_port }) => RecordedEvent::PeerBootstrapped {
+                peer: peer.name().to_string(),
+                peer_id: peer_id.clone(),
+                peer_metadata: peer_metadata.clone(),
+                listener_port: *listener_port,
+            },
+            NetworkChannelMsg::PeerBootstrapped(PeerBootstrapped::Failure { address, potential_peers_to_connect, misbehavior }) => RecordedEvent::PeerBootstrapFailed {
+                address: *address,
+                potential_peers_to_connect: potential_peers_to_connect.clone(),
+                misbehavior: *misbehavior,
+            },
+            NetworkChannelMsg::PeerMessageReceived(PeerMessageReceived { peer, message, .. }) => RecordedEvent::PeerMessageReceived {
+                peer: peer.name().to_string(),
+                message: message.as_bytes().map_err(|_| RecorderError::MessageEncodeError)?,
+            },
+            NetworkChannelMsg::PeerMisbehaved(PeerMisbehaved { peer, misbehavior, disconnect }) => RecordedEvent::PeerMisbehaved {
+                peer: peer.name().to_string(),
+                misbehavior: *misbehavior,
+                disconnect: *disconnect,
+            },
+        };
+        Ok(event)
+    }
+
+    /// Resolve recorded event from shell channel message, returns `None` for messages which are not recorded
+    /// (commands and mempool state, which is derived from other events)
+    pub fn from_shell_msg(msg: &ShellChannelMsg) -> Option<RecordedEvent> {
+        match msg {
+            ShellChannelMsg::NewCurrentHead(head, block) => Some(RecordedEvent::NewCurrentHead {
+                head: head.clone(),
+                header: block.header().clone(),
+                json_data: block.json_data().clone(),
+            }),
+            ShellChannelMsg::BlockApplied(block) => Some(RecordedEvent::BlockApplied {
+                header: block.header().clone(),
+                json_data: block.json_data().clone(),
+            }),
+            ShellChannelMsg::BlockReceived(BlockReceived { hash, level }) => Some(RecordedEvent::BlockReceived {
+                hash: hash.clone(),
+                level: *level,
+            }),
+            ShellChannelMsg::AllBlockOperationsReceived(AllBlockOperationsReceived { hash, level }) => Some(RecordedEvent::AllBlockOperationsReceived {
+                hash: hash.clone(),
+                level: *level,
+            }),
+            ShellChannelMsg::MempoolOperationReceived(MempoolOperationReceived { operation_hash, operation_type, peer_id }) => Some(RecordedEvent::MempoolOperationReceived {
+                operation_hash: operation_hash.clone(),
+                operation_type: operation_type.clone(),
+                peer_id: peer_id.clone(),
+            }),
+            ShellChannelMsg::ShuttingDown(_) => Some(RecordedEvent::ShuttingDown),
+            _ => None,
+        }
+    }
+
+    /// Decode recorded peer message, returns `None` if event is not [RecordedEvent::PeerMessageReceived]
+    pub fn peer_message(&self) -> Result<Option<PeerMessageResponse>, RecorderError> {
+        match self {
+            RecordedEvent::PeerMessageReceived { message, .. } => PeerMessageResponse::from_bytes(message)
+                .map(Some)
+                .map_err(|_| RecorderError::MessageDecodeError),
+            _ => Ok(None),
+        }
+    }
+
+    /// Block applied message for recorded block events
+    pub fn block_applied(&self) -> Option<BlockApplied> {
+        match self {
+            RecordedEvent::NewCurrentHead { header, json_data, .. }
+            | RecordedEvent::BlockApplied { header, json_data } => Some(BlockApplied::new(header.clone(), json_data.clone())),
+            _ => None,
+        }
+    }
+}
+
+/// One recorded event with the time when it was recorded
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Record {
+    pub timestamp: SystemTime,
+    pub event: RecordedEvent,
+}
+
+impl Record {
+    pub fn new(event: RecordedEvent) -> Self {
+        Record { timestamp: SystemTime::now(), event }
+    }
+}
+
+/// Writes length prefixed records to the file
+pub struct RecordWriter {
+    writer: BufWriter<File>,
+}
+
+impl RecordWriter {
+    /// Create new record file, existing file is truncated
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self, RecorderError> {
+        Ok(RecordWriter { writer: BufWriter::new(File::create(path)?) })
+    }
+
+    pub fn write(&mut self, record: &Record) -> Result<(), RecorderError> {
+        let bytes = bincode::serialize(record)?;
+        if bytes.len() > MAX_RECORD_SIZE {
+            return Err(RecorderError::RecordTooLarge { size: bytes.len(), max: MAX_RECORD_SIZE });
+        }
+        self.writer.write_all(&(bytes.len() as u32).to_be_bytes())?;
+        self.writer.write_all(&bytes)?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), RecorderError> {
+        self.writer.flush().map_err(RecorderError::from)
+    }
+}
+
+/// Read all records from the file.
+///
+/// Last record could be incomplete, if node was killed during recording, such record is ignored.
+pub fn read_records<P: AsRef<Path>>(path: P) -> Result<Vec<Record>, RecorderError> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut records = Vec::new();
+    loop {
+        let mut size = [0u8; 4];
+        match reader.read_exact(&mut size) {
+            Ok(()) => (),
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+
+        // size is checked before the allocation, so corrupted file cannot exhaust the memory
+        let size = u32::from_be_bytes(size) as usize;
+        if size > MAX_RECORD_SIZE {
+            return Err(RecorderError::RecordTooLarge { size, max: MAX_RECORD_SIZE });
+        }
+        let mut bytes = vec![0u8; size];
+        match reader.read_exact(&mut bytes) {
+            Ok(()) => records.push(bincode::deserialize(&bytes)?),
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(records)
+}
+
+pub type RecorderRef = ActorRef<RecorderMsg>;
+
+/// Records network and shell channel traffic to the file
+#[cfg_attr(feature = "barrier", actor(NetworkChannelMsg, ShellChannelMsg, Synchronize))]
+#[cfg_attr(not(feature = "barrier"), actor(NetworkChannelMsg, ShellChannelMsg))]
+pub struct Recorder {
+    network_channel: NetworkChannelRef,
+    shell_channel: ShellChannelRef,
+    writer: Arc<Mutex<RecordWriter>>,
+}
+
+impl Recorder {
+    pub fn actor(sys: &impl ActorRefFactory, network_channel: NetworkChannelRef, shell_channel: ShellChannelRef, writer: RecordWriter) -> Result<RecorderRef, CreateError> {
+        sys.actor_of_props::<Recorder>(
+            Recorder::name(),
+            Props::new_args((network_channel, shell_channel, Arc::new(Mutex::new(writer)))),
+        )
+    }
+
+    /// The `Recorder` is intended to serve as a singleton actor so that's why
+    /// we won't support multiple names per instance.
+    fn name() -> &'static str {
+        "recorder"
+    }
+
+    fn write(&mut self, ctx: &Context<RecorderMsg>, event: RecordedEvent, flush: bool) {
+        let mut writer = self.writer.lock().unwrap();
+        let result = writer.write(&Record::new(event))
+            .and_then(|_| if flush { writer.flush() } else { Ok(()) });
+        if let Err(e) = result {
+            warn!(ctx.system.log(), "Failed to record event"; "reason" => format!("{:?}", e));
+        }
+    }
+}
+
+impl ActorFactoryArgs<(NetworkChannelRef, ShellChannelRef, Arc<Mutex<RecordWriter>>)> for Recorder {
+    fn create_args((network_channel, shell_channel, writer): (NetworkChannelRef, ShellChannelRef, Arc<Mutex<RecordWriter>>)) -> Self {
+        Recorder { network_channel, shell_channel, writer }
+    }
+}
+
+impl Actor for Recorder {
+    type Msg = RecorderMsg;
+
+    fn pre_start(&mut self, ctx: &Context<Self::Msg>) {
+        subscribe_to_network_events(&self.network_channel, ctx.myself());
+        subscribe_to_shell_events(&self.shell_channel, ctx.myself());
+        info!(ctx.system.log(), "Recording of the network and shell events started");
+    }
+
+    fn post_stop(&mut self) {
+        let _ = self.writer.lock().unwrap().flush();
+    }
+
+    fn recv(&mut self, ctx: &Context<Self::Msg>, msg: Self::Msg, sender: Sender) {
+        self.receive(ctx, msg, sender);
+    }
+}
+
+impl Receive<NetworkChannelMsg> for Recorder {
+    type Msg = RecorderMsg;
+
+    fn receive(&mut self, ctx: &Context<Self::Msg>, msg: NetworkChannelMsg, _sender: Sender) {
+        match RecordedEvent::from_network_msg(&msg) {
+            Ok(event) => self.write(ctx, event, false),
+            Err(e) => warn!(ctx.system.log(), "Failed to record network event"; "reason" => format!("{:?}", e)),
+        }
+    }
+}
+
+impl Receive<ShellChannelMsg> for Recorder {
+    type Msg = RecorderMsg;
+
+    fn receive(&mut self, ctx: &Context<Self::Msg>, msg: ShellChannelMsg, _sender: Sender) {
+        if let Some(event) = RecordedEvent::from_shell_msg(&msg) {
+            // flush everything before shutdown
+            let flush = matches!(event, RecordedEvent::ShuttingDown);
+            self.write(ctx, event, flush);
+        }
+    }
+}
+
+#[cfg(feature = "barrier")]
+impl Receive<Synchronize> for Recorder {
+    type Msg = RecorderMsg;
+
+    fn receive(&mut self, ctx: &Context<Self::Msg>, _msg: Synchronize, sender: Sender) {
+        // everything recorded so far is available to the reader of the file
+        if let Err(e) = self.writer.lock().unwrap().flush() {
+            warn!(ctx.system.log(), "Failed to flush recorded events"; "reason" => format!("{:?}", e));
+        }
+        answer_synchronized(sender);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use tezos_messages::p2p::encoding::prelude::PeerMessage;
+
+    use super::*;
+
+    #[test]
+    fn test_write_and_read_records() -> Result<(), failure::Error> {
+        let path = env::temp_dir().join("__test_write_and_read_records.rec");
+        let message: PeerMessageResponse = PeerMessage::Bootstrap.into();
+
+        let mut writer = RecordWriter::create(&path)?;
+        writer.write(&Record::new(RecordedEvent::PeerCreated { peer: "peer-1".to_string(), address: "127.0.0.1:9732".parse()? }))?;
+        writer.write(&Record::new(RecordedEvent::PeerMessageReceived { peer: "peer-1".to_string(), message: message.as_bytes()? }))?;
+        writer.write(&Record::new(RecordedEvent::ShuttingDown))?;
+        writer.flush()?;
+
+        // simulate killed node with incomplete last record
+        {
+            let mut file = std::fs::OpenOptions::new().append(true).open(&path)?;
+            file.write_all(&100u32.to_be_bytes())?;
+            file.write_all(&[1, 2, 3])?;
+        }
+
+        let records = read_records(&path)?;
+        assert_eq!(3, records.len());
+        assert!(matches!(&records[0].event, RecordedEvent::PeerCreated { peer, .. } if peer == "peer-1"));
+        let replayed = records[1].event.peer_message()?.expect("Expected peer message");
+        assert!(matches!(replayed.messages()[0], PeerMessage::Bootstrap));
+        assert!(matches!(records[2].event, RecordedEvent::ShuttingDown));
+
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_records_rejects_too_large_record() -> Result<(), failure::Error> {
+        let path = env::temp_dir().join("__test_read_records_rejects_too_large_record.rec");
+        {
+            let mut file = File::create(&path)?;
+            file.write_all(&u32::max_value().to_be_bytes())?;
+            file.write_all(&[1, 2, 3])?;
+        }
+
+        let result = read_records(&path);
+        assert!(matches!(result, Err(RecorderError::RecordTooLarge { size, .. }) if size == u32::max_value() as usize));
+
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+}
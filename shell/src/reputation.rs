@@ -0,0 +1,219 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Peer reputation scoring.
+//!
+//! Every misbehaviour adds penalty points to the offending IP address and peer id. Points are slowly
+//! forgiven over time, but once the score reaches the threshold the item is greylisted.
+//! Repeated offenders are greylisted for exponentially longer periods. Greylist is persisted,
+//! so bans (and ban counts) survive node restart.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime};
+
+use networking::p2p::network_channel::Misbehavior;
+use storage::{GreylistEntry, GreylistKey, PeerGreylistStorage, StorageError};
+
+/// When score reaches this value, item is greylisted
+const GREYLIST_SCORE_THRESHOLD: u32 = 100;
+/// One penalty point is forgiven after this interval
+const SCORE_DECAY_INTERVAL: Duration = Duration::from_secs(6);
+/// Duration of the first ban, every subsequent ban doubles the duration
+const GREYLIST_BASE_DURATION: Duration = Duration::from_secs(900);
+/// Upper limit of the ban duration
+const GREYLIST_MAX_DURATION: Duration = Duration::from_secs(86_400);
+
+/// Penalty points for the specific misbehaviour
+fn penalty(misbehavior: Misbehavior) -> u32 {
+    match misbehavior {
+        Misbehavior::UnsupportedProtocol => 100,
+        Misbehavior::InvalidProofOfWork => 100,
+        Misbehavior::Stalled => 50,
+        Misbehavior::InvalidOperations => 50,
+        Misbehavior::InvalidProtocol => 50,
+        Misbehavior::RateLimitExceeded => 50,
+        Misbehavior::Timeout => 35,
+        // handshake can fail for innocent reasons (e.g. dropped connection), only repeated failures lead to a ban
+        Misbehavior::BootstrapFailed => 35,
+        Misbehavior::InvalidBlockHeader => 25,
+    }
+}
+
+/// Resolve how long should be the item banned. Duration doubles with every ban.
+fn ban_duration(ban_count: u32) -> Duration {
+    let exponent = cmp::min(ban_count.saturating_sub(1), 16);
+    cmp::min(GREYLIST_BASE_DURATION * 2u32.pow(exponent), GREYLIST_MAX_DURATION)
+}
+
+struct Score {
+    points: u32,
+    updated: Instant,
+}
+
+impl Score {
+    /// Points left after forgiving the time elapsed since last update
+    fn decayed(&self, now: Instant) -> u32 {
+        let forgiven = now.saturating_duration_since(self.updated).as_secs() / SCORE_DECAY_INTERVAL.as_secs();
+        self.points.saturating_sub(cmp::min(forgiven, u64::from(u32::MAX)) as u32)
+    }
+}
+
+/// Keeps track of peer scores and greylisted items.
+pub(crate) struct PeerReputation {
+    /// Current penalty scores
+    scores: HashMap<GreylistKey, Score>,
+    /// Greylist, including expired bans (ban count is needed for repeated offenders)
+    greylist: HashMap<GreylistKey, GreylistEntry>,
+    /// Greylist is persisted here
+    storage: PeerGreylistStorage,
+}
+
+impl PeerReputation {
+    pub(crate) fn new(storage: PeerGreylistStorage) -> Self {
+        PeerReputation {
+            scores: HashMap::new(),
+            greylist: HashMap::new(),
+            storage,
+        }
+    }
+
+    /// Load persisted greylist from storage
+    pub(crate) fn load(&mut self) -> Result<(), StorageError> {
+        self.greylist = self.storage.iter()?.into_iter().collect();
+        Ok(())
+    }
+
+    /// Check if item is currently banned
+    pub(crate) fn is_greylisted(&self, key: &GreylistKey) -> bool {
+        self.greylist.get(key)
+            .map(|entry| entry.is_banned_at(SystemTime::now()))
+            .unwrap_or(false)
+    }
+
+    /// Add penalty points to the item.
+    ///
+    /// Returns ban duration if item was greylisted as a result of this misbehaviour.
+    pub(crate) fn penalize(&mut self, key: GreylistKey, misbehavior: Misbehavior) -> Result<Option<Duration>, StorageError> {
+        if self.is_greylisted(&key) {
+            return Ok(None);
+        }
+
+        let now = Instant::now();
+        // forget items which were already forgiven
+        self.scores.retain(|_, score| score.decayed(now) > 0);
+
+        let points = self.scores.get(&key)
+            .map(|score| score.decayed(now))
+            .unwrap_or(0)
+            .saturating_add(penalty(misbehavior));
+
+        if points >= GREYLIST_SCORE_THRESHOLD {
+            self.scores.remove(&key);
+            self.ban(key).map(Some)
+        } else {
+            self.scores.insert(key, Score { points, updated: now });
+            Ok(None)
+        }
+    }
+
+    /// Greylist item, returns ban duration
+    pub(crate) fn ban(&mut self, key: GreylistKey) -> Result<Duration, StorageError> {
+        let ban_count = self.greylist.get(&key)
+            .map(|entry| *entry.ban_count())
+            .unwrap_or(0)
+            .saturating_add(1);
+        let duration = ban_duration(ban_count);
+        let entry = GreylistEntry::new(SystemTime::now() + duration, ban_count);
+
+        self.storage.put(&key, &entry)?;
+        self.greylist.insert(key, entry);
+        Ok(duration)
+    }
+
+    /// Remove item from greylist and forget its score and ban history
+    pub(crate) fn unban(&mut self, key: &GreylistKey) -> Result<(), StorageError> {
+        self.scores.remove(key);
+        self.greylist.remove(key);
+        self.storage.delete(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use failure::Error;
+
+    use storage::tests_common::TmpStorage;
+
+    use super::*;
+
+    #[test]
+    fn test_ban_duration() {
+        assert_eq!(GREYLIST_BASE_DURATION, ban_duration(1));
+        assert_eq!(GREYLIST_BASE_DURATION * 2, ban_duration(2));
+        assert_eq!(GREYLIST_BASE_DURATION * 4, ban_duration(3));
+        assert_eq!(GREYLIST_MAX_DURATION, ban_duration(10));
+        assert_eq!(GREYLIST_MAX_DURATION, ban_duration(u32::MAX));
+    }
+
+    #[test]
+    fn test_penalize_and_greylist() -> Result<(), Error> {
+        let tmp_storage = TmpStorage::create("__test_peer_reputation_penalize")?;
+        let mut reputation = PeerReputation::new(PeerGreylistStorage::new(tmp_storage.storage()));
+
+        let ip = GreylistKey::Ip("10.0.0.1".parse()?);
+        let other_ip = GreylistKey::Ip("10.0.0.2".parse()?);
+
+        // accumulate penalty points
+        assert_eq!(None, reputation.penalize(ip.clone(), Misbehavior::Stalled)?);
+        assert!(!reputation.is_greylisted(&ip));
+        assert_eq!(None, reputation.penalize(other_ip.clone(), Misbehavior::Timeout)?);
+        assert!(!reputation.is_greylisted(&other_ip));
+
+        // threshold reached
+        assert_eq!(Some(GREYLIST_BASE_DURATION), reputation.penalize(ip.clone(), Misbehavior::InvalidOperations)?);
+        assert!(reputation.is_greylisted(&ip));
+        assert!(!reputation.is_greylisted(&other_ip));
+
+        // single failed handshake is not a ban, repeated failures are
+        let failing_ip = GreylistKey::Ip("10.0.0.3".parse()?);
+        assert_eq!(None, reputation.penalize(failing_ip.clone(), Misbehavior::BootstrapFailed)?);
+        assert_eq!(None, reputation.penalize(failing_ip.clone(), Misbehavior::BootstrapFailed)?);
+        assert!(!reputation.is_greylisted(&failing_ip));
+        assert!(reputation.penalize(failing_ip.clone(), Misbehavior::BootstrapFailed)?.is_some());
+        assert!(reputation.is_greylisted(&failing_ip));
+
+        // repeated ban doubles the duration
+        assert_eq!(GREYLIST_BASE_DURATION * 2, reputation.ban(ip.clone())?);
+
+        // unban
+        reputation.unban(&ip)?;
+        assert!(!reputation.is_greylisted(&ip));
+        assert_eq!(GREYLIST_BASE_DURATION, reputation.ban(ip.clone())?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_greylist_is_persisted() -> Result<(), Error> {
+        let tmp_storage = TmpStorage::create("__test_peer_reputation_persisted")?;
+
+        let ip = GreylistKey::Ip("10.0.0.1".parse()?);
+        let peer_id = GreylistKey::PeerId("idtgUrRAAvuH3cbZrXyfaKZovoFUqt".to_string());
+        {
+            let mut reputation = PeerReputation::new(PeerGreylistStorage::new(tmp_storage.storage()));
+            assert!(reputation.penalize(ip.clone(), Misbehavior::UnsupportedProtocol)?.is_some());
+            assert!(reputation.penalize(peer_id.clone(), Misbehavior::InvalidProofOfWork)?.is_some());
+            reputation.unban(&peer_id)?;
+        }
+
+        let mut reputation = PeerReputation::new(PeerGreylistStorage::new(tmp_storage.storage()));
+        assert!(!reputation.is_greylisted(&ip));
+        reputation.load()?;
+        assert!(reputation.is_greylisted(&ip));
+        assert!(!reputation.is_greylisted(&peer_id));
+        assert_eq!(GREYLIST_BASE_DURATION * 2, reputation.ban(ip)?);
+
+        Ok(())
+    }
+}
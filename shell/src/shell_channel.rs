@@ -9,9 +9,9 @@ use std::sync::{Arc, RwLock};
 use getset::Getters;
 use riker::actors::*;
 
-use crypto::hash::{BlockHash, OperationHash, ProtocolHash};
+use crypto::hash::{BlockHash, ChainId, HashType, OperationHash, ProtocolHash};
 use storage::block_storage::BlockJsonData;
-use storage::BlockHeaderWithHash;
+use storage::{BlockHeaderWithHash, GreylistKey};
 use storage::mempool_storage::MempoolOperationType;
 use tezos_api::ffi::{ApplyBlockRequest, ValidateOperationResult};
 use tezos_messages::Head;
@@ -33,6 +33,17 @@ impl BlockApplied {
     }
 }
 
+/// Message informing actors that applied block forked a test chain
+#[derive(Clone, Debug)]
+pub struct TestChainForked {
+    pub chain_id: ChainId,
+    pub forking_block_hash: BlockHash,
+    /// Protocol tested on the test chain
+    pub protocol: Option<ProtocolHash>,
+    /// UNIX timestamp, when test chain expires
+    pub expiration: Option<i64>,
+}
+
 /// Notify actors that system is about to shut down
 #[derive(Clone, Debug)]
 pub struct ShuttingDown;
@@ -56,6 +67,8 @@ pub struct AllBlockOperationsReceived {
 pub struct MempoolOperationReceived {
     pub operation_hash: OperationHash,
     pub operation_type: MempoolOperationType,
+    /// Peer which sent the operation, `None` for locally injected operations
+    pub peer_id: Option<String>,
 }
 
 #[derive(Clone, Debug)]
@@ -89,6 +102,12 @@ pub enum ShellChannelMsg {
     MempoolOperationReceived(MempoolOperationReceived),
     MempoolStateChanged(Arc<RwLock<CurrentMempoolState>>),
     InjectBlock(InjectBlock),
+    /// Chain_feeder propagates, if applied block forked a test chain
+    TestChainForked(TestChainForked),
+    /// Command to greylist IP address or peer id and to disconnect matching peers
+    BanPeer(GreylistKey),
+    /// Command to remove IP address or peer id from greylist
+    UnbanPeer(GreylistKey),
     ShuttingDown(ShuttingDown),
 }
 
@@ -128,6 +147,12 @@ impl From<AllBlockOperationsReceived> for ShellChannelMsg {
     }
 }
 
+impl From<TestChainForked> for ShellChannelMsg {
+    fn from(msg: TestChainForked) -> Self {
+        ShellChannelMsg::TestChainForked(msg)
+    }
+}
+
 impl From<ShuttingDown> for ShellChannelMsg {
     fn from(msg: ShuttingDown) -> Self {
         ShellChannelMsg::ShuttingDown(msg)
@@ -140,13 +165,16 @@ pub enum ShellChannelTopic {
     ShellEvents,
     /// Control event
     ShellCommands,
+    /// Events generated by the validator of the test chain (see [ChainRegistry::events_topic](crate::chain_registry::ChainRegistry::events_topic))
+    TestChainEvents(ChainId),
 }
 
 impl From<ShellChannelTopic> for Topic {
     fn from(evt: ShellChannelTopic) -> Self {
         match evt {
             ShellChannelTopic::ShellEvents => Topic::from("shell.events"),
-            ShellChannelTopic::ShellCommands => Topic::from("shell.command")
+            ShellChannelTopic::ShellCommands => Topic::from("shell.command"),
+            ShellChannelTopic::TestChainEvents(chain_id) => Topic::from(format!("shell.events.{}", HashType::ChainId.bytes_to_string(&chain_id))),
         }
     }
 }
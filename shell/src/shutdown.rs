@@ -0,0 +1,197 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Ordered shutdown of the node.
+//!
+//! Shutdown runs in [phases](ShutdownPhase), every phase waits until its components finished their work,
+//! but at most for the phase timeout, so one stuck component cannot block the whole shutdown:
+//! 1. stop accepting peers and RPC requests ([ShuttingDown](crate::shell_channel::ShuttingDown) is published)
+//! 2. block applier finishes the block being applied, queued blocks are dropped (they are not marked as applied)
+//! 3. context listener processes the final `Commit` of the last applied block
+//! 4. databases and commit logs are flushed
+//! 5. protocol runners are stopped
+//! 6. actors are stopped
+//!
+//! Context of the last applied block can still be missing, if the node was killed, or if some phase timed out.
+//! Such block is detected and repaired on the next startup, see [repair_half_applied_blocks](storage::repair_half_applied_blocks).
+
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use slog::{info, Logger, warn};
+
+use crypto::hash::ChainId;
+use storage::{BlockStorage, BlockStorageReader, ChainMetaStorage, is_block_context_committed, StorageError};
+use storage::chain_meta_storage::ChainMetaStorageReader;
+use storage::persistent::PersistentStorage;
+
+/// How often is checked, if the phase is finished
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ShutdownPhase {
+    StopNetworkAndRpc,
+    DrainApplyQueue,
+    WaitForContextCommit,
+    FlushStorage,
+    StopProtocolRunners,
+    StopActors,
+}
+
+impl ShutdownPhase {
+    /// Maximal time to wait for the phase to finish
+    pub fn timeout(&self) -> Duration {
+        match self {
+            ShutdownPhase::StopNetworkAndRpc => Duration::from_secs(5),
+            // apply of one block can take a long time
+            ShutdownPhase::DrainApplyQueue => Duration::from_secs(60),
+            ShutdownPhase::WaitForContextCommit => Duration::from_secs(30),
+            ShutdownPhase::FlushStorage => Duration::from_secs(30),
+            // watchdog checks the runner every second and waits for its process to terminate
+            ShutdownPhase::StopProtocolRunners => Duration::from_secs(10),
+            ShutdownPhase::StopActors => Duration::from_secs(10),
+        }
+    }
+}
+
+impl fmt::Display for ShutdownPhase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ShutdownPhase::StopNetworkAndRpc => "stop_network_and_rpc",
+            ShutdownPhase::DrainApplyQueue => "drain_apply_queue",
+            ShutdownPhase::WaitForContextCommit => "wait_for_context_commit",
+            ShutdownPhase::FlushStorage => "flush_storage",
+            ShutdownPhase::StopProtocolRunners => "stop_protocol_runners",
+            ShutdownPhase::StopActors => "stop_actors",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Running state of a worker (thread or server), which is waited for during shutdown
+#[derive(Clone, Debug)]
+pub struct WorkerStatus(Arc<AtomicBool>);
+
+impl WorkerStatus {
+    pub fn new() -> Self {
+        WorkerStatus(Arc::new(AtomicBool::new(true)))
+    }
+
+    /// Worker calls this, when it finished
+    pub fn set_finished(&self) {
+        self.0.store(false, Ordering::Release);
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+}
+
+impl Default for WorkerStatus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Runs shutdown phases one after another and logs their progress
+pub struct ShutdownCoordinator {
+    started: Instant,
+    log: Logger,
+}
+
+impl ShutdownCoordinator {
+    pub fn new(log: Logger) -> Self {
+        ShutdownCoordinator { started: Instant::now(), log }
+    }
+
+    /// Run `action` of the phase and wait until `is_finished` returns true or the phase times out.
+    /// Returns false, if the phase timed out.
+    pub fn run_phase<A, F>(&self, phase: ShutdownPhase, action: A, mut is_finished: F) -> bool
+        where
+            A: FnOnce(),
+            F: FnMut() -> bool
+    {
+        let phase_started = Instant::now();
+        info!(self.log, "Shutdown phase started"; "phase" => phase.to_string());
+        action();
+
+        loop {
+            if is_finished() {
+                info!(self.log, "Shutdown phase finished"; "phase" => phase.to_string(), "elapsed" => format!("{:?}", phase_started.elapsed()));
+                return true;
+            }
+            if phase_started.elapsed() >= phase.timeout() {
+                warn!(self.log, "Shutdown phase timed out, continuing with the next phase"; "phase" => phase.to_string(), "timeout" => format!("{:?}", phase.timeout()));
+                return false;
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// Time elapsed since the shutdown started
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+}
+
+/// Returns true, if the context of the current head was already committed by the context listener
+pub fn is_current_head_committed(persistent_storage: &PersistentStorage, chain_id: &ChainId) -> Result<bool, StorageError> {
+    let block_storage = BlockStorage::new(persistent_storage);
+    match ChainMetaStorage::new(persistent_storage).get_current_head(chain_id)? {
+        Some(current_head) => match block_storage.get(current_head.hash())? {
+            Some(block) => is_block_context_committed(&block_storage, &block),
+            None => Ok(true),
+        },
+        None => Ok(true),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use slog::Discard;
+
+    use super::*;
+
+    #[test]
+    fn test_run_phase() {
+        let coordinator = ShutdownCoordinator::new(Logger::root(Discard, slog::o!()));
+        let worker = WorkerStatus::new();
+
+        // worker finishes after phase action
+        let finishing_worker = worker.clone();
+        assert!(worker.is_running());
+        assert!(coordinator.run_phase(ShutdownPhase::StopNetworkAndRpc, || finishing_worker.set_finished(), || !worker.is_running()));
+        assert!(!worker.is_running());
+    }
+
+    #[test]
+    fn test_run_phase_blocks_until_finished() {
+        let coordinator = ShutdownCoordinator::new(Logger::root(Discard, slog::o!()));
+        let worker = WorkerStatus::new();
+        let (started_tx, started_rx) = std::sync::mpsc::channel();
+
+        // worker finishes only after the phase started to wait for it
+        let finishing_worker = worker.clone();
+        let handle = thread::spawn(move || {
+            started_rx.recv().unwrap();
+            finishing_worker.set_finished();
+        });
+
+        let mut polls = 0;
+        assert!(coordinator.run_phase(ShutdownPhase::DrainApplyQueue, || (), || {
+            polls += 1;
+            let finished = !worker.is_running();
+            if polls == 1 {
+                assert!(!finished);
+                started_tx.send(()).unwrap();
+            }
+            finished
+        }));
+        assert!(polls > 1);
+        assert!(!worker.is_running());
+        handle.join().unwrap();
+    }
+}
@@ -7,7 +7,6 @@ use std::cmp::Ordering;
 use std::sync::{Arc, RwLock};
 
 use failure::_core::fmt::Formatter;
-use rand::prelude::ThreadRng;
 use rand::Rng;
 use slog::Logger;
 
@@ -17,10 +16,11 @@ use storage::block_meta_storage::Meta;
 use storage::persistent::PersistentStorage;
 use tezos_messages::Head;
 use tezos_messages::p2p::encoding::block_header::{BlockHeader, Level};
-use tezos_messages::p2p::encoding::current_branch::{CurrentBranchMessage, HISTORY_MAX_SIZE};
+use tezos_messages::p2p::encoding::current_branch::CurrentBranchMessage;
 
 use crate::collections::{BlockData, UniqueBlockData};
 use crate::shell_channel::{BlockApplied, CurrentMempoolState};
+use crate::state::download_scheduler::branch_segments;
 use crate::validation;
 
 /// Holds state of all known blocks
@@ -102,15 +102,15 @@ impl BlockchainState {
         Ok(false)
     }
 
-    /// Resolves missing blocks and schedules them for download from network
-    pub fn schedule_branch_bootstrap(&mut self, block_hash: &BlockHash, block_header: &BlockHeader, history: &Vec<BlockHash>) -> Result<(), StorageError> {
+    /// Resolves missing blocks and schedules them for download from network,
+    /// missing range above the `local_head_level` is split into segments by the `history`
+    pub fn schedule_branch_bootstrap(&mut self, block_hash: &BlockHash, block_header: &BlockHeader, history: &[BlockHash], local_head_level: Level) -> Result<(), StorageError> {
         let block_level = block_header.level();
 
-        // at first schedule history - we try to prioritize download from the beginning, so the history is reversed here
-        self.push_missing_history(
-            history.iter().cloned().rev().collect(),
-            block_level,
-        )?;
+        // at first schedule history - blocks are downloaded from the lowest segment
+        for (history_block_hash, level_guess) in branch_segments(local_head_level, block_level, history) {
+            self.push_missing_block(MissingBlock::with_level_guess(history_block_hash, level_guess))?;
+        }
 
         // schedule predecessor (if not present in history)
         if !history.contains(block_header.predecessor()) {
@@ -221,29 +221,6 @@ impl BlockchainState {
         Ok(())
     }
 
-    #[inline]
-    fn push_missing_history(&mut self, history: Vec<BlockHash>, level: Level) -> Result<(), StorageError> {
-        let mut rng = rand::thread_rng();
-        let history_max_parts = if history.len() < usize::from(HISTORY_MAX_SIZE) {
-            history.len() as u8
-        } else {
-            HISTORY_MAX_SIZE
-        };
-
-        history.iter().enumerate()
-            .map(|(idx, history_block_hash)| {
-                self.push_missing_block(
-                    MissingBlock::with_level_guess(
-                        history_block_hash.clone(),
-                        Self::guess_level(&mut rng, level, history_max_parts, idx),
-                    )
-                )
-            })
-            .collect::<Result<Vec<_>, _>>()?;
-
-        Ok(())
-    }
-
     #[inline]
     pub fn has_missing_blocks(&self) -> bool {
         !self.missing_blocks.is_empty()
@@ -291,33 +268,6 @@ impl BlockchainState {
         }
         Ok(history)
     }
-
-    fn guess_level(rng: &mut ThreadRng, level: Level, parts: u8, index: usize) -> i32 {
-        // e.g. we have: level 100 a 5 record in history, so split is 20, never <= 0
-        let split = level / i32::from(parts);
-        // corner case for 1 level;
-        let split = cmp::max(1, split);
-
-        // we try to guess level, because in history there is no level
-        if index == 0 {
-            // first block in history is always genesis
-            0
-        } else {
-            // e.g. next block: idx * split, e.g. for index in history: 1 and split, we guess level is in range (0 * 20 - 1 * 20) -> (0, 20)
-            let start_level = ((index as i32 - 1) * split) + 1;
-            let end_level = (index as i32) * split;
-
-            // corner case for 1 level
-            let start_level = cmp::min(start_level, level);
-            let end_level = cmp::min(end_level, level);
-
-            if start_level == end_level {
-                start_level
-            } else {
-                rng.gen_range(start_level, end_level)
-            }
-        }
-    }
 }
 
 #[derive(Clone, Debug)]
@@ -455,75 +405,4 @@ mod tests {
 
         assert_eq!(expected_order, ordered_hashes)
     }
-
-    #[test]
-    fn test_guess_level() {
-        let mut rng = rand::thread_rng();
-
-        // for block 0 in history (always 0)
-        for _ in 0..100 {
-            let level = BlockchainState::guess_level(&mut rng, 100, 5, 0);
-            assert_eq!(level, 0);
-        }
-
-        // for block 1 in history [1, 20)
-        for _ in 0..100 {
-            let level = BlockchainState::guess_level(&mut rng, 100, 5, 1);
-            assert!(level >= 1 && level < 20);
-        }
-
-        // for block 2 in history [20, 40)
-        for _ in 0..100 {
-            let level = BlockchainState::guess_level(&mut rng, 100, 5, 2);
-            assert!(level >= 20 && level < 40);
-        }
-
-        // for block 3 in history [40, 60)
-        for _ in 0..100 {
-            let level = BlockchainState::guess_level(&mut rng, 100, 5, 3);
-            assert!(level >= 40 && level < 60);
-        }
-
-        // for block 4 in history [60, 80)
-        for _ in 0..100 {
-            let level = BlockchainState::guess_level(&mut rng, 100, 5, 4);
-            assert!(level >= 60 && level < 80);
-        }
-
-        // for block 5 in history [80, 100)
-        for _ in 0..100 {
-            let level = BlockchainState::guess_level(&mut rng, 100, 5, 5);
-            assert!(level >= 80 && level < 100);
-        }
-
-        // for block 0 in history [0, 1)
-        for _ in 0..100 {
-            let level = BlockchainState::guess_level(&mut rng, 1, 1, 0);
-            assert!(level >= 0 && level < 1);
-        }
-
-        // corner case (for level 1 if there are two elements)
-        // for block 0 in history [0, 1)
-        for _ in 0..100 {
-            let level = BlockchainState::guess_level(&mut rng, 1, 2, 0);
-            assert!(level >= 0 && level < 1);
-        }
-
-        // corner case (for level 1 if there are two elements)
-        // for block 1 in history [1, 2)
-        for _ in 0..100 {
-            let level = BlockchainState::guess_level(&mut rng, 1, 2, 1);
-            assert!(level >= 1 && level < 2);
-        }
-
-        // corner cases
-        for _ in 0..100 {
-            let level = BlockchainState::guess_level(&mut rng, 1, 3, 0);
-            assert!(level >= 0 && level < 1);
-            let level = BlockchainState::guess_level(&mut rng, 1, 3, 1);
-            assert!(level >= 1 && level < 2);
-            let level = BlockchainState::guess_level(&mut rng, 1, 3, 2);
-            assert!(level >= 1 && level < 2);
-        }
-    }
 }
\ No newline at end of file
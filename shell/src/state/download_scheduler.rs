@@ -0,0 +1,318 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Scheduling of the block header and block operations downloads between peers.
+//!
+//! Range between the local head and the remote head is split into [segments](branch_segments) by the history
+//! of the remote `CurrentBranch`, every segment is downloaded from its top by walking the predecessors,
+//! so segments are downloaded in parallel.
+//! Missing blocks are ordered by level, so every peer gets a continuous segment of the missing range
+//! (lowest levels first). Peers are served in the order of their [score](DownloadQueue::score),
+//! so the fastest peer gets the segment, which is needed first.
+//!
+//! Every peer has its own [DownloadQueue] for headers and for operations, which:
+//! - measures latency and throughput of the peer from the request/response times
+//! - adapts the batch size of the peer - it grows with every timely response and is halved on timeout
+//! - expires requests, which were not answered on time, so they can be re-assigned to another peer
+
+use std::cmp;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crypto::hash::BlockHash;
+use tezos_messages::p2p::encoding::block_header::Level;
+
+use crate::collections::BlockData;
+
+/// Batch size of the new peer
+pub(crate) const INITIAL_BATCH_SIZE: usize = 10;
+/// Maximal count of requests in flight for one peer
+pub(crate) const MAX_BATCH_SIZE: usize = 64;
+/// Request of the peer without measured latency expires after this time
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+/// Request timeout is never shorter than this
+const MIN_REQUEST_TIMEOUT: Duration = Duration::from_secs(3);
+/// Request timeout is never longer than this (peer is disconnected after 30s of silence)
+const MAX_REQUEST_TIMEOUT: Duration = Duration::from_secs(20);
+/// Request timeout is this multiple of the measured latency
+const REQUEST_TIMEOUT_LATENCY_MULTIPLIER: u32 = 4;
+/// Late responses to expired requests are accepted (ignored without penalty) for this time
+const EXPIRED_REQUEST_LINGER: Duration = Duration::from_secs(60);
+/// Weight of the new sample in the moving averages of latency and throughput
+const MOVING_AVERAGE_WEIGHT: f64 = 0.25;
+/// Block locator of the OCaml node keeps the distance between the history blocks for this many blocks, then the distance doubles
+const HISTORY_STEP_REPEAT: usize = 9;
+
+/// Split the missing range between the local head and the remote head into segments, returns top block of every segment with its estimated level.
+///
+/// Every block of the `history` (ordered from the remote head down) is the top of the segment, which ends at the next history block.
+/// Levels of the history blocks are not sent, they are estimated from the block locator, which gets sparser with the distance from the head.
+/// History blocks estimated at or below the local head are placed just above it, they are either already stored (and not downloaded at all)
+/// or they belong to the fork, which has to be downloaded before the blocks above it.
+pub(crate) fn branch_segments(local_head_level: Level, head_level: Level, history: &[BlockHash]) -> Vec<(BlockHash, Level)> {
+    let lowest_level = cmp::min(local_head_level + 1, head_level);
+    let mut level = head_level;
+    let mut step: Level = 1;
+    history.iter()
+        .enumerate()
+        .map(|(index, block_hash)| {
+            if index > 0 && index % HISTORY_STEP_REPEAT == 0 {
+                step = step.saturating_mul(2);
+            }
+            level = level.saturating_sub(step);
+            (block_hash.clone(), cmp::max(level, lowest_level))
+        })
+        .collect()
+}
+
+/// Requests in flight to one peer together with the measured performance of the peer
+pub(crate) struct DownloadQueue<T> {
+    /// Requested items and time of the request
+    queued: HashMap<BlockHash, (T, Instant)>,
+    /// Requests, which were not answered on time and were returned to be scheduled again
+    expired: HashMap<BlockHash, Instant>,
+    /// Current limit of the requests in flight
+    batch_size: usize,
+    /// Moving average of the time between request and response
+    latency: Option<Duration>,
+    /// Moving average of the received items per second
+    throughput: Option<f64>,
+}
+
+impl<T: BlockData> DownloadQueue<T> {
+    pub(crate) fn new() -> Self {
+        DownloadQueue {
+            queued: HashMap::new(),
+            expired: HashMap::new(),
+            batch_size: INITIAL_BATCH_SIZE,
+            latency: None,
+            throughput: None,
+        }
+    }
+
+    /// Queue requested item, returns false, if it was already queued
+    pub(crate) fn insert(&mut self, item: T, now: Instant) -> bool {
+        let block_hash = item.block_hash().clone();
+        self.expired.remove(&block_hash);
+        if self.queued.contains_key(&block_hash) {
+            false
+        } else {
+            self.queued.insert(block_hash, (item, now));
+            true
+        }
+    }
+
+    /// Remove answered item and update measured performance of the peer
+    pub(crate) fn remove(&mut self, block_hash: &BlockHash, now: Instant) -> Option<T> {
+        let in_flight = self.queued.len();
+        let (item, requested_at) = self.queued.remove(block_hash)?;
+
+        let latency = now.saturating_duration_since(requested_at);
+        self.latency = Some(match self.latency {
+            Some(average) => average.mul_f64(1.0 - MOVING_AVERAGE_WEIGHT) + latency.mul_f64(MOVING_AVERAGE_WEIGHT),
+            None => latency,
+        });
+        // all requests in flight were processed by the peer in parallel
+        let throughput = in_flight as f64 / cmp::max(latency, Duration::from_millis(1)).as_secs_f64();
+        self.throughput = Some(match self.throughput {
+            Some(average) => average * (1.0 - MOVING_AVERAGE_WEIGHT) + throughput * MOVING_AVERAGE_WEIGHT,
+            None => throughput,
+        });
+        self.batch_size = cmp::min(self.batch_size + 1, MAX_BATCH_SIZE);
+
+        Some(item)
+    }
+
+    pub(crate) fn get(&self, block_hash: &BlockHash) -> Option<&T> {
+        self.queued.get(block_hash).map(|(item, _)| item)
+    }
+
+    pub(crate) fn get_mut(&mut self, block_hash: &BlockHash) -> Option<&mut T> {
+        self.queued.get_mut(block_hash).map(|(item, _)| item)
+    }
+
+    /// Returns true, if the request for the item expired recently, so late response is not a misbehavior
+    pub(crate) fn is_expired(&self, block_hash: &BlockHash) -> bool {
+        self.expired.contains_key(block_hash)
+    }
+
+    /// Remove requests, which were not answered on time, they should be scheduled again.
+    /// Batch size of the peer is halved and its throughput is lowered, if anything expired.
+    pub(crate) fn expire(&mut self, now: Instant) -> Vec<T> {
+        self.expired.retain(|_, expired_at| now.saturating_duration_since(*expired_at) <= EXPIRED_REQUEST_LINGER);
+
+        let timeout = self.timeout();
+        let expired_hashes = self.queued.iter()
+            .filter(|(_, (_, requested_at))| now.saturating_duration_since(*requested_at) > timeout)
+            .map(|(block_hash, _)| block_hash.clone())
+            .collect::<Vec<_>>();
+        if !expired_hashes.is_empty() {
+            self.batch_size = cmp::max(self.batch_size / 2, 1);
+            // nothing was received for the expired requests
+            self.throughput = Some(self.throughput.map_or(0.0, |average| average * (1.0 - MOVING_AVERAGE_WEIGHT)));
+        }
+
+        expired_hashes.into_iter()
+            .filter_map(|block_hash| {
+                let (item, _) = self.queued.remove(&block_hash)?;
+                self.expired.insert(block_hash, now);
+                Some(item)
+            })
+            .collect()
+    }
+
+    /// Remove all queued items, e.g. when peer is disconnected
+    pub(crate) fn drain(&mut self) -> impl Iterator<Item=T> + '_ {
+        self.expired.clear();
+        self.queued.drain().map(|(_, (item, _))| item)
+    }
+}
+
+impl<T: BlockData> Default for DownloadQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> DownloadQueue<T> {
+    #[inline]
+    pub(crate) fn len(&self) -> usize {
+        self.queued.len()
+    }
+
+    #[inline]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.queued.is_empty()
+    }
+
+    /// How many new items can be requested from the peer
+    #[inline]
+    pub(crate) fn available_capacity(&self) -> usize {
+        self.batch_size.saturating_sub(self.queued.len())
+    }
+
+    #[inline]
+    pub(crate) fn batch_size(&self) -> usize {
+        self.batch_size
+    }
+
+    #[inline]
+    pub(crate) fn latency(&self) -> Option<Duration> {
+        self.latency
+    }
+
+    /// Time after which unanswered request expires, derived from the measured latency
+    pub(crate) fn timeout(&self) -> Duration {
+        match self.latency {
+            Some(latency) => cmp::min(cmp::max(latency * REQUEST_TIMEOUT_LATENCY_MULTIPLIER, MIN_REQUEST_TIMEOUT), MAX_REQUEST_TIMEOUT),
+            None => DEFAULT_REQUEST_TIMEOUT,
+        }
+    }
+
+    /// Expected count of items received per second, peers without measurement are expected to answer the whole batch in one second
+    pub(crate) fn score(&self) -> f64 {
+        self.throughput.unwrap_or(INITIAL_BATCH_SIZE as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct Item(BlockHash);
+
+    impl BlockData for Item {
+        fn block_hash(&self) -> &BlockHash {
+            &self.0
+        }
+    }
+
+    fn item(id: u8) -> Item {
+        Item(vec![id; 32])
+    }
+
+    #[test]
+    fn test_batch_size_grows_with_responses() {
+        let now = Instant::now();
+        let mut queue = DownloadQueue::new();
+        assert_eq!(INITIAL_BATCH_SIZE, queue.available_capacity());
+
+        assert!(queue.insert(item(1), now));
+        assert!(queue.insert(item(2), now));
+        assert!(!queue.insert(item(2), now));
+        assert_eq!(INITIAL_BATCH_SIZE - 2, queue.available_capacity());
+
+        assert_eq!(Some(item(1)), queue.remove(&item(1).0, now + Duration::from_millis(500)));
+        assert_eq!(None, queue.remove(&item(1).0, now + Duration::from_millis(500)));
+        assert_eq!(INITIAL_BATCH_SIZE + 1, queue.batch_size());
+        assert_eq!(Some(Duration::from_millis(500)), queue.latency());
+        // two requests answered in 0.5s
+        assert_eq!(4.0, queue.score());
+        assert_eq!(MIN_REQUEST_TIMEOUT, queue.timeout());
+    }
+
+    #[test]
+    fn test_expire_halves_batch_size() {
+        let now = Instant::now();
+        let mut queue = DownloadQueue::new();
+        queue.insert(item(1), now);
+        queue.insert(item(2), now + Duration::from_secs(10));
+
+        assert!(queue.expire(now + DEFAULT_REQUEST_TIMEOUT).is_empty());
+        assert_eq!(vec![item(1)], queue.expire(now + DEFAULT_REQUEST_TIMEOUT + Duration::from_secs(1)));
+        assert_eq!(INITIAL_BATCH_SIZE / 2, queue.batch_size());
+        assert_eq!(0.0, queue.score());
+        assert_eq!(1, queue.len());
+
+        // late response is recognized, until it lingers too long
+        assert!(queue.is_expired(&item(1).0));
+        assert!(!queue.is_expired(&item(2).0));
+        queue.expire(now + DEFAULT_REQUEST_TIMEOUT + EXPIRED_REQUEST_LINGER + Duration::from_secs(2));
+        assert!(!queue.is_expired(&item(1).0));
+
+        // second request expired meanwhile, but it is not expired anymore, once requested again
+        assert!(queue.is_expired(&item(2).0));
+        assert!(queue.insert(item(2), now));
+        assert!(!queue.is_expired(&item(2).0));
+        assert_eq!(1, queue.drain().count());
+    }
+
+    #[test]
+    fn test_branch_segments() {
+        let history = (0..20).map(|id| item(id).0).collect::<Vec<_>>();
+
+        // nothing is stored locally, history blocks get sparser with the distance from the head
+        let segments = branch_segments(0, 1000, &history);
+        assert_eq!(20, segments.len());
+        assert_eq!((item(0).0, 999), segments[0]);
+        assert_eq!((item(8).0, 991), segments[8]);
+        assert_eq!((item(9).0, 989), segments[9]);
+        assert_eq!((item(17).0, 973), segments[17]);
+        assert_eq!((item(18).0, 969), segments[18]);
+
+        // history below the local head ends up just above it
+        let segments = branch_segments(995, 1000, &history);
+        assert_eq!(vec![999, 998, 997, 996, 996], segments.iter().take(5).map(|(_, level)| *level).collect::<Vec<_>>());
+        assert!(segments.iter().all(|(_, level)| *level >= 996));
+
+        // local head above the remote head
+        assert!(branch_segments(1010, 1000, &history).iter().all(|(_, level)| *level == 1000));
+        assert!(branch_segments(0, 1000, &[]).is_empty());
+    }
+
+    #[test]
+    fn test_timeout_follows_latency() {
+        let now = Instant::now();
+        let mut queue = DownloadQueue::new();
+        assert_eq!(DEFAULT_REQUEST_TIMEOUT, queue.timeout());
+
+        queue.insert(item(1), now);
+        queue.remove(&item(1).0, now + Duration::from_secs(2));
+        assert_eq!(Duration::from_secs(8), queue.timeout());
+
+        queue.insert(item(2), now);
+        queue.remove(&item(2).0, now + Duration::from_secs(30));
+        assert_eq!(MAX_REQUEST_TIMEOUT, queue.timeout());
+    }
+}
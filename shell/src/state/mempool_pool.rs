@@ -0,0 +1,555 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Bounded pool of mempool operations.
+//!
+//! Pool holds operation data together with its priority and source peer. Operations are
+//! kept ordered by priority, so pending operations are validated from the most valuable ones
+//! and the least valuable ones are evicted, when some of the limits is reached.
+
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::convert::TryInto;
+
+use crypto::hash::OperationHash;
+use tezos_messages::p2p::encoding::prelude::Operation;
+
+use crate::mempool_prevalidator::MempoolLimits;
+
+/// Default minimal fee per gas unit used by tezos bakers (nanotez)
+const NANOTEZ_PER_GAS_UNIT: u128 = 100;
+/// Default minimal fee per byte used by tezos bakers (nanotez)
+const NANOTEZ_PER_BYTE: u128 = 1000;
+
+/// Tag of the endorsement operation content
+const ENDORSEMENT_TAG: u8 = 0;
+/// Tags of the anonymous and voting operation contents (seed nonce revelation, double endorsement and double baking evidence, account activation, proposals, ballot)
+const OTHER_TAGS: std::ops::RangeInclusive<u8> = 1..=6;
+/// Tags of the manager operation contents (reveal, transaction, origination, delegation) since protocol 005
+const MANAGER_TAGS: std::ops::RangeInclusive<u8> = 107..=110;
+/// Size of the public key hash of the manager operation source (tag + hash)
+const SOURCE_SIZE: usize = 21;
+
+/// Priority of the operation in the mempool. Higher priority operations are validated first and evicted last.
+#[derive(Clone, Copy, Debug)]
+pub enum OperationPriority {
+    /// Operations with unknown tag or which cannot be decoded, they go always last
+    Unknown,
+    /// Manager operation, ordered by fee per gas and byte of the first operation in the batch
+    Manager {
+        fee: u64,
+        gas_limit: u64,
+        size: u64,
+    },
+    /// Other operations (voting and anonymous operations)
+    Other,
+    /// Endorsements are needed for the next block, so they go always first
+    Endorsement,
+}
+
+impl OperationPriority {
+    /// Resolve priority from the binary operation data
+    pub fn resolve(operation: &Operation) -> Self {
+        let data = operation.data();
+        match data.first() {
+            Some(&ENDORSEMENT_TAG) => OperationPriority::Endorsement,
+            Some(tag) if MANAGER_TAGS.contains(tag) => {
+                // tag + source + fee + counter + gas_limit
+                let mut position = 1 + SOURCE_SIZE;
+                let fee = read_natural(data, &mut position);
+                let _counter = read_natural(data, &mut position);
+                let gas_limit = read_natural(data, &mut position);
+                match (fee, gas_limit) {
+                    (Some(fee), Some(gas_limit)) => OperationPriority::Manager {
+                        fee,
+                        gas_limit,
+                        size: (operation.branch().len() + data.len()) as u64,
+                    },
+                    _ => OperationPriority::Unknown,
+                }
+            }
+            Some(tag) if OTHER_TAGS.contains(tag) => OperationPriority::Other,
+            _ => OperationPriority::Unknown,
+        }
+    }
+
+    fn rank(&self) -> u8 {
+        match self {
+            OperationPriority::Unknown => 0,
+            OperationPriority::Manager { .. } => 1,
+            OperationPriority::Other => 2,
+            OperationPriority::Endorsement => 3,
+        }
+    }
+}
+
+impl Ord for OperationPriority {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (OperationPriority::Manager { fee, gas_limit, size }, OperationPriority::Manager { fee: other_fee, gas_limit: other_gas_limit, size: other_size }) => {
+                // compare fee / (gas_limit * NANOTEZ_PER_GAS_UNIT + size * NANOTEZ_PER_BYTE) without division
+                let cost = (*gas_limit as u128) * NANOTEZ_PER_GAS_UNIT + (*size as u128) * NANOTEZ_PER_BYTE;
+                let other_cost = (*other_gas_limit as u128) * NANOTEZ_PER_GAS_UNIT + (*other_size as u128) * NANOTEZ_PER_BYTE;
+                ((*fee as u128) * other_cost).cmp(&((*other_fee as u128) * cost))
+            }
+            _ => self.rank().cmp(&other.rank()),
+        }
+    }
+}
+
+impl PartialOrd for OperationPriority {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for OperationPriority {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for OperationPriority {}
+
+/// Read zarith encoded natural number, returns `None` if it is not valid or does not fit into `u64`
+fn read_natural(data: &[u8], position: &mut usize) -> Option<u64> {
+    let mut value: u128 = 0;
+    let mut shift = 0;
+    loop {
+        let byte = *data.get(*position)?;
+        *position += 1;
+        value |= ((byte & 0x7f) as u128) << shift;
+        if byte & 0x80 == 0 {
+            return value.try_into().ok();
+        }
+        shift += 7;
+        if shift > 64 {
+            return None;
+        }
+    }
+}
+
+/// Position of the operation in the pool ordering, the first key is the least valuable one
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct PoolKey {
+    priority: OperationPriority,
+    /// Insertion order, with the same priority the older operation is more valuable
+    sequence: u64,
+    hash: OperationHash,
+}
+
+impl Ord for PoolKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority.cmp(&other.priority)
+            .then_with(|| other.sequence.cmp(&self.sequence))
+            .then_with(|| self.hash.cmp(&other.hash))
+    }
+}
+
+impl PartialOrd for PoolKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[derive(Clone, Debug)]
+struct PoolEntry {
+    operation: Operation,
+    key: PoolKey,
+    size: usize,
+    /// Peer which sent us the operation (`None` for locally injected operations)
+    peer_id: Option<String>,
+}
+
+/// Operations and bytes used by a single peer
+#[derive(Clone, Debug, Default)]
+struct PeerUsage {
+    operations: BTreeSet<PoolKey>,
+    bytes: usize,
+}
+
+/// Result of adding operation to the pool
+#[derive(Debug, PartialEq)]
+pub enum PoolInsert {
+    /// Operation was added, evicted operations were removed from the pool
+    Inserted { evicted: Vec<OperationHash> },
+    /// Operation is already in the pool
+    AlreadyPresent,
+    /// Pool is full of more valuable operations
+    Rejected,
+}
+
+/// Bounded pool of mempool operations, indexed by operation hash and ordered by priority.
+#[derive(Clone, Debug)]
+pub struct OperationPool {
+    limits: MempoolLimits,
+    operations: HashMap<OperationHash, PoolEntry>,
+    /// All operations ordered by priority
+    by_priority: BTreeSet<PoolKey>,
+    /// Operations waiting for validation ordered by priority
+    pending: BTreeSet<PoolKey>,
+    peers: HashMap<String, PeerUsage>,
+    bytes: usize,
+    sequence: u64,
+}
+
+impl OperationPool {
+    pub fn new(limits: MempoolLimits) -> Self {
+        OperationPool {
+            limits,
+            operations: HashMap::new(),
+            by_priority: BTreeSet::new(),
+            pending: BTreeSet::new(),
+            peers: HashMap::new(),
+            bytes: 0,
+            sequence: 0,
+        }
+    }
+
+    /// Add operation to the pool as pending. If some limit is reached, less valuable operations are evicted.
+    pub fn insert(&mut self, operation_hash: OperationHash, operation: Operation, peer_id: Option<String>) -> PoolInsert {
+        if self.operations.contains_key(&operation_hash) {
+            return PoolInsert::AlreadyPresent;
+        }
+
+        let size = operation.branch().len() + operation.data().len();
+        let key = PoolKey {
+            priority: OperationPriority::resolve(&operation),
+            sequence: self.sequence,
+            hash: operation_hash.clone(),
+        };
+
+        let evicted = match self.resolve_evictions(&key, size, peer_id.as_ref()) {
+            Some(evicted) => evicted,
+            None => return PoolInsert::Rejected,
+        };
+        for evicted_hash in &evicted {
+            self.remove(evicted_hash);
+        }
+
+        self.sequence += 1;
+        self.bytes += size;
+        self.by_priority.insert(key.clone());
+        self.pending.insert(key.clone());
+        if let Some(peer_id) = &peer_id {
+            let usage = self.peers.entry(peer_id.clone()).or_default();
+            usage.operations.insert(key.clone());
+            usage.bytes += size;
+        }
+        self.operations.insert(operation_hash, PoolEntry { operation, key, size, peer_id });
+
+        PoolInsert::Inserted { evicted }
+    }
+
+    /// Find operations, which have to be evicted to make space for the new one.
+    /// Returns `None` if there is not enough less valuable operations.
+    fn resolve_evictions(&self, key: &PoolKey, size: usize, peer_id: Option<&String>) -> Option<Vec<OperationHash>> {
+        if size > self.limits.max_bytes {
+            return None;
+        }
+
+        let mut evicted: HashSet<&OperationHash> = HashSet::new();
+        let mut count = self.operations.len();
+        let mut bytes = self.bytes;
+
+        // per peer limits
+        if let Some(peer_id) = peer_id {
+            if size > self.limits.max_bytes_per_peer {
+                return None;
+            }
+            if let Some(usage) = self.peers.get(peer_id) {
+                let mut peer_count = usage.operations.len();
+                let mut peer_bytes = usage.bytes;
+                let mut candidates = usage.operations.iter();
+                while peer_count + 1 > self.limits.max_operations_per_peer || peer_bytes + size > self.limits.max_bytes_per_peer {
+                    let candidate = candidates.next().filter(|candidate| *candidate < key)?;
+                    let candidate_size = self.operations.get(&candidate.hash)?.size;
+                    peer_count -= 1;
+                    peer_bytes -= candidate_size;
+                    count -= 1;
+                    bytes -= candidate_size;
+                    evicted.insert(&candidate.hash);
+                }
+            }
+        }
+
+        // global limits
+        let mut candidates = self.by_priority.iter().filter(|candidate| !evicted.contains(&candidate.hash));
+        let mut global_evicted = Vec::new();
+        while count + 1 > self.limits.max_operations || bytes + size > self.limits.max_bytes {
+            let candidate = candidates.next().filter(|candidate| *candidate < key)?;
+            count -= 1;
+            bytes -= self.operations.get(&candidate.hash)?.size;
+            global_evicted.push(&candidate.hash);
+        }
+
+        Some(evicted.into_iter().chain(global_evicted).cloned().collect())
+    }
+
+    /// Remove operation from the pool
+    pub fn remove(&mut self, operation_hash: &OperationHash) -> Option<Operation> {
+        let entry = self.operations.remove(operation_hash)?;
+        self.bytes -= entry.size;
+        self.by_priority.remove(&entry.key);
+        self.pending.remove(&entry.key);
+        if let Some(peer_id) = &entry.peer_id {
+            let remove_peer = match self.peers.get_mut(peer_id) {
+                Some(usage) => {
+                    usage.operations.remove(&entry.key);
+                    usage.bytes -= entry.size;
+                    usage.operations.is_empty()
+                }
+                None => false,
+            };
+            if remove_peer {
+                self.peers.remove(peer_id);
+            }
+        }
+        Some(entry.operation)
+    }
+
+    #[inline]
+    pub fn contains(&self, operation_hash: &OperationHash) -> bool {
+        self.operations.contains_key(operation_hash)
+    }
+
+    #[inline]
+    pub fn get(&self, operation_hash: &OperationHash) -> Option<&Operation> {
+        self.operations.get(operation_hash).map(|entry| &entry.operation)
+    }
+
+    #[inline]
+    pub fn is_pending(&self, operation_hash: &OperationHash) -> bool {
+        match self.operations.get(operation_hash) {
+            Some(entry) => self.pending.contains(&entry.key),
+            None => false,
+        }
+    }
+
+    /// Remove operation from pending (operation stays in the pool), returns true if operation was pending
+    pub fn remove_from_pending(&mut self, operation_hash: &OperationHash) -> bool {
+        match self.operations.get(operation_hash) {
+            Some(entry) => self.pending.remove(&entry.key),
+            None => false,
+        }
+    }
+
+    /// Mark operation as pending again, returns true if operation is in the pool
+    pub fn add_to_pending(&mut self, operation_hash: &OperationHash) -> bool {
+        match self.operations.get(operation_hash) {
+            Some(entry) => {
+                self.pending.insert(entry.key.clone());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Pending operation hashes ordered from the most valuable one
+    pub fn pending_by_priority(&self) -> Vec<OperationHash> {
+        self.pending.iter().rev().map(|key| key.hash.clone()).collect()
+    }
+
+    #[inline]
+    pub fn pending_len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn pending(&self) -> HashSet<OperationHash> {
+        self.pending.iter().map(|key| key.hash.clone()).collect()
+    }
+
+    /// Hashes of all operations, which are not pending
+    pub fn not_pending(&self) -> Vec<OperationHash> {
+        self.operations.iter()
+            .filter(|(_, entry)| !self.pending.contains(&entry.key))
+            .map(|(hash, _)| hash.clone())
+            .collect()
+    }
+
+    pub fn operations(&self) -> HashMap<OperationHash, Operation> {
+        self.operations.iter()
+            .map(|(hash, entry)| (hash.clone(), entry.operation.clone()))
+            .collect()
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.operations.len()
+    }
+
+    #[inline]
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tezos_messages::p2p::binary_message::BinaryMessage;
+
+    use super::*;
+
+    fn encode_natural(mut value: u64, bytes: &mut Vec<u8>) {
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                bytes.push(byte);
+                return;
+            }
+            bytes.push(byte | 0x80);
+        }
+    }
+
+    fn operation(data: Vec<u8>) -> Result<Operation, failure::Error> {
+        let mut bytes = vec![0; 32];
+        bytes.extend(data);
+        bytes.extend(vec![0; 64]);
+        Ok(Operation::from_bytes(bytes)?)
+    }
+
+    fn endorsement(level: i32) -> Result<Operation, failure::Error> {
+        let mut data = vec![ENDORSEMENT_TAG];
+        data.extend(&level.to_be_bytes());
+        operation(data)
+    }
+
+    fn transaction(fee: u64, gas_limit: u64) -> Result<Operation, failure::Error> {
+        let mut data = vec![108];
+        data.extend(vec![0; SOURCE_SIZE]);
+        encode_natural(fee, &mut data);
+        encode_natural(1, &mut data);
+        encode_natural(gas_limit, &mut data);
+        encode_natural(257, &mut data);
+        operation(data)
+    }
+
+    fn limits(max_operations: usize, max_operations_per_peer: usize) -> MempoolLimits {
+        MempoolLimits {
+            max_operations,
+            max_bytes: 1_000_000,
+            max_operations_per_peer,
+            max_bytes_per_peer: 1_000_000,
+        }
+    }
+
+    fn hash(id: u8) -> OperationHash {
+        vec![id; 32]
+    }
+
+    #[test]
+    fn test_operation_priority() -> Result<(), failure::Error> {
+        let endorsement = OperationPriority::resolve(&endorsement(10)?);
+        let high_fee = OperationPriority::resolve(&transaction(10_000, 10_000)?);
+        let low_fee = OperationPriority::resolve(&transaction(1_500, 10_000)?);
+        let low_gas = OperationPriority::resolve(&transaction(1_500, 1_000)?);
+        let ballot = OperationPriority::resolve(&operation(vec![6, 1, 2, 3])?);
+        let unknown = OperationPriority::resolve(&operation(vec![42, 1, 2, 3])?);
+        let overflowing_fee = {
+            let mut data = vec![108];
+            data.extend(vec![0; SOURCE_SIZE]);
+            data.extend(vec![0xff; 10]);
+            OperationPriority::resolve(&operation(data)?)
+        };
+
+        assert!(matches!(endorsement, OperationPriority::Endorsement));
+        assert!(matches!(high_fee, OperationPriority::Manager { fee: 10_000, gas_limit: 10_000, .. }));
+        assert!(matches!(ballot, OperationPriority::Other));
+        assert!(matches!(unknown, OperationPriority::Unknown));
+        assert!(matches!(overflowing_fee, OperationPriority::Unknown));
+
+        assert!(endorsement > ballot);
+        assert!(ballot > high_fee);
+        assert!(high_fee > low_fee);
+        assert!(low_gas > low_fee);
+        assert!(low_fee > unknown);
+        assert!(low_fee > overflowing_fee);
+        Ok(())
+    }
+
+    #[test]
+    fn test_pending_by_priority() -> Result<(), failure::Error> {
+        let mut pool = OperationPool::new(limits(10, 10));
+        pool.insert(hash(1), transaction(1_500, 10_000)?, None);
+        pool.insert(hash(2), endorsement(10)?, None);
+        pool.insert(hash(3), transaction(10_000, 10_000)?, None);
+        assert_eq!(PoolInsert::AlreadyPresent, pool.insert(hash(3), transaction(10_000, 10_000)?, None));
+
+        assert_eq!(vec![hash(2), hash(3), hash(1)], pool.pending_by_priority());
+
+        assert!(pool.remove_from_pending(&hash(2)));
+        assert_eq!(vec![hash(3), hash(1)], pool.pending_by_priority());
+        assert_eq!(vec![hash(2)], pool.not_pending());
+        assert_eq!(3, pool.len());
+
+        assert!(pool.add_to_pending(&hash(2)));
+        assert!(pool.is_pending(&hash(2)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_evict_lowest_priority() -> Result<(), failure::Error> {
+        let mut pool = OperationPool::new(limits(2, 10));
+        pool.insert(hash(1), transaction(5_000, 10_000)?, None);
+        pool.insert(hash(2), transaction(1_500, 10_000)?, None);
+
+        // less valuable operation is rejected
+        assert_eq!(PoolInsert::Rejected, pool.insert(hash(3), transaction(1_000, 10_000)?, None));
+        assert!(!pool.contains(&hash(3)));
+
+        // more valuable operation evicts the lowest one
+        assert_eq!(PoolInsert::Inserted { evicted: vec![hash(2)] }, pool.insert(hash(4), endorsement(10)?, None));
+        assert_eq!(2, pool.len());
+        assert!(pool.contains(&hash(1)));
+        assert!(pool.contains(&hash(4)));
+        assert!(!pool.contains(&hash(2)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_evict_by_bytes() -> Result<(), failure::Error> {
+        let operation_size = {
+            let operation = transaction(1_500, 10_000)?;
+            operation.branch().len() + operation.data().len()
+        };
+        let mut pool = OperationPool::new(MempoolLimits {
+            max_operations: 10,
+            max_bytes: 2 * operation_size,
+            max_operations_per_peer: 10,
+            max_bytes_per_peer: 2 * operation_size,
+        });
+        pool.insert(hash(1), transaction(1_500, 10_000)?, None);
+        pool.insert(hash(2), transaction(2_500, 10_000)?, None);
+        assert_eq!(2 * operation_size, pool.bytes());
+
+        assert_eq!(PoolInsert::Inserted { evicted: vec![hash(1)] }, pool.insert(hash(3), transaction(3_500, 10_000)?, None));
+        assert_eq!(2 * operation_size, pool.bytes());
+        Ok(())
+    }
+
+    #[test]
+    fn test_per_peer_limit() -> Result<(), failure::Error> {
+        let peer = Some("idtgUrRAAvuH3cbZrXyfaKZovoFUqt".to_string());
+        let other_peer = Some("idrr4xmYRZRzyRr2ehVBU5HXPp64tn".to_string());
+        let mut pool = OperationPool::new(limits(10, 2));
+
+        pool.insert(hash(1), transaction(5_000, 10_000)?, peer.clone());
+        pool.insert(hash(2), transaction(6_000, 10_000)?, peer.clone());
+        pool.insert(hash(3), transaction(1_000, 10_000)?, other_peer.clone());
+
+        // peer can replace just its own operations
+        assert_eq!(PoolInsert::Rejected, pool.insert(hash(4), transaction(2_000, 10_000)?, peer.clone()));
+        assert_eq!(PoolInsert::Inserted { evicted: vec![hash(1)] }, pool.insert(hash(5), transaction(7_000, 10_000)?, peer.clone()));
+
+        // other peer and local operations are not affected
+        assert_eq!(PoolInsert::Inserted { evicted: vec![] }, pool.insert(hash(6), transaction(1_000, 10_000)?, other_peer.clone()));
+        assert_eq!(PoolInsert::Inserted { evicted: vec![] }, pool.insert(hash(7), transaction(1_000, 10_000)?, None));
+
+        // removing operation releases peer quota
+        pool.remove(&hash(2));
+        assert_eq!(PoolInsert::Inserted { evicted: vec![] }, pool.insert(hash(4), transaction(2_000, 10_000)?, peer));
+        assert_eq!(5, pool.len());
+        Ok(())
+    }
+}
@@ -2,4 +2,6 @@
 // SPDX-License-Identifier: MIT
 
 pub mod block_state;
+pub mod download_scheduler;
+pub mod mempool_pool;
 pub mod operations_state;
@@ -179,6 +179,15 @@ impl From<&MissingOperations> for Vec<OperationsForBlock> {
     }
 }
 
+impl From<&MissingOperations> for Vec<OperationHashesForBlock> {
+    fn from(ops: &MissingOperations) -> Self {
+        ops.validation_passes
+            .iter()
+            .map(|vp| OperationHashesForBlock::new(ops.block_hash.clone(), *vp))
+            .collect()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
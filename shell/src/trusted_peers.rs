@@ -0,0 +1,95 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Trusted peers are configured by the node operator as points (see `--peers`) or peer ids (see `--trusted-peer-ids`).
+//!
+//! Trusted peers are exempt from scoring and bans and they are always reconnected.
+//! Private node connects to and accepts connections only from trusted peers.
+
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+
+use networking::p2p::peer::PeerId;
+use storage::GreylistKey;
+use tezos_messages::p2p::point::{canonical_ip, canonical_point};
+
+pub(crate) struct TrustedPeers {
+    points: HashSet<SocketAddr>,
+    peer_ids: HashSet<PeerId>,
+}
+
+impl TrustedPeers {
+    pub(crate) fn new(points: &[SocketAddr], peer_ids: &[PeerId]) -> Self {
+        TrustedPeers {
+            points: points.iter().copied().map(canonical_point).collect(),
+            peer_ids: peer_ids.iter().cloned().collect(),
+        }
+    }
+
+    pub(crate) fn points(&self) -> &HashSet<SocketAddr> {
+        &self.points
+    }
+
+    pub(crate) fn peer_ids(&self) -> &HashSet<PeerId> {
+        &self.peer_ids
+    }
+
+    /// Trusted point is configured explicitly
+    pub(crate) fn is_trusted_point(&self, point: &SocketAddr) -> bool {
+        self.points.contains(&canonical_point(*point))
+    }
+
+    /// Host of a trusted point, its address cannot be banned
+    pub(crate) fn is_trusted_ip(&self, ip: &IpAddr) -> bool {
+        let ip = canonical_ip(*ip);
+        self.points.iter().any(|point| point.ip() == ip)
+    }
+
+    pub(crate) fn is_trusted_peer_id(&self, peer_id: &str) -> bool {
+        self.peer_ids.contains(peer_id)
+    }
+
+    /// Connected peer is trusted, if it is connected to (or listens on) a trusted point, or if its peer id is trusted
+    pub(crate) fn is_trusted(&self, address: &SocketAddr, listener_address: Option<&SocketAddr>, peer_id: Option<&str>) -> bool {
+        self.is_trusted_point(address)
+            || listener_address.map(|address| self.is_trusted_point(address)).unwrap_or(false)
+            || peer_id.map(|peer_id| self.is_trusted_peer_id(peer_id)).unwrap_or(false)
+    }
+
+    /// Greylist key matches a trusted peer, such key cannot be banned
+    pub(crate) fn is_trusted_key(&self, key: &GreylistKey) -> bool {
+        match key {
+            GreylistKey::Ip(ip) => self.is_trusted_ip(ip),
+            GreylistKey::PeerId(peer_id) => self.is_trusted_peer_id(peer_id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trusted_peers() {
+        let trusted = TrustedPeers::new(
+            &["10.0.0.1:9732".parse().unwrap(), "[::1]:9732".parse().unwrap()],
+            &["idtgUrRAAvuH3cbZrXyfaKZovoFUqt".to_string()],
+        );
+
+        assert!(trusted.is_trusted_point(&"10.0.0.1:9732".parse().unwrap()));
+        assert!(trusted.is_trusted_point(&"[::ffff:10.0.0.1]:9732".parse().unwrap()));
+        assert!(!trusted.is_trusted_point(&"10.0.0.1:9733".parse().unwrap()));
+        assert!(trusted.is_trusted_ip(&"::1".parse().unwrap()));
+        assert!(!trusted.is_trusted_ip(&"10.0.0.2".parse().unwrap()));
+
+        // incoming connection from trusted host, which listens on the trusted point
+        assert!(trusted.is_trusted(&"10.0.0.1:50123".parse().unwrap(), Some(&"10.0.0.1:9732".parse().unwrap()), None));
+        assert!(!trusted.is_trusted(&"10.0.0.1:50123".parse().unwrap(), Some(&"10.0.0.1:9733".parse().unwrap()), None));
+        // trusted peer id on any point
+        assert!(trusted.is_trusted(&"10.0.0.5:9732".parse().unwrap(), None, Some("idtgUrRAAvuH3cbZrXyfaKZovoFUqt")));
+
+        assert!(trusted.is_trusted_key(&GreylistKey::Ip("10.0.0.1".parse().unwrap())));
+        assert!(trusted.is_trusted_key(&GreylistKey::PeerId("idtgUrRAAvuH3cbZrXyfaKZovoFUqt".to_string())));
+        assert!(!trusted.is_trusted_key(&GreylistKey::PeerId("idrr4xmYRZRzyRr2ehVBU5HXPp64tn".to_string())));
+    }
+}
@@ -333,6 +333,7 @@ fn add_operations_to_mempool(request: &String, shell_channel: ShellChannelRef, m
                     msg: MempoolOperationReceived {
                         operation_hash: operation_hash.clone(),
                         operation_type: MempoolOperationType::Pending,
+                        peer_id: None,
                     }.into(),
                     topic: ShellChannelTopic::ShellEvents.into(),
                 },
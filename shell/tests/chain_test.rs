@@ -3,11 +3,14 @@
 #![feature(test)]
 extern crate test;
 
+use std::net::{IpAddr, Ipv4Addr};
 use std::time::{Duration, Instant};
 
 use lazy_static::lazy_static;
 
-use shell::peer_manager::P2p;
+use networking::p2p::peer::KeepaliveConfig;
+use networking::p2p::rate_limit::RateLimits;
+use shell::peer_manager::{P2p, PeerDiversityLimits};
 use shell::PeerConnectionThreshold;
 use storage::tests_common::TmpStorage;
 use tezos_identity::Identity;
@@ -23,12 +26,19 @@ lazy_static! {
         tezos_identity::Identity::generate(0f64),
         P2p {
             listener_port: NODE_P2P_PORT.clone(),
+            bind_address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
             bootstrap_lookup_addresses: vec![],
             disable_bootstrap_lookup: true,
             disable_mempool: false,
             private_node: false,
+            expected_pow: 0f64,
             initial_peers: vec![],
+            trusted_peer_ids: vec![],
             peer_threshold: PeerConnectionThreshold::new(0, 10),
+            peer_diversity: PeerDiversityLimits::unlimited(),
+            rate_limits: RateLimits::unlimited(),
+            capture: None,
+            keepalive: KeepaliveConfig::default(),
         },
         NETWORK_VERSION.clone(),
     );
@@ -59,7 +69,7 @@ fn test_process_current_branch_on_level3_with_empty_storage() -> Result<(), fail
 
     // connect mocked node peer with test data set
     let clocks = Instant::now();
-    let mocked_peer_node = test_node_peer::TestNodePeer::connect(
+    let mocked_peer_node = common::test_node_peer::TestNodePeer::connect(
         "TEST_PEER_NODE",
         NODE_P2P_CFG.1.listener_port,
         NODE_P2P_CFG.2.clone(),
@@ -115,7 +125,7 @@ fn test_process_reorg_with_different_current_branches_with_empty_storage() -> Re
     // connect mocked node peer with data for branch_1
     let (db_branch_1, ..) = test_cases_data::sandbox_branch_1_level3::init_data(&node.log);
     let clocks = Instant::now();
-    let mocked_peer_node_branch_1 = test_node_peer::TestNodePeer::connect(
+    let mocked_peer_node_branch_1 = common::test_node_peer::TestNodePeer::connect(
         "TEST_PEER_NODE_BRANCH_1",
         NODE_P2P_CFG.1.listener_port,
         NODE_P2P_CFG.2.clone(),
@@ -134,7 +144,7 @@ fn test_process_reorg_with_different_current_branches_with_empty_storage() -> Re
     // connect mocked node peer with data for branch_2
     let clocks = Instant::now();
     let (db_branch_2, ..) = test_cases_data::sandbox_branch_2_level4::init_data(&node.log);
-    let mocked_peer_node_branch_2 = test_node_peer::TestNodePeer::connect(
+    let mocked_peer_node_branch_2 = common::test_node_peer::TestNodePeer::connect(
         "TEST_PEER_NODE_BRANCH_2",
         NODE_P2P_CFG.1.listener_port,
         NODE_P2P_CFG.2.clone(),
@@ -445,185 +455,3 @@ mod test_cases_data {
         }
     }
 }
-
-/// Test node peer, which simulates p2p remote peer, communicates through real p2p socket
-mod test_node_peer {
-    use std::net::{Shutdown, SocketAddr};
-    use std::sync::Arc;
-    use std::sync::atomic::{AtomicBool, Ordering};
-    use std::time::Duration;
-
-    use slog::{crit, debug, error, info, Logger, warn};
-    use tokio::net::TcpStream;
-    use tokio::runtime::Runtime;
-    use tokio::time::timeout;
-
-    use networking::p2p::peer;
-    use networking::p2p::peer::{Bootstrap, BootstrapOutput, Local};
-    use tezos_identity::Identity;
-    use tezos_messages::p2p::encoding::prelude::{PeerMessage, PeerMessageResponse};
-    use tezos_messages::p2p::encoding::version::NetworkVersion;
-
-    const CONNECT_TIMEOUT: Duration = Duration::from_secs(8);
-    const READ_TIMEOUT_LONG: Duration = Duration::from_secs(30);
-
-    pub struct TestNodePeer {
-        run: Arc<AtomicBool>,
-    }
-
-    impl TestNodePeer {
-        pub fn connect(
-            name: &'static str,
-            connect_to_node_port: u16,
-            network_version: NetworkVersion,
-            identity: Identity,
-            log: Logger,
-            tokio_runtime: &Runtime,
-            handle_message_callback: fn(PeerMessageResponse) -> Result<Vec<PeerMessageResponse>, failure::Error>) -> TestNodePeer {
-            let server_address = format!("0.0.0.0:{}", connect_to_node_port).parse::<SocketAddr>().expect("Failed to parse server address");
-            let tokio_executor = tokio_runtime.handle().clone();
-            let run = Arc::new(AtomicBool::new(false));
-
-            {
-                let run = run.clone();
-                tokio_executor.spawn(async move {
-                    // init socket connection to server node
-                    match timeout(CONNECT_TIMEOUT, TcpStream::connect(&server_address)).await {
-                        Ok(Ok(stream)) => {
-                            info!(log, "[{}] Connection successful", name; "ip" => server_address);
-
-                            // authenticate
-                            let local = Arc::new(Local::new(
-                                1235,
-                                identity.public_key,
-                                identity.secret_key,
-                                identity.proof_of_work_stamp,
-                                network_version,
-                            ));
-                            let bootstrap = Bootstrap::outgoing(
-                                stream,
-                                server_address,
-                                false,
-                                false,
-                            );
-
-                            let bootstrap_result = peer::bootstrap(
-                                bootstrap,
-                                local,
-                                &log,
-                            ).await.expect(&format!("[{}] Failed to bootstrap", name));
-
-                            // process messages
-                            run.store(true, Ordering::Release);
-                            Self::begin_process_incoming(name, bootstrap_result, run, log, server_address, handle_message_callback).await;
-                        }
-                        Ok(Err(e)) => {
-                            error!(log, "[{}] Connection failed", name; "ip" => server_address, "reason" => format!("{:?}", e));
-                        }
-                        Err(_) => {
-                            error!(log, "[{}] Connection timed out", name; "ip" => server_address);
-                        }
-                    }
-                });
-            }
-
-            TestNodePeer {
-                run,
-            }
-        }
-
-        /// Start to process incoming data
-        async fn begin_process_incoming(
-            name: &str,
-            bootstrap: BootstrapOutput,
-            run: Arc<AtomicBool>,
-            log: Logger,
-            peer_address: SocketAddr,
-            handle_message_callback: fn(PeerMessageResponse) -> Result<Vec<PeerMessageResponse>, failure::Error>) {
-            info!(log, "[{}] Starting to accept messages", name; "ip" => format!("{:?}", &peer_address));
-            let BootstrapOutput(mut rx, mut tx, ..) = bootstrap;
-
-            while run.load(Ordering::Acquire) {
-                match timeout(READ_TIMEOUT_LONG, rx.read_message::<PeerMessageResponse>()).await {
-                    Ok(res) => match res {
-                        Ok(msg) => {
-                            let msg_type = msg_type(&msg);
-                            info!(log, "[{}] Handle message", name; "ip" => format!("{:?}", &peer_address), "msg_type" => msg_type.clone());
-
-                            // apply callback
-                            match handle_message_callback(msg) {
-                                Ok(responses) => {
-                                    info!(log, "[{}] Message handled({})", name, !responses.is_empty(); "msg_type" => msg_type);
-                                    for response in responses {
-                                        // send back response
-                                        tx.write_message(&response).await.expect(&format!("[{}] Failed to send message", name));
-                                    };
-                                }
-                                Err(e) => error!(log, "[{}] Failed to handle message", name; "reason" => format!("{:?}", e), "msg_type" => msg_type)
-                            }
-                        }
-                        Err(e) => {
-                            crit!(log, "[{}] Failed to read peer message", name; "reason" => e);
-                            break;
-                        }
-                    }
-                    Err(_) => {
-                        warn!(log, "[{}] Peer message read timed out", name; "secs" => READ_TIMEOUT_LONG.as_secs());
-                        break;
-                    }
-                }
-            }
-
-            debug!(log, "[{}] Shutting down peer connection", name; "ip" => format!("{:?}", &peer_address));
-            // let mut tx_lock = tx.lock().await;
-            // if let Some(tx) = tx_lock.take() {
-            let socket = rx.unsplit(tx);
-            match socket.shutdown(Shutdown::Both) {
-                Ok(()) => debug!(log, "[{}] Connection shutdown successful", name; "socket" => format!("{:?}", socket)),
-                Err(err) => debug!(log, "[{}] Failed to shutdown connection", name; "err" => format!("{:?}", err), "socket" => format!("{:?}", socket)),
-            }
-            // }
-
-            info!(log, "[{}] Stopped to accept messages", name; "ip" => format!("{:?}", &peer_address));
-        }
-
-        pub fn stop(&mut self) {
-            self.run.store(false, Ordering::Release);
-        }
-    }
-
-    impl Drop for TestNodePeer {
-        fn drop(&mut self) {
-            self.stop();
-        }
-    }
-
-    fn msg_type(msg: &PeerMessageResponse) -> String {
-        msg.messages()
-            .iter()
-            .map(|m| match m {
-                PeerMessage::Disconnect => "Disconnect",
-                PeerMessage::Advertise(_) => "Advertise",
-                PeerMessage::SwapRequest(_) => "SwapRequest",
-                PeerMessage::SwapAck(_) => "SwapAck",
-                PeerMessage::Bootstrap => "Bootstrap",
-                PeerMessage::GetCurrentBranch(_) => "GetCurrentBranch",
-                PeerMessage::CurrentBranch(_) => "CurrentBranch",
-                PeerMessage::Deactivate(_) => "Deactivate",
-                PeerMessage::GetCurrentHead(_) => "GetCurrentHead",
-                PeerMessage::CurrentHead(_) => "CurrentHead",
-                PeerMessage::GetBlockHeaders(_) => "GetBlockHeaders",
-                PeerMessage::BlockHeader(_) => "BlockHeader",
-                PeerMessage::GetOperations(_) => "GetOperations",
-                PeerMessage::Operation(_) => "Operation",
-                PeerMessage::GetProtocols(_) => "GetProtocols",
-                PeerMessage::Protocol(_) => "Protocol",
-                PeerMessage::GetOperationHashesForBlocks(_) => "GetOperationHashesForBlocks",
-                PeerMessage::OperationHashesForBlock(_) => "OperationHashesForBlock",
-                PeerMessage::GetOperationsForBlocks(_) => "GetOperationsForBlocks",
-                PeerMessage::OperationsForBlocks(_) => "OperationsForBlocks",
-            })
-            .collect::<Vec<&str>>()
-            .join(",")
-    }
-}
@@ -0,0 +1,154 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Waiting for the shell actors to process published events, see [networking::barrier].
+//!
+//! Channels and actors process messages in the order, in which they received them, so:
+//! - channel forwarded all events published before, once it forwards the marker published to the private [BARRIER_TOPIC]
+//! - actor processed all messages received before, once it answers [Synchronize]
+//! - stopped actor cannot answer, [Synchronize] sent to it ends in the dead letters, which counts as the answer
+
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender as QueueSender};
+use std::time::Duration;
+
+use riker::actors::*;
+
+use networking::barrier::{Synchronize, Synchronized};
+use networking::p2p::network_channel::{NetworkChannelMsg, NetworkChannelRef, PeerBootstrapped};
+use shell::shell_channel::{ShellChannelMsg, ShellChannelRef, ShuttingDown};
+
+/// Topic without any shell actor subscribed
+const BARRIER_TOPIC: &str = "barrier";
+/// Actor which does not answer in this time is considered to be stuck
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(30);
+
+type Answers = Arc<Mutex<QueueSender<()>>>;
+
+/// Receives markers from the channel or answers from the actors, every message of type `M` is an answer.
+///
+/// Answers to the [Synchronize] are sent with `try_tell`, which delivers only message of exactly the listener's type,
+/// so there is a listener for every type.
+struct BarrierListener<M> {
+    answers: Answers,
+    _message: PhantomData<M>,
+}
+
+impl<M: Message> ActorFactoryArgs<Answers> for BarrierListener<M> {
+    fn create_args(answers: Answers) -> Self {
+        BarrierListener { answers, _message: PhantomData }
+    }
+}
+
+impl<M: Message> Actor for BarrierListener<M> {
+    type Msg = M;
+
+    fn recv(&mut self, _: &Context<Self::Msg>, _: Self::Msg, _: Sender) {
+        let _ = self.answers.lock().unwrap().send(());
+    }
+}
+
+/// Answers instead of the stopped actor, when [Synchronize] sent by the barrier ends in the dead letters
+struct DeadLetterListener {
+    answers: Answers,
+    synchronized_listener: ActorUri,
+}
+
+impl ActorFactoryArgs<(Answers, ActorUri)> for DeadLetterListener {
+    fn create_args((answers, synchronized_listener): (Answers, ActorUri)) -> Self {
+        DeadLetterListener { answers, synchronized_listener }
+    }
+}
+
+impl Actor for DeadLetterListener {
+    type Msg = DeadLetter;
+
+    fn recv(&mut self, _: &Context<Self::Msg>, msg: Self::Msg, _: Sender) {
+        if msg.sender.as_ref().map_or(false, |sender| sender.uri() == &self.synchronized_listener) {
+            let _ = self.answers.lock().unwrap().send(());
+        }
+    }
+}
+
+/// Waits for the network and shell channels and for the actors
+pub struct Barrier {
+    synchronized_listener: ActorRef<Synchronized>,
+    answers: Receiver<()>,
+    network_channel: NetworkChannelRef,
+    shell_channel: ShellChannelRef,
+}
+
+impl Barrier {
+    pub fn new(sys: &ActorSystem, network_channel: NetworkChannelRef, shell_channel: ShellChannelRef) -> Result<Self, failure::Error> {
+        let (answers_tx, answers) = channel();
+        let answers_tx = Arc::new(Mutex::new(answers_tx));
+        let synchronized_listener = listener::<Synchronized>(sys, "barrier-synchronized-listener", &answers_tx)?;
+        let network_listener = listener::<NetworkChannelMsg>(sys, "barrier-network-listener", &answers_tx)?;
+        let shell_listener = listener::<ShellChannelMsg>(sys, "barrier-shell-listener", &answers_tx)?;
+        let dead_letter_listener = sys.actor_of_props::<DeadLetterListener>(
+            "barrier-dead-letter-listener",
+            Props::new_args((answers_tx.clone(), synchronized_listener.uri().clone())),
+        ).map_err(|e| failure::format_err!("Failed to create barrier-dead-letter-listener, reason: {:?}", e))?;
+
+        sys.dead_letters().tell(
+            Subscribe {
+                actor: Box::new(dead_letter_listener),
+                topic: All.into(),
+            }, None);
+
+        network_channel.tell(
+            Subscribe {
+                actor: Box::new(network_listener),
+                topic: BARRIER_TOPIC.into(),
+            }, None);
+        shell_channel.tell(
+            Subscribe {
+                actor: Box::new(shell_listener),
+                topic: BARRIER_TOPIC.into(),
+            }, None);
+
+        Ok(Barrier { synchronized_listener, answers, network_channel, shell_channel })
+    }
+
+    /// Wait until both channels forward all events published before
+    pub fn flush_channels(&self) {
+        self.network_channel.tell(
+            Publish {
+                msg: PeerBootstrapped::Failure {
+                    address: "0.0.0.0:0".parse().unwrap(),
+                    potential_peers_to_connect: None,
+                    misbehavior: None,
+                }.into(),
+                topic: BARRIER_TOPIC.into(),
+            }, None);
+        self.wait("network channel");
+
+        self.shell_channel.tell(
+            Publish {
+                msg: ShuttingDown.into(),
+                topic: BARRIER_TOPIC.into(),
+            }, None);
+        self.wait("shell channel");
+    }
+
+    /// Wait until `actor` processes all messages it received before
+    pub fn synchronize<M>(&self, actor: &ActorRef<M>)
+        where
+            M: Message,
+            Synchronize: Into<M>
+    {
+        actor.tell(Synchronize, Some(self.synchronized_listener.clone().into()));
+        self.wait(actor.name());
+    }
+
+    fn wait(&self, name: &str) {
+        self.answers.recv_timeout(ANSWER_TIMEOUT)
+            .unwrap_or_else(|_| panic!("No answer from {} in {:?}", name, ANSWER_TIMEOUT));
+    }
+}
+
+fn listener<M: Message>(sys: &ActorSystem, name: &str, answers: &Answers) -> Result<ActorRef<M>, failure::Error> {
+    sys.actor_of_props::<BarrierListener<M>>(name, Props::new_args(answers.clone()))
+        .map_err(|e| failure::format_err!("Failed to create {}, reason: {:?}", name, e))
+}
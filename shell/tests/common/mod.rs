@@ -8,6 +8,15 @@ use std::path::{Path, PathBuf};
 use serde::{Deserialize, Serialize};
 use slog::{Drain, Level, Logger};
 
+#[allow(dead_code)]
+pub mod barrier;
+#[allow(dead_code)]
+pub mod replay;
+#[allow(dead_code)]
+pub mod simulation;
+#[allow(dead_code)]
+pub mod test_node_peer;
+
 pub fn prepare_empty_dir(dir_name: &str) -> String {
     let path = test_storage_dir_path(dir_name);
     if path.exists() {
@@ -67,7 +76,7 @@ pub struct NoopMessage;
 #[allow(dead_code)]
 pub mod infra {
     use std::path::PathBuf;
-    use std::sync::Arc;
+    use std::sync::{Arc, RwLock};
     use std::sync::atomic::{AtomicBool, Ordering};
     use std::thread;
     use std::time::{Duration, SystemTime};
@@ -79,13 +88,16 @@ pub mod infra {
 
     use crypto::hash::{BlockHash, ContextHash, HashType};
     use networking::p2p::network_channel::{NetworkChannel, NetworkChannelRef};
+    use networking::p2p::stats::NetworkStats;
     use shell::chain_feeder::ChainFeeder;
     use shell::chain_manager::ChainManager;
+    use shell::chain_registry::ChainRegistry;
     use shell::context_listener::ContextListener;
-    use shell::mempool_prevalidator::MempoolPrevalidator;
+    use shell::mempool_prevalidator::{MempoolLimits, MempoolPrevalidator};
     use shell::peer_manager::{P2p, PeerManager};
     use shell::PeerConnectionThreshold;
     use shell::shell_channel::{ShellChannel, ShellChannelRef, ShellChannelTopic, ShuttingDown};
+    use shell::shutdown::WorkerStatus;
     use storage::{BlockStorage, ChainMetaStorage, resolve_storage_init_chain_data};
     use storage::chain_meta_storage::ChainMetaStorageReader;
     use storage::context::{ContextApi, TezedgeContext};
@@ -159,7 +171,7 @@ pub mod infra {
                 log.clone(),
             );
             let (apply_restarting_feature, apply_protocol_commands, apply_protocol_events) = match apply_protocol_runner_endpoint.start_in_restarting_mode() {
-                Ok(restarting_feature) => {
+                Ok((restarting_feature, _)) => {
                     let ProtocolRunnerEndpoint {
                         commands,
                         events,
@@ -205,14 +217,15 @@ pub mod infra {
             let shell_channel = ShellChannel::actor(&actor_system).expect("Failed to create shell channel");
             let network_channel = NetworkChannel::actor(&actor_system).expect("Failed to create network channel");
             let _ = ContextListener::actor(&actor_system, &persistent_storage, apply_protocol_events.expect("Context listener needs event server"), log.clone(), false).expect("Failed to create context event listener");
-            let _ = ChainFeeder::actor(&actor_system, shell_channel.clone(), &persistent_storage, &init_storage_data, &tezos_env, apply_protocol_commands, log.clone()).expect("Failed to create chain feeder");
-            let _ = ChainManager::actor(&actor_system, network_channel.clone(), shell_channel.clone(), &persistent_storage, tezos_readonly_api.clone(), &init_storage_data.chain_id, is_sandbox, &p2p_threshold).expect("Failed to create chain manager");
+            let _ = ChainFeeder::actor(&actor_system, shell_channel.clone(), &persistent_storage, &init_storage_data, &tezos_env, apply_protocol_commands, WorkerStatus::new(), log.clone()).expect("Failed to create chain feeder");
+            let _ = ChainManager::actor(&actor_system, network_channel.clone(), shell_channel.clone(), &persistent_storage, tezos_readonly_api.clone(), Arc::new(RwLock::new(ChainRegistry::new(init_storage_data.chain_id.clone()))), &init_storage_data.chain_id, is_sandbox, &p2p_threshold, Duration::from_secs(120)).expect("Failed to create chain manager");
             let _ = MempoolPrevalidator::actor(
                 &actor_system,
                 shell_channel.clone(),
                 &persistent_storage,
                 &init_storage_data,
                 tezos_readonly_api.clone(),
+                MempoolLimits::default(),
                 log.clone(),
             ).expect("Failed to create chain feeder");
 
@@ -222,10 +235,12 @@ pub mod infra {
                     &actor_system,
                     network_channel.clone(),
                     shell_channel.clone(),
+                    &persistent_storage,
                     tokio_runtime.handle().clone(),
                     identity,
                     network_version,
                     p2p_config,
+                    NetworkStats::default(),
                 ).expect("Failed to create peer manager");
             }
 
@@ -0,0 +1,391 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Replay harness for the traffic recorded by [shell::recorder::Recorder].
+//!
+//! Recorded events are published to the fresh `ChainManager` and `PeerManager`:
+//! - recorded peers are replaced by [MockPeer] actors, which just collect messages sent by the node
+//! - protocol runner pool is mocked, no protocol runner is started and every call to the protocol fails
+//! - events which are produced by the `ChainManager` itself (new current head, block received, ...) are not published,
+//!   they are collected from the shell channel instead, so they can be compared with the recorded ones
+//!
+//! Events are replayed in the recorded order (recorded timing is ignored, actors use [VirtualClock]) and every event is completely processed
+//! by the node before the next one is published (see [Barrier]), so the same recording produces the same run every time.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use riker::actors::*;
+use riker::system::SystemBuilder;
+use slog::{Logger, warn};
+use tokio::runtime::Runtime;
+
+use networking::barrier::{answer_synchronized, Synchronize};
+use networking::clock::{ClockRef, VirtualClock};
+use networking::p2p::network_channel::{NetworkChannel, NetworkChannelMsg, NetworkChannelRef, NetworkChannelTopic, PeerBootstrapped, PeerCreated, PeerMessageReceived, PeerMisbehaved};
+use networking::p2p::peer::{KeepaliveConfig, PeerMsg, PeerRef};
+use networking::p2p::rate_limit::{PeerRate, RateLimits};
+use networking::p2p::stats::{NetworkStats, PeerStats};
+use shell::chain_manager::{ChainManager, ChainManagerRef};
+use shell::chain_registry::ChainRegistry;
+use shell::peer_manager::{P2p, PeerDiversityLimits, PeerManager, PeerManagerRef};
+use shell::PeerConnectionThreshold;
+use shell::recorder::{Record, RecordedEvent, Recorder, RecorderRef, RecordWriter};
+use shell::shell_channel::{ShellChannel, ShellChannelMsg, ShellChannelRef, ShellChannelTopic, ShuttingDown};
+use storage::tests_common::TmpStorage;
+use tezos_api::environment::{TEZOS_ENV, TezosEnvironment};
+use tezos_api::ffi::TezosRuntimeConfiguration;
+use tezos_identity::Identity;
+use tezos_messages::p2p::encoding::prelude::PeerMessage;
+use tezos_messages::p2p::encoding::version::NetworkVersion;
+use tezos_wrapper::{TezosApiConnectionPool, TezosApiConnectionPoolConfiguration};
+use tezos_wrapper::service::ProtocolEndpointConfiguration;
+
+use crate::common;
+use crate::common::barrier::Barrier;
+
+/// Messages sent by the node, grouped by the recorded peer name
+type SentMessages = Arc<Mutex<HashMap<String, Vec<PeerMessage>>>>;
+
+/// Mocked peer, which collects all messages sent by the node instead of sending them to the network
+struct MockPeer {
+    name: String,
+    sent: SentMessages,
+}
+
+impl ActorFactoryArgs<(String, SentMessages)> for MockPeer {
+    fn create_args((name, sent): (String, SentMessages)) -> Self {
+        MockPeer { name, sent }
+    }
+}
+
+impl Actor for MockPeer {
+    type Msg = PeerMsg;
+
+    fn recv(&mut self, _: &Context<Self::Msg>, msg: Self::Msg, sender: Sender) {
+        match msg {
+            PeerMsg::SendMessage(msg) => {
+                self.sent.lock().unwrap()
+                    .entry(self.name.clone())
+                    .or_insert_with(Vec::new)
+                    .extend(msg.message().messages().iter().cloned());
+            }
+            PeerMsg::Synchronize(_) => answer_synchronized(sender),
+            _ => (),
+        }
+    }
+}
+
+/// Collects shell events published by the node during replay
+#[actor(ShellChannelMsg, Synchronize)]
+struct ShellEventsCollector {
+    shell_channel: ShellChannelRef,
+    published: Arc<Mutex<Vec<RecordedEvent>>>,
+}
+
+impl ActorFactoryArgs<(ShellChannelRef, Arc<Mutex<Vec<RecordedEvent>>>)> for ShellEventsCollector {
+    fn create_args((shell_channel, published): (ShellChannelRef, Arc<Mutex<Vec<RecordedEvent>>>)) -> Self {
+        ShellEventsCollector { shell_channel, published }
+    }
+}
+
+impl Actor for ShellEventsCollector {
+    type Msg = ShellEventsCollectorMsg;
+
+    fn pre_start(&mut self, ctx: &Context<Self::Msg>) {
+        self.shell_channel.tell(
+            Subscribe {
+                actor: Box::new(ctx.myself()),
+                topic: ShellChannelTopic::ShellEvents.into(),
+            }, None);
+    }
+
+    fn recv(&mut self, ctx: &Context<Self::Msg>, msg: Self::Msg, sender: Sender) {
+        self.receive(ctx, msg, sender);
+    }
+}
+
+impl Receive<ShellChannelMsg> for ShellEventsCollector {
+    type Msg = ShellEventsCollectorMsg;
+
+    fn receive(&mut self, _: &Context<Self::Msg>, msg: ShellChannelMsg, _: Sender) {
+        if let Some(event) = RecordedEvent::from_shell_msg(&msg) {
+            self.published.lock().unwrap().push(event);
+        }
+    }
+}
+
+impl Receive<Synchronize> for ShellEventsCollector {
+    type Msg = ShellEventsCollectorMsg;
+
+    fn receive(&mut self, _: &Context<Self::Msg>, _: Synchronize, sender: Sender) {
+        answer_synchronized(sender);
+    }
+}
+
+/// Fresh chain manager and peer manager driven by the recorded events
+pub struct ReplayNode {
+    log: Logger,
+    actor_system: ActorSystem,
+    network_channel: NetworkChannelRef,
+    shell_channel: ShellChannelRef,
+    chain_manager: ChainManagerRef,
+    peer_manager: PeerManagerRef,
+    recorder: Option<RecorderRef>,
+    shell_events_collector: ActorRef<ShellEventsCollectorMsg>,
+    barrier: Barrier,
+    _tokio_runtime: Runtime,
+    _tmp_storage: TmpStorage,
+    /// Recorded peer name -> mocked peer
+    peers: HashMap<String, PeerRef>,
+    sent: SentMessages,
+    published: Arc<Mutex<Vec<RecordedEvent>>>,
+}
+
+impl ReplayNode {
+    /// Start node for replay, if `record_to` is set, replayed traffic is recorded again
+    pub fn start(name: &str, tezos_env: TezosEnvironment, network_version: NetworkVersion, listener_port: u16, record_to: Option<PathBuf>, log: Logger) -> Result<Self, failure::Error> {
+        let tmp_storage = TmpStorage::create(common::prepare_empty_dir(&format!("__{}", name)))?;
+        let tezos_env = TEZOS_ENV.get(&tezos_env).expect("no environment configuration");
+        let chain_id = tezos_env.main_chain_id()?;
+        let tokio_runtime = tokio::runtime::Builder::new()
+            .threaded_scheduler()
+            .enable_all()
+            .build()?;
+
+        // mocked protocol runner pool - there is no protocol runner, so every connection attempt fails fast
+        let tezos_readonly_api = Arc::new(
+            TezosApiConnectionPool::new_without_context(
+                format!("{}_mocked_pool", name),
+                TezosApiConnectionPoolConfiguration {
+                    min_connections: 0,
+                    max_connections: 1,
+                    connection_timeout: Duration::from_millis(100),
+                    max_lifetime: Duration::from_secs(60),
+                    idle_timeout: Duration::from_secs(60),
+                },
+                ProtocolEndpointConfiguration::new(
+                    TezosRuntimeConfiguration {
+                        log_enabled: false,
+                        no_of_ffi_calls_treshold_for_gc: common::no_of_ffi_calls_treshold_for_gc(),
+                        debug_mode: false,
+                    },
+                    tezos_env.clone(),
+                    false,
+                    tmp_storage.path(),
+                    &PathBuf::from("__mocked_protocol_runner"),
+                    common::log_level(),
+                    false,
+                ),
+                log.clone(),
+            )
+        );
+
+        // single dispatcher thread, so the actors are always scheduled in the same order
+        let mut actor_system_cfg = riker::load_config();
+        actor_system_cfg.set("dispatcher.pool_size", 1)?;
+        let actor_system = SystemBuilder::new().name(name).cfg(actor_system_cfg).log(log.clone()).create().expect("Failed to create actor system");
+        let shell_channel = ShellChannel::actor(&actor_system).expect("Failed to create shell channel");
+        let network_channel = NetworkChannel::actor(&actor_system).expect("Failed to create network channel");
+
+        let recorder = match record_to {
+            Some(record_to) => Some(Recorder::actor(&actor_system, network_channel.clone(), shell_channel.clone(), RecordWriter::create(record_to)?).expect("Failed to create recorder")),
+            None => None,
+        };
+
+        let published = Arc::new(Mutex::new(Vec::new()));
+        let shell_events_collector = actor_system.actor_of_props::<ShellEventsCollector>(
+            "replay-shell-events-collector",
+            Props::new_args((shell_channel.clone(), published.clone())),
+        ).expect("Failed to create shell events collector");
+
+        // no timer of the actors runs during replay, recorded timing is ignored anyway
+        let clock: ClockRef = Arc::new(VirtualClock::new());
+        let chain_manager = ChainManager::actor_with_clock(
+            &actor_system,
+            network_channel.clone(),
+            shell_channel.clone(),
+            tmp_storage.storage(),
+            tezos_readonly_api,
+            Arc::new(RwLock::new(ChainRegistry::new(chain_id.clone()))),
+            &chain_id,
+            false,
+            &PeerConnectionThreshold::new(1, 1),
+            Duration::from_secs(120),
+            clock.clone(),
+        ).expect("Failed to create chain manager");
+        // low threshold is 0, so peer manager does not try to connect to any real peer
+        let peer_manager = PeerManager::actor_with_clock(
+            &actor_system,
+            network_channel.clone(),
+            shell_channel.clone(),
+            tmp_storage.storage(),
+            tokio_runtime.handle().clone(),
+            Identity::generate(0f64),
+            network_version,
+            P2p {
+                listener_port,
+                bind_address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                bootstrap_lookup_addresses: vec![],
+                disable_bootstrap_lookup: true,
+                disable_mempool: false,
+                private_node: false,
+                expected_pow: 0f64,
+                initial_peers: vec![],
+                trusted_peer_ids: vec![],
+                peer_threshold: PeerConnectionThreshold::new(0, 10),
+                peer_diversity: PeerDiversityLimits::unlimited(),
+                rate_limits: RateLimits::unlimited(),
+                capture: None,
+                keepalive: KeepaliveConfig::default(),
+            },
+            NetworkStats::default(),
+            clock,
+        ).expect("Failed to create peer manager");
+
+        // actors subscribe to channels, before they answer the first message
+        let barrier = Barrier::new(&actor_system, network_channel.clone(), shell_channel.clone())?;
+        barrier.synchronize(&chain_manager);
+        barrier.synchronize(&peer_manager);
+        barrier.synchronize(&shell_events_collector);
+        if let Some(recorder) = &recorder {
+            barrier.synchronize(recorder);
+        }
+
+        Ok(ReplayNode {
+            log,
+            actor_system,
+            network_channel,
+            shell_channel,
+            chain_manager,
+            peer_manager,
+            recorder,
+            shell_events_collector,
+            barrier,
+            _tokio_runtime: tokio_runtime,
+            _tmp_storage: tmp_storage,
+            peers: HashMap::new(),
+            sent: Arc::new(Mutex::new(HashMap::new())),
+            published,
+        })
+    }
+
+    /// Publish recorded events one by one, next event is published, when the node processed the previous one
+    pub fn replay(&mut self, records: &[Record]) -> Result<(), failure::Error> {
+        for record in records {
+            match &record.event {
+                RecordedEvent::PeerCreated { peer, address } => {
+                    let peer = self.mock_peer(peer)?;
+                    self.publish_network_event(PeerCreated { peer, address: *address, rate: PeerRate::default(), stats: PeerStats::default() }.into());
+                }
+                RecordedEvent::PeerBootstrapped { peer, peer_id, peer_metadata, listener_port } => {
+                    let peer = self.mock_peer(peer)?;
+                    self.publish_network_event(PeerBootstrapped::Success { peer, peer_id: peer_id.clone(), peer_metadata: peer_metadata.clone(), listener_port: *listener_port }.into());
+                }
+                RecordedEvent::PeerBootstrapFailed { address, potential_peers_to_connect, misbehavior } => {
+                    self.publish_network_event(PeerBootstrapped::Failure { address: *address, potential_peers_to_connect: potential_peers_to_connect.clone(), misbehavior: *misbehavior }.into());
+                }
+                RecordedEvent::PeerMessageReceived { peer, .. } => {
+                    let message = record.event.peer_message()?.expect("Expected peer message");
+                    let peer = self.mock_peer(peer)?;
+                    self.publish_network_event(PeerMessageReceived::new(peer, Arc::new(message)).into());
+                }
+                RecordedEvent::PeerMisbehaved { peer, misbehavior, disconnect } => {
+                    let peer = self.mock_peer(peer)?;
+                    self.publish_network_event(PeerMisbehaved { peer, misbehavior: *misbehavior, disconnect: *disconnect }.into());
+                }
+                RecordedEvent::BlockApplied { .. } => {
+                    // block applied is the only recorded shell event, which is not produced by the chain manager or peer manager
+                    let block = record.event.block_applied().expect("Expected block applied");
+                    self.shell_channel.tell(
+                        Publish {
+                            msg: block.into(),
+                            topic: ShellChannelTopic::ShellEvents.into(),
+                        }, None);
+                }
+                // produced by the replayed actors, see `published_events`
+                RecordedEvent::NewCurrentHead { .. }
+                | RecordedEvent::BlockReceived { .. }
+                | RecordedEvent::AllBlockOperationsReceived { .. }
+                | RecordedEvent::MempoolOperationReceived { .. }
+                | RecordedEvent::ShuttingDown => (),
+            }
+            self.settle();
+        }
+        Ok(())
+    }
+
+    /// Wait until the node processes all published events together with the messages its actors sent to each other,
+    /// every round waits for the channels and the actors, until the round does not produce anything new
+    fn settle(&self) {
+        loop {
+            let activity = self.activity();
+            self.barrier.flush_channels();
+            self.barrier.synchronize(&self.chain_manager);
+            self.barrier.synchronize(&self.peer_manager);
+            self.barrier.flush_channels();
+            self.barrier.synchronize(&self.shell_events_collector);
+            for peer in self.peers.values() {
+                self.barrier.synchronize(peer);
+            }
+            if activity == self.activity() {
+                break;
+            }
+        }
+    }
+
+    /// Count of the messages sent by the node and of the shell events published by the node
+    fn activity(&self) -> (usize, usize) {
+        let sent = self.sent.lock().unwrap().values().map(Vec::len).sum();
+        (sent, self.published.lock().unwrap().len())
+    }
+
+    /// Messages sent by the node to the recorded peer
+    pub fn sent_messages(&self, peer: &str) -> Vec<PeerMessage> {
+        self.sent.lock().unwrap().get(peer).cloned().unwrap_or_default()
+    }
+
+    /// Shell events published by the node during replay
+    pub fn published_events(&self) -> Vec<RecordedEvent> {
+        self.published.lock().unwrap().clone()
+    }
+
+    fn mock_peer(&mut self, name: &str) -> Result<PeerRef, failure::Error> {
+        if let Some(peer) = self.peers.get(name) {
+            return Ok(peer.clone());
+        }
+        let peer = self.actor_system.actor_of_props::<MockPeer>(name, Props::new_args((name.to_string(), self.sent.clone())))
+            .map_err(|e| failure::format_err!("Failed to create mocked peer: {}, reason: {:?}", name, e))?;
+        self.peers.insert(name.to_string(), peer.clone());
+        Ok(peer)
+    }
+
+    fn publish_network_event(&self, msg: NetworkChannelMsg) {
+        self.network_channel.tell(
+            Publish {
+                msg,
+                topic: NetworkChannelTopic::NetworkEvents.into(),
+            }, None);
+    }
+}
+
+impl Drop for ReplayNode {
+    fn drop(&mut self) {
+        warn!(self.log, "[NODE] Stopping replay node");
+        self.shell_channel.tell(
+            Publish {
+                msg: ShuttingDown.into(),
+                topic: ShellChannelTopic::ShellCommands.into(),
+            }, None,
+        );
+        // let recorder flush the records
+        self.barrier.flush_channels();
+        if let Some(recorder) = &self.recorder {
+            self.barrier.synchronize(recorder);
+        }
+        let _ = self.actor_system.shutdown();
+    }
+}
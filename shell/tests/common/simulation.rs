@@ -0,0 +1,918 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Simulated network for the shell actors, which runs without real peers and without protocol runner.
+//!
+//! `ChainManager`, `PeerManager` and `MempoolPrevalidator` run as in the node, but:
+//! - peers are replaced by [VirtualPeer] actors connected through the `NetworkChannel`,
+//!   each peer serves its own [ScriptedChain] according to its [Behavior]
+//! - blocks are applied by [StubProtocolRunner], which accepts every block (no OCaml is involved)
+//! - `ChainManager` and `PeerManager` use [VirtualClock], so timeouts are reached by [Simulation::advance] instead of waiting,
+//!   no timer of the actors runs, the test sends timer messages itself (see [Simulation::tell_chain_manager])
+//!
+//! Virtual peers do not answer immediately, requests are collected and answered in [Simulation::step],
+//! peers are processed in the order of connection, so every run gets the same sequence of inputs.
+//! Every step waits until the actors process everything (see [Barrier]), nothing depends on real time.
+
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+
+use riker::actors::*;
+use riker::system::SystemBuilder;
+use slog::{Logger, warn};
+use tokio::runtime::Runtime;
+
+use crypto::hash::{BlockHash, ChainId, HashType, ProtocolHash};
+use networking::barrier::{answer_synchronized, Synchronize};
+use networking::clock::VirtualClock;
+use networking::p2p::network_channel::{Misbehavior, NetworkChannel, NetworkChannelMsg, NetworkChannelRef, NetworkChannelTopic, PeerBootstrapped, PeerCreated, PeerMessageReceived, PeerMisbehaved};
+use networking::p2p::peer::{KeepaliveConfig, PeerMsg, PeerRef};
+use networking::p2p::rate_limit::{PeerRate, RateLimits};
+use networking::p2p::stream::CONTENT_LENGTH_MAX;
+use networking::p2p::stats::{NetworkStats, PeerStats};
+use shell::chain_manager::{ChainManager, ChainManagerMsg, ChainManagerRef};
+use shell::chain_registry::ChainRegistry;
+use shell::mempool_prevalidator::{MempoolLimits, MempoolPrevalidator};
+use shell::peer_manager::{P2p, PeerDiversityLimits, PeerManager, PeerManagerMsg, PeerManagerRef};
+use shell::PeerConnectionThreshold;
+use shell::shell_channel::{BlockApplied, ShellChannel, ShellChannelMsg, ShellChannelRef, ShellChannelTopic, ShuttingDown};
+use storage::{BlockHeaderWithHash, BlockMetaStorage, BlockMetaStorageReader, BlockStorage, BlockStorageReader, ChainMetaStorage, initialize_storage_with_genesis_block, MempoolStorage, OperationsMetaStorage, ProtocolStorage, StorageInitInfo, store_applied_block_result, store_commit_genesis_result};
+use storage::chain_meta_storage::ChainMetaStorageReader;
+use storage::persistent::PersistentStorage;
+use storage::tests_common::TmpStorage;
+use tezos_api::environment::{OPERATION_LIST_LIST_HASH_EMPTY, TEZOS_ENV, TezosEnvironment};
+use tezos_api::ffi::{ApplyBlockResponse, CommitGenesisResult, TezosRuntimeConfiguration};
+use tezos_encoding::binary_reader::BinaryReaderError;
+use tezos_identity::Identity;
+use tezos_messages::Head;
+use tezos_messages::p2p::binary_message::{BinaryMessage, MessageHash};
+use tezos_messages::p2p::encoding::block_header::{BlockHeaderBuilder, Level};
+use tezos_messages::p2p::encoding::current_branch::HISTORY_MAX_SIZE;
+use tezos_messages::p2p::encoding::metadata::MetadataMessage;
+use tezos_messages::p2p::encoding::operations_for_blocks::Path;
+use tezos_messages::p2p::encoding::prelude::*;
+use tezos_messages::p2p::encoding::version::NetworkVersion;
+use tezos_wrapper::{TezosApiConnectionPool, TezosApiConnectionPoolConfiguration};
+use tezos_wrapper::service::ProtocolEndpointConfiguration;
+
+use crate::common;
+use crate::common::barrier::Barrier;
+
+/// How much virtual time passes in one [Simulation::step]
+pub const STEP_DURATION: Duration = Duration::from_secs(1);
+
+/// Block of the [ScriptedChain] with all its operations
+#[derive(Clone, Debug)]
+pub struct ScriptedBlock {
+    pub header: BlockHeaderWithHash,
+    pub operations: Vec<OperationsForBlocksMessage>,
+}
+
+/// Chain served by the virtual peer, block on index `n` has level `n`
+#[derive(Clone, Debug)]
+pub struct ScriptedChain {
+    blocks: Vec<ScriptedBlock>,
+    /// Distinguishes blocks of different forks on the same level
+    branch: u8,
+    /// Protocol sources served by the peer
+    protocols: Vec<Protocol>,
+}
+
+impl ScriptedChain {
+    pub fn new(genesis: BlockHeaderWithHash) -> Self {
+        ScriptedChain {
+            blocks: vec![ScriptedBlock { header: genesis, operations: vec![] }],
+            branch: 0,
+            protocols: vec![],
+        }
+    }
+
+    /// Serve sources of the `protocol` too
+    pub fn with_protocol(mut self, protocol: Protocol) -> Self {
+        self.protocols.push(protocol);
+        self
+    }
+
+    /// Append `count` blocks, if `with_operations` is set, blocks have one (empty) validation pass
+    pub fn extend(mut self, count: usize, with_operations: bool) -> Self {
+        for _ in 0..count {
+            let predecessor = &self.head().header;
+            let level = predecessor.header.level() + 1;
+            let header = BlockHeaderBuilder::default()
+                .level(level)
+                .proto(1)
+                .predecessor(predecessor.hash.clone())
+                .timestamp(predecessor.header.timestamp() + 60)
+                .validation_pass(if with_operations { 1 } else { 0 })
+                .operations_hash(OPERATION_LIST_LIST_HASH_EMPTY.clone())
+                .fitness(fitness(level))
+                .context(predecessor.header.context().clone())
+                .protocol_data(vec![self.branch])
+                .build().unwrap();
+            let header = BlockHeaderWithHash::new(header).unwrap();
+            let operations = if with_operations {
+                vec![OperationsForBlocksMessage::new(OperationsForBlock::new(header.hash.clone(), 0), Path::Op, vec![])]
+            } else {
+                vec![]
+            };
+            self.blocks.push(ScriptedBlock { header, operations });
+        }
+        self
+    }
+
+    /// Append block with one validation pass of `count` operations
+    pub fn extend_with_operations(self, count: usize) -> Self {
+        let mut chain = self.extend(1, true);
+        let block = chain.blocks.last_mut().unwrap();
+        let operations = (0..count)
+            .map(|index| {
+                let mut bytes = block.header.hash.clone();
+                bytes.extend_from_slice(&(index as u32).to_be_bytes());
+                Operation::from_bytes(bytes)
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .expect("Failed to create operation");
+        block.operations = vec![OperationsForBlocksMessage::new(OperationsForBlock::new(block.header.hash.clone(), 0), Path::Op, operations)];
+        chain
+    }
+
+    /// New chain which shares blocks up to `level` with this one, following blocks are marked with `branch`
+    pub fn fork(&self, level: Level, branch: u8) -> Self {
+        ScriptedChain {
+            blocks: self.blocks[..=level as usize].to_vec(),
+            branch,
+            protocols: self.protocols.clone(),
+        }
+    }
+
+    pub fn head(&self) -> &ScriptedBlock {
+        self.blocks.last().unwrap()
+    }
+
+    pub fn block(&self, level: Level) -> &ScriptedBlock {
+        &self.blocks[level as usize]
+    }
+
+    /// Block locator of the head like the OCaml node sends it, distance between the blocks doubles after every 9 blocks
+    pub fn history(&self) -> Vec<BlockHash> {
+        let mut history = vec![];
+        let mut level = self.head().header.header.level();
+        let mut step = 1;
+        while history.len() < usize::from(HISTORY_MAX_SIZE) {
+            if !history.is_empty() && history.len() % 9 == 0 {
+                step *= 2;
+            }
+            level -= step;
+            if level < 0 {
+                break;
+            }
+            history.push(self.block(level).header.hash.clone());
+        }
+        history
+    }
+
+    fn find(&self, block_hash: &BlockHash) -> Option<&ScriptedBlock> {
+        self.blocks.iter().find(|block| &block.header.hash == block_hash)
+    }
+
+    fn find_protocol(&self, protocol_hash: &ProtocolHash) -> Option<&Protocol> {
+        self.protocols.iter().find(|protocol| protocol.message_hash().ok().as_ref() == Some(protocol_hash))
+    }
+}
+
+/// Fitness grows with the level, so longer chain always wins
+fn fitness(level: Level) -> Vec<Vec<u8>> {
+    vec![vec![0], (level as u64).to_be_bytes().to_vec()]
+}
+
+/// How virtual peer answers requests of the node
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Behavior {
+    /// Answers all requests
+    Honest,
+    /// Answers all requests except requests for operations of the blocks
+    WithholdOperations,
+    /// Answers requests for blocks and operations only after [Simulation::release]
+    Slow,
+    /// Answers requests for block headers with headers, which were not requested
+    Malicious,
+    /// Answers requests for protocols with sources, which do not match the requested hash
+    ForgeProtocols,
+}
+
+/// Messages sent by the node to the virtual peer
+#[derive(Default)]
+struct Inbox {
+    /// Not answered yet
+    pending: VecDeque<PeerMessage>,
+    /// Everything received from the node
+    received: Vec<PeerMessage>,
+}
+
+type SharedInbox = Arc<Mutex<Inbox>>;
+
+/// Virtual peer, which just collects messages sent by the node, they are answered by the [Simulation]
+struct VirtualPeer {
+    inbox: SharedInbox,
+}
+
+impl ActorFactoryArgs<SharedInbox> for VirtualPeer {
+    fn create_args(inbox: SharedInbox) -> Self {
+        VirtualPeer { inbox }
+    }
+}
+
+impl Actor for VirtualPeer {
+    type Msg = PeerMsg;
+
+    fn recv(&mut self, _: &Context<Self::Msg>, msg: Self::Msg, sender: Sender) {
+        match msg {
+            PeerMsg::SendMessage(msg) => {
+                let mut inbox = self.inbox.lock().unwrap();
+                for message in msg.message().messages() {
+                    inbox.pending.push_back(message.clone());
+                    inbox.received.push(message.clone());
+                }
+            }
+            PeerMsg::Synchronize(_) => answer_synchronized(sender),
+            _ => (),
+        }
+    }
+}
+
+/// Block header metadata, which the [StubProtocolRunner] stores for every applied block
+type SharedMetadata = Arc<Mutex<String>>;
+
+/// Count of blocks applied by the [StubProtocolRunner]
+type AppliedCount = Arc<AtomicUsize>;
+
+/// Applies every block by fiat and publishes [BlockApplied], as chain feeder does with the real protocol runner
+#[actor(ShellChannelMsg, Synchronize)]
+struct StubProtocolRunner {
+    shell_channel: ShellChannelRef,
+    block_storage: BlockStorage,
+    block_meta_storage: BlockMetaStorage,
+    block_header_proto_metadata: SharedMetadata,
+    applied: AppliedCount,
+}
+
+impl ActorFactoryArgs<(ShellChannelRef, PersistentStorage, SharedMetadata, AppliedCount)> for StubProtocolRunner {
+    fn create_args((shell_channel, persistent_storage, block_header_proto_metadata, applied): (ShellChannelRef, PersistentStorage, SharedMetadata, AppliedCount)) -> Self {
+        StubProtocolRunner {
+            shell_channel,
+            block_storage: BlockStorage::new(&persistent_storage),
+            block_meta_storage: BlockMetaStorage::new(&persistent_storage),
+            block_header_proto_metadata,
+            applied,
+        }
+    }
+}
+
+impl StubProtocolRunner {
+    fn apply_block(&mut self, block_hash: &BlockHash) -> Result<(), failure::Error> {
+        let mut block_meta = match self.block_meta_storage.get(block_hash)? {
+            Some(block_meta) if !block_meta.is_applied() => block_meta,
+            _ => return Ok(()),
+        };
+
+        let (block_json_data, _) = store_applied_block_result(
+            &self.block_storage,
+            &self.block_meta_storage,
+            block_hash,
+            ApplyBlockResponse {
+                validation_result_message: "applied by stub protocol runner".to_string(),
+                context_hash: HashType::ContextHash.string_to_bytes("CoVmAcMV64uAQo8XvfLr9VDuz7HVZLT4cgK1w1qYmTjQNbGwQwDd")?,
+                block_header_proto_json: "{}".to_string(),
+                block_header_proto_metadata_json: self.block_header_proto_metadata.lock().unwrap().clone(),
+                operations_proto_metadata_json: "[]".to_string(),
+                max_operations_ttl: 60,
+                last_allowed_fork_level: 0,
+                forking_testchain: false,
+                forking_testchain_data: None,
+            },
+            &mut block_meta,
+        )?;
+
+        let block = self.block_storage.get(block_hash)?.ok_or_else(|| failure::format_err!("Applied block not found"))?;
+        self.shell_channel.tell(
+            Publish {
+                msg: BlockApplied::new(block, block_json_data).into(),
+                topic: ShellChannelTopic::ShellEvents.into(),
+            }, None);
+        self.applied.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+impl Actor for StubProtocolRunner {
+    type Msg = StubProtocolRunnerMsg;
+
+    fn pre_start(&mut self, ctx: &Context<Self::Msg>) {
+        self.shell_channel.tell(
+            Subscribe {
+                actor: Box::new(ctx.myself()),
+                topic: ShellChannelTopic::ShellEvents.into(),
+            }, None);
+    }
+
+    fn recv(&mut self, ctx: &Context<Self::Msg>, msg: Self::Msg, sender: Sender) {
+        self.receive(ctx, msg, sender);
+    }
+}
+
+impl Receive<ShellChannelMsg> for StubProtocolRunner {
+    type Msg = StubProtocolRunnerMsg;
+
+    fn receive(&mut self, ctx: &Context<Self::Msg>, msg: ShellChannelMsg, _: Sender) {
+        if let ShellChannelMsg::ApplyBlock(block_hash, _) = msg {
+            if let Err(e) = self.apply_block(&block_hash) {
+                warn!(ctx.system.log(), "Stub protocol runner failed to apply block"; "reason" => format!("{:?}", e));
+            }
+        }
+    }
+}
+
+impl Receive<Synchronize> for StubProtocolRunner {
+    type Msg = StubProtocolRunnerMsg;
+
+    fn receive(&mut self, _: &Context<Self::Msg>, _: Synchronize, sender: Sender) {
+        answer_synchronized(sender);
+    }
+}
+
+/// Collects misbehavior reported for the virtual peers
+#[actor(NetworkChannelMsg, Synchronize)]
+struct MisbehaviorCollector {
+    network_channel: NetworkChannelRef,
+    misbehaved: Arc<Mutex<Vec<(String, Misbehavior)>>>,
+}
+
+impl ActorFactoryArgs<(NetworkChannelRef, Arc<Mutex<Vec<(String, Misbehavior)>>>)> for MisbehaviorCollector {
+    fn create_args((network_channel, misbehaved): (NetworkChannelRef, Arc<Mutex<Vec<(String, Misbehavior)>>>)) -> Self {
+        MisbehaviorCollector { network_channel, misbehaved }
+    }
+}
+
+impl Actor for MisbehaviorCollector {
+    type Msg = MisbehaviorCollectorMsg;
+
+    fn pre_start(&mut self, ctx: &Context<Self::Msg>) {
+        self.network_channel.tell(
+            Subscribe {
+                actor: Box::new(ctx.myself()),
+                topic: NetworkChannelTopic::NetworkEvents.into(),
+            }, None);
+    }
+
+    fn recv(&mut self, ctx: &Context<Self::Msg>, msg: Self::Msg, sender: Sender) {
+        self.receive(ctx, msg, sender);
+    }
+}
+
+impl Receive<NetworkChannelMsg> for MisbehaviorCollector {
+    type Msg = MisbehaviorCollectorMsg;
+
+    fn receive(&mut self, _: &Context<Self::Msg>, msg: NetworkChannelMsg, _: Sender) {
+        if let NetworkChannelMsg::PeerMisbehaved(PeerMisbehaved { peer, misbehavior, .. }) = msg {
+            self.misbehaved.lock().unwrap().push((peer.name().to_string(), misbehavior));
+        }
+    }
+}
+
+impl Receive<Synchronize> for MisbehaviorCollector {
+    type Msg = MisbehaviorCollectorMsg;
+
+    fn receive(&mut self, _: &Context<Self::Msg>, _: Synchronize, sender: Sender) {
+        answer_synchronized(sender);
+    }
+}
+
+struct SimulatedPeer {
+    name: String,
+    peer_ref: PeerRef,
+    chain: ScriptedChain,
+    behavior: Behavior,
+    inbox: SharedInbox,
+    /// Requests delayed by the slow peer
+    held: Vec<PeerMessage>,
+    /// Messages which peer sends on its own, not as an answer
+    outbox: Vec<PeerMessage>,
+}
+
+/// Shell actors connected to the virtual peers
+pub struct Simulation {
+    log: Logger,
+    actor_system: ActorSystem,
+    network_channel: NetworkChannelRef,
+    shell_channel: ShellChannelRef,
+    chain_manager: ChainManagerRef,
+    peer_manager: PeerManagerRef,
+    stub_protocol_runner: ActorRef<StubProtocolRunnerMsg>,
+    misbehavior_collector: ActorRef<MisbehaviorCollectorMsg>,
+    barrier: Barrier,
+    clock: Arc<VirtualClock>,
+    chain_id: ChainId,
+    genesis: BlockHeaderWithHash,
+    chain_meta_storage: ChainMetaStorage,
+    protocol_storage: ProtocolStorage,
+    mempool_storage: MempoolStorage,
+    peers: Vec<SimulatedPeer>,
+    misbehaved: Arc<Mutex<Vec<(String, Misbehavior)>>>,
+    block_header_proto_metadata: SharedMetadata,
+    applied: AppliedCount,
+    _tokio_runtime: Runtime,
+    _tmp_storage: TmpStorage,
+}
+
+impl Simulation {
+    /// Start shell actors on empty storage initialized with genesis,
+    /// `peers_threshold` is used by the chain manager to resolve, if it is bootstrapped
+    pub fn start(name: &str, listener_port: u16, peers_threshold: PeerConnectionThreshold, log: Logger) -> Result<Self, failure::Error> {
+        let tmp_storage = TmpStorage::create(common::prepare_empty_dir(&format!("__{}", name)))?;
+        let persistent_storage = tmp_storage.storage();
+        let tezos_env = TEZOS_ENV.get(&TezosEnvironment::Carthagenet).expect("no environment configuration");
+        let init_storage_data = StorageInitInfo {
+            chain_id: tezos_env.main_chain_id()?,
+            genesis_block_header_hash: tezos_env.genesis_header_hash()?,
+            patch_context: None,
+        };
+        let tokio_runtime = tokio::runtime::Builder::new()
+            .threaded_scheduler()
+            .enable_all()
+            .build()?;
+
+        // commit genesis the same way as chain feeder does, just without protocol runner
+        let block_storage = BlockStorage::new(&persistent_storage);
+        let genesis = initialize_storage_with_genesis_block(
+            &block_storage,
+            &init_storage_data,
+            tezos_env,
+            &HashType::ContextHash.string_to_bytes("CoV16kW8WgL51SpcftQKdeqc94D6ekghMgPMmEn7TSZzFA697PeE")?,
+            &log,
+        )?;
+        let _ = store_commit_genesis_result(
+            &block_storage,
+            &BlockMetaStorage::new(&persistent_storage),
+            &ChainMetaStorage::new(&persistent_storage),
+            &OperationsMetaStorage::new(&persistent_storage),
+            &init_storage_data,
+            CommitGenesisResult {
+                block_header_proto_json: "{}".to_string(),
+                block_header_proto_metadata_json: "{}".to_string(),
+                operations_proto_metadata_json: "[]".to_string(),
+            },
+        )?;
+
+        // mocked protocol runner pool - there is no protocol runner, so every prevalidation fails fast
+        let tezos_readonly_api = Arc::new(
+            TezosApiConnectionPool::new_without_context(
+                format!("{}_mocked_pool", name),
+                TezosApiConnectionPoolConfiguration {
+                    min_connections: 0,
+                    max_connections: 1,
+                    connection_timeout: Duration::from_millis(100),
+                    max_lifetime: Duration::from_secs(60),
+                    idle_timeout: Duration::from_secs(60),
+                },
+                ProtocolEndpointConfiguration::new(
+                    TezosRuntimeConfiguration {
+                        log_enabled: false,
+                        no_of_ffi_calls_treshold_for_gc: common::no_of_ffi_calls_treshold_for_gc(),
+                        debug_mode: false,
+                    },
+                    tezos_env.clone(),
+                    false,
+                    tmp_storage.path(),
+                    &PathBuf::from("__mocked_protocol_runner"),
+                    common::log_level(),
+                    false,
+                ),
+                log.clone(),
+            )
+        );
+
+        let clock = Arc::new(VirtualClock::new());
+        let actor_system = SystemBuilder::new().name(name).log(log.clone()).create().expect("Failed to create actor system");
+        let shell_channel = ShellChannel::actor(&actor_system).expect("Failed to create shell channel");
+        let network_channel = NetworkChannel::actor(&actor_system).expect("Failed to create network channel");
+
+        let misbehaved = Arc::new(Mutex::new(Vec::new()));
+        let block_header_proto_metadata = Arc::new(Mutex::new("{}".to_string()));
+        let applied = AppliedCount::default();
+        let misbehavior_collector = actor_system.actor_of_props::<MisbehaviorCollector>(
+            "simulation-misbehavior-collector",
+            Props::new_args((network_channel.clone(), misbehaved.clone())),
+        ).expect("Failed to create misbehavior collector");
+        let stub_protocol_runner = actor_system.actor_of_props::<StubProtocolRunner>(
+            "simulation-stub-protocol-runner",
+            Props::new_args((shell_channel.clone(), persistent_storage.clone(), block_header_proto_metadata.clone(), applied.clone())),
+        ).expect("Failed to create stub protocol runner");
+
+        let chain_manager = ChainManager::actor_with_clock(
+            &actor_system,
+            network_channel.clone(),
+            shell_channel.clone(),
+            &persistent_storage,
+            tezos_readonly_api.clone(),
+            Arc::new(RwLock::new(ChainRegistry::new(init_storage_data.chain_id.clone()))),
+            &init_storage_data.chain_id,
+            false,
+            &peers_threshold,
+            Duration::from_secs(120),
+            clock.clone(),
+        ).expect("Failed to create chain manager");
+        let _ = MempoolPrevalidator::actor(
+            &actor_system,
+            shell_channel.clone(),
+            &persistent_storage,
+            &init_storage_data,
+            tezos_readonly_api,
+            MempoolLimits::default(),
+            log.clone(),
+        ).expect("Failed to create mempool prevalidator");
+        // low threshold is 0, so peer manager does not try to connect to any real peer
+        let peer_manager = PeerManager::actor_with_clock(
+            &actor_system,
+            network_channel.clone(),
+            shell_channel.clone(),
+            &persistent_storage,
+            tokio_runtime.handle().clone(),
+            Identity::generate(0f64),
+            NetworkVersion::new("SIMULATION".to_string(), 0, 0),
+            P2p {
+                listener_port,
+                bind_address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                bootstrap_lookup_addresses: vec![],
+                disable_bootstrap_lookup: true,
+                disable_mempool: false,
+                private_node: false,
+                expected_pow: 0f64,
+                initial_peers: vec![],
+                trusted_peer_ids: vec![],
+                peer_threshold: PeerConnectionThreshold::new(0, 10),
+                peer_diversity: PeerDiversityLimits::unlimited(),
+                rate_limits: RateLimits::unlimited(),
+                capture: None,
+                keepalive: KeepaliveConfig::default(),
+            },
+            NetworkStats::default(),
+            clock.clone(),
+        ).expect("Failed to create peer manager");
+
+        // actors subscribe to channels, before they answer the first message
+        let barrier = Barrier::new(&actor_system, network_channel.clone(), shell_channel.clone())?;
+        barrier.synchronize(&chain_manager);
+        barrier.synchronize(&peer_manager);
+        barrier.synchronize(&stub_protocol_runner);
+        barrier.synchronize(&misbehavior_collector);
+
+        Ok(Simulation {
+            log,
+            actor_system,
+            network_channel,
+            shell_channel,
+            chain_manager,
+            peer_manager,
+            stub_protocol_runner,
+            misbehavior_collector,
+            barrier,
+            clock,
+            chain_id: init_storage_data.chain_id,
+            genesis,
+            chain_meta_storage: ChainMetaStorage::new(&persistent_storage),
+            protocol_storage: ProtocolStorage::new(&persistent_storage),
+            mempool_storage: MempoolStorage::new(&persistent_storage),
+            peers: vec![],
+            misbehaved,
+            block_header_proto_metadata,
+            applied,
+            _tokio_runtime: tokio_runtime,
+            _tmp_storage: tmp_storage,
+        })
+    }
+
+    pub fn chain_id(&self) -> &ChainId {
+        &self.chain_id
+    }
+
+    /// Chain with just the genesis block of the simulated node
+    pub fn genesis_chain(&self) -> ScriptedChain {
+        ScriptedChain::new(self.genesis.clone())
+    }
+
+    /// Connect new virtual peer, which serves `chain`
+    pub fn connect(&mut self, name: &str, chain: ScriptedChain, behavior: Behavior) -> Result<(), failure::Error> {
+        let inbox = SharedInbox::default();
+        let peer_ref = self.actor_system.actor_of_props::<VirtualPeer>(name, Props::new_args(inbox.clone()))
+            .map_err(|e| failure::format_err!("Failed to create virtual peer: {}, reason: {:?}", name, e))?;
+        let address: SocketAddr = format!("10.0.0.{}:9732", self.peers.len() + 1).parse()?;
+
+        self.publish_network_event(PeerCreated { peer: peer_ref.clone(), address, rate: PeerRate::default(), stats: PeerStats::default() }.into());
+        self.publish_network_event(PeerBootstrapped::Success {
+            peer: peer_ref.clone(),
+            peer_id: format!("idtSimulatedPeer{}", self.peers.len() + 1),
+            peer_metadata: MetadataMessage::new(false, false),
+            listener_port: 9732,
+        }.into());
+        self.peers.push(SimulatedPeer { name: name.to_string(), peer_ref, chain, behavior, inbox, held: vec![], outbox: vec![] });
+
+        self.settle();
+        Ok(())
+    }
+
+    /// Replace the chain served by the peer, e.g. to let the peer announce new blocks
+    pub fn update_chain(&mut self, name: &str, chain: ScriptedChain) {
+        self.peer_mut(name).chain = chain;
+    }
+
+    /// Answer delayed requests of the slow peer in the next step
+    pub fn release(&mut self, name: &str) {
+        let peer = self.peer_mut(name);
+        let held = std::mem::replace(&mut peer.held, vec![]);
+        peer.inbox.lock().unwrap().pending.extend(held);
+        peer.behavior = Behavior::Honest;
+    }
+
+    /// Move virtual time forward, let peers answer all pending requests and wait until actors process the answers
+    pub fn step(&mut self) {
+        self.clock.advance(STEP_DURATION);
+
+        let mut answers = Vec::new();
+        for peer in self.peers.iter_mut() {
+            answers.extend(peer.outbox.drain(..).map(|message| (peer.peer_ref.clone(), message)));
+            let requests: Vec<PeerMessage> = peer.inbox.lock().unwrap().pending.drain(..).collect();
+            for request in requests {
+                if peer.behavior == Behavior::Slow && is_data_request(&request) {
+                    peer.held.push(request);
+                } else {
+                    answers.extend(
+                        answer(&self.chain_id, &peer.chain, peer.behavior, &request)
+                            .into_iter()
+                            .map(|message| (peer.peer_ref.clone(), message))
+                    );
+                }
+            }
+        }
+        for (peer, message) in answers {
+            let message = transmit(message).expect("Failed to transmit message of the virtual peer");
+            self.publish_network_event(PeerMessageReceived::new(peer, Arc::new(message)).into());
+        }
+
+        self.settle();
+    }
+
+    pub fn run(&mut self, steps: usize) {
+        for _ in 0..steps {
+            self.step();
+        }
+    }
+
+    /// Run steps until `condition` is met, returns false, if it was not met in `max_steps`
+    pub fn run_until<F: Fn(&Simulation) -> bool>(&mut self, max_steps: usize, condition: F) -> bool {
+        for _ in 0..max_steps {
+            if condition(self) {
+                return true;
+            }
+            self.step();
+        }
+        condition(self)
+    }
+
+    /// Blocks applied from now on announce `protocol_hash` as the protocol of their successors
+    pub fn announce_next_protocol(&self, protocol_hash: &ProtocolHash) {
+        let protocol_hash = HashType::ProtocolHash.bytes_to_string(protocol_hash);
+        *self.block_header_proto_metadata.lock().unwrap() = format!(r#"{{ "protocol": "{}", "next_protocol": "{}" }}"#, protocol_hash, protocol_hash);
+    }
+
+    /// Store protocol sources in the storage of the simulated node
+    pub fn store_protocol(&self, protocol: &Protocol) -> Result<(), failure::Error> {
+        self.protocol_storage.put(&protocol.message_hash()?, protocol)?;
+        Ok(())
+    }
+
+    /// Store operation in the mempool of the simulated node, as if it was received from some peer
+    pub fn store_mempool_operation(&mut self, operation: Operation) -> Result<(), failure::Error> {
+        self.mempool_storage.put_pending(operation.into(), SystemTime::now() + Duration::from_secs(600))?;
+        Ok(())
+    }
+
+    /// Returns true, if sources of the protocol are stored by the simulated node
+    pub fn has_protocol(&self, protocol_hash: &ProtocolHash) -> bool {
+        self.protocol_storage.contains(protocol_hash).unwrap_or(false)
+    }
+
+    /// Peer sends the message to the node, it is processed in the next step
+    pub fn send(&mut self, name: &str, message: PeerMessage) {
+        self.peer_mut(name).outbox.push(message);
+    }
+
+    /// Move virtual time forward without any network activity
+    pub fn advance(&self, duration: Duration) {
+        self.clock.advance(duration);
+    }
+
+    /// Send message directly to the chain manager, e.g. [DisconnectStalledPeers](shell::chain_manager::DisconnectStalledPeers)
+    pub fn tell_chain_manager<M: Into<ChainManagerMsg>>(&self, msg: M) {
+        self.chain_manager.tell(msg, None);
+        self.settle();
+    }
+
+    /// Send message directly to the peer manager, e.g. [CheckPeerCount](shell::peer_manager::CheckPeerCount)
+    pub fn tell_peer_manager<M: Into<PeerManagerMsg>>(&self, msg: M) {
+        self.peer_manager.tell(msg, None);
+        self.settle();
+    }
+
+    /// Wait until the actors process all published events together with the messages they sent to each other,
+    /// every round waits for the channels and the actors, until the round does not produce anything new
+    fn settle(&self) {
+        loop {
+            let activity = self.activity();
+            self.barrier.flush_channels();
+            self.barrier.synchronize(&self.chain_manager);
+            self.barrier.synchronize(&self.peer_manager);
+            self.barrier.flush_channels();
+            self.barrier.synchronize(&self.stub_protocol_runner);
+            self.barrier.synchronize(&self.misbehavior_collector);
+            for peer in &self.peers {
+                self.barrier.synchronize(&peer.peer_ref);
+            }
+            if activity == self.activity() {
+                break;
+            }
+        }
+    }
+
+    /// Count of the messages received by the virtual peers, of the applied blocks and of the reported misbehavior
+    fn activity(&self) -> (usize, usize, usize) {
+        let received = self.peers.iter().map(|peer| peer.inbox.lock().unwrap().received.len()).sum();
+        (received, self.applied.load(Ordering::SeqCst), self.misbehaved.lock().unwrap().len())
+    }
+
+    /// Current head of the simulated node
+    pub fn current_head(&self) -> Option<Head> {
+        self.chain_meta_storage.get_current_head(&self.chain_id).expect("Failed to read current head")
+    }
+
+    pub fn current_head_level(&self) -> Level {
+        self.current_head().map(|head| *head.level()).unwrap_or(0)
+    }
+
+    /// All messages the node sent to the peer
+    pub fn received_messages(&self, name: &str) -> Vec<PeerMessage> {
+        self.peer(name).inbox.lock().unwrap().received.clone()
+    }
+
+    /// Misbehavior reported for the peer by the shell actors
+    pub fn misbehaved(&self, name: &str) -> Vec<Misbehavior> {
+        self.misbehaved.lock().unwrap().iter()
+            .filter(|(peer, _)| peer == name)
+            .map(|(_, misbehavior)| *misbehavior)
+            .collect()
+    }
+
+    fn peer(&self, name: &str) -> &SimulatedPeer {
+        self.peers.iter().find(|peer| peer.name == name).unwrap_or_else(|| panic!("Unknown virtual peer: {}", name))
+    }
+
+    fn peer_mut(&mut self, name: &str) -> &mut SimulatedPeer {
+        self.peers.iter_mut().find(|peer| peer.name == name).unwrap_or_else(|| panic!("Unknown virtual peer: {}", name))
+    }
+
+    fn publish_network_event(&self, msg: NetworkChannelMsg) {
+        self.network_channel.tell(
+            Publish {
+                msg,
+                topic: NetworkChannelTopic::NetworkEvents.into(),
+            }, None);
+    }
+}
+
+impl Drop for Simulation {
+    fn drop(&mut self) {
+        warn!(self.log, "[NODE] Stopping simulation");
+        self.shell_channel.tell(
+            Publish {
+                msg: ShuttingDown.into(),
+                topic: ShellChannelTopic::ShellCommands.into(),
+            }, None,
+        );
+        self.barrier.flush_channels();
+        self.barrier.synchronize(&self.chain_manager);
+        self.barrier.synchronize(&self.peer_manager);
+        let _ = self.actor_system.shutdown();
+    }
+}
+
+/// Encode message of the virtual peer and decode it from the chunks, as the peer stream reader does,
+/// so the node gets exactly what it would get from the network, even for the messages split into several chunks
+fn transmit(message: PeerMessage) -> Result<PeerMessageResponse, failure::Error> {
+    let bytes = PeerMessageResponse::from(message).as_bytes()?;
+    let mut received = Vec::with_capacity(bytes.len());
+    for chunk in bytes.chunks(CONTENT_LENGTH_MAX) {
+        received.extend_from_slice(chunk);
+        match PeerMessageResponse::from_bytes(&received) {
+            Ok(message) => return Ok(message),
+            Err(BinaryReaderError::Underflow { .. }) => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Err(failure::format_err!("Message is not complete after {} bytes", received.len()))
+}
+
+/// Requests for blocks and operations, which can be delayed by the slow peer
+fn is_data_request(request: &PeerMessage) -> bool {
+    match request {
+        PeerMessage::GetBlockHeaders(_)
+        | PeerMessage::GetOperationsForBlocks(_)
+        | PeerMessage::GetOperationHashesForBlocks(_) => true,
+        _ => false,
+    }
+}
+
+/// Answers of the virtual peer to the request of the node
+fn answer(chain_id: &ChainId, chain: &ScriptedChain, behavior: Behavior, request: &PeerMessage) -> Vec<PeerMessage> {
+    match request {
+        PeerMessage::GetCurrentBranch(message) if &message.chain_id == chain_id && chain.head().header.header.level() > 0 => {
+            vec![PeerMessage::CurrentBranch(CurrentBranchMessage::new(chain_id.clone(), CurrentBranch::new((*chain.head().header.header).clone(), chain.history())))]
+        }
+        PeerMessage::GetCurrentHead(message) if message.chain_id() == chain_id => {
+            vec![PeerMessage::CurrentHead(CurrentHeadMessage::new(chain_id.clone(), (*chain.head().header.header).clone(), Mempool::default()))]
+        }
+        PeerMessage::GetBlockHeaders(message) => {
+            message.get_block_headers().iter()
+                .filter_map(|block_hash| chain.find(block_hash))
+                .map(|block| if behavior == Behavior::Malicious {
+                    forged_header(block)
+                } else {
+                    (*block.header.header).clone()
+                })
+                .map(|header| PeerMessage::BlockHeader(header.into()))
+                .collect()
+        }
+        PeerMessage::GetOperationsForBlocks(message) if behavior != Behavior::WithholdOperations => {
+            message.get_operations_for_blocks().iter()
+                .filter_map(|request| {
+                    let validation_pass: usize = request.validation_pass().try_into().ok()?;
+                    chain.find(request.block_hash())?.operations.get(validation_pass).cloned()
+                })
+                .map(PeerMessage::OperationsForBlocks)
+                .collect()
+        }
+        PeerMessage::GetOperationHashesForBlocks(message) if behavior != Behavior::WithholdOperations => {
+            message.get_operation_hashes_for_blocks().iter()
+                .filter_map(|request| {
+                    let validation_pass: usize = request.validation_pass().try_into().ok()?;
+                    let operations = chain.find(request.hash())?.operations.get(validation_pass)?;
+                    let operation_hashes = operations.operations().iter()
+                        .map(|operation| operation.message_hash())
+                        .collect::<Result<Vec<_>, _>>()
+                        .ok()?;
+                    Some(OperationHashesForBlocksMessage::new(request.clone(), operations.operation_hashes_path().clone(), operation_hashes))
+                })
+                .map(PeerMessage::OperationHashesForBlock)
+                .collect()
+        }
+        PeerMessage::GetProtocols(message) => {
+            message.get_protocols().iter()
+                .filter_map(|protocol_hash| chain.find_protocol(protocol_hash))
+                .map(|protocol| if behavior == Behavior::ForgeProtocols {
+                    forged_protocol(protocol)
+                } else {
+                    protocol.clone()
+                })
+                .map(|protocol| PeerMessage::Protocol(ProtocolMessage::new(protocol)))
+                .collect()
+        }
+        _ => vec![],
+    }
+}
+
+/// Header with the same level and predecessor as `block`, but with different hash
+fn forged_header(block: &ScriptedBlock) -> BlockHeader {
+    let header = &block.header.header;
+    BlockHeaderBuilder::default()
+        .level(header.level())
+        .proto(header.proto())
+        .predecessor(header.predecessor().clone())
+        .timestamp(header.timestamp())
+        .validation_pass(header.validation_pass())
+        .operations_hash(header.operations_hash().clone())
+        .fitness(header.fitness().clone())
+        .context(header.context().clone())
+        .protocol_data(vec![0xFF; 8])
+        .build().unwrap()
+}
+
+/// Protocol with the same components as `protocol`, but with different sources
+fn forged_protocol(protocol: &Protocol) -> Protocol {
+    let components = protocol.components().iter()
+        .map(|component| Component::new(component.name().clone(), component.interface().clone(), "let forged = true".to_string()))
+        .collect();
+    Protocol::new(protocol.expected_env_version(), components)
+}
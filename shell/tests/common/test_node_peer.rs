@@ -0,0 +1,398 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Test node peer, which simulates p2p remote peer, communicates through real p2p socket
+
+use std::net::{Shutdown, SocketAddr};
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::lock::Mutex;
+use futures::StreamExt;
+use slog::{crit, debug, error, info, Logger, warn};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Runtime;
+use tokio::time::timeout;
+
+use networking::p2p::peer;
+use networking::p2p::peer::{Bootstrap, BootstrapOutput, Local, PeerError};
+use networking::p2p::stream::EncryptedMessageWriter;
+use tezos_identity::Identity;
+use tezos_messages::p2p::encoding::ack::NackInfo;
+use tezos_messages::p2p::encoding::prelude::{PeerMessage, PeerMessageResponse};
+use tezos_messages::p2p::encoding::version::NetworkVersion;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(8);
+const READ_TIMEOUT_LONG: Duration = Duration::from_secs(30);
+/// Port which is announced to the node by the connecting test peers
+const DEFAULT_LISTENER_PORT: u16 = 1235;
+
+type HandleMessageCallback = fn(PeerMessageResponse) -> Result<Vec<PeerMessageResponse>, failure::Error>;
+
+pub struct TestNodePeer {
+    pub identity: Identity,
+    run: Arc<AtomicBool>,
+    /// Set after successful bootstrap, cleared when connection is closed
+    connected: Arc<AtomicBool>,
+    /// All messages received from the node
+    received: Arc<RwLock<Vec<PeerMessage>>>,
+    /// Nack with motive received from the node, which refused the connection
+    nack: Arc<RwLock<Option<NackInfo>>>,
+    /// Set, when the bootstrap failed for other reason than nack, e.g. node closed the connection
+    bootstrap_failed: Arc<AtomicBool>,
+    /// Queue of messages to send to the node, messages are sent once the peer is bootstrapped
+    outgoing: UnboundedSender<PeerMessageResponse>,
+}
+
+impl TestNodePeer {
+    /// Connect to the node
+    pub fn connect(
+        name: &'static str,
+        connect_to_node_port: u16,
+        network_version: NetworkVersion,
+        identity: Identity,
+        log: Logger,
+        tokio_runtime: &Runtime,
+        handle_message_callback: HandleMessageCallback) -> TestNodePeer {
+        let server_address = format!("0.0.0.0:{}", connect_to_node_port).parse::<SocketAddr>().expect("Failed to parse server address");
+        Self::connect_to(name, server_address, network_version, identity, log, tokio_runtime, handle_message_callback)
+    }
+
+    /// Connect to the node listening on `server_address`
+    pub fn connect_to(
+        name: &'static str,
+        server_address: SocketAddr,
+        network_version: NetworkVersion,
+        identity: Identity,
+        log: Logger,
+        tokio_runtime: &Runtime,
+        handle_message_callback: HandleMessageCallback) -> TestNodePeer {
+        let tokio_executor = tokio_runtime.handle().clone();
+        let (test_peer, state) = Self::new(identity.clone());
+
+        tokio_executor.spawn(async move {
+            // init socket connection to server node
+            match timeout(CONNECT_TIMEOUT, TcpStream::connect(&server_address)).await {
+                Ok(Ok(stream)) => {
+                    info!(log, "[{}] Connection successful", name; "ip" => server_address);
+                    let bootstrap = Bootstrap::outgoing(
+                        stream,
+                        server_address,
+                        false,
+                        false,
+                    );
+                    Self::bootstrap_and_process(name, bootstrap, DEFAULT_LISTENER_PORT, identity, network_version, state, log, server_address, handle_message_callback).await;
+                }
+                Ok(Err(e)) => {
+                    error!(log, "[{}] Connection failed", name; "ip" => server_address, "reason" => format!("{:?}", e));
+                }
+                Err(_) => {
+                    error!(log, "[{}] Connection timed out", name; "ip" => server_address);
+                }
+            }
+        });
+
+        test_peer
+    }
+
+    /// Listen on `listener_port` and wait for a single incoming connection from the node
+    pub fn listen(
+        name: &'static str,
+        listener_port: u16,
+        network_version: NetworkVersion,
+        identity: Identity,
+        log: Logger,
+        tokio_runtime: &Runtime,
+        handle_message_callback: HandleMessageCallback) -> TestNodePeer {
+        let listener_address = format!("0.0.0.0:{}", listener_port).parse::<SocketAddr>().expect("Failed to parse listener address");
+        Self::listen_on(name, listener_address, network_version, identity, log, tokio_runtime, handle_message_callback)
+    }
+
+    /// Listen on `listener_address` and wait for a single incoming connection from the node
+    pub fn listen_on(
+        name: &'static str,
+        listener_address: SocketAddr,
+        network_version: NetworkVersion,
+        identity: Identity,
+        log: Logger,
+        tokio_runtime: &Runtime,
+        handle_message_callback: HandleMessageCallback) -> TestNodePeer {
+        let listener_port = listener_address.port();
+        // bind immediately, so the node can connect as soon as this method returns
+        let listener = std::net::TcpListener::bind(&listener_address).expect("Failed to bind to address");
+        listener.set_nonblocking(true).expect("Failed to set listener to non-blocking mode");
+        let tokio_executor = tokio_runtime.handle().clone();
+        let (test_peer, state) = Self::new(identity.clone());
+
+        tokio_executor.spawn(async move {
+            let mut listener = TcpListener::from_std(listener).expect("Failed to create listener");
+            info!(log, "[{}] Waiting for incoming connection", name; "port" => listener_port);
+            match listener.accept().await {
+                Ok((stream, address)) => {
+                    info!(log, "[{}] Connection from", name; "ip" => address);
+                    let bootstrap = Bootstrap::incoming(
+                        Arc::new(Mutex::new(Some(stream))),
+                        address,
+                        false,
+                        false,
+                    );
+                    Self::bootstrap_and_process(name, bootstrap, listener_port, identity, network_version, state, log, address, handle_message_callback).await;
+                }
+                Err(e) => {
+                    error!(log, "[{}] Failed to accept connection", name; "reason" => format!("{:?}", e));
+                }
+            }
+        });
+
+        test_peer
+    }
+
+    fn new(identity: Identity) -> (TestNodePeer, PeerProcessingState) {
+        let (outgoing, outgoing_rx) = unbounded();
+        let test_peer = TestNodePeer {
+            identity,
+            run: Arc::new(AtomicBool::new(false)),
+            connected: Arc::new(AtomicBool::new(false)),
+            received: Arc::new(RwLock::new(Vec::new())),
+            nack: Arc::new(RwLock::new(None)),
+            bootstrap_failed: Arc::new(AtomicBool::new(false)),
+            outgoing,
+        };
+        let state = PeerProcessingState {
+            run: test_peer.run.clone(),
+            connected: test_peer.connected.clone(),
+            received: test_peer.received.clone(),
+            nack: test_peer.nack.clone(),
+            bootstrap_failed: test_peer.bootstrap_failed.clone(),
+            outgoing: outgoing_rx,
+        };
+        (test_peer, state)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn bootstrap_and_process(
+        name: &str,
+        bootstrap: Bootstrap,
+        listener_port: u16,
+        identity: Identity,
+        network_version: NetworkVersion,
+        state: PeerProcessingState,
+        log: Logger,
+        peer_address: SocketAddr,
+        handle_message_callback: HandleMessageCallback) {
+        // authenticate
+        let local = Arc::new(Local::new(
+            listener_port,
+            identity.public_key,
+            identity.secret_key,
+            identity.proof_of_work_stamp,
+            0f64,
+            network_version,
+            None,
+        ));
+
+        let bootstrap_result = match peer::bootstrap(bootstrap, local, &log).await {
+            Ok(bootstrap_result) => bootstrap_result,
+            Err(PeerError::NackWithMotiveReceived { nack_info }) => {
+                warn!(log, "[{}] Connection refused by the node", name; "nack_info" => format!("{:?}", &nack_info));
+                *state.nack.write().expect("Failed to lock nack") = Some(nack_info);
+                return;
+            }
+            Err(e) => {
+                warn!(log, "[{}] Failed to bootstrap", name; "reason" => format!("{}", e));
+                state.bootstrap_failed.store(true, Ordering::Release);
+                return;
+            }
+        };
+
+        // process messages
+        state.run.store(true, Ordering::Release);
+        state.connected.store(true, Ordering::Release);
+        Self::begin_process_incoming(name, bootstrap_result, state, log, peer_address, handle_message_callback).await;
+    }
+
+    /// Start to process incoming data
+    async fn begin_process_incoming(
+        name: &str,
+        bootstrap: BootstrapOutput,
+        state: PeerProcessingState,
+        log: Logger,
+        peer_address: SocketAddr,
+        handle_message_callback: HandleMessageCallback) {
+        info!(log, "[{}] Starting to accept messages", name; "ip" => format!("{:?}", &peer_address));
+        let BootstrapOutput(mut rx, tx, ..) = bootstrap;
+        let PeerProcessingState { run, connected, received, outgoing, .. } = state;
+        let tx = Arc::new(Mutex::new(Some(tx)));
+
+        // send queued messages
+        tokio::spawn(Self::begin_process_outgoing(name.to_string(), tx.clone(), outgoing, log.clone()));
+
+        while run.load(Ordering::Acquire) {
+            match timeout(READ_TIMEOUT_LONG, rx.read_message::<PeerMessageResponse>()).await {
+                Ok(res) => match res {
+                    Ok(msg) => {
+                        let msg_type = msg_type(&msg);
+                        info!(log, "[{}] Handle message", name; "ip" => format!("{:?}", &peer_address), "msg_type" => msg_type.clone());
+                        let disconnect = msg.messages().iter().any(|m| matches!(m, PeerMessage::Disconnect));
+                        received.write().expect("Failed to lock received messages").extend(msg.messages().iter().cloned());
+
+                        // apply callback
+                        match handle_message_callback(msg) {
+                            Ok(responses) => {
+                                info!(log, "[{}] Message handled({})", name, !responses.is_empty(); "msg_type" => msg_type);
+                                let mut tx_lock = tx.lock().await;
+                                if let Some(tx) = tx_lock.as_mut() {
+                                    for response in responses {
+                                        // send back response
+                                        tx.write_message(&response).await.unwrap_or_else(|_| panic!("[{}] Failed to send message", name));
+                                    };
+                                }
+                            }
+                            Err(e) => error!(log, "[{}] Failed to handle message", name; "reason" => format!("{:?}", e), "msg_type" => msg_type)
+                        }
+
+                        if disconnect {
+                            info!(log, "[{}] Disconnected by the node", name; "ip" => format!("{:?}", &peer_address));
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        crit!(log, "[{}] Failed to read peer message", name; "reason" => e);
+                        break;
+                    }
+                }
+                Err(_) => {
+                    warn!(log, "[{}] Peer message read timed out", name; "secs" => READ_TIMEOUT_LONG.as_secs());
+                    break;
+                }
+            }
+        }
+
+        debug!(log, "[{}] Shutting down peer connection", name; "ip" => format!("{:?}", &peer_address));
+        connected.store(false, Ordering::Release);
+        let mut tx_lock = tx.lock().await;
+        if let Some(tx) = tx_lock.take() {
+            let socket = rx.unsplit(tx);
+            match socket.shutdown(Shutdown::Both) {
+                Ok(()) => debug!(log, "[{}] Connection shutdown successful", name; "socket" => format!("{:?}", socket)),
+                Err(err) => debug!(log, "[{}] Failed to shutdown connection", name; "err" => format!("{:?}", err), "socket" => format!("{:?}", socket)),
+            }
+        }
+
+        info!(log, "[{}] Stopped to accept messages", name; "ip" => format!("{:?}", &peer_address));
+    }
+
+    /// Send messages queued by [send_message](TestNodePeer::send_message) until connection is closed
+    async fn begin_process_outgoing(
+        name: String,
+        tx: Arc<Mutex<Option<EncryptedMessageWriter>>>,
+        mut outgoing: UnboundedReceiver<PeerMessageResponse>,
+        log: Logger) {
+        while let Some(msg) = outgoing.next().await {
+            let mut tx_lock = tx.lock().await;
+            match tx_lock.as_mut() {
+                Some(tx) => if let Err(e) = tx.write_message(&msg).await {
+                    warn!(log, "[{}] Failed to send message", name; "reason" => e);
+                    break;
+                },
+                None => break,
+            }
+        }
+    }
+
+    /// Queue message to be sent to the node
+    pub fn send_message(&self, msg: PeerMessageResponse) {
+        self.outgoing.unbounded_send(msg).expect("Failed to queue message");
+    }
+
+    /// Returns true, if peer is bootstrapped and connection was not closed yet
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Acquire)
+    }
+
+    /// Returns nack info, if the node refused the connection
+    pub fn nack_info(&self) -> Option<NackInfo> {
+        self.nack.read().expect("Failed to lock nack").clone()
+    }
+
+    /// Returns true, if the bootstrap failed without nack, e.g. node closed the connection
+    pub fn is_bootstrap_failed(&self) -> bool {
+        self.bootstrap_failed.load(Ordering::Acquire)
+    }
+
+    /// Returns all messages received from the node
+    pub fn received_messages(&self) -> Vec<PeerMessage> {
+        self.received.read().expect("Failed to lock received messages").clone()
+    }
+
+    /// Wait until condition is satisfied for this peer
+    pub fn wait_for<F>(&self, marker: &str, condition: F, (timeout, delay): (Duration, Duration)) -> Result<(), failure::Error>
+        where F: Fn(&TestNodePeer) -> bool
+    {
+        let start = SystemTime::now();
+        loop {
+            if condition(self) {
+                break Ok(());
+            }
+
+            // kind of simple retry policy
+            if start.elapsed()?.le(&timeout) {
+                thread::sleep(delay);
+            } else {
+                break Err(failure::format_err!("wait_for({}) - timeout (timeout: {:?}, delay: {:?}) exceeded!", marker, timeout, delay));
+            }
+        }
+    }
+
+    pub fn stop(&mut self) {
+        self.run.store(false, Ordering::Release);
+    }
+}
+
+impl Drop for TestNodePeer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Shared state used by the connection processing task
+struct PeerProcessingState {
+    run: Arc<AtomicBool>,
+    connected: Arc<AtomicBool>,
+    received: Arc<RwLock<Vec<PeerMessage>>>,
+    nack: Arc<RwLock<Option<NackInfo>>>,
+    bootstrap_failed: Arc<AtomicBool>,
+    outgoing: UnboundedReceiver<PeerMessageResponse>,
+}
+
+fn msg_type(msg: &PeerMessageResponse) -> String {
+    msg.messages()
+        .iter()
+        .map(|m| match m {
+            PeerMessage::Disconnect => "Disconnect",
+            PeerMessage::Advertise(_) => "Advertise",
+            PeerMessage::SwapRequest(_) => "SwapRequest",
+            PeerMessage::SwapAck(_) => "SwapAck",
+            PeerMessage::Bootstrap => "Bootstrap",
+            PeerMessage::GetCurrentBranch(_) => "GetCurrentBranch",
+            PeerMessage::CurrentBranch(_) => "CurrentBranch",
+            PeerMessage::Deactivate(_) => "Deactivate",
+            PeerMessage::GetCurrentHead(_) => "GetCurrentHead",
+            PeerMessage::CurrentHead(_) => "CurrentHead",
+            PeerMessage::GetBlockHeaders(_) => "GetBlockHeaders",
+            PeerMessage::BlockHeader(_) => "BlockHeader",
+            PeerMessage::GetOperations(_) => "GetOperations",
+            PeerMessage::Operation(_) => "Operation",
+            PeerMessage::GetProtocols(_) => "GetProtocols",
+            PeerMessage::Protocol(_) => "Protocol",
+            PeerMessage::GetOperationHashesForBlocks(_) => "GetOperationHashesForBlocks",
+            PeerMessage::OperationHashesForBlock(_) => "OperationHashesForBlock",
+            PeerMessage::GetOperationsForBlocks(_) => "GetOperationsForBlocks",
+            PeerMessage::OperationsForBlocks(_) => "OperationsForBlocks",
+        })
+        .collect::<Vec<&str>>()
+        .join(",")
+}
@@ -0,0 +1,745 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender as QueueSender};
+use std::thread;
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use riker::actors::*;
+use riker::system::SystemBuilder;
+use slog::{Logger, warn};
+use tokio::net::TcpStream;
+use tokio::runtime::Runtime;
+
+use crypto::proof_of_work::check_proof_of_work;
+use networking::clock::{ClockRef, system_clock, VirtualClock};
+use networking::p2p::network_channel::{NetworkChannel, NetworkChannelMsg, NetworkChannelTopic};
+use networking::p2p::peer::{Bootstrap, CheckKeepalive, KeepaliveConfig, Peer, PeerRef};
+use networking::p2p::rate_limit::{OutboundBandwidth, PeerRateLimiting, RateLimits};
+use networking::p2p::stats::{NetworkStats, PeerStats};
+use shell::peer_manager::{ConnectToPeer, P2p, PeerDiversityLimits, PeerManager, PeerManagerRef};
+use shell::PeerConnectionThreshold;
+use shell::shell_channel::{ShellChannel, ShellChannelRef, ShellChannelTopic, ShuttingDown};
+use storage::tests_common::TmpStorage;
+use tezos_identity::Identity;
+use tezos_messages::p2p::encoding::ack::NackMotive;
+use tezos_messages::p2p::encoding::block_header::BlockHeaderBuilder;
+use tezos_messages::p2p::encoding::prelude::{CurrentHeadMessage, Mempool, PeerMessage, PeerMessageResponse, SwapMessage};
+use tezos_messages::p2p::encoding::version::NetworkVersion;
+
+use crate::common::barrier::Barrier;
+use crate::common::test_node_peer::TestNodePeer;
+
+mod common;
+
+lazy_static! {
+    pub static ref NETWORK_VERSION: NetworkVersion = NetworkVersion::new("TEST_CHAIN".to_string(), 0, 0);
+    /// Version of the peers, which understand nack with motive
+    pub static ref NACK_NETWORK_VERSION: NetworkVersion = NetworkVersion::new("TEST_CHAIN".to_string(), 0, 1);
+    /// Identity of the listening peer, which is offered in the swap ack
+    pub static ref SWAP_PEER_IDENTITY: Identity = Identity::generate(0f64);
+}
+
+/// Port announced by the connecting test peers, see [TestNodePeer::connect]
+const TEST_PEER_LISTENER_PORT: u16 = 1235;
+const SWAP_PEER_PORT: u16 = 1261;
+/// Chain id of the keepalive pings
+const PING_CHAIN_ID: [u8; 4] = [0x01, 0x02, 0x03, 0x04];
+
+const WAIT_TIMEOUT: (Duration, Duration) = (Duration::from_secs(40), Duration::from_millis(250));
+
+#[test]
+fn test_accept_swap_request() -> Result<(), failure::Error> {
+    let log = common::create_logger(common::log_level());
+    let node_port = 1250;
+    let node = PeerManagerNode::start("test_accept_swap_request", node_port, PeerConnectionThreshold::new(0, 10), PeerDiversityLimits::unlimited(), 0f64, log.clone())?;
+
+    // peer which will be swapped
+    let peer_b = TestNodePeer::connect(
+        "TEST_PEER_B", node_port, NETWORK_VERSION.clone(), Identity::generate(0f64), log.clone(), &node.tokio_runtime, no_response,
+    );
+    peer_b.wait_for("peer_b_connected", TestNodePeer::is_connected, WAIT_TIMEOUT)?;
+    // let peer manager process the bootstrap
+    thread::sleep(Duration::from_secs(1));
+
+    // peer which is proposed in the swap request
+    let peer_c = TestNodePeer::listen(
+        "TEST_PEER_C", 1251, NETWORK_VERSION.clone(), Identity::generate(0f64), log.clone(), &node.tokio_runtime, no_response,
+    );
+
+    // peer which asks for swap
+    let peer_a = TestNodePeer::connect(
+        "TEST_PEER_A", node_port, NETWORK_VERSION.clone(), Identity::generate(0f64), log.clone(), &node.tokio_runtime, no_response,
+    );
+    peer_a.send_message(PeerMessage::SwapRequest(SwapMessage::new(&format!("127.0.0.1:{}", 1251).parse::<SocketAddr>()?, &peer_c.identity.peer_id)).into());
+    // second swap request is over the rate limit
+    peer_a.send_message(PeerMessage::SwapRequest(SwapMessage::new(&format!("127.0.0.1:{}", 1252).parse::<SocketAddr>()?, &Identity::generate(0f64).peer_id)).into());
+
+    // node answers with the peer it gives away
+    peer_a.wait_for("swap_ack", |peer| !swap_acks(peer).is_empty(), WAIT_TIMEOUT)?;
+    let swap_ack = swap_acks(&peer_a).remove(0);
+    assert_eq!(&format!("127.0.0.1:{}", TEST_PEER_LISTENER_PORT), swap_ack.point());
+    assert_eq!(&peer_b.identity.peer_id, swap_ack.peer_id());
+
+    // node connects to the proposed point and disconnects the swapped peer
+    peer_c.wait_for("peer_c_connected", TestNodePeer::is_connected, WAIT_TIMEOUT)?;
+    peer_b.wait_for("peer_b_disconnected", |peer| !peer.is_connected(), WAIT_TIMEOUT)?;
+    assert!(peer_b.received_messages().iter().any(|message| matches!(message, PeerMessage::Disconnect)));
+    assert!(peer_a.is_connected());
+
+    // rate limited swap request was ignored
+    thread::sleep(Duration::from_secs(2));
+    assert_eq!(1, swap_acks(&peer_a).len());
+
+    drop(node);
+    Ok(())
+}
+
+#[test]
+fn test_propose_swap_over_high_threshold() -> Result<(), failure::Error> {
+    let log = common::create_logger(common::log_level());
+    let node_port = 1260;
+    let node = PeerManagerNode::start("test_propose_swap_over_high_threshold", node_port, PeerConnectionThreshold::new(0, 2), PeerDiversityLimits::unlimited(), 0f64, log.clone())?;
+
+    // peer which is offered by the peer accepting the swap
+    let peer_c = TestNodePeer::listen(
+        "TEST_PEER_C", SWAP_PEER_PORT, NETWORK_VERSION.clone(), SWAP_PEER_IDENTITY.clone(), log.clone(), &node.tokio_runtime, no_response,
+    );
+
+    // reach high threshold
+    let peer_a = TestNodePeer::connect(
+        "TEST_PEER_A", node_port, NETWORK_VERSION.clone(), Identity::generate(0f64), log.clone(), &node.tokio_runtime, accept_swap_request,
+    );
+    let peer_b = TestNodePeer::connect(
+        "TEST_PEER_B", node_port, NETWORK_VERSION.clone(), Identity::generate(0f64), log.clone(), &node.tokio_runtime, accept_swap_request,
+    );
+    peer_a.wait_for("peer_a_connected", TestNodePeer::is_connected, WAIT_TIMEOUT)?;
+    peer_b.wait_for("peer_b_connected", TestNodePeer::is_connected, WAIT_TIMEOUT)?;
+
+    // node asks one peer to swap the other one
+    peer_a.wait_for("swap_request", |peer| !swap_requests(peer).is_empty() || !swap_requests(&peer_b).is_empty(), WAIT_TIMEOUT)?;
+    let (recipient, proposed) = if swap_requests(&peer_a).is_empty() {
+        (&peer_b, &peer_a)
+    } else {
+        (&peer_a, &peer_b)
+    };
+    let requests = swap_requests(recipient);
+    assert_eq!(1, requests.len());
+    assert_eq!(&format!("127.0.0.1:{}", TEST_PEER_LISTENER_PORT), requests[0].point());
+    assert_eq!(&proposed.identity.peer_id, requests[0].peer_id());
+
+    // after swap ack, node connects to the offered point and disconnects the proposed peer
+    peer_c.wait_for("peer_c_connected", TestNodePeer::is_connected, WAIT_TIMEOUT)?;
+    proposed.wait_for("proposed_disconnected", |peer| !peer.is_connected(), WAIT_TIMEOUT)?;
+    assert!(recipient.is_connected());
+
+    drop(node);
+    Ok(())
+}
+
+#[test]
+fn test_connections_per_ip_limit() -> Result<(), failure::Error> {
+    let log = common::create_logger(common::log_level());
+    let node_port = 1270;
+    // test peers connect from the loopback
+    let peer_diversity = PeerDiversityLimits {
+        max_per_ip: 2,
+        limit_local_addresses: true,
+        ..PeerDiversityLimits::unlimited()
+    };
+    let node = PeerManagerNode::start("test_connections_per_ip_limit", node_port, PeerConnectionThreshold::new(0, 10), peer_diversity, 0f64, log.clone())?;
+
+    // node is asked to connect to three peers with the same IP address
+    let listening_peers = (1271..=1273)
+        .map(|port| TestNodePeer::listen("TEST_PEER_LISTEN", port, NETWORK_VERSION.clone(), Identity::generate(0f64), log.clone(), &node.tokio_runtime, no_response))
+        .collect::<Vec<_>>();
+    for port in 1271..=1273 {
+        node.peer_manager.tell(ConnectToPeer { address: format!("127.0.0.1:{}", port).parse()? }, None);
+    }
+    // third connection is refused by peer manager, before it is even opened
+    node.barrier.synchronize(&node.peer_manager);
+    listening_peers[0].wait_for("first_connected", TestNodePeer::is_connected, WAIT_TIMEOUT)?;
+    listening_peers[1].wait_for("second_connected", TestNodePeer::is_connected, WAIT_TIMEOUT)?;
+    assert!(!listening_peers[2].is_connected());
+
+    // incoming connection from the same IP address is closed during bootstrap
+    let connecting_peer = TestNodePeer::connect(
+        "TEST_PEER_CONNECT", node_port, NETWORK_VERSION.clone(), Identity::generate(0f64), log.clone(), &node.tokio_runtime, no_response,
+    );
+    connecting_peer.wait_for("connecting_peer_refused", TestNodePeer::is_bootstrap_failed, WAIT_TIMEOUT)?;
+    assert!(!connecting_peer.is_connected());
+
+    drop(node);
+    Ok(())
+}
+
+#[test]
+fn test_remote_proof_of_work() -> Result<(), failure::Error> {
+    let log = common::create_logger(common::log_level());
+    let node_port = 1275;
+    let expected_pow = 8f64;
+    let node = PeerManagerNode::start("test_remote_proof_of_work", node_port, PeerConnectionThreshold::new(0, 10), PeerDiversityLimits::unlimited(), expected_pow, log.clone())?;
+
+    // peer with stamp meeting expected pow is accepted
+    let valid_peer = TestNodePeer::connect(
+        "TEST_PEER_VALID_POW", node_port, NETWORK_VERSION.clone(), Identity::generate(expected_pow), log.clone(), &node.tokio_runtime, no_response,
+    );
+    valid_peer.wait_for("valid_pow_connected", TestNodePeer::is_connected, WAIT_TIMEOUT)?;
+
+    // peer with stamp not meeting expected pow is rejected
+    let invalid_peer = TestNodePeer::connect(
+        "TEST_PEER_INVALID_POW", node_port, NETWORK_VERSION.clone(), identity_without_pow(expected_pow), log.clone(), &node.tokio_runtime, no_response,
+    );
+    thread::sleep(Duration::from_secs(2));
+    assert!(!invalid_peer.is_connected());
+
+    drop(node);
+    Ok(())
+}
+
+#[test]
+fn test_refuse_connection_over_high_threshold() -> Result<(), failure::Error> {
+    let log = common::create_logger(common::log_level());
+    let node_port = 1280;
+    let node = PeerManagerNode::start("test_refuse_connection_over_high_threshold", node_port, PeerConnectionThreshold::new(0, 1), PeerDiversityLimits::unlimited(), 0f64, log.clone())?;
+
+    // reach high threshold
+    let peer_a = TestNodePeer::connect(
+        "TEST_PEER_A", node_port, NETWORK_VERSION.clone(), Identity::generate(0f64), log.clone(), &node.tokio_runtime, no_response,
+    );
+    peer_a.wait_for("peer_a_connected", TestNodePeer::is_connected, WAIT_TIMEOUT)?;
+    // let peer manager process the bootstrap
+    thread::sleep(Duration::from_secs(1));
+
+    // next peer is refused with nack and receives connected peer instead
+    let peer_b = TestNodePeer::connect(
+        "TEST_PEER_B", node_port, NACK_NETWORK_VERSION.clone(), Identity::generate(0f64), log.clone(), &node.tokio_runtime, no_response,
+    );
+    peer_b.wait_for("peer_b_refused", |peer| peer.nack_info().is_some(), WAIT_TIMEOUT)?;
+    let nack_info = peer_b.nack_info().unwrap();
+    assert_eq!(&NackMotive::TooManyConnections, nack_info.motive());
+    assert_eq!(&vec![format!("127.0.0.1:{}", TEST_PEER_LISTENER_PORT)], nack_info.potential_peers_to_connect());
+    assert!(!peer_b.is_connected());
+    assert!(peer_a.is_connected());
+
+    drop(node);
+    Ok(())
+}
+
+#[test]
+fn test_refuse_already_connected_peer() -> Result<(), failure::Error> {
+    let log = common::create_logger(common::log_level());
+    let node_port = 1285;
+    let node = PeerManagerNode::start("test_refuse_already_connected_peer", node_port, PeerConnectionThreshold::new(0, 10), PeerDiversityLimits::unlimited(), 0f64, log.clone())?;
+    let identity = Identity::generate(0f64);
+
+    let peer = TestNodePeer::connect(
+        "TEST_PEER", node_port, NACK_NETWORK_VERSION.clone(), identity.clone(), log.clone(), &node.tokio_runtime, no_response,
+    );
+    peer.wait_for("peer_connected", TestNodePeer::is_connected, WAIT_TIMEOUT)?;
+
+    // second connection with the same identity is refused
+    let duplicate_peer = TestNodePeer::connect(
+        "TEST_PEER_DUPLICATE", node_port, NACK_NETWORK_VERSION.clone(), identity, log.clone(), &node.tokio_runtime, no_response,
+    );
+    duplicate_peer.wait_for("duplicate_peer_refused", |peer| peer.nack_info().is_some(), WAIT_TIMEOUT)?;
+    assert_eq!(&NackMotive::AlreadyConnected, duplicate_peer.nack_info().unwrap().motive());
+    assert!(peer.is_connected());
+
+    drop(node);
+    Ok(())
+}
+
+#[test]
+fn test_advertise_listener_addresses_of_bootstrapped_peers() -> Result<(), failure::Error> {
+    let log = common::create_logger(common::log_level());
+    let node_port = 1302;
+    let node = PeerManagerNode::start("test_advertise_listener_addresses_of_bootstrapped_peers", node_port, PeerConnectionThreshold::new(0, 10), PeerDiversityLimits::unlimited(), 0f64, log.clone())?;
+
+    // incoming peers connect from ephemeral ports
+    let peer_a = TestNodePeer::connect(
+        "TEST_PEER_A", node_port, NETWORK_VERSION.clone(), Identity::generate(0f64), log.clone(), &node.tokio_runtime, no_response,
+    );
+    peer_a.wait_for("peer_a_connected", TestNodePeer::is_connected, WAIT_TIMEOUT)?;
+    let peer_b = TestNodePeer::connect(
+        "TEST_PEER_B", node_port, NETWORK_VERSION.clone(), Identity::generate(0f64), log.clone(), &node.tokio_runtime, no_response,
+    );
+    peer_b.wait_for("peer_b_connected", TestNodePeer::is_connected, WAIT_TIMEOUT)?;
+    // let peer manager process the bootstrap
+    thread::sleep(Duration::from_secs(1));
+
+    // other peer is advertised with its listener address
+    peer_a.send_message(PeerMessage::Bootstrap.into());
+    peer_a.wait_for("peer_a_advertised", |peer| peer.received_messages().iter().any(|message| matches!(message, PeerMessage::Advertise(_))), WAIT_TIMEOUT)?;
+    let advertised = peer_a.received_messages().into_iter()
+        .filter_map(|message| match message {
+            PeerMessage::Advertise(message) => Some(message.id().clone()),
+            _ => None,
+        })
+        .next()
+        .unwrap();
+    assert_eq!(vec![format!("127.0.0.1:{}", TEST_PEER_LISTENER_PORT)], advertised);
+
+    drop(node);
+    Ok(())
+}
+
+#[test]
+fn test_ipv6_connections() -> Result<(), failure::Error> {
+    let log = common::create_logger(common::log_level());
+    let node_port = 1290;
+    let node = PeerManagerNode::start_on("test_ipv6_connections", IpAddr::V6(Ipv6Addr::LOCALHOST), node_port, PeerConnectionThreshold::new(0, 2), PeerDiversityLimits::unlimited(), 0f64, log.clone())?;
+
+    // outgoing connection
+    let listening_peer = TestNodePeer::listen_on(
+        "TEST_PEER_LISTEN", "[::1]:1291".parse()?, NETWORK_VERSION.clone(), Identity::generate(0f64), log.clone(), &node.tokio_runtime, no_response,
+    );
+    node.peer_manager.tell(ConnectToPeer { address: "[::1]:1291".parse()? }, None);
+    listening_peer.wait_for("listening_peer_connected", TestNodePeer::is_connected, WAIT_TIMEOUT)?;
+
+    // incoming connection
+    let node_address: SocketAddr = format!("[::1]:{}", node_port).parse()?;
+    let peer_a = TestNodePeer::connect_to(
+        "TEST_PEER_A", node_address, NETWORK_VERSION.clone(), Identity::generate(0f64), log.clone(), &node.tokio_runtime, no_response,
+    );
+    peer_a.wait_for("peer_a_connected", TestNodePeer::is_connected, WAIT_TIMEOUT)?;
+    // peer manager answers the bootstrap message only after it processed the connection of the peer
+    peer_a.send_message(PeerMessage::Bootstrap.into());
+    peer_a.wait_for("peer_a_advertised", |peer| peer.received_messages().iter().any(|message| matches!(message, PeerMessage::Advertise(_))), WAIT_TIMEOUT)?;
+
+    // IPv6 points of connected peers are advertised in nack
+    let peer_b = TestNodePeer::connect_to(
+        "TEST_PEER_B", node_address, NACK_NETWORK_VERSION.clone(), Identity::generate(0f64), log.clone(), &node.tokio_runtime, no_response,
+    );
+    peer_b.wait_for("peer_b_refused", |peer| peer.nack_info().is_some(), WAIT_TIMEOUT)?;
+    let mut potential_peers = peer_b.nack_info().unwrap().potential_peers_to_connect().clone();
+    potential_peers.sort();
+    assert_eq!(vec![format!("[::1]:{}", TEST_PEER_LISTENER_PORT), "[::1]:1291".to_string()], potential_peers);
+
+    drop(node);
+    Ok(())
+}
+
+#[test]
+fn test_private_node_accepts_only_trusted_peers() -> Result<(), failure::Error> {
+    let log = common::create_logger(common::log_level());
+    let node_port = 1295;
+    let trusted_identity = Identity::generate(0f64);
+    let node = PeerManagerNode::start_private("test_private_node_accepts_only_trusted_peers", node_port, vec![trusted_identity.peer_id.clone()], log.clone())?;
+
+    // trusted peer is accepted
+    let trusted_peer = TestNodePeer::connect(
+        "TEST_PEER_TRUSTED", node_port, NETWORK_VERSION.clone(), trusted_identity, log.clone(), &node.tokio_runtime, no_response,
+    );
+    trusted_peer.wait_for("trusted_peer_connected", TestNodePeer::is_connected, WAIT_TIMEOUT)?;
+
+    // other peers are refused
+    let untrusted_peer = TestNodePeer::connect(
+        "TEST_PEER_UNTRUSTED", node_port, NACK_NETWORK_VERSION.clone(), Identity::generate(0f64), log.clone(), &node.tokio_runtime, no_response,
+    );
+    untrusted_peer.wait_for("untrusted_peer_refused", |peer| peer.nack_info().is_some(), WAIT_TIMEOUT)?;
+    assert_eq!(&NackMotive::NoMotive, untrusted_peer.nack_info().unwrap().motive());
+    assert!(untrusted_peer.nack_info().unwrap().potential_peers_to_connect().is_empty());
+
+    // node does not connect to untrusted points
+    let listening_peer = TestNodePeer::listen(
+        "TEST_PEER_LISTEN", 1296, NETWORK_VERSION.clone(), Identity::generate(0f64), log.clone(), &node.tokio_runtime, no_response,
+    );
+    node.peer_manager.tell(ConnectToPeer { address: "127.0.0.1:1296".parse()? }, None);
+    node.barrier.synchronize(&node.peer_manager);
+    assert!(!listening_peer.is_connected());
+    assert!(!listening_peer.is_bootstrap_failed());
+
+    // and does not advertise its peers
+    trusted_peer.send_message(PeerMessage::Bootstrap.into());
+    node.wait_for_received("bootstrap_received", |message| matches!(message, PeerMessage::Bootstrap))?;
+    node.barrier.flush_channels();
+    node.barrier.synchronize(&node.peer_manager);
+    assert!(!trusted_peer.received_messages().iter().any(|message| matches!(message, PeerMessage::Advertise(_))));
+    assert!(trusted_peer.is_connected());
+
+    drop(node);
+    Ok(())
+}
+
+#[test]
+fn test_idle_responsive_peer_is_kept_connected() -> Result<(), failure::Error> {
+    let log = common::create_logger(common::log_level());
+    let node_port = 1297;
+    let clock = Arc::new(VirtualClock::new());
+    let keepalive = KeepaliveConfig {
+        ping_chain_id: Some(PING_CHAIN_ID.to_vec()),
+        ping_interval: Duration::from_secs(10),
+        dead_timeout: Duration::from_secs(30),
+    };
+    let node = PeerManagerNode::start_with("test_idle_responsive_peer_is_kept_connected", P2p {
+        listener_port: node_port,
+        bind_address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        bootstrap_lookup_addresses: vec![],
+        disable_bootstrap_lookup: true,
+        disable_mempool: false,
+        private_node: false,
+        expected_pow: 0f64,
+        initial_peers: vec![],
+        trusted_peer_ids: vec![],
+        peer_threshold: PeerConnectionThreshold::new(0, 10),
+        peer_diversity: PeerDiversityLimits::unlimited(),
+        rate_limits: RateLimits::unlimited(),
+        capture: None,
+        keepalive,
+    }, clock.clone(), log.clone())?;
+
+    let test_peer = TestNodePeer::connect(
+        "TEST_PEER_IDLE", node_port, NETWORK_VERSION.clone(), Identity::generate(0f64), log.clone(), &node.tokio_runtime, answer_ping,
+    );
+    test_peer.wait_for("test_peer_connected", TestNodePeer::is_connected, WAIT_TIMEOUT)?;
+    test_peer.send_message(PeerMessage::Bootstrap.into());
+    let peer = node.wait_for_received("bootstrap_received", |message| matches!(message, PeerMessage::Bootstrap))?;
+
+    // idle peer is pinged and its answer is received
+    clock.advance(Duration::from_secs(11));
+    peer.tell(CheckKeepalive, None);
+    node.wait_for_received("first_ping_answered", |message| matches!(message, PeerMessage::CurrentHead(_)))?;
+    assert!(test_peer.received_messages().iter().any(|message| matches!(message, PeerMessage::GetCurrentHead(_))));
+
+    // dead timeout has elapsed since the connection, but the peer answered in the meantime
+    clock.advance(Duration::from_secs(25));
+    peer.tell(CheckKeepalive, None);
+    node.wait_for_received("second_ping_answered", |message| matches!(message, PeerMessage::CurrentHead(_)))?;
+    node.barrier.synchronize(&peer);
+    assert!(test_peer.is_connected());
+
+    drop(node);
+    Ok(())
+}
+
+#[test]
+fn test_peer_stops_itself_without_peer_manager() -> Result<(), failure::Error> {
+    let log = common::create_logger(common::log_level());
+    let peer_port = 1298;
+    let tokio_runtime = tokio::runtime::Builder::new()
+        .threaded_scheduler()
+        .enable_all()
+        .build()?;
+    let actor_system = SystemBuilder::new().name("test_peer_stops_itself_without_peer_manager").log(log.clone()).create().expect("Failed to create actor system");
+    let network_channel = NetworkChannel::actor(&actor_system).expect("Failed to create network channel");
+    let (terminated_tx, terminated) = channel();
+    actor_system.actor_of_props::<TerminatedListener>(
+        "terminated-listener",
+        Props::new_args(Arc::new(Mutex::new(terminated_tx))),
+    ).map_err(|e| failure::format_err!("Failed to create terminated-listener, reason: {:?}", e))?;
+
+    let test_peer = TestNodePeer::listen(
+        "TEST_PEER_FLOODING", peer_port, NETWORK_VERSION.clone(), Identity::generate(0f64), log.clone(), &tokio_runtime, no_response,
+    );
+    // every request over the limit disconnects the peer
+    let rate_limits = RateLimits {
+        other_messages_per_sec: 1,
+        max_throttle_duration: Duration::from_secs(0),
+        ..RateLimits::unlimited()
+    };
+    let identity = Identity::generate(0f64);
+    let address: SocketAddr = format!("127.0.0.1:{}", peer_port).parse()?;
+    let peer = Peer::actor(
+        &actor_system,
+        network_channel,
+        TEST_PEER_LISTENER_PORT,
+        &identity.public_key,
+        &identity.secret_key,
+        &identity.proof_of_work_stamp,
+        0f64,
+        NETWORK_VERSION.clone(),
+        None,
+        KeepaliveConfig::default(),
+        tokio_runtime.handle().clone(),
+        &address,
+        PeerRateLimiting::new(rate_limits, OutboundBandwidth::new(0)),
+        PeerStats::new(&NetworkStats::default()),
+        system_clock(),
+    ).expect("Failed to create peer");
+    let stream = tokio_runtime.block_on(TcpStream::connect(address))?;
+    peer.tell(Bootstrap::outgoing(stream, address, false, false), None);
+    test_peer.wait_for("test_peer_connected", TestNodePeer::is_connected, WAIT_TIMEOUT)?;
+
+    // peer is disconnected because of the flood and its actor is stopped, although there is no peer manager
+    for _ in 0..10 {
+        test_peer.send_message(PeerMessage::Bootstrap.into());
+    }
+    let (timeout, _) = WAIT_TIMEOUT;
+    loop {
+        match terminated.recv_timeout(timeout) {
+            Ok(name) if name == peer.name() => break,
+            Ok(_) => continue,
+            Err(e) => return Err(failure::format_err!("Peer actor was not stopped, reason: {:?}", e)),
+        }
+    }
+    test_peer.wait_for("test_peer_disconnected", |peer| !peer.is_connected(), WAIT_TIMEOUT)?;
+
+    Ok(())
+}
+
+/// Generate identity, which proof of work stamp does not meet `expected_pow`
+fn identity_without_pow(expected_pow: f64) -> Identity {
+    loop {
+        let identity = Identity::generate(0f64);
+        let data = hex::decode(format!("{}{}", identity.public_key, identity.proof_of_work_stamp)).expect("Invalid identity");
+        if check_proof_of_work(&data, expected_pow).is_err() {
+            return identity;
+        }
+    }
+}
+
+fn no_response(_: PeerMessageResponse) -> Result<Vec<PeerMessageResponse>, failure::Error> {
+    Ok(vec![])
+}
+
+/// Answer every keepalive ping with a current head
+fn answer_ping(msg: PeerMessageResponse) -> Result<Vec<PeerMessageResponse>, failure::Error> {
+    let mut responses = vec![];
+    for message in msg.messages() {
+        if let PeerMessage::GetCurrentHead(_) = message {
+            let header = BlockHeaderBuilder::default()
+                .level(1)
+                .proto(1)
+                .predecessor(vec![0; 32])
+                .timestamp(0)
+                .validation_pass(0)
+                .operations_hash(vec![0; 32])
+                .fitness(vec![])
+                .context(vec![0; 32])
+                .protocol_data(vec![])
+                .build().map_err(|e| failure::format_err!("Failed to build block header, reason: {}", e))?;
+            let current_head = CurrentHeadMessage::new(PING_CHAIN_ID.to_vec(), header, Mempool::default());
+            responses.push(PeerMessage::CurrentHead(current_head).into());
+        }
+    }
+    Ok(responses)
+}
+
+/// Answer every swap request with [SWAP_PEER_IDENTITY] listening on [SWAP_PEER_PORT]
+fn accept_swap_request(msg: PeerMessageResponse) -> Result<Vec<PeerMessageResponse>, failure::Error> {
+    let mut responses = vec![];
+    for message in msg.messages() {
+        if let PeerMessage::SwapRequest(_) = message {
+            let ack = SwapMessage::new(&format!("127.0.0.1:{}", SWAP_PEER_PORT).parse::<SocketAddr>()?, &SWAP_PEER_IDENTITY.peer_id);
+            responses.push(PeerMessage::SwapAck(ack).into());
+        }
+    }
+    Ok(responses)
+}
+
+fn swap_requests(peer: &TestNodePeer) -> Vec<SwapMessage> {
+    peer.received_messages()
+        .into_iter()
+        .filter_map(|message| match message {
+            PeerMessage::SwapRequest(swap) => Some(swap),
+            _ => None,
+        })
+        .collect()
+}
+
+fn swap_acks(peer: &TestNodePeer) -> Vec<SwapMessage> {
+    peer.received_messages()
+        .into_iter()
+        .filter_map(|message| match message {
+            PeerMessage::SwapAck(swap) => Some(swap),
+            _ => None,
+        })
+        .collect()
+}
+
+type TerminatedActors = Arc<Mutex<QueueSender<String>>>;
+
+/// Forwards names of terminated actors to the test
+struct TerminatedListener {
+    terminated: TerminatedActors,
+}
+
+impl ActorFactoryArgs<TerminatedActors> for TerminatedListener {
+    fn create_args(terminated: TerminatedActors) -> Self {
+        TerminatedListener { terminated }
+    }
+}
+
+impl Actor for TerminatedListener {
+    type Msg = SystemEvent;
+
+    fn pre_start(&mut self, ctx: &Context<Self::Msg>) {
+        ctx.system.sys_events().tell(
+            Subscribe {
+                topic: SysTopic::ActorTerminated.into(),
+                actor: Box::new(ctx.myself()),
+            }, None);
+    }
+
+    fn recv(&mut self, _: &Context<Self::Msg>, msg: Self::Msg, _: Sender) {
+        if let SystemEvent::ActorTerminated(terminated) = msg {
+            let _ = self.terminated.lock().unwrap().send(terminated.actor.name().to_string());
+        }
+    }
+}
+
+type ReceivedMessages = Arc<Mutex<QueueSender<(PeerRef, PeerMessage)>>>;
+
+/// Forwards messages, which the node received from its peers, to the test
+struct ReceivedMessagesListener {
+    received: ReceivedMessages,
+}
+
+impl ActorFactoryArgs<ReceivedMessages> for ReceivedMessagesListener {
+    fn create_args(received: ReceivedMessages) -> Self {
+        ReceivedMessagesListener { received }
+    }
+}
+
+impl Actor for ReceivedMessagesListener {
+    type Msg = NetworkChannelMsg;
+
+    fn recv(&mut self, _: &Context<Self::Msg>, msg: Self::Msg, _: Sender) {
+        if let NetworkChannelMsg::PeerMessageReceived(received) = msg {
+            let received_tx = self.received.lock().unwrap();
+            for message in received.message.messages() {
+                let _ = received_tx.send((received.peer.clone(), message.clone()));
+            }
+        }
+    }
+}
+
+/// Runs just peer manager with its channels, without any chain processing
+struct PeerManagerNode {
+    log: Logger,
+    peer_manager: PeerManagerRef,
+    barrier: Barrier,
+    received: Receiver<(PeerRef, PeerMessage)>,
+    shell_channel: ShellChannelRef,
+    actor_system: ActorSystem,
+    tokio_runtime: Runtime,
+    _tmp_storage: TmpStorage,
+}
+
+impl PeerManagerNode {
+    fn start(name: &str, listener_port: u16, peer_threshold: PeerConnectionThreshold, peer_diversity: PeerDiversityLimits, expected_pow: f64, log: Logger) -> Result<Self, failure::Error> {
+        Self::start_on(name, IpAddr::V4(Ipv4Addr::UNSPECIFIED), listener_port, peer_threshold, peer_diversity, expected_pow, log)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn start_on(name: &str, bind_address: IpAddr, listener_port: u16, peer_threshold: PeerConnectionThreshold, peer_diversity: PeerDiversityLimits, expected_pow: f64, log: Logger) -> Result<Self, failure::Error> {
+        Self::start_with(name, P2p {
+            listener_port,
+            bind_address,
+            bootstrap_lookup_addresses: vec![],
+            disable_bootstrap_lookup: true,
+            disable_mempool: false,
+            private_node: false,
+            expected_pow,
+            initial_peers: vec![],
+            trusted_peer_ids: vec![],
+            peer_threshold,
+            peer_diversity,
+            rate_limits: RateLimits::unlimited(),
+            capture: None,
+            keepalive: KeepaliveConfig::default(),
+        }, system_clock(), log)
+    }
+
+    /// Start private node, which accepts only peers with `trusted_peer_ids`
+    fn start_private(name: &str, listener_port: u16, trusted_peer_ids: Vec<String>, log: Logger) -> Result<Self, failure::Error> {
+        Self::start_with(name, P2p {
+            listener_port,
+            bind_address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            bootstrap_lookup_addresses: vec![],
+            disable_bootstrap_lookup: true,
+            disable_mempool: false,
+            private_node: true,
+            expected_pow: 0f64,
+            initial_peers: vec![],
+            trusted_peer_ids,
+            peer_threshold: PeerConnectionThreshold::new(0, 10),
+            peer_diversity: PeerDiversityLimits::unlimited(),
+            rate_limits: RateLimits::unlimited(),
+            capture: None,
+            keepalive: KeepaliveConfig::default(),
+        }, system_clock(), log)
+    }
+
+    fn start_with(name: &str, p2p_config: P2p, clock: ClockRef, log: Logger) -> Result<Self, failure::Error> {
+        let tmp_storage = TmpStorage::create(common::prepare_empty_dir(&format!("__{}", name)))?;
+        let tokio_runtime = tokio::runtime::Builder::new()
+            .threaded_scheduler()
+            .enable_all()
+            .build()?;
+
+        let actor_system = SystemBuilder::new().name(name).log(log.clone()).create().expect("Failed to create actor system");
+        let shell_channel = ShellChannel::actor(&actor_system).expect("Failed to create shell channel");
+        let network_channel = NetworkChannel::actor(&actor_system).expect("Failed to create network channel");
+        let barrier = Barrier::new(&actor_system, network_channel.clone(), shell_channel.clone())?;
+        let (received_tx, received) = channel();
+        let received_listener = actor_system.actor_of_props::<ReceivedMessagesListener>(
+            "received-messages-listener",
+            Props::new_args(Arc::new(Mutex::new(received_tx))),
+        ).map_err(|e| failure::format_err!("Failed to create received-messages-listener, reason: {:?}", e))?;
+        network_channel.tell(
+            Subscribe {
+                actor: Box::new(received_listener),
+                topic: NetworkChannelTopic::NetworkEvents.into(),
+            }, None);
+        let peer_manager = PeerManager::actor_with_clock(
+            &actor_system,
+            network_channel,
+            shell_channel.clone(),
+            tmp_storage.storage(),
+            tokio_runtime.handle().clone(),
+            Identity::generate(0f64),
+            NETWORK_VERSION.clone(),
+            p2p_config,
+            NetworkStats::default(),
+            clock,
+        ).expect("Failed to create peer manager");
+
+        // wait for listener
+        thread::sleep(Duration::from_secs(1));
+
+        Ok(PeerManagerNode {
+            log,
+            peer_manager,
+            barrier,
+            received,
+            shell_channel,
+            actor_system,
+            tokio_runtime,
+            _tmp_storage: tmp_storage,
+        })
+    }
+}
+
+impl PeerManagerNode {
+    /// Wait until the node receives a message matching the condition from any of its peers, returns the peer, which received it
+    fn wait_for_received<F>(&self, marker: &str, condition: F) -> Result<PeerRef, failure::Error>
+        where F: Fn(&PeerMessage) -> bool
+    {
+        let (timeout, _) = WAIT_TIMEOUT;
+        loop {
+            match self.received.recv_timeout(timeout) {
+                Ok((peer, message)) if condition(&message) => break Ok(peer),
+                Ok(_) => continue,
+                Err(_) => break Err(failure::format_err!("wait_for_received({}) - timeout (timeout: {:?}) exceeded!", marker, timeout)),
+            }
+        }
+    }
+}
+
+impl Drop for PeerManagerNode {
+    fn drop(&mut self) {
+        warn!(self.log, "[NODE] Stopping peer manager node");
+        self.shell_channel.tell(
+            Publish {
+                msg: ShuttingDown.into(),
+                topic: ShellChannelTopic::ShellCommands.into(),
+            }, None,
+        );
+        let _ = self.actor_system.shutdown();
+    }
+}
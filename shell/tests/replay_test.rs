@@ -0,0 +1,88 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use lazy_static::lazy_static;
+
+use shell::recorder::{read_records, Record, RecordedEvent, RecordWriter};
+use tezos_api::environment::TezosEnvironment;
+use tezos_messages::p2p::binary_message::BinaryMessage;
+use tezos_messages::p2p::encoding::metadata::MetadataMessage;
+use tezos_messages::p2p::encoding::prelude::{PeerMessage, PeerMessageResponse};
+use tezos_messages::p2p::encoding::version::NetworkVersion;
+
+use crate::common::replay::ReplayNode;
+
+mod common;
+
+lazy_static! {
+    pub static ref NETWORK_VERSION: NetworkVersion = NetworkVersion::new("TEST_CHAIN".to_string(), 0, 0);
+}
+
+#[test]
+fn test_replay_drives_chain_manager_and_peer_manager() -> Result<(), failure::Error> {
+    let log = common::create_logger(common::log_level());
+    let mut node = ReplayNode::start("test_replay_drives_managers", TezosEnvironment::Carthagenet, NETWORK_VERSION.clone(), 1270, None, log)?;
+
+    node.replay(&incident_records("test_replay_drives_managers")?)?;
+
+    // chain manager asks bootstrapped peer for current branch
+    let sent = node.sent_messages("peer-1");
+    assert!(sent.iter().any(|message| matches!(message, PeerMessage::GetCurrentBranch(_))));
+    // peer manager answers bootstrap with advertise
+    assert!(sent.iter().any(|message| matches!(message, PeerMessage::Advertise(_))));
+    // nothing was published for another peer
+    assert!(node.sent_messages("peer-2").is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn test_recorded_traffic_replays_the_same() -> Result<(), failure::Error> {
+    let log = common::create_logger(common::log_level());
+    let record_file = common::test_storage_dir_path("__test_recorded_traffic_replays_the_same.rec");
+
+    // replay incident and record it again
+    let first_run = {
+        let mut node = ReplayNode::start("test_recorded_traffic_first_run", TezosEnvironment::Carthagenet, NETWORK_VERSION.clone(), 1271, Some(record_file.clone()), log.clone())?;
+        node.replay(&incident_records("test_recorded_traffic_replays_the_same")?)?;
+        node.sent_messages("peer-1")
+    };
+
+    // replay of the recorded file leads to the same messages sent to the peer
+    let records = read_records(&record_file)?;
+    assert!(records.iter().any(|record| matches!(&record.event, RecordedEvent::PeerBootstrapped { peer, .. } if peer == "peer-1")));
+    let second_run = {
+        let mut node = ReplayNode::start("test_recorded_traffic_second_run", TezosEnvironment::Carthagenet, NETWORK_VERSION.clone(), 1272, None, log)?;
+        node.replay(&records)?;
+        node.sent_messages("peer-1")
+    };
+
+    assert!(!first_run.is_empty());
+    assert_eq!(format!("{:?}", first_run), format!("{:?}", second_run));
+
+    Ok(())
+}
+
+/// Simulated incident: peer connects, bootstraps and asks for other peers
+fn incident_records(test_name: &str) -> Result<Vec<Record>, failure::Error> {
+    let bootstrap: PeerMessageResponse = PeerMessage::Bootstrap.into();
+    let records = vec![
+        Record::new(RecordedEvent::PeerCreated { peer: "peer-1".to_string(), address: "127.0.0.1:1280".parse()? }),
+        Record::new(RecordedEvent::PeerBootstrapped {
+            peer: "peer-1".to_string(),
+            peer_id: "idtJunqYgSTgkwuPkFSBBGLh4fVtYM".to_string(),
+            peer_metadata: MetadataMessage::new(false, false),
+            listener_port: 1280,
+        }),
+        Record::new(RecordedEvent::PeerMessageReceived { peer: "peer-1".to_string(), message: bootstrap.as_bytes()? }),
+    ];
+
+    // records should survive the round trip through the file
+    let record_file = common::test_storage_dir_path(&format!("__{}_incident.rec", test_name));
+    let mut writer = RecordWriter::create(&record_file)?;
+    for record in &records {
+        writer.write(record)?;
+    }
+    writer.flush()?;
+    read_records(&record_file).map_err(failure::Error::from)
+}
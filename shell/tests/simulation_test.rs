@@ -0,0 +1,409 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Chain manager tests on the simulated network, see [common::simulation]
+
+use std::time::Duration;
+
+use crypto::hash::ProtocolHash;
+
+use networking::p2p::network_channel::Misbehavior;
+use networking::p2p::stream::CONTENT_LENGTH_MAX;
+use shell::chain_manager::{AskPeersAboutCurrentBranch, CheckChainCompleteness, DisconnectStalledPeers};
+use shell::PeerConnectionThreshold;
+use tezos_messages::p2p::binary_message::{BinaryMessage, MessageHash};
+use tezos_messages::p2p::encoding::limits::GET_BLOCK_HEADERS_MAX_LENGTH;
+use tezos_messages::p2p::encoding::prelude::{Component, DeactivateMessage, GetProtocolsMessage, OperationHashesForBlock, OperationHashesForBlocksMessage, Path, PeerMessage, PeerMessageResponse, Protocol};
+
+use crate::common::simulation::{Behavior, Simulation};
+
+mod common;
+
+const MAX_STEPS: usize = 25;
+/// Encoded hashes of so many operations do not fit into one chunk
+const OPERATIONS_COUNT: usize = 2_000;
+
+#[test]
+fn test_simulation_bootstrap_completeness() -> Result<(), failure::Error> {
+    let log = common::create_logger(common::log_level());
+    // bootstrap threshold is 1 peer
+    let mut sim = Simulation::start("test_simulation_bootstrap_completeness", 1273, PeerConnectionThreshold::new(2, 6), log)?;
+    let chain = sim.genesis_chain().extend(5, true);
+    sim.connect("peer-1", chain.clone(), Behavior::Honest)?;
+
+    assert!(sim.run_until(MAX_STEPS, |sim| sim.current_head_level() == 5));
+    assert_eq!(&chain.head().header.hash, sim.current_head().unwrap().hash());
+
+    // node reached the level of the peer, so it is bootstrapped and announces its head,
+    // heads applied before were not announced
+    let announced = sim.received_messages("peer-1").into_iter()
+        .filter_map(|message| match message {
+            PeerMessage::CurrentHead(message) => Some(message.current_block_header().level()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(vec![5], announced);
+
+    Ok(())
+}
+
+#[test]
+fn test_simulation_not_bootstrapped_under_threshold() -> Result<(), failure::Error> {
+    let log = common::create_logger(common::log_level());
+    // bootstrap threshold is 2 peers
+    let mut sim = Simulation::start("test_simulation_not_bootstrapped_under_threshold", 1274, PeerConnectionThreshold::new(6, 10), log)?;
+    let chain = sim.genesis_chain().extend(3, false);
+    sim.connect("peer-1", chain, Behavior::Honest)?;
+
+    assert!(sim.run_until(MAX_STEPS, |sim| sim.current_head_level() == 3));
+    assert!(!sim.received_messages("peer-1").iter().any(|message| matches!(message, PeerMessage::CurrentHead(_))));
+
+    Ok(())
+}
+
+#[test]
+fn test_simulation_branch_switch() -> Result<(), failure::Error> {
+    let log = common::create_logger(common::log_level());
+    let mut sim = Simulation::start("test_simulation_branch_switch", 1275, PeerConnectionThreshold::new(2, 6), log)?;
+    let chain_a = sim.genesis_chain().extend(3, true);
+    let chain_b = chain_a.fork(1, 1).extend(4, true);
+
+    sim.connect("peer-a", chain_a.clone(), Behavior::Honest)?;
+    assert!(sim.run_until(MAX_STEPS, |sim| sim.current_head_level() == 3));
+    assert_eq!(&chain_a.head().header.hash, sim.current_head().unwrap().hash());
+
+    // longer fork from level 1 wins, peer-a has the fork too (without announcing it), so both peers can serve its blocks
+    sim.update_chain("peer-a", chain_b.clone());
+    sim.connect("peer-b", chain_b.clone(), Behavior::Honest)?;
+    assert!(sim.run_until(MAX_STEPS, |sim| sim.current_head_level() == 5));
+    assert_eq!(&chain_b.head().header.hash, sim.current_head().unwrap().hash());
+    assert_ne!(chain_a.block(2).header.hash, chain_b.block(2).header.hash);
+    assert!(sim.misbehaved("peer-a").is_empty());
+    assert!(sim.misbehaved("peer-b").is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn test_simulation_stalled_peer_is_disconnected() -> Result<(), failure::Error> {
+    let log = common::create_logger(common::log_level());
+    let mut sim = Simulation::start("test_simulation_stalled_peer_is_disconnected", 1276, PeerConnectionThreshold::new(2, 6), log)?;
+    let chain = sim.genesis_chain().extend(2, false);
+    sim.connect("peer-live", chain.clone(), Behavior::Honest)?;
+    sim.connect("peer-stalled", chain.clone(), Behavior::Honest)?;
+    assert!(sim.run_until(MAX_STEPS, |sim| sim.current_head_level() == 2));
+
+    // only live peer announces new blocks
+    sim.advance(Duration::from_secs(100));
+    sim.update_chain("peer-live", chain.extend(1, false));
+    sim.tell_chain_manager(AskPeersAboutCurrentBranch);
+    assert!(sim.run_until(MAX_STEPS, |sim| sim.current_head_level() == 3));
+
+    // nobody is disconnected before the timeout
+    sim.tell_chain_manager(DisconnectStalledPeers);
+    assert!(sim.misbehaved("peer-stalled").is_empty());
+
+    sim.advance(Duration::from_secs(30));
+    sim.tell_chain_manager(DisconnectStalledPeers);
+    assert_eq!(vec![Misbehavior::Stalled], sim.misbehaved("peer-stalled"));
+    assert!(sim.misbehaved("peer-live").is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn test_simulation_slow_and_withholding_peers() -> Result<(), failure::Error> {
+    let log = common::create_logger(common::log_level());
+    let mut sim = Simulation::start("test_simulation_slow_and_withholding_peers", 1277, PeerConnectionThreshold::new(2, 6), log)?;
+    let chain = sim.genesis_chain().extend(2, true);
+
+    // node cannot get operations from the withholding peer
+    sim.connect("peer-withholding", chain.clone(), Behavior::WithholdOperations)?;
+    sim.run(5);
+    assert_eq!(0, sim.current_head_level());
+
+    sim.advance(Duration::from_secs(31));
+    sim.tell_chain_manager(DisconnectStalledPeers);
+    assert_eq!(vec![Misbehavior::Stalled], sim.misbehaved("peer-withholding"));
+
+    // slow peer provides blocks, once released
+    sim.connect("peer-slow", chain.clone(), Behavior::Slow)?;
+    sim.run(5);
+    assert_eq!(0, sim.current_head_level());
+
+    sim.release("peer-slow");
+    assert!(sim.run_until(MAX_STEPS, |sim| sim.current_head_level() == 2));
+    assert!(sim.misbehaved("peer-slow").is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn test_simulation_expired_requests_are_reassigned() -> Result<(), failure::Error> {
+    let log = common::create_logger(common::log_level());
+    let mut sim = Simulation::start("test_simulation_expired_requests_are_reassigned", 1279, PeerConnectionThreshold::new(2, 6), log)?;
+    let chain = sim.genesis_chain().extend(3, true);
+
+    sim.connect("peer-slow", chain.clone(), Behavior::Slow)?;
+    sim.run(2);
+    sim.connect("peer-fast", chain.clone(), Behavior::Honest)?;
+    sim.run(3);
+
+    // requests held by the slow peer expire and are downloaded from the fast peer
+    sim.advance(Duration::from_secs(16));
+    sim.tell_chain_manager(CheckChainCompleteness);
+    assert!(sim.run_until(MAX_STEPS, |sim| sim.current_head_level() == 3));
+
+    // late answers to the expired requests are not a misbehavior
+    sim.release("peer-slow");
+    sim.run(2);
+    assert!(sim.misbehaved("peer-slow").is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn test_simulation_malicious_header() -> Result<(), failure::Error> {
+    let log = common::create_logger(common::log_level());
+    let mut sim = Simulation::start("test_simulation_malicious_header", 1278, PeerConnectionThreshold::new(2, 6), log)?;
+    let chain = sim.genesis_chain().extend(2, false);
+
+    sim.connect("peer-malicious", chain.clone(), Behavior::Malicious)?;
+    sim.run(5);
+    assert_eq!(0, sim.current_head_level());
+    assert!(sim.misbehaved("peer-malicious").contains(&Misbehavior::InvalidBlockHeader));
+
+    Ok(())
+}
+
+#[test]
+fn test_simulation_answers_get_protocols() -> Result<(), failure::Error> {
+    let log = common::create_logger(common::log_level());
+    let mut sim = Simulation::start("test_simulation_answers_get_protocols", 1281, PeerConnectionThreshold::new(2, 6), log)?;
+    let protocol = test_protocol();
+    let protocol_hash = protocol.message_hash()?;
+    sim.store_protocol(&protocol)?;
+
+    sim.connect("peer-1", sim.genesis_chain(), Behavior::Honest)?;
+    sim.send("peer-1", GetProtocolsMessage::new(&[protocol_hash.clone()]).into());
+    assert!(sim.run_until(MAX_STEPS, |sim| sim.received_messages("peer-1").iter().any(|message| matches!(message, PeerMessage::Protocol(_)))));
+
+    let received = sim.received_messages("peer-1").into_iter()
+        .filter_map(|message| match message {
+            PeerMessage::Protocol(message) => Some(message.protocol().message_hash()),
+            _ => None,
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(vec![protocol_hash], received);
+
+    Ok(())
+}
+
+#[test]
+fn test_simulation_protocol_is_fetched_in_background() -> Result<(), failure::Error> {
+    let log = common::create_logger(common::log_level());
+    let mut sim = Simulation::start("test_simulation_protocol_is_fetched_in_background", 1282, PeerConnectionThreshold::new(2, 6), log)?;
+    let protocol = test_protocol();
+    let protocol_hash = protocol.message_hash()?;
+    // block on level 1 announces protocol, which is needed to apply the block on level 2
+    sim.announce_next_protocol(&protocol_hash);
+    let chain = sim.genesis_chain().extend(2, false).with_protocol(protocol);
+
+    sim.connect("peer-1", chain.clone(), Behavior::Honest)?;
+    sim.connect("peer-2", sim.genesis_chain(), Behavior::Honest)?;
+    assert!(sim.run_until(MAX_STEPS, |sim| sim.current_head_level() == 2 && sim.has_protocol(&protocol_hash)));
+    assert_eq!(&chain.head().header.hash, sim.current_head().unwrap().hash());
+
+    // only the peer, which sent the block, is asked for the protocol
+    assert_eq!(vec![vec![protocol_hash]], requested_protocols(&sim, "peer-1"));
+    assert!(requested_protocols(&sim, "peer-2").is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn test_simulation_unanswered_protocol_does_not_block_apply() -> Result<(), failure::Error> {
+    let log = common::create_logger(common::log_level());
+    let mut sim = Simulation::start("test_simulation_unanswered_protocol_does_not_block_apply", 1299, PeerConnectionThreshold::new(2, 6), log)?;
+    let protocol_hash = test_protocol().message_hash()?;
+    sim.announce_next_protocol(&protocol_hash);
+    // peer does not serve the protocol, so the request is never answered
+    let chain = sim.genesis_chain().extend(2, false);
+    sim.connect("peer-1", chain.clone(), Behavior::Honest)?;
+
+    // block is applied without waiting for the protocol request to time out
+    assert!(sim.run_until(5, |sim| sim.current_head_level() == 2));
+    assert!(!sim.has_protocol(&protocol_hash));
+    assert_eq!(vec![vec![protocol_hash.clone()]], requested_protocols(&sim, "peer-1"));
+
+    // protocol, which was not served, is not requested again by the next blocks
+    sim.advance(Duration::from_secs(61));
+    sim.tell_chain_manager(CheckChainCompleteness);
+    let chain = chain.extend(2, false);
+    sim.update_chain("peer-1", chain.clone());
+    sim.tell_chain_manager(AskPeersAboutCurrentBranch);
+    assert!(sim.run_until(MAX_STEPS, |sim| sim.current_head_level() == 4));
+    assert_eq!(&chain.head().header.hash, sim.current_head().unwrap().hash());
+    assert_eq!(vec![vec![protocol_hash]], requested_protocols(&sim, "peer-1"));
+
+    Ok(())
+}
+
+#[test]
+fn test_simulation_forged_protocol_is_rejected() -> Result<(), failure::Error> {
+    let log = common::create_logger(common::log_level());
+    let mut sim = Simulation::start("test_simulation_forged_protocol_is_rejected", 1283, PeerConnectionThreshold::new(2, 6), log)?;
+    let protocol = test_protocol();
+    let protocol_hash = protocol.message_hash()?;
+    sim.announce_next_protocol(&protocol_hash);
+    let chain = sim.genesis_chain().extend(2, false).with_protocol(protocol);
+
+    sim.connect("peer-forging", chain, Behavior::ForgeProtocols)?;
+    assert!(sim.run_until(MAX_STEPS, |sim| sim.misbehaved("peer-forging").contains(&Misbehavior::InvalidProtocol)));
+    // block is applied anyway, but forged sources are not stored
+    assert_eq!(2, sim.current_head_level());
+    assert!(!sim.has_protocol(&protocol_hash));
+
+    Ok(())
+}
+
+#[test]
+fn test_simulation_gap_is_downloaded_in_segments() -> Result<(), failure::Error> {
+    let log = common::create_logger(common::log_level());
+    let mut sim = Simulation::start("test_simulation_gap_is_downloaded_in_segments", 1284, PeerConnectionThreshold::new(2, 6), log)?;
+    let chain = sim.genesis_chain().extend(5, true);
+    sim.connect("peer-1", chain.clone(), Behavior::Honest)?;
+    assert!(sim.run_until(MAX_STEPS, |sim| sim.current_head_level() == 5));
+
+    // gap of 60 blocks is split by the history of the current branch, so both peers download its segments at once
+    let chain = chain.extend(60, true);
+    sim.update_chain("peer-1", chain.clone());
+    sim.connect("peer-2", chain.clone(), Behavior::Honest)?;
+    sim.tell_chain_manager(AskPeersAboutCurrentBranch);
+    assert!(sim.run_until(4 * MAX_STEPS, |sim| sim.current_head_level() == 65));
+    assert_eq!(&chain.head().header.hash, sim.current_head().unwrap().hash());
+
+    let stored = (0..=5).map(|level| chain.block(level).header.hash.clone()).collect::<Vec<_>>();
+    let requested = |name: &str| sim.received_messages(name).into_iter()
+        .filter_map(|message| match message {
+            PeerMessage::GetBlockHeaders(message) => Some(message.get_block_headers().clone()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    for name in &["peer-1", "peer-2"] {
+        let requests = requested(name);
+        assert!(!requests.is_empty());
+        assert!(requests.iter().all(|block_hashes| block_hashes.len() <= GET_BLOCK_HEADERS_MAX_LENGTH));
+        // blocks below the local head are not downloaded again
+        assert!(requests.iter().flatten().all(|block_hash| !stored.contains(block_hash)));
+    }
+    assert!(sim.misbehaved("peer-1").is_empty());
+    assert!(sim.misbehaved("peer-2").is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn test_simulation_operation_hashes_round_trip() -> Result<(), failure::Error> {
+    let log = common::create_logger(common::log_level());
+    let mut sim = Simulation::start("test_simulation_operation_hashes_round_trip", 1300, PeerConnectionThreshold::new(2, 6), log)?;
+    let chain = sim.genesis_chain().extend(2, true);
+    sim.connect("peer-1", chain.clone(), Behavior::Honest)?;
+    // bootstrapped node asks for operation hashes of the new blocks at first
+    assert!(sim.run_until(MAX_STEPS, |sim| sim.current_head_level() == 2));
+
+    // operation hashes of the validation pass do not fit into one chunk
+    let chain = chain.extend_with_operations(OPERATIONS_COUNT).extend_with_operations(OPERATIONS_COUNT);
+    let from_mempool = chain.block(3).clone();
+    let downloaded = chain.block(4).clone();
+    let operation_hashes = from_mempool.operations[0].operations().iter()
+        .map(|operation| operation.message_hash())
+        .collect::<Result<Vec<_>, _>>()?;
+    let message = OperationHashesForBlocksMessage::new(OperationHashesForBlock::new(from_mempool.header.hash.clone(), 0), Path::Op, operation_hashes);
+    assert!(PeerMessageResponse::from(PeerMessage::OperationHashesForBlock(message)).as_bytes()?.len() > CONTENT_LENGTH_MAX);
+
+    // operations of the first block are already in mempool
+    for operation in from_mempool.operations[0].operations() {
+        sim.store_mempool_operation(operation.clone())?;
+    }
+    sim.update_chain("peer-1", chain.clone());
+    sim.tell_chain_manager(AskPeersAboutCurrentBranch);
+    assert!(sim.run_until(MAX_STEPS, |sim| sim.current_head_level() == 4));
+    assert_eq!(&chain.head().header.hash, sim.current_head().unwrap().hash());
+
+    let requested_hashes = sim.received_messages("peer-1").into_iter()
+        .filter_map(|message| match message {
+            PeerMessage::GetOperationHashesForBlocks(message) => Some(message.get_operation_hashes_for_blocks().iter().map(|request| request.hash().clone()).collect::<Vec<_>>()),
+            _ => None,
+        })
+        .flatten()
+        .collect::<Vec<_>>();
+    assert!(requested_hashes.contains(&from_mempool.header.hash));
+    assert!(requested_hashes.contains(&downloaded.header.hash));
+
+    // just the operations, which were not assembled from mempool, are downloaded
+    let requested_operations = sim.received_messages("peer-1").into_iter()
+        .filter_map(|message| match message {
+            PeerMessage::GetOperationsForBlocks(message) => Some(message.get_operations_for_blocks().iter().map(|request| request.block_hash().clone()).collect::<Vec<_>>()),
+            _ => None,
+        })
+        .flatten()
+        .collect::<Vec<_>>();
+    assert!(!requested_operations.contains(&from_mempool.header.hash));
+    assert!(requested_operations.contains(&downloaded.header.hash));
+    assert!(sim.misbehaved("peer-1").is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn test_simulation_deactivated_peer_is_not_asked() -> Result<(), failure::Error> {
+    let log = common::create_logger(common::log_level());
+    let mut sim = Simulation::start("test_simulation_deactivated_peer_is_not_asked", 1301, PeerConnectionThreshold::new(2, 6), log)?;
+    let chain = sim.genesis_chain().extend(2, true);
+    sim.connect("peer-live", chain.clone(), Behavior::Honest)?;
+    sim.connect("peer-deactivated", chain.clone(), Behavior::Honest)?;
+    assert!(sim.run_until(MAX_STEPS, |sim| sim.current_head_level() == 2));
+
+    let chain_id = sim.chain_id().clone();
+    sim.send("peer-deactivated", DeactivateMessage::new(chain_id).into());
+    sim.step();
+    let received_before = sim.received_messages("peer-deactivated").len();
+
+    // both peers have new blocks, but only the active peer is asked for the branch, the blocks and their operations
+    let chain = chain.extend(3, true);
+    sim.update_chain("peer-live", chain.clone());
+    sim.update_chain("peer-deactivated", chain.clone());
+    sim.tell_chain_manager(AskPeersAboutCurrentBranch);
+    assert!(sim.run_until(MAX_STEPS, |sim| sim.current_head_level() == 5));
+    assert_eq!(&chain.head().header.hash, sim.current_head().unwrap().hash());
+    let requests = sim.received_messages("peer-deactivated")[received_before..].iter()
+        .filter(|message| matches!(message,
+            PeerMessage::GetCurrentBranch(_)
+            | PeerMessage::GetBlockHeaders(_)
+            | PeerMessage::GetOperationsForBlocks(_)
+            | PeerMessage::GetOperationHashesForBlocks(_)))
+        .count();
+    assert_eq!(0, requests);
+
+    // deactivated peer is not disconnected, because it does not update its head
+    sim.advance(Duration::from_secs(121));
+    sim.tell_chain_manager(DisconnectStalledPeers);
+    assert!(sim.misbehaved("peer-deactivated").is_empty());
+
+    Ok(())
+}
+
+/// Protocol hashes requested from the peer, one vector per `GetProtocols` message
+fn requested_protocols(sim: &Simulation, name: &str) -> Vec<Vec<ProtocolHash>> {
+    sim.received_messages(name).into_iter()
+        .filter_map(|message| match message {
+            PeerMessage::GetProtocols(message) => Some(message.get_protocols().clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+fn test_protocol() -> Protocol {
+    Protocol::new(0, vec![Component::new("Main".to_string(), None, "let simulated = true".to_string())])
+}
@@ -139,6 +139,15 @@ impl BlockMetaStorage {
             .map_err(StorageError::from)
     }
 
+    /// Mark block as not applied, merge operator cannot do it, because it never clears the applied flag
+    pub fn reset_applied(&self, block_hash: &BlockHash) -> Result<(), StorageError> {
+        if let Some(mut meta) = self.get(block_hash)? {
+            meta.set_is_applied(false);
+            self.kv.put(block_hash, &meta)?;
+        }
+        Ok(())
+    }
+
     #[inline]
     pub fn iter(&self, mode: IteratorMode<Self>) -> Result<IteratorWithSchema<Self>, StorageError> {
         self.kv.iterator(mode)
@@ -10,7 +10,7 @@ use std::sync::Arc;
 use failure::Fail;
 use rocksdb::Cache;
 use serde::{Deserialize, Serialize};
-use slog::{error, info, Logger};
+use slog::{error, info, Logger, warn};
 
 use crypto::hash::{BlockHash, ChainId, ContextHash, HashType};
 use tezos_api::environment::{OPERATION_LIST_LIST_HASH_EMPTY, TezosEnvironmentConfiguration, TezosEnvironmentError};
@@ -22,13 +22,17 @@ use tezos_messages::p2p::encoding::prelude::BlockHeader;
 pub use crate::block_meta_storage::{BlockMetaStorage, BlockMetaStorageKV, BlockMetaStorageReader};
 pub use crate::block_storage::{BlockAdditionalData, BlockAdditionalDataBuilder, BlockJsonData, BlockJsonDataBuilder, BlockStorage, BlockStorageReader};
 pub use crate::chain_meta_storage::ChainMetaStorage;
+use crate::chain_meta_storage::ChainMetaStorageReader;
 pub use crate::context_action_storage::{ContextActionByBlockHashKey, ContextActionRecordValue, ContextActionStorage};
 pub use crate::mempool_storage::{MempoolStorage, MempoolStorageKV};
 use crate::merkle_storage::MerkleStorage;
 pub use crate::operations_meta_storage::{OperationsMetaStorage, OperationsMetaStorageKV};
 pub use crate::operations_storage::{OperationKey, OperationsStorage, OperationsStorageKV, OperationsStorageReader};
 use crate::persistent::{CommitLogError, DBError, Decoder, Encoder, SchemaError};
+pub use crate::peer_greylist_storage::{GreylistEntry, GreylistKey, PeerGreylistStorage};
+pub use crate::peer_storage::{PeerPointInfo, PeerStorage};
 pub use crate::persistent::database::{Direction, IteratorMode};
+pub use crate::protocol_storage::ProtocolStorage;
 use crate::persistent::sequence::SequenceError;
 pub use crate::system_storage::SystemStorage;
 
@@ -44,6 +48,9 @@ pub mod system_storage;
 pub mod skip_list;
 pub mod context;
 pub mod chain_meta_storage;
+pub mod peer_greylist_storage;
+pub mod peer_storage;
+pub mod protocol_storage;
 
 /// Extension of block header with block hash
 #[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
@@ -221,6 +228,71 @@ pub fn store_applied_block_result(
     Ok((block_json_data, block_additional_data))
 }
 
+/// Returns true, if the context of the applied block was committed by the context listener (commit assigns block to its context hash).
+/// Genesis context is committed by "commit_genesis", so genesis is always considered as committed.
+pub fn is_block_context_committed(block_storage: &BlockStorage, block: &BlockHeaderWithHash) -> Result<bool, StorageError> {
+    if block.header.level() == 0 {
+        return Ok(true);
+    }
+    Ok(block_storage.get_by_context_hash(block.header.context())?.is_some())
+}
+
+/// Repairs blocks, which were marked as applied, but their context commit was not stored (e.g. node was killed during apply).
+/// Such blocks are marked as not applied and current head is moved back to the last block with committed context,
+/// so the blocks are applied again. Returns count of repaired blocks.
+pub fn repair_half_applied_blocks(
+    block_storage: &BlockStorage,
+    block_meta_storage: &BlockMetaStorage,
+    chain_meta_storage: &ChainMetaStorage,
+    chain_id: &ChainId,
+    log: &Logger) -> Result<usize, StorageError> {
+    let mut current_head = match chain_meta_storage.get_current_head(chain_id)? {
+        Some(current_head) => current_head,
+        None => return Ok(0),
+    };
+
+    let mut repaired_count = 0;
+    while let Some(block) = block_storage.get(current_head.hash())? {
+        if is_block_context_committed(block_storage, &block)? {
+            break;
+        }
+
+        warn!(log, "Block was applied, but its context was not committed, block will be applied again";
+                   "block_header_hash" => HashType::BlockHash.bytes_to_string(&block.hash),
+                   "level" => block.header.level());
+        block_meta_storage.reset_applied(&block.hash)?;
+        repaired_count += 1;
+
+        let predecessor = block_storage.get(block.header.predecessor())?.ok_or(StorageError::MissingKey)?;
+        current_head = Head::new(predecessor.hash.clone(), predecessor.header.level(), predecessor.header.fitness().clone());
+    }
+
+    if repaired_count > 0 {
+        info!(log, "Current head was moved back to the last block with committed context";
+                   "block_header_hash" => HashType::BlockHash.bytes_to_string(current_head.hash()),
+                   "level" => current_head.level(),
+                   "repaired_blocks" => repaired_count);
+        chain_meta_storage.set_current_head(chain_id, current_head)?;
+    }
+
+    Ok(repaired_count)
+}
+
+/// Repairs half applied blocks of the main chain and of the test chain, which was active before restart,
+/// see [repair_half_applied_blocks]. Returns count of repaired blocks of all chains.
+pub fn repair_half_applied_blocks_of_chains(
+    block_storage: &BlockStorage,
+    block_meta_storage: &BlockMetaStorage,
+    chain_meta_storage: &ChainMetaStorage,
+    main_chain_id: &ChainId,
+    log: &Logger) -> Result<usize, StorageError> {
+    let mut repaired_count = repair_half_applied_blocks(block_storage, block_meta_storage, chain_meta_storage, main_chain_id, log)?;
+    if let Some(test_chain_id) = chain_meta_storage.get_test_chain_id(main_chain_id)? {
+        repaired_count += repair_half_applied_blocks(block_storage, block_meta_storage, chain_meta_storage, &test_chain_id, log)?;
+    }
+    Ok(repaired_count)
+}
+
 /// Stores commit_genesis result to storage and mark genesis block as applied, if everythnig is ok.
 /// !Important, this rewrites context_hash on stored genesis - because in initialize_storage_with_genesis_block we stored wiht Context_hash_zero
 /// And context hash of block is used for appling of successor
@@ -367,8 +439,11 @@ pub mod tests_common {
     use crate::block_storage;
     use crate::chain_meta_storage::ChainMetaStorage;
     use crate::mempool_storage::MempoolStorage;
+    use crate::peer_greylist_storage::PeerGreylistStorage;
+    use crate::peer_storage::PeerStorage;
     use crate::persistent::*;
     use crate::persistent::sequence::Sequences;
+    use crate::protocol_storage::ProtocolStorage;
     use crate::skip_list::{DatabaseBackedSkipList, Lane, ListValue};
 
     use super::*;
@@ -417,6 +492,9 @@ pub mod tests_common {
                 MempoolStorage::descriptor(&cache),
                 ContextActionStorage::descriptor(&cache),
                 ChainMetaStorage::descriptor(&cache),
+                PeerGreylistStorage::descriptor(&cache),
+                PeerStorage::descriptor(&cache),
+                ProtocolStorage::descriptor(&cache),
             ], &cfg)?;
             let clog = open_cl(&path, vec![
                 BlockStorage::descriptor(),
@@ -0,0 +1,156 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use getset::Getters;
+use rocksdb::{Cache, ColumnFamilyDescriptor};
+use serde::{Deserialize, Serialize};
+
+use crate::IteratorMode;
+use crate::persistent::{BincodeEncoded, default_table_options, KeyValueSchema, KeyValueStoreWithSchema, PersistentStorage};
+use crate::StorageError;
+
+pub type PeerGreylistStorageKV = dyn KeyValueStoreWithSchema<PeerGreylistStorage> + Sync + Send;
+
+/// Represents storage of greylisted (temporarily banned) peers.
+///
+/// Peers are greylisted either by their IP address or by their peer id (public key hash).
+/// Every entry remembers how many times it was already banned, so that repeated offenders
+/// can be banned for exponentially longer periods even after node restart.
+#[derive(Clone)]
+pub struct PeerGreylistStorage {
+    kv: Arc<PeerGreylistStorageKV>
+}
+
+impl PeerGreylistStorage {
+    pub fn new(persistent_storage: &PersistentStorage) -> Self {
+        Self { kv: persistent_storage.kv() }
+    }
+
+    #[inline]
+    pub fn put(&self, key: &GreylistKey, entry: &GreylistEntry) -> Result<(), StorageError> {
+        self.kv.put(key, entry)
+            .map_err(StorageError::from)
+    }
+
+    #[inline]
+    pub fn get(&self, key: &GreylistKey) -> Result<Option<GreylistEntry>, StorageError> {
+        self.kv.get(key)
+            .map_err(StorageError::from)
+    }
+
+    #[inline]
+    pub fn delete(&self, key: &GreylistKey) -> Result<(), StorageError> {
+        self.kv.delete(key)
+            .map_err(StorageError::from)
+    }
+
+    /// Load all greylist entries, including the expired ones
+    pub fn iter(&self) -> Result<Vec<(GreylistKey, GreylistEntry)>, StorageError> {
+        let mut entries = Vec::new();
+        for (key, value) in self.kv.iterator(IteratorMode::Start)? {
+            entries.push((key?, value?));
+        }
+        Ok(entries)
+    }
+}
+
+impl KeyValueSchema for PeerGreylistStorage {
+    type Key = GreylistKey;
+    type Value = GreylistEntry;
+
+    fn descriptor(cache: &Cache) -> ColumnFamilyDescriptor {
+        let cf_opts = default_table_options(cache);
+        ColumnFamilyDescriptor::new(Self::name(), cf_opts)
+    }
+
+    #[inline]
+    fn name() -> &'static str {
+        "peer_greylist_storage"
+    }
+}
+
+/// Greylisted item
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum GreylistKey {
+    Ip(IpAddr),
+    PeerId(String),
+}
+
+impl BincodeEncoded for GreylistKey {}
+
+#[derive(Serialize, Deserialize, Getters, Clone, Debug, PartialEq)]
+pub struct GreylistEntry {
+    /// Item is banned until this time
+    #[get = "pub"]
+    banned_until: SystemTime,
+    /// How many times was item already banned
+    #[get = "pub"]
+    ban_count: u32,
+}
+
+impl GreylistEntry {
+    pub fn new(banned_until: SystemTime, ban_count: u32) -> Self {
+        Self { banned_until, ban_count }
+    }
+
+    /// Returns true if ban is still in effect at the specified time
+    #[inline]
+    pub fn is_banned_at(&self, time: SystemTime) -> bool {
+        self.banned_until > time
+    }
+}
+
+impl BincodeEncoded for GreylistEntry {}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use failure::Error;
+
+    use crate::tests_common::TmpStorage;
+
+    use super::*;
+
+    #[test]
+    fn test_greylist_storage() -> Result<(), Error> {
+        let tmp_storage = TmpStorage::create("__test_peer_greylist_storage")?;
+        let storage = PeerGreylistStorage::new(tmp_storage.storage());
+
+        let now = SystemTime::now();
+        let ip_key = GreylistKey::Ip("127.0.0.1".parse()?);
+        let ipv6_key = GreylistKey::Ip("::1".parse()?);
+        let peer_key = GreylistKey::PeerId("idtgUrRAAvuH3cbZrXyfaKZovoFUqt".to_string());
+
+        assert!(storage.get(&ip_key)?.is_none());
+        assert!(storage.iter()?.is_empty());
+
+        storage.put(&ip_key, &GreylistEntry::new(now + Duration::from_secs(60), 1))?;
+        storage.put(&peer_key, &GreylistEntry::new(now - Duration::from_secs(60), 3))?;
+        storage.put(&ipv6_key, &GreylistEntry::new(now + Duration::from_secs(60), 2))?;
+
+        let ip_entry = storage.get(&ip_key)?.expect("Expected greylisted IP");
+        assert_eq!(1, *ip_entry.ban_count());
+        assert!(ip_entry.is_banned_at(now));
+
+        let peer_entry = storage.get(&peer_key)?.expect("Expected greylisted peer");
+        assert_eq!(3, *peer_entry.ban_count());
+        assert!(!peer_entry.is_banned_at(now));
+
+        let ipv6_entry = storage.get(&ipv6_key)?.expect("Expected greylisted IPv6");
+        assert_eq!(2, *ipv6_entry.ban_count());
+
+        assert_eq!(3, storage.iter()?.len());
+
+        storage.delete(&ip_key)?;
+        assert!(storage.get(&ip_key)?.is_none());
+        assert!(storage.get(&ipv6_key)?.is_some());
+        assert_eq!(2, storage.iter()?.len());
+
+        Ok(())
+    }
+}
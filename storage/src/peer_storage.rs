@@ -0,0 +1,181 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use getset::{CopyGetters, Getters};
+use rocksdb::{Cache, ColumnFamilyDescriptor};
+use serde::{Deserialize, Serialize};
+
+use crate::IteratorMode;
+use crate::persistent::{BincodeEncoded, default_table_options, KeyValueSchema, KeyValueStoreWithSchema, PersistentStorage};
+use crate::StorageError;
+
+pub type PeerStorageKV = dyn KeyValueStoreWithSchema<PeerStorage> + Sync + Send;
+
+/// Represents storage of known peer points (addresses we can connect to) together with their connection history.
+///
+/// Points are collected from DNS bootstrap, configured peers and `Advertise` messages. Because they are persisted,
+/// node can reconnect to the last good peers after restart, even if DNS bootstrap is not available.
+#[derive(Clone)]
+pub struct PeerStorage {
+    kv: Arc<PeerStorageKV>
+}
+
+impl PeerStorage {
+    pub fn new(persistent_storage: &PersistentStorage) -> Self {
+        Self { kv: persistent_storage.kv() }
+    }
+
+    #[inline]
+    pub fn put(&self, point: &SocketAddr, info: &PeerPointInfo) -> Result<(), StorageError> {
+        self.kv.put(point, info)
+            .map_err(StorageError::from)
+    }
+
+    #[inline]
+    pub fn get(&self, point: &SocketAddr) -> Result<Option<PeerPointInfo>, StorageError> {
+        self.kv.get(point)
+            .map_err(StorageError::from)
+    }
+
+    #[inline]
+    pub fn delete(&self, point: &SocketAddr) -> Result<(), StorageError> {
+        self.kv.delete(point)
+            .map_err(StorageError::from)
+    }
+
+    /// Load all known points
+    pub fn iter(&self) -> Result<Vec<(SocketAddr, PeerPointInfo)>, StorageError> {
+        let mut points = Vec::new();
+        for (key, value) in self.kv.iterator(IteratorMode::Start)? {
+            points.push((key?, value?));
+        }
+        Ok(points)
+    }
+}
+
+impl KeyValueSchema for PeerStorage {
+    type Key = SocketAddr;
+    type Value = PeerPointInfo;
+
+    fn descriptor(cache: &Cache) -> ColumnFamilyDescriptor {
+        let cf_opts = default_table_options(cache);
+        ColumnFamilyDescriptor::new(Self::name(), cf_opts)
+    }
+
+    #[inline]
+    fn name() -> &'static str {
+        "peer_storage"
+    }
+}
+
+impl BincodeEncoded for SocketAddr {}
+
+/// Connection history of a single peer point
+#[derive(Serialize, Deserialize, Getters, CopyGetters, Clone, Debug, PartialEq)]
+pub struct PeerPointInfo {
+    /// Peer id which was seen on this point last time
+    #[get = "pub"]
+    peer_id: Option<String>,
+    /// Last time when connection (including bootstrap) was successful
+    #[get_copy = "pub"]
+    last_success: Option<SystemTime>,
+    /// Last time when connection or bootstrap failed
+    #[get_copy = "pub"]
+    last_failure: Option<SystemTime>,
+    /// Count of failures since the last successful connection
+    #[get_copy = "pub"]
+    failure_count: u32,
+    /// Peer id of the peer which advertised this point to us (`None` for DNS or configured points)
+    #[get = "pub"]
+    advertised_by: Option<String>,
+    /// Point was configured by the node operator
+    #[get_copy = "pub"]
+    trusted: bool,
+}
+
+impl PeerPointInfo {
+    pub fn new(advertised_by: Option<String>, trusted: bool) -> Self {
+        PeerPointInfo {
+            peer_id: None,
+            last_success: None,
+            last_failure: None,
+            failure_count: 0,
+            advertised_by,
+            trusted,
+        }
+    }
+
+    pub fn set_trusted(&mut self, trusted: bool) {
+        self.trusted = trusted;
+    }
+
+    /// Connection to the point was successful, reset failure count
+    pub fn record_success(&mut self, peer_id: String, time: SystemTime) {
+        self.peer_id = Some(peer_id);
+        self.last_success = Some(time);
+        self.failure_count = 0;
+    }
+
+    /// Connection to the point failed
+    pub fn record_failure(&mut self, time: SystemTime) {
+        self.last_failure = Some(time);
+        self.failure_count = self.failure_count.saturating_add(1);
+    }
+}
+
+impl BincodeEncoded for PeerPointInfo {}
+
+#[cfg(test)]
+mod tests {
+    use failure::Error;
+
+    use crate::tests_common::TmpStorage;
+
+    use super::*;
+
+    #[test]
+    fn test_peer_storage() -> Result<(), Error> {
+        let tmp_storage = TmpStorage::create("__test_peer_storage")?;
+        let storage = PeerStorage::new(tmp_storage.storage());
+
+        let point1: SocketAddr = "127.0.0.1:9732".parse()?;
+        let point2: SocketAddr = "[::1]:9732".parse()?;
+        assert!(storage.get(&point1)?.is_none());
+
+        storage.put(&point1, &PeerPointInfo::new(None, true))?;
+        storage.put(&point2, &PeerPointInfo::new(Some("idtgUrRAAvuH3cbZrXyfaKZovoFUqt".to_string()), false))?;
+        assert_eq!(2, storage.iter()?.len());
+
+        // update history
+        let now = SystemTime::now();
+        let mut info = storage.get(&point2)?.expect("Expected stored point");
+        info.record_failure(now);
+        info.record_failure(now);
+        storage.put(&point2, &info)?;
+
+        let info = storage.get(&point2)?.expect("Expected stored point");
+        assert_eq!(2, info.failure_count());
+        assert_eq!(Some(now), info.last_failure());
+        assert!(!info.trusted());
+
+        let mut info = storage.get(&point1)?.expect("Expected stored point");
+        info.record_success("idrr4xmYRZRzyRr2ehVBU5HXPp64tn".to_string(), now);
+        storage.put(&point1, &info)?;
+
+        let info = storage.get(&point1)?.expect("Expected stored point");
+        assert_eq!(0, info.failure_count());
+        assert_eq!(Some(now), info.last_success());
+        assert_eq!(Some("idrr4xmYRZRzyRr2ehVBU5HXPp64tn".to_string()), *info.peer_id());
+        assert!(info.trusted());
+
+        storage.delete(&point2)?;
+        assert!(storage.get(&point2)?.is_none());
+        assert_eq!(1, storage.iter()?.len());
+
+        Ok(())
+    }
+}
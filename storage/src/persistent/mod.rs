@@ -161,6 +161,13 @@ impl PersistentStorage {
         self.merkle.clone()
     }
 
+    /// Flush commit logs and key-value store without closing them
+    pub fn flush(&self) -> Result<(), failure::Error> {
+        self.clog.flush()?;
+        self.kv.flush()?;
+        Ok(())
+    }
+
     pub fn flush_dbs(&mut self) {
         self.clog.flush().expect("Failed to flush commit logs");
         self.kv.flush().expect("Failed to flush database");
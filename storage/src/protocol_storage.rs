@@ -0,0 +1,124 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::sync::Arc;
+
+use rocksdb::{Cache, ColumnFamilyDescriptor};
+
+use crypto::hash::ProtocolHash;
+use tezos_messages::p2p::binary_message::BinaryMessage;
+use tezos_messages::p2p::encoding::prelude::Protocol;
+
+use crate::IteratorMode;
+use crate::persistent::{Decoder, default_table_options, Encoder, KeyValueSchema, KeyValueStoreWithSchema, PersistentStorage, SchemaError};
+use crate::StorageError;
+
+pub type ProtocolStorageKV = dyn KeyValueStoreWithSchema<ProtocolStorage> + Sync + Send;
+
+/// Represents storage of protocol sources.
+///
+/// Sources are received from peers (`Protocol` message) and served back to the peers
+/// asking for them (`GetProtocols` message). Protocol hash must be verified before storing.
+#[derive(Clone)]
+pub struct ProtocolStorage {
+    kv: Arc<ProtocolStorageKV>
+}
+
+impl ProtocolStorage {
+    pub fn new(persistent_storage: &PersistentStorage) -> Self {
+        Self { kv: persistent_storage.kv() }
+    }
+
+    #[inline]
+    pub fn put(&self, protocol_hash: &ProtocolHash, protocol: &Protocol) -> Result<(), StorageError> {
+        self.kv.put(protocol_hash, protocol)
+            .map_err(StorageError::from)
+    }
+
+    #[inline]
+    pub fn get(&self, protocol_hash: &ProtocolHash) -> Result<Option<Protocol>, StorageError> {
+        self.kv.get(protocol_hash)
+            .map_err(StorageError::from)
+    }
+
+    #[inline]
+    pub fn contains(&self, protocol_hash: &ProtocolHash) -> Result<bool, StorageError> {
+        self.kv.contains(protocol_hash)
+            .map_err(StorageError::from)
+    }
+
+    /// Load hashes of all stored protocols
+    pub fn hashes(&self) -> Result<Vec<ProtocolHash>, StorageError> {
+        let mut hashes = Vec::new();
+        for (key, _) in self.kv.iterator(IteratorMode::Start)? {
+            hashes.push(key?);
+        }
+        Ok(hashes)
+    }
+}
+
+impl KeyValueSchema for ProtocolStorage {
+    type Key = ProtocolHash;
+    type Value = Protocol;
+
+    fn descriptor(cache: &Cache) -> ColumnFamilyDescriptor {
+        let cf_opts = default_table_options(cache);
+        ColumnFamilyDescriptor::new(Self::name(), cf_opts)
+    }
+
+    #[inline]
+    fn name() -> &'static str {
+        "protocol_storage"
+    }
+}
+
+impl Decoder for Protocol {
+    #[inline]
+    fn decode(bytes: &[u8]) -> Result<Self, SchemaError> {
+        Protocol::from_bytes(bytes)
+            .map_err(|_| SchemaError::DecodeError)
+    }
+}
+
+impl Encoder for Protocol {
+    #[inline]
+    fn encode(&self) -> Result<Vec<u8>, SchemaError> {
+        self.as_bytes()
+            .map_err(|_| SchemaError::EncodeError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use failure::Error;
+
+    use tezos_messages::p2p::binary_message::MessageHash;
+    use tezos_messages::p2p::encoding::prelude::Component;
+
+    use crate::tests_common::TmpStorage;
+
+    use super::*;
+
+    #[test]
+    fn test_protocol_storage() -> Result<(), Error> {
+        let tmp_storage = TmpStorage::create("__test_protocol_storage")?;
+        let storage = ProtocolStorage::new(tmp_storage.storage());
+
+        let protocol = Protocol::new(0, vec![
+            Component::new("Main".to_string(), None, "let main = ()".to_string())
+        ]);
+        let protocol_hash = protocol.message_hash()?;
+        assert!(!storage.contains(&protocol_hash)?);
+
+        storage.put(&protocol_hash, &protocol)?;
+        assert!(storage.contains(&protocol_hash)?);
+        assert_eq!(vec![protocol_hash.clone()], storage.hashes()?);
+
+        let stored = storage.get(&protocol_hash)?.expect("Expected stored protocol");
+        assert_eq!(protocol_hash, stored.message_hash()?);
+        assert_eq!(1, stored.components().len());
+        assert_eq!("Main", stored.components()[0].name());
+
+        Ok(())
+    }
+}
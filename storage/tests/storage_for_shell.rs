@@ -7,11 +7,11 @@ use std::path::{Path, PathBuf};
 use failure::Error;
 use slog::{Drain, Level, Logger};
 
-use crypto::hash::{chain_id_from_block_hash, ContextHash, HashType};
+use crypto::hash::{BlockHash, chain_id_from_block_hash, ContextHash, HashType};
 use storage::*;
 use storage::chain_meta_storage::ChainMetaStorageReader;
 use storage::tests_common::TmpStorage;
-use tezos_api::environment::TezosEnvironmentConfiguration;
+use tezos_api::environment::{OPERATION_LIST_LIST_HASH_EMPTY, TezosEnvironmentConfiguration};
 use tezos_api::ffi::{ApplyBlockResponse, CommitGenesisResult, GenesisChain, ProtocolOverrides};
 use tezos_messages::Head;
 use tezos_messages::p2p::binary_message::BinaryMessage;
@@ -176,6 +176,90 @@ fn test_storage() -> Result<(), Error> {
     Ok(())
 }
 
+#[test]
+fn test_repair_half_applied_blocks() -> Result<(), Error> {
+    let log = create_logger();
+    let tmp_storage = TmpStorage::create(test_storage_dir_path("__repair_half_applied_blocks"))?;
+    let block_storage = BlockStorage::new(tmp_storage.storage());
+    let block_meta_storage = BlockMetaStorage::new(tmp_storage.storage());
+    let chain_meta_storage = ChainMetaStorage::new(tmp_storage.storage());
+
+    let genesis = make_block_header(0, vec![0; HashType::BlockHash.size()], vec![0; HashType::ContextHash.size()])?;
+    let chain_id = chain_id_from_block_hash(&genesis.hash);
+    let block = make_block_header(1, genesis.hash.clone(), vec![1; HashType::ContextHash.size()])?;
+    block_storage.put_block_header(&genesis)?;
+    block_storage.put_block_header(&block)?;
+    block_meta_storage.put(&genesis.hash, &block_meta_storage::Meta::genesis_meta(&genesis.hash, &chain_id, true))?;
+    let mut metadata = block_meta_storage.put_block_header(&block, &chain_id, &log)?;
+
+    // block was applied, but node was killed before context of the block was committed
+    metadata.set_is_applied(true);
+    block_meta_storage.put(&block.hash, &metadata)?;
+    chain_meta_storage.set_current_head(&chain_id, Head::new(block.hash.clone(), block.header.level(), block.header.fitness().clone()))?;
+    assert!(!is_block_context_committed(&block_storage, &block)?);
+
+    assert_eq!(1, repair_half_applied_blocks(&block_storage, &block_meta_storage, &chain_meta_storage, &chain_id, &log)?);
+    assert_eq!(&genesis.hash, chain_meta_storage.get_current_head(&chain_id)?.expect("Current head should be set").hash());
+    assert!(!block_meta_storage.get(&block.hash)?.expect("No metadata was found").is_applied());
+
+    // block is applied again and its context is committed, nothing to repair
+    block_meta_storage.put(&block.hash, &metadata)?;
+    block_storage.assign_to_context(&block.hash, block.header.context())?;
+    chain_meta_storage.set_current_head(&chain_id, Head::new(block.hash.clone(), block.header.level(), block.header.fitness().clone()))?;
+    assert_eq!(0, repair_half_applied_blocks(&block_storage, &block_meta_storage, &chain_meta_storage, &chain_id, &log)?);
+    assert_eq!(&block.hash, chain_meta_storage.get_current_head(&chain_id)?.expect("Current head should be set").hash());
+
+    Ok(())
+}
+
+#[test]
+fn test_repair_half_applied_blocks_of_test_chain() -> Result<(), Error> {
+    let log = create_logger();
+    let tmp_storage = TmpStorage::create(test_storage_dir_path("__repair_half_applied_blocks_of_test_chain"))?;
+    let block_storage = BlockStorage::new(tmp_storage.storage());
+    let block_meta_storage = BlockMetaStorage::new(tmp_storage.storage());
+    let chain_meta_storage = ChainMetaStorage::new(tmp_storage.storage());
+
+    let genesis = make_block_header(0, vec![0; HashType::BlockHash.size()], vec![0; HashType::ContextHash.size()])?;
+    let main_chain_id = chain_id_from_block_hash(&genesis.hash);
+    let test_chain_id = chain_id_from_block_hash(&vec![2; HashType::BlockHash.size()]);
+    let test_block = make_block_header(1, genesis.hash.clone(), vec![2; HashType::ContextHash.size()])?;
+    block_storage.put_block_header(&genesis)?;
+    block_storage.put_block_header(&test_block)?;
+    block_meta_storage.put(&genesis.hash, &block_meta_storage::Meta::genesis_meta(&genesis.hash, &main_chain_id, true))?;
+    let mut metadata = block_meta_storage.put_block_header(&test_block, &test_chain_id, &log)?;
+
+    // main chain is fine, but block of the test chain was not committed
+    metadata.set_is_applied(true);
+    block_meta_storage.put(&test_block.hash, &metadata)?;
+    chain_meta_storage.set_current_head(&main_chain_id, Head::new(genesis.hash.clone(), genesis.header.level(), genesis.header.fitness().clone()))?;
+    chain_meta_storage.set_current_head(&test_chain_id, Head::new(test_block.hash.clone(), test_block.header.level(), test_block.header.fitness().clone()))?;
+
+    // test chain is repaired just when it is persisted as the test chain of the main chain
+    assert_eq!(0, repair_half_applied_blocks_of_chains(&block_storage, &block_meta_storage, &chain_meta_storage, &main_chain_id, &log)?);
+    chain_meta_storage.set_test_chain_id(&main_chain_id, &test_chain_id)?;
+    assert_eq!(1, repair_half_applied_blocks_of_chains(&block_storage, &block_meta_storage, &chain_meta_storage, &main_chain_id, &log)?);
+    assert_eq!(&genesis.hash, chain_meta_storage.get_current_head(&test_chain_id)?.expect("Current head should be set").hash());
+    assert!(!block_meta_storage.get(&test_block.hash)?.expect("No metadata was found").is_applied());
+
+    Ok(())
+}
+
+fn make_block_header(level: i32, predecessor: BlockHash, context: ContextHash) -> Result<BlockHeaderWithHash, Error> {
+    let block_header = BlockHeaderBuilder::default()
+        .level(level)
+        .proto(1)
+        .predecessor(predecessor)
+        .timestamp(5_635_634)
+        .validation_pass(0)
+        .operations_hash(OPERATION_LIST_LIST_HASH_EMPTY.clone())
+        .fitness(vec![vec![0, 1], vec![0, 0, 0, 0, 0, 0, 0, level as u8]])
+        .context(context)
+        .protocol_data(vec![0, 1, 2, 3, 4, 5, 6, 7, 8])
+        .build().unwrap();
+    Ok(BlockHeaderWithHash::new(block_header)?)
+}
+
 fn make_test_block_header() -> Result<BlockHeaderWithHash, Error> {
     let message_bytes = hex::decode("00006d6e0102dd00defaf70c53e180ea148b349a6feb4795610b2abc7b07fe91ce50a90814000000005c1276780432bc1d3a28df9a67b363aa1638f807214bb8987e5f9c0abcbd69531facffd1c80000001100000001000000000800000000000c15ef15a6f54021cb353780e2847fb9c546f1d72c1dc17c3db510f45553ce501ce1de000000000003c762c7df00a856b8bfcaf0676f069f825ca75f37f2bee9fe55ba109cec3d1d041d8c03519626c0c0faa557e778cb09d2e0c729e8556ed6a7a518c84982d1f2682bc6aa753f")?;
     let block_header = BlockHeaderWithHash::new(BlockHeader::from_bytes(message_bytes)?)?;
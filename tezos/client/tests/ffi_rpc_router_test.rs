@@ -1,6 +1,8 @@
 // Copyright (c) SimpleStaking and Tezedge Contributors
 // SPDX-License-Identifier: MIT
 
+use std::convert::TryFrom;
+
 use assert_json_diff::assert_json_eq;
 use serial_test::serial;
 
@@ -308,9 +310,9 @@ fn test_compute_path() -> Result<(), failure::Error> {
     let validation_passes: Vec<Vec<Operation>> =
         serde_json::from_str::<ValidationPasses>(test_data::VALIDATION_PASSES_WITH_OPERATIONS)?.into_iter()
             .map(|validation_pass| validation_pass.into_iter()
-                .map(|op| op.into())
-                .collect())
-            .collect();
+                .map(Operation::try_from)
+                .collect::<Result<_, _>>())
+            .collect::<Result<_, _>>()?;
 
     let request = ComputePathRequest {
         operations: validation_passes.iter().map(|validation_pass| validation_pass.iter().map(|op| op.message_hash().unwrap()).collect()).collect(),
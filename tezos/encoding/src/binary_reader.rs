@@ -38,6 +38,12 @@ pub enum BinaryReaderError {
     UnsupportedTag {
         tag: u16
     },
+    /// Bounded encoding contains more data than its boundary allows.
+    #[fail(display = "{} exceeds its boundary {}", name, boundary)]
+    EncodingBoundaryExceeded {
+        name: String,
+        boundary: usize,
+    },
 }
 
 impl From<crate::de::Error> for BinaryReaderError {
@@ -170,10 +176,14 @@ impl BinaryReader {
             }
             Encoding::String => {
                 let bytes_sz = safe!(buf, get_u32, u32) as usize;
-                let mut str_buf = vec![0u8; bytes_sz].into_boxed_slice();
-                safe!(buf, bytes_sz, buf.copy_to_slice(&mut str_buf));
-                let str_buf = str_buf.into_vec();
-                Ok(Value::String(String::from_utf8(str_buf)?))
+                self.decode_string(buf, bytes_sz)
+            }
+            Encoding::BoundedString(max_length) => {
+                let bytes_sz = safe!(buf, get_u32, u32) as usize;
+                if bytes_sz > *max_length {
+                    return Err(BinaryReaderError::EncodingBoundaryExceeded { name: "String".to_string(), boundary: *max_length });
+                }
+                self.decode_string(buf, bytes_sz)
             }
             Encoding::Enum => Ok(Value::Enum(None, Some(u32::from(safe!(buf, get_u8, u8))))),
             Encoding::Dynamic(dynamic_encoding) => {
@@ -206,16 +216,10 @@ impl BinaryReader {
                 }
             }
             Encoding::List(encoding_inner) => {
-                let bytes_sz = buf.remaining();
-
-                let mut buf_slice = buf.take(bytes_sz);
-
-                let mut values = vec![];
-                while buf_slice.remaining() > 0 {
-                    values.push(self.decode_value(&mut buf_slice, encoding_inner)?);
-                }
-
-                Ok(Value::List(values))
+                self.decode_list(buf, encoding_inner, usize::MAX)
+            }
+            Encoding::BoundedList(max_length, encoding_inner) => {
+                self.decode_list(buf, encoding_inner, *max_length)
             }
             Encoding::Option(_) => {
                 let is_present_byte = safe!(buf, get_u8, u8);
@@ -321,10 +325,13 @@ impl BinaryReader {
                 Ok(Value::String(str_num))
             }
             Encoding::Bytes => {
-                let bytes_sz = buf.remaining();
-                let mut buf_slice = vec![0u8; bytes_sz].into_boxed_slice();
-                buf.copy_to_slice(&mut buf_slice);
-                Ok(Value::List(buf_slice.into_vec().iter().map(|&byte| Value::Uint8(byte)).collect()))
+                self.decode_bytes(buf)
+            }
+            Encoding::BoundedBytes(max_length) => {
+                if buf.remaining() > *max_length {
+                    return Err(BinaryReaderError::EncodingBoundaryExceeded { name: "Bytes".to_string(), boundary: *max_length });
+                }
+                self.decode_bytes(buf)
             }
             Encoding::Hash(hash_type) => {
                 let bytes_sz = hash_type.size();
@@ -345,6 +352,34 @@ impl BinaryReader {
             | Encoding::RangedFloat => Err(de::Error::custom(format!("Unsupported encoding {:?}", encoding)).into())
         }
     }
+
+    /// Read string of `bytes_sz` bytes. Input length is checked before the string buffer is allocated.
+    fn decode_string(&self, buf: &mut dyn Buf, bytes_sz: usize) -> Result<Value, BinaryReaderError> {
+        let mut str_buf = safe!(buf, bytes_sz, vec![0u8; bytes_sz]);
+        buf.copy_to_slice(&mut str_buf);
+        Ok(Value::String(String::from_utf8(str_buf)?))
+    }
+
+    fn decode_bytes(&self, buf: &mut dyn Buf) -> Result<Value, BinaryReaderError> {
+        let bytes_sz = buf.remaining();
+        let mut buf_slice = vec![0u8; bytes_sz].into_boxed_slice();
+        buf.copy_to_slice(&mut buf_slice);
+        Ok(Value::List(buf_slice.into_vec().iter().map(|&byte| Value::Uint8(byte)).collect()))
+    }
+
+    /// Read list elements until the input is consumed. Element count is checked before each element is decoded,
+    /// so reading fails as soon as the list would have more than `max_length` elements.
+    fn decode_list(&self, buf: &mut dyn Buf, encoding_inner: &Encoding, max_length: usize) -> Result<Value, BinaryReaderError> {
+        let mut values = vec![];
+        while buf.remaining() > 0 {
+            if values.len() >= max_length {
+                return Err(BinaryReaderError::EncodingBoundaryExceeded { name: "List".to_string(), boundary: max_length });
+            }
+            values.push(self.decode_value(buf, encoding_inner)?);
+        }
+
+        Ok(Value::List(values))
+    }
 }
 
 #[cfg(test)]
@@ -559,4 +594,41 @@ mod tests {
         let record_deserialized: Option<Record> = de::from_value(&value).unwrap();
         assert_eq!(record, record_deserialized);
     }
+    #[test]
+    fn can_deserialize_bounded_list() {
+        let record_encoding = Encoding::Obj(vec![
+            Field::new("items", Encoding::bounded_list(2, Encoding::Uint16)),
+        ]);
+
+        let reader = BinaryReader::new();
+        let value = reader.read(hex::decode("00010002").unwrap(), &record_encoding).unwrap();
+        assert_eq!(Value::Record(vec![("items".to_string(), Value::List(vec![Value::Uint16(1), Value::Uint16(2)]))]), value);
+
+        let result = reader.read(hex::decode("000100020003").unwrap(), &record_encoding);
+        assert!(matches!(result, Err(BinaryReaderError::EncodingBoundaryExceeded { boundary: 2, .. })));
+
+        // boundary is exceeded before the rest of the input is decoded, so the trailing garbage is never read
+        let result = reader.read(hex::decode("00010002ff").unwrap(), &Encoding::bounded_list(2, Encoding::Uint16));
+        assert!(matches!(result, Err(BinaryReaderError::EncodingBoundaryExceeded { boundary: 2, .. })));
+        let result = reader.read(hex::decode("0001ff").unwrap(), &Encoding::bounded_list(2, Encoding::Uint16));
+        assert!(matches!(result, Err(BinaryReaderError::Underflow { .. })));
+    }
+
+    #[test]
+    fn can_not_deserialize_oversized_string_and_bytes() {
+        let reader = BinaryReader::new();
+
+        // declared length is checked against the boundary
+        let result = reader.read(hex::decode("0000000461626364").unwrap(), &Encoding::BoundedString(3));
+        assert!(matches!(result, Err(BinaryReaderError::EncodingBoundaryExceeded { boundary: 3, .. })));
+        let value = reader.read(hex::decode("00000003616263").unwrap(), &Encoding::BoundedString(3)).unwrap();
+        assert_eq!(Value::String("abc".to_string()), value);
+
+        // declared length is checked against the input before the string is allocated
+        let result = reader.read(hex::decode("ffffffff616263").unwrap(), &Encoding::String);
+        assert!(matches!(result, Err(BinaryReaderError::Underflow { .. })));
+
+        let result = reader.read(hex::decode("01020304").unwrap(), &Encoding::BoundedBytes(3));
+        assert!(matches!(result, Err(BinaryReaderError::EncodingBoundaryExceeded { boundary: 3, .. })));
+    }
 }
\ No newline at end of file
@@ -48,15 +48,23 @@ use crate::types::{self, Value};
 pub fn write<T>(data: &T, encoding: &Encoding) -> Result<Vec<u8>, Error>
     where
         T: ?Sized + Serialize
+{
+    let mut bytes = Vec::with_capacity(512);
+    write_to(data, encoding, &mut bytes)?;
+    Ok(bytes)
+}
+
+/// Append Tezos binary form of the rust type to the `output`, see [write].
+///
+/// Returns count of bytes appended.
+pub fn write_to<T>(data: &T, encoding: &Encoding, output: &mut Vec<u8>) -> Result<usize, Error>
+    where
+        T: ?Sized + Serialize
 {
     let mut serializer = Serializer::default();
     let value = data.serialize(&mut serializer)?;
 
-    let mut data = Vec::with_capacity(512);
-
-    encode_any(&mut data, &value, encoding)?;
-
-    Ok(data)
+    encode_any(output, &value, encoding)
 }
 
 fn encode_any(data: &mut Vec<u8>, value: &Value, encoding: &Encoding) -> Result<usize, Error> {
@@ -229,6 +237,12 @@ fn encode_value(data: &mut Vec<u8>, value: &Value, encoding: &Encoding) -> Resul
                 _ => Err(Error::encoding_mismatch(encoding, value))
             }
         }
+        Encoding::BoundedString(max_length) => {
+            match value {
+                Value::String(v) if v.len() > *max_length => Err(Error::custom(format!("String of {} bytes exceeds its boundary {}", v.len(), max_length))),
+                _ => encode_value(data, value, &Encoding::String)
+            }
+        }
         Encoding::Enum => {
             match value {
                 Value::Enum(_, ordinal) => {
@@ -239,9 +253,14 @@ fn encode_value(data: &mut Vec<u8>, value: &Value, encoding: &Encoding) -> Resul
                 _ => Err(Error::encoding_mismatch(encoding, value))
             }
         }
-        Encoding::List(list_inner_encoding) => {
+        Encoding::List(list_inner_encoding) | Encoding::BoundedList(_, list_inner_encoding) => {
             match value {
                 Value::List(values) => {
+                    if let Encoding::BoundedList(max_length, _) = encoding {
+                        if values.len() > *max_length {
+                            return Err(Error::custom(format!("List of {} elements exceeds its boundary {}", values.len(), max_length)));
+                        }
+                    }
                     let data_len_before_write = data.len();
                     // write data
                     for value in values {
@@ -267,6 +286,12 @@ fn encode_value(data: &mut Vec<u8>, value: &Value, encoding: &Encoding) -> Resul
                 _ => Err(Error::encoding_mismatch(encoding, value))
             }
         }
+        Encoding::BoundedBytes(max_length) => {
+            match value {
+                Value::List(values) if values.len() > *max_length => Err(Error::custom(format!("Bytes of length {} exceed their boundary {}", values.len(), max_length))),
+                _ => encode_value(data, value, &Encoding::Bytes)
+            }
+        }
         Encoding::Hash(hash_type) => {
             match value {
                 Value::List(ref values) => {
@@ -856,5 +881,23 @@ mod tests {
         let expected_writer_result = hex::decode("00").unwrap();
         assert_eq!(expected_writer_result, writer_result);
     }
-}
+    #[test]
+    fn can_serialize_bounded_list() {
+        #[derive(Serialize, Debug)]
+        struct Record {
+            pub items: Vec<u16>,
+        }
+
+        let record_schema = vec![
+            Field::new("items", Encoding::bounded_list(2, Encoding::Uint16)),
+        ];
+        let record_encoding = Encoding::Obj(record_schema);
 
+        let record = Record { items: vec![1, 2] };
+        let writer_result = write(&record, &record_encoding).unwrap();
+        assert_eq!(hex::decode("00010002").unwrap(), writer_result);
+
+        let record = Record { items: vec![1, 2, 3] };
+        assert!(write(&record, &record_encoding).is_err());
+    }
+}
@@ -0,0 +1,148 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! This feature can provide cache mechanism for BinaryMessages.
+//! Cache is used to reduce computation time of encoding/decoding process.
+//!
+//! When we use cache (see macro [cached_data]):
+//! - first time we read [from_bytes], original bytes are stored to cache and message/struct is constructed from bytes
+//! - so next time we want to use/call [as_bytes], bytes are not calculated with encoding, but just returned from cache
+//!
+//! e.g: this is used, when we receive data from p2p as bytes, and then store them also as bytes to storage and calculate count of bytes in monitoring
+//!
+//! When we dont need cache (see macro [non_cached_data]):
+//!
+//! e.g.: we we just want to read data from bytes and never convert back to bytes
+
+use std::fmt;
+
+use serde::{Deserialize, Deserializer};
+
+pub trait CacheReader {
+    fn get(&self) -> Option<Vec<u8>>;
+}
+
+pub trait CacheWriter {
+    fn put(&mut self, body: &[u8]);
+}
+
+pub trait CachedData {
+    fn cache_reader(&self) -> Option<&dyn CacheReader>;
+    fn cache_writer(&mut self) -> Option<&mut dyn CacheWriter>;
+}
+
+#[derive(Clone, Default)]
+pub struct BinaryDataCache {
+    data: Option<Vec<u8>>
+}
+
+impl CacheReader for BinaryDataCache {
+    #[inline]
+    fn get(&self) -> Option<Vec<u8>> {
+        self.data.as_ref().cloned()
+    }
+}
+
+impl CacheWriter for BinaryDataCache {
+    #[inline]
+    fn put(&mut self, body: &[u8]) {
+        self.data.replace(body.to_vec());
+    }
+}
+
+impl PartialEq for BinaryDataCache {
+    #[inline]
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for BinaryDataCache {}
+
+impl<'de> Deserialize<'de> for BinaryDataCache {
+    fn deserialize<D>(_: D) -> Result<Self, D::Error>
+        where
+            D: Deserializer<'de>
+    {
+        Ok(BinaryDataCache::default())
+    }
+}
+
+impl fmt::Debug for BinaryDataCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.get() {
+            Some(data) => write!(f, "BinaryDataCache {{ has_value: true, len: {} }}", data.len()),
+            None => write!(f, "BinaryDataCache {{ has_value: false }}"),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Default)]
+pub struct NeverCache;
+
+impl<'de> Deserialize<'de> for NeverCache {
+    fn deserialize<D>(_: D) -> Result<Self, D::Error>
+        where
+            D: Deserializer<'de>
+    {
+        Ok(NeverCache::default())
+    }
+}
+
+impl CacheReader for NeverCache {
+    #[inline]
+    fn get(&self) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+impl CacheWriter for NeverCache {
+    #[inline]
+    fn put(&mut self, _: &[u8]) {
+        // ..
+    }
+}
+
+impl fmt::Debug for NeverCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "NeverCache {{ }}")
+    }
+}
+
+/// Adds implementation CachedData for given struct
+/// Struct should contains property [$property_cache_name] with cache struct, e.g. BinaryDataCache,
+/// usually this cache does not need to be serialized, so can be marked with [#[serde(skip_serializing)]]
+#[macro_export]
+macro_rules! cached_data {
+    ($struct_name:ident, $property_cache_name:ident) => {
+        impl $crate::cache::CachedData for $struct_name {
+            #[inline]
+            fn cache_reader(&self) -> Option<&dyn $crate::cache::CacheReader> {
+                Some(&self.$property_cache_name)
+            }
+
+            #[inline]
+            fn cache_writer(&mut self) -> Option<&mut dyn $crate::cache::CacheWriter> {
+                Some(&mut self.$property_cache_name)
+            }
+        }
+    };
+}
+
+/// Adds empty non-caching implementation CachedData for given struct
+#[macro_export]
+macro_rules! non_cached_data {
+    ($struct_name:ident) => {
+        impl $crate::cache::CachedData for $struct_name {
+            #[inline]
+            fn cache_reader(&self) -> Option<&dyn $crate::cache::CacheReader> {
+                None
+            }
+
+            #[inline]
+            fn cache_writer(&mut self) -> Option<&mut dyn $crate::cache::CacheWriter> {
+                None
+            }
+        }
+    };
+}
@@ -162,16 +162,25 @@ pub enum Encoding {
     /// of the string
     /// - encoded as a string in JSON.
     String,
+    /// Same as [Encoding::String], but length of the string in bytes is limited by the boundary.
+    BoundedString(usize),
     /// Encoding of arbitrary sized bytes (encoded via hex in JSON and directly as a sequence byte in binary).
     Bytes,
+    /// Same as [Encoding::Bytes], but count of bytes is limited by the boundary.
+    BoundedBytes(usize),
     /// Tag is prefixed by tag id and followed by encoded bytes
     /// First argument represents size of the tag marker in bytes.
+    /// In JSON, tag is encoded as an object with the variant name as its only key.
     Tags(usize, TagMap),
     /// List combinator. It's behavior is similar to [Encoding::Greedy] encoding. Main distinction
     /// is that we are expecting list of items instead of a single item to be contained in binary data.
     /// - encoded as an array in JSON
     /// - encoded as the concatenation of all the element in binary
     List(Box<Encoding>),
+    /// Same as [Encoding::List], but count of elements is limited by the boundary.
+    ///
+    /// Compatible with ocaml usage: (list ~max_length encoding)
+    BoundedList(usize, Box<Encoding>),
     /// Encode enumeration via association list
     ///  - represented as a string in JSON and
     ///  - represented as an integer representing the element's position in the list in binary. The integer size depends on the list size.
@@ -233,6 +242,13 @@ impl Encoding {
         Encoding::List(Box::new(encoding))
     }
 
+    /// Utility function to construct [Encoding::BoundedList] without the need
+    /// to manually create new [Box].
+    #[inline]
+    pub fn bounded_list(max_length: usize, encoding: Encoding) -> Encoding {
+        Encoding::BoundedList(max_length, Box::new(encoding))
+    }
+
     /// Utility function to construct [Encoding::Sized] without the need
     /// to manually create new [Box].
     #[inline]
@@ -269,6 +285,9 @@ impl Encoding {
     }
 }
 
+/// Derives [HasEncoding] with the schema described by `#[encoding(...)]` attributes (see [tezos_encoding_derive]).
+pub use tezos_encoding_derive::HasEncoding;
+
 /// Indicates that type has it's own ser/de schema.
 pub trait HasEncoding {
     fn encoding() -> &'static Encoding;
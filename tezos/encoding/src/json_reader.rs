@@ -0,0 +1,586 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Tezos json data reader.
+
+use std::fmt;
+
+use chrono::DateTime;
+use failure::Fail;
+use serde_json::Value as JsonValue;
+
+use crypto::base58::FromBase58Check;
+use crypto::hash::HashType;
+
+use crate::binary_reader::BinaryReaderError;
+use crate::encoding::{Encoding, Field, SchemaType};
+use crate::types::Value;
+
+/// Error produced by a [JsonReader].
+///
+/// Every error carries the path of the offending JSON value, e.g. `$.current_branch.history[3]`.
+#[derive(Debug, Fail)]
+pub enum JsonReaderError {
+    /// JSON value is of a different type than the encoding expects.
+    #[fail(display = "{}: expected {}, but found {}", path, expected, found)]
+    UnexpectedType {
+        path: String,
+        expected: &'static str,
+        found: &'static str,
+    },
+    /// JSON value has the expected type, but its content cannot be decoded.
+    #[fail(display = "{}: invalid value, reason: {}", path, reason)]
+    InvalidValue {
+        path: String,
+        reason: String,
+    },
+    /// Required field of an object is not present.
+    #[fail(display = "{}: missing field", path)]
+    MissingField {
+        path: String,
+    },
+    /// No tag with the corresponding variant name was found.
+    #[fail(display = "{}: no tag found for variant: {}", path, variant)]
+    UnsupportedTag {
+        path: String,
+        variant: String,
+    },
+    /// Bounded encoding contains more data than its boundary allows.
+    #[fail(display = "{}: {} exceeds its boundary {}", path, name, boundary)]
+    EncodingBoundaryExceeded {
+        path: String,
+        name: String,
+        boundary: usize,
+    },
+    /// Encoding cannot be read from JSON.
+    #[fail(display = "{}: unsupported encoding {}", path, encoding)]
+    UnsupportedEncoding {
+        path: String,
+        encoding: String,
+    },
+    /// Value read from JSON cannot be deserialized into the target type.
+    #[fail(display = "Message de-serialization error: {}", error)]
+    DeserializationError {
+        error: BinaryReaderError
+    },
+}
+
+impl JsonReaderError {
+    /// Path of the JSON value which caused the error.
+    pub fn path(&self) -> &str {
+        match self {
+            JsonReaderError::UnexpectedType { path, .. }
+            | JsonReaderError::InvalidValue { path, .. }
+            | JsonReaderError::MissingField { path }
+            | JsonReaderError::UnsupportedTag { path, .. }
+            | JsonReaderError::EncodingBoundaryExceeded { path, .. }
+            | JsonReaderError::UnsupportedEncoding { path, .. } => path,
+            // whole value is deserialized at once
+            JsonReaderError::DeserializationError { .. } => "$",
+        }
+    }
+}
+
+impl From<BinaryReaderError> for JsonReaderError {
+    fn from(error: BinaryReaderError) -> Self {
+        JsonReaderError::DeserializationError { error }
+    }
+}
+
+/// Path of the currently decoded JSON value, it is formatted only when an error is reported.
+enum JsonPath<'a> {
+    Root,
+    Field(&'a JsonPath<'a>, &'a str),
+    Index(&'a JsonPath<'a>, usize),
+}
+
+impl fmt::Display for JsonPath<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JsonPath::Root => write!(f, "$"),
+            JsonPath::Field(parent, name) => write!(f, "{}.{}", parent, name),
+            JsonPath::Index(parent, idx) => write!(f, "{}[{}]", parent, idx),
+        }
+    }
+}
+
+/// Converts Tezos json data into rust types.
+///
+/// JSON is expected in the form produced by [crate::json_writer::JsonWriter].
+pub struct JsonReader;
+
+impl JsonReader {
+    /// Construct new instance of the [JsonReader].
+    pub fn new() -> Self {
+        Self
+    }
+
+    /// Convert JSON into [intermadiate form](Value). Input JSON is parsed according to [`encoding`](Encoding).
+    ///
+    /// # Examples:
+    ///
+    /// ```
+    /// use serde::Deserialize;
+    /// use tezos_encoding::json_reader::JsonReader;
+    /// use tezos_encoding::de;
+    /// use tezos_encoding::encoding::{Field, Encoding};
+    ///
+    /// #[derive(Deserialize, Debug, PartialEq)]
+    /// struct Version {
+    ///    name: String,
+    ///    major: u16,
+    ///    minor: u16,
+    /// }
+    ///
+    /// let version_schema = Encoding::Obj(vec![
+    ///     Field::new("name", Encoding::String),
+    ///     Field::new("major", Encoding::Uint16),
+    ///     Field::new("minor", Encoding::Uint16)
+    /// ]);
+    ///
+    /// let reader = JsonReader::new();
+    /// // create intermediate form
+    /// let json = serde_json::json!({ "name": "v1.0", "major": 1, "minor": 0 });
+    /// let intermediate = reader.read(&json, &version_schema).unwrap();
+    /// // deserialize from intermediate form
+    /// let version = de::from_value::<Version>(&intermediate).unwrap();
+    ///
+    /// let version_expected = Version { name: "v1.0".into(), major: 1, minor: 0 };
+    ///
+    /// assert_eq!(version, version_expected);
+    /// ```
+    pub fn read(&self, json: &JsonValue, encoding: &Encoding) -> Result<Value, JsonReaderError> {
+        self.decode_value(json, encoding, &JsonPath::Root)
+    }
+
+    fn decode_record(&self, json: &JsonValue, schema: &[Field], path: &JsonPath) -> Result<Value, JsonReaderError> {
+        let object = match json {
+            JsonValue::Object(object) => object,
+            _ => return Err(unexpected_type("object", json, path)),
+        };
+
+        let mut values = Vec::with_capacity(schema.len());
+        for field in schema {
+            let name = field.get_name();
+            let encoding = field.get_encoding();
+            let field_path = JsonPath::Field(path, name);
+            let value = match (object.get(name.as_str()), encoding) {
+                (Some(field_json), _) => self.decode_value(field_json, encoding, &field_path)?,
+                // optional field can be omitted
+                (None, Encoding::OptionalField(_)) => Value::Option(None),
+                (None, _) => return Err(JsonReaderError::MissingField { path: field_path.to_string() }),
+            };
+            values.push((name.clone(), value));
+        }
+        Ok(Value::Record(values))
+    }
+
+    fn decode_tuple(&self, json: &JsonValue, encodings: &[Encoding], path: &JsonPath) -> Result<Value, JsonReaderError> {
+        match json {
+            JsonValue::Array(items) if items.len() == encodings.len() => {
+                let mut values = Vec::with_capacity(encodings.len());
+                for (idx, (item, encoding)) in items.iter().zip(encodings).enumerate() {
+                    values.push(self.decode_value(item, encoding, &JsonPath::Index(path, idx))?);
+                }
+                Ok(Value::Tuple(values))
+            }
+            JsonValue::Array(items) => Err(invalid_value(format!("expected {} elements, but found {}", encodings.len(), items.len()), path)),
+            _ => Err(unexpected_type("array", json, path)),
+        }
+    }
+
+    fn decode_list(&self, json: &JsonValue, encoding_inner: &Encoding, max_length: usize, path: &JsonPath) -> Result<Value, JsonReaderError> {
+        let items = match json {
+            JsonValue::Array(items) => items,
+            _ => return Err(unexpected_type("array", json, path)),
+        };
+        if items.len() > max_length {
+            return Err(JsonReaderError::EncodingBoundaryExceeded { path: path.to_string(), name: "List".to_string(), boundary: max_length });
+        }
+
+        let mut values = Vec::with_capacity(items.len());
+        for (idx, item) in items.iter().enumerate() {
+            values.push(self.decode_value(item, encoding_inner, &JsonPath::Index(path, idx))?);
+        }
+        Ok(Value::List(values))
+    }
+
+    fn decode_value(&self, json: &JsonValue, encoding: &Encoding, path: &JsonPath) -> Result<Value, JsonReaderError> {
+        match encoding {
+            Encoding::Unit => {
+                match json {
+                    JsonValue::Null => Ok(Value::Unit),
+                    _ => Err(unexpected_type("null", json, path))
+                }
+            }
+            Encoding::Int8 => Ok(Value::Int8(decode_integer(json, path)?)),
+            Encoding::Uint8 => Ok(Value::Uint8(decode_integer(json, path)?)),
+            Encoding::Int16 => Ok(Value::Int16(decode_integer(json, path)?)),
+            Encoding::Uint16 => Ok(Value::Uint16(decode_integer(json, path)?)),
+            Encoding::Int31 => {
+                let v: i32 = decode_integer(json, path)?;
+                if (-0x4000_0000..0x4000_0000).contains(&v) {
+                    Ok(Value::Int31(v))
+                } else {
+                    Err(invalid_value("value is outside of Int31 range", path))
+                }
+            }
+            Encoding::Int32 => Ok(Value::Int32(decode_integer(json, path)?)),
+            Encoding::Uint32 => {
+                // Uint32 has the same intermediate form as Int32, see the binary writer
+                let v: i32 = decode_integer(json, path)?;
+                if v >= 0 {
+                    Ok(Value::Int32(v))
+                } else {
+                    Err(invalid_value("value is outside of Uint32 range", path))
+                }
+            }
+            Encoding::Int64 => {
+                match json {
+                    // 64 bit integers are decimal strings in Tezos JSON, but numbers are accepted too
+                    JsonValue::String(v) => v.parse::<i64>()
+                        .map(Value::Int64)
+                        .map_err(|e| invalid_value(e, path)),
+                    _ => Ok(Value::Int64(decode_integer(json, path)?)),
+                }
+            }
+            Encoding::Timestamp => {
+                match json {
+                    JsonValue::String(v) => DateTime::parse_from_rfc3339(v)
+                        .map(|timestamp| Value::Int64(timestamp.timestamp()))
+                        .map_err(|e| invalid_value(e, path)),
+                    _ => Err(unexpected_type("string", json, path))
+                }
+            }
+            Encoding::Float => {
+                match json.as_f64() {
+                    Some(v) => Ok(Value::Float(v)),
+                    None => Err(unexpected_type("number", json, path))
+                }
+            }
+            Encoding::Bool => {
+                match json {
+                    JsonValue::Bool(v) => Ok(Value::Bool(*v)),
+                    _ => Err(unexpected_type("boolean", json, path))
+                }
+            }
+            Encoding::String => Ok(Value::String(decode_string(json, path)?.to_string())),
+            Encoding::BoundedString(max_length) => {
+                let v = decode_string(json, path)?;
+                if v.len() > *max_length {
+                    return Err(JsonReaderError::EncodingBoundaryExceeded { path: path.to_string(), name: "String".to_string(), boundary: *max_length });
+                }
+                Ok(Value::String(v.to_string()))
+            }
+            Encoding::Z | Encoding::Mutez => {
+                let v = decode_string(json, path)?;
+                let num = num_bigint::BigInt::parse_bytes(v.as_bytes(), 10)
+                    .ok_or_else(|| invalid_value(format!("'{}' is not a decimal number", v), path))?;
+                if let (Encoding::Mutez, num_bigint::Sign::Minus) = (encoding, num.sign()) {
+                    return Err(invalid_value("Mutez cannot be negative", path));
+                }
+                // intermediate form of big numbers is a hex string
+                Ok(Value::String(num.to_str_radix(16)))
+            }
+            Encoding::Enum => Ok(Value::Enum(Some(decode_string(json, path)?.to_string()), None)),
+            Encoding::List(encoding_inner) => {
+                self.decode_list(json, encoding_inner, usize::MAX, path)
+            }
+            Encoding::BoundedList(max_length, encoding_inner) => {
+                self.decode_list(json, encoding_inner, *max_length, path)
+            }
+            Encoding::Bytes => {
+                let bytes = hex::decode(decode_string(json, path)?).map_err(|e| invalid_value(e, path))?;
+                Ok(bytes_value(bytes))
+            }
+            Encoding::BoundedBytes(max_length) => {
+                let bytes = hex::decode(decode_string(json, path)?).map_err(|e| invalid_value(e, path))?;
+                if bytes.len() > *max_length {
+                    return Err(JsonReaderError::EncodingBoundaryExceeded { path: path.to_string(), name: "Bytes".to_string(), boundary: *max_length });
+                }
+                Ok(bytes_value(bytes))
+            }
+            Encoding::Hash(hash_type) => {
+                let bytes = decode_hash(*hash_type, decode_string(json, path)?).map_err(|e| invalid_value(e, path))?;
+                Ok(bytes_value(bytes))
+            }
+            Encoding::Option(_) | Encoding::OptionalField(_) => {
+                match json {
+                    JsonValue::Null => Ok(Value::Option(None)),
+                    _ => {
+                        let v = self.decode_value(json, encoding.try_unwrap_option_encoding(), path)?;
+                        Ok(Value::Option(Some(Box::new(v))))
+                    }
+                }
+            }
+            Encoding::Obj(schema_inner) => {
+                self.decode_record(json, schema_inner, path)
+            }
+            Encoding::Tup(encodings_inner) => {
+                self.decode_tuple(json, encodings_inner, path)
+            }
+            Encoding::Dynamic(dynamic_encoding) => {
+                self.decode_value(json, dynamic_encoding, path)
+            }
+            Encoding::Sized(_, sized_encoding) => {
+                self.decode_value(json, sized_encoding, path)
+            }
+            Encoding::Greedy(un_sized_encoding) => {
+                self.decode_value(json, un_sized_encoding, path)
+            }
+            Encoding::Tags(_, tag_map) => {
+                // tag is an object with the variant name as its only key
+                match json {
+                    JsonValue::Object(object) if object.len() == 1 => {
+                        let (variant, tag_json) = object.iter().next().expect("Object has exactly one key");
+                        match tag_map.find_by_variant(variant) {
+                            Some(tag) => {
+                                let tag_value = self.decode_value(tag_json, tag.get_encoding(), &JsonPath::Field(path, variant))?;
+                                Ok(Value::Tag(variant.clone(), Box::new(tag_value)))
+                            }
+                            None => Err(JsonReaderError::UnsupportedTag { path: path.to_string(), variant: variant.clone() })
+                        }
+                    }
+                    JsonValue::Object(_) => Err(invalid_value("tag object has to contain exactly one variant", path)),
+                    _ => Err(unexpected_type("object", json, path))
+                }
+            }
+            Encoding::Split(inner_encoding) => {
+                let inner_encoding = inner_encoding(SchemaType::Json);
+                self.decode_value(json, &inner_encoding, path)
+            }
+            Encoding::Lazy(fn_encoding) => {
+                let inner_encoding = fn_encoding();
+                self.decode_value(json, &inner_encoding, path)
+            }
+            Encoding::RangedInt
+            | Encoding::RangedFloat => Err(JsonReaderError::UnsupportedEncoding { path: path.to_string(), encoding: format!("{:?}", encoding) })
+        }
+    }
+}
+
+impl Default for JsonReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn unexpected_type(expected: &'static str, json: &JsonValue, path: &JsonPath) -> JsonReaderError {
+    let found = match json {
+        JsonValue::Null => "null",
+        JsonValue::Bool(_) => "boolean",
+        JsonValue::Number(_) => "number",
+        JsonValue::String(_) => "string",
+        JsonValue::Array(_) => "array",
+        JsonValue::Object(_) => "object",
+    };
+    JsonReaderError::UnexpectedType { path: path.to_string(), expected, found }
+}
+
+fn invalid_value<T: fmt::Display>(reason: T, path: &JsonPath) -> JsonReaderError {
+    JsonReaderError::InvalidValue { path: path.to_string(), reason: reason.to_string() }
+}
+
+fn decode_string<'a>(json: &'a JsonValue, path: &JsonPath) -> Result<&'a str, JsonReaderError> {
+    match json {
+        JsonValue::String(v) => Ok(v),
+        _ => Err(unexpected_type("string", json, path))
+    }
+}
+
+/// Read JSON integer and check that it fits into the target type
+fn decode_integer<T: std::convert::TryFrom<i64>>(json: &JsonValue, path: &JsonPath) -> Result<T, JsonReaderError> {
+    match json.as_i64() {
+        Some(v) => T::try_from(v).map_err(|_| invalid_value(format!("{} is out of range", v), path)),
+        None => match json {
+            JsonValue::Number(v) => Err(invalid_value(format!("{} is not an integer", v), path)),
+            _ => Err(unexpected_type("integer", json, path))
+        }
+    }
+}
+
+/// Bytes have the same intermediate form as when they are read from binary
+fn bytes_value(bytes: Vec<u8>) -> Value {
+    Value::List(bytes.into_iter().map(Value::Uint8).collect())
+}
+
+/// Decode base58check encoded hash and check its prefix and length
+fn decode_hash(hash_type: HashType, data: &str) -> Result<Vec<u8>, String> {
+    let mut hash = data.from_base58check().map_err(|e| e.to_string())?;
+    let prefix = hash_type.prefix();
+    if hash.len() != prefix.len() + hash_type.size() || !hash.starts_with(prefix) {
+        return Err(format!("'{}' is not {:?}", data, hash_type));
+    }
+    // prefix is not present in a binary representation
+    hash.drain(0..prefix.len());
+    Ok(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::size_of;
+
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+
+    use crate::de;
+    use crate::encoding::{Tag, TagMap};
+    use crate::json_writer::JsonWriter;
+    use crate::types::BigInt;
+
+    use super::*;
+
+    #[test]
+    fn can_deserialize_complex_schema_from_json() {
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct Version {
+            name: String,
+            major: u16,
+            minor: u16,
+        }
+
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct Record {
+            a: i32,
+            b: bool,
+            c: Option<BigInt>,
+            d: f64,
+            f: Vec<Version>,
+            h: Vec<u8>,
+            p: Vec<u8>,
+            t: i64,
+            m: BigInt,
+            ofs: Option<String>,
+        }
+
+        let record_schema = Encoding::Obj(vec![
+            Field::new("a", Encoding::Int31),
+            Field::new("b", Encoding::Bool),
+            Field::new("t", Encoding::Timestamp),
+            Field::new("p", Encoding::sized(32, Encoding::Bytes)),
+            Field::new("c", Encoding::Option(Box::new(Encoding::Z))),
+            Field::new("d", Encoding::Float),
+            Field::new("f", Encoding::dynamic(Encoding::list(Encoding::Obj(vec![
+                Field::new("name", Encoding::String),
+                Field::new("major", Encoding::Uint16),
+                Field::new("minor", Encoding::Uint16)
+            ])))),
+            Field::new("h", Encoding::Hash(HashType::ChainId)),
+            Field::new("m", Encoding::Mutez),
+            Field::new("ofs", Encoding::OptionalField(Box::new(Encoding::String))),
+        ]);
+
+        let json = json!({
+            "a": 32,
+            "b": true,
+            "t": "2019-03-21T00:10:11+00:00",
+            "p": "6cf20139cedef0ed52395a327ad13390d9e8c1e999339a24f8513fe513ed689a",
+            "c": "-1548569249",
+            "d": 12.34,
+            "f": [{ "name": "A", "major": 1, "minor": 1 }, { "name": "B", "major": 2, "minor": 0 }],
+            "h": "NetXgtSLGNJvNye",
+            "m": "3000"
+        });
+        let value = JsonReader::new().read(&json, &record_schema).unwrap();
+        let record: Record = de::from_value(&value).unwrap();
+
+        let expected = Record {
+            a: 32,
+            b: true,
+            c: Some(num_bigint::BigInt::from(-1_548_569_249).into()),
+            d: 12.34,
+            f: vec![Version { name: "A".to_string(), major: 1, minor: 1 }, Version { name: "B".to_string(), major: 2, minor: 0 }],
+            h: hex::decode("8eceda2f").unwrap(),
+            p: hex::decode("6cf20139cedef0ed52395a327ad13390d9e8c1e999339a24f8513fe513ed689a").unwrap(),
+            t: 1_553_127_011,
+            m: num_bigint::BigInt::from(3000).into(),
+            ofs: None,
+        };
+        assert_eq!(expected, record);
+
+        // written JSON can be read again
+        let written = JsonWriter::new().write(&record, &record_schema).unwrap();
+        let value = JsonReader::new().read(&serde_json::from_str(&written).unwrap(), &record_schema).unwrap();
+        assert_eq!(expected, de::from_value::<Record>(&value).unwrap());
+    }
+
+    #[test]
+    fn can_deserialize_tags_from_json() {
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        enum Message {
+            Ping,
+            Head(Version),
+        }
+
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct Version {
+            major: u16,
+        }
+
+        let encoding = Encoding::list(Encoding::Tags(
+            size_of::<u8>(),
+            TagMap::new(vec![
+                Tag::new(0x01, "Ping", Encoding::Unit),
+                Tag::new(0x02, "Head", Encoding::Obj(vec![Field::new("major", Encoding::Uint16)])),
+            ]),
+        ));
+
+        let messages = vec![Message::Ping, Message::Head(Version { major: 2 })];
+        let written = JsonWriter::new().write(&messages, &encoding).unwrap();
+        assert_eq!(r#"[{ "Ping": null }, { "Head": { "major": 2 } }]"#, written);
+
+        let value = JsonReader::new().read(&serde_json::from_str(&written).unwrap(), &encoding).unwrap();
+        assert_eq!(messages, de::from_value::<Vec<Message>>(&value).unwrap());
+
+        let error = JsonReader::new().read(&json!([{ "Pong": null }]), &encoding).unwrap_err();
+        assert_eq!("$[0]", error.path());
+    }
+
+    #[test]
+    fn can_report_json_path_of_error() {
+        let encoding = Encoding::Obj(vec![
+            Field::new("branch", Encoding::Obj(vec![
+                Field::new("history", Encoding::bounded_list(2, Encoding::Hash(HashType::BlockHash))),
+                Field::new("level", Encoding::Int32),
+            ])),
+        ]);
+        let reader = JsonReader::new();
+
+        let error = reader.read(&json!({ "branch": { "history": ["BLaf78njreWdt2WigJjM9e3ecEdVKm5ehahUfYBKvcWvZ8vfTcJ", "NetXgtSLGNJvNye"], "level": 1 } }), &encoding).unwrap_err();
+        assert_eq!("$.branch.history[1]", error.path());
+
+        let error = reader.read(&json!({ "branch": { "history": [], "level": "1" } }), &encoding).unwrap_err();
+        assert_eq!("$.branch.level", error.path());
+        assert_eq!("$.branch.level: expected integer, but found string", error.to_string());
+
+        let error = reader.read(&json!({ "branch": { "history": [] } }), &encoding).unwrap_err();
+        assert_eq!("$.branch.level: missing field", error.to_string());
+
+        let error = reader.read(&json!({ "branch": { "history": [1, 2, 3], "level": 1 } }), &encoding).unwrap_err();
+        assert_eq!("$.branch.history: List exceeds its boundary 2", error.to_string());
+
+        let error = reader.read(&json!({ "branch": { "history": [], "level": 5_000_000_000_i64 } }), &encoding).unwrap_err();
+        assert_eq!("$.branch.level", error.path());
+    }
+
+    #[test]
+    fn can_deserialize_uint32_from_json() {
+        let value = JsonReader::new().read(&json!(2_147_483_647), &Encoding::Uint32).unwrap();
+        assert_eq!(Value::Int32(i32::MAX), value);
+        assert_eq!(2_147_483_647_u32, de::from_value::<u32>(&value).unwrap());
+    }
+
+    #[test]
+    fn can_not_deserialize_invalid_values() {
+        let reader = JsonReader::new();
+        assert!(reader.read(&json!("-1"), &Encoding::Mutez).is_err());
+        assert!(reader.read(&json!("0x10"), &Encoding::Z).is_err());
+        assert!(reader.read(&json!("a0b"), &Encoding::Bytes).is_err());
+        assert!(reader.read(&json!("2019-03-21"), &Encoding::Timestamp).is_err());
+        assert!(reader.read(&json!(256), &Encoding::Uint8).is_err());
+        assert!(reader.read(&json!(-1), &Encoding::Uint32).is_err());
+        assert!(reader.read(&json!("abc"), &Encoding::BoundedString(2)).is_err());
+        // chain id is not a block hash
+        assert!(reader.read(&json!("NetXgtSLGNJvNye"), &Encoding::Hash(HashType::BlockHash)).is_err());
+    }
+}
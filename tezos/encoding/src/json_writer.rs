@@ -78,7 +78,18 @@ impl JsonWriter {
 
     fn push_str(&mut self, value: &str) {
         self.data.push('"');
-        self.data.push_str(&value.replace("\"", "\\\"").replace("\n", "\\n").replace("\r", "\\r"));
+        for c in value.chars() {
+            match c {
+                '"' => self.data.push_str("\\\""),
+                '\\' => self.data.push_str("\\\\"),
+                '\n' => self.data.push_str("\\n"),
+                '\r' => self.data.push_str("\\r"),
+                '\t' => self.data.push_str("\\t"),
+                // remaining control characters have to be escaped too
+                c if (c as u32) < 0x20 => self.data.push_str(&format!("\\u{:04x}", c as u32)),
+                c => self.data.push(c),
+            }
+        }
         self.data.push('"');
     }
 
@@ -204,12 +215,22 @@ impl JsonWriter {
                 }
             }
             Encoding::String |
-            Encoding::Z | Encoding::Mutez => {
+            Encoding::BoundedString(_) => {
                 match value {
                     Value::String(v) => Ok(self.push_str(v)),
                     _ => Err(Error::encoding_mismatch(encoding, value))
                 }
             }
+            Encoding::Z | Encoding::Mutez => {
+                match value {
+                    // intermediate form of big numbers is a hex string, but JSON contains a decimal string
+                    Value::String(v) => match num_bigint::BigInt::parse_bytes(v.as_bytes(), 16) {
+                        Some(num) => Ok(self.push_str(&num.to_string())),
+                        None => Err(Error::custom(format!("Value is not a hex number: {}", v)))
+                    },
+                    _ => Err(Error::encoding_mismatch(encoding, value))
+                }
+            }
             Encoding::Enum => {
                 match value {
                     Value::Enum(name, _) => {
@@ -219,7 +240,7 @@ impl JsonWriter {
                     _ => Err(Error::encoding_mismatch(encoding, value))
                 }
             }
-            Encoding::List(list_inner_encoding) => {
+            Encoding::List(list_inner_encoding) | Encoding::BoundedList(_, list_inner_encoding) => {
                 match value {
                     Value::List(ref values) => {
                         self.open_array();
@@ -234,7 +255,7 @@ impl JsonWriter {
                     _ => Err(Error::encoding_mismatch(encoding, value))
                 }
             }
-            Encoding::Bytes => {
+            Encoding::Bytes | Encoding::BoundedBytes(_) => {
                 match value {
                     Value::List(values) => {
                         let mut bytes = vec![];
@@ -294,8 +315,26 @@ impl JsonWriter {
             Encoding::Greedy(un_sized_encoding) => {
                 self.encode_value(value, un_sized_encoding)
             }
-            Encoding::Tags(_, _) => {
-                unimplemented!("Encoding::Tags encoding is not supported for JSON format")
+            Encoding::Tags(_, tag_map) => {
+                // tag is written as an object with the variant name as its only key
+                let (tag_variant, tag_value) = match value {
+                    Value::Tag(tag_variant, tag_value) => (tag_variant, Some(tag_value)),
+                    Value::Enum(Some(tag_variant), _) => (tag_variant, None),
+                    _ => return Err(Error::encoding_mismatch(encoding, value))
+                };
+                match tag_map.find_by_variant(tag_variant) {
+                    Some(tag) => {
+                        self.open_record();
+                        self.push_key(tag_variant);
+                        match tag_value {
+                            Some(tag_value) => self.encode_value(tag_value, tag.get_encoding())?,
+                            None => self.push_null()
+                        }
+                        self.close_record();
+                        Ok(())
+                    }
+                    None => Err(Error::custom(format!("No tag found for variant: {}", tag_variant)))
+                }
             }
             Encoding::Split(fn_encoding) => {
                 let inner_encoding = fn_encoding(SchemaType::Json);
@@ -317,10 +356,13 @@ impl JsonWriter {
 
 #[cfg(test)]
 mod tests {
+    use std::mem::size_of;
+
     use serde::Serialize;
 
     use crypto::hash::HashType;
 
+    use crate::encoding::{Tag, TagMap};
     use crate::types::BigInt;
 
     use super::*;
@@ -412,7 +454,33 @@ mod tests {
         let writer_result = writer.write(&record, &Encoding::Obj(record_schema));
         assert!(writer_result.is_ok());
 
-        let expected_writer_result = r#"{ "a": 32, "b": true, "t": "2019-03-21T00:10:11+00:00", "s": { "x": 5, "y": 32, "v": [12, 34] }, "p": "6cf20139cedef0ed52395a327ad13390d9e8c1e999339a24f8513fe513ed689a", "c": "5c4d4aa1", "d": 12.34, "e": "Disconnected", "f": [{ "name": "A", "major": 1, "minor": 1 }, { "name": "B", "major": 2, "minor": 0 }], "h": "NetXgtSLGNJvNye", "ofs": "ofs" }"#;
+        let expected_writer_result = r#"{ "a": 32, "b": true, "t": "2019-03-21T00:10:11+00:00", "s": { "x": 5, "y": 32, "v": [12, 34] }, "p": "6cf20139cedef0ed52395a327ad13390d9e8c1e999339a24f8513fe513ed689a", "c": "1548569249", "d": 12.34, "e": "Disconnected", "f": [{ "name": "A", "major": 1, "minor": 1 }, { "name": "B", "major": 2, "minor": 0 }], "h": "NetXgtSLGNJvNye", "ofs": "ofs" }"#;
+        assert_eq!(expected_writer_result, writer_result.unwrap());
+    }
+
+    #[test]
+    fn can_serialize_tags_and_escaped_strings_to_json() {
+        #[derive(Serialize, Debug)]
+        enum Message {
+            Disconnect,
+            Text(String),
+        }
+
+        let schema = Encoding::dynamic(Encoding::list(Encoding::Tags(
+            size_of::<u16>(),
+            TagMap::new(vec![
+                Tag::new(0x01, "Disconnect", Encoding::Unit),
+                Tag::new(0x02, "Text", Encoding::String),
+            ]),
+        )));
+
+        let messages = vec![Message::Disconnect, Message::Text("a\"b\\c\nd\u{1}".to_string())];
+
+        let mut writer = JsonWriter::new();
+        let writer_result = writer.write(&messages, &schema);
+        assert!(writer_result.is_ok());
+
+        let expected_writer_result = r#"[{ "Disconnect": null }, { "Text": "a\"b\\c\nd\u0001" }]"#;
         assert_eq!(expected_writer_result, writer_result.unwrap());
     }
 }
@@ -8,8 +8,17 @@ pub mod types;
 mod bit_utils;
 
 pub mod encoding;
+pub mod cache;
 pub mod de;
 pub mod ser;
 pub mod binary_reader;
 pub mod binary_writer;
-pub mod json_writer;
\ No newline at end of file
+pub mod json_writer;
+pub mod json_reader;
+
+/// Items used by the code generated by `#[derive(HasEncoding)]`, not a public API.
+#[doc(hidden)]
+pub mod __private {
+    pub use crypto::hash::HashType;
+    pub use lazy_static::lazy_static;
+}
@@ -0,0 +1,30 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! `HasEncoding` with the binary data cache is derived outside of `tezos_messages`.
+
+use tezos_encoding::cache::{BinaryDataCache, CachedData};
+use tezos_encoding::encoding::{Encoding, HasEncoding};
+
+#[derive(HasEncoding)]
+struct CachedMessage {
+    #[encoding(bounded_string = 10)]
+    #[allow(dead_code)]
+    name: String,
+    #[encoding(cache)]
+    body: BinaryDataCache,
+}
+
+#[test]
+fn can_derive_cached_data_outside_of_messages() {
+    // cache field is not encoded
+    match CachedMessage::encoding() {
+        Encoding::Obj(fields) => assert_eq!(vec!["name"], fields.iter().map(|field| field.get_name().as_str()).collect::<Vec<_>>()),
+        encoding => panic!("Expected Obj, got: {:?}", encoding),
+    }
+
+    let mut message = CachedMessage { name: "test".to_string(), body: Default::default() };
+    assert_eq!(None, message.cache_reader().unwrap().get());
+    message.cache_writer().unwrap().put(&[1, 2, 3]);
+    assert_eq!(Some(vec![1, 2, 3]), message.cache_reader().unwrap().get());
+}
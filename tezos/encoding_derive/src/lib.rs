@@ -0,0 +1,385 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+#![forbid(unsafe_code)]
+
+//! Derive macro for the `tezos_encoding::encoding::HasEncoding` trait.
+//!
+//! Struct is encoded as [Encoding::Obj] with fields in the order of declaration, enum as [Encoding::Tags].
+//! Encoding of a field is described by `#[encoding(...)]` attribute, which is a chain of combinators
+//! (applied from the outermost one) optionally ended by an encoding of the innermost item:
+//!
+//! ```ignore
+//! #[derive(Serialize, Deserialize, HasEncoding)]
+//! pub struct GetBlockHeadersMessage {
+//!     #[encoding(dynamic, bounded_list = "GET_BLOCK_HEADERS_MAX_LENGTH", hash = "BlockHash")]
+//!     get_block_headers: Vec<BlockHash>,
+//!     #[encoding(cache)]
+//!     body: BinaryDataCache,
+//! }
+//! ```
+//!
+//! Combinators:
+//! - `dynamic`, `greedy`, `sized = N`
+//! - `list`, `bounded_list = N` - the field type is expected to be `Vec<T>`
+//! - `option`, `optional_field` - the field type is expected to be `Option<T>`
+//! - `lazy` - for recursive encodings, `Box<T>` is unwrapped
+//!
+//! Innermost item:
+//! - `unit`, `int8`, `uint8`, `int16`, `uint16`, `int31`, `int32`, `uint32`, `int64`, `z`, `mutez`, `float`, `bool`, `string`, `bytes`, `timestamp`
+//! - `bounded_string = N`, `bounded_bytes = N`
+//! - `hash = "HashType"`, e.g. `hash = "BlockHash"`
+//! - `custom = "expr"`, any expression of type `Encoding`
+//!
+//! If the innermost item is not specified, it is inferred from the type: primitive types and `String`
+//! have their own encodings, encoding of other types is taken from their `HasEncoding` implementation.
+//!
+//! Field marked with `#[encoding(cache)]` is not encoded, it is used as a binary data cache by `tezos_encoding::cached_data!`.
+//!
+//! Enum requires `#[encoding(tags = "u8")]` (or `"u16"`), every variant requires `#[encoding(tag = ID)]`.
+//! Variant is either a unit variant, or it has a single unnamed field, which encoding is described
+//! in the same attribute as the tag.
+//!
+//! [Encoding::Obj]: ../tezos_encoding/encoding/enum.Encoding.html#variant.Obj
+//! [Encoding::Tags]: ../tezos_encoding/encoding/enum.Encoding.html#variant.Tags
+
+extern crate proc_macro;
+
+use proc_macro2::{Span, TokenStream};
+use quote::quote;
+use syn::{Attribute, Data, DeriveInput, Error, Expr, Fields, GenericArgument, Ident, Lit, Meta, NestedMeta, parse_macro_input, PathArguments, Result, Type};
+
+#[proc_macro_derive(HasEncoding, attributes(encoding))]
+pub fn derive_has_encoding(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+fn expand(input: DeriveInput) -> Result<TokenStream> {
+    if !input.generics.params.is_empty() {
+        return Err(Error::new_spanned(&input.generics, "HasEncoding cannot be derived for generic types"));
+    }
+
+    let name = &input.ident;
+    let (encoding, cache) = match &input.data {
+        Data::Struct(data) => expand_struct(&data.fields)?,
+        Data::Enum(data) => (expand_enum(&input.attrs, data.variants.iter())?, None),
+        Data::Union(_) => return Err(Error::new_spanned(&input, "HasEncoding cannot be derived for unions")),
+    };
+
+    let cached_data = cache.map(|cache| quote! {
+        ::tezos_encoding::cached_data!(#name, #cache);
+    });
+
+    Ok(quote! {
+        impl ::tezos_encoding::encoding::HasEncoding for #name {
+            fn encoding() -> &'static ::tezos_encoding::encoding::Encoding {
+                ::tezos_encoding::__private::lazy_static! {
+                    static ref ENCODING: ::tezos_encoding::encoding::Encoding = #encoding;
+                }
+                &ENCODING
+            }
+        }
+
+        #cached_data
+    })
+}
+
+/// Returns [Encoding::Obj] of the struct and the cache field, if there is any
+fn expand_struct(fields: &Fields) -> Result<(TokenStream, Option<Ident>)> {
+    let fields = match fields {
+        Fields::Named(fields) => &fields.named,
+        _ => return Err(Error::new_spanned(fields, "HasEncoding can be derived only for structs with named fields")),
+    };
+
+    let mut cache = None;
+    let mut encoded_fields = Vec::new();
+    for field in fields {
+        let ident = field.ident.as_ref().expect("Named field has an identifier");
+        let spec = FieldSpec::parse(&field.attrs)?;
+        if spec.cache {
+            if cache.is_some() {
+                return Err(Error::new_spanned(field, "Only one field can be marked as cache"));
+            }
+            cache = Some(ident.clone());
+            continue;
+        }
+        let field_name = ident.to_string();
+        let encoding = spec.encoding(&field.ty)?;
+        encoded_fields.push(quote! {
+            ::tezos_encoding::encoding::Field::new(#field_name, #encoding)
+        });
+    }
+
+    let encoding = quote! {
+        ::tezos_encoding::encoding::Encoding::Obj(vec![#(#encoded_fields),*])
+    };
+    Ok((encoding, cache))
+}
+
+/// Returns [Encoding::Tags] of the enum
+fn expand_enum<'a>(attrs: &[Attribute], variants: impl Iterator<Item=&'a syn::Variant>) -> Result<TokenStream> {
+    let tag_size = parse_tag_size(attrs)?;
+
+    let mut tags = Vec::new();
+    for variant in variants {
+        let spec = FieldSpec::parse(&variant.attrs)?;
+        let id = match &spec.tag {
+            Some(id) => id,
+            None => return Err(Error::new_spanned(variant, "Missing #[encoding(tag = ID)] of the variant")),
+        };
+        let variant_name = variant.ident.to_string();
+        let encoding = match &variant.fields {
+            Fields::Unit => quote! { ::tezos_encoding::encoding::Encoding::Unit },
+            Fields::Unnamed(fields) if fields.unnamed.len() == 1 => spec.encoding(&fields.unnamed[0].ty)?,
+            _ => return Err(Error::new_spanned(variant, "Variant must be a unit variant or it must have a single unnamed field")),
+        };
+        tags.push(quote! {
+            ::tezos_encoding::encoding::Tag::new(#id, #variant_name, #encoding)
+        });
+    }
+
+    Ok(quote! {
+        ::tezos_encoding::encoding::Encoding::Tags(
+            #tag_size,
+            ::tezos_encoding::encoding::TagMap::new(vec![#(#tags),*]),
+        )
+    })
+}
+
+/// Parse `#[encoding(tags = "u8")]` of the enum
+fn parse_tag_size(attrs: &[Attribute]) -> Result<TokenStream> {
+    match encoding_metas(attrs)?.first() {
+        Some(Meta::NameValue(name_value)) if name_value.path.is_ident("tags") => {
+            match &name_value.lit {
+                Lit::Str(size) if size.value() == "u8" => Ok(quote! { ::std::mem::size_of::<u8>() }),
+                Lit::Str(size) if size.value() == "u16" => Ok(quote! { ::std::mem::size_of::<u16>() }),
+                lit => Err(Error::new_spanned(lit, "Tag size must be \"u8\" or \"u16\"")),
+            }
+        }
+        Some(meta) => Err(Error::new_spanned(meta, "Unsupported enum encoding attribute")),
+        None => Err(Error::new(Span::call_site(), "Missing #[encoding(tags = \"u8\")] or #[encoding(tags = \"u16\")] of the enum")),
+    }
+}
+
+/// Items of all `#[encoding(...)]` attributes
+fn encoding_metas(attrs: &[Attribute]) -> Result<Vec<Meta>> {
+    let mut metas = Vec::new();
+    for attr in attrs.iter().filter(|attr| attr.path.is_ident("encoding")) {
+        match attr.parse_meta()? {
+            Meta::List(list) => {
+                for nested in list.nested {
+                    match nested {
+                        NestedMeta::Meta(meta) => metas.push(meta),
+                        NestedMeta::Lit(lit) => return Err(Error::new_spanned(lit, "Unexpected literal in encoding attribute")),
+                    }
+                }
+            }
+            meta => return Err(Error::new_spanned(meta, "Expected #[encoding(...)]")),
+        }
+    }
+    Ok(metas)
+}
+
+enum Combinator {
+    Dynamic,
+    Greedy,
+    Sized(Expr),
+    List,
+    BoundedList(Expr),
+    Option,
+    OptionalField,
+    Lazy,
+}
+
+/// Encoding described by attributes of a field or a variant
+#[derive(Default)]
+struct FieldSpec {
+    combinators: Vec<Combinator>,
+    inner: Option<TokenStream>,
+    cache: bool,
+    tag: Option<Expr>,
+}
+
+impl FieldSpec {
+    fn parse(attrs: &[Attribute]) -> Result<Self> {
+        let mut spec = FieldSpec::default();
+        for meta in encoding_metas(attrs)? {
+            let is_tag = matches!(&meta, Meta::NameValue(name_value) if name_value.path.is_ident("tag"));
+            if spec.inner.is_some() && !is_tag {
+                return Err(Error::new_spanned(meta, "Encoding of the innermost item must be the last one"));
+            }
+            match &meta {
+                Meta::Path(path) => {
+                    let name = path.get_ident().map(|ident| ident.to_string()).unwrap_or_default();
+                    match name.as_str() {
+                        "dynamic" => spec.combinators.push(Combinator::Dynamic),
+                        "greedy" => spec.combinators.push(Combinator::Greedy),
+                        "list" => spec.combinators.push(Combinator::List),
+                        "option" => spec.combinators.push(Combinator::Option),
+                        "optional_field" => spec.combinators.push(Combinator::OptionalField),
+                        "lazy" => spec.combinators.push(Combinator::Lazy),
+                        "cache" => spec.cache = true,
+                        _ => match primitive_encoding(&name) {
+                            Some(variant) => spec.inner = Some(quote! { ::tezos_encoding::encoding::Encoding::#variant }),
+                            None => return Err(Error::new_spanned(path, "Unknown encoding")),
+                        }
+                    }
+                }
+                Meta::NameValue(name_value) => {
+                    let name = name_value.path.get_ident().map(|ident| ident.to_string()).unwrap_or_default();
+                    match name.as_str() {
+                        "sized" => spec.combinators.push(Combinator::Sized(lit_to_expr(&name_value.lit)?)),
+                        "bounded_list" => spec.combinators.push(Combinator::BoundedList(lit_to_expr(&name_value.lit)?)),
+                        "bounded_string" => {
+                            let max_length = lit_to_expr(&name_value.lit)?;
+                            spec.inner = Some(quote! { ::tezos_encoding::encoding::Encoding::BoundedString(#max_length) });
+                        }
+                        "bounded_bytes" => {
+                            let max_length = lit_to_expr(&name_value.lit)?;
+                            spec.inner = Some(quote! { ::tezos_encoding::encoding::Encoding::BoundedBytes(#max_length) });
+                        }
+                        "hash" => {
+                            let hash_type = match &name_value.lit {
+                                Lit::Str(hash_type) => Ident::new(&hash_type.value(), hash_type.span()),
+                                lit => return Err(Error::new_spanned(lit, "Expected name of the hash type, e.g. \"BlockHash\"")),
+                            };
+                            spec.inner = Some(quote! { ::tezos_encoding::encoding::Encoding::Hash(::tezos_encoding::__private::HashType::#hash_type) });
+                        }
+                        "custom" => {
+                            let encoding = lit_to_expr(&name_value.lit)?;
+                            spec.inner = Some(quote! { #encoding });
+                        }
+                        "tag" => spec.tag = Some(lit_to_expr(&name_value.lit)?),
+                        _ => return Err(Error::new_spanned(&name_value.path, "Unknown encoding")),
+                    }
+                }
+                Meta::List(list) => return Err(Error::new_spanned(list, "Unexpected nested list in encoding attribute")),
+            }
+        }
+
+        if spec.cache && (!spec.combinators.is_empty() || spec.inner.is_some()) {
+            return Err(Error::new(Span::call_site(), "Cache field cannot have an encoding"));
+        }
+        Ok(spec)
+    }
+
+    /// Encoding of the value of type `ty`
+    fn encoding(&self, ty: &Type) -> Result<TokenStream> {
+        // unwrap the type by the combinators to get to the innermost item
+        let mut item_ty = ty;
+        for combinator in &self.combinators {
+            let wrapper = match combinator {
+                Combinator::List | Combinator::BoundedList(_) => "Vec",
+                Combinator::Option | Combinator::OptionalField => "Option",
+                Combinator::Lazy => "Box",
+                _ => continue,
+            };
+            if let Some(inner_ty) = generic_argument(item_ty, wrapper) {
+                item_ty = inner_ty;
+            }
+        }
+
+        let mut encoding = match &self.inner {
+            Some(inner) => inner.clone(),
+            None => inferred_encoding(item_ty)?,
+        };
+        for combinator in self.combinators.iter().rev() {
+            encoding = match combinator {
+                Combinator::Dynamic => quote! { ::tezos_encoding::encoding::Encoding::dynamic(#encoding) },
+                Combinator::Greedy => quote! { ::tezos_encoding::encoding::Encoding::greedy(#encoding) },
+                Combinator::Sized(size) => quote! { ::tezos_encoding::encoding::Encoding::sized(#size, #encoding) },
+                Combinator::List => quote! { ::tezos_encoding::encoding::Encoding::list(#encoding) },
+                Combinator::BoundedList(max_length) => quote! { ::tezos_encoding::encoding::Encoding::bounded_list(#max_length, #encoding) },
+                Combinator::Option => quote! { ::tezos_encoding::encoding::Encoding::option(#encoding) },
+                Combinator::OptionalField => quote! { ::tezos_encoding::encoding::Encoding::option_field(#encoding) },
+                Combinator::Lazy => quote! { ::tezos_encoding::encoding::Encoding::Lazy(::std::sync::Arc::new(|| #encoding)) },
+            };
+        }
+        Ok(encoding)
+    }
+}
+
+/// Encoding variant of the primitive type named in the attribute
+fn primitive_encoding(name: &str) -> Option<Ident> {
+    let variant = match name {
+        "unit" => "Unit",
+        "int8" => "Int8",
+        "uint8" => "Uint8",
+        "int16" => "Int16",
+        "uint16" => "Uint16",
+        "int31" => "Int31",
+        "int32" => "Int32",
+        "uint32" => "Uint32",
+        "int64" => "Int64",
+        "z" => "Z",
+        "mutez" => "Mutez",
+        "float" => "Float",
+        "bool" => "Bool",
+        "string" => "String",
+        "bytes" => "Bytes",
+        "timestamp" => "Timestamp",
+        _ => return None,
+    };
+    Some(Ident::new(variant, Span::call_site()))
+}
+
+/// Encoding inferred from the type of the innermost item
+fn inferred_encoding(ty: &Type) -> Result<TokenStream> {
+    let ty = generic_argument(ty, "Box").unwrap_or(ty);
+    if let Type::Path(type_path) = ty {
+        if let Some(ident) = type_path.path.get_ident() {
+            let variant = match ident.to_string().as_str() {
+                "i8" => Some("Int8"),
+                "u8" => Some("Uint8"),
+                "i16" => Some("Int16"),
+                "u16" => Some("Uint16"),
+                "i32" => Some("Int32"),
+                "u32" => Some("Uint32"),
+                "i64" => Some("Int64"),
+                "f64" => Some("Float"),
+                "bool" => Some("Bool"),
+                "String" => Some("String"),
+                _ => None,
+            };
+            if let Some(variant) = variant {
+                let variant = Ident::new(variant, Span::call_site());
+                return Ok(quote! { ::tezos_encoding::encoding::Encoding::#variant });
+            }
+        }
+        if generic_argument(ty, "Vec").is_some() || generic_argument(ty, "Option").is_some() {
+            return Err(Error::new_spanned(ty, "Encoding of Vec or Option must be specified, e.g. #[encoding(list)]"));
+        }
+    }
+    Ok(quote! {
+        <#ty as ::tezos_encoding::encoding::HasEncoding>::encoding().clone()
+    })
+}
+
+/// Returns `T`, if `ty` is `wrapper<T>`
+fn generic_argument<'a>(ty: &'a Type, wrapper: &str) -> Option<&'a Type> {
+    let segment = match ty {
+        Type::Path(type_path) if type_path.qself.is_none() => type_path.path.segments.last()?,
+        _ => return None,
+    };
+    if segment.ident != wrapper {
+        return None;
+    }
+    match &segment.arguments {
+        PathArguments::AngleBracketed(arguments) if arguments.args.len() == 1 => match &arguments.args[0] {
+            GenericArgument::Type(ty) => Some(ty),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Integer literals are used as they are, string literals are parsed as expressions (e.g. constants)
+fn lit_to_expr(lit: &Lit) -> Result<Expr> {
+    match lit {
+        Lit::Int(_) => Ok(syn::parse_quote!(#lit)),
+        Lit::Str(expr) => expr.parse(),
+        _ => Err(Error::new_spanned(lit, "Expected integer or expression in a string")),
+    }
+}
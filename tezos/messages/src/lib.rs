@@ -22,6 +22,11 @@ pub fn ts_to_rfc3339(ts: i64) -> String {
         .to_rfc3339_opts(SecondsFormat::Secs, true)
 }
 
+/// Helper function to parse RFC3339 string timestamp to UNIX (integral) timestamp
+pub fn rfc3339_to_ts(value: &str) -> Result<i64, chrono::ParseError> {
+    DateTime::parse_from_rfc3339(value).map(|datetime| datetime.timestamp())
+}
+
 /// This common struct holds info (hash, level, fitness) about block used as head,
 /// e.g. for fast computations without need to access storage
 /// (if you need here more attributes from block_header, consider refactor block_header with this struct as shell_header)
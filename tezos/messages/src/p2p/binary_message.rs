@@ -13,6 +13,7 @@ use tezos_encoding::binary_reader::{BinaryReader, BinaryReaderError};
 use tezos_encoding::binary_writer;
 use tezos_encoding::de::from_value as deserialize_from_value;
 use tezos_encoding::encoding::HasEncoding;
+use tezos_encoding::json_reader::{JsonReader, JsonReaderError};
 use tezos_encoding::json_writer::JsonWriter;
 use tezos_encoding::ser;
 
@@ -23,153 +24,7 @@ pub const CONTENT_LENGTH_FIELD_BYTES: usize = 2;
 /// Max allowed message length in bytes
 pub const CONTENT_LENGTH_MAX: usize = u16::max_value() as usize;
 
-/// This feature can provide cache mechanism for BinaryMessages.
-/// Cache is used to reduce computation time of encoding/decoding process.
-///
-/// When we use cache (see macro [cached_data]):
-/// - first time we read [from_bytes], original bytes are stored to cache and message/struct is constructed from bytes
-/// - so next time we want to use/call [as_bytes], bytes are not calculated with encoding, but just returned from cache
-///
-/// e.g: this is used, when we receive data from p2p as bytes, and then store them also as bytes to storage and calculate count of bytes in monitoring
-///
-/// When we dont need cache (see macro [non_cached_data]):
-///
-/// e.g.: we we just want to read data from bytes and never convert back to bytes
-///
-pub mod cache {
-    use std::fmt;
-
-    use serde::{Deserialize, Deserializer};
-
-    pub trait CacheReader {
-        fn get(&self) -> Option<Vec<u8>>;
-    }
-
-    pub trait CacheWriter {
-        fn put(&mut self, body: &[u8]);
-    }
-
-    pub trait CachedData {
-        fn cache_reader(&self) -> Option<&dyn CacheReader>;
-        fn cache_writer(&mut self) -> Option<&mut dyn CacheWriter>;
-    }
-
-    #[derive(Clone, Default)]
-    pub struct BinaryDataCache {
-        data: Option<Vec<u8>>
-    }
-
-    impl CacheReader for BinaryDataCache {
-        #[inline]
-        fn get(&self) -> Option<Vec<u8>> {
-            self.data.as_ref().cloned()
-        }
-    }
-
-    impl CacheWriter for BinaryDataCache {
-        #[inline]
-        fn put(&mut self, body: &[u8]) {
-            self.data.replace(body.to_vec());
-        }
-    }
-
-    impl PartialEq for BinaryDataCache {
-        #[inline]
-        fn eq(&self, _: &Self) -> bool {
-            true
-        }
-    }
-
-    impl Eq for BinaryDataCache {}
-
-    impl<'de> Deserialize<'de> for BinaryDataCache {
-        fn deserialize<D>(_: D) -> Result<Self, D::Error>
-            where
-                D: Deserializer<'de>
-        {
-            Ok(BinaryDataCache::default())
-        }
-    }
-
-    impl fmt::Debug for BinaryDataCache {
-        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-            match self.get() {
-                Some(data) => write!(f, "BinaryDataCache {{ has_value: true, len: {} }}", data.len()),
-                None => write!(f, "BinaryDataCache {{ has_value: false }}"),
-            }
-        }
-    }
-
-    #[derive(Clone, PartialEq, Default)]
-    pub struct NeverCache;
-
-    impl<'de> Deserialize<'de> for NeverCache {
-        fn deserialize<D>(_: D) -> Result<Self, D::Error>
-            where
-                D: Deserializer<'de>
-        {
-            Ok(NeverCache::default())
-        }
-    }
-
-    impl CacheReader for NeverCache {
-        #[inline]
-        fn get(&self) -> Option<Vec<u8>> {
-            None
-        }
-    }
-
-    impl CacheWriter for NeverCache {
-        #[inline]
-        fn put(&mut self, _: &[u8]) {
-            // ..
-        }
-    }
-
-    impl fmt::Debug for NeverCache {
-        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-            write!(f, "NeverCache {{ }}")
-        }
-    }
-
-    /// Adds implementation CachedData for given struct
-    /// Struct should contains property [$property_cache_name] with cache struct, e.g. BinaryDataCache,
-    /// usually this cache does not need to be serialized, so can be marked with [#[serde(skip_serializing)]]
-    #[macro_export]
-    macro_rules! cached_data {
-        ($struct_name:ident, $property_cache_name:ident) => {
-            impl $crate::p2p::binary_message::cache::CachedData for $struct_name {
-                #[inline]
-                fn cache_reader(&self) -> Option<&dyn $crate::p2p::binary_message::cache::CacheReader> {
-                    Some(&self.$property_cache_name)
-                }
-
-                #[inline]
-                fn cache_writer(&mut self) -> Option<&mut dyn $crate::p2p::binary_message::cache::CacheWriter> {
-                    Some(&mut self.$property_cache_name)
-                }
-            }
-        };
-    }
-
-    /// Adds empty non-caching implementation CachedData for given struct
-    #[macro_export]
-    macro_rules! non_cached_data {
-        ($struct_name:ident) => {
-            impl $crate::p2p::binary_message::cache::CachedData for $struct_name {
-                #[inline]
-                fn cache_reader(&self) -> Option<&dyn $crate::p2p::binary_message::cache::CacheReader> {
-                    None
-                }
-
-                #[inline]
-                fn cache_writer(&mut self) -> Option<&mut dyn $crate::p2p::binary_message::cache::CacheWriter> {
-                    None
-                }
-            }
-        };
-    }
-}
+pub use tezos_encoding::cache;
 
 /// Trait for binary encoding to implement.
 ///
@@ -179,6 +34,9 @@ pub trait BinaryMessage: Sized {
     /// Produce bytes from the struct.
     fn as_bytes(&self) -> Result<Vec<u8>, ser::Error>;
 
+    /// Append bytes of the struct to the `output`, returns count of bytes appended.
+    fn write_bytes(&self, output: &mut Vec<u8>) -> Result<usize, ser::Error>;
+
     /// Create new struct from bytes.
     fn from_bytes<B: AsRef<[u8]>>(buf: B) -> Result<Self, BinaryReaderError>;
 }
@@ -198,6 +56,20 @@ impl<T> BinaryMessage for T
         binary_writer::write(self, &Self::encoding())
     }
 
+    #[inline]
+    fn write_bytes(&self, output: &mut Vec<u8>) -> Result<usize, ser::Error> {
+        // check cache at first
+        if let Some(cache) = self.cache_reader() {
+            if let Some(data) = cache.get() {
+                output.extend_from_slice(&data);
+                return Ok(data.len());
+            }
+        }
+
+        // if cache not configured or empty, resolve by encoding
+        binary_writer::write_to(self, &Self::encoding(), output)
+    }
+
     #[inline]
     fn from_bytes<B: AsRef<[u8]>>(bytes: B) -> Result<Self, BinaryReaderError> {
         let bytes = bytes.as_ref();
@@ -284,18 +156,27 @@ impl TryFrom<Vec<u8>> for BinaryChunk {
 }
 
 /// Trait for json encoding to implement.
-pub trait JsonMessage {
+pub trait JsonMessage: Sized {
     /// Produce JSON from the struct.
     fn as_json(&self) -> Result<String, ser::Error>;
+
+    /// Create new struct from JSON in the form produced by [JsonMessage::as_json].
+    fn from_json(json: &serde_json::Value) -> Result<Self, JsonReaderError>;
 }
 
 impl<T> JsonMessage for T
-    where T: HasEncoding + Serialize + Sized {
+    where T: HasEncoding + DeserializeOwned + Serialize + Sized {
     #[inline]
     fn as_json(&self) -> Result<String, ser::Error> {
         let mut writer = JsonWriter::new();
         writer.write(self, &Self::encoding())
     }
+
+    #[inline]
+    fn from_json(json: &serde_json::Value) -> Result<Self, JsonReaderError> {
+        let value = JsonReader::new().read(json, Self::encoding())?;
+        Ok(deserialize_from_value(&value)?)
+    }
 }
 
 /// Message hash error
@@ -2,38 +2,47 @@
 // SPDX-License-Identifier: MIT
 
 use std::fmt;
-use std::mem::size_of;
 
 use getset::Getters;
 use serde::{Deserialize, Serialize};
 
-use tezos_encoding::encoding::{Encoding, Field, HasEncoding, Tag, TagMap};
-use tezos_encoding::has_encoding;
+use tezos_encoding::encoding::HasEncoding;
+use tezos_encoding::non_cached_data;
 
-use crate::non_cached_data;
-
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, HasEncoding)]
+#[encoding(tags = "u8")]
 pub enum AckMessage {
+    #[encoding(tag = 0x00)]
     Ack,
+    #[encoding(tag = 0xFF)]
     NackV0,
+    #[encoding(tag = 0x01)]
     Nack(NackInfo),
 }
 
-#[derive(Serialize, Deserialize, PartialEq)]
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug, HasEncoding)]
+#[encoding(tags = "u16")]
 pub enum NackMotive {
+    #[encoding(tag = 0)]
     NoMotive,
+    #[encoding(tag = 1)]
     TooManyConnections,
+    #[encoding(tag = 2)]
     UnknownChainName,
+    #[encoding(tag = 3)]
     DeprecatedP2pVersion,
+    #[encoding(tag = 4)]
     DeprecatedDistributedDbVersion,
+    #[encoding(tag = 5)]
     AlreadyConnected,
 }
 
-#[derive(Serialize, Deserialize, Getters, PartialEq)]
+#[derive(Serialize, Deserialize, Getters, PartialEq, Clone, HasEncoding)]
 pub struct NackInfo {
     #[get = "pub"]
     motive: NackMotive,
     #[get = "pub"]
+    #[encoding(dynamic, list)]
     potential_peers_to_connect: Vec<String>,
 }
 
@@ -61,35 +70,4 @@ impl fmt::Debug for NackInfo {
     }
 }
 
-impl NackInfo {
-    fn encoding() -> Encoding {
-        Encoding::Obj(
-            vec![
-                Field::new("motive", Encoding::Tags(
-                    size_of::<u16>(),
-                    TagMap::new(vec![
-                        Tag::new(0, "NoMotive", Encoding::Unit),
-                        Tag::new(1, "TooManyConnections", Encoding::Unit),
-                        Tag::new(2, "UnknownChainName", Encoding::Unit),
-                        Tag::new(3, "DeprecatedP2pVersion", Encoding::Unit),
-                        Tag::new(4, "DeprecatedDistributedDbVersion", Encoding::Unit),
-                        Tag::new(5, "AlreadyConnected", Encoding::Unit),
-                    ]),
-                )),
-                Field::new("potential_peers_to_connect", Encoding::dynamic(Encoding::list(Encoding::String))),
-            ]
-        )
-    }
-}
-
 non_cached_data!(AckMessage);
-has_encoding!(AckMessage, ACK_MESSAGE_ENCODING, {
-        Encoding::Tags(
-            size_of::<u8>(),
-            TagMap::new(vec![
-                Tag::new(0x00, "Ack", Encoding::Unit),
-                Tag::new(0x01, "Nack", NackInfo::encoding()),
-                Tag::new(0xFF, "NackV0", Encoding::Unit),
-            ]),
-        )
-});
\ No newline at end of file
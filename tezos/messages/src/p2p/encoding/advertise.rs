@@ -6,33 +6,33 @@ use std::net::SocketAddr;
 use getset::Getters;
 use serde::{Deserialize, Serialize};
 
-use tezos_encoding::encoding::{Encoding, Field, HasEncoding};
-use tezos_encoding::has_encoding;
+use tezos_encoding::encoding::HasEncoding;
 
-use crate::cached_data;
 use crate::p2p::binary_message::cache::BinaryDataCache;
+use crate::p2p::encoding::limits::ADVERTISE_ID_LIST_MAX_LENGTH;
+use crate::p2p::point::{format_point, parse_point};
 
-#[derive(Serialize, Deserialize, Debug, Getters, Clone)]
+#[derive(Serialize, Deserialize, Debug, Getters, Clone, HasEncoding)]
 pub struct AdvertiseMessage {
     #[get = "pub"]
+    #[encoding(bounded_list = "ADVERTISE_ID_LIST_MAX_LENGTH")]
     id: Vec<String>,
 
     #[serde(skip_serializing)]
+    #[encoding(cache)]
     body: BinaryDataCache,
 }
 
 impl AdvertiseMessage {
     pub fn new(addresses: &[SocketAddr]) -> Self {
         Self {
-            id: addresses.iter().map(|address| format!("{}", address)).collect(),
+            id: addresses.iter().map(format_point).collect(),
             body: Default::default(),
         }
     }
-}
 
-cached_data!(AdvertiseMessage, body);
-has_encoding!(AdvertiseMessage, ADVERTISE_MESSAGE_ENCODING, {
-    Encoding::Obj(vec![
-        Field::new("id", Encoding::list(Encoding::String)),
-    ])
-});
+    /// Advertised points, invalid points are skipped
+    pub fn points(&self) -> Vec<SocketAddr> {
+        self.id.iter().filter_map(|point| parse_point(point)).collect()
+    }
+}
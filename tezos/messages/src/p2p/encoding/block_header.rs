@@ -7,12 +7,11 @@ use derive_builder::Builder;
 use getset::{CopyGetters, Getters};
 use serde::{Deserialize, Serialize};
 
-use crypto::hash::{BlockHash, ContextHash, HashType, OperationListListHash};
-use tezos_encoding::encoding::{Encoding, Field, HasEncoding, SchemaType};
-use tezos_encoding::has_encoding;
+use crypto::hash::{BlockHash, ContextHash, OperationListListHash};
+use tezos_encoding::encoding::{Encoding, HasEncoding, SchemaType};
 
-use crate::cached_data;
 use crate::p2p::binary_message::cache::BinaryDataCache;
+use crate::p2p::encoding::limits::GET_BLOCK_HEADERS_MAX_LENGTH;
 
 pub type Fitness = Vec<Vec<u8>>;
 pub type Level = i32;
@@ -28,6 +27,16 @@ pub fn fitness_encoding() -> Encoding {
     ))
 }
 
+/// Protocol data are bytes in JSON, but a list of bytes in binary
+fn protocol_data_encoding() -> Encoding {
+    Encoding::Split(Arc::new(|schema_type|
+        match schema_type {
+            SchemaType::Json => Encoding::Bytes,
+            SchemaType::Binary => Encoding::list(Encoding::Uint8)
+        }
+    ))
+}
+
 pub fn display_fitness(fitness: &Fitness) -> String {
     fitness
         .iter()
@@ -36,22 +45,16 @@ pub fn display_fitness(fitness: &Fitness) -> String {
         .join("::")
 }
 
-#[derive(Serialize, Deserialize, Debug, Getters, Clone)]
+#[derive(Serialize, Deserialize, Debug, Getters, Clone, HasEncoding)]
 pub struct BlockHeaderMessage {
     #[get = "pub"]
     block_header: BlockHeader,
 
     #[serde(skip_serializing)]
+    #[encoding(cache)]
     body: BinaryDataCache,
 }
 
-cached_data!(BlockHeaderMessage, body);
-has_encoding!(BlockHeaderMessage, BLOCK_HEADER_MESSAGE_ENCODING, {
-        Encoding::Obj(vec![
-            Field::new("block_header", BlockHeader::encoding().clone()),
-        ])
-});
-
 impl From<BlockHeader> for BlockHeaderMessage {
     fn from(block_header: BlockHeader) -> Self {
         BlockHeaderMessage { block_header, body: Default::default() }
@@ -65,12 +68,14 @@ impl From<BlockHeaderMessage> for BlockHeader {
 }
 
 // -----------------------------------------------------------------------------------------------
-#[derive(Serialize, Deserialize, Debug, Getters, Clone)]
+#[derive(Serialize, Deserialize, Debug, Getters, Clone, HasEncoding)]
 pub struct GetBlockHeadersMessage {
     #[get = "pub"]
+    #[encoding(dynamic, bounded_list = "GET_BLOCK_HEADERS_MAX_LENGTH", hash = "BlockHash")]
     get_block_headers: Vec<BlockHash>,
 
     #[serde(skip_serializing)]
+    #[encoding(cache)]
     body: BinaryDataCache,
 }
 
@@ -83,56 +88,37 @@ impl GetBlockHeadersMessage {
     }
 }
 
-cached_data!(GetBlockHeadersMessage, body);
-has_encoding!(GetBlockHeadersMessage, GET_BLOCK_HEADERS_MESSAGE_ENCODING, {
-        Encoding::Obj(vec![
-            Field::new("get_block_headers", Encoding::dynamic(Encoding::list(Encoding::Hash(HashType::BlockHash)))),
-        ])
-});
-
 // -----------------------------------------------------------------------------------------------
-#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Builder, Getters, CopyGetters)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Builder, Getters, CopyGetters, HasEncoding)]
 pub struct BlockHeader {
     #[get_copy = "pub"]
+    #[encoding(int32)]
     level: Level,
     #[get_copy = "pub"]
     proto: u8,
     #[get = "pub"]
+    #[encoding(hash = "BlockHash")]
     predecessor: BlockHash,
     #[get_copy = "pub"]
+    #[encoding(timestamp)]
     timestamp: i64,
     #[get_copy = "pub"]
     validation_pass: u8,
     #[get = "pub"]
+    #[encoding(hash = "OperationListListHash")]
     operations_hash: OperationListListHash,
     #[get = "pub"]
+    #[encoding(custom = "fitness_encoding()")]
     fitness: Fitness,
     #[get = "pub"]
+    #[encoding(hash = "ContextHash")]
     context: ContextHash,
     #[get = "pub"]
+    #[encoding(custom = "protocol_data_encoding()")]
     protocol_data: Vec<u8>,
 
     #[serde(skip_serializing)]
     #[builder(default)]
+    #[encoding(cache)]
     body: BinaryDataCache,
 }
-
-cached_data!(BlockHeader, body);
-has_encoding!(BlockHeader, BLOCK_HEADER_ENCODING, {
-        Encoding::Obj(vec![
-            Field::new("level", Encoding::Int32),
-            Field::new("proto", Encoding::Uint8),
-            Field::new("predecessor", Encoding::Hash(HashType::BlockHash)),
-            Field::new("timestamp", Encoding::Timestamp),
-            Field::new("validation_pass", Encoding::Uint8),
-            Field::new("operations_hash", Encoding::Hash(HashType::OperationListListHash)),
-            Field::new("fitness", fitness_encoding()),
-            Field::new("context", Encoding::Hash(HashType::ContextHash)),
-            Field::new("protocol_data", Encoding::Split(Arc::new(|schema_type|
-                match schema_type {
-                    SchemaType::Json => Encoding::Bytes,
-                    SchemaType::Binary => Encoding::list(Encoding::Uint8)
-                }
-            )))
-        ])
-});
\ No newline at end of file
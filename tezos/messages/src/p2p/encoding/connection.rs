@@ -8,34 +8,39 @@ use getset::Getters;
 use serde::{Deserialize, Serialize};
 
 use tezos_encoding::binary_reader::BinaryReaderError;
-use tezos_encoding::encoding::{Encoding, Field, HasEncoding};
-use tezos_encoding::has_encoding;
+use tezos_encoding::encoding::HasEncoding;
+use tezos_encoding::non_cached_data;
 
-use crate::non_cached_data;
 use crate::p2p::binary_message::{BinaryChunk, BinaryMessage};
 use crate::p2p::encoding::version::NetworkVersion;
 
-#[derive(Serialize, Deserialize, Debug, Getters, Clone)]
+#[derive(Serialize, Deserialize, Debug, Getters, Clone, HasEncoding)]
 pub struct ConnectionMessage {
-    port: u16,
     #[get = "pub"]
-    versions: Vec<NetworkVersion>,
+    port: u16,
     #[get = "pub"]
+    #[encoding(sized = 32, bytes)]
     public_key: Vec<u8>,
+    #[get = "pub"]
+    #[encoding(sized = 24, bytes)]
     proof_of_work_stamp: Vec<u8>,
+    #[encoding(sized = 24, bytes)]
     message_nonce: Vec<u8>,
+    #[get = "pub"]
+    #[encoding(list)]
+    versions: Vec<NetworkVersion>,
 }
 
 impl ConnectionMessage {
     pub fn new(port: u16, public_key: &str, proof_of_work_stamp: &str, message_nonce: &[u8], versions: Vec<NetworkVersion>) -> Self {
         ConnectionMessage {
             port,
-            versions,
             public_key: hex::decode(public_key)
                 .expect("Failed to decode public ket from hex string"),
             proof_of_work_stamp: hex::decode(proof_of_work_stamp)
                 .expect("Failed to decode proof of work stamp from hex string"),
             message_nonce: message_nonce.into(),
+            versions,
         }
     }
 }
@@ -52,12 +57,3 @@ impl TryFrom<BinaryChunk> for ConnectionMessage {
 }
 
 non_cached_data!(ConnectionMessage);
-has_encoding!(ConnectionMessage, CONNECTION_MESSAGE_ENCODING, {
-        Encoding::Obj(vec![
-            Field::new("port", Encoding::Uint16),
-            Field::new("public_key", Encoding::sized(32, Encoding::Bytes)),
-            Field::new("proof_of_work_stamp", Encoding::sized(24, Encoding::Bytes)),
-            Field::new("message_nonce", Encoding::sized(24, Encoding::Bytes)),
-            Field::new("versions", Encoding::list(NetworkVersion::encoding().clone()))
-        ])
-});
\ No newline at end of file
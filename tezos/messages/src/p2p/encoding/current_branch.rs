@@ -1,28 +1,27 @@
 // Copyright (c) SimpleStaking and Tezedge Contributors
 // SPDX-License-Identifier: MIT
 
-use std::sync::Arc;
-
 use getset::Getters;
 use serde::{Deserialize, Serialize};
 
-use crypto::hash::{BlockHash, ChainId, HashType};
-use tezos_encoding::encoding::{Encoding, Field, HasEncoding, SchemaType};
-use tezos_encoding::has_encoding;
+use crypto::hash::{BlockHash, ChainId};
+use tezos_encoding::encoding::HasEncoding;
 
-use crate::cached_data;
 use crate::p2p::binary_message::cache::BinaryDataCache;
+use crate::p2p::encoding::limits::CURRENT_BRANCH_HISTORY_MAX_LENGTH;
 use crate::p2p::encoding::block_header::BlockHeader;
 
 pub const HISTORY_MAX_SIZE: u8 = u8::MAX;
 
-#[derive(Clone, Serialize, Deserialize, Debug, Getters)]
+#[derive(Clone, Serialize, Deserialize, Debug, Getters, HasEncoding)]
 pub struct CurrentBranchMessage {
     #[get = "pub"]
+    #[encoding(hash = "ChainId")]
     chain_id: ChainId,
     #[get = "pub"]
     current_branch: CurrentBranch,
     #[serde(skip_serializing)]
+    #[encoding(cache)]
     body: BinaryDataCache,
 }
 
@@ -36,23 +35,18 @@ impl CurrentBranchMessage {
     }
 }
 
-cached_data!(CurrentBranchMessage, body);
-has_encoding!(CurrentBranchMessage, CURRENT_BRANCH_MESSAGE_ENCODING, {
-        Encoding::Obj(vec![
-            Field::new("chain_id", Encoding::Hash(HashType::ChainId)),
-            Field::new("current_branch", CurrentBranch::encoding().clone())
-        ])
-});
-
 // -----------------------------------------------------------------------------------------------
-#[derive(Clone, Serialize, Deserialize, Debug, Getters)]
+#[derive(Clone, Serialize, Deserialize, Debug, Getters, HasEncoding)]
 pub struct CurrentBranch {
     #[get = "pub"]
+    #[encoding(dynamic)]
     current_head: BlockHeader,
     /// These hashes go from the top of the chain to the bottom (to genesis)
     #[get = "pub"]
+    #[encoding(bounded_list = "CURRENT_BRANCH_HISTORY_MAX_LENGTH", hash = "BlockHash")]
     history: Vec<BlockHash>,
     #[serde(skip_serializing)]
+    #[encoding(cache)]
     body: BinaryDataCache,
 }
 
@@ -66,25 +60,14 @@ impl CurrentBranch {
     }
 }
 
-cached_data!(CurrentBranch, body);
-has_encoding!(CurrentBranch, CURRENT_BRANCH_ENCODING, {
-        Encoding::Obj(vec![
-            Field::new("current_head", Encoding::dynamic(BlockHeader::encoding().clone())),
-            Field::new("history", Encoding::Split(Arc::new(|schema_type|
-                match schema_type {
-                    SchemaType::Json => Encoding::Unit, // TODO: decode as list of hashes when history is needed
-                    SchemaType::Binary => Encoding::list(Encoding::Hash(HashType::BlockHash))
-                }
-            )))
-        ])
-});
-
 // -----------------------------------------------------------------------------------------------
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, HasEncoding)]
 pub struct GetCurrentBranchMessage {
+    #[encoding(hash = "ChainId")]
     pub chain_id: ChainId,
 
     #[serde(skip_serializing)]
+    #[encoding(cache)]
     body: BinaryDataCache,
 }
 
@@ -93,10 +76,3 @@ impl GetCurrentBranchMessage {
         GetCurrentBranchMessage { chain_id, body: Default::default() }
     }
 }
-
-cached_data!(GetCurrentBranchMessage, body);
-has_encoding!(GetCurrentBranchMessage, GET_CURRENT_BRANCH_MESSAGE_ENCODING, {
-        Encoding::Obj(vec![
-            Field::new("chain_id", Encoding::Hash(HashType::ChainId))
-        ])
-});
\ No newline at end of file
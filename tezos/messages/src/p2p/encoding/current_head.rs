@@ -4,25 +4,26 @@
 use getset::Getters;
 use serde::{Deserialize, Serialize};
 
-use crypto::hash::{ChainId, HashType};
-use tezos_encoding::encoding::{Encoding, Field, HasEncoding};
-use tezos_encoding::has_encoding;
+use crypto::hash::ChainId;
+use tezos_encoding::encoding::HasEncoding;
 
-use crate::cached_data;
 use crate::p2p::binary_message::cache::BinaryDataCache;
 
 use super::block_header::BlockHeader;
 use super::mempool::Mempool;
 
-#[derive(Serialize, Deserialize, Debug, Getters, Clone)]
+#[derive(Serialize, Deserialize, Debug, Getters, Clone, HasEncoding)]
 pub struct CurrentHeadMessage {
     #[get = "pub"]
+    #[encoding(hash = "ChainId")]
     chain_id: ChainId,
     #[get = "pub"]
+    #[encoding(dynamic)]
     current_block_header: BlockHeader,
     #[get = "pub"]
     current_mempool: Mempool,
     #[serde(skip_serializing)]
+    #[encoding(cache)]
     body: BinaryDataCache,
 }
 
@@ -37,22 +38,15 @@ impl CurrentHeadMessage {
     }
 }
 
-cached_data!(CurrentHeadMessage, body);
-has_encoding!(CurrentHeadMessage, CURRENT_HEAD_MESSAGE_ENCODING, {
-        Encoding::Obj(vec![
-            Field::new("chain_id", Encoding::Hash(HashType::ChainId)),
-            Field::new("current_block_header", Encoding::dynamic(BlockHeader::encoding().clone())),
-            Field::new("current_mempool", Mempool::encoding().clone())
-        ])
-});
-
 // -----------------------------------------------------------------------------------------------
-#[derive(Serialize, Deserialize, Debug, Getters, Clone)]
+#[derive(Serialize, Deserialize, Debug, Getters, Clone, HasEncoding)]
 pub struct GetCurrentHeadMessage {
     #[get = "pub"]
+    #[encoding(hash = "ChainId")]
     chain_id: ChainId,
 
     #[serde(skip_serializing)]
+    #[encoding(cache)]
     body: BinaryDataCache,
 }
 
@@ -64,10 +58,3 @@ impl GetCurrentHeadMessage {
         }
     }
 }
-
-cached_data!(GetCurrentHeadMessage, body);
-has_encoding!(GetCurrentHeadMessage, GET_CURRENT_HEAD_MESSAGE_ENCODING, {
-        Encoding::Obj(vec![
-            Field::new("chain_id", Encoding::Hash(HashType::ChainId))
-        ])
-});
\ No newline at end of file
@@ -4,19 +4,19 @@
 use getset::Getters;
 use serde::{Deserialize, Serialize};
 
-use crypto::hash::{ChainId, HashType};
-use tezos_encoding::encoding::{Encoding, Field, HasEncoding};
-use tezos_encoding::has_encoding;
+use crypto::hash::ChainId;
+use tezos_encoding::encoding::HasEncoding;
 
-use crate::cached_data;
 use crate::p2p::binary_message::cache::BinaryDataCache;
 
-#[derive(Serialize, Deserialize, Debug, Getters, Clone)]
+#[derive(Serialize, Deserialize, Debug, Getters, Clone, HasEncoding)]
 pub struct DeactivateMessage {
     #[get = "pub"]
+    #[encoding(hash = "ChainId")]
     deactivate: ChainId,
 
     #[serde(skip_serializing)]
+    #[encoding(cache)]
     body: BinaryDataCache,
 }
 
@@ -28,10 +28,3 @@ impl DeactivateMessage {
         }
     }
 }
-
-cached_data!(DeactivateMessage, body);
-has_encoding!(DeactivateMessage, DEACTIVATE_MESSAGE_ENCODING, {
-        Encoding::Obj(vec![
-            Field::new("deactivate", Encoding::Hash(HashType::ChainId)),
-        ])
-});
\ No newline at end of file
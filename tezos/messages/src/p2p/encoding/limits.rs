@@ -0,0 +1,30 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Limits of the p2p messages, they are the same as in the OCaml node.
+//!
+//! Messages exceeding these limits are rejected by the [BinaryReader](tezos_encoding::binary_reader::BinaryReader)
+//! before they are processed, so a peer cannot make us allocate and process huge requests.
+
+/// Maximal count of block hashes in `GetBlockHeaders`
+pub const GET_BLOCK_HEADERS_MAX_LENGTH: usize = 10;
+/// Maximal count of operation hashes in `GetOperations`
+pub const GET_OPERATIONS_MAX_LENGTH: usize = 10;
+/// Maximal count of protocol hashes in `GetProtocols`
+pub const GET_PROTOCOLS_MAX_LENGTH: usize = 10;
+/// Maximal count of requested blocks in `GetOperationHashesForBlocks`
+pub const GET_OPERATION_HASHES_FOR_BLOCKS_MAX_LENGTH: usize = 10;
+/// Maximal count of requested blocks in `GetOperationsForBlocks`
+pub const GET_OPERATIONS_FOR_BLOCKS_MAX_LENGTH: usize = 10;
+/// Maximal count of block hashes in the history of `CurrentBranch` (block locator)
+pub const CURRENT_BRANCH_HISTORY_MAX_LENGTH: usize = 1000;
+/// Maximal count of points in `Advertise`
+pub const ADVERTISE_ID_LIST_MAX_LENGTH: usize = 100;
+/// Maximal count of operation hashes in one list of `Mempool`.
+/// OCaml node does not limit the lists explicitly, they are bounded by the maximal size of the p2p message (64 KiB),
+/// so there cannot be more operation hashes in a valid message.
+pub const MEMPOOL_MAX_OPERATIONS: usize = 2048;
+/// Maximal size of the encoded p2p message, which can be split into many chunks.
+/// Encrypted stream rejects a message as soon as its chunks exceed this size, so a peer cannot make us buffer
+/// an endless message. The largest valid messages (`OperationsForBlocks` of a full validation pass) fit well below it.
+pub const MESSAGE_MAX_SIZE: usize = 4 * 1024 * 1024;
@@ -4,20 +4,22 @@
 use getset::Getters;
 use serde::{Deserialize, Serialize};
 
-use crypto::hash::{HashType, OperationHash};
-use tezos_encoding::encoding::{Encoding, Field, HasEncoding};
-use tezos_encoding::has_encoding;
+use crypto::hash::OperationHash;
+use tezos_encoding::encoding::HasEncoding;
 
-use crate::cached_data;
 use crate::p2p::binary_message::cache::BinaryDataCache;
+use crate::p2p::encoding::limits::MEMPOOL_MAX_OPERATIONS;
 
-#[derive(Clone, Serialize, Deserialize, Debug, Default, Getters)]
+#[derive(Clone, Serialize, Deserialize, Debug, Default, Getters, HasEncoding)]
 pub struct Mempool {
     #[get = "pub"]
+    #[encoding(dynamic, bounded_list = "MEMPOOL_MAX_OPERATIONS", hash = "OperationHash")]
     known_valid: Vec<OperationHash>,
     #[get = "pub"]
+    #[encoding(dynamic, dynamic, bounded_list = "MEMPOOL_MAX_OPERATIONS", hash = "OperationHash")]
     pending: Vec<OperationHash>,
     #[serde(skip_serializing)]
+    #[encoding(cache)]
     body: BinaryDataCache,
 }
 
@@ -34,11 +36,3 @@ impl Mempool {
         self.known_valid.is_empty() && self.pending.is_empty()
     }
 }
-
-cached_data!(Mempool, body);
-has_encoding!(Mempool, MEMPOOL_ENCODING, {
-        Encoding::Obj(vec![
-            Field::new("known_valid", Encoding::dynamic(Encoding::list(Encoding::Hash(HashType::OperationHash)))),
-            Field::new("pending", Encoding::dynamic(Encoding::dynamic(Encoding::list(Encoding::Hash(HashType::OperationHash))))),
-        ])
-});
@@ -19,6 +19,7 @@ pub mod peer;
 pub mod swap;
 pub mod deactivate;
 pub mod operation_hashes_for_blocks;
+pub mod limits;
 
 pub mod prelude {
     pub use super::ack::AckMessage;
@@ -36,5 +37,5 @@ pub mod prelude {
     pub use super::version::NetworkVersion;
     pub use super::swap::SwapMessage;
     pub use super::deactivate::DeactivateMessage;
-    pub use super::operation_hashes_for_blocks::{GetOperationHashesForBlocksMessage, OperationHashesForBlocksMessage};
+    pub use super::operation_hashes_for_blocks::{GetOperationHashesForBlocksMessage, OperationHashesForBlock, OperationHashesForBlocksMessage};
 }
\ No newline at end of file
@@ -1,34 +1,30 @@
 // Copyright (c) SimpleStaking and Tezedge Contributors
 // SPDX-License-Identifier: MIT
 
+use std::convert::TryFrom;
 use std::sync::Arc;
 
 use getset::Getters;
 use serde::{Deserialize, Serialize};
 
 use crypto::hash::{BlockHash, HashType, OperationHash};
-use tezos_encoding::encoding::{Encoding, Field, HasEncoding, SchemaType};
-use tezos_encoding::has_encoding;
+use tezos_encoding::encoding::{Encoding, HasEncoding, SchemaType};
+use tezos_encoding::json_reader::JsonReaderError;
 
-use crate::cached_data;
 use crate::p2p::binary_message::cache::BinaryDataCache;
+use crate::p2p::binary_message::JsonMessage;
+use crate::p2p::encoding::limits::GET_OPERATIONS_MAX_LENGTH;
 
-#[derive(Serialize, Deserialize, PartialEq, Debug, Getters, Clone)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Getters, Clone, HasEncoding)]
 pub struct OperationMessage {
     #[get = "pub"]
     operation: Operation,
 
     #[serde(skip_serializing)]
+    #[encoding(cache)]
     body: BinaryDataCache,
 }
 
-cached_data!(OperationMessage, body);
-has_encoding!(OperationMessage, OPERATION_MESSAGE_ENCODING, {
-    Encoding::Obj(vec![
-        Field::new("operation", Operation::encoding().clone())
-    ])
-});
-
 impl From<Operation> for OperationMessage {
     fn from(operation: Operation) -> Self {
         Self {
@@ -45,12 +41,15 @@ impl From<OperationMessage> for Operation {
 }
 
 // -----------------------------------------------------------------------------------------------
-#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug, HasEncoding)]
 pub struct Operation {
+    #[encoding(hash = "BlockHash")]
     branch: BlockHash,
+    #[encoding(custom = "data_encoding()")]
     data: Vec<u8>,
 
     #[serde(skip_serializing)]
+    #[encoding(cache)]
     body: BinaryDataCache,
 }
 
@@ -64,28 +63,23 @@ impl Operation {
     }
 }
 
-impl From<DecodedOperation> for Operation {
-    fn from(dop: DecodedOperation) -> Operation {
-        Operation {
-            branch: HashType::BlockHash.string_to_bytes(&dop.branch).unwrap(),
-            data: hex::decode(&dop.data).unwrap(),
-            body: Default::default(),
-        }
+impl TryFrom<DecodedOperation> for Operation {
+    type Error = JsonReaderError;
+
+    fn try_from(dop: DecodedOperation) -> Result<Operation, Self::Error> {
+        Operation::from_json(&serde_json::json!({ "branch": dop.branch, "data": dop.data }))
     }
 }
 
-cached_data!(Operation, body);
-has_encoding!(Operation, OPERATION_ENCODING, {
-        Encoding::Obj(vec![
-            Field::new("branch", Encoding::Hash(HashType::BlockHash)),
-            Field::new("data", Encoding::Split(Arc::new(|schema_type|
-                match schema_type {
-                    SchemaType::Json => Encoding::Bytes,
-                    SchemaType::Binary => Encoding::list(Encoding::Uint8)
-                }
-            )))
-        ])
-});
+/// Operation data are bytes in JSON, but a list of bytes in binary
+fn data_encoding() -> Encoding {
+    Encoding::Split(Arc::new(|schema_type|
+        match schema_type {
+            SchemaType::Json => Encoding::Bytes,
+            SchemaType::Binary => Encoding::list(Encoding::Uint8)
+        }
+    ))
+}
 
 // -----------------------------------------------------------------------------------------------
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
@@ -104,12 +98,14 @@ impl From<Operation> for DecodedOperation {
 }
 
 // -----------------------------------------------------------------------------------------------
-#[derive(Serialize, Deserialize, Debug, Getters, Clone)]
+#[derive(Serialize, Deserialize, Debug, Getters, Clone, HasEncoding)]
 pub struct GetOperationsMessage {
     #[get = "pub"]
+    #[encoding(dynamic, bounded_list = "GET_OPERATIONS_MAX_LENGTH", hash = "OperationHash")]
     get_operations: Vec<OperationHash>,
 
     #[serde(skip_serializing)]
+    #[encoding(cache)]
     body: BinaryDataCache,
 }
 
@@ -121,10 +117,3 @@ impl GetOperationsMessage {
         }
     }
 }
-
-cached_data!(GetOperationsMessage, body);
-has_encoding!(GetOperationsMessage, GET_OPERATION_MESSAGE_ENCODING, {
-        Encoding::Obj(vec![
-            Field::new("get_operations", Encoding::dynamic(Encoding::list(Encoding::Hash(HashType::OperationHash)))),
-        ])
-});
\ No newline at end of file
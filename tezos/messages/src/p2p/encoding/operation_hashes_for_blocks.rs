@@ -4,22 +4,21 @@
 use getset::{CopyGetters, Getters};
 use serde::{Deserialize, Serialize};
 
-use crypto::hash::{BlockHash, HashType, OperationHash};
-use tezos_encoding::encoding::{Encoding, Field, HasEncoding};
-use tezos_encoding::has_encoding;
+use crypto::hash::{BlockHash, OperationHash};
+use tezos_encoding::encoding::HasEncoding;
 
-use crate::cached_data;
 use crate::p2p::binary_message::cache::BinaryDataCache;
+use crate::p2p::encoding::limits::GET_OPERATION_HASHES_FOR_BLOCKS_MAX_LENGTH;
 use crate::p2p::encoding::prelude::Path;
 
-use super::operations_for_blocks::path_encoding;
-
-#[derive(Serialize, Deserialize, Debug, Getters, Clone)]
+#[derive(Serialize, Deserialize, Debug, Getters, Clone, HasEncoding)]
 pub struct GetOperationHashesForBlocksMessage {
     #[get = "pub"]
+    #[encoding(dynamic, bounded_list = "GET_OPERATION_HASHES_FOR_BLOCKS_MAX_LENGTH")]
     get_operation_hashes_for_blocks: Vec<OperationHashesForBlock>,
 
     #[serde(skip_serializing)]
+    #[encoding(cache)]
     body: BinaryDataCache,
 }
 
@@ -32,24 +31,19 @@ impl GetOperationHashesForBlocksMessage {
     }
 }
 
-cached_data!(GetOperationHashesForBlocksMessage, body);
-has_encoding!(GetOperationHashesForBlocksMessage, GET_OPERATION_HASHES_FOR_BLOCKS_MESSAGE_ENCODING, {
-    Encoding::Obj(vec![
-        Field::new("get_operation_hashes_for_blocks", Encoding::dynamic(Encoding::list(OperationHashesForBlock::encoding().clone()))),
-    ])
-});
-
 // ------------------ Response ------------------ //
-#[derive(Serialize, Deserialize, Debug, Getters, Clone)]
+#[derive(Serialize, Deserialize, Debug, Getters, Clone, HasEncoding)]
 pub struct OperationHashesForBlocksMessage {
     #[get = "pub"]
     operation_hashes_for_block: OperationHashesForBlock,
     #[get = "pub"]
     operation_hashes_path: Path,
     #[get = "pub"]
+    #[encoding(list, dynamic, list, uint8)]
     operation_hashes: Vec<OperationHash>,
 
     #[serde(skip_serializing)]
+    #[encoding(cache)]
     body: BinaryDataCache,
 }
 
@@ -64,24 +58,17 @@ impl OperationHashesForBlocksMessage {
     }
 }
 
-cached_data!(OperationHashesForBlocksMessage, body);
-has_encoding!(OperationHashesForBlocksMessage, OPERATION_HASHES_FOR_BLOCKS_MESSAGE_ENCODING, {
-        Encoding::Obj(vec![
-            Field::new("operation_hashes_for_block", OperationHashesForBlock::encoding().clone()),
-            Field::new("operation_hashes_path", path_encoding()),
-            Field::new("operation_hashes", Encoding::list(Encoding::dynamic(Encoding::list(Encoding::Uint8)))),
-        ])
-});
-
 // ------------------ Inner message for operation hashes message ------------------ //
-#[derive(Serialize, Deserialize, Debug, Getters, CopyGetters, Clone)]
+#[derive(Serialize, Deserialize, Debug, Getters, CopyGetters, Clone, HasEncoding)]
 pub struct OperationHashesForBlock {
     #[get = "pub"]
+    #[encoding(hash = "BlockHash")]
     hash: BlockHash,
     #[get_copy = "pub"]
     validation_pass: i8,
 
     #[serde(skip_serializing)]
+    #[encoding(cache)]
     body: BinaryDataCache,
 }
 
@@ -94,11 +81,3 @@ impl OperationHashesForBlock {
         }
     }
 }
-
-cached_data!(OperationHashesForBlock, body);
-has_encoding!(OperationHashesForBlock, OPERATION_HASHES_FOR_BLOCK_ENCODING, {
-        Encoding::Obj(vec![
-            Field::new("hash", Encoding::Hash(HashType::BlockHash)),
-            Field::new("validation_pass", Encoding::Int8),
-        ])
-});
\ No newline at end of file
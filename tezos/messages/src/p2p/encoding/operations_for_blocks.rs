@@ -1,27 +1,25 @@
 // Copyright (c) SimpleStaking and Tezedge Contributors
 // SPDX-License-Identifier: MIT
 
-use std::mem::size_of;
-use std::sync::Arc;
-
 use getset::{CopyGetters, Getters};
 use serde::{Deserialize, Serialize};
 
-use crypto::hash::{BlockHash, Hash, HashType};
-use tezos_encoding::encoding::{Encoding, Field, HasEncoding, Tag, TagMap};
-use tezos_encoding::has_encoding;
+use crypto::hash::{BlockHash, Hash};
+use tezos_encoding::encoding::HasEncoding;
 
-use crate::cached_data;
 use crate::p2p::binary_message::cache::BinaryDataCache;
+use crate::p2p::encoding::limits::GET_OPERATIONS_FOR_BLOCKS_MAX_LENGTH;
 use crate::p2p::encoding::operation::Operation;
 
-#[derive(Clone, Serialize, Deserialize, PartialEq, Debug, CopyGetters, Getters)]
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug, CopyGetters, Getters, HasEncoding)]
 pub struct OperationsForBlock {
     #[get = "pub"]
+    #[encoding(hash = "BlockHash")]
     hash: BlockHash,
     #[get_copy = "pub"]
     validation_pass: i8,
     #[serde(skip_serializing)]
+    #[encoding(cache)]
     body: BinaryDataCache,
 }
 
@@ -41,24 +39,18 @@ impl OperationsForBlock {
     }
 }
 
-cached_data!(OperationsForBlock, body);
-has_encoding!(OperationsForBlock, OPERATIONS_FOR_BLOCK_ENCODING, {
-        Encoding::Obj(vec![
-            Field::new("hash", Encoding::Hash(HashType::BlockHash)),
-            Field::new("validation_pass", Encoding::Int8),
-        ])
-});
-
 // -----------------------------------------------------------------------------------------------
-#[derive(Clone, Serialize, Deserialize, PartialEq, Debug, Getters)]
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug, Getters, HasEncoding)]
 pub struct OperationsForBlocksMessage {
     #[get = "pub"]
     operations_for_block: OperationsForBlock,
     #[get = "pub"]
     operation_hashes_path: Path,
     #[get = "pub"]
+    #[encoding(list, dynamic)]
     operations: Vec<Operation>,
     #[serde(skip_serializing)]
+    #[encoding(cache)]
     body: BinaryDataCache,
 }
 
@@ -73,15 +65,6 @@ impl OperationsForBlocksMessage {
     }
 }
 
-cached_data!(OperationsForBlocksMessage, body);
-has_encoding!(OperationsForBlocksMessage, OPERATIONS_FOR_BLOCKS_MESSAGE_ENCODING, {
-        Encoding::Obj(vec![
-            Field::new("operations_for_block", OperationsForBlock::encoding().clone()),
-            Field::new("operation_hashes_path", path_encoding()),
-            Field::new("operations", Encoding::list(Encoding::dynamic(Operation::encoding().clone()))),
-        ])
-});
-
 impl From<OperationsForBlocksMessage> for Vec<Operation> {
     fn from(msg: OperationsForBlocksMessage) -> Self {
         msg.operations
@@ -89,24 +72,18 @@ impl From<OperationsForBlocksMessage> for Vec<Operation> {
 }
 
 // -----------------------------------------------------------------------------------------------
-#[derive(Clone, Serialize, Deserialize, PartialEq, Debug, Getters)]
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug, Getters, HasEncoding)]
 pub struct PathRight {
     #[get = "pub"]
+    #[encoding(hash = "OperationListListHash")]
     left: Hash,
     #[get = "pub"]
     path: Path,
     #[serde(skip_serializing)]
+    #[encoding(cache)]
     body: BinaryDataCache,
 }
 
-cached_data!(PathRight, body);
-has_encoding!(PathRight, PATH_RIGHT_ENCODING, {
-        Encoding::Obj(vec![
-            Field::new("left", Encoding::Hash(HashType::OperationListListHash)),
-            Field::new("path", path_encoding()),
-        ])
-});
-
 impl PathRight {
     pub fn new(left: Hash, path: Path, body: BinaryDataCache) -> Self {
         Self { left, path, body }
@@ -114,24 +91,18 @@ impl PathRight {
 }
 
 // -----------------------------------------------------------------------------------------------
-#[derive(Clone, Serialize, Deserialize, PartialEq, Debug, Getters)]
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug, Getters, HasEncoding)]
 pub struct PathLeft {
     #[get = "pub"]
     path: Path,
     #[get = "pub"]
+    #[encoding(hash = "OperationListListHash")]
     right: Hash,
     #[serde(skip_serializing)]
+    #[encoding(cache)]
     body: BinaryDataCache,
 }
 
-cached_data!(PathLeft, body);
-has_encoding!(PathLeft, PATH_LEFT_ENCODING, {
-        Encoding::Obj(vec![
-            Field::new("path", path_encoding()),
-            Field::new("right", Encoding::Hash(HashType::OperationListListHash)),
-        ])
-});
-
 impl PathLeft {
     pub fn new(path: Path, right: Hash, body: BinaryDataCache) -> Self {
         Self { path, right, body }
@@ -139,30 +110,25 @@ impl PathLeft {
 }
 
 // -----------------------------------------------------------------------------------------------
-#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug, HasEncoding)]
+#[encoding(tags = "u8")]
 pub enum Path {
+    #[encoding(tag = 0xF0, lazy)]
     Left(Box<PathLeft>),
+    #[encoding(tag = 0x0F, lazy)]
     Right(Box<PathRight>),
+    #[encoding(tag = 0x00)]
     Op,
 }
 
-pub fn path_encoding() -> Encoding {
-    Encoding::Tags(
-        size_of::<u8>(),
-        TagMap::new(vec![
-            Tag::new(0xF0, "Left", Encoding::Lazy(Arc::new(|| PathLeft::encoding().clone()))),
-            Tag::new(0x0F, "Right", Encoding::Lazy(Arc::new(|| PathRight::encoding().clone()))),
-            Tag::new(0x00, "Op", Encoding::Unit),
-        ]),
-    )
-}
-
 // -----------------------------------------------------------------------------------------------
-#[derive(Serialize, Deserialize, Debug, Getters, Clone)]
+#[derive(Serialize, Deserialize, Debug, Getters, Clone, HasEncoding)]
 pub struct GetOperationsForBlocksMessage {
     #[get = "pub"]
+    #[encoding(dynamic, bounded_list = "GET_OPERATIONS_FOR_BLOCKS_MAX_LENGTH")]
     get_operations_for_blocks: Vec<OperationsForBlock>,
     #[serde(skip_serializing)]
+    #[encoding(cache)]
     body: BinaryDataCache,
 }
 
@@ -174,10 +140,3 @@ impl GetOperationsForBlocksMessage {
         }
     }
 }
-
-cached_data!(GetOperationsForBlocksMessage, body);
-has_encoding!(GetOperationsForBlocksMessage, GET_OPERATIONS_FOR_BLOCKS_MESSAGE_ENCODING, {
-        Encoding::Obj(vec![
-            Field::new("get_operations_for_blocks", Encoding::dynamic(Encoding::list(OperationsForBlock::encoding().clone()))),
-        ])
-});
\ No newline at end of file
@@ -1,61 +1,82 @@
 // Copyright (c) SimpleStaking and Tezedge Contributors
 // SPDX-License-Identifier: MIT
 
+use getset::Getters;
 use serde::{Deserialize, Serialize};
 
-use crypto::hash::{HashType, ProtocolHash};
-use tezos_encoding::encoding::{Encoding, Field, HasEncoding};
-use tezos_encoding::has_encoding;
+use crypto::hash::ProtocolHash;
+use tezos_encoding::encoding::HasEncoding;
 
-use crate::cached_data;
 use crate::p2p::binary_message::cache::BinaryDataCache;
+use crate::p2p::encoding::limits::GET_PROTOCOLS_MAX_LENGTH;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Getters, Clone, HasEncoding)]
 pub struct ProtocolMessage {
+    #[get = "pub"]
     protocol: Protocol,
 
     #[serde(skip_serializing)]
+    #[encoding(cache)]
     body: BinaryDataCache,
 }
 
-cached_data!(ProtocolMessage, body);
-has_encoding!(ProtocolMessage, PROTOCOL_MESSAGE_ENCODING, {
-        Encoding::Obj(vec![
-            Field::new("protocol", Protocol::encoding().clone())
-        ])
-});
+impl ProtocolMessage {
+    pub fn new(protocol: Protocol) -> Self {
+        ProtocolMessage {
+            protocol,
+            body: Default::default(),
+        }
+    }
+}
 
 // -----------------------------------------------------------------------------------------------
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Getters, Clone, HasEncoding)]
 pub struct Component {
+    #[get = "pub"]
     name: String,
+    #[get = "pub"]
+    #[encoding(optional_field)]
     interface: Option<String>,
+    #[get = "pub"]
     implementation: String,
 
     #[serde(skip_serializing)]
+    #[encoding(cache)]
     body: BinaryDataCache,
 }
 
-cached_data!(Component, body);
-has_encoding!(Component, COMPONENT_ENCODING, {
-        Encoding::Obj(vec![
-            Field::new("name", Encoding::String),
-            Field::new("interface", Encoding::option_field(Encoding::String)),
-            Field::new("implementation", Encoding::String),
-        ])
-});
+impl Component {
+    pub fn new(name: String, interface: Option<String>, implementation: String) -> Self {
+        Component {
+            name,
+            interface,
+            implementation,
+            body: Default::default(),
+        }
+    }
+}
 
 // -----------------------------------------------------------------------------------------------
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, HasEncoding)]
 pub struct Protocol {
     expected_env_version: i16,
+    #[encoding(dynamic, list)]
     components: Vec<Component>,
 
     #[serde(skip_serializing)]
+    #[encoding(cache)]
     body: BinaryDataCache,
 }
 
 impl Protocol {
+    pub fn new(expected_env_version: i16, components: Vec<Component>) -> Self {
+        Protocol {
+            expected_env_version,
+            components,
+            body: Default::default(),
+        }
+    }
+
     pub fn expected_env_version(&self) -> i16 {
         self.expected_env_version
     }
@@ -65,26 +86,23 @@ impl Protocol {
     }
 }
 
-cached_data!(Protocol, body);
-has_encoding!(Protocol, PROTOCOL_ENCODING, {
-        Encoding::Obj(vec![
-            Field::new("expected_env_version", Encoding::Int16),
-            Field::new("components", Encoding::dynamic(Encoding::list(Component::encoding().clone())))
-        ])
-});
-
 // -----------------------------------------------------------------------------------------------
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Getters, Clone, HasEncoding)]
 pub struct GetProtocolsMessage {
+    #[get = "pub"]
+    #[encoding(dynamic, bounded_list = "GET_PROTOCOLS_MAX_LENGTH", hash = "ProtocolHash")]
     get_protocols: Vec<ProtocolHash>,
 
     #[serde(skip_serializing)]
+    #[encoding(cache)]
     body: BinaryDataCache,
 }
 
-cached_data!(GetProtocolsMessage, body);
-has_encoding!(GetProtocolsMessage, GET_PROTOCOLS_MESSAGE_ENCODING, {
-        Encoding::Obj(vec![
-            Field::new("get_protocols", Encoding::dynamic(Encoding::list(Encoding::Hash(HashType::ProtocolHash)))),
-        ])
-});
\ No newline at end of file
+impl GetProtocolsMessage {
+    pub fn new(protocol_hashes: &[ProtocolHash]) -> Self {
+        GetProtocolsMessage {
+            get_protocols: protocol_hashes.to_vec(),
+            body: Default::default(),
+        }
+    }
+}
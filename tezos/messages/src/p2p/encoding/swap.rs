@@ -1,16 +1,17 @@
 // Copyright (c) SimpleStaking and Tezedge Contributors
 // SPDX-License-Identifier: MIT
 
+use std::net::SocketAddr;
+
 use getset::Getters;
 use serde::{Deserialize, Serialize};
 
-use tezos_encoding::encoding::{Encoding, Field, HasEncoding};
-use tezos_encoding::has_encoding;
+use tezos_encoding::encoding::HasEncoding;
 
-use crate::cached_data;
 use crate::p2p::binary_message::cache::BinaryDataCache;
+use crate::p2p::point::format_point;
 
-#[derive(Serialize, Deserialize, Debug, Getters, Clone)]
+#[derive(Serialize, Deserialize, Debug, Getters, Clone, HasEncoding)]
 pub struct SwapMessage {
     #[get = "pub"]
     point: String,
@@ -18,13 +19,16 @@ pub struct SwapMessage {
     peer_id: String,
 
     #[serde(skip_serializing)]
+    #[encoding(cache)]
     body: BinaryDataCache,
 }
 
-cached_data!(SwapMessage, body);
-has_encoding!(SwapMessage, SWAP_MESSAGE_ENCODING, {
-        Encoding::Obj(vec![
-            Field::new("point", Encoding::String),
-            Field::new("peer_id", Encoding::String),
-        ])
-});
\ No newline at end of file
+impl SwapMessage {
+    pub fn new(point: &SocketAddr, peer_id: &str) -> Self {
+        Self {
+            point: format_point(point),
+            peer_id: peer_id.to_string(),
+            body: Default::default(),
+        }
+    }
+}
@@ -5,18 +5,21 @@ use std::fmt;
 
 use serde::{Deserialize, Serialize};
 
-use tezos_encoding::encoding::{Encoding, Field, HasEncoding};
-use tezos_encoding::has_encoding;
+use tezos_encoding::encoding::HasEncoding;
 
-use crate::cached_data;
 use crate::p2p::binary_message::cache::BinaryDataCache;
+use crate::p2p::encoding::ack::NackMotive;
 
-#[derive(Serialize, Deserialize, Clone)]
+/// First p2p version, which understands `Nack` with motive and potential peers, older versions understand only `NackV0`
+const NACK_WITH_MOTIVE_P2P_VERSION: u16 = 1;
+
+#[derive(Serialize, Deserialize, Clone, HasEncoding)]
 pub struct NetworkVersion {
     chain_name: String,
     distributed_db_version: u16,
     p2p_version: u16,
     #[serde(skip_serializing)]
+    #[encoding(cache)]
     body: BinaryDataCache,
 }
 
@@ -42,16 +45,36 @@ impl NetworkVersion {
     pub fn supports(&self, other: &NetworkVersion) -> bool {
         self.chain_name == other.chain_name && self.distributed_db_version == other.distributed_db_version
     }
-}
 
-cached_data!(NetworkVersion, body);
-has_encoding!(NetworkVersion, NETWORK_VERSION_ENCODING, {
-        Encoding::Obj(vec![
-            Field::new("chain_name", Encoding::String),
-            Field::new("distributed_db_version", Encoding::Uint16),
-            Field::new("p2p_version", Encoding::Uint16)
-        ])
-});
+    /// Select the best version supported by us and by the remote peer.
+    ///
+    /// Remote peer has to be on the same chain and its `distributed_db_version` cannot be older than ours.
+    /// From every compatible remote version the lower `distributed_db_version` and `p2p_version` of both sides is taken
+    /// and the highest of these versions is selected. If there is no compatible version, the motive for `Nack` is returned.
+    pub fn select_compatible(&self, remote_versions: &[NetworkVersion]) -> Result<NetworkVersion, NackMotive> {
+        let same_chain: Vec<&NetworkVersion> = remote_versions.iter()
+            .filter(|remote| remote.chain_name == self.chain_name)
+            .collect();
+        if same_chain.is_empty() {
+            return Err(NackMotive::UnknownChainName);
+        }
+
+        same_chain.into_iter()
+            .filter(|remote| remote.distributed_db_version >= self.distributed_db_version)
+            .map(|remote| NetworkVersion::new(
+                self.chain_name.clone(),
+                self.distributed_db_version.min(remote.distributed_db_version),
+                self.p2p_version.min(remote.p2p_version),
+            ))
+            .max_by_key(|version| (version.distributed_db_version, version.p2p_version))
+            .ok_or(NackMotive::DeprecatedDistributedDbVersion)
+    }
+
+    /// Returns true if peer with this version understands `Nack` with motive, otherwise `NackV0` has to be sent
+    pub fn supports_nack_with_motive(&self) -> bool {
+        self.p2p_version >= NACK_WITH_MOTIVE_P2P_VERSION
+    }
+}
 
 impl Eq for NetworkVersion {}
 
@@ -61,4 +84,40 @@ impl PartialEq for NetworkVersion {
             && self.distributed_db_version == other.distributed_db_version
             && self.p2p_version == other.p2p_version
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_select_compatible_version() {
+        let local = NetworkVersion::new("TEZOS_ALPHANET_CARTHAGE_2019-11-28T13:02:13Z".to_string(), 1, 1);
+
+        // the highest common version is selected
+        let remote = vec![
+            NetworkVersion::new("TEZOS_ALPHANET_CARTHAGE_2019-11-28T13:02:13Z".to_string(), 1, 0),
+            NetworkVersion::new("TEZOS_ALPHANET_CARTHAGE_2019-11-28T13:02:13Z".to_string(), 2, 2),
+        ];
+        assert_eq!(Ok(NetworkVersion::new("TEZOS_ALPHANET_CARTHAGE_2019-11-28T13:02:13Z".to_string(), 1, 1)), local.select_compatible(&remote));
+
+        // older p2p version of the remote peer is used
+        let remote = vec![NetworkVersion::new("TEZOS_ALPHANET_CARTHAGE_2019-11-28T13:02:13Z".to_string(), 1, 0)];
+        let selected = local.select_compatible(&remote).unwrap();
+        assert_eq!(0, selected.p2p_version);
+        assert!(!selected.supports_nack_with_motive());
+    }
+
+    #[test]
+    fn can_not_select_incompatible_version() {
+        let local = NetworkVersion::new("TEZOS_ALPHANET_CARTHAGE_2019-11-28T13:02:13Z".to_string(), 1, 1);
+
+        let remote = vec![NetworkVersion::new("TEZOS_MAINNET".to_string(), 1, 1)];
+        assert_eq!(Err(NackMotive::UnknownChainName), local.select_compatible(&remote));
+
+        let remote = vec![NetworkVersion::new("TEZOS_ALPHANET_CARTHAGE_2019-11-28T13:02:13Z".to_string(), 0, 1)];
+        assert_eq!(Err(NackMotive::DeprecatedDistributedDbVersion), local.select_compatible(&remote));
+
+        assert_eq!(Err(NackMotive::UnknownChainName), local.select_compatible(&[]));
+    }
+}
@@ -0,0 +1,54 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Points are `ip:port` addresses of the peers exchanged in p2p messages.
+//!
+//! IPv4 point is formatted as `1.2.3.4:9732`, IPv6 point as `[::1]:9732`.
+//! IPv4-mapped IPv6 addresses (e.g. of IPv4 peers connected to dual-stack listener) are handled as IPv4 addresses.
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+/// Convert IPv4-mapped IPv6 address (`::ffff:a.b.c.d`) to IPv4 address, other addresses are returned unchanged
+pub fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(ipv6) => match ipv6.octets() {
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d] => IpAddr::V4(Ipv4Addr::new(a, b, c, d)),
+            _ => ip,
+        },
+        IpAddr::V4(_) => ip,
+    }
+}
+
+/// Socket address with [canonical IP address](canonical_ip)
+pub fn canonical_point(point: SocketAddr) -> SocketAddr {
+    SocketAddr::new(canonical_ip(point.ip()), point.port())
+}
+
+/// Format point as it is sent to the remote peers
+pub fn format_point(point: &SocketAddr) -> String {
+    canonical_point(*point).to_string()
+}
+
+/// Parse point received from the remote peer
+pub fn parse_point(point: &str) -> Option<SocketAddr> {
+    point.parse::<SocketAddr>().ok().map(canonical_point)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_format_point() {
+        assert_eq!(Some("1.2.3.4:9732".parse().unwrap()), parse_point("1.2.3.4:9732"));
+        assert_eq!(Some("[::1]:9732".parse().unwrap()), parse_point("[::1]:9732"));
+        assert_eq!(Some("1.2.3.4:9732".parse().unwrap()), parse_point("[::ffff:1.2.3.4]:9732"));
+        assert_eq!(None, parse_point("::1:9732"));
+        assert_eq!(None, parse_point("1.2.3.4"));
+
+        assert_eq!("[2001:db8::1]:9732", format_point(&"[2001:db8::1]:9732".parse().unwrap()));
+        assert_eq!("1.2.3.4:9732", format_point(&"[::ffff:1.2.3.4]:9732".parse().unwrap()));
+        // IPv4-compatible address is not mapped
+        assert_eq!("[::1]:9732", format_point(&"[::1]:9732".parse().unwrap()));
+    }
+}
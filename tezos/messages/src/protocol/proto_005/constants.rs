@@ -9,8 +9,8 @@ use tezos_encoding::{
     has_encoding,
     types::BigInt,
 };
+use tezos_encoding::non_cached_data;
 
-use crate::non_cached_data;
 use crate::protocol::{ToRpcJsonMap, UniversalValue};
 
 pub const FIXED: FixedConstants = FixedConstants {
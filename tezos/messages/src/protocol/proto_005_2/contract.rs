@@ -9,8 +9,7 @@ use tezos_encoding::{
     has_encoding,
     types::BigInt,
 };
-
-use crate::non_cached_data;
+use tezos_encoding::non_cached_data;
 
 #[derive(Serialize, Deserialize, Debug, Clone, Getters)]
 pub struct Counter {
@@ -28,3 +28,24 @@ fn can_format_ip_address() {
     assert_eq!("123.123.124.21:9876", &message.id()[0]);
     assert_eq!("[fe80:e828:209d:20e:c0ae::]:375", &message.id()[1]);
 }
+
+#[test]
+fn can_parse_ipv6_points() -> Result<(), Error> {
+    let message = AdvertiseMessage::new(&[
+        "[::1]:9732".parse()?,
+        "[::ffff:10.0.0.1]:9732".parse()?,
+        "[2001:db8::7]:19732".parse()?,
+    ]);
+    assert_eq!(vec!["[::1]:9732", "10.0.0.1:9732", "[2001:db8::7]:19732"], *message.id());
+
+    let message = AdvertiseMessage::from_bytes(message.as_bytes()?)?;
+    assert_eq!(
+        vec![
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 9732),
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 9732),
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 7)), 19732),
+        ],
+        message.points()
+    );
+    Ok(())
+}
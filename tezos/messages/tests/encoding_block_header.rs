@@ -36,4 +36,25 @@ fn can_deserialize_get_block_headers() -> Result<(), Error> {
         }
         _ => panic!("Unsupported encoding: {:?}", message)
     }
-}
\ No newline at end of file
+}
+#[test]
+fn can_not_deserialize_get_block_headers_over_limit() -> Result<(), Error> {
+    let block_hash = "2253698f0c94788689fb95ca35eb1535ec3a8b7c613a97e6683f8007d7959e4b";
+
+    // 10 hashes are allowed
+    let message_bytes = hex::decode(format!("00000146002000000140{}", block_hash.repeat(10)))?;
+    let messages = PeerMessageResponse::from_bytes(message_bytes)?;
+    match messages.messages().get(0).unwrap() {
+        PeerMessage::GetBlockHeaders(message) => assert_eq!(10, message.get_block_headers().len()),
+        message => panic!("Unsupported encoding: {:?}", message)
+    }
+
+    // 11 hashes exceed the limit
+    let message_bytes = hex::decode(format!("00000166002000000160{}", block_hash.repeat(11)))?;
+    assert!(PeerMessageResponse::from_bytes(message_bytes).is_err());
+
+    // and we cannot send such message either
+    let message = GetBlockHeadersMessage::new(vec![hex::decode(block_hash)?; 11]);
+    assert!(message.as_bytes().is_err());
+    Ok(())
+}
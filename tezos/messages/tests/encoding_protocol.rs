@@ -2,6 +2,8 @@
 // SPDX-License-Identifier: MIT
 
 use failure::Error;
+
+use crypto::hash::HashType;
 use tezos_messages::p2p::binary_message::BinaryMessage;
 use tezos_messages::p2p::encoding::prelude::*;
 
@@ -11,4 +13,26 @@ fn can_deserialize_protocol() -> Result<(), Error> {
     let message = Protocol::from_bytes(message_bytes)?;
     assert_eq!(68, message.components().len());
     Ok(assert_eq!(0, message.expected_env_version()))
-}
\ No newline at end of file
+}
+
+#[test]
+fn can_serialize_and_deserialize_protocol_message() -> Result<(), Error> {
+    let protocol = Protocol::new(0, vec![
+        Component::new("Main".to_string(), Some("val main : unit".to_string()), "let main = ()".to_string())
+    ]);
+    let message_bytes = ProtocolMessage::new(protocol).as_bytes()?;
+    let message = ProtocolMessage::from_bytes(message_bytes)?;
+    assert_eq!(1, message.protocol().components().len());
+    let component = &message.protocol().components()[0];
+    assert_eq!("Main", component.name());
+    assert_eq!(&Some("val main : unit".to_string()), component.interface());
+    Ok(assert_eq!("let main = ()", component.implementation()))
+}
+
+#[test]
+fn can_serialize_and_deserialize_get_protocols() -> Result<(), Error> {
+    let protocol_hash = HashType::ProtocolHash.string_to_bytes("PsCARTHAGazKbHtnKfLzQg3kms52kSRpgnDY982a9oYsSXRLQEb")?;
+    let message_bytes = GetProtocolsMessage::new(&[protocol_hash.clone()]).as_bytes()?;
+    let message = GetProtocolsMessage::from_bytes(message_bytes)?;
+    Ok(assert_eq!(&vec![protocol_hash], message.get_protocols()))
+}
@@ -0,0 +1,21 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::net::SocketAddr;
+
+use failure::Error;
+
+use tezos_messages::p2p::binary_message::BinaryMessage;
+use tezos_messages::p2p::encoding::prelude::*;
+
+#[test]
+fn can_serialize_and_deserialize_swap() -> Result<(), Error> {
+    let point: SocketAddr = "[fe80::e828:209d:20e:c0ae]:375".parse()?;
+    let message = SwapMessage::new(&point, "idtgUrRAAvuH3cbZrXyfaKZovoFUqt");
+    assert_eq!("[fe80::e828:209d:20e:c0ae]:375", message.point());
+
+    let message_bytes = message.as_bytes()?;
+    let message = SwapMessage::from_bytes(message_bytes)?;
+    assert_eq!("[fe80::e828:209d:20e:c0ae]:375", message.point());
+    Ok(assert_eq!("idtgUrRAAvuH3cbZrXyfaKZovoFUqt", message.peer_id()))
+}
@@ -650,10 +650,11 @@ impl<Runner: ProtocolRunner + 'static> ProtocolRunnerEndpoint<Runner> {
 
     /// Starts protocol runner sub-process and takes care of it automatically.
     /// If sub-process failed, it is automatically spawned another sub-process.
-    /// Returns AtomicBool, if set to false, than terminates sub-process
-    pub fn start_in_restarting_mode(&mut self) -> Result<Arc<AtomicBool>, ProtocolServiceError> {
+    /// Returns AtomicBool, if set to false, than terminates sub-process,
+    /// and handle of the watchdog thread, which finishes after the sub-process was terminated
+    pub fn start_in_restarting_mode(&mut self) -> Result<(Arc<AtomicBool>, thread::JoinHandle<()>), ProtocolServiceError> {
         let run_restarting_feature = Arc::new(AtomicBool::new(true));
-        {
+        let watchdog = {
             let log = self.log.clone();
             let run = run_restarting_feature.clone();
             let runner = self.runner.clone();
@@ -686,7 +687,7 @@ impl<Runner: ProtocolRunner + 'static> ProtocolRunnerEndpoint<Runner> {
             })
         };
 
-        Ok(run_restarting_feature)
+        Ok((run_restarting_feature, watchdog))
     }
 }
 